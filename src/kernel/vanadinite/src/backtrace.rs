@@ -0,0 +1,188 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Frame pointer based stack unwinding and symbolization of kernel addresses.
+//!
+//! The kernel is built with frame pointers, so each frame stores the return
+//! address at `fp - 8` and the caller's frame pointer at `fp - 16`. Function
+//! names come from the `.ksyms` section, which is reserved by the linker
+//! script and filled in by `xtask` after linking with a table of the kernel's
+//! function symbols, sorted by address:
+//!
+//! ```text
+//! magic: [u8; 4] = b"KSYM", count: u32, strings_offset: u32, reserved: u32
+//! count * { address: u64, size: u32, name_offset: u32, name_len: u32, reserved: u32 }
+//! string data
+//! ```
+
+use crate::{mem::paging::VirtualAddress, utils::LinkerSymbol};
+use core::cell::Cell;
+
+const MAGIC: [u8; 4] = *b"KSYM";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 24;
+const MAX_FRAMES: usize = 64;
+
+extern "C" {
+    static __ksyms_start: LinkerSymbol;
+    static __ksyms_end: LinkerSymbol;
+}
+
+/// The program counter and frame pointer of a kernel context interrupted by a
+/// trap that's about to panic, so the backtrace can continue past the trap
+/// entry code, which doesn't keep a frame record
+#[thread_local]
+static TRAPPED_CONTEXT: Cell<Option<(usize, usize)>> = Cell::new(None);
+
+/// A function symbol from the kernel symbol table
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub address: usize,
+    pub size: usize,
+}
+
+/// The embedded symbol table, or `None` if it was never filled in
+fn table() -> Option<(&'static [u8], usize)> {
+    let table = unsafe {
+        let start = __ksyms_start.as_ptr();
+        let len = __ksyms_end.as_usize() - __ksyms_start.as_usize();
+        // The section is patched after linking, so it can't be read through a
+        // reference that the compiler might assume still holds zeroes
+        core::slice::from_raw_parts(core::hint::black_box(start), len)
+    };
+
+    if table.len() < HEADER_SIZE || table[..4] != MAGIC {
+        return None;
+    }
+
+    let count = read_u32(table, 4) as usize;
+    match HEADER_SIZE + count * ENTRY_SIZE <= table.len() {
+        true => Some((table, count)),
+        false => None,
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..][..4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..][..8].try_into().unwrap())
+}
+
+fn entry(table: &'static [u8], index: usize) -> Option<Symbol> {
+    let entry = HEADER_SIZE + index * ENTRY_SIZE;
+    let strings = read_u32(table, 8) as usize;
+    let name_offset = read_u32(table, entry + 12) as usize;
+    let name_len = read_u32(table, entry + 16) as usize;
+    let name = table.get(strings + name_offset..)?.get(..name_len)?;
+
+    Some(Symbol {
+        name: core::str::from_utf8(name).ok()?,
+        address: read_u64(table, entry) as usize,
+        size: read_u32(table, entry + 8) as usize,
+    })
+}
+
+/// Find the function containing `address`, along with the offset of `address`
+/// into it
+pub fn resolve(address: usize) -> Option<(Symbol, usize)> {
+    let (table, count) = table()?;
+
+    // Index of the first symbol starting after `address`
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        match read_u64(table, HEADER_SIZE + mid * ENTRY_SIZE) as usize <= address {
+            true => low = mid + 1,
+            false => high = mid,
+        }
+    }
+
+    let symbol = entry(table, low.checked_sub(1)?)?;
+    let offset = address - symbol.address;
+    match offset < symbol.size.max(1) {
+        true => Some((symbol, offset)),
+        false => None,
+    }
+}
+
+/// Displays an address as `function+offset` if it can be resolved
+pub struct Symbolized(pub usize);
+
+impl core::fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match resolve(self.0) {
+            Some((symbol, offset)) => write!(f, "{:#018x} {}+{:#x}", self.0, symbol.name, offset),
+            None => write!(f, "{:#018x} <unknown>", self.0),
+        }
+    }
+}
+
+/// Walk the chain of frame records starting at the frame pointer `fp`,
+/// yielding the return address of each frame
+pub fn frames(mut fp: usize) -> impl Iterator<Item = usize> {
+    core::iter::from_fn(move || {
+        if fp % 8 != 0 || !VirtualAddress::new(fp).is_kernel_region() {
+            return None;
+        }
+
+        let (ra, next) = unsafe { (*(fp as *const usize).sub(1), *(fp as *const usize).sub(2)) };
+
+        // Stacks grow down, so a caller's frame is always above its callee's.
+        // Anything else means the chain is corrupt or has ended.
+        fp = match next > fp && next - fp < 1024 * 1024 {
+            true => next,
+            false => 0,
+        };
+
+        match ra {
+            0 => None,
+            ra => Some(ra),
+        }
+    })
+    .take(MAX_FRAMES)
+}
+
+/// The current frame pointer
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    let fp: usize;
+    unsafe { core::arch::asm!("mv {}, s0", out(reg) fp) };
+    fp
+}
+
+/// Record the kernel context a trap interrupted before panicking over it, so
+/// that [`print_backtrace`] can continue into the code that trapped
+pub fn set_trapped_context(pc: usize, fp: usize) {
+    TRAPPED_CONTEXT.set(Some((pc, fp)));
+}
+
+/// Print a symbolized backtrace of the current hart starting at the caller,
+/// followed by the backtrace of the trapped kernel context if one was recorded
+#[inline(never)]
+pub fn print_backtrace() {
+    if table().is_none() {
+        crate::error!("No kernel symbol table present, addresses are unsymbolized");
+    }
+
+    crate::error!("Backtrace:");
+    for (i, ra) in frames(frame_pointer()).enumerate() {
+        // The return address is the instruction after the call, so step back
+        // into the call instruction itself for symbolization
+        crate::error!("  {:>2}: {}", i, Symbolized(ra - 2));
+    }
+
+    if let Some((pc, fp)) = TRAPPED_CONTEXT.take() {
+        crate::error!("Trapped context:");
+        crate::error!("  pc: {}", Symbolized(pc));
+        for (i, ra) in frames(fp).enumerate() {
+            crate::error!("  {:>2}: {}", i, Symbolized(ra - 2));
+        }
+    }
+}
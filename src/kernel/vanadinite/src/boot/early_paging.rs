@@ -80,6 +80,25 @@ pub unsafe extern "C" fn early_paging(hart_id: usize, fdt: *const u8) -> ! {
         }
     }
 
+    // The persistent store has to survive until the next boot
+    if let Some(pstore) = crate::platform::pstore::region(&fdt_struct) {
+        for page in (pstore.start & !0xFFF..pstore.end).step_by(4096) {
+            if page >= kernel_end && page < start + size {
+                pf_alloc.set_used(crate::mem::phys::PhysicalPage::from_ptr(page as *mut _));
+            }
+        }
+    }
+
+    // Keep the initrd around until any device tree overlays in it have been
+    // applied, after which `devicetree::init` frees it
+    if let Some(initrd) = crate::platform::devicetree::initrd(&fdt_struct) {
+        for page in (initrd.start & !0xFFF..initrd.end).step_by(4096) {
+            if page >= kernel_end {
+                pf_alloc.set_used(crate::mem::phys::PhysicalPage::from_ptr(page as *mut _));
+            }
+        }
+    }
+
     drop(pf_alloc);
 
     let mut root_page_table = PageTable::new_raw();
@@ -5,8 +5,13 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::mem::{manager::AddressRegionKind, paging::VirtualAddress, region::SharedPhysicalRegion};
-use alloc::collections::BTreeMap;
+use crate::{
+    io::mux::ConsoleStream,
+    mem::{manager::AddressRegionKind, paging::VirtualAddress, region::SharedPhysicalRegion},
+    scheduler::WaitId,
+    syscall::{dma::DmaRegion, taskgroup::TaskGroup},
+};
+use alloc::{collections::BTreeMap, sync::Arc};
 use core::ops::Range;
 use librust::{
     capabilities::{CapabilityPtr, CapabilityRights},
@@ -50,14 +55,44 @@ impl CapabilitySpace {
     }
 }
 
+impl IntoIterator for CapabilitySpace {
+    type Item = (CapabilityPtr, Capability);
+    type IntoIter = alloc::collections::btree_map::IntoIter<CapabilityPtr, Capability>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.inner.into_iter()
+    }
+}
+
 pub struct Capability {
     pub resource: CapabilityResource,
     pub rights: CapabilityRights,
+    /// Value chosen by whoever minted the capability, which is delivered to
+    /// the receiver along with every message sent through it. Only channel
+    /// capabilities can be badged, `0` means the capability is unbadged.
+    pub badge: usize,
 }
 
 #[derive(Debug)]
 pub enum CapabilityResource {
     Channel(ChannelId),
     Memory(SharedPhysicalRegion, Range<VirtualAddress>, AddressRegionKind),
-    Mmio(Range<VirtualAddress>, alloc::vec::Vec<usize>),
+    /// Registers of a claimed device, its interrupts, and its path in the
+    /// device tree
+    Mmio(Range<VirtualAddress>, alloc::vec::Vec<usize>, alloc::string::String),
+    /// One-shot capability to reply to a blocked caller, consumed by the
+    /// `Reply` syscall
+    Reply(WaitId),
+    /// A stream that a task's stdin or stdout can be bound to
+    ConsoleStream(ConsoleStream),
+    /// Memory devices can be pointed at, and where it's mapped
+    Dma(Arc<DmaRegion>, Range<VirtualAddress>),
+    /// Allows shutting down and rebooting the system, minted once for `init`
+    SystemControl,
+    /// Allows mapping the kernel log ring, minted once for `init`
+    KernelLog,
+    /// Allows reading and stopping the profiling session
+    Perf,
+    /// Allows spawning tasks into and killing a task group
+    TaskGroup(Arc<TaskGroup>),
 }
@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Edge coverage of the kernel, for finding the paths the tests never take.
+//!
+//! Building with `cargo xtask build vanadinite --coverage` turns on LLVM's
+//! SanitizerCoverage, which gives every edge of the control flow graph a flag
+//! that's set the first time the edge is taken. The linker script collects the
+//! flags into one region, and the address each flag belongs to into a table
+//! which only needs to exist in the kernel binary, since `xtask` maps the flags
+//! back to functions on the host. Without `--coverage` the region is empty.
+//!
+//! The flags are dumped to the console when the kernel exits, and can be read
+//! and cleared from userspace with the system control capability. Either way
+//! they're printed as `coverage-flags: <index> <hex>` lines, which
+//! `cargo xtask decode` turns into a report.
+
+use crate::utils::LinkerSymbol;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+/// Flags printed on each line of a dump, which keeps lines under 2 KiB
+const DUMP_CHUNK: usize = 1024;
+
+extern "C" {
+    static __coverage_start: LinkerSymbol;
+    static __coverage_end: LinkerSymbol;
+}
+
+/// Flags are set by whichever hart takes the edge without any synchronization,
+/// so they're only ever accessed through raw pointers
+fn region() -> (*mut u8, usize) {
+    unsafe { (__coverage_start.as_mut_ptr(), __coverage_end.as_usize() - __coverage_start.as_usize()) }
+}
+
+/// The number of edges being tracked, zero if the kernel wasn't built with
+/// coverage
+pub fn len() -> usize {
+    region().1
+}
+
+/// Copy the flags from `start` onwards into a buffer of at most `max` bytes
+pub fn read(start: usize, max: usize) -> Vec<u8> {
+    let (base, len) = region();
+    (start..len.min(start.saturating_add(max))).map(|i| unsafe { base.add(i).read_volatile() }).collect()
+}
+
+/// Clear every flag, so that only edges taken from now on are reported
+pub fn reset() {
+    let (base, len) = region();
+    for i in 0..len {
+        unsafe { base.add(i).write_volatile(0) };
+    }
+}
+
+/// Print the flags to the console, skipping chunks where no edge was taken.
+/// Nothing is allocated, since this runs on the way out after running out of
+/// memory too.
+pub fn dump() {
+    let (base, len) = region();
+    if len == 0 {
+        return;
+    }
+
+    let flag = |i: usize| unsafe { base.add(i).read_volatile() };
+    let mut console = crate::io::CONSOLE.lock();
+    let _ = write!(console, "coverage: dumping {} edges\r\n", len);
+    for start in (0..len).step_by(DUMP_CHUNK) {
+        let chunk = start..len.min(start + DUMP_CHUNK);
+        if chunk.clone().all(|i| flag(i) == 0) {
+            continue;
+        }
+
+        let _ = write!(console, "coverage-flags: {} ", start);
+        for i in chunk {
+            let _ = write!(console, "{:02x}", flag(i));
+        }
+        let _ = write!(console, "\r\n");
+    }
+}
+
+// LLVM emits a constructor passing each module's flags and address table to
+// these, which the kernel never runs since it finds them through the linker
+// script instead, but they still need to exist to link
+
+#[no_mangle]
+extern "C" fn __sanitizer_cov_bool_flag_init(_start: *mut bool, _end: *mut bool) {}
+
+#[no_mangle]
+extern "C" fn __sanitizer_cov_pcs_init(_start: *const usize, _end: *const usize) {}
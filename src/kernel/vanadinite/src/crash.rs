@@ -0,0 +1,72 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Crash dumps of userspace tasks killed by faults, which are kept around so
+//! that a debugger or supervisor can inspect them after the task is gone
+
+use crate::{task::Task, trap::GeneralRegisters};
+use alloc::{collections::VecDeque, string::ToString, vec::Vec};
+use librust::syscalls::{
+    mem::RegionInfo,
+    system::{CrashInfo, InfoString},
+};
+use sync::SpinMutex;
+
+/// Number of crashes kept before the oldest are dropped
+const MAX_CRASHES: usize = 8;
+
+static CRASHES: SpinMutex<CrashBuffer> = SpinMutex::new(CrashBuffer { next_id: 1, crashes: VecDeque::new() });
+
+struct CrashBuffer {
+    next_id: usize,
+    crashes: VecDeque<CrashDump>,
+}
+
+#[derive(Clone)]
+pub struct CrashDump {
+    pub info: CrashInfo,
+    /// The task's memory map when it died
+    pub regions: Vec<RegionInfo>,
+}
+
+/// Record that `task` is being killed by the fault `cause`, with the
+/// registers it faulted with, returning the ID of the crash. The task must not
+/// have its memory manager locked.
+pub fn record(task: &Task, registers: &GeneralRegisters, cause: usize, pc: usize, fault_address: usize) -> usize {
+    // `GeneralRegisters` is `x1` through `x31` in order
+    let registers: [usize; 31] = unsafe { core::mem::transmute(*registers) };
+    let regions = crate::syscall::mem::region_info(&task.group.memory_manager.lock());
+
+    let mut buffer = CRASHES.lock();
+    let id = buffer.next_id;
+    buffer.next_id += 1;
+
+    if buffer.crashes.len() == MAX_CRASHES {
+        buffer.crashes.pop_front();
+    }
+
+    buffer.crashes.push_back(CrashDump {
+        info: CrashInfo {
+            id,
+            tid: task.tid.value(),
+            name: InfoString::new(&task.display_name().to_string()),
+            cause,
+            pc,
+            fault_address,
+            registers,
+        },
+        regions,
+    });
+
+    log::info!("Recorded crash dump {} for task {}", id, task.display_name());
+    id
+}
+
+/// The oldest crash still recorded with an ID of at least `min_id`
+pub fn find(min_id: usize) -> Option<CrashDump> {
+    CRASHES.lock().crashes.iter().find(|crash| crash.info.id >= min_id).cloned()
+}
@@ -0,0 +1,255 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Kernel random numbers, which don't depend on any particular RNG hardware.
+//! Entropy from whatever is available (seeds passed in the device tree, timer
+//! jitter, interrupt timing, and anything drivers add) is mixed into a global
+//! pool. Each hart runs its own ChaCha20 generator seeded from the pool, so
+//! generating numbers never contends on a lock, and reseeds it after it's
+//! produced enough output or enough time has passed.
+
+use super::chacha20::{self, BLOCK_SIZE, KEY_WORDS};
+use crate::{csr, TIMER_FREQ};
+use core::{
+    cell::{Cell, RefCell},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use fdt::Fdt;
+use sync::SpinMutex;
+
+/// Nonces keeping the pool's uses of the block function apart
+const MIX_NONCE: [u32; 3] = [0x786D_6970, 0, 0];
+const EXTRACT_NONCE: [u32; 3] = [0x7478_6570, 0, 0];
+
+/// Hart generators are reseeded after producing this many bytes
+const RESEED_BYTES: usize = 1024 * 1024;
+/// Or after this many seconds, whichever comes first
+const RESEED_INTERVAL_SECS: u64 = 300;
+
+static POOL: SpinMutex<EntropyPool> = SpinMutex::new(EntropyPool::new());
+
+#[thread_local]
+static HART_RNG: RefCell<Option<HartRng>> = RefCell::new(None);
+
+/// Interrupt arrival times folded together, mixed into the hart's generator
+/// when it's next reseeded
+#[thread_local]
+static INTERRUPT_TIMINGS: Cell<u64> = Cell::new(0);
+
+struct EntropyPool {
+    key: [u32; KEY_WORDS],
+    counter: u32,
+}
+
+impl EntropyPool {
+    const fn new() -> Self {
+        Self { key: [0; KEY_WORDS], counter: 0 }
+    }
+
+    /// Fold `bytes` into the pool key, running the block function over it for
+    /// every 32 bytes so no input can cancel out what came before it
+    fn mix(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(KEY_WORDS * 4) {
+            for (i, byte) in chunk.iter().enumerate() {
+                self.key[i / 4] ^= u32::from(*byte) << (8 * (i % 4));
+            }
+
+            let block = chacha20::block(&self.key, self.counter, &MIX_NONCE);
+            self.counter = self.counter.wrapping_add(1);
+            self.key.copy_from_slice(&block[..KEY_WORDS]);
+        }
+    }
+
+    /// Take a seed out of the pool. Fresh timer jitter goes in first, and half
+    /// of the block becomes the new pool key while the other half is handed
+    /// out, so seeds can't be used to work out the pool or each other.
+    fn extract(&mut self) -> [u32; KEY_WORDS] {
+        self.mix(&timer_jitter().to_le_bytes());
+
+        let block = chacha20::block(&self.key, self.counter, &EXTRACT_NONCE);
+        self.counter = self.counter.wrapping_add(1);
+        self.key.copy_from_slice(&block[..KEY_WORDS]);
+
+        let mut seed = [0; KEY_WORDS];
+        seed.copy_from_slice(&block[KEY_WORDS..]);
+        seed
+    }
+}
+
+struct HartRng {
+    key: [u32; KEY_WORDS],
+    nonce: [u32; 3],
+    counter: u32,
+    buffer: [u8; BLOCK_SIZE],
+    /// Bytes at the end of the buffer which haven't been handed out
+    available: usize,
+    generated: usize,
+    reseed_at: u64,
+}
+
+impl HartRng {
+    fn new() -> Self {
+        let mut rng = Self {
+            key: [0; KEY_WORDS],
+            nonce: [crate::HART_ID.get() as u32, 0, 0],
+            counter: 0,
+            buffer: [0; BLOCK_SIZE],
+            available: 0,
+            generated: 0,
+            reseed_at: 0,
+        };
+
+        rng.reseed();
+        rng
+    }
+
+    fn reseed(&mut self) {
+        let seed = POOL.lock().extract();
+        let timings = INTERRUPT_TIMINGS.replace(0);
+
+        for (key, seed) in self.key.iter_mut().zip(seed) {
+            *key ^= seed;
+        }
+        self.key[0] ^= timings as u32;
+        self.key[1] ^= (timings >> 32) as u32;
+
+        self.available = 0;
+        self.generated = 0;
+        self.reseed_at = csr::time::read() + RESEED_INTERVAL_SECS * TIMER_FREQ.load(Ordering::Relaxed);
+    }
+
+    fn refill(&mut self) {
+        let block = chacha20::block(&self.key, self.counter, &self.nonce);
+        self.buffer = chacha20::block_bytes(&block);
+        self.available = BLOCK_SIZE;
+
+        self.counter = self.counter.wrapping_add(1);
+        if self.counter == 0 {
+            self.nonce[1] = self.nonce[1].wrapping_add(1);
+        }
+    }
+
+    fn fill_bytes(&mut self, mut dest: &mut [u8]) {
+        if self.generated >= RESEED_BYTES || csr::time::read() >= self.reseed_at {
+            self.reseed();
+        }
+
+        self.generated += dest.len();
+        while !dest.is_empty() {
+            if self.available == 0 {
+                self.refill();
+            }
+
+            let start = BLOCK_SIZE - self.available;
+            let n = self.available.min(dest.len());
+            dest[..n].copy_from_slice(&self.buffer[start..][..n]);
+            self.buffer[start..][..n].fill(0);
+            self.available -= n;
+            dest = &mut dest[n..];
+        }
+
+        // Replace the key with fresh output and throw away whatever's left in
+        // the buffer, so a later compromise of the hart's state doesn't reveal
+        // anything handed out before it
+        self.refill();
+        for (key, chunk) in self.key.iter_mut().zip(self.buffer.chunks_exact(4)) {
+            *key = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        self.buffer.fill(0);
+        self.available = 0;
+    }
+}
+
+/// Count how long it takes for the timer to tick over a few times, which varies
+/// with caches, bus contention, and whatever the other harts are doing
+fn timer_jitter() -> u64 {
+    let mut sample = 0u64;
+    for _ in 0..64 {
+        let start = csr::time::read();
+        let mut spins = 0u64;
+        while csr::time::read() == start {
+            spins += 1;
+        }
+
+        sample = sample.rotate_left(7) ^ spins ^ start;
+    }
+
+    sample
+}
+
+/// Seed the entropy pool with everything available at boot
+pub fn init(fdt: &Fdt<'_>) {
+    let mut pool = POOL.lock();
+    pool.mix(&csr::time::read().to_le_bytes());
+    pool.mix(&(crate::HART_ID.get() as u64).to_le_bytes());
+
+    // Bootloaders can pass along randomness from their own sources
+    if let Some(chosen) = fdt.find_node("/chosen") {
+        for name in ["rng-seed", "kaslr-seed"] {
+            if let Some(property) = chosen.property(name) {
+                pool.mix(property.value);
+            }
+        }
+    }
+
+    for _ in 0..4 {
+        pool.mix(&timer_jitter().to_le_bytes());
+    }
+}
+
+/// Mix bytes from some entropy source into the pool, they'll be used by each
+/// hart the next time it reseeds
+pub fn add_entropy(bytes: &[u8]) {
+    POOL.lock().mix(bytes);
+}
+
+/// Note the arrival of an interrupt on the current hart
+pub fn add_interrupt_timing() {
+    let timings = INTERRUPT_TIMINGS.get();
+    INTERRUPT_TIMINGS.set(timings.rotate_left(11) ^ csr::time::read());
+}
+
+/// Fill `dest` with random bytes from the current hart's generator
+pub fn fill_bytes(dest: &mut [u8]) {
+    HART_RNG.borrow_mut().get_or_insert_with(HartRng::new).fill_bytes(dest);
+}
+
+pub fn next_u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+/// A uniformly distributed number in `0..bound`
+pub fn range(bound: usize) -> usize {
+    assert_ne!(bound, 0, "empty range");
+
+    // Throw away anything in the partial range at the top which would bias the
+    // result towards smaller numbers
+    let zone = usize::MAX - (usize::MAX - bound + 1) % bound;
+    loop {
+        let n = next_u64() as usize;
+        if n <= zone {
+            return n % bound;
+        }
+    }
+}
+
+static CANARY_SECRET: AtomicU64 = AtomicU64::new(0);
+
+/// The canary value for something at `address`, mixed with a secret chosen
+/// once per boot so that one leaked canary doesn't give away any others
+pub fn canary(address: usize) -> u64 {
+    let mut secret = CANARY_SECRET.load(Ordering::Acquire);
+    if secret == 0 {
+        // Whichever hart gets here first picks the secret
+        let _ = CANARY_SECRET.compare_exchange(0, next_u64() | 1, Ordering::AcqRel, Ordering::Acquire);
+        secret = CANARY_SECRET.load(Ordering::Acquire);
+    }
+
+    secret ^ (address as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+}
@@ -0,0 +1,58 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+use vanadinite_macros::test;
+
+#[test]
+fn chacha20_block_function() {
+    // RFC 8439 section 2.3.2
+    let key = [0x0302_0100, 0x0706_0504, 0x0B0A_0908, 0x0F0E_0D0C, 0x1312_1110, 0x1716_1514, 0x1B1A_1918, 0x1F1E_1D1C];
+    let nonce = [0x0900_0000, 0x4A00_0000, 0x0000_0000];
+
+    assert_eq!(
+        chacha20::block(&key, 1, &nonce),
+        [
+            0xE4E7_F110,
+            0x1559_3BD1,
+            0x1FDD_0F50,
+            0xC471_20A3,
+            0xC7F4_D1C7,
+            0x0368_C033,
+            0x9AAA_2204,
+            0x4E6C_D4C3,
+            0x4664_82D2,
+            0x09AA_9F07,
+            0x05D7_C214,
+            0xA202_8BD9,
+            0xD19C_12B5,
+            0xB94E_16DE,
+            0xE883_D0CB,
+            0x4E3C_50A2,
+        ]
+    );
+}
+
+#[test]
+fn rand_output_differs() {
+    let mut a = [0; 64];
+    let mut b = [0; 64];
+    rand::fill_bytes(&mut a);
+    rand::fill_bytes(&mut b);
+
+    assert_ne!(a, [0; 64]);
+    assert_ne!(a, b);
+}
+
+#[test]
+fn rand_range_in_bounds() {
+    for bound in [1, 2, 3, 7, 4096, usize::MAX] {
+        for _ in 0..64 {
+            assert!(rand::range(bound) < bound);
+        }
+    }
+}
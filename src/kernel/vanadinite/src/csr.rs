@@ -17,7 +17,9 @@ pub mod sie {
     use core::arch::asm;
     #[inline(always)]
     pub fn enable() {
-        unsafe { asm!("csrw sie, {}", in(reg) 0x222) };
+        // Software, timer, and external interrupts, along with local counter
+        // overflow interrupts from `Sscofpmf` which is a no-op without it
+        unsafe { asm!("csrw sie, {}", in(reg) 0x2222) };
     }
 
     #[inline(always)]
@@ -40,6 +42,16 @@ pub mod sip {
 
         val
     }
+
+    #[inline(always)]
+    pub fn clear_ssip() {
+        unsafe { asm!("csrci sip, 2") };
+    }
+
+    #[inline(always)]
+    pub fn clear_lcofip() {
+        unsafe { asm!("csrc sip, {}", in(reg) 1 << 13) };
+    }
 }
 
 pub mod sstatus {
@@ -106,6 +118,15 @@ pub mod sstatus {
     }
 }
 
+pub mod scounteren {
+    use core::arch::asm;
+    /// Let userspace read the `time` CSR
+    #[inline(always)]
+    pub fn enable_time() {
+        unsafe { asm!("csrs scounteren, {}", in(reg) 1 << 1) };
+    }
+}
+
 pub mod time {
     use core::arch::asm;
     pub fn read() -> u64 {
@@ -0,0 +1,30 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::Driver;
+use crate::{
+    drivers::{
+        allwinner::d1::{ccu::Ccu, ths::Ths},
+        generic::{goldfish_rtc::GoldfishRtc, uart16550::Uart16550},
+        sifive::{fu540_c000::uart::SifiveUart, prci::Prci},
+        virtio::console::VirtioConsole,
+        CompatibleWith,
+    },
+    io::console,
+    platform::{clock, sensors},
+};
+
+/// Every driver the kernel can bind, devices are matched against them in order
+pub static DRIVERS: &[Driver] = &[
+    Driver { name: "console-uart16550", compatible: Uart16550::compatible_with, probe: console::probe },
+    Driver { name: "console-sifive-uart", compatible: SifiveUart::compatible_with, probe: console::probe },
+    Driver { name: "console-virtio", compatible: VirtioConsole::compatible_with, probe: console::probe },
+    Driver { name: "d1-ccu", compatible: Ccu::compatible_with, probe: sensors::probe_d1_ccu },
+    Driver { name: "d1-ths", compatible: Ths::compatible_with, probe: sensors::probe_d1_ths },
+    Driver { name: "sifive-prci", compatible: Prci::compatible_with, probe: sensors::probe_prci },
+    Driver { name: "goldfish-rtc", compatible: GoldfishRtc::compatible_with, probe: clock::probe_goldfish_rtc },
+];
@@ -0,0 +1,407 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Devices described by the device tree and the kernel drivers bound to them.
+//! The tree is walked once at boot to build a graph of every node, with `reg`
+//! entries translated through the `ranges` of each parent bus into physical
+//! addresses and the interrupt parent of each node resolved, so drivers don't
+//! each have to do it themselves. Drivers are then matched against the
+//! `compatible` strings of each device and probed, and a driver which depends
+//! on another device can defer until that device's driver has been bound.
+//! Devices no kernel driver has been bound to can instead be claimed by a
+//! single userspace task at a time.
+
+mod drivers;
+#[cfg(test)]
+mod tests;
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use fdt::{node::FdtNode, Fdt};
+use librust::task::Tid;
+use sync::{ReadGuard, SpinRwLock};
+
+static DEVICES: SpinRwLock<Vec<Device>> = SpinRwLock::new(Vec::new());
+/// Owners of the devices claimed by userspace, by device path. Devices stay in
+/// the table once their owner exits since their interrupt handlers can't be
+/// unregistered, but can be claimed again.
+static CLAIMS: SpinRwLock<BTreeMap<String, Option<Tid>>> = SpinRwLock::new(BTreeMap::new());
+
+/// Address and size cells used when a node doesn't specify them
+const DEFAULT_ADDRESS_CELLS: usize = 2;
+const DEFAULT_SIZE_CELLS: usize = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    /// Physical address of the start of the region
+    pub address: usize,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindState {
+    /// No driver has claimed the device, though userspace may still use it
+    Unbound,
+    /// A driver is waiting on another device before it can be probed again
+    Deferred(&'static str),
+    Bound(&'static str),
+    Failed(&'static str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClaimError {
+    NotFound,
+    /// Another task already owns the device
+    InUse(Tid),
+    /// A kernel driver is using the device
+    Bound(&'static str),
+    /// The device doesn't have any memory mapped registers
+    NoRegisters,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeError {
+    /// The driver matched but doesn't want the device
+    NotSupported,
+    /// The driver needs some other device to be bound first
+    Deferred,
+    Failed(&'static str),
+}
+
+pub struct Driver {
+    pub name: &'static str,
+    /// Usually [`crate::drivers::CompatibleWith::compatible_with`]
+    pub compatible: fn() -> &'static [&'static str],
+    pub probe: fn(&Fdt<'_>, &Device) -> Result<(), ProbeError>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Device {
+    /// Node name, including the unit address
+    pub name: String,
+    /// Full path to the node from the root of the tree
+    pub path: String,
+    pub compatible: Vec<String>,
+    pub phandle: Option<u32>,
+    /// Index of the parent node in the graph
+    pub parent: Option<usize>,
+    /// Register regions which are visible to the CPU. Regions on buses that
+    /// aren't memory mapped (e.g. I2C addresses) are left out.
+    pub regions: Vec<Region>,
+    /// `phandle` of the interrupt controller the node's interrupts are wired
+    /// to, inherited from the closest ancestor if the node doesn't have one
+    pub interrupt_parent: Option<u32>,
+    /// Interrupt sources local to the interrupt parent
+    pub interrupt_sources: Vec<usize>,
+    pub state: BindState,
+}
+
+impl Device {
+    pub fn is_compatible(&self, compatible: &[&str]) -> bool {
+        self.compatible.iter().any(|c| compatible.contains(&&**c))
+    }
+
+    /// The global interrupt IDs of the device, only available once the
+    /// interrupt controllers have been probed
+    pub fn interrupts(&self) -> Vec<usize> {
+        self.interrupt_sources
+            .iter()
+            .filter_map(|&source| match crate::interrupts::global_interrupt(self.interrupt_parent, source) {
+                Some(id) => Some(id),
+                None => {
+                    log::warn!("Interrupt {} of {} has no controller", source, self.path);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Look up the device's node, for drivers which need properties that
+    /// aren't parsed here
+    pub fn node<'b, 'a>(&self, fdt: &'b Fdt<'a>) -> Option<FdtNode<'b, 'a>> {
+        fdt.find_node(&self.path)
+    }
+}
+
+/// A single entry of a bus's `ranges` property
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressRange {
+    pub child: u64,
+    pub parent: u64,
+    pub size: u64,
+}
+
+/// How addresses on a bus map to addresses on its parent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressMap {
+    /// An empty `ranges`, the addresses are the same on both sides
+    Identity,
+    Ranges(Vec<AddressRange>),
+    /// No `ranges` at all, addresses on this bus aren't visible to the parent
+    Untranslatable,
+}
+
+impl AddressMap {
+    pub fn translate(&self, address: u64) -> Option<u64> {
+        match self {
+            AddressMap::Identity => Some(address),
+            AddressMap::Ranges(ranges) => ranges
+                .iter()
+                .find(|r| address >= r.child && address - r.child < r.size)
+                .map(|r| r.parent + (address - r.child)),
+            AddressMap::Untranslatable => None,
+        }
+    }
+}
+
+/// Everything about a node's ancestors needed to interpret the node
+#[derive(Clone)]
+struct BusContext {
+    address_cells: usize,
+    size_cells: usize,
+    /// Address maps from the innermost bus outwards to the root
+    maps: Vec<AddressMap>,
+    interrupt_parent: Option<u32>,
+}
+
+fn read_cells(bytes: &[u8], cells: usize) -> Option<u64> {
+    if cells > 2 {
+        // Only the low 64 bits matter, anything above is flags (e.g. PCI)
+        return read_cells(bytes.get((cells - 2) * 4..)?, 2);
+    }
+
+    bytes
+        .chunks_exact(4)
+        .take(cells)
+        .try_fold(0u64, |acc, cell| Some((acc << 32) | u64::from(u32::from_be_bytes(cell.try_into().ok()?))))
+}
+
+fn u32_property(node: &FdtNode<'_, '_>, name: &str) -> Option<u32> {
+    let value = node.property(name)?.value;
+    Some(u32::from_be_bytes(value.get(..4)?.try_into().ok()?))
+}
+
+/// Parse a `ranges` property, where `parent_cells` is the parent bus's
+/// `#address-cells`
+pub fn parse_ranges(value: Option<&[u8]>, child_cells: usize, parent_cells: usize, size_cells: usize) -> AddressMap {
+    let value = match value {
+        Some([]) => return AddressMap::Identity,
+        Some(value) => value,
+        None => return AddressMap::Untranslatable,
+    };
+
+    let entry_size = (child_cells + parent_cells + size_cells) * 4;
+    if entry_size == 0 {
+        return AddressMap::Untranslatable;
+    }
+
+    let ranges = value
+        .chunks_exact(entry_size)
+        .filter_map(|entry| {
+            let (child, rest) = entry.split_at(child_cells * 4);
+            let (parent, size) = rest.split_at(parent_cells * 4);
+
+            Some(AddressRange {
+                child: read_cells(child, child_cells)?,
+                parent: read_cells(parent, parent_cells)?,
+                size: read_cells(size, size_cells)?,
+            })
+        })
+        .collect();
+
+    AddressMap::Ranges(ranges)
+}
+
+/// Translate an address on the innermost bus in `maps` all the way up to a
+/// physical address
+pub fn translate(maps: &[AddressMap], address: u64) -> Option<u64> {
+    maps.iter().try_fold(address, |address, map| map.translate(address))
+}
+
+fn add_node(devices: &mut Vec<Device>, node: FdtNode<'_, '_>, path: String, parent: Option<usize>, bus: &BusContext) {
+    let interrupt_parent = u32_property(&node, "interrupt-parent").or(bus.interrupt_parent);
+
+    let regions = match node.property("reg") {
+        Some(reg) if bus.address_cells > 0 => reg
+            .value
+            .chunks_exact((bus.address_cells + bus.size_cells) * 4)
+            .filter_map(|entry| {
+                let (address, size) = entry.split_at(bus.address_cells * 4);
+                let address = translate(&bus.maps, read_cells(address, bus.address_cells)?)?;
+                let size = match bus.size_cells {
+                    0 => 0,
+                    cells => read_cells(size, cells)?,
+                };
+
+                Some(Region { address: address as usize, size: size as usize })
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let device = Device {
+        name: node.name.to_string(),
+        compatible: node.compatible().map(|c| c.all().map(ToString::to_string).collect()).unwrap_or_default(),
+        phandle: u32_property(&node, "phandle").or_else(|| u32_property(&node, "linux,phandle")),
+        parent,
+        regions,
+        interrupt_parent,
+        // The first cell of each specifier is the source on every interrupt
+        // controller supported so far
+        interrupt_sources: node.interrupts().into_iter().flatten().collect(),
+        state: BindState::Unbound,
+        path,
+    };
+
+    let index = devices.len();
+    let path = device.path.clone();
+    devices.push(device);
+
+    let cell_sizes = (
+        u32_property(&node, "#address-cells").map_or(DEFAULT_ADDRESS_CELLS, |n| n as usize),
+        u32_property(&node, "#size-cells").map_or(DEFAULT_SIZE_CELLS, |n| n as usize),
+    );
+
+    // The root's `ranges` (if any) has nothing to map to
+    let mut maps = bus.maps.clone();
+    if parent.is_some() {
+        let value = node.property("ranges").map(|p| p.value);
+        maps.insert(0, parse_ranges(value, cell_sizes.0, bus.address_cells, cell_sizes.1));
+    }
+
+    let child_bus = BusContext { address_cells: cell_sizes.0, size_cells: cell_sizes.1, maps, interrupt_parent };
+    for child in node.children() {
+        let child_path = match path.as_str() {
+            "/" => alloc::format!("/{}", child.name),
+            _ => alloc::format!("{}/{}", path, child.name),
+        };
+
+        add_node(devices, child, child_path, Some(index), &child_bus);
+    }
+}
+
+/// Build the device graph from the device tree
+pub fn init(fdt: &Fdt<'_>) {
+    let root = match fdt.find_node("/") {
+        Some(root) => root,
+        None => return,
+    };
+
+    let bus = BusContext { address_cells: 0, size_cells: 0, maps: Vec::new(), interrupt_parent: None };
+    let mut devices = Vec::new();
+    add_node(&mut devices, root, String::from("/"), None, &bus);
+
+    log::debug!("Found {} device tree nodes", devices.len());
+    *DEVICES.write() = devices;
+}
+
+/// Match every unbound device against the registered drivers and probe them.
+/// Devices whose drivers defer are retried as long as some other device was
+/// bound in the previous pass.
+pub fn probe_all(fdt: &Fdt<'_>) {
+    let mut devices = DEVICES.write();
+
+    loop {
+        let mut progress = false;
+
+        for device in devices.iter_mut() {
+            if !matches!(device.state, BindState::Unbound | BindState::Deferred(_)) {
+                continue;
+            }
+
+            for driver in drivers::DRIVERS.iter().filter(|d| device.is_compatible((d.compatible)())) {
+                match (driver.probe)(fdt, device) {
+                    Ok(()) => {
+                        log::debug!("Bound {} to {}", driver.name, device.path);
+                        device.state = BindState::Bound(driver.name);
+                        progress = true;
+                        break;
+                    }
+                    Err(ProbeError::NotSupported) => continue,
+                    Err(ProbeError::Deferred) => {
+                        device.state = BindState::Deferred(driver.name);
+                        break;
+                    }
+                    Err(ProbeError::Failed(e)) => {
+                        log::warn!("Driver {} failed to probe {}: {}", driver.name, device.path, e);
+                        device.state = BindState::Failed(driver.name);
+                        break;
+                    }
+                }
+            }
+        }
+
+        if !progress {
+            break;
+        }
+    }
+
+    for device in devices.iter().filter(|d| matches!(d.state, BindState::Deferred(_))) {
+        log::warn!("Gave up waiting on dependencies of {}", device.path);
+    }
+}
+
+pub fn devices() -> ReadGuard<'static, Vec<Device>> {
+    DEVICES.read()
+}
+
+/// Find a device by its full path, or failing that the first device with the
+/// given node name
+pub fn find(name: &str) -> Option<Device> {
+    let devices = DEVICES.read();
+    let by_path = devices.iter().find(|d| d.path == name);
+
+    by_path.or_else(|| devices.iter().find(|d| d.name == name)).cloned()
+}
+
+/// Give `tid` ownership of a device, returning the device and whether this is
+/// the first time it's been claimed, in which case its interrupts still need
+/// to be routed
+pub fn claim(name: &str, tid: Tid) -> Result<(Device, bool), ClaimError> {
+    let device = find(name).ok_or(ClaimError::NotFound)?;
+    if let BindState::Bound(driver) = device.state {
+        return Err(ClaimError::Bound(driver));
+    }
+
+    if !device.regions.first().map_or(false, |region| region.size > 0) {
+        return Err(ClaimError::NoRegisters);
+    }
+
+    let mut claims = CLAIMS.write();
+    let first_claim = match claims.get(&device.path) {
+        Some(Some(owner)) => return Err(ClaimError::InUse(*owner)),
+        Some(None) => false,
+        None => true,
+    };
+
+    claims.insert(device.path.clone(), Some(tid));
+
+    Ok((device, first_claim))
+}
+
+/// The task which currently owns the device at `path`, if any
+pub fn owner(path: &str) -> Option<Tid> {
+    CLAIMS.read().get(path).copied().flatten()
+}
+
+/// Release every device owned by `tid`
+pub fn release(tid: Tid) {
+    for (path, owner) in CLAIMS.write().iter_mut().filter(|(_, owner)| **owner == Some(tid)) {
+        log::debug!("Releasing {} from task {}", path, tid.value());
+        *owner = None;
+    }
+}
+
+/// Give a claimed device to a different task
+pub fn reassign(path: &str, to: Tid) {
+    if let Some(owner) = CLAIMS.write().get_mut(path) {
+        *owner = Some(to);
+    }
+}
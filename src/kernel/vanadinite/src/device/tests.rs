@@ -0,0 +1,74 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+use vanadinite_macros::test;
+
+fn cells(cells: &[u32]) -> Vec<u8> {
+    cells.iter().flat_map(|cell| cell.to_be_bytes()).collect()
+}
+
+#[test]
+fn parse_empty_and_missing_ranges() {
+    assert_eq!(parse_ranges(Some(&[]), 1, 2, 1), AddressMap::Identity);
+    assert_eq!(parse_ranges(None, 1, 2, 1), AddressMap::Untranslatable);
+}
+
+#[test]
+fn translate_through_buses() {
+    // A 32-bit bus at 0x4000_0000 inside a 64-bit bus mapped at 0x1_0000_0000
+    let inner = parse_ranges(Some(&cells(&[0x0, 0x0, 0x4000_0000, 0x1000])), 1, 2, 1);
+    let outer = parse_ranges(Some(&cells(&[0x0, 0x4000_0000, 0x1, 0x4000_0000, 0x0, 0x1000_0000])), 2, 2, 2);
+    let maps = [inner, outer];
+
+    assert_eq!(translate(&maps, 0x10), Some(0x1_4000_0010));
+    assert_eq!(translate(&maps, 0x1000), None);
+    assert_eq!(translate(&[AddressMap::Identity, AddressMap::Untranslatable], 0x10), None);
+}
+
+#[test]
+fn boot_tree_graph() {
+    let devices = devices();
+    let root = devices.first().unwrap();
+    assert_eq!(root.path, "/");
+    assert_eq!(root.parent, None);
+
+    // The interrupt controller's phandle should be what every interrupt
+    // generating device refers to
+    for device in devices.iter().filter(|d| !d.interrupt_sources.is_empty()) {
+        let parent = device.interrupt_parent.unwrap();
+        assert!(devices.iter().any(|d| d.phandle == Some(parent)), "{}", device.path);
+        assert!(device.path.starts_with('/'));
+    }
+
+    // The QEMU `virt` UART sits on the memory mapped `soc` bus
+    let uart = devices.iter().find(|d| d.path == "/soc/serial@10000000").unwrap();
+    assert_eq!(uart.regions, [Region { address: 0x1000_0000, size: 0x100 }]);
+    assert_eq!(devices[uart.parent.unwrap()].path, "/soc");
+}
+
+#[test]
+fn claims_are_exclusive() {
+    let first = Tid::new(core::num::NonZeroUsize::new(1000).unwrap());
+    let second = Tid::new(core::num::NonZeroUsize::new(1001).unwrap());
+
+    // Claiming by node name resolves to the same device as its full path
+    let (device, first_claim) = claim("/soc/rtc@101000", first).unwrap();
+    assert!(first_claim);
+    assert_eq!(claim("rtc@101000", second).unwrap_err(), ClaimError::InUse(first));
+    assert_eq!(owner(&device.path), Some(first));
+
+    reassign(&device.path, second);
+    assert_eq!(owner(&device.path), Some(second));
+
+    release(second);
+    assert_eq!(owner(&device.path), None);
+    assert_eq!(claim(&device.path, first).map(|(_, first_claim)| first_claim), Ok(false));
+    release(first);
+
+    assert_eq!(claim("/not/a/device", first).unwrap_err(), ClaimError::NotFound);
+}
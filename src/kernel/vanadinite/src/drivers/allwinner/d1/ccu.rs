@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{drivers::CompatibleWith, platform::sensors::CpuClock};
+use volatile::Volatile;
+
+const HOSC_FREQ: u64 = 24_000_000;
+const PLL_PERI_800M_FREQ: u64 = 800_000_000;
+const PLL_PERI_1X_FREQ: u64 = 600_000_000;
+/// Lowest operating point of the C906 used by the vendor kernel
+const MIN_CPU_FREQ: u64 = 408_000_000;
+
+const RISCV_CLOCK_SOURCE_MASK: u32 = 0b111 << 24;
+const RISCV_CLOCK_SOURCE_PLL_PERI_1X: u32 = 0b100 << 24;
+const RISCV_CLOCK_SOURCE_PLL_CPU: u32 = 0b101 << 24;
+const PLL_CPU_LOCK_ENABLE: u32 = 1 << 29;
+const PLL_CPU_LOCKED: u32 = 1 << 28;
+
+volatile::register_block! {
+    /// Clock control unit on the Allwinner D1, only the registers needed for
+    /// controlling the CPU frequency and gating peripherals the kernel uses are
+    /// described
+    pub struct Ccu [0xD04] {
+        0x000 => pll_cpu: Volatile<u32>,
+        0x9FC => ths_bus_gating_reset: Volatile<u32>,
+        0xD00 => riscv_clock: Volatile<u32>,
+    }
+}
+
+impl Ccu {
+    /// Ungate the thermal sensor's bus clock and take it out of reset
+    pub fn enable_ths(&self) {
+        self.ths_bus_gating_reset().modify(|reg| reg | 1 << 16 | 1);
+    }
+
+    /// Frequency of `PLL_CPU` in Hz, or `None` if it's disabled
+    pub fn pll_cpu_frequency(&self) -> Option<u64> {
+        let pll = self.pll_cpu().read();
+        if pll & 1 << 31 == 0 {
+            return None;
+        }
+
+        let n = u64::from((pll >> 8) & 0xFF) + 1;
+        Some(HOSC_FREQ * n)
+    }
+
+    /// Frequency of the RISC-V core clock in Hz, or `None` if it's running
+    /// from a source whose rate isn't known
+    pub fn cpu_frequency(&self) -> Option<u64> {
+        let clock = self.riscv_clock().read();
+        let source = match (clock >> 24) & 0b111 {
+            0b000 => HOSC_FREQ,
+            0b011 => PLL_PERI_800M_FREQ,
+            0b100 => PLL_PERI_1X_FREQ,
+            0b101 => self.pll_cpu_frequency()?,
+            _ => return None,
+        };
+
+        let m = u64::from(clock & 0x1F) + 1;
+        Some(source / m)
+    }
+
+    /// Reprogram `PLL_CPU` as close to `hz` as it can get without going over,
+    /// returning the new frequency. The core is moved to `PLL_PERI(1X)` while
+    /// the PLL relocks, and `None` is returned without changing anything if it
+    /// wasn't running from `PLL_CPU` to begin with.
+    pub fn set_cpu_frequency(&self, hz: u64) -> Option<u64> {
+        let clock = self.riscv_clock().read();
+        if clock & RISCV_CLOCK_SOURCE_MASK != RISCV_CLOCK_SOURCE_PLL_CPU {
+            return None;
+        }
+
+        let n = (hz / HOSC_FREQ).clamp(1, 256);
+
+        self.riscv_clock().write((clock & !RISCV_CLOCK_SOURCE_MASK) | RISCV_CLOCK_SOURCE_PLL_PERI_1X);
+
+        let pll = self.pll_cpu().read() & !(0xFF << 8) & !PLL_CPU_LOCK_ENABLE;
+        self.pll_cpu().write(pll | ((n - 1) as u32) << 8);
+        self.pll_cpu().write(pll | ((n - 1) as u32) << 8 | PLL_CPU_LOCK_ENABLE);
+        while self.pll_cpu().read() & PLL_CPU_LOCKED == 0 {}
+
+        // Run the core undivided from the PLL again
+        self.riscv_clock().write((clock & !RISCV_CLOCK_SOURCE_MASK & !0x1F) | RISCV_CLOCK_SOURCE_PLL_CPU);
+
+        Some(HOSC_FREQ * n)
+    }
+}
+
+impl CpuClock for Ccu {
+    fn cpu_frequency(&self) -> Option<u64> {
+        self.cpu_frequency()
+    }
+
+    fn min_cpu_frequency(&self) -> Option<u64> {
+        Some(MIN_CPU_FREQ)
+    }
+
+    fn set_cpu_frequency(&self, hz: u64) -> Option<u64> {
+        self.set_cpu_frequency(hz)
+    }
+}
+
+impl CompatibleWith for Ccu {
+    fn compatible_with() -> &'static [&'static str] {
+        &["allwinner,sun20i-d1-ccu"]
+    }
+}
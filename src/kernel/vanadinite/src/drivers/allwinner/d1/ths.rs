@@ -0,0 +1,66 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{drivers::CompatibleWith, platform::sensors::TemperatureSensor};
+use volatile::{Read, Volatile};
+
+const TEMPERATURE_OFFSET: i32 = 188552;
+const TEMPERATURE_SCALE: i32 = 673;
+
+volatile::register_block! {
+    /// Thermal sensor controller on the Allwinner D1, which has a single sensor
+    /// next to the CPU
+    pub struct Ths [0xC4] {
+        0x00 => control: Volatile<u32>,
+        0x04 => enable: Volatile<u32>,
+        0x08 => period_control: Volatile<u32>,
+        0x10 => data_interrupt_control: Volatile<u32>,
+        0x30 => filter_control: Volatile<u32>,
+        0xC0 => data: Volatile<u32, Read>,
+    }
+}
+
+impl Ths {
+    /// Start periodic sampling, the bus clock must already be ungated with
+    /// [`super::ccu::Ccu::enable_ths`]
+    pub fn init(&self) {
+        // Acquire time of 20us with the 24 MHz oscillator: 20us * 24 MHz - 1
+        self.control().write(479);
+        // Enable filtering, averaging over 4 samples
+        self.filter_control().write(1 << 2 | 1);
+        // Sample period of 250ms: 0.25s * 24 MHz / 4096 / 4 samples - 1
+        self.period_control().write(365 << 12);
+        self.enable().write(1);
+        self.data_interrupt_control().write(1);
+    }
+
+    /// The most recent temperature reading in millidegrees Celsius, or `None`
+    /// if the first sample hasn't completed yet. Readings aren't calibrated
+    /// against the factory values in the SID, so may be off by a few degrees.
+    pub fn temperature(&self) -> Option<i32> {
+        match self.data().read() & 0xFFF {
+            0 => None,
+            raw => Some(TEMPERATURE_OFFSET - (raw as i32 * TEMPERATURE_SCALE / 10)),
+        }
+    }
+}
+
+impl TemperatureSensor for Ths {
+    fn name(&self) -> &'static str {
+        "cpu"
+    }
+
+    fn temperature(&self) -> Option<i32> {
+        self.temperature()
+    }
+}
+
+impl CompatibleWith for Ths {
+    fn compatible_with() -> &'static [&'static str] {
+        &["allwinner,sun20i-d1-ths"]
+    }
+}
@@ -0,0 +1,140 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! RISC-V Advanced Platform-Level Interrupt Controller, used in direct delivery
+//! mode so that it behaves like a PLIC. Unlike the PLIC, each source is routed
+//! to exactly one hart, and lower priority numbers are more urgent. Priorities
+//! and thresholds are translated from the PLIC's `0..=7` range so callers
+//! don't need to care which controller they're talking to.
+
+use super::plic::Plic;
+use crate::drivers::CompatibleWith;
+use volatile::{Read, ReadWrite, Volatile, Write};
+
+const DOMAINCFG_INTERRUPT_ENABLE: u32 = 1 << 8;
+const SOURCECFG_INACTIVE: u32 = 0;
+// FIXME: the trigger type is in the second cell of the device's `interrupts`
+// property, but everything on the platforms we support is level triggered
+const SOURCECFG_LEVEL_HIGH: u32 = 6;
+const TARGET_HART_SHIFT: u32 = 18;
+const TARGET_PRIORITY_MASK: u32 = 0xFF;
+
+volatile::register_block! {
+    pub struct Aplic [0x8000] {
+        0x0000 => domaincfg: Volatile<u32, ReadWrite>,
+        0x0004 => sourcecfg: [Volatile<u32, ReadWrite>; 1023],
+        0x1C00 => setip: [Volatile<u32, Read>; 32],
+        0x1E00 => setie: [Volatile<u32, Read>; 32],
+        0x1EDC => setienum: Volatile<u32, Write>,
+        0x1FDC => clrienum: Volatile<u32, Write>,
+        0x3004 => target: [Volatile<u32, ReadWrite>; 1023],
+        0x4000 => idcs: [Idc; 512],
+    }
+}
+
+volatile::register_block! {
+    struct Idc [0x20] {
+        0x00 => idelivery: Volatile<u32, ReadWrite>,
+        0x08 => ithreshold: Volatile<u32, ReadWrite>,
+        0x1C => claimi: Volatile<u32, Read>,
+    }
+}
+
+impl Aplic {
+    /// Disable every source and set up direct delivery to the given harts. The
+    /// IDC of a hart is assumed to be at the same index as its hart ID.
+    pub fn init(&self, n_sources: usize, harts: impl Iterator<Item = usize>) {
+        self.domaincfg().write(0);
+
+        for source in 1..=n_sources {
+            self.clrienum().write(source as u32);
+            self.sourcecfg()[source - 1].write(SOURCECFG_INACTIVE);
+        }
+
+        for hart in harts {
+            let idc = &self.idcs()[hart];
+            idc.ithreshold().write(0);
+            idc.idelivery().write(1);
+        }
+
+        self.domaincfg().write(DOMAINCFG_INTERRUPT_ENABLE);
+    }
+
+    /// Enable the source, routing it to `hart`
+    pub fn enable_interrupt(&self, hart: usize, source: usize) {
+        log::debug!("Enabling interrupt {} on hart {}", source, hart);
+        self.sourcecfg()[source - 1].write(SOURCECFG_LEVEL_HIGH);
+        self.target()[source - 1]
+            .modify(|target| ((hart as u32) << TARGET_HART_SHIFT) | (target & TARGET_PRIORITY_MASK).max(1));
+        self.setienum().write(source as u32);
+    }
+
+    pub fn disable_interrupt(&self, source: usize) {
+        log::debug!("Disabling interrupt {}", source);
+        self.clrienum().write(source as u32);
+    }
+
+    pub fn set_interrupt_priority(&self, source: usize, priority: usize) {
+        let priority = priority.min(Plic::max_priority());
+        log::debug!("Setting priority {} for source {}", priority, source);
+
+        let aplic_priority = (Plic::max_priority() + 1 - priority) as u32;
+        self.target()[source - 1].modify(|target| (target & !TARGET_PRIORITY_MASK) | aplic_priority);
+    }
+
+    pub fn set_hart_threshold(&self, hart: usize, threshold: usize) {
+        let threshold = threshold.min(Plic::max_priority());
+        log::debug!("Setting threshold {} for hart {}", threshold, hart);
+
+        // Sources with a priority number lower than the threshold are
+        // delivered, with zero meaning no threshold
+        let aplic_threshold = match threshold {
+            0 => 0,
+            threshold => (Plic::max_priority() + 1 - threshold) as u32,
+        };
+        self.idcs()[hart].ithreshold().write(aplic_threshold);
+    }
+
+    pub fn is_pending(&self, source: usize) -> bool {
+        (self.setip()[source / 32].read() >> (source % 32)) & 1 == 1
+    }
+
+    pub fn is_enabled(&self, hart: usize, source: usize) -> bool {
+        let enabled = (self.setie()[source / 32].read() >> (source % 32)) & 1 == 1;
+        enabled && (self.target()[source - 1].read() >> TARGET_HART_SHIFT) as usize == hart
+    }
+
+    pub fn interrupt_priority(&self, source: usize) -> usize {
+        match (self.target()[source - 1].read() & TARGET_PRIORITY_MASK) as usize {
+            0 => 0,
+            n => (Plic::max_priority() + 1).saturating_sub(n),
+        }
+    }
+
+    pub fn hart_threshold(&self, hart: usize) -> usize {
+        match self.idcs()[hart].ithreshold().read() as usize {
+            0 => 0,
+            n => (Plic::max_priority() + 1).saturating_sub(n),
+        }
+    }
+
+    /// Claim the highest priority pending interrupt for `hart`. In direct mode
+    /// claiming also clears the pending bit, so there's nothing to do to
+    /// complete it.
+    pub fn claim(&self, hart: usize) -> Option<usize> {
+        match (self.idcs()[hart].claimi().read() >> 16) & 0x3FF {
+            0 => None,
+            source => Some(source as usize),
+        }
+    }
+}
+
+impl CompatibleWith for Aplic {
+    fn compatible_with() -> &'static [&'static str] {
+        &["riscv,aplic"]
+    }
+}
@@ -0,0 +1,49 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{drivers::CompatibleWith, platform::clock::ClockSource};
+use volatile::{Read, Volatile};
+
+volatile::register_block! {
+    /// Real time clock on the QEMU `virt` platform, counting nanoseconds since
+    /// the Unix epoch
+    pub struct GoldfishRtc [0x08] {
+        0x00 => time_low: Volatile<u32, Read>,
+        0x04 => time_high: Volatile<u32, Read>,
+    }
+}
+
+impl GoldfishRtc {
+    pub fn nanoseconds(&self) -> u64 {
+        // Reading the low half latches the high half, so they have to be read
+        // in this order
+        let low = self.time_low().read();
+        let high = self.time_high().read();
+
+        (u64::from(high) << 32) | u64::from(low)
+    }
+}
+
+impl ClockSource for GoldfishRtc {
+    fn name(&self) -> &'static str {
+        "goldfish-rtc"
+    }
+
+    fn ticks(&self) -> u64 {
+        self.nanoseconds()
+    }
+
+    fn frequency(&self) -> u64 {
+        1_000_000_000
+    }
+}
+
+impl CompatibleWith for GoldfishRtc {
+    fn compatible_with() -> &'static [&'static str] {
+        &["google,goldfish-rtc"]
+    }
+}
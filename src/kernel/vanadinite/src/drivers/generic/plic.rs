@@ -9,40 +9,40 @@ use crate::drivers::CompatibleWith;
 pub use registers::InterruptClaim;
 use volatile::{Read, ReadWrite, Volatile};
 
-#[repr(C)]
-pub struct Plic {
-    source_priorities: [registers::Priority; 1024],
-    interrupt_pending: registers::InterruptPending,
-    _padding1: [u8; 3968],
-    interrupt_enable: [registers::Context<registers::InterruptEnable>; 15872],
-    _padding2: [u8; 57344],
-    threshold_and_claim: [registers::Context<registers::ThresholdAndClaim>; 15872],
-    _padding3: [u8; 8184],
+volatile::register_block! {
+    pub struct Plic [0x400_0000] {
+        0x00_0000 => source_priorities: [registers::Priority; 1024],
+        0x00_1000 => interrupt_pending: registers::InterruptPending,
+        0x00_2000 => interrupt_enable: [registers::Context<registers::InterruptEnable>; 15872],
+        0x20_0000 => threshold_and_claim: [registers::Context<registers::ThresholdAndClaim>; 15872],
+    }
 }
 
 impl Plic {
     pub fn init(&self, max_interrupts: usize, contexts: impl Iterator<Item = usize>) {
         for i in 1..max_interrupts {
-            self.source_priorities[i].set(0);
+            self.source_priorities()[i].set(0);
         }
 
         for context in contexts {
             for i in 0..max_interrupts {
-                self.interrupt_enable[context].disable(i);
+                self.interrupt_enable()[context].disable(i);
             }
 
-            self.threshold_and_claim[context].priority_threshold.set(0);
+            self.threshold_and_claim()[context].priority_threshold().set(0);
         }
     }
 
+    /// Enabling and disabling sources modifies a register shared with other
+    /// sources, so callers must make sure they aren't racing with another hart
     pub fn enable_interrupt(&self, context: usize, source: usize) {
         log::debug!("Enabling interrupt {}", source);
-        self.interrupt_enable[context].enable(source);
+        self.interrupt_enable()[context].enable(source);
     }
 
     pub fn disable_interrupt(&self, context: usize, source: usize) {
         log::debug!("Disabling interrupt {}", source);
-        self.interrupt_enable[context].disable(source);
+        self.interrupt_enable()[context].disable(source);
     }
 
     pub fn set_interrupt_priority(&self, source: usize, mut priority: usize) {
@@ -52,7 +52,7 @@ impl Plic {
         }
 
         log::debug!("Setting priority {} for source {}", priority, source);
-        self.source_priorities[source].set(priority as u32)
+        self.source_priorities()[source].set(priority as u32)
     }
 
     pub fn set_context_threshold(&self, context: usize, mut threshold: usize) {
@@ -62,19 +62,34 @@ impl Plic {
         }
 
         log::debug!("Setting threshold {} for context {}", threshold, context);
-        self.threshold_and_claim[context].priority_threshold.set(threshold as u32)
+        self.threshold_and_claim()[context].priority_threshold().set(threshold as u32)
     }
 
     pub fn is_pending(&self, source: usize) -> bool {
-        self.interrupt_pending.is_pending(source)
+        self.interrupt_pending().is_pending(source)
+    }
+
+    pub fn is_enabled(&self, context: usize, source: usize) -> bool {
+        self.interrupt_enable()[context].is_enabled(source)
+    }
+
+    pub fn interrupt_priority(&self, source: usize) -> usize {
+        self.source_priorities()[source].get() as usize
+    }
+
+    pub fn context_threshold(&self, context: usize) -> usize {
+        self.threshold_and_claim()[context].priority_threshold().get() as usize
     }
 
+    /// Claiming and completing only touch the registers of the given context,
+    /// which belongs to a single hart, so they're safe to do without any
+    /// synchronization as long as it's only done from that hart
     pub fn claim(&self, context: usize) -> Option<registers::InterruptClaim<'_>> {
-        self.threshold_and_claim[context].claim_complete.claim()
+        self.threshold_and_claim()[context].claim_complete().claim()
     }
 
     pub fn complete(&self, context: usize, interrupt_id: usize) {
-        self.threshold_and_claim[context].claim_complete.complete(interrupt_id);
+        self.threshold_and_claim()[context].claim_complete().complete(interrupt_id);
     }
 
     pub const fn max_priority() -> usize {
@@ -107,6 +122,10 @@ mod registers {
     pub struct Priority(Volatile<u32, ReadWrite>);
 
     impl Priority {
+        pub fn get(&self) -> u32 {
+            self.0.read()
+        }
+
         pub fn set(&self, priority: u32) {
             self.0.write(priority);
         }
@@ -131,15 +150,18 @@ mod registers {
         pub fn enable(&self, interrupt_id: usize) {
             let (u32_index, bit_index) = (interrupt_id / 32, interrupt_id % 32);
 
-            let val = self.0[u32_index].read() | (1 << bit_index);
-            self.0[u32_index].write(val);
+            self.0[u32_index].modify(|val| val | (1 << bit_index));
         }
 
         pub fn disable(&self, interrupt_id: usize) {
             let (u32_index, bit_index) = (interrupt_id / 32, interrupt_id % 32);
 
-            let val = self.0[u32_index].read() & !(1 << bit_index);
-            self.0[u32_index].write(val);
+            self.0[u32_index].modify(|val| val & !(1 << bit_index));
+        }
+
+        pub fn is_enabled(&self, interrupt_id: usize) -> bool {
+            let (u32_index, bit_index) = (interrupt_id / 32, interrupt_id % 32);
+            (self.0[u32_index].read() >> bit_index) & 1 == 1
         }
     }
 
@@ -148,6 +170,10 @@ mod registers {
     pub struct PriorityThreshold(Volatile<u32, ReadWrite>);
 
     impl PriorityThreshold {
+        pub fn get(&self) -> u32 {
+            self.0.read()
+        }
+
         pub fn set(&self, priority: u32) {
             self.0.write(priority);
         }
@@ -191,11 +217,11 @@ mod registers {
         }
     }
 
-    #[repr(C)]
-    pub struct ThresholdAndClaim {
-        pub priority_threshold: PriorityThreshold,
-        pub claim_complete: ClaimComplete,
-        _reserved: [u8; 4088],
+    volatile::register_block! {
+        pub struct ThresholdAndClaim [0x1000] {
+            0x0 => pub priority_threshold: PriorityThreshold,
+            0x4 => pub claim_complete: ClaimComplete,
+        }
     }
 }
 
@@ -0,0 +1,332 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! SD cards driven in SPI mode over any [`SpiBus`]. SPI mode is slower than
+//! the native SD bus, but every card supports it and it only needs a plain SPI
+//! controller.
+
+use crate::{
+    drivers::SpiBus,
+    io::block_device::{BlockDevice, BLOCK_SIZE},
+};
+
+/// Cards must be initialized with a clock between 100 and 400 KHz
+const INIT_FREQUENCY: u64 = 400_000;
+/// Default speed mode, which all cards support
+const TRANSFER_FREQUENCY: u64 = 25_000_000;
+
+const GO_IDLE_STATE: u8 = 0;
+const SEND_IF_COND: u8 = 8;
+const SEND_CSD: u8 = 9;
+const SET_BLOCKLEN: u8 = 16;
+const READ_SINGLE_BLOCK: u8 = 17;
+const WRITE_BLOCK: u8 = 24;
+const APP_CMD: u8 = 55;
+const READ_OCR: u8 = 58;
+const SD_SEND_OP_COND: u8 = 41;
+
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const START_BLOCK_TOKEN: u8 = 0xFE;
+const DATA_ACCEPTED: u8 = 0x05;
+/// 2.7-3.6V and the check pattern echoed back by `SEND_IF_COND`
+const IF_COND_ARGUMENT: u32 = 0x1AA;
+/// Host supports high capacity cards, for `SD_SEND_OP_COND`
+const HCS: u32 = 1 << 30;
+/// Card is high capacity and uses block addressing, from the OCR
+const CCS: u32 = 1 << 30;
+
+/// Number of bytes to poll for a response before giving up, responses arrive
+/// within 8 bytes of the command but data tokens and busy signals can take
+/// much longer
+const RESPONSE_POLLS: usize = 8;
+const TOKEN_POLLS: usize = 100_000;
+const INIT_ATTEMPTS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdError {
+    /// The card never answered a command
+    NoResponse(u8),
+    /// The card answered a command with error bits set in its R1 response
+    CommandFailed { command: u8, response: u8 },
+    /// A data block never started, or the card rejected one
+    DataError(u8),
+    /// The card never left the idle state
+    InitTimeout,
+    /// The card doesn't support our voltage range, or echoed the wrong check
+    /// pattern
+    UnsupportedCard,
+    BlockOutOfRange(u64),
+}
+
+pub struct SdCard<S: SpiBus> {
+    bus: S,
+    /// High capacity cards are addressed by block instead of by byte
+    block_addressed: bool,
+    block_count: u64,
+}
+
+impl<S: SpiBus> SdCard<S> {
+    /// Run through the SPI mode initialization sequence and read the card's
+    /// capacity
+    pub fn new(mut bus: S) -> Result<Self, SdError> {
+        bus.set_frequency(INIT_FREQUENCY);
+        bus.set_chip_select(false);
+
+        // The card needs at least 74 clock cycles with chip select deasserted
+        // before it will accept commands
+        for _ in 0..10 {
+            bus.transfer(0xFF);
+        }
+
+        let mut card = Self { bus, block_addressed: false, block_count: 0 };
+
+        match card.command(GO_IDLE_STATE, 0)? {
+            R1_IDLE => {}
+            response => return Err(SdError::CommandFailed { command: GO_IDLE_STATE, response }),
+        }
+
+        // Version 1 cards don't know about `SEND_IF_COND`, and are never high
+        // capacity
+        let version2 = match card.command(SEND_IF_COND, IF_COND_ARGUMENT)? {
+            response if response & R1_ILLEGAL_COMMAND != 0 => false,
+            R1_IDLE => {
+                let r7 = card.read_u32();
+                if r7 & 0xFFF != IF_COND_ARGUMENT {
+                    card.bus.set_chip_select(false);
+                    return Err(SdError::UnsupportedCard);
+                }
+
+                true
+            }
+            response => return Err(SdError::CommandFailed { command: SEND_IF_COND, response }),
+        };
+        card.bus.set_chip_select(false);
+
+        let argument = if version2 { HCS } else { 0 };
+        let mut attempts = 0;
+        while card.app_command(SD_SEND_OP_COND, argument)? != 0 {
+            attempts += 1;
+            if attempts == INIT_ATTEMPTS {
+                return Err(SdError::InitTimeout);
+            }
+        }
+
+        if version2 {
+            match card.command(READ_OCR, 0)? {
+                0 => card.block_addressed = card.read_u32() & CCS != 0,
+                response => return Err(SdError::CommandFailed { command: READ_OCR, response }),
+            }
+            card.bus.set_chip_select(false);
+        }
+
+        // Byte addressed cards can have other block lengths, high capacity
+        // cards are always 512 bytes
+        if !card.block_addressed {
+            match card.command(SET_BLOCKLEN, BLOCK_SIZE as u32)? {
+                0 => card.bus.set_chip_select(false),
+                response => return Err(SdError::CommandFailed { command: SET_BLOCKLEN, response }),
+            }
+        }
+
+        card.bus.set_frequency(TRANSFER_FREQUENCY);
+        card.block_count = card.read_capacity()?;
+
+        Ok(card)
+    }
+
+    /// Read the CSD register to find the number of 512 byte blocks on the card
+    fn read_capacity(&mut self) -> Result<u64, SdError> {
+        let mut csd = [0; 16];
+        match self.command(SEND_CSD, 0)? {
+            0 => self.read_data(&mut csd)?,
+            response => return Err(SdError::CommandFailed { command: SEND_CSD, response }),
+        }
+
+        let bits = u128::from_be_bytes(csd);
+        let field = |start: u32, len: u32| ((bits >> start) & ((1 << len) - 1)) as u64;
+
+        match field(126, 2) {
+            // CSD version 1: capacity is (C_SIZE + 1) * 2^(C_SIZE_MULT + 2)
+            // blocks of 2^READ_BL_LEN bytes
+            0 => {
+                let c_size = field(62, 12);
+                let c_size_mult = field(47, 3);
+                let read_bl_len = field(80, 4);
+
+                Ok(((c_size + 1) << (c_size_mult + 2 + read_bl_len)) / BLOCK_SIZE as u64)
+            }
+            // CSD version 2: capacity is (C_SIZE + 1) * 512 KiB
+            _ => Ok((field(48, 22) + 1) * 1024),
+        }
+    }
+
+    /// Send a command and return its R1 response, leaving chip select asserted
+    /// so the rest of the response can be read
+    fn command(&mut self, command: u8, argument: u32) -> Result<u8, SdError> {
+        self.bus.set_chip_select(true);
+        // Give the card a chance to finish anything it was doing
+        self.bus.transfer(0xFF);
+
+        let [a0, a1, a2, a3] = argument.to_be_bytes();
+        let packet = [0x40 | command, a0, a1, a2, a3];
+        for byte in packet {
+            self.bus.transfer(byte);
+        }
+
+        // The CRC is only checked for commands sent before switching into SPI
+        // mode
+        self.bus.transfer((crc7(&packet) << 1) | 1);
+
+        for _ in 0..RESPONSE_POLLS {
+            let response = self.bus.transfer(0xFF);
+            if response & 0x80 == 0 {
+                return Ok(response);
+            }
+        }
+
+        self.bus.set_chip_select(false);
+        Err(SdError::NoResponse(command))
+    }
+
+    fn app_command(&mut self, command: u8, argument: u32) -> Result<u8, SdError> {
+        match self.command(APP_CMD, 0)? {
+            response if response & !R1_IDLE != 0 => {
+                self.bus.set_chip_select(false);
+                Err(SdError::CommandFailed { command: APP_CMD, response })
+            }
+            _ => {
+                self.bus.set_chip_select(false);
+                let response = self.command(command, argument);
+                self.bus.set_chip_select(false);
+                response
+            }
+        }
+    }
+
+    fn read_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        for byte in &mut bytes {
+            *byte = self.bus.transfer(0xFF);
+        }
+
+        u32::from_be_bytes(bytes)
+    }
+
+    /// Wait for a data block to start, then read it into `buffer` and
+    /// deassert chip select
+    fn read_data(&mut self, buffer: &mut [u8]) -> Result<(), SdError> {
+        let mut token = 0xFF;
+        for _ in 0..TOKEN_POLLS {
+            token = self.bus.transfer(0xFF);
+            if token != 0xFF {
+                break;
+            }
+        }
+
+        if token != START_BLOCK_TOKEN {
+            self.bus.set_chip_select(false);
+            return Err(SdError::DataError(token));
+        }
+
+        for byte in buffer {
+            *byte = self.bus.transfer(0xFF);
+        }
+
+        // Ignore the CRC
+        self.bus.transfer(0xFF);
+        self.bus.transfer(0xFF);
+        self.bus.set_chip_select(false);
+
+        Ok(())
+    }
+
+    fn address(&self, block: u64) -> Result<u32, SdError> {
+        if block >= self.block_count {
+            return Err(SdError::BlockOutOfRange(block));
+        }
+
+        match self.block_addressed {
+            true => Ok(block as u32),
+            false => Ok((block * BLOCK_SIZE as u64) as u32),
+        }
+    }
+}
+
+impl<S: SpiBus> BlockDevice for SdCard<S> {
+    type Error = SdError;
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_block(&mut self, block: u64, buffer: &mut [u8; BLOCK_SIZE]) -> Result<(), Self::Error> {
+        let address = self.address(block)?;
+        match self.command(READ_SINGLE_BLOCK, address)? {
+            0 => self.read_data(buffer),
+            response => {
+                self.bus.set_chip_select(false);
+                Err(SdError::CommandFailed { command: READ_SINGLE_BLOCK, response })
+            }
+        }
+    }
+
+    fn write_block(&mut self, block: u64, buffer: &[u8; BLOCK_SIZE]) -> Result<(), Self::Error> {
+        let address = self.address(block)?;
+        if let response @ 1.. = self.command(WRITE_BLOCK, address)? {
+            self.bus.set_chip_select(false);
+            return Err(SdError::CommandFailed { command: WRITE_BLOCK, response });
+        }
+
+        self.bus.transfer(0xFF);
+        self.bus.transfer(START_BLOCK_TOKEN);
+        for &byte in buffer {
+            self.bus.transfer(byte);
+        }
+
+        // Dummy CRC
+        self.bus.transfer(0xFF);
+        self.bus.transfer(0xFF);
+
+        let response = self.bus.transfer(0xFF) & 0x1F;
+        if response != DATA_ACCEPTED {
+            self.bus.set_chip_select(false);
+            return Err(SdError::DataError(response));
+        }
+
+        // The card holds the data line low while it's programming the block
+        let mut busy = true;
+        for _ in 0..TOKEN_POLLS {
+            if self.bus.transfer(0xFF) != 0 {
+                busy = false;
+                break;
+            }
+        }
+
+        self.bus.set_chip_select(false);
+        match busy {
+            true => Err(SdError::DataError(0)),
+            false => Ok(()),
+        }
+    }
+}
+
+/// CRC7 with polynomial `x^7 + x^3 + 1`, used for command packets
+fn crc7(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        for bit in (0..8).rev() {
+            let feedback = ((crc >> 6) ^ (byte >> bit)) & 1;
+            crc = (crc << 1) & 0x7F;
+            if feedback == 1 {
+                crc ^= 0x09;
+            }
+        }
+    }
+
+    crc
+}
@@ -5,17 +5,36 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod allwinner {
+    pub mod d1 {
+        pub mod ccu;
+        pub mod ths;
+    }
+}
+
 pub mod sifive {
     pub mod fu540_c000 {
+        pub mod spi;
         pub mod uart;
     }
+
+    pub mod prci;
 }
 
 pub mod generic {
+    pub mod aplic;
+    pub mod goldfish_rtc;
     pub mod plic;
+    pub mod sdcard;
     pub mod uart16550;
 }
 
+pub mod virtio {
+    pub mod console;
+    pub mod mmio;
+    pub mod queue;
+}
+
 pub trait CompatibleWith {
     fn compatible_with() -> &'static [&'static str];
 }
@@ -23,3 +42,14 @@ pub trait CompatibleWith {
 pub trait InterruptServicable {
     fn isr(source: usize, private: usize) -> Result<(), &'static str>;
 }
+
+/// A SPI controller with a single device attached
+pub trait SpiBus {
+    /// Send `byte` to the device while receiving the byte clocked in at the
+    /// same time
+    fn transfer(&mut self, byte: u8) -> u8;
+    fn set_chip_select(&mut self, asserted: bool);
+    /// Set the serial clock as close to `hz` as possible without going over,
+    /// returning the resulting frequency
+    fn set_frequency(&mut self, hz: u64) -> u64;
+}
@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::drivers::{CompatibleWith, SpiBus};
+use volatile::Volatile;
+
+const FIFO_FULL: u32 = 1 << 31;
+const FIFO_EMPTY: u32 = 1 << 31;
+
+/// Chip select is driven according to `csdef` and only changes when told to
+const CSMODE_HOLD: u32 = 2;
+/// Single lane, MSB first, full duplex, 8 bit frames
+const FMT_SINGLE_MSB_8BIT: u32 = 8 << 16;
+
+volatile::register_block! {
+    /// SPI controller found on the FU540 and FU740, the FU540 has three of
+    /// them: one for the boot flash and two general purpose ones, the second of
+    /// which is wired to the SD card slot on the HiFive Unleashed and Unmatched
+    pub struct SifiveSpi [0x78] {
+        0x00 => serial_clock_divisor: Volatile<u32>,
+        0x04 => serial_clock_mode: Volatile<u32>,
+        0x10 => chip_select_id: Volatile<u32>,
+        0x14 => chip_select_default: Volatile<u32>,
+        0x18 => chip_select_mode: Volatile<u32>,
+        0x40 => frame_format: Volatile<u32>,
+        0x48 => tx_data: Volatile<u32>,
+        0x4C => rx_data: Volatile<u32>,
+        0x60 => flash_control: Volatile<u32>,
+        0x70 => interrupt_enable: Volatile<u32>,
+    }
+}
+
+impl SifiveSpi {
+    /// Put the controller into plain SPI mode 0 with chip select `cs` under
+    /// manual control, and drain anything left in the receive FIFO
+    pub fn init(&self, cs: u32) {
+        // Memory-mapped flash mode would take over the controller entirely
+        self.flash_control().write(0);
+        self.interrupt_enable().write(0);
+        self.serial_clock_mode().write(0);
+        self.frame_format().write(FMT_SINGLE_MSB_8BIT);
+        self.chip_select_id().write(cs);
+        self.chip_select_default().write(u32::MAX);
+        self.chip_select_mode().write(CSMODE_HOLD);
+
+        while self.rx_data().read() & FIFO_EMPTY == 0 {}
+    }
+
+    /// Set the serial clock as close to `hz` as possible without going over,
+    /// given the frequency of the controller's input clock. The serial clock
+    /// runs at `input_hz / (2 * (divisor + 1))`.
+    pub fn set_frequency(&self, input_hz: u64, hz: u64) -> u64 {
+        let divided = 2 * hz.max(1);
+        let divisor = ((input_hz + divided - 1) / divided).saturating_sub(1).min(0xFFF);
+        self.serial_clock_divisor().write(divisor as u32);
+
+        input_hz / (2 * (divisor + 1))
+    }
+
+    /// Drive the chip select selected in [`SifiveSpi::init`], the line is
+    /// active low so asserting it pulls it to zero
+    pub fn set_chip_select(&self, asserted: bool) {
+        let cs = self.chip_select_id().read();
+        self.chip_select_default().modify(|val| match asserted {
+            true => val & !(1 << cs),
+            false => val | (1 << cs),
+        });
+    }
+
+    /// Send `byte` while receiving the byte clocked in at the same time
+    pub fn transfer(&self, byte: u8) -> u8 {
+        while self.tx_data().read() & FIFO_FULL != 0 {}
+        self.tx_data().write(byte as u32);
+
+        loop {
+            let read = self.rx_data().read();
+            if read & FIFO_EMPTY == 0 {
+                break read as u8;
+            }
+        }
+    }
+}
+
+impl CompatibleWith for SifiveSpi {
+    fn compatible_with() -> &'static [&'static str] {
+        &["sifive,spi0", "sifive,fu540-c000-spi", "sifive,fu740-c000-spi"]
+    }
+}
+
+/// A [`SifiveSpi`] along with the rate of its input clock, which is only known
+/// from the clock controller
+pub struct SifiveSpiBus {
+    pub spi: &'static SifiveSpi,
+    pub input_hz: u64,
+}
+
+impl SpiBus for SifiveSpiBus {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.spi.transfer(byte)
+    }
+
+    fn set_chip_select(&mut self, asserted: bool) {
+        self.spi.set_chip_select(asserted);
+    }
+
+    fn set_frequency(&mut self, hz: u64) -> u64 {
+        self.spi.set_frequency(self.input_hz, hz)
+    }
+}
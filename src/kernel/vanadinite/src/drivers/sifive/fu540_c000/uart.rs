@@ -7,49 +7,55 @@
 
 use crate::{drivers::CompatibleWith, io::ConsoleDevice};
 
-#[derive(Debug)]
-#[repr(C)]
-pub struct SifiveUart {
-    tx_data: registers::TxData,
-    rx_data: registers::RxData,
-    tx_control: registers::TxCtrl,
-    rx_control: registers::RxCtrl,
-    interrupt_enable: registers::InterruptEnable,
-    interrupt_pending: registers::InterruptPending,
-    baud_rate_divisor: registers::BaudDivisor,
+volatile::register_block! {
+    pub struct SifiveUart [0x1C] {
+        0x00 => tx_data: registers::TxData,
+        0x04 => rx_data: registers::RxData,
+        0x08 => tx_control: registers::TxCtrl,
+        0x0C => rx_control: registers::RxCtrl,
+        0x10 => interrupt_enable: registers::InterruptEnable,
+        0x14 => interrupt_pending: registers::InterruptPending,
+        0x18 => baud_rate_divisor: registers::BaudDivisor,
+    }
 }
 
 impl SifiveUart {
     pub fn init(&self) {
         // Enable receive
-        self.rx_control.rx_enable(true);
+        self.rx_control().rx_enable(true);
         // Enable transmit
-        self.tx_control.tx_enable(true);
+        self.tx_control().tx_enable(true);
 
-        self.tx_control.extra_stop_bit(false);
-        self.rx_control.watermark_level(1);
+        self.tx_control().extra_stop_bit(false);
+        // Interrupt once the transmit FIFO is empty
+        self.tx_control().watermark_level(1);
+        self.rx_control().watermark_level(1);
 
         // Set interrupt enables
-        self.interrupt_enable.rx_watermark_enable(true);
-        self.interrupt_enable.tx_watermark_enable(false);
+        self.interrupt_enable().rx_watermark_enable(true);
+        self.interrupt_enable().tx_watermark_enable(false);
 
         // Set baud rate to 31250 Hz
-        self.baud_rate_divisor.divisor(16000);
+        self.baud_rate_divisor().divisor(16000);
     }
 
     pub fn read(&self) -> u8 {
         loop {
-            match self.rx_data.try_read() {
+            match self.rx_data().try_read() {
                 Some(c) => break c,
                 None => continue,
             }
         }
     }
 
+    pub fn try_read(&self) -> Option<u8> {
+        self.rx_data().try_read()
+    }
+
     pub fn write(&self, n: u8) {
-        while self.tx_data.is_full() {}
+        while self.tx_data().is_full() {}
 
-        self.tx_data.write(n);
+        self.tx_data().write(n);
     }
 }
 
@@ -65,6 +71,22 @@ impl ConsoleDevice for SifiveUart {
     fn write(&mut self, n: u8) {
         (&*self).write(n);
     }
+
+    fn try_read(&self) -> Option<u8> {
+        (&*self).try_read()
+    }
+
+    fn tx_capacity(&self) -> usize {
+        // There's no way to tell how full the FIFO is, only whether it's full
+        match self.tx_data().is_full() {
+            true => 0,
+            false => 1,
+        }
+    }
+
+    fn set_tx_interrupt(&mut self, enabled: bool) {
+        self.interrupt_enable().tx_watermark_enable(enabled);
+    }
 }
 
 impl CompatibleWith for SifiveUart {
@@ -111,13 +133,15 @@ mod registers {
 
     impl TxCtrl {
         pub fn tx_enable(&self, enable: bool) {
-            let val = (self.0.read() & !1) | (enable as u32);
-            self.0.write(val);
+            self.0.modify(|val| (val & !1) | (enable as u32));
         }
 
         pub fn extra_stop_bit(&self, enable: bool) {
-            let val = (self.0.read() & !2) | ((enable as u32) << 1);
-            self.0.write(val);
+            self.0.modify(|val| (val & !2) | ((enable as u32) << 1));
+        }
+
+        pub fn watermark_level(&self, watermark: u8) {
+            self.0.modify(|val| (val & !(0b111 << 16)) | ((watermark as u32 & 0b111) << 16));
         }
     }
 
@@ -127,13 +151,11 @@ mod registers {
 
     impl RxCtrl {
         pub fn rx_enable(&self, enable: bool) {
-            let val = (self.0.read() & !1) | (enable as u32);
-            self.0.write(val);
+            self.0.modify(|val| (val & !1) | (enable as u32));
         }
 
         pub fn watermark_level(&self, watermark: u8) {
-            let val = (self.0.read() & !(0b111 << 16)) | ((watermark as u32 & 0b111) << 16);
-            self.0.write(val);
+            self.0.modify(|val| (val & !(0b111 << 16)) | ((watermark as u32 & 0b111) << 16));
         }
     }
 
@@ -143,13 +165,11 @@ mod registers {
 
     impl InterruptEnable {
         pub fn tx_watermark_enable(&self, enable: bool) {
-            let val = (self.0.read() & !1) | (enable as u32);
-            self.0.write(val);
+            self.0.modify(|val| (val & !1) | (enable as u32));
         }
 
         pub fn rx_watermark_enable(&self, enable: bool) {
-            let val = (self.0.read() & !2) | ((enable as u32) << 1);
-            self.0.write(val);
+            self.0.modify(|val| (val & !2) | ((enable as u32) << 1));
         }
     }
 
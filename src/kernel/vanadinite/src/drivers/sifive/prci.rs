@@ -0,0 +1,74 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::drivers::CompatibleWith;
+use volatile::Volatile;
+
+/// Range the PLL's VCO must stay within, before the output divider
+const VCO_MIN: u64 = 2_400_000_000;
+const VCO_MAX: u64 = 4_800_000_000;
+
+const PLL_LOCKED: u32 = 1 << 31;
+const PLL_DIVF_MASK: u32 = 0x1FF << 6;
+const PLL_DIVQ_MASK: u32 = 0b111 << 15;
+
+volatile::register_block! {
+    /// Power, reset, clock, and interrupt controller found on the FU540 and
+    /// FU740, which share the layout of the registers used here
+    pub struct Prci [0x28] {
+        0x04 => core_pll_config: Volatile<u32>,
+        0x24 => core_clock_select: Volatile<u32>,
+    }
+}
+
+impl Prci {
+    /// Frequency of the core clock in Hz, given the frequency of the `hfclk`
+    /// input oscillator
+    pub fn core_frequency(&self, hfclk: u64) -> u64 {
+        // The core clock is running directly off of `hfclk`
+        if self.core_clock_select().read() & 1 == 1 {
+            return hfclk;
+        }
+
+        let config = self.core_pll_config().read();
+        let divr = u64::from(config & 0x3F);
+        let divf = u64::from((config >> 6) & 0x1FF);
+        let divq = (config >> 15) & 0b111;
+
+        (hfclk * 2 * (divf + 1) / (divr + 1)) >> divq
+    }
+
+    /// Reprogram the core PLL as close to `hz` as it can get without going
+    /// over, returning the new frequency. The core runs directly from `hfclk`
+    /// while the PLL relocks. Returns `None` if there's no valid PLL
+    /// configuration for the frequency.
+    ///
+    /// On the FU540 the peripheral clock is derived from the core clock, so
+    /// this will also change the baud rate of the UARTs and the like.
+    pub fn set_core_frequency(&self, hfclk: u64, hz: u64) -> Option<u64> {
+        let config = self.core_pll_config().read();
+        let divr = u64::from(config & 0x3F);
+
+        // The reference divider and filter range are left as firmware set
+        // them, only the feedback and output dividers change
+        let divq = (1..=6).find(|&divq| (VCO_MIN..=VCO_MAX).contains(&(hz << divq)))?;
+        let divf = ((hz << divq) * (divr + 1) / (2 * hfclk)).checked_sub(1)?.min(0x1FF);
+
+        self.core_clock_select().write(1);
+        self.core_pll_config().write((config & !PLL_DIVF_MASK & !PLL_DIVQ_MASK) | (divf as u32) << 6 | divq << 15);
+        while self.core_pll_config().read() & PLL_LOCKED == 0 {}
+        self.core_clock_select().write(0);
+
+        Some(self.core_frequency(hfclk))
+    }
+}
+
+impl CompatibleWith for Prci {
+    fn compatible_with() -> &'static [&'static str] {
+        &["sifive,fu540-c000-prci", "sifive,fu740-c000-prci"]
+    }
+}
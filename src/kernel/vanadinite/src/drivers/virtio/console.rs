@@ -0,0 +1,195 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The virtio console device, e.g. QEMU's `virtio-serial-device` with a
+//! `virtconsole` attached. Only the first port is used since
+//! `VIRTIO_CONSOLE_F_MULTIPORT` isn't negotiated.
+
+use super::{
+    mmio::{status, Registers},
+    queue::{physical_address, Descriptor, SplitQueue, DESCRIPTOR_WRITE, QUEUE_SIZE},
+};
+use crate::drivers::CompatibleWith;
+use sync::SpinMutex;
+
+const DEVICE_ID: u32 = 3;
+const RECEIVE_QUEUE: u32 = 0;
+const TRANSMIT_QUEUE: u32 = 1;
+const RX_BUFFER_LEN: usize = 64;
+
+// The queues and buffers are kept in the kernel image instead of the register
+// block since the device is set up by casting its MMIO address, the same as
+// the UARTs
+static STATE: SpinMutex<State> = SpinMutex::new(State::new());
+
+struct State {
+    ready: bool,
+    receive: SplitQueue,
+    transmit: SplitQueue,
+    rx_buffers: [[u8; RX_BUFFER_LEN]; QUEUE_SIZE],
+    /// The receive buffer being read from: its descriptor, the number of
+    /// bytes the device wrote to it, and how many have been read
+    rx_current: Option<(u16, usize, usize)>,
+    /// Each byte of output is sent in its own transmit buffer, so writes
+    /// don't have to wait for a buffer to fill up
+    tx_buffers: [u8; QUEUE_SIZE],
+    /// Transmit descriptors not owned by the device
+    tx_free: [u16; QUEUE_SIZE],
+    tx_free_len: usize,
+}
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            ready: false,
+            receive: SplitQueue::new(),
+            transmit: SplitQueue::new(),
+            rx_buffers: [[0; RX_BUFFER_LEN]; QUEUE_SIZE],
+            rx_current: None,
+            tx_buffers: [0; QUEUE_SIZE],
+            tx_free: [0; QUEUE_SIZE],
+            tx_free_len: 0,
+        }
+    }
+
+    fn init(&mut self, registers: &Registers) -> Result<(), &'static str> {
+        if !registers.negotiate(0) {
+            return Err("device rejected features");
+        }
+
+        self.receive.attach(registers, RECEIVE_QUEUE)?;
+        self.transmit.attach(registers, TRANSMIT_QUEUE)?;
+
+        for id in 0..QUEUE_SIZE as u16 {
+            let address = physical_address(&self.rx_buffers[usize::from(id)]);
+            *self.receive.descriptor(id) =
+                Descriptor { address, len: RX_BUFFER_LEN as u32, flags: DESCRIPTOR_WRITE, next: 0 };
+            self.receive.submit(id);
+
+            let address = physical_address(&self.tx_buffers[usize::from(id)]);
+            *self.transmit.descriptor(id) = Descriptor { address, len: 1, flags: 0, next: 0 };
+            self.tx_free[usize::from(id)] = id;
+        }
+
+        self.tx_free_len = QUEUE_SIZE;
+        self.transmit.set_interrupts(false);
+
+        registers.set_status(status::DRIVER_OK);
+        registers.queue_notify().write(RECEIVE_QUEUE);
+        self.ready = true;
+
+        Ok(())
+    }
+
+    /// Take back any transmit buffers the device is done with
+    fn reclaim_tx(&mut self) {
+        while let Some((id, _)) = self.transmit.pop_used() {
+            self.tx_free[self.tx_free_len] = id;
+            self.tx_free_len += 1;
+        }
+    }
+
+    fn try_read(&mut self, registers: &Registers) -> Option<u8> {
+        loop {
+            match self.rx_current {
+                Some((id, len, read)) if read < len => {
+                    self.rx_current = Some((id, len, read + 1));
+                    return Some(self.rx_buffers[usize::from(id)][read]);
+                }
+                // Give the empty buffer back to the device
+                Some((id, ..)) => {
+                    self.rx_current = None;
+                    self.receive.submit(id);
+                    registers.queue_notify().write(RECEIVE_QUEUE);
+                }
+                None => {
+                    let (id, len) = self.receive.pop_used()?;
+                    self.rx_current = Some((id, (len as usize).min(RX_BUFFER_LEN), 0));
+                }
+            }
+        }
+    }
+}
+
+#[repr(transparent)]
+pub struct VirtioConsole(Registers);
+
+impl crate::io::ConsoleDevice for VirtioConsole {
+    fn init(&mut self) {
+        // Other virtio devices have the same compatible string, so leave the
+        // console disconnected if this turns out not to be one. The console
+        // isn't usable yet, so there's nowhere to report errors to.
+        if self.0.is_device(DEVICE_ID) {
+            let _ = STATE.lock().init(&self.0);
+        }
+    }
+
+    fn read(&self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_read() {
+                return byte;
+            }
+
+            core::hint::spin_loop();
+        }
+    }
+
+    fn try_read(&self) -> Option<u8> {
+        let mut state = STATE.lock();
+        match state.ready {
+            true => state.try_read(&self.0),
+            false => None,
+        }
+    }
+
+    fn write(&mut self, n: u8) {
+        let mut state = STATE.lock();
+        if !state.ready {
+            return;
+        }
+
+        state.reclaim_tx();
+        while state.tx_free_len == 0 {
+            core::hint::spin_loop();
+            state.reclaim_tx();
+        }
+
+        state.tx_free_len -= 1;
+        let id = state.tx_free[state.tx_free_len];
+        state.tx_buffers[usize::from(id)] = n;
+        state.transmit.submit(id);
+        self.0.queue_notify().write(TRANSMIT_QUEUE);
+    }
+
+    fn tx_capacity(&self) -> usize {
+        let mut state = STATE.lock();
+        match state.ready {
+            true => {
+                state.reclaim_tx();
+                state.tx_free_len
+            }
+            // Writes are dropped anyway
+            false => usize::MAX,
+        }
+    }
+
+    fn set_tx_interrupt(&mut self, enabled: bool) {
+        STATE.lock().transmit.set_interrupts(enabled);
+    }
+
+    fn acknowledge_interrupt(&mut self) {
+        if STATE.lock().ready {
+            self.0.acknowledge_interrupts();
+        }
+    }
+}
+
+impl CompatibleWith for VirtioConsole {
+    fn compatible_with() -> &'static [&'static str] {
+        &["virtio,mmio"]
+    }
+}
@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The virtio MMIO transport, only the non-legacy (version 2) interface is
+//! supported
+
+use volatile::{Read, ReadWrite, Volatile, Write};
+
+const MAGIC: u32 = u32::from_le_bytes(*b"virt");
+const VERSION: u32 = 2;
+
+volatile::register_block! {
+    pub struct Registers [0x100] {
+        0x000 => magic: Volatile<u32, Read>,
+        0x004 => version: Volatile<u32, Read>,
+        0x008 => device_id: Volatile<u32, Read>,
+        0x010 => pub device_features: Volatile<u32, Read>,
+        0x014 => pub device_features_select: Volatile<u32, Write>,
+        0x020 => pub driver_features: Volatile<u32, Write>,
+        0x024 => pub driver_features_select: Volatile<u32, Write>,
+        0x030 => pub queue_select: Volatile<u32, Write>,
+        0x034 => pub queue_size_max: Volatile<u32, Read>,
+        0x038 => pub queue_size: Volatile<u32, Write>,
+        0x044 => pub queue_ready: Volatile<u32, ReadWrite>,
+        0x050 => pub queue_notify: Volatile<u32, Write>,
+        0x060 => pub interrupt_status: Volatile<u32, Read>,
+        0x064 => pub interrupt_ack: Volatile<u32, Write>,
+        0x070 => pub status: Volatile<u32, ReadWrite>,
+        0x080 => pub queue_descriptor: Volatile<[u32; 2], ReadWrite>,
+        0x090 => pub queue_available: Volatile<[u32; 2], ReadWrite>,
+        0x0A0 => pub queue_used: Volatile<[u32; 2], ReadWrite>,
+    }
+}
+
+impl Registers {
+    /// Whether this is a (non-legacy) virtio device of the given type, the
+    /// same `virtio,mmio` compatible string is used for every device type
+    pub fn is_device(&self, device_id: u32) -> bool {
+        self.magic().read() == MAGIC && self.version().read() == VERSION && self.device_id().read() == device_id
+    }
+
+    /// Reset the device and negotiate `VIRTIO_F_VERSION_1` along with the
+    /// given device specific features, returning `false` if the device
+    /// doesn't accept them
+    pub fn negotiate(&self, features: u32) -> bool {
+        self.status().write(0);
+        self.set_status(status::ACKNOWLEDGE | status::DRIVER);
+
+        self.device_features_select().write(0);
+        let features = features & self.device_features().read();
+        self.driver_features_select().write(0);
+        self.driver_features().write(features);
+        self.driver_features_select().write(1);
+        self.driver_features().write(1);
+
+        self.set_status(status::FEATURES_OK);
+        if self.status().read() & status::FEATURES_OK == 0 {
+            self.set_status(status::FAILED);
+            return false;
+        }
+
+        true
+    }
+
+    pub fn set_status(&self, flags: u32) {
+        self.status().modify(|status| status | flags);
+    }
+
+    /// Acknowledge all pending interrupts
+    pub fn acknowledge_interrupts(&self) {
+        self.interrupt_ack().write(self.interrupt_status().read());
+    }
+}
+
+pub mod status {
+    pub const ACKNOWLEDGE: u32 = 1;
+    pub const DRIVER: u32 = 2;
+    pub const DRIVER_OK: u32 = 4;
+    pub const FEATURES_OK: u32 = 8;
+    pub const FAILED: u32 = 128;
+}
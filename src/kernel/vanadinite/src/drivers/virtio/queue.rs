@@ -0,0 +1,140 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Split virtqueues which live in the kernel image, so devices needed early in
+//! boot can be set up before the physical memory allocator exists
+
+use super::mmio::Registers;
+use crate::mem::{kernel_patching::kernel_section_v2p, paging::VirtualAddress};
+use core::ptr;
+use librust::mem::{fence, FenceMode};
+
+pub const QUEUE_SIZE: usize = 16;
+
+/// The device writes to the buffer instead of reading from it
+pub const DESCRIPTOR_WRITE: u16 = 2;
+/// Ask the device not to interrupt when it uses a buffer
+const AVAILABLE_NO_INTERRUPT: u16 = 1;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(16))]
+pub struct Descriptor {
+    pub address: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+#[derive(Debug)]
+#[repr(C, align(2))]
+struct Available {
+    flags: u16,
+    index: u16,
+    ring: [u16; QUEUE_SIZE],
+    used_event: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+#[derive(Debug)]
+#[repr(C, align(4))]
+struct Used {
+    flags: u16,
+    index: u16,
+    ring: [UsedElement; QUEUE_SIZE],
+    avail_event: u16,
+}
+
+#[derive(Debug)]
+#[repr(C, align(4096))]
+pub struct SplitQueue {
+    descriptors: [Descriptor; QUEUE_SIZE],
+    available: Available,
+    used: Used,
+    last_used: u16,
+}
+
+impl SplitQueue {
+    pub const fn new() -> Self {
+        Self {
+            descriptors: [Descriptor { address: 0, len: 0, flags: 0, next: 0 }; QUEUE_SIZE],
+            available: Available { flags: 0, index: 0, ring: [0; QUEUE_SIZE], used_event: 0 },
+            used: Used { flags: 0, index: 0, ring: [UsedElement { id: 0, len: 0 }; QUEUE_SIZE], avail_event: 0 },
+            last_used: 0,
+        }
+    }
+
+    /// Hand the queue to the device as the queue at `index`
+    pub fn attach(&mut self, registers: &Registers, index: u32) -> Result<(), &'static str> {
+        registers.queue_select().write(index);
+        if (registers.queue_size_max().read() as usize) < QUEUE_SIZE {
+            return Err("virtqueue is too small");
+        }
+
+        registers.queue_size().write(QUEUE_SIZE as u32);
+        set_address(registers.queue_descriptor(), physical_address(&self.descriptors));
+        set_address(registers.queue_available(), physical_address(&self.available));
+        set_address(registers.queue_used(), physical_address(&self.used));
+        registers.queue_ready().write(1);
+
+        Ok(())
+    }
+
+    pub fn descriptor(&mut self, id: u16) -> &mut Descriptor {
+        &mut self.descriptors[usize::from(id)]
+    }
+
+    /// Make the descriptor available to the device, it still needs to be
+    /// notified afterwards
+    pub fn submit(&mut self, id: u16) {
+        let index = self.available.index;
+        self.available.ring[usize::from(index) % QUEUE_SIZE] = id;
+        fence(FenceMode::Full);
+        unsafe { ptr::write_volatile(&mut self.available.index, index.wrapping_add(1)) };
+        fence(FenceMode::Full);
+    }
+
+    /// The next descriptor the device is done with, along with the number of
+    /// bytes it wrote to the buffer
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if unsafe { ptr::read_volatile(&self.used.index) } == self.last_used {
+            return None;
+        }
+
+        fence(FenceMode::Full);
+        let used = unsafe { ptr::read_volatile(&self.used.ring[usize::from(self.last_used) % QUEUE_SIZE]) };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        Some((used.id as u16, used.len))
+    }
+
+    /// Enable or disable the interrupt raised when the device uses a buffer,
+    /// which is only a hint to the device
+    pub fn set_interrupts(&mut self, enabled: bool) {
+        let flags = match enabled {
+            true => 0,
+            false => AVAILABLE_NO_INTERRUPT,
+        };
+
+        unsafe { ptr::write_volatile(&mut self.available.flags, flags) };
+    }
+}
+
+/// The physical address of something in the kernel image
+pub fn physical_address<T>(object: &T) -> u64 {
+    unsafe { kernel_section_v2p(VirtualAddress::from_ptr(object)).as_usize() as u64 }
+}
+
+fn set_address(register: &volatile::Volatile<[u32; 2]>, address: u64) {
+    register[0].write(address as u32);
+    register[1].write((address >> 32) as u32);
+}
@@ -0,0 +1,41 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Work handed off by ISRs to be run once the interrupts which are being
+//! handled have been claimed and completed. ISRs run while the ISR registry is
+//! locked and before the interrupt is completed, so they should only do what's
+//! needed to quiet the device and queue the rest here. Anything which takes
+//! the console or task locks belongs in deferred work, since those may be
+//! taken again by whatever else the ISR calls into.
+
+use alloc::boxed::Box;
+use crossbeam_queue::ArrayQueue;
+use sync::Lazy;
+
+/// Maximum amount of work that can be waiting to run before ISRs start
+/// running their work immediately instead
+const QUEUE_LEN: usize = 256;
+
+type Work = Box<dyn FnOnce() + Send + 'static>;
+
+static QUEUE: Lazy<ArrayQueue<Work>> = Lazy::new(|| ArrayQueue::new(QUEUE_LEN));
+
+/// Queue `f` to run after the current interrupt has been handled
+pub fn defer(f: impl FnOnce() + Send + 'static) {
+    if let Err(f) = QUEUE.push(Box::new(f)) {
+        log::warn!("Deferred interrupt work queue is full, running work immediately");
+        f();
+    }
+}
+
+/// Run all of the work that's currently waiting, including any that's queued
+/// while doing so
+pub fn run_pending() {
+    while let Some(work) = QUEUE.pop() {
+        work();
+    }
+}
@@ -5,43 +5,92 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::drivers::generic::plic::{InterruptClaim, Plic};
+use super::{rate_limit, InterruptClaim};
+use alloc::{boxed::Box, vec::Vec};
 use sync::SpinRwLock;
 
-const ISR_LIMIT: usize = 128;
+pub const ISR_LIMIT: usize = 1024;
 
 static ISR_REGISTRY: [IsrEntry; ISR_LIMIT] = [const { IsrEntry::new() }; ISR_LIMIT];
 
-type DynIsrCallback = dyn Fn(&Plic, InterruptClaim<'_>, usize) -> Result<(), &'static str> + Send + 'static;
+type DynIsrCallback = dyn Fn(&InterruptClaim, usize) -> Result<IsrStatus, &'static str> + Send + Sync + 'static;
+
+/// What an ISR did with an interrupt. Interrupt lines can be shared between
+/// devices, so every handler registered for an interrupt ID is run and each
+/// one reports whether its device was the one that raised it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsrStatus {
+    /// The interrupt wasn't raised by this handler's device
+    NotMine,
+    /// The interrupt was handled, and can be completed
+    Handled,
+    /// The interrupt was handed off to be completed later, so it must not be
+    /// completed yet
+    Deferred,
+}
 
 #[derive(Debug)]
 pub struct IsrEntry {
-    f: SpinRwLock<Option<alloc::boxed::Box<DynIsrCallback>>>,
+    handlers: SpinRwLock<Vec<Box<DynIsrCallback>>>,
 }
 
 impl IsrEntry {
     const fn new() -> Self {
-        Self { f: SpinRwLock::new(None) }
+        Self { handlers: SpinRwLock::new(Vec::new()) }
     }
 
-    fn set(&self, f: impl Fn(&Plic, InterruptClaim<'_>, usize) -> Result<(), &'static str> + Send + 'static) {
-        *self.f.write() = Some(alloc::boxed::Box::new(f));
+    fn add(&self, f: impl Fn(&InterruptClaim, usize) -> Result<IsrStatus, &'static str> + Send + Sync + 'static) {
+        self.handlers.write().push(Box::new(f));
     }
 }
 
+/// Add a handler for the given interrupt ID, after any which are already
+/// registered for it
 // TODO: move the trait bound to a trait alias when it doesn't cause inference
 // issues...
 pub fn register_isr<F>(interrupt_id: usize, f: F)
 where
-    F: Fn(&Plic, InterruptClaim<'_>, usize) -> Result<(), &'static str> + Send + 'static,
+    F: Fn(&InterruptClaim, usize) -> Result<IsrStatus, &'static str> + Send + Sync + 'static,
 {
     log::debug!("Registering ISR for interrupt ID {}", interrupt_id);
-    ISR_REGISTRY[interrupt_id].set(f);
+    ISR_REGISTRY[interrupt_id].add(f);
 }
 
-pub fn invoke_isr(plic: &Plic, claim: InterruptClaim<'_>, interrupt_id: usize) -> Result<(), &'static str> {
-    match ISR_REGISTRY[interrupt_id].f.read().as_ref() {
-        Some(f) => f(plic, claim, interrupt_id),
-        None => Ok(claim.complete()),
+/// Run every handler for the claimed interrupt, completing it unless one of
+/// them deferred it. A failing handler doesn't stop the remaining handlers
+/// from running, the last error is returned.
+pub fn invoke_isr(claim: InterruptClaim) -> Result<(), &'static str> {
+    let interrupt_id = claim.interrupt_id();
+    crate::metrics::INTERRUPTS.increment();
+    rate_limit::record(&claim);
+
+    let handlers = ISR_REGISTRY[interrupt_id].handlers.read();
+
+    let mut status = IsrStatus::NotMine;
+    let mut result = Ok(());
+    for handler in handlers.iter() {
+        match handler(&claim, interrupt_id) {
+            Ok(IsrStatus::NotMine) => {}
+            Ok(IsrStatus::Handled) if status == IsrStatus::NotMine => status = IsrStatus::Handled,
+            Ok(IsrStatus::Handled) => {}
+            Ok(IsrStatus::Deferred) => status = IsrStatus::Deferred,
+            Err(e) => result = Err(e),
+        }
     }
+
+    if status == IsrStatus::NotMine && !handlers.is_empty() && result.is_ok() {
+        log::debug!("No handler claimed interrupt ID {}", interrupt_id);
+    }
+
+    match status {
+        // Whoever the interrupt was handed off to is responsible for
+        // completing it
+        IsrStatus::Deferred => {
+            rate_limit::defer(interrupt_id);
+            drop(claim)
+        }
+        IsrStatus::NotMine | IsrStatus::Handled => claim.complete(),
+    }
+
+    result
 }
@@ -5,15 +5,327 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod deferred;
 pub mod isr;
+pub mod rate_limit;
 
-use crate::drivers::generic::plic;
-use sync::SpinMutex;
+use crate::{
+    drivers::{
+        generic::{aplic::Aplic, plic::Plic},
+        CompatibleWith,
+    },
+    mem::{paging::PhysicalAddress, phys2virt},
+};
+use alloc::{boxed::Box, vec::Vec};
+use core::{ops::RangeInclusive, sync::atomic::Ordering};
+use sync::{AtomicConstPtr, SpinMutex};
 
-pub static PLIC: SpinMutex<Option<&'static plic::Plic>> = SpinMutex::new(None);
+/// Every interrupt controller in the system. Each one is assigned a contiguous
+/// range of global interrupt IDs starting at `base`, which is what ISRs are
+/// registered with and what userspace sees, so sources on different
+/// controllers never collide.
+///
+/// The list is built once while probing and never changes afterwards, so the
+/// external interrupt path can walk it without taking any locks.
+static CONTROLLERS: AtomicConstPtr<Vec<RegisteredController>> = AtomicConstPtr::new(core::ptr::null());
 
-pub fn register_plic(plic: &'static plic::Plic) {
-    *PLIC.lock() = Some(plic);
+/// Serializes changes to which sources are enabled and their priorities.
+/// Enabling a source is a read-modify-write of a register shared by every
+/// source in the same word, and any hart can reconfigure any other hart's
+/// context. Claiming and completing only ever touch the current hart's own
+/// context, so they don't need it. Interrupts are disabled while it's held,
+/// since ISRs may need to mask their own source.
+static CONFIG: SpinMutex<()> = SpinMutex::new(());
+
+#[derive(Debug, Clone, Copy)]
+pub enum InterruptController {
+    Plic(&'static Plic),
+    Aplic(&'static Aplic),
+}
+
+impl InterruptController {
+    pub fn name(&self) -> &'static str {
+        match self {
+            InterruptController::Plic(_) => "PLIC",
+            InterruptController::Aplic(_) => "APLIC",
+        }
+    }
+
+    pub fn enable_interrupt(&self, hart: usize, source: usize) {
+        let _disabler = InterruptDisabler::new();
+        let _guard = CONFIG.lock();
+        match self {
+            InterruptController::Plic(plic) => plic.enable_interrupt(crate::platform::plic_context_for(hart), source),
+            InterruptController::Aplic(aplic) => aplic.enable_interrupt(hart, source),
+        }
+    }
+
+    pub fn disable_interrupt(&self, hart: usize, source: usize) {
+        let _disabler = InterruptDisabler::new();
+        let _guard = CONFIG.lock();
+        match self {
+            InterruptController::Plic(plic) => plic.disable_interrupt(crate::platform::plic_context_for(hart), source),
+            InterruptController::Aplic(aplic) => aplic.disable_interrupt(source),
+        }
+    }
+
+    pub fn set_interrupt_priority(&self, source: usize, priority: usize) {
+        let _disabler = InterruptDisabler::new();
+        let _guard = CONFIG.lock();
+        match self {
+            InterruptController::Plic(plic) => plic.set_interrupt_priority(source, priority),
+            InterruptController::Aplic(aplic) => aplic.set_interrupt_priority(source, priority),
+        }
+    }
+
+    pub fn set_hart_threshold(&self, hart: usize, threshold: usize) {
+        let _disabler = InterruptDisabler::new();
+        let _guard = CONFIG.lock();
+        match self {
+            InterruptController::Plic(plic) => {
+                plic.set_context_threshold(crate::platform::plic_context_for(hart), threshold)
+            }
+            InterruptController::Aplic(aplic) => aplic.set_hart_threshold(hart, threshold),
+        }
+    }
+
+    pub fn is_pending(&self, source: usize) -> bool {
+        match self {
+            InterruptController::Plic(plic) => plic.is_pending(source),
+            InterruptController::Aplic(aplic) => aplic.is_pending(source),
+        }
+    }
+
+    pub fn is_enabled(&self, hart: usize, source: usize) -> bool {
+        match self {
+            InterruptController::Plic(plic) => plic.is_enabled(crate::platform::plic_context_for(hart), source),
+            InterruptController::Aplic(aplic) => aplic.is_enabled(hart, source),
+        }
+    }
+
+    pub fn interrupt_priority(&self, source: usize) -> usize {
+        match self {
+            InterruptController::Plic(plic) => plic.interrupt_priority(source),
+            InterruptController::Aplic(aplic) => aplic.interrupt_priority(source),
+        }
+    }
+
+    pub fn hart_threshold(&self, hart: usize) -> usize {
+        match self {
+            InterruptController::Plic(plic) => plic.context_threshold(crate::platform::plic_context_for(hart)),
+            InterruptController::Aplic(aplic) => aplic.hart_threshold(hart),
+        }
+    }
+
+    fn claim(&self, hart: usize) -> Option<usize> {
+        match self {
+            InterruptController::Plic(plic) => {
+                plic.claim(crate::platform::plic_context_for(hart)).map(|claim| claim.interrupt_id())
+            }
+            InterruptController::Aplic(aplic) => aplic.claim(hart),
+        }
+    }
+
+    fn complete(&self, hart: usize, source: usize) {
+        match self {
+            InterruptController::Plic(plic) => plic.complete(crate::platform::plic_context_for(hart), source),
+            // Claiming already cleared the pending bit
+            InterruptController::Aplic(_) => {}
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RegisteredController {
+    pub controller: InterruptController,
+    /// The `phandle` of the controller's device tree node, which devices
+    /// refer to with their `interrupt-parent` property
+    pub phandle: Option<u32>,
+    /// The global interrupt ID of source zero
+    pub base: usize,
+    pub n_sources: usize,
+}
+
+impl RegisteredController {
+    /// Global interrupt IDs of every source on this controller
+    pub fn sources(&self) -> RangeInclusive<usize> {
+        self.base + 1..=self.base + self.n_sources
+    }
+
+    pub fn claim(&self, hart: usize) -> Option<InterruptClaim> {
+        self.controller.claim(hart).map(|source| InterruptClaim {
+            controller: self.controller,
+            hart,
+            source,
+            interrupt_id: self.base + source,
+        })
+    }
+}
+
+/// A claimed interrupt that must be completed before the controller will
+/// deliver it again
+#[derive(Debug)]
+#[must_use]
+pub struct InterruptClaim {
+    controller: InterruptController,
+    hart: usize,
+    source: usize,
+    interrupt_id: usize,
+}
+
+impl InterruptClaim {
+    /// The global interrupt ID
+    pub fn interrupt_id(&self) -> usize {
+        self.interrupt_id
+    }
+
+    pub fn controller(&self) -> InterruptController {
+        self.controller
+    }
+
+    pub fn hart(&self) -> usize {
+        self.hart
+    }
+
+    /// The source ID local to the claiming controller
+    pub fn source(&self) -> usize {
+        self.source
+    }
+
+    pub fn complete(self) {
+        self.controller.complete(self.hart, self.source);
+    }
+}
+
+/// Register an interrupt controller, returning the global interrupt ID of its
+/// source zero
+fn register_controller(
+    controllers: &mut Vec<RegisteredController>,
+    controller: InterruptController,
+    phandle: Option<u32>,
+    n_sources: usize,
+) -> usize {
+    let base = controllers.last().map(|last| last.base + last.n_sources + 1).unwrap_or(0);
+
+    if base + n_sources >= isr::ISR_LIMIT {
+        log::warn!("Interrupt controller sources exceed the ISR limit, some will be unusable");
+    }
+
+    controllers.push(RegisteredController { controller, phandle, base, n_sources });
+    base
+}
+
+/// Every registered interrupt controller, which is empty until [`probe`] has
+/// run
+pub fn controllers() -> &'static [RegisteredController] {
+    match CONTROLLERS.load(Ordering::Acquire) {
+        ptr if ptr.is_null() => &[],
+        ptr => unsafe { &*ptr },
+    }
+}
+
+/// Find the controller responsible for the given global interrupt ID, along
+/// with the ID of the source local to it
+pub fn resolve(interrupt_id: usize) -> Option<(InterruptController, usize)> {
+    controllers().iter().find(|c| c.sources().contains(&interrupt_id)).map(|c| (c.controller, interrupt_id - c.base))
+}
+
+/// Translate a source on the controller with the given `phandle` to its global
+/// interrupt ID. Devices without an `interrupt-parent` are assumed to be
+/// wired to the first controller.
+pub fn global_interrupt(phandle: Option<u32>, source: usize) -> Option<usize> {
+    let controllers = controllers();
+    let controller = match phandle {
+        Some(phandle) => controllers.iter().find(|c| c.phandle == Some(phandle))?,
+        None => controllers.first()?,
+    };
+
+    (1..=controller.n_sources).contains(&source).then_some(controller.base + source)
+}
+
+/// Complete an interrupt claimed on `hart` which was left disabled while it
+/// was being handled by a task, and enable it again
+pub fn complete_deferred(hart: usize, interrupt_id: usize) {
+    if let Some((controller, source)) = resolve(interrupt_id) {
+        controller.complete(hart, source);
+        if rate_limit::complete(interrupt_id) {
+            controller.enable_interrupt(hart, source);
+        }
+    }
+}
+
+/// Register every PLIC in the device tree, or the APLIC domains which deliver
+/// interrupts to S-mode if there are none. Must only be called once, before
+/// any external interrupts are enabled.
+pub fn probe(fdt: &fdt::Fdt<'_>) {
+    assert!(CONTROLLERS.load(Ordering::Acquire).is_null(), "interrupt controllers were already probed");
+
+    // Find harts which have S-mode available
+    let harts = || {
+        fdt.cpus()
+            .filter(|cpu| {
+                cpu.properties()
+                    .find(|p| p.name == "riscv,isa")
+                    .and_then(|p| p.as_str()?.chars().find(|c| *c == 's'))
+                    .is_some()
+            })
+            .map(|cpu| cpu.ids().first())
+    };
+
+    let compatible_nodes = |compatible: &'static [&'static str]| {
+        fdt.all_nodes()
+            .filter(move |node| node.compatible().map(|c| c.all().any(|c| compatible.contains(&c))).unwrap_or(false))
+    };
+
+    let phandle =
+        |node: &fdt::node::FdtNode<'_, '_>| node.property("phandle").and_then(|p| p.as_usize()).map(|p| p as u32);
+
+    let mut controllers = Vec::new();
+
+    for ic in compatible_nodes(Plic::compatible_with()) {
+        let reg = ic.reg().unwrap().next().unwrap();
+        let ic_virt = phys2virt(PhysicalAddress::from_ptr(reg.starting_address));
+
+        // Number of interrupts available
+        let ndevs = ic
+            .properties()
+            .find(|p| p.name == "riscv,ndev")
+            .and_then(|p| p.as_usize())
+            .expect("missing number of interrupts");
+
+        let plic = unsafe { &*ic_virt.as_ptr().cast::<Plic>() };
+        plic.init(ndevs, harts().map(crate::platform::plic_context_for));
+
+        log::debug!("Registering PLIC @ {:#p}", ic_virt);
+        register_controller(&mut controllers, InterruptController::Plic(plic), phandle(&ic), ndevs);
+    }
+
+    // APLIC domains with children delegate their sources, only the leaves
+    // actually deliver interrupts to harts
+    if controllers.is_empty() {
+        for ic in compatible_nodes(Aplic::compatible_with()).filter(|n| n.property("riscv,delegate").is_none()) {
+            let reg = ic.reg().unwrap().next().unwrap();
+            let ic_virt = phys2virt(PhysicalAddress::from_ptr(reg.starting_address));
+
+            let n_sources =
+                ic.property("riscv,num-sources").and_then(|p| p.as_usize()).expect("missing number of interrupts");
+
+            let aplic = unsafe { &*ic_virt.as_ptr().cast::<Aplic>() };
+            aplic.init(n_sources, harts());
+
+            log::debug!("Registering APLIC @ {:#p}", ic_virt);
+            register_controller(&mut controllers, InterruptController::Aplic(aplic), phandle(&ic), n_sources);
+        }
+    }
+
+    CONTROLLERS.store(Box::into_raw(Box::new(controllers)), Ordering::Release);
+}
+
+/// Set the priority threshold of the current hart on every controller
+pub fn set_hart_threshold(threshold: usize) {
+    for controller in controllers() {
+        controller.controller.set_hart_threshold(crate::HART_ID.get(), threshold);
+    }
 }
 
 pub struct InterruptDisabler(bool);
@@ -0,0 +1,159 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Per-interrupt rate limiting, so misbehaving hardware (or a hostile virtio
+//! host) can't keep a hart busy servicing interrupts. An interrupt that fires
+//! too often is masked at its controller, and unmasked from the timer tick once
+//! the mask period is over. Devices keep queueing work while masked, which is
+//! then handled in one batch by the next interrupt.
+
+use super::{isr::ISR_LIMIT, InterruptClaim, InterruptController};
+use crate::{utils, TIMER_FREQ};
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+use librust::syscalls::io::InterruptRateLimit;
+use sync::SpinMutex;
+
+static STATES: [SpinMutex<RateState>; ISR_LIMIT] = [const { SpinMutex::new(RateState::new()) }; ISR_LIMIT];
+/// Interrupt IDs which are currently masked
+static THROTTLED: SpinMutex<Vec<usize>> = SpinMutex::new(Vec::new());
+
+#[derive(Debug)]
+struct RateState {
+    limit: InterruptRateLimit,
+    window_start: u64,
+    count: u32,
+    /// Where the interrupt was masked, and the time it can be unmasked at
+    masked: Option<(InterruptController, usize, usize, u64)>,
+    /// Whether the interrupt was handed off to be completed later, in which
+    /// case it's unmasked by the completion instead of the timer
+    deferred: bool,
+    /// Number of times the interrupt has been masked
+    throttle_count: u64,
+}
+
+impl RateState {
+    const fn new() -> Self {
+        Self {
+            limit: InterruptRateLimit::DEFAULT,
+            window_start: 0,
+            count: 0,
+            masked: None,
+            deferred: false,
+            throttle_count: 0,
+        }
+    }
+}
+
+fn now_us() -> u64 {
+    utils::micros(crate::csr::time::read(), TIMER_FREQ.load(Ordering::Relaxed))
+}
+
+pub fn set_limit(interrupt_id: usize, limit: InterruptRateLimit) {
+    log::debug!("Setting rate limit for interrupt ID {}: {:?}", interrupt_id, limit);
+    STATES[interrupt_id].lock().limit = limit;
+}
+
+/// Account for an occurrence of the claimed interrupt, masking it if it's over
+/// its limit
+pub fn record(claim: &InterruptClaim) {
+    let interrupt_id = claim.interrupt_id();
+    let mut state = STATES[interrupt_id].lock();
+    if state.limit.max_burst == 0 {
+        return;
+    }
+
+    let now = now_us();
+    if now.saturating_sub(state.window_start) > state.limit.window_us {
+        state.window_start = now;
+        state.count = 0;
+    }
+
+    state.count += 1;
+    if state.count <= state.limit.max_burst || state.masked.is_some() {
+        return;
+    }
+
+    log::warn!(
+        "Interrupt ID {} fired {} times in {}us, masking it for {}us",
+        interrupt_id,
+        state.count,
+        state.limit.window_us,
+        state.limit.mask_us
+    );
+
+    claim.controller().disable_interrupt(claim.hart(), claim.source());
+    state.masked = Some((claim.controller(), claim.hart(), claim.source(), now + state.limit.mask_us));
+    state.throttle_count += 1;
+    drop(state);
+
+    THROTTLED.lock().push(interrupt_id);
+}
+
+/// Mark the interrupt as handed off, it stays disabled until [`complete`] is
+/// called
+pub fn defer(interrupt_id: usize) {
+    STATES[interrupt_id].lock().deferred = true;
+}
+
+/// Mark a deferred interrupt as completed, returning whether it can be enabled
+/// again or needs to stay masked until its mask period ends
+pub fn complete(interrupt_id: usize) -> bool {
+    let mut state = STATES[interrupt_id].lock();
+    state.deferred = false;
+    state.masked.is_none()
+}
+
+/// Unmask any interrupts whose mask period is over, called from the timer
+/// interrupt
+pub fn tick() {
+    // Interrupts are rarely throttled, so avoid contending on the lock from
+    // every hart when there's nothing to do
+    let mut throttled = match THROTTLED.try_lock() {
+        Some(throttled) if !throttled.is_empty() => throttled,
+        _ => return,
+    };
+
+    let now = now_us();
+    throttled.retain(|&interrupt_id| {
+        let mut state = STATES[interrupt_id].lock();
+        match state.masked {
+            Some((controller, hart, source, until)) if until <= now => {
+                log::debug!("Unmasking interrupt ID {}", interrupt_id);
+                state.masked = None;
+                state.window_start = now;
+                state.count = 0;
+
+                if !state.deferred {
+                    controller.enable_interrupt(hart, source);
+                }
+
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    });
+}
+
+/// The earliest time, in ticks of the `time` CSR, that a masked interrupt can
+/// be unmasked at, so idle harts know when to wake up for it
+pub fn next_deadline() -> Option<u64> {
+    let throttled = THROTTLED.lock();
+    let until = throttled
+        .iter()
+        .filter_map(|&interrupt_id| STATES[interrupt_id].lock().masked)
+        .map(|(.., until)| until)
+        .min()?;
+
+    Some(utils::ticks_per_us(until, TIMER_FREQ.load(Ordering::Relaxed)))
+}
+
+/// The number of times the interrupt has been masked for exceeding its limit
+pub fn throttle_count(interrupt_id: usize) -> u64 {
+    STATES[interrupt_id].try_lock().map_or(0, |state| state.throttle_count)
+}
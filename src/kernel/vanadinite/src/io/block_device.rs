@@ -5,4 +5,29 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-pub trait BlockDevice {}
+pub const BLOCK_SIZE: usize = 512;
+
+pub trait BlockDevice {
+    type Error: core::fmt::Debug;
+
+    /// Number of [`BLOCK_SIZE`] byte blocks on the device
+    fn block_count(&self) -> u64;
+    fn read_block(&mut self, block: u64, buffer: &mut [u8; BLOCK_SIZE]) -> Result<(), Self::Error>;
+    fn write_block(&mut self, block: u64, buffer: &[u8; BLOCK_SIZE]) -> Result<(), Self::Error>;
+}
+
+/// Lets a [`BlockDevice`] be used to mount FAT32 volumes, which have the same
+/// sector size
+pub struct Fat32Device<B: BlockDevice>(pub B);
+
+impl<B: BlockDevice> fat32::BlockDevice for Fat32Device<B> {
+    type Error = B::Error;
+
+    fn read_sector(&mut self, sector: u64, buffer: &mut [u8; fat32::SECTOR_SIZE]) -> Result<(), Self::Error> {
+        self.0.read_block(sector, buffer)
+    }
+
+    fn write_sector(&mut self, sector: u64, buffer: &[u8; fat32::SECTOR_SIZE]) -> Result<(), Self::Error> {
+        self.0.write_block(sector, buffer)
+    }
+}
@@ -6,15 +6,51 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 use crate::{
-    drivers::{generic::uart16550::Uart16550, sifive::fu540_c000::uart::SifiveUart, CompatibleWith},
-    interrupts::isr::register_isr,
+    device::{Device, ProbeError},
+    drivers::{
+        generic::uart16550::Uart16550, sifive::fu540_c000::uart::SifiveUart, virtio::console::VirtioConsole,
+        CompatibleWith,
+    },
+    interrupts::{
+        isr::{register_isr, IsrStatus},
+        InterruptClaim,
+    },
+    mem::{paging::PhysicalAddress, phys2virt},
 };
-use sync::SpinMutex;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crossbeam_queue::ArrayQueue;
+use fdt::Fdt;
+use sync::{Lazy, SpinMutex};
+
+const TX_FIFO_LEN: usize = 4096;
+const RX_PENDING_LEN: usize = 256;
+
+/// Virtual address of the device set with [`ConsoleDevices::set_raw_console`]
+static CONSOLE_ADDRESS: AtomicUsize = AtomicUsize::new(0);
 
 pub trait ConsoleDevice: 'static {
     fn init(&mut self);
     fn read(&self) -> u8;
     fn write(&mut self, n: u8);
+
+    fn try_read(&self) -> Option<u8> {
+        Some(self.read())
+    }
+
+    /// The number of bytes that can be written without waiting, devices which
+    /// can't interrupt when they're ready for more keep the default of always
+    /// writing synchronously
+    fn tx_capacity(&self) -> usize {
+        usize::MAX
+    }
+
+    /// Enable or disable the interrupt raised when the device is ready to
+    /// transmit more data
+    fn set_tx_interrupt(&mut self, _enabled: bool) {}
+
+    /// Clear the device's pending interrupt, for devices where reading and
+    /// writing don't already do so
+    fn acknowledge_interrupt(&mut self) {}
 }
 
 impl core::fmt::Write for dyn ConsoleDevice {
@@ -27,14 +63,56 @@ impl core::fmt::Write for dyn ConsoleDevice {
     }
 }
 
-pub struct StaticConsoleDevice(Option<&'static mut dyn ConsoleDevice>);
+/// The kernel console. Once its interrupt is registered, output is queued in a
+/// software FIFO which is drained from the transmit interrupt instead of
+/// waiting on the device for every byte.
+pub struct StaticConsoleDevice {
+    device: Option<&'static mut dyn ConsoleDevice>,
+    tx: TxFifo,
+    interrupt_driven: bool,
+}
+
+impl StaticConsoleDevice {
+    const fn new(device: Option<&'static mut dyn ConsoleDevice>) -> Self {
+        Self { device, tx: TxFifo::new(), interrupt_driven: false }
+    }
+
+    /// Write as much queued output as the device can currently accept,
+    /// disabling the transmit interrupt once the queue is empty
+    pub fn drain_tx(&mut self) {
+        let device = match &mut self.device {
+            Some(device) => device,
+            None => return,
+        };
+
+        while !self.tx.is_empty() {
+            match device.tx_capacity() {
+                0 => return,
+                n => (0..n).map_while(|_| self.tx.pop()).for_each(|b| device.write(b)),
+            }
+        }
+
+        device.set_tx_interrupt(false);
+    }
+
+    /// Write out all queued output, waiting on the device if necessary
+    pub fn flush(&mut self) {
+        if let Some(device) = &mut self.device {
+            while let Some(byte) = self.tx.pop() {
+                device.write(byte);
+            }
+        }
+    }
+
+    fn enable_tx_interrupts(&mut self) {
+        self.interrupt_driven = true;
+    }
+}
 
 impl core::fmt::Write for StaticConsoleDevice {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        if let Some(console) = &mut self.0 {
-            for byte in s.as_bytes() {
-                console.write(*byte);
-            }
+        for byte in s.as_bytes() {
+            self.write(*byte);
         }
 
         Ok(())
@@ -43,30 +121,114 @@ impl core::fmt::Write for StaticConsoleDevice {
 
 impl ConsoleDevice for StaticConsoleDevice {
     fn init(&mut self) {
-        if let Some(inner) = &mut self.0 {
+        if let Some(inner) = &mut self.device {
             inner.init();
         }
     }
 
     fn read(&self) -> u8 {
-        if let Some(inner) = &self.0 {
+        if let Some(inner) = &self.device {
             return inner.read();
         }
 
         0
     }
 
+    fn try_read(&self) -> Option<u8> {
+        self.device.as_ref()?.try_read()
+    }
+
+    fn acknowledge_interrupt(&mut self) {
+        if let Some(inner) = &mut self.device {
+            inner.acknowledge_interrupt();
+        }
+    }
+
     fn write(&mut self, n: u8) {
-        if let Some(inner) = &mut self.0 {
-            inner.write(n);
+        if !self.interrupt_driven || SYNCHRONOUS.load(Ordering::Relaxed) {
+            self.flush();
+            if let Some(inner) = &mut self.device {
+                inner.write(n);
+            }
+
+            return;
         }
+
+        let device = match &mut self.device {
+            Some(device) => device,
+            None => return,
+        };
+
+        // Skip the queue if nothing is waiting ahead of this byte, so output
+        // only goes through the interrupt when the device falls behind
+        if self.tx.is_empty() && device.tx_capacity() > 0 {
+            return device.write(n);
+        }
+
+        if self.tx.is_full() {
+            // Nowhere to put it, wait for the device to make room instead of
+            // dropping output
+            if let Some(byte) = self.tx.pop() {
+                device.write(byte);
+            }
+        }
+
+        self.tx.push(n);
+        device.set_tx_interrupt(true);
     }
 }
 
 unsafe impl Send for StaticConsoleDevice {}
 unsafe impl Sync for StaticConsoleDevice {}
 
-pub static CONSOLE: SpinMutex<StaticConsoleDevice> = SpinMutex::new(StaticConsoleDevice(None));
+pub static CONSOLE: SpinMutex<StaticConsoleDevice> = SpinMutex::new(StaticConsoleDevice::new(None));
+
+/// Set when the transmit interrupt can't be relied on anymore (e.g. while
+/// panicking), after which all console output is written synchronously
+static SYNCHRONOUS: AtomicBool = AtomicBool::new(false);
+
+/// Stop queueing console output, flushing anything already queued the next
+/// time the console is written to
+pub fn force_synchronous() {
+    SYNCHRONOUS.store(true, Ordering::Relaxed);
+}
+
+struct TxFifo {
+    buffer: [u8; TX_FIFO_LEN],
+    head: usize,
+    len: usize,
+}
+
+impl TxFifo {
+    const fn new() -> Self {
+        Self { buffer: [0; TX_FIFO_LEN], head: 0, len: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == TX_FIFO_LEN
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.buffer[(self.head + self.len) % TX_FIFO_LEN] = byte;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let byte = self.buffer[self.head];
+        self.head = (self.head + 1) % TX_FIFO_LEN;
+        self.len -= 1;
+
+        Some(byte)
+    }
+}
 
 /// # Safety
 ///
@@ -77,26 +239,35 @@ pub unsafe fn set_raw_console<T: ConsoleDevice>(device: *mut T) {
     let device = &mut *device;
     device.init();
 
-    *CONSOLE.lock() = StaticConsoleDevice(Some(device));
+    *CONSOLE.lock() = StaticConsoleDevice::new(Some(device));
 }
 
 pub fn set_console(device: &'static mut dyn ConsoleDevice) {
     device.init();
 
-    *CONSOLE.lock() = StaticConsoleDevice(Some(device));
+    *CONSOLE.lock() = StaticConsoleDevice::new(Some(device));
 }
 
 pub enum ConsoleDevices {
     Uart16550,
     SifiveUart,
+    VirtioConsole,
 }
 
 impl ConsoleDevices {
     pub fn from_compatible(compatible: fdt::standard_nodes::Compatible<'_>) -> Option<Self> {
-        if compatible.all().any(|s| Uart16550::compatible_with().contains(&s)) {
+        Self::from_compatible_strs(compatible.all())
+    }
+
+    pub fn from_compatible_strs<'a>(compatible: impl Iterator<Item = &'a str> + Clone) -> Option<Self> {
+        let any = |with: &[&str]| compatible.clone().any(|s| with.contains(&s));
+
+        if any(Uart16550::compatible_with()) {
             Some(ConsoleDevices::Uart16550)
-        } else if compatible.all().any(|s| SifiveUart::compatible_with().contains(&s)) {
+        } else if any(SifiveUart::compatible_with()) {
             Some(ConsoleDevices::SifiveUart)
+        } else if any(VirtioConsole::compatible_with()) {
+            Some(ConsoleDevices::VirtioConsole)
         } else {
             None
         }
@@ -106,9 +277,12 @@ impl ConsoleDevices {
     ///
     /// `ptr` must be a valid instance of the device described by the variant in `self`
     pub unsafe fn set_raw_console(&self, ptr: *mut u8) {
+        CONSOLE_ADDRESS.store(ptr as usize, Ordering::Release);
+
         match self {
             ConsoleDevices::Uart16550 => set_raw_console(ptr as *mut Uart16550),
             ConsoleDevices::SifiveUart => set_raw_console(ptr as *mut SifiveUart),
+            ConsoleDevices::VirtioConsole => set_raw_console(ptr as *mut VirtioConsole),
         }
     }
 
@@ -116,23 +290,94 @@ impl ConsoleDevices {
         match self {
             ConsoleDevices::Uart16550 => register_isr(interrupt_id, console_interrupt),
             ConsoleDevices::SifiveUart => register_isr(interrupt_id, console_interrupt),
+            ConsoleDevices::VirtioConsole => register_isr(interrupt_id, console_interrupt),
         }
 
-        if let Some(plic) = &*crate::interrupts::PLIC.lock() {
-            plic.enable_interrupt(crate::platform::current_plic_context(), interrupt_id);
-            plic.set_interrupt_priority(interrupt_id, 1);
+        if let Some((controller, source)) = crate::interrupts::resolve(interrupt_id) {
+            controller.enable_interrupt(crate::HART_ID.get(), source);
+            controller.set_interrupt_priority(source, 1);
+            CONSOLE.lock().enable_tx_interrupts();
         }
     }
 }
 
-fn console_interrupt(
-    _: &crate::drivers::generic::plic::Plic,
-    claim: crate::drivers::generic::plic::InterruptClaim<'_>,
-    _: usize,
-) -> Result<(), &'static str> {
-    let c = CONSOLE.lock().read();
-    claim.complete();
-    super::INPUT_QUEUE.push(c).map_err(|_| "failed to write to input queue")
+/// Bind the console's interrupts once the interrupt controllers are up. Only
+/// the device currently in use as the console is bound, any others with the
+/// same compatible strings are left for userspace.
+pub fn probe(_: &Fdt<'_>, device: &Device) -> Result<(), ProbeError> {
+    let console =
+        ConsoleDevices::from_compatible_strs(device.compatible.iter().map(|s| &**s)).ok_or(ProbeError::NotSupported)?;
+    let region = device.regions.first().ok_or(ProbeError::NotSupported)?;
+
+    if phys2virt(PhysicalAddress::new(region.address)).as_usize() != CONSOLE_ADDRESS.load(Ordering::Acquire) {
+        return Err(ProbeError::NotSupported);
+    }
+
+    for interrupt in device.interrupts() {
+        console.register_isr(interrupt);
+    }
+
+    Ok(())
+}
+
+/// `Ctrl-]`, which begins a kernel console command when followed by one of the
+/// keys in [`super::sysrq`]. Pressing it twice sends a single literal `Ctrl-]`.
+pub const ESCAPE: u8 = 0x1D;
+
+static ESCAPE_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Input read from the console device by its ISR, waiting to be handled
+static PENDING_INPUT: Lazy<ArrayQueue<u8>> = Lazy::new(|| ArrayQueue::new(RX_PENDING_LEN));
+
+fn console_interrupt(_: &InterruptClaim, _: usize) -> Result<IsrStatus, &'static str> {
+    let mut console = CONSOLE.lock();
+    console.acknowledge_interrupt();
+    console.drain_tx();
+
+    // Input is pulled off of the device here to quiet the interrupt, but
+    // handling it may echo it back, log, or start the monitor, all of which
+    // need the console lock. Anything that doesn't fit is left in the device
+    // until the next interrupt.
+    let mut received = false;
+    while !PENDING_INPUT.is_full() {
+        match console.try_read() {
+            Some(c) => {
+                let _ = PENDING_INPUT.push(c);
+                received = true;
+            }
+            None => break,
+        }
+    }
+    drop(console);
+
+    if received {
+        crate::interrupts::deferred::defer(handle_pending_input);
+    }
+
+    Ok(IsrStatus::Handled)
+}
+
+fn handle_pending_input() {
+    while let Some(c) = PENDING_INPUT.pop() {
+        if let Err(e) = receive_input(c) {
+            log::error!("Error handling console input: {}", e);
+        }
+    }
+}
+
+/// Handle a byte of console input, from the console device or from a keyboard
+/// driven by userspace
+pub fn receive_input(c: u8) -> Result<(), &'static str> {
+    let push = super::line_discipline::input;
+    match (ESCAPE_PENDING.swap(false, Ordering::AcqRel), c) {
+        (false, ESCAPE) => ESCAPE_PENDING.store(true, Ordering::Release),
+        (false, c) | (true, c @ ESCAPE) => push(c)?,
+        (true, c) if super::sysrq::handle(c) => {}
+        // Not a command, pass both through
+        (true, c) => push(ESCAPE).and_then(|_| push(c))?,
+    }
+
+    Ok(())
 }
 
 pub struct LegacySbiConsoleOut;
@@ -144,6 +389,10 @@ impl ConsoleDevice for LegacySbiConsoleOut {
         sbi::legacy::console_getchar().unwrap_or(0)
     }
 
+    fn try_read(&self) -> Option<u8> {
+        sbi::legacy::console_getchar()
+    }
+
     fn write(&mut self, n: u8) {
         sbi::legacy::console_putchar(n)
     }
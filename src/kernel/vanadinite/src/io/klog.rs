@@ -0,0 +1,165 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A copy of everything logged by the kernel in a ring buffer which can be
+//! mapped read-only into a privileged logger task, so that it can stream the
+//! log elsewhere without making a syscall per record. See
+//! [`librust::syscalls::klog`] for the layout of the shared pages.
+
+use crate::{
+    mem::{
+        paging::PageSize,
+        phys2virt,
+        region::{SharedPhysicalRegion, UniquePhysicalRegion},
+    },
+    syscall::poll::{WaitQueue, Waitable, Waiter},
+};
+use alloc::sync::Arc;
+use core::{
+    fmt::Write,
+    sync::atomic::{fence, AtomicUsize, Ordering},
+};
+use librust::syscalls::{klog::KernelLogControl, poll::WaitEvents};
+use sync::SpinMutex;
+
+/// Number of 4 KiB pages of log kept in the ring
+const DATA_PAGES: usize = 16;
+/// Longest record kept, anything past this is cut off
+const MAX_RECORD_LEN: usize = 512;
+
+static RING: SpinMutex<Option<Ring>> = SpinMutex::new(None);
+static WAITERS: WaitQueue = WaitQueue::new();
+/// Where `head` was at the last timer tick, so that waiters are only woken
+/// when something's been logged since
+static LAST_HEAD: AtomicUsize = AtomicUsize::new(0);
+
+struct Ring {
+    control: SharedPhysicalRegion,
+    data: SharedPhysicalRegion,
+    capacity: usize,
+}
+
+impl Ring {
+    fn control(&self) -> &KernelLogControl {
+        control_of(&self.control)
+    }
+
+    fn data(&self) -> *mut u8 {
+        phys2virt(self.data.physical_addresses().next().unwrap()).as_mut_ptr()
+    }
+
+    fn push(&self, record: &[u8]) {
+        let control = self.control();
+        let head = control.head.load(Ordering::Relaxed);
+        let end = head + record.len();
+
+        // Let readers know what's about to be overwritten before touching it
+        control.reserved.store(end, Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        let start = head % self.capacity;
+        let first = record.len().min(self.capacity - start);
+        unsafe {
+            core::ptr::copy_nonoverlapping(record.as_ptr(), self.data().add(start), first);
+            core::ptr::copy_nonoverlapping(record.as_ptr().add(first), self.data(), record.len() - first);
+        }
+
+        control.head.store(end, Ordering::Release);
+    }
+}
+
+fn control_of(region: &SharedPhysicalRegion) -> &KernelLogControl {
+    unsafe { &*phys2virt(region.physical_addresses().next().unwrap()).as_ptr().cast() }
+}
+
+/// Allocate the ring, anything logged before this is only on the console
+pub fn init() {
+    let mut control = UniquePhysicalRegion::alloc_contiguous(PageSize::Kilopage, 1);
+    control.zero();
+    let mut data = UniquePhysicalRegion::alloc_contiguous(PageSize::Kilopage, DATA_PAGES);
+    data.zero();
+
+    let capacity = DATA_PAGES * 4096;
+    let control_ptr: *mut KernelLogControl =
+        phys2virt(control.physical_addresses().next().unwrap()).as_mut_ptr().cast();
+    unsafe { (*control_ptr).capacity = capacity };
+
+    let (control, data) = (control.into_shared_region(), data.into_shared_region());
+
+    *RING.lock() = Some(Ring { control, data, capacity });
+}
+
+/// The pages to map into the logger, the control page followed by the data
+pub fn regions() -> Option<(SharedPhysicalRegion, SharedPhysicalRegion)> {
+    RING.lock().as_ref().map(|ring| (ring.control.clone(), ring.data.clone()))
+}
+
+/// Add a record to the ring, a newline is added to the end of it
+pub fn record(args: core::fmt::Arguments) {
+    let mut record = Record { bytes: [0; MAX_RECORD_LEN], len: 0 };
+    let _ = record.write_fmt(args);
+
+    let len = record.len.min(MAX_RECORD_LEN - 1);
+    record.bytes[len] = b'\n';
+
+    if let Some(ring) = &*RING.lock() {
+        ring.push(&record.bytes[..len + 1]);
+    }
+}
+
+/// Wake up the logger if anything's been logged since the last tick, called
+/// on each timer tick
+pub fn tick() {
+    let head = match &*RING.lock() {
+        Some(ring) => ring.control().head.load(Ordering::Relaxed),
+        None => return,
+    };
+
+    if LAST_HEAD.swap(head, Ordering::Relaxed) != head {
+        WAITERS.wake_all();
+    }
+}
+
+/// The kernel log capability, which is readable whenever the logger hasn't
+/// caught up with the kernel
+pub struct KernelLog;
+
+impl Waitable for KernelLog {
+    fn poll(&self, interest: WaitEvents) -> WaitEvents {
+        let unread = match &*RING.lock() {
+            Some(ring) => {
+                let control = ring.control();
+                control.head.load(Ordering::Acquire) != control.tail.load(Ordering::Relaxed)
+            }
+            None => false,
+        };
+
+        match unread && interest & WaitEvents::READABLE {
+            true => WaitEvents::READABLE,
+            false => WaitEvents::NONE,
+        }
+    }
+
+    fn register(&self, waiter: &Arc<Waiter>) {
+        WAITERS.register(waiter);
+    }
+}
+
+struct Record {
+    bytes: [u8; MAX_RECORD_LEN],
+    len: usize,
+}
+
+impl Write for Record {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = s.len().min(MAX_RECORD_LEN - self.len);
+        self.bytes[self.len..][..n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+
+        Ok(())
+    }
+}
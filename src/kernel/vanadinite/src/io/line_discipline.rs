@@ -0,0 +1,210 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Processing of console input before it reaches the input queue of the active
+//! console, see [`super::mux`]. In cooked mode input is echoed and edited a
+//! line at a time, and `Ctrl-C` interrupts the foreground task. In raw mode
+//! bytes are queued untouched. Each task has its own [`ConsoleMode`], which
+//! becomes the active one when it reads from the console.
+
+use crate::{
+    scheduler::{self, Scheduler, SCHEDULER, TASKS},
+    syscall::{events::post_event, exit::notify_watchers},
+};
+use alloc::vec::Vec;
+use core::{
+    num::NonZeroUsize,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use librust::{
+    syscalls::io::ConsoleMode,
+    task::{ExitStatus, TaskEvent, Tid},
+};
+use sync::SpinMutex;
+
+/// `Ctrl-C`
+pub const INTERRUPT: u8 = 0x03;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+/// `Ctrl-U`
+const KILL_LINE: u8 = 0x15;
+/// `Ctrl-W`
+const ERASE_WORD: u8 = 0x17;
+const LINE_MAX: usize = 1024;
+
+static LINE_DISCIPLINE: SpinMutex<LineDiscipline> = SpinMutex::new(LineDiscipline::new());
+
+/// The task which is interrupted by `Ctrl-C`, zero if there isn't one
+static FOREGROUND_TASK: AtomicUsize = AtomicUsize::new(0);
+/// Whether [`FOREGROUND_TASK`] was explicitly designated, otherwise it follows
+/// whichever task last read from the console
+static FOREGROUND_DESIGNATED: AtomicBool = AtomicBool::new(false);
+
+struct LineDiscipline {
+    mode: ConsoleMode,
+    line: Vec<u8>,
+}
+
+impl LineDiscipline {
+    const fn new() -> Self {
+        Self { mode: ConsoleMode::Cooked, line: Vec::new() }
+    }
+
+    fn input(&mut self, byte: u8) -> Result<(), &'static str> {
+        if self.mode == ConsoleMode::Raw {
+            return push(byte);
+        }
+
+        match byte {
+            INTERRUPT => {
+                self.line.clear();
+                echo(b"^C\r\n");
+                interrupt_foreground();
+            }
+            BACKSPACE | DELETE => {
+                self.erase_char();
+            }
+            KILL_LINE => while self.erase_char() {},
+            ERASE_WORD => {
+                while self.line.last() == Some(&b' ') {
+                    self.erase_char();
+                }
+
+                while self.line.last().map_or(false, |&c| c != b' ') {
+                    self.erase_char();
+                }
+            }
+            b'\r' | b'\n' => {
+                echo(b"\r\n");
+                self.line.push(b'\n');
+                return self.flush();
+            }
+            _ if self.line.len() < LINE_MAX => {
+                echo(&[byte]);
+                self.line.push(byte);
+            }
+            // Line is full, drop anything else until it's submitted
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Erase the last character of the current line, which may be more than
+    /// one byte long, returning `false` if the line is empty
+    fn erase_char(&mut self) -> bool {
+        if self.line.is_empty() {
+            return false;
+        }
+
+        while let Some(byte) = self.line.pop() {
+            // Stop at the first byte that isn't a UTF-8 continuation byte
+            if byte & 0xC0 != 0x80 {
+                break;
+            }
+        }
+
+        echo(b"\x08 \x08");
+        true
+    }
+
+    fn flush(&mut self) -> Result<(), &'static str> {
+        self.line.drain(..).try_for_each(push)
+    }
+}
+
+fn push(byte: u8) -> Result<(), &'static str> {
+    super::mux::route_input(byte)
+}
+
+fn echo(bytes: &[u8]) {
+    super::mux::echo(bytes);
+}
+
+/// Process a byte of console input
+pub fn input(byte: u8) -> Result<(), &'static str> {
+    LINE_DISCIPLINE.lock().input(byte)
+}
+
+/// Switch to the mode of the task reading from the console. Any partially
+/// edited line is made readable when switching to raw mode.
+pub fn set_mode(mode: ConsoleMode) {
+    let mut discipline = LINE_DISCIPLINE.lock();
+    if discipline.mode == mode {
+        return;
+    }
+
+    discipline.mode = mode;
+    if mode == ConsoleMode::Raw {
+        if let Err(e) = discipline.flush() {
+            log::warn!("Dropping console input while switching to raw mode: {}", e);
+        }
+    }
+}
+
+pub fn foreground() -> Option<Tid> {
+    NonZeroUsize::new(FOREGROUND_TASK.load(Ordering::Acquire)).map(Tid::new)
+}
+
+/// Designate `tid` as the foreground task
+pub fn set_foreground(tid: Tid) {
+    FOREGROUND_TASK.store(tid.value(), Ordering::Release);
+    FOREGROUND_DESIGNATED.store(true, Ordering::Release);
+}
+
+/// Make the task reading from the console the foreground task, unless one has
+/// been designated
+pub fn reader_foreground(tid: Tid) {
+    if !FOREGROUND_DESIGNATED.load(Ordering::Acquire) {
+        FOREGROUND_TASK.store(tid.value(), Ordering::Release);
+    }
+}
+
+/// Clear the foreground task if it's still `tid`, after which the next task to
+/// read from the console becomes the foreground task
+pub fn clear_foreground(tid: Tid) {
+    if FOREGROUND_TASK.compare_exchange(tid.value(), 0, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+        FOREGROUND_DESIGNATED.store(false, Ordering::Release);
+    }
+}
+
+fn interrupt_foreground() {
+    let tid = match foreground() {
+        Some(tid) => tid,
+        None => return,
+    };
+
+    let task = match TASKS.get(tid) {
+        Some(task) => task,
+        None => return clear_foreground(tid),
+    };
+
+    // This is run from the console ISR, so the task may already be locked on
+    // this hart
+    let mut task = match task.try_lock() {
+        Some(task) => task,
+        None => return log::warn!("Foreground task is locked, unable to interrupt it"),
+    };
+
+    let watchers = post_event(&mut task, TaskEvent::Interrupt);
+    let killed = task.state.is_dead();
+    drop(task);
+
+    if killed {
+        clear_foreground(tid);
+        notify_watchers(ExitStatus::Killed, watchers);
+        reschedule_if_active(tid);
+    }
+}
+
+/// Get `tid` off of this hart right away if it's running on it, instead of
+/// waiting for its time slice to expire
+pub fn reschedule_if_active(tid: Tid) {
+    if SCHEDULER.active_on_cpu().map_or(false, |active| active.try_lock().map_or(false, |t| t.tid == tid)) {
+        scheduler::request_reschedule();
+    }
+}
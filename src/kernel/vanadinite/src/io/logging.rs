@@ -133,6 +133,16 @@ impl log::Log for Logger {
                 mod_path,
                 record.args()
             );
+
+            crate::io::klog::record(format_args!(
+                "[{:>5}.{:<03}] [ {:>5} ] [HART {}] [{}] {}",
+                secs,
+                ms,
+                record.level(),
+                crate::HART_ID.get(),
+                mod_path,
+                record.args()
+            ));
         }
     }
 
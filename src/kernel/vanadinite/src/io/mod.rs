@@ -7,17 +7,18 @@
 
 pub mod block_device;
 pub mod console;
+pub mod klog;
+pub mod line_discipline;
 pub mod logging;
+pub mod mux;
+pub mod sysrq;
 pub mod terminal;
 
-use alloc::{collections::BTreeMap, string::String};
 pub use console::*;
 use core::fmt::Write;
 use crossbeam_queue::ArrayQueue;
-use librust::task::Tid;
 
 pub static INPUT_QUEUE: sync::Lazy<ArrayQueue<u8>> = sync::Lazy::new(|| ArrayQueue::new(4096));
-pub static CLAIMED_DEVICES: sync::SpinRwLock<BTreeMap<String, Tid>> = sync::SpinRwLock::new(BTreeMap::new());
 
 #[macro_export]
 macro_rules! print {
@@ -0,0 +1,320 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Console multiplexing. Every task's stdin and stdout is bound to a
+//! [`ConsoleStream`], which is either the physical console, one of the virtual
+//! consoles, an end of a pipe whose other end is read or written by another
+//! task, or nothing at all for output that's being thrown away.
+//!
+//! Only one virtual console is shown on the physical console at a time, the
+//! rest keep a scrollback buffer which is replayed when switching to them.
+//! Console input goes to whichever virtual console is active, or to the
+//! physical console's input queue if none are.
+
+use super::{ConsoleDevice, CONSOLE, INPUT_QUEUE};
+use crate::syscall::poll::{WaitQueue, Waitable, Waiter};
+use alloc::{collections::BTreeMap, collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crossbeam_queue::ArrayQueue;
+use librust::syscalls::{io::MAX_VIRTUAL_CONSOLES, poll::WaitEvents};
+use sync::SpinMutex;
+
+const PIPE_CAPACITY: usize = 4096;
+const SCROLLBACK_LEN: usize = 16 * 1024;
+const INPUT_LEN: usize = 4096;
+
+static VIRTUAL_CONSOLES: SpinMutex<BTreeMap<usize, Arc<VirtualConsole>>> = SpinMutex::new(BTreeMap::new());
+/// The virtual console being shown, zero if it's the physical console
+static ACTIVE_CONSOLE: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Default)]
+pub enum ConsoleStream {
+    #[default]
+    Physical,
+    Virtual(Arc<VirtualConsole>),
+    Pipe(PipeEnd),
+    Null,
+}
+
+impl ConsoleStream {
+    /// Write as many bytes as will fit, returning how many were written
+    pub fn write(&self, bytes: &[u8]) -> usize {
+        match self {
+            ConsoleStream::Physical => {
+                let mut console = CONSOLE.lock();
+                bytes.iter().for_each(|&b| console.write(b));
+                bytes.len()
+            }
+            ConsoleStream::Virtual(vc) => vc.write(bytes),
+            ConsoleStream::Pipe(pipe) => pipe.write(bytes),
+            ConsoleStream::Null => bytes.len(),
+        }
+    }
+
+    /// Read any available bytes without blocking, returning how many were read
+    pub fn read(&self, buffer: &mut [u8]) -> usize {
+        let queue = match self {
+            ConsoleStream::Physical => &**INPUT_QUEUE,
+            ConsoleStream::Virtual(vc) => &vc.input,
+            ConsoleStream::Pipe(pipe) => return pipe.read(buffer),
+            ConsoleStream::Null => return 0,
+        };
+
+        buffer.iter_mut().map_while(|byte| queue.pop().map(|value| *byte = value)).count()
+    }
+
+    /// Whether input for this stream comes from the keyboard, and so goes
+    /// through the [line discipline](super::line_discipline)
+    pub fn is_interactive(&self) -> bool {
+        !matches!(self, ConsoleStream::Pipe(_) | ConsoleStream::Null)
+    }
+
+    /// Whether the other end of the stream has gone away, so a read or write
+    /// that doesn't make progress never will. Reading from [`ConsoleStream::Null`]
+    /// is always at the end.
+    pub fn is_closed(&self) -> bool {
+        match self {
+            ConsoleStream::Physical | ConsoleStream::Virtual(_) => false,
+            ConsoleStream::Pipe(end) => end.is_closed(),
+            ConsoleStream::Null => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VirtualConsole {
+    id: usize,
+    input: ArrayQueue<u8>,
+    scrollback: SpinMutex<VecDeque<u8>>,
+}
+
+impl VirtualConsole {
+    fn write(&self, bytes: &[u8]) -> usize {
+        let mut scrollback = self.scrollback.lock();
+        for &byte in bytes {
+            if scrollback.len() == SCROLLBACK_LEN {
+                scrollback.pop_front();
+            }
+
+            scrollback.push_back(byte);
+        }
+
+        // Keep the scrollback locked while writing so that output can't be
+        // reordered by switching consoles at the same time
+        if ACTIVE_CONSOLE.load(Ordering::Acquire) == self.id {
+            let mut console = CONSOLE.lock();
+            bytes.iter().for_each(|&b| console.write(b));
+        }
+
+        bytes.len()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Pipe {
+    buffer: SpinMutex<VecDeque<u8>>,
+    /// How many ends can read from and write to the pipe, once either drops to
+    /// zero the other side sees the pipe as closed
+    readers: AtomicUsize,
+    writers: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Pipe {
+    fn write(&self, bytes: &[u8]) -> usize {
+        // Nobody is left to read it
+        if self.readers.load(Ordering::Acquire) == 0 {
+            return 0;
+        }
+
+        let mut buffer = self.buffer.lock();
+        let n = bytes.len().min(PIPE_CAPACITY - buffer.len());
+        buffer.extend(&bytes[..n]);
+        drop(buffer);
+
+        if n > 0 {
+            self.waiters.wake_all();
+        }
+
+        n
+    }
+
+    fn read(&self, bytes: &mut [u8]) -> usize {
+        let mut buffer = self.buffer.lock();
+        let n = bytes.len().min(buffer.len());
+        for (byte, value) in bytes.iter_mut().zip(buffer.drain(..n)) {
+            *byte = value;
+        }
+        drop(buffer);
+
+        // Writers may be waiting for room
+        if n > 0 {
+            self.waiters.wake_all();
+        }
+
+        n
+    }
+}
+
+/// A handle to a [`Pipe`] which can read from it, write to it, or both. Every
+/// copy of an end, whether it's a capability or a task's stdin or stdout, keeps
+/// its side of the pipe open until it's dropped.
+#[derive(Debug)]
+pub struct PipeEnd {
+    pipe: Arc<Pipe>,
+    reads: bool,
+    writes: bool,
+}
+
+impl PipeEnd {
+    fn new(pipe: Arc<Pipe>, reads: bool, writes: bool) -> Self {
+        if reads {
+            pipe.readers.fetch_add(1, Ordering::AcqRel);
+        }
+
+        if writes {
+            pipe.writers.fetch_add(1, Ordering::AcqRel);
+        }
+
+        Self { pipe, reads, writes }
+    }
+
+    /// A new pipe, returning its read end and write end
+    pub fn pair() -> (Self, Self) {
+        let pipe = Arc::new(Pipe::default());
+        (Self::new(Arc::clone(&pipe), true, false), Self::new(pipe, false, true))
+    }
+
+    /// A new pipe which is read and written through the same end, for
+    /// capturing the output of a task. It's never closed while the end is held.
+    pub fn loopback() -> Self {
+        Self::new(Arc::new(Pipe::default()), true, true)
+    }
+
+    fn write(&self, bytes: &[u8]) -> usize {
+        match self.writes {
+            true => self.pipe.write(bytes),
+            false => 0,
+        }
+    }
+
+    fn read(&self, bytes: &mut [u8]) -> usize {
+        match self.reads {
+            true => self.pipe.read(bytes),
+            false => 0,
+        }
+    }
+
+    /// Whether every end on the other side of the pipe has been dropped
+    fn is_closed(&self) -> bool {
+        (self.reads && self.pipe.writers.load(Ordering::Acquire) == 0)
+            || (self.writes && self.pipe.readers.load(Ordering::Acquire) == 0)
+    }
+}
+
+impl Clone for PipeEnd {
+    fn clone(&self) -> Self {
+        Self::new(Arc::clone(&self.pipe), self.reads, self.writes)
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        let last_reader = self.reads && self.pipe.readers.fetch_sub(1, Ordering::AcqRel) == 1;
+        let last_writer = self.writes && self.pipe.writers.fetch_sub(1, Ordering::AcqRel) == 1;
+
+        if last_reader || last_writer {
+            self.pipe.waiters.wake_all();
+        }
+    }
+}
+
+impl Waitable for PipeEnd {
+    fn poll(&self, interest: WaitEvents) -> WaitEvents {
+        let buffer = self.pipe.buffer.lock();
+        let mut ready = WaitEvents::NONE;
+
+        if self.reads && interest & WaitEvents::READABLE && !buffer.is_empty() {
+            ready |= WaitEvents::READABLE;
+        }
+
+        if self.writes && interest & WaitEvents::WRITABLE && buffer.len() < PIPE_CAPACITY {
+            ready |= WaitEvents::WRITABLE;
+        }
+
+        if self.is_closed() {
+            ready |= WaitEvents::CLOSED;
+        }
+
+        ready
+    }
+
+    fn register(&self, waiter: &Arc<Waiter>) {
+        self.pipe.waiters.register(waiter);
+    }
+}
+
+/// Get the virtual console with the given ID, creating it if it doesn't exist
+pub fn virtual_console(id: usize) -> Option<Arc<VirtualConsole>> {
+    if !(1..=MAX_VIRTUAL_CONSOLES).contains(&id) {
+        return None;
+    }
+
+    let vc = VIRTUAL_CONSOLES.lock().entry(id).or_insert_with(|| {
+        Arc::new(VirtualConsole { id, input: ArrayQueue::new(INPUT_LEN), scrollback: SpinMutex::new(VecDeque::new()) })
+    });
+
+    Some(Arc::clone(vc))
+}
+
+/// Show the virtual console with the given ID, or the physical console if it's
+/// zero, returning `false` if it doesn't exist
+pub fn switch_to(id: usize) -> bool {
+    let vc = match id {
+        0 => None,
+        id => match VIRTUAL_CONSOLES.lock().get(&id) {
+            Some(vc) => Some(Arc::clone(vc)),
+            None => return false,
+        },
+    };
+
+    let scrollback = vc.as_ref().map(|vc| vc.scrollback.lock());
+    ACTIVE_CONSOLE.store(id, Ordering::Release);
+
+    // Clear the screen and replay whatever the console has written so far
+    let mut console = CONSOLE.lock();
+    b"\x1B[2J\x1B[H".iter().for_each(|&b| console.write(b));
+    if let Some(scrollback) = scrollback {
+        scrollback.iter().for_each(|&b| console.write(b));
+    }
+
+    true
+}
+
+fn active() -> Option<Arc<VirtualConsole>> {
+    match ACTIVE_CONSOLE.load(Ordering::Acquire) {
+        0 => None,
+        id => VIRTUAL_CONSOLES.lock().get(&id).cloned(),
+    }
+}
+
+/// Write to the active console, so that echoed input is kept in its scrollback
+pub fn echo(bytes: &[u8]) {
+    match active() {
+        Some(vc) => ConsoleStream::Virtual(vc).write(bytes),
+        None => ConsoleStream::Physical.write(bytes),
+    };
+}
+
+/// Queue a byte of console input for the active console
+pub fn route_input(byte: u8) -> Result<(), &'static str> {
+    match active() {
+        Some(vc) => vc.input.push(byte),
+        None => INPUT_QUEUE.push(byte),
+    }
+    .map_err(|_| "failed to write to input queue")
+}
@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Emergency console commands, entered with [`ESCAPE`](super::ESCAPE)
+//! followed by a single key. They're handled directly in the console ISR, so
+//! they work even when userspace is wedged or the system is under heavy load.
+
+use super::{line_discipline, mux};
+use crate::{
+    monitor::Monitor,
+    println,
+    scheduler::{Scheduler, SCHEDULER, TASKS},
+    syscall::exit::notify_watchers,
+};
+use alloc::vec::Vec;
+use librust::{
+    message::{KernelNotification, Message, Sender},
+    task::ExitStatus,
+};
+
+const COMMANDS: &[(u8, &str)] = &[
+    (b'h', "show this help"),
+    (b'k', "enter the kernel monitor"),
+    (b't', "dump the state of all tasks"),
+    (b'c', "kill the foreground task"),
+    (b's', "ask all tasks to sync their filesystems"),
+    (b'b', "reboot immediately"),
+    (b'o', "power off immediately"),
+    (b'0', "show the physical console"),
+    (b'1', "show virtual console 1 (up to 8)"),
+];
+
+/// Handle the key following the escape byte, returning `false` if it isn't a
+/// known command
+pub fn handle(key: u8) -> bool {
+    match key {
+        b'h' => {
+            println!("\nConsole commands (Ctrl-] followed by a key):");
+            for (key, description) in COMMANDS {
+                println!("  {}  {}", *key as char, description);
+            }
+        }
+        b'k' => match Monitor::attach() {
+            Some(mut monitor) => monitor.run_break(),
+            None => log::warn!("Console is locked, unable to start the monitor"),
+        },
+        b't' => match Monitor::attach() {
+            Some(mut monitor) => {
+                let _ = monitor.tasks();
+            }
+            None => log::warn!("Console is locked, unable to dump tasks"),
+        },
+        b'c' => kill_foreground(),
+        b's' => {
+            let mut notified = 0;
+            let unlocked = TASKS.try_for_each(|_, task| {
+                // Pushing the notification wakes tasks waiting in
+                // `read_message`, which is where the filesystem server waits
+                if let Some(mut task) = task.try_lock() {
+                    if !task.state.is_dead() {
+                        task.message_queue.push(Sender::kernel(), Message::from(KernelNotification::SyncRequested));
+                        notified += 1;
+                    }
+                }
+            });
+
+            match unlocked {
+                true => println!("\nRequested sync from {} tasks", notified),
+                false => println!("\nTask list is locked, unable to request sync"),
+            }
+        }
+        b'b' => crate::panicking::reboot(),
+        b'o' => crate::platform::exit(crate::platform::ExitStatus::Ok),
+        b'0'..=b'8' => {
+            let id = usize::from(key - b'0');
+            if !mux::switch_to(id) {
+                println!("\nVirtual console {} hasn't been created", id);
+            }
+        }
+        _ => return false,
+    }
+
+    true
+}
+
+fn kill_foreground() {
+    let tid = match line_discipline::foreground() {
+        Some(tid) => tid,
+        None => return println!("\nNo foreground task to kill"),
+    };
+
+    let task = match TASKS.get(tid) {
+        Some(task) => task,
+        None => return println!("\nForeground task no longer exists"),
+    };
+
+    let mut task = match task.try_lock() {
+        Some(task) => task,
+        None => return println!("\nForeground task is locked, unable to kill"),
+    };
+
+    let mut watchers = Vec::new();
+    if !task.state.is_dead() {
+        println!("\nKilling task {} ({})", tid.value(), task.display_name());
+        watchers = task.exit(ExitStatus::Killed);
+        SCHEDULER.dequeue(tid);
+    }
+
+    line_discipline::clear_foreground(tid);
+    drop(task);
+    notify_watchers(ExitStatus::Killed, watchers);
+    line_discipline::reschedule_if_active(tid);
+}
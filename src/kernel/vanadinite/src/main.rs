@@ -11,6 +11,7 @@
     alloc_error_handler,
     allocator_api,
     arbitrary_self_types,
+    asm_const,
     asm_sym,
     const_btree_new,
     custom_test_frameworks,
@@ -37,27 +38,36 @@ extern crate alloc;
 extern crate vanadinite_macros;
 
 pub mod asm;
+pub mod backtrace;
 pub mod boot;
 pub mod capabilities;
+pub mod coverage;
 pub mod cpu_local;
+pub mod crash;
+pub mod crypto;
 pub mod csr;
+pub mod device;
 pub mod drivers;
 pub mod interrupts;
 pub mod io;
 pub mod mem;
+pub mod metrics;
+pub mod monitor;
+pub mod panicking;
+pub mod perf;
 pub mod platform;
 pub mod scheduler;
 pub mod syscall;
 pub mod task;
 #[cfg(debug_assertions)]
 pub mod tests;
+pub mod timepage;
+pub mod trace;
 pub mod trap;
 pub mod utils;
 
 use {
     core::sync::atomic::{AtomicUsize, Ordering},
-    drivers::{generic::plic::Plic, CompatibleWith},
-    interrupts::PLIC,
     mem::{
         kernel_patching,
         paging::{PhysicalAddress, VirtualAddress},
@@ -77,6 +87,7 @@ pub use vanadinite_macros::{debug, error, info, trace, warn};
 
 static N_CPUS: AtomicUsize = AtomicUsize::new(1);
 static TIMER_FREQ: AtomicU64 = AtomicU64::new(0);
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
 static INIT: &[u8] = include_bytes!("../../../../build/init");
 
 #[thread_local]
@@ -85,6 +96,7 @@ static HART_ID: core::cell::Cell<usize> = core::cell::Cell::new(0);
 #[no_mangle]
 #[repr(align(4))]
 extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
+    BOOT_TIME.store(csr::time::read(), Ordering::Relaxed);
     csr::stvec::set(trap::stvec_trap_shim);
 
     unsafe { cpu_local::init_thread_locals() };
@@ -92,38 +104,48 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
 
     io::logging::init_logging();
 
-    let (heap_start, heap_end) = mem::heap::HEAP_ALLOCATOR.init(64.mib());
+    let (heap_start, heap_end) = mem::heap::HEAP_ALLOCATOR.init(8.mib());
+    io::klog::init();
 
+    let fdt = platform::devicetree::init(fdt);
     platform::FDT.store(fdt, Ordering::Release);
     let fdt: Fdt<'static> = match unsafe { Fdt::from_ptr(fdt) } {
         Ok(fdt) => fdt,
         Err(e) => platform::exit(platform::ExitStatus::Error(&e)),
     };
 
+    // Some firmware leaves out the timebase frequency or gets it wrong, it's
+    // calibrated once the devices have been probed if there's a clock to
+    // calibrate it against
     let current_cpu = fdt.cpus().find(|cpu| cpu.ids().first() == hart_id).unwrap();
-    let timebase_frequency = current_cpu.timebase_frequency();
-    TIMER_FREQ.store(timebase_frequency as u64, Ordering::Relaxed);
+    let timebase_frequency = current_cpu
+        .property("timebase-frequency")
+        .or_else(|| fdt.find_node("/cpus")?.property("timebase-frequency"))
+        .and_then(|p| p.as_usize())
+        .filter(|&frequency| frequency != 0)
+        .map(|frequency| frequency as u64);
+    TIMER_FREQ.store(timebase_frequency.unwrap_or(platform::clock::FALLBACK_TIMEBASE_FREQUENCY), Ordering::Relaxed);
+
+    crypto::rand::init(&fdt);
+    platform::pstore::init(&fdt);
+    device::init(&fdt);
 
-    let mut stdout_interrupts = None;
     let stdout = fdt.chosen().stdout();
-    if let Some((node, reg, compatible)) = stdout.and_then(|n| Some((n, n.reg()?.next()?, n.compatible()?))) {
+    if let Some((reg, compatible)) = stdout.and_then(|n| Some((n.reg()?.next()?, n.compatible()?))) {
         let stdout_addr = reg.starting_address as *mut u8;
 
         if let Some(device) = io::ConsoleDevices::from_compatible(compatible) {
             let stdout_phys = PhysicalAddress::from_ptr(stdout_addr);
             let ptr = phys2virt(stdout_phys);
 
+            // Try to get stdout loaded ASAP, its interrupts are registered
+            // later on when it's probed once the interrupt controllers are up
             unsafe { device.set_raw_console(ptr.as_mut_ptr()) };
-
-            if let Some(interrupts) = node.interrupts() {
-                // Try to get stdout loaded ASAP, so register interrupts later
-                // on if there are any
-                stdout_interrupts = Some((device, interrupts));
-            }
         }
     }
 
     let mut init_args = None;
+    let mut init_from_sd = false;
     if let Some(args) = fdt.chosen().bootargs() {
         let split_args = args.split(' ').map(|s| {
             let mut parts = s.splitn(2, '=');
@@ -137,7 +159,18 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
                     Some(path) => init_args = Some(path.split(',')),
                     None => log::warn!("No path provided for init process! Defaulting to `init`"),
                 },
+                "root" => match value {
+                    Some("sd") => init_from_sd = true,
+                    Some(root) => log::warn!("Unknown root device `{}`, using the built-in init", root),
+                    None => log::warn!("No root device provided, using the built-in init"),
+                },
                 "no-color" | "no-colour" => io::logging::USE_COLOR.store(false, Ordering::Relaxed),
+                "panic" => panicking::parse_panic_policy(value),
+                "idle" => platform::idle::parse_idle_mode(value),
+                "cpufreq" => platform::cpufreq::parse_governor(value),
+                "scheduler" => scheduler::parse_scheduler(value),
+                "trace" => trace::parse_trace_events(value),
+                "audit" => syscall::hooks::register(&syscall::hooks::Audit),
                 "console" => match value {
                     Some("sbi") => {
                         if let ExtensionAvailability::Available(_) = probe_extension(sbi::legacy::CONSOLE_PUTCHAR_EID) {
@@ -146,8 +179,8 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
                         }
                     }
                     Some(fdt_node) => {
-                        if let Some((node, reg, compatible)) =
-                            fdt.find_node(fdt_node).and_then(|n| Some((n, n.reg()?.next()?, n.compatible()?)))
+                        if let Some((reg, compatible)) =
+                            fdt.find_node(fdt_node).and_then(|n| Some((n.reg()?.next()?, n.compatible()?)))
                         {
                             let stdout_addr = reg.starting_address as *mut u8;
 
@@ -156,12 +189,6 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
                                 let ptr = phys2virt(stdout_phys);
 
                                 unsafe { device.set_raw_console(ptr.as_mut_ptr()) };
-
-                                if let Some(interrupts) = node.interrupts() {
-                                    // Try to get stdout loaded ASAP, so register interrupts later
-                                    // on if there are any
-                                    stdout_interrupts = Some((device, interrupts));
-                                }
                             }
                         }
                     }
@@ -214,7 +241,8 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
     info!(" Device Model: {}", model);
     info!(" Total CPUs: {}", n_cpus);
     info!(" RAM: {} MiB @ {:#X}", mem_size, mem_start as usize);
-    info!(" Timer Clock: {}Hz", timebase_frequency);
+    info!(" Timer Clock: {}Hz", TIMER_FREQ.load(Ordering::Relaxed));
+    info!(" Last Reset: {}", platform::pstore::last_reset().as_str());
     for memory_reservation in fdt.memory_reservations() {
         if first_mem_resv {
             info!(" Reserved Memory Regions:");
@@ -234,46 +262,22 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
     info!(" stvec_trap_shim: {:#p}", trap::stvec_trap_shim as *const u8);
     info!(" Heap region: {:#p}-{:#p}", heap_start, heap_end);
     info!(" Paging scheme: {:?}", csr::satp::read().mode);
+    info!(" Scheduler: {:?}", scheduler::selected());
 
-    if let Some(ic) = fdt.find_compatible(Plic::compatible_with()) {
-        let reg = ic.reg().unwrap().next().unwrap();
-        let ic_phys = PhysicalAddress::from_ptr(reg.starting_address);
-        let ic_virt = phys2virt(ic_phys);
-
-        // Number of interrupts available
-        let ndevs = ic
-            .properties()
-            .find(|p| p.name == "riscv,ndev")
-            .and_then(|p| p.as_usize())
-            .expect("missing number of interrupts");
-
-        // Find harts which have S-mode available
-        let contexts = fdt
-            .cpus()
-            .filter(|cpu| {
-                cpu.properties()
-                    .find(|p| p.name == "riscv,isa")
-                    .and_then(|p| p.as_str()?.chars().find(|c| *c == 's'))
-                    .is_some()
-            })
-            .map(|cpu| platform::plic_context_for(cpu.ids().first()));
-
-        let plic = unsafe { &*ic_virt.as_ptr().cast::<Plic>() };
-
-        plic.init(ndevs, contexts);
-        plic.set_context_threshold(platform::current_plic_context(), 0);
-        plic.enable_interrupt(platform::current_plic_context(), 8);
-        plic.set_interrupt_priority(8, 7);
+    interrupts::probe(&fdt);
+    interrupts::set_hart_threshold(0);
 
-        debug!("Registering PLIC @ {:#p}", ic_virt);
-        interrupts::register_plic(plic);
+    if let Some((controller, source)) = interrupts::resolve(8) {
+        controller.enable_interrupt(HART_ID.get(), source);
+        controller.set_interrupt_priority(source, 7);
     }
 
-    if let Some((device, interrupts)) = stdout_interrupts {
-        for interrupt in interrupts {
-            device.register_isr(interrupt);
-        }
-    }
+    device::probe_all(&fdt);
+    timepage::init();
+    platform::clock::calibrate(timebase_frequency);
+    platform::cpufreq::init();
+    perf::init(&fdt);
+    trace::init();
 
     let ptr = Box::leak(Box::new(task::ThreadControlBlock {
         kernel_stack: mem::alloc_kernel_stack(8.kib()),
@@ -283,6 +287,7 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
         saved_tp: 0,
         saved_gp: 0,
         kernel_stack_size: 8.kib(),
+        scratch_stack: mem::alloc_kernel_stack(mem::SCRATCH_STACK_SIZE),
     }));
 
     csr::sscratch::write(ptr as *mut _ as usize);
@@ -295,12 +300,25 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
 
     csr::sstatus::set_fs(csr::sstatus::FloatingPointStatus::Initial);
     csr::sie::enable();
+    mem::tlb::hart_online();
+    mem::phys::reserve::replenish();
 
     //scheduler::init_scheduler(Box::new(scheduler::round_robin::RoundRobinScheduler::new()));
 
+    let init: &'static [u8] = match init_from_sd {
+        true => match platform::storage::load_init(&fdt) {
+            Ok(init) => Box::leak(init.into_boxed_slice()),
+            Err(e) => {
+                log::warn!("Unable to load init from the SD card ({}), using the built-in init", e);
+                INIT
+            }
+        },
+        false => INIT,
+    };
+
     scheduler::SCHEDULER.enqueue(task::Task::load(
         "init",
-        &elf64::Elf::new(INIT).unwrap(),
+        &elf64::Elf::new(init).unwrap(),
         init_args.into_iter().flatten(),
     ));
 
@@ -329,9 +347,7 @@ extern "C" fn kalt(hart_id: usize) -> ! {
 
     info!(brightgreen, "Hart {} successfully booted", HART_ID.get());
 
-    if let Some(plic) = &*PLIC.lock() {
-        plic.set_context_threshold(platform::current_plic_context(), 0);
-    }
+    interrupts::set_hart_threshold(0);
 
     let ptr = Box::leak(Box::new(task::ThreadControlBlock {
         kernel_stack: mem::alloc_kernel_stack(8.kib()),
@@ -341,11 +357,15 @@ extern "C" fn kalt(hart_id: usize) -> ! {
         saved_tp: 0,
         saved_gp: 0,
         kernel_stack_size: 8.kib(),
+        scratch_stack: mem::alloc_kernel_stack(mem::SCRATCH_STACK_SIZE),
     }));
 
     csr::sscratch::write(ptr as *mut _ as usize);
     csr::sstatus::set_fs(csr::sstatus::FloatingPointStatus::Initial);
+    csr::scounteren::enable_time();
     csr::sie::enable();
+    mem::tlb::hart_online();
+    mem::phys::reserve::replenish();
 
     scheduler::SCHEDULER.schedule();
 }
@@ -468,12 +488,12 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
         }
     }
 
+    // Output queued for the console interrupt might never be sent
+    io::force_synchronous();
     error!("{}", info);
-    error!("Shutting hart down");
+    backtrace::print_backtrace();
 
-    sbi::hart_state_management::hart_stop().unwrap();
-    #[allow(unreachable_code)]
-    loop {}
+    panicking::finish_panic()
 }
 
 #[no_mangle]
@@ -483,5 +503,6 @@ pub extern "C" fn abort() -> ! {
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    panicking::note_out_of_memory();
     panic!("out of memory: {:?}", layout)
 }
@@ -5,48 +5,124 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+use super::{HEAP_REGION, HEAP_REGION_SIZE};
 use crate::{
     mem::{
-        paging::PageSize,
-        phys::{PhysicalMemoryAllocator, PHYSICAL_MEMORY_ALLOCATOR},
-        phys2virt,
+        paging::{self, flags, PageSize, PhysicalAddress, VirtualAddress},
+        phys::{PhysicalMemoryAllocator, PhysicalPage, PHYSICAL_MEMORY_ALLOCATOR},
+        tlb,
     },
     utils::{round_up_to_next, Units},
 };
-use core::ptr::NonNull;
+use core::{
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
+};
 use sync::SpinMutex;
 
+/// Smallest amount of memory the heap grows by at once, and how much free
+/// memory at the end of the heap is kept around when shrinking it
+const GROW_SIZE: usize = 1024 * 1024;
+
+/// Most pages handed back to the physical memory allocator per TLB shootdown
+const TRIM_BATCH: usize = 64;
+
 pub struct FreeListAllocator {
     inner: SpinMutex<FreeList>,
+    /// Set when a free leaves enough memory at the end of the heap for
+    /// [`FreeListAllocator::trim`] to release
+    trim_wanted: AtomicBool,
 }
 
 impl FreeListAllocator {
     pub const fn new() -> Self {
-        Self { inner: SpinMutex::new(FreeList { head: None }) }
+        Self {
+            inner: SpinMutex::new(FreeList { head: None, brk: 0, floor: 0, trimming: false }),
+            trim_wanted: AtomicBool::new(false),
+        }
     }
 
+    /// Map the first `size` bytes of the heap, which it never shrinks below.
     /// Returns the start and end for logging purposes
     pub fn init(&self, size: usize) -> (*mut u8, *mut u8) {
-        let n_pages = round_up_to_next(size, 4.kib()) / 4.kib();
-        let origin = unsafe {
-            phys2virt(
-                PHYSICAL_MEMORY_ALLOCATOR
-                    .lock()
-                    .alloc_contiguous(PageSize::Kilopage, n_pages)
-                    .expect("unable to allocate memory for heap")
-                    .as_phys_address(),
-            )
-            .as_mut_ptr()
-        };
+        let size = round_up_to_next(size, 4.kib());
+        let origin = HEAP_REGION as *mut u8;
 
         let mut inner = self.inner.lock();
+        inner.brk = HEAP_REGION;
+        inner.floor = HEAP_REGION + size;
+        assert_eq!(inner.map_pages(size), size, "unable to allocate memory for heap");
+
         inner.head = Some(NonNull::new(origin.cast()).expect("bad origin passed"));
 
         unsafe {
             *inner.head.unwrap().as_ptr() = FreeListNode { next: None, size: size - FreeListNode::struct_size() }
         };
 
-        (origin, unsafe { origin.add(round_up_to_next(size, 4.kib())) })
+        (origin, unsafe { origin.add(size) })
+    }
+
+    /// Hand the pages at the end of the heap back to the physical memory
+    /// allocator while the last free node covers more than [`GROW_SIZE`] of
+    /// them past the first [`GROW_SIZE`], if a free has left that many. This
+    /// waits on a TLB shootdown, so it must be called with no locks held, which
+    /// is done when a hart goes idle.
+    pub fn trim(&self) {
+        if !self.trim_wanted.swap(false, Ordering::AcqRel) {
+            return;
+        }
+
+        loop {
+            let mut pages = [PhysicalAddress::new(0); TRIM_BATCH];
+            let range = {
+                let mut inner = self.inner.lock();
+                if inner.trimming {
+                    return;
+                }
+
+                let tail = match inner.tail() {
+                    Some(tail) => unsafe { &mut *tail },
+                    None => return,
+                };
+
+                let data = tail.data() as usize;
+                let keep = round_up_to_next(data + GROW_SIZE, 4.kib()).max(inner.floor);
+                if inner.brk < keep + GROW_SIZE {
+                    return;
+                }
+
+                let start = (inner.brk - TRIM_BATCH * 4.kib()).max(keep);
+                tail.size = start - data;
+
+                for (page, slot) in (start..inner.brk).step_by(4.kib()).zip(&mut pages) {
+                    *slot = paging::unmap_kernel_page(VirtualAddress::new(page));
+                }
+
+                // `brk` stays put until the other harts have flushed the pages,
+                // so growing the heap in the meantime can't map over them
+                inner.trimming = true;
+                start..inner.brk
+            };
+
+            log::debug!("FreeListAllocator::trim: releasing {:#x}-{:#x}", range.start, range.end);
+            tlb::shootdown_kernel(VirtualAddress::new(range.start)..VirtualAddress::new(range.end));
+
+            let n_pages = (range.end - range.start) / 4.kib();
+            let mut phys = PHYSICAL_MEMORY_ALLOCATOR.lock();
+            for page in &pages[..n_pages] {
+                unsafe { phys.dealloc(PhysicalPage::from_ptr(page.as_mut_ptr()), PageSize::Kilopage) };
+            }
+            drop(phys);
+
+            let mut inner = self.inner.lock();
+            inner.trimming = false;
+
+            // If the heap grew while the shootdown was happening, the released
+            // pages are left as a hole in the window
+            if inner.brk == range.end {
+                inner.brk = range.start;
+            }
+        }
     }
 }
 
@@ -65,12 +141,56 @@ unsafe impl alloc::alloc::GlobalAlloc for FreeListAllocator {
             todo!("FreeListAllocator::alloc: >8 byte alignment");
         }
 
-        let head = this.head.expect("Heap allocator wasn't initialized!").as_ptr();
+        assert_ne!(this.brk, 0, "Heap allocator wasn't initialized!");
 
+        let ptr = this.take(size);
+        if ptr.is_null() && this.grow(size) {
+            return this.take(size);
+        }
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _: core::alloc::Layout) {
+        assert!(!ptr.is_null());
+
+        let mut inner = self.inner.lock();
+        let ptr = (ptr as usize - core::mem::size_of::<FreeListNode>()) as *mut FreeListNode;
+
+        log::debug!("Freeing {:?}, head={:?}", &*ptr, inner.head.map(|head| *head.as_ptr()));
+        (*ptr).next = inner.head;
+        inner.head = Some(NonNull::new_unchecked(ptr));
+
+        let ends_heap = (*ptr).data() as usize + (*ptr).size == inner.brk;
+        if ends_heap && (*ptr).size >= 2 * GROW_SIZE && inner.brk > inner.floor {
+            self.trim_wanted.store(true, Ordering::Release);
+        }
+    }
+}
+
+struct FreeList {
+    head: Option<NonNull<FreeListNode>>,
+    /// End of the memory mapped into the heap window
+    brk: usize,
+    /// End of the memory mapped by [`FreeListAllocator::init`], which the heap
+    /// never shrinks below
+    floor: usize,
+    /// Whether pages at the end of the heap are waiting on a TLB shootdown
+    /// before being freed
+    trimming: bool,
+}
+
+impl FreeList {
+    /// Take a block of `size` bytes off the free list, or return null if none
+    /// of the nodes are large enough
+    unsafe fn take(&mut self, size: usize) -> *mut u8 {
         let mut prev_node: Option<*mut FreeListNode> = None;
-        let mut node = head;
+        let mut node = match self.head {
+            Some(head) => head.as_ptr(),
+            None => return core::ptr::null_mut(),
+        };
 
-        log::trace!("FreeListAllocator::alloc: head={:?}", &*head);
+        log::trace!("FreeListAllocator::alloc: head={:?}", &*node);
 
         loop {
             log::trace!("FreeListAllocator::alloc: checking node, node={:?}", &*node);
@@ -83,7 +203,7 @@ unsafe impl alloc::alloc::GlobalAlloc for FreeListAllocator {
 
                 match prev_node {
                     Some(prev_node) => (*prev_node).next = (*node).next,
-                    None => this.head = Some((*node).next.expect("valid next")),
+                    None => self.head = (*node).next,
                 }
 
                 break (&*node).data();
@@ -104,7 +224,7 @@ unsafe impl alloc::alloc::GlobalAlloc for FreeListAllocator {
                     Some(prev_node) => (*prev_node).next = Some(new_node),
                     None => {
                         log::trace!("Setting head to {:?}", &*new_node.as_ptr());
-                        this.head = Some(new_node);
+                        self.head = Some(new_node);
                     }
                 }
 
@@ -121,20 +241,78 @@ unsafe impl alloc::alloc::GlobalAlloc for FreeListAllocator {
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _: core::alloc::Layout) {
-        assert!(!ptr.is_null());
+    /// Map more memory onto the end of the heap so there's a free node that
+    /// can fit `size` bytes, returning whether that succeeded
+    unsafe fn grow(&mut self, size: usize) -> bool {
+        let old_brk = self.brk;
+        let wanted = round_up_to_next(size + FreeListNode::struct_size(), 4.kib()).max(GROW_SIZE);
+        let mapped = self.map_pages(wanted);
 
-        let mut inner = self.inner.lock();
-        let ptr = (ptr as usize - core::mem::size_of::<FreeListNode>()) as *mut FreeListNode;
+        log::debug!("FreeListAllocator::grow: mapped {:#x} bytes at {:#x}", mapped, old_brk);
 
-        log::debug!("Freeing {:?}, head={:?}", &*ptr, &*inner.head.unwrap().as_ptr());
-        (*ptr).next = inner.head;
-        inner.head = Some(NonNull::new_unchecked(ptr));
+        if mapped == 0 {
+            return false;
+        }
+
+        // Extend the free node at the end of the heap if there is one, so a
+        // large allocation can span the old and new memory
+        if let Some(tail) = self.tail_before(old_brk) {
+            (*tail).size += mapped;
+            return (*tail).size >= size;
+        }
+
+        let node = old_brk as *mut FreeListNode;
+        *node = FreeListNode { next: self.head, size: mapped - FreeListNode::struct_size() };
+        self.head = Some(NonNull::new_unchecked(node));
+
+        (*node).size >= size
     }
-}
 
-struct FreeList {
-    head: Option<NonNull<FreeListNode>>,
+    /// Map up to `len` bytes of new pages at `brk`, returning how many were
+    /// mapped before running out of physical memory or room in the window
+    fn map_pages(&mut self, len: usize) -> usize {
+        let mut mapped = 0;
+
+        while mapped < len && self.brk < HEAP_REGION + HEAP_REGION_SIZE {
+            let page = match unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc(PageSize::Kilopage) } {
+                Some(page) => page,
+                None => break,
+            };
+
+            paging::map_kernel_page(
+                page.as_phys_address(),
+                VirtualAddress::new(self.brk),
+                flags::DIRTY | flags::ACCESSED | flags::READ | flags::WRITE | flags::VALID,
+            );
+
+            self.brk += 4.kib();
+            mapped += 4.kib();
+        }
+
+        mapped
+    }
+
+    /// The free node ending at `brk`, if any
+    fn tail(&self) -> Option<*mut FreeListNode> {
+        self.tail_before(self.brk)
+    }
+
+    fn tail_before(&self, end: usize) -> Option<*mut FreeListNode> {
+        let mut node = self.head;
+
+        while let Some(current) = node {
+            let current = current.as_ptr();
+            unsafe {
+                if (*current).data() as usize + (*current).size == end {
+                    return Some(current);
+                }
+
+                node = (*current).next;
+            }
+        }
+
+        None
+    }
 }
 
 unsafe impl Send for FreeList {}
@@ -6,9 +6,27 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 pub mod free_list;
+pub mod slab;
 
-use free_list::FreeListAllocator;
+use crate::mem::paging::VirtualAddress;
 
-#[cfg(any(not(any(feature = "vmalloc.allocator.buddy")), feature = "vmalloc.allocator.freelist"))]
+/// Start of the window the heap maps memory into as it grows, which is above
+/// the kernel stacks
+const HEAP_REGION: usize = 0xFFFF_FFE8_0000_0000;
+
+/// The heap window fits in a single top level page table entry, which is
+/// created when the heap is initialized so that every page table shares it
+const HEAP_REGION_SIZE: usize = 1 << 30;
+
+/// Whether `address` is inside the heap window
+pub fn in_heap_region(address: VirtualAddress) -> bool {
+    (HEAP_REGION..HEAP_REGION + HEAP_REGION_SIZE).contains(&address.as_usize())
+}
+
+#[cfg(feature = "vmalloc.allocator.freelist")]
+#[global_allocator]
+pub static HEAP_ALLOCATOR: free_list::FreeListAllocator = free_list::FreeListAllocator::new();
+
+#[cfg(not(feature = "vmalloc.allocator.freelist"))]
 #[global_allocator]
-pub static HEAP_ALLOCATOR: FreeListAllocator = FreeListAllocator::new();
+pub static HEAP_ALLOCATOR: slab::SlabAllocator = slab::SlabAllocator::new();
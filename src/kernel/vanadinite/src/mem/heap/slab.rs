@@ -0,0 +1,202 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::free_list::FreeListAllocator;
+use crate::{
+    interrupts::InterruptDisabler,
+    mem::{
+        paging::PageSize,
+        phys::{PhysicalMemoryAllocator, PHYSICAL_MEMORY_ALLOCATOR},
+        phys2virt,
+    },
+};
+use alloc::alloc::GlobalAlloc;
+use core::{alloc::Layout, cell::UnsafeCell, ptr::NonNull};
+use sync::SpinMutex;
+
+/// Object sizes served from slabs, anything larger goes to the fallback
+/// allocator
+const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+const N_CLASSES: usize = SIZE_CLASSES.len();
+/// Number of free objects each hart can cache per size class
+const MAGAZINE_SIZE: usize = 32;
+
+/// Per-hart caches of free objects, which can be used without taking any
+/// locks. Interrupts must be disabled while they're accessed since ISRs are
+/// free to allocate.
+#[thread_local]
+static MAGAZINES: UnsafeCell<[Magazine; N_CLASSES]> = UnsafeCell::new([const { Magazine::new() }; N_CLASSES]);
+
+struct Magazine {
+    objects: [*mut u8; MAGAZINE_SIZE],
+    len: usize,
+}
+
+impl Magazine {
+    const fn new() -> Self {
+        Self { objects: [core::ptr::null_mut(); MAGAZINE_SIZE], len: 0 }
+    }
+
+    fn pop(&mut self) -> Option<*mut u8> {
+        self.len = self.len.checked_sub(1)?;
+        Some(self.objects[self.len])
+    }
+
+    fn push(&mut self, object: *mut u8) -> Result<(), *mut u8> {
+        match self.objects.get_mut(self.len) {
+            Some(slot) => {
+                *slot = object;
+                self.len += 1;
+                Ok(())
+            }
+            None => Err(object),
+        }
+    }
+}
+
+/// Free objects shared between all harts for a single size class, stored as
+/// an intrusive list inside of the objects themselves
+struct Depot {
+    head: Option<NonNull<FreeObject>>,
+}
+
+unsafe impl Send for Depot {}
+
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+impl Depot {
+    fn pop(&mut self) -> Option<*mut u8> {
+        let head = self.head?;
+        self.head = unsafe { head.as_ref().next };
+
+        Some(head.as_ptr().cast())
+    }
+
+    fn push(&mut self, object: *mut u8) {
+        let object: *mut FreeObject = object.cast();
+        unsafe { object.write(FreeObject { next: self.head }) };
+        self.head = NonNull::new(object);
+    }
+
+    /// Carve a new page up into objects of the given size
+    fn grow(&mut self, object_size: usize) -> bool {
+        let page = unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc(PageSize::Kilopage) };
+        let page = match page.or_else(crate::mem::phys::reserve::take) {
+            Some(page) => phys2virt(page.as_phys_address()).as_mut_ptr(),
+            None => return false,
+        };
+
+        for offset in (0..PageSize::Kilopage.to_byte_size()).step_by(object_size) {
+            self.push(unsafe { page.add(offset) });
+        }
+
+        true
+    }
+}
+
+/// Slab allocator for small, fixed-size kernel objects (tasks, channel
+/// messages, capability entries, etc). Objects are grouped into power of two
+/// size classes carved out of whole pages, and each hart keeps a small
+/// magazine of free objects per class so that the common allocation path
+/// never touches a lock. Larger allocations are passed on to the
+/// [`FreeListAllocator`].
+///
+/// Slab pages are never returned to the physical memory allocator, freed
+/// objects are instead kept around for reuse.
+pub struct SlabAllocator {
+    depots: [SpinMutex<Depot>; N_CLASSES],
+    fallback: FreeListAllocator,
+}
+
+impl SlabAllocator {
+    pub const fn new() -> Self {
+        Self { depots: [const { SpinMutex::new(Depot { head: None }) }; N_CLASSES], fallback: FreeListAllocator::new() }
+    }
+
+    /// Initialize the fallback allocator used for large allocations. Returns
+    /// the start and end for logging purposes.
+    pub fn init(&self, size: usize) -> (*mut u8, *mut u8) {
+        self.fallback.init(size)
+    }
+
+    /// Shrink the fallback allocator, see [`FreeListAllocator::trim`]
+    pub fn trim(&self) {
+        self.fallback.trim()
+    }
+
+    fn size_class(layout: Layout) -> Option<usize> {
+        // Objects are naturally aligned to their size class since slabs are
+        // page aligned, so the alignment only needs to fit within it
+        let size = layout.size().max(layout.align());
+        SIZE_CLASSES.iter().position(|&class| class >= size)
+    }
+
+    /// Refill an empty magazine with half its capacity from the depot
+    fn refill(&self, class: usize, magazine: &mut Magazine) {
+        let mut depot = self.depots[class].lock();
+
+        for _ in 0..MAGAZINE_SIZE / 2 {
+            let object = match depot.pop() {
+                Some(object) => object,
+                None if depot.grow(SIZE_CLASSES[class]) => depot.pop().unwrap(),
+                None => break,
+            };
+
+            let _ = magazine.push(object);
+        }
+    }
+
+    /// Return half of a full magazine to the depot
+    fn flush(&self, class: usize, magazine: &mut Magazine) {
+        let mut depot = self.depots[class].lock();
+
+        for _ in 0..MAGAZINE_SIZE / 2 {
+            match magazine.pop() {
+                Some(object) => depot.push(object),
+                None => break,
+            }
+        }
+    }
+}
+
+unsafe impl Send for SlabAllocator {}
+unsafe impl Sync for SlabAllocator {}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let class = match Self::size_class(layout) {
+            Some(class) => class,
+            None => return self.fallback.alloc(layout),
+        };
+
+        let _disabler = InterruptDisabler::new();
+        let magazine = &mut (*MAGAZINES.get())[class];
+
+        if magazine.len == 0 {
+            self.refill(class, magazine);
+        }
+
+        magazine.pop().unwrap_or(core::ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let class = match Self::size_class(layout) {
+            Some(class) => class,
+            None => return self.fallback.dealloc(ptr, layout),
+        };
+
+        let _disabler = InterruptDisabler::new();
+        let magazine = &mut (*MAGAZINES.get())[class];
+
+        if let Err(ptr) = magazine.push(ptr) {
+            self.flush(class, magazine);
+            let _ = magazine.push(ptr);
+        }
+    }
+}
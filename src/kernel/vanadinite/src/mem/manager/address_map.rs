@@ -7,7 +7,7 @@
 
 use super::VirtualAddress;
 use crate::mem::region::MemoryRegion;
-use alloc::collections::BTreeMap;
+use alloc::{boxed::Box, collections::BTreeMap};
 use core::ops::Range;
 
 // TODO: probably could split this up slightly more and represent the
@@ -22,6 +22,9 @@ pub struct AddressRegion {
     pub span: Range<VirtualAddress>,
     /// The type of memory contained in the region, used for debugging purposes
     pub kind: AddressRegionKind,
+    /// What the region holds, as described by the kernel or the task which
+    /// owns it
+    pub name: Option<Box<str>>,
 }
 
 impl AddressRegion {
@@ -44,6 +47,7 @@ pub enum AddressRegionKind {
     UserAllocated,
     Dma,
     Mmio,
+    TimePage,
 }
 
 /// Represents the userspace address space and allows for allocating and
@@ -60,7 +64,7 @@ impl AddressMap {
         let mut map = BTreeMap::new();
         map.insert(
             complete_range.end,
-            AddressRegion { region: None, span: complete_range, kind: AddressRegionKind::Unoccupied },
+            AddressRegion { region: None, span: complete_range, kind: AddressRegionKind::Unoccupied, name: None },
         );
 
         Self { map }
@@ -109,7 +113,7 @@ impl AddressMap {
                 self.map.insert(unsafe { old_range.span.end.unchecked_offset(-1) }, old_range);
                 self.map.insert(
                     unsafe { subrange.end.unchecked_offset(-1) },
-                    AddressRegion { region: Some(backing), span: subrange, kind },
+                    AddressRegion { region: Some(backing), span: subrange, kind, name: None },
                 );
             }
             // Chop off the end
@@ -118,14 +122,14 @@ impl AddressMap {
                 self.map.insert(unsafe { old_range.span.end.unchecked_offset(-1) }, old_range);
                 self.map.insert(
                     unsafe { subrange.end.unchecked_offset(-1) },
-                    AddressRegion { region: Some(backing), span: subrange, kind },
+                    AddressRegion { region: Some(backing), span: subrange, kind, name: None },
                 );
             }
             // its the whole ass range
             (true, true) => {
                 self.map.insert(
                     unsafe { subrange.end.unchecked_offset(-1) },
-                    AddressRegion { region: Some(backing), span: subrange, kind },
+                    AddressRegion { region: Some(backing), span: subrange, kind, name: None },
                 );
             }
             // its a true subrange, need to splice out an generate 3 new ranges
@@ -134,12 +138,14 @@ impl AddressMap {
                     region: None,
                     span: old_range.span.start..subrange.start,
                     kind: AddressRegionKind::Unoccupied,
+                    name: None,
                 };
-                let active = AddressRegion { region: Some(backing), span: subrange.clone(), kind };
+                let active = AddressRegion { region: Some(backing), span: subrange.clone(), kind, name: None };
                 let after = AddressRegion {
                     region: None,
                     span: subrange.end..old_range.span.end,
                     kind: AddressRegionKind::Unoccupied,
+                    name: None,
                 };
 
                 self.map.insert(unsafe { before.span.end.unchecked_offset(-1) }, before);
@@ -180,6 +186,7 @@ impl AddressMap {
         }
 
         let ret = range.region.take().unwrap();
+        range.name = None;
 
         self.map.insert(unsafe { range.span.end.unchecked_offset(-1) }, range);
 
@@ -191,6 +198,11 @@ impl AddressMap {
         self.map.range(address..).next().map(|(_, r)| r)
     }
 
+    /// Same as [`Self::find`], but returns a mutable reference to the region
+    pub fn find_mut(&mut self, address: VirtualAddress) -> Option<&mut AddressRegion> {
+        self.map.range_mut(address..).next().map(|(_, r)| r)
+    }
+
     /// Returns the unoccupied regions in the address space
     pub fn unoccupied_regions(&self) -> impl Iterator<Item = &AddressRegion> {
         self.map.values().filter(|v| v.region.is_none())
@@ -275,6 +287,7 @@ mod tests {
                 region: Some(MemoryRegion::GuardPage),
                 span: subrange.clone(),
                 kind: AddressRegionKind::Unoccupied,
+                name: None,
             }]
         );
 
@@ -284,12 +297,14 @@ mod tests {
                 &AddressRegion {
                     region: None,
                     span: VirtualAddress::new(0)..subrange.start,
-                    kind: AddressRegionKind::Unoccupied
+                    kind: AddressRegionKind::Unoccupied,
+                    name: None,
                 },
                 &AddressRegion {
                     region: None,
                     span: subrange.end..VirtualAddress::userspace_range().end,
                     kind: AddressRegionKind::Unoccupied,
+                    name: None,
                 }
             ]
         );
@@ -308,11 +323,13 @@ mod tests {
                     region: Some(MemoryRegion::GuardPage),
                     span: VirtualAddress::new(0)..subrange.start,
                     kind: AddressRegionKind::Unoccupied,
+                    name: None,
                 },
                 &AddressRegion {
                     region: Some(MemoryRegion::GuardPage),
                     span: subrange.clone(),
                     kind: AddressRegionKind::Unoccupied,
+                    name: None,
                 },
             ]
         );
@@ -323,6 +340,7 @@ mod tests {
                 region: None,
                 span: subrange.end..VirtualAddress::userspace_range().end,
                 kind: AddressRegionKind::Unoccupied,
+                name: None,
             }]
         );
 
@@ -344,11 +362,13 @@ mod tests {
                     region: Some(MemoryRegion::GuardPage),
                     span: subrange.clone(),
                     kind: AddressRegionKind::Unoccupied,
+                    name: None,
                 },
                 &AddressRegion {
                     region: Some(MemoryRegion::GuardPage),
                     span: subrange.end..VirtualAddress::userspace_range().end,
                     kind: AddressRegionKind::Unoccupied,
+                    name: None,
                 }
             ]
         );
@@ -359,6 +379,7 @@ mod tests {
                 region: None,
                 span: VirtualAddress::new(0)..subrange.start,
                 kind: AddressRegionKind::Unoccupied,
+                name: None,
             }]
         );
     }
@@ -377,6 +398,7 @@ mod tests {
                 region: None,
                 span: VirtualAddress::userspace_range(),
                 kind: AddressRegionKind::Unoccupied,
+                name: None,
             }
         );
     }
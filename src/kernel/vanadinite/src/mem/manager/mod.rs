@@ -14,16 +14,24 @@ use crate::{
             PageSize, PageTable, PageTableDebug, PhysicalAddress, VirtualAddress,
         },
         region::{MemoryRegion, PhysicalRegion, UniquePhysicalRegion},
-        sfence,
+        sfence, tlb,
     },
     utils::{self, Units},
 };
 use address_map::AddressMap;
 pub use address_map::{AddressRegion, AddressRegionKind};
+use alloc::boxed::Box;
 use core::ops::Range;
 
 use super::region::SharedPhysicalRegion;
 
+/// `RSW` bit marking a page of a region which was snapshotted by
+/// [`MemoryManager::snapshot_region`]
+const RSW_SNAPSHOT: u8 = 0b01;
+/// `RSW` bit marking a snapshotted page which was writable beforehand, so
+/// writing to it copies the region
+const RSW_WAS_WRITABLE: u8 = 0b10;
+
 pub enum FillOption<'a> {
     Data(&'a [u8]),
     Unitialized,
@@ -56,6 +64,15 @@ impl MemoryManager {
 
         this.guard(VirtualAddress::new(0));
 
+        if let Some(region) = crate::timepage::region() {
+            this.apply_shared_region(
+                Some(VirtualAddress::new(crate::timepage::TIME_PAGE_ADDRESS)),
+                flags::USER | flags::READ | flags::VALID,
+                region,
+                AddressRegionKind::TimePage,
+            );
+        }
+
         this
     }
 
@@ -63,22 +80,39 @@ impl MemoryManager {
     /// will choose a suitable, random address) with the given [`PageSize`], the
     /// number of required pages, with the given permission [`Flags`],
     /// optionally filled or zeroed.
+    ///
+    /// Kilopage regions which are a multiple of a megapage in size (and
+    /// megapage aligned, if an address is given) are transparently backed by
+    /// megapages when the physical memory allocator is able to provide them,
+    /// see [`Self::demote`] for splitting them back up.
     pub fn alloc_region(
         &mut self,
         at: Option<VirtualAddress>,
         description: RegionDescription,
     ) -> Range<VirtualAddress> {
-        let RegionDescription { size, len, contiguous, flags, fill, kind } = description;
-        let at = at.unwrap_or_else(|| self.find_free_region(size, len));
+        let RegionDescription { mut size, mut len, contiguous, flags, fill, kind } = description;
+        assert_not_writable_and_executable(flags);
+
+        let (at, mut backing) = match self.try_promote(at, size, len, contiguous) {
+            Some((at, backing)) => {
+                size = backing.page_size();
+                len = backing.n_pages();
+                (at, backing)
+            }
+            None => {
+                let at = at.unwrap_or_else(|| self.find_free_region(size, len));
+                let backing = if contiguous {
+                    UniquePhysicalRegion::alloc_contiguous(size, len)
+                } else {
+                    UniquePhysicalRegion::alloc_sparse(size, len)
+                };
+
+                (at, backing)
+            }
+        };
 
         log::debug!("Allocating region at {:#p}: size={:?} n_pages={} flags={:?}", at, size, len, flags);
 
-        let mut backing = if contiguous {
-            UniquePhysicalRegion::alloc_contiguous(size, len)
-        } else {
-            UniquePhysicalRegion::alloc_sparse(size, len)
-        };
-
         match fill {
             FillOption::Data(data) => backing.copy_data_into(data),
             FillOption::Zeroed => backing.zero(),
@@ -99,6 +133,77 @@ impl MemoryManager {
         range
     }
 
+    /// Attempt to back a kilopage region with megapages instead, returning the
+    /// address to map the region at along with the megapage backing
+    fn try_promote(
+        &self,
+        at: Option<VirtualAddress>,
+        size: PageSize,
+        len: usize,
+        contiguous: bool,
+    ) -> Option<(VirtualAddress, UniquePhysicalRegion)> {
+        let pages_per_megapage = PageSize::Megapage.to_byte_size() / PageSize::Kilopage.to_byte_size();
+
+        if size != PageSize::Kilopage || len == 0 || len % pages_per_megapage != 0 {
+            return None;
+        }
+
+        if at.map_or(false, |at| !at.is_aligned(PageSize::Megapage)) {
+            return None;
+        }
+
+        let n_megapages = len / pages_per_megapage;
+        let backing = match contiguous {
+            true => UniquePhysicalRegion::try_alloc_contiguous(PageSize::Megapage, n_megapages)?,
+            false => UniquePhysicalRegion::try_alloc_sparse(PageSize::Megapage, n_megapages)?,
+        };
+
+        let at = at.unwrap_or_else(|| self.find_free_region(PageSize::Megapage, n_megapages));
+        log::debug!("Promoting region of {} kilopages at {:#p} to {} megapages", len, at, n_megapages);
+
+        Some((at, backing))
+    }
+
+    /// Split the region containing the given [`VirtualAddress`] into
+    /// kilopages if it's backed by larger pages, so that parts of it can be
+    /// modified independently. Returns `false` if the region can't be split
+    /// because its physical memory is shared.
+    pub fn demote(&mut self, at: VirtualAddress) -> bool {
+        let (region, span) = match self.address_map.find_mut(at) {
+            Some(AddressRegion { region: Some(region), span, .. }) => (region, span.clone()),
+            _ => return true,
+        };
+
+        let old_size = region.page_size();
+        if old_size == PageSize::Kilopage {
+            return true;
+        }
+
+        if !region.split(PageSize::Kilopage) {
+            return false;
+        }
+
+        log::debug!("Demoting {:?} region at {:#p}-{:#p} to kilopages", old_size, span.start, span.end);
+
+        for large_page in (span.start.as_usize()..span.end.as_usize()).step_by(old_size.to_byte_size()) {
+            let large_page = VirtualAddress::new(large_page);
+            let (phys, flags) = match (self.table.resolve(large_page), self.table.page_flags(large_page)) {
+                (Some(phys), Some(flags)) => (phys, flags),
+                _ => continue,
+            };
+
+            self.table.unmap(large_page);
+
+            for offset in (0..old_size.to_byte_size()).step_by(PageSize::Kilopage.to_byte_size()) {
+                self.table.map(phys.offset(offset), large_page.add(offset), flags, PageSize::Kilopage);
+            }
+        }
+
+        tlb::shootdown(self.table.physical_address(), span);
+
+        true
+    }
+
     /// Same as [`Self::alloc_region`], except attempts to find a free region
     /// with available space above and below the region to place guard pages.
     pub fn alloc_guarded_region(&mut self, description: RegionDescription) -> VirtualAddress {
@@ -123,6 +228,7 @@ impl MemoryManager {
         description: RegionDescription,
     ) -> (Range<VirtualAddress>, SharedPhysicalRegion) {
         let RegionDescription { size, len, contiguous, flags, fill, kind } = description;
+        assert_not_writable_and_executable(flags);
         let at = at.unwrap_or_else(|| self.find_free_region(size, len));
         let mut backing = if contiguous {
             UniquePhysicalRegion::alloc_contiguous(size, len)
@@ -157,12 +263,14 @@ impl MemoryManager {
     /// will allow aliasing physical memory if used incorrectly.
     ///
     /// Memory regions will be mapped using kilopages (TODO: is larger
-    /// granularity necessary?)
+    /// granularity necessary?), and are named after the device tree `path` of
+    /// the device
     pub unsafe fn map_mmio_device(
         &mut self,
         from: PhysicalAddress,
         to: Option<VirtualAddress>,
         len: usize,
+        path: &str,
     ) -> Range<VirtualAddress> {
         let n_pages = crate::utils::round_up_to_next(4.kib(), len) / 4.kib();
         let at = to.unwrap_or_else(|| self.find_free_region(PageSize::Kilopage, n_pages));
@@ -195,6 +303,7 @@ impl MemoryManager {
         self.address_map
             .alloc(range.clone(), MemoryRegion::Backed(PhysicalRegion::Unique(backing)), AddressRegionKind::Mmio)
             .expect("bad address mapping");
+        self.name_region(at, Some(Box::from(path)));
 
         log::trace!("Mapped MMIO at {:#p}-{:#p}", range.start, range.end);
         range
@@ -208,6 +317,7 @@ impl MemoryManager {
         region: SharedPhysicalRegion,
         kind: AddressRegionKind,
     ) -> Range<VirtualAddress> {
+        assert_not_writable_and_executable(flags);
         let at = at.unwrap_or_else(|| self.find_free_region(region.page_size(), region.n_pages()));
 
         // FIXME: I suspect there's a bug with the address map where its giving
@@ -256,20 +366,158 @@ impl MemoryManager {
         let iter = (0..region.page_count()).map(|i| at.add(i * region.page_size().to_byte_size()));
         for virt_addr in iter {
             self.table.unmap(virt_addr);
-            // FIXME: this is unnecessary when unmapping from other tasks than
-            // the current one? need IPIs for that?
-            sfence(Some(virt_addr), None);
         }
 
+        tlb::shootdown(self.table.physical_address(), span);
+
         region
     }
 
+    /// Seal the current contents of the region containing `at`, which must be
+    /// memory the task allocated for itself, into a [`SharedPhysicalRegion`]
+    /// which never changes afterwards. The region stays mapped, but its pages
+    /// are made read-only until it's written to, which copies it first (see
+    /// [`Self::break_snapshot`]). Snapshotting the region again before then
+    /// returns the same memory.
+    pub fn snapshot_region(&mut self, at: VirtualAddress) -> Option<SharedPhysicalRegion> {
+        let snapshotted = self.table.page_rsw(at).map_or(false, |rsw| rsw & RSW_SNAPSHOT != 0);
+        let region = self.address_map.find_mut(at)?;
+        if region.kind != AddressRegionKind::UserAllocated {
+            return None;
+        }
+
+        let shared = match region.region.take() {
+            Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique))) => unique.into_shared_region(),
+            Some(MemoryRegion::Backed(PhysicalRegion::Shared(shared))) if snapshotted => {
+                region.region = Some(MemoryRegion::Backed(PhysicalRegion::Shared(shared.clone())));
+                return Some(shared);
+            }
+            other => {
+                region.region = other;
+                return None;
+            }
+        };
+
+        region.region = Some(MemoryRegion::Backed(PhysicalRegion::Shared(shared.clone())));
+        let span = region.span.clone();
+
+        for page in (span.start.as_usize()..span.end.as_usize()).step_by(shared.page_size().to_byte_size()) {
+            let page = VirtualAddress::new(page);
+            let writable = self.table.page_flags(page).map_or(false, |f| f & flags::WRITE);
+            let rsw = if writable { RSW_SNAPSHOT | RSW_WAS_WRITABLE } else { RSW_SNAPSHOT };
+
+            self.table.modify_page_flags(page, |f| Flags::new(f.value() & !flags::WRITE.value()));
+            self.table.modify_page_rsw(page, |r| r | rsw);
+        }
+
+        tlb::shootdown(self.table.physical_address(), span);
+
+        Some(shared)
+    }
+
+    /// Whether writing to `at` needs to copy its region away from a snapshot
+    /// first
+    pub fn is_copy_on_write(&self, at: VirtualAddress) -> bool {
+        self.table.page_rsw(at).map_or(false, |rsw| rsw & RSW_WAS_WRITABLE != 0)
+    }
+
+    /// Give the snapshotted region containing `at` its own copy of its memory
+    /// and restore its original permissions, leaving the snapshot untouched.
+    /// Returns `false` if the region isn't snapshotted or there isn't enough
+    /// memory to copy it.
+    pub fn break_snapshot(&mut self, at: VirtualAddress) -> bool {
+        if self.table.page_rsw(at).map_or(true, |rsw| rsw & RSW_SNAPSHOT == 0) {
+            return false;
+        }
+
+        let region = match self.address_map.find_mut(at) {
+            Some(region) => region,
+            None => return false,
+        };
+
+        let shared = match region.region.take() {
+            Some(MemoryRegion::Backed(PhysicalRegion::Shared(shared))) => shared,
+            other => {
+                region.region = other;
+                return false;
+            }
+        };
+
+        // Once every copy of the snapshot is gone nothing else can see the
+        // memory, so it can be written to in place
+        let unique = match shared.try_into_unique() {
+            Ok(unique) => unique,
+            Err(shared) => match UniquePhysicalRegion::try_alloc_sparse(shared.page_size(), shared.n_pages()) {
+                Some(mut copy) => {
+                    copy.copy_from(&shared);
+                    copy
+                }
+                None => {
+                    region.region = Some(MemoryRegion::Backed(PhysicalRegion::Shared(shared)));
+                    return false;
+                }
+            },
+        };
+
+        let span = region.span.clone();
+        let page_size = unique.page_size();
+
+        for (i, phys_addr) in unique.physical_addresses().enumerate() {
+            let virt_addr = span.start.add(i * page_size.to_byte_size());
+            let rsw = self.table.page_rsw(virt_addr).unwrap_or_default();
+            let mut flags = self.table.page_flags(virt_addr).expect("snapshotted page isn't mapped");
+
+            if rsw & RSW_WAS_WRITABLE != 0 {
+                flags |= flags::WRITE;
+            }
+
+            self.table.unmap(virt_addr);
+            self.table.map(phys_addr, virt_addr, flags, page_size);
+        }
+
+        region.region = Some(MemoryRegion::Backed(PhysicalRegion::Unique(unique)));
+        tlb::shootdown(self.table.physical_address(), span);
+
+        true
+    }
+
+    /// Copy every region in `range` which would need to be copied before it
+    /// could be written to away from its snapshot
+    pub fn break_snapshots(&mut self, range: Range<VirtualAddress>) {
+        let start = range.start.align_down_to(PageSize::Kilopage);
+        let end = range.end.align_to_next(PageSize::Kilopage);
+
+        for page in (start.as_usize()..end.as_usize()).step_by(4.kib()) {
+            let page = VirtualAddress::new(page);
+            if self.is_copy_on_write(page) {
+                self.break_snapshot(page);
+            }
+        }
+    }
+
     /// Returns the [`AddressRegion`] that contains the given
     /// [`VirtualAddress`], if it exists
     pub fn region_for(&self, at: VirtualAddress) -> Option<&AddressRegion> {
         self.address_map.find(at)
     }
 
+    /// Name the occupied region containing `at`, or clear its name, returning
+    /// whether there was one to name
+    pub fn name_region(&mut self, at: VirtualAddress, name: Option<Box<str>>) -> bool {
+        match self.address_map.find_mut(at) {
+            Some(region) if !region.is_unoccupied() => {
+                region.name = name;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the occupied [`AddressRegion`]s, ordered by address
+    pub fn occupied_regions(&self) -> impl Iterator<Item = &AddressRegion> {
+        self.address_map.occupied_regions()
+    }
+
     pub fn map_direct(&mut self, map_from: PhysicalAddress, map_to: VirtualAddress, n_pages: PageSize, flags: Flags) {
         self.table.map(map_from, map_to, flags, n_pages);
 
@@ -313,7 +561,44 @@ impl MemoryManager {
     /// Modify the page flags of the given [`VirtualAddress`] mapping, returning
     /// whether or not the mapping exists
     pub fn modify_page_flags(&mut self, virt: VirtualAddress, f: impl FnOnce(Flags) -> Flags) -> bool {
-        self.table.modify_page_flags(virt, f)
+        let exists = self.table.modify_page_flags(virt, f);
+
+        if exists {
+            tlb::shootdown(self.table.physical_address(), virt..virt.add(1));
+        }
+
+        exists
+    }
+
+    /// Replace the read, write, and execute permissions of every page mapped in
+    /// the given range with those in `permissions`, leaving all other flags
+    /// untouched. The range must be kilopage aligned, regions backed by larger
+    /// pages are demoted as needed.
+    pub fn modify_page_permissions(&mut self, range: Range<VirtualAddress>, permissions: Flags) {
+        let rwx = (flags::READ | flags::WRITE | flags::EXECUTE).value();
+        let permissions = Flags::new(permissions.value() & rwx);
+        assert!(
+            !(permissions & flags::WRITE && permissions & flags::EXECUTE),
+            "tried to make memory both writable and executable"
+        );
+
+        let mut page_size = match self.address_map.find(range.start).and_then(|r| r.region.as_ref()) {
+            Some(region) => region.page_size(),
+            None => PageSize::Kilopage,
+        };
+
+        // Changing the permissions of only part of a large page requires
+        // splitting it up first
+        if !range.start.is_aligned(page_size) || !range.end.is_aligned(page_size) {
+            assert!(self.demote(range.start), "tried to change permissions of part of a shared large page");
+            page_size = PageSize::Kilopage;
+        }
+
+        for page in (range.start.as_usize()..range.end.as_usize()).step_by(page_size.to_byte_size()) {
+            self.table.modify_page_flags(VirtualAddress::new(page), |f| Flags::new(f.value() & !rwx) | permissions);
+        }
+
+        tlb::shootdown(self.table.physical_address(), range);
     }
 
     /// Returns the `RSW` bits of the given [`VirtualAddress`] mapping, if it's
@@ -358,9 +643,7 @@ impl MemoryManager {
         // FIXME: there's probably a better way to do this
         // Try to find a hole big enough 100 times, fall back to linear search otherwise.
         for _ in 0..100 {
-            // FIXME: this needs replaced by proper RNG
-            let jittered_start =
-                (crate::csr::time::read() as usize * 717) % VirtualAddress::userspace_range().end.as_usize();
+            let jittered_start = crate::crypto::rand::range(VirtualAddress::userspace_range().end.as_usize());
 
             let region = match self.address_map.find(VirtualAddress::new(jittered_start)) {
                 Some(r) => r.span.clone(),
@@ -406,9 +689,7 @@ impl MemoryManager {
         // FIXME: there's probably a better way to do this
         // Try to find a hole big enough 100 times, fall back to linear search otherwise.
         for _ in 0..100 {
-            // FIXME: this needs replaced by proper RNG
-            let jittered_start =
-                (crate::csr::time::read() as usize * 717) % VirtualAddress::userspace_range().end.as_usize();
+            let jittered_start = crate::crypto::rand::range(VirtualAddress::userspace_range().end.as_usize());
 
             let region = match self.address_map.find(VirtualAddress::new(jittered_start)) {
                 Some(r) => r.span.clone(),
@@ -462,3 +743,14 @@ impl MemoryManager {
         todo!("exhausted address space -- this should be an `Err(...)` in the future")
     }
 }
+
+/// User memory can be either writable or executable, but never both at once,
+/// so the syscalls which create mappings check for it before getting here
+#[track_caller]
+fn assert_not_writable_and_executable(flags: Flags) {
+    assert!(
+        !(flags & flags::USER && flags & flags::WRITE && flags & flags::EXECUTE),
+        "tried to map user memory as both writable and executable: {:?}",
+        flags
+    );
+}
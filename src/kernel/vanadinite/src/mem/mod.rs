@@ -10,14 +10,17 @@ use {
         arch::asm,
         sync::atomic::{AtomicUsize, Ordering},
     },
-    paging::{PageSize, PhysicalAddress, VirtualAddress},
+    paging::{flags, PageSize, PhysicalAddress, VirtualAddress},
     phys::{PhysicalMemoryAllocator, PHYSICAL_MEMORY_ALLOCATOR},
+    sync::SpinMutex,
 };
 
 pub mod heap;
 pub mod manager;
 pub mod phys;
+pub mod quota;
 pub mod region;
+pub mod tlb;
 pub mod user;
 pub mod paging {
     mod table;
@@ -52,6 +55,21 @@ pub fn sfence(vaddr: Option<paging::VirtualAddress>, asid: Option<u16>) {
     }
 }
 
+/// Start of the region kernel stacks are mapped into, which is above both the
+/// physical memory map and the kernel image
+const KERNEL_STACK_REGION: usize = 0xFFFF_FFE0_0000_0000;
+
+/// Size of the stack each hart handles traps on once its kernel stack has
+/// overflowed, which needs enough room to panic
+pub const SCRATCH_STACK_SIZE: usize = 16 * 1024;
+
+/// Where the next kernel stack's guard page goes
+static NEXT_KERNEL_STACK: SpinMutex<usize> = SpinMutex::new(KERNEL_STACK_REGION);
+
+/// Allocate and map a kernel stack of `size` bytes with an unmapped guard page
+/// below it, returning the top of the stack. The first stack has to be
+/// allocated before any tasks' page tables are created so that they share the
+/// stack region's mappings.
 pub fn alloc_kernel_stack(size: usize) -> *mut u8 {
     assert!(size.is_power_of_two());
     assert_eq!(size % 4096, 0);
@@ -60,9 +78,38 @@ pub fn alloc_kernel_stack(size: usize) -> *mut u8 {
     let phys_start =
         unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc_contiguous(PageSize::Kilopage, total_pages) }.expect("oom :(");
 
-    // FIXME: Eventually make these proper virtual address ranges so we can add
-    // guard pages which will detect stack overflowing
-    phys2virt(phys_start.as_phys_address().offset(total_pages * 4096)).as_mut_ptr()
+    let mut next = NEXT_KERNEL_STACK.lock();
+    let bottom = VirtualAddress::new(*next + 4096);
+    *next = bottom.as_usize() + size;
+
+    for i in 0..total_pages {
+        paging::map_kernel_page(
+            phys_start.as_phys_address().offset(i * 4096),
+            bottom.add(i * 4096),
+            flags::DIRTY | flags::ACCESSED | flags::READ | flags::WRITE | flags::VALID,
+        );
+    }
+    drop(next);
+
+    // Anything with a frame larger than the guard page can skip over it, a
+    // canary at the bottom of the stack catches those after the fact
+    unsafe { *bottom.as_mut_ptr().cast::<u64>() = crate::crypto::rand::canary(bottom.as_usize()) };
+
+    bottom.add(total_pages * 4096).as_mut_ptr()
+}
+
+/// The guard page below a stack from [`alloc_kernel_stack`], given the top of
+/// the stack and its size
+pub fn kernel_stack_guard(stack: *mut u8, size: usize) -> core::ops::Range<usize> {
+    let bottom = stack as usize - size;
+    bottom - 4096..bottom
+}
+
+/// Whether the canary at the bottom of a stack from [`alloc_kernel_stack`] is
+/// still there, given the top of the stack and its size
+pub fn kernel_stack_intact(stack: *mut u8, size: usize) -> bool {
+    let bottom = stack.wrapping_sub(size);
+    unsafe { *bottom.cast::<u64>() == crate::crypto::rand::canary(bottom as usize) }
 }
 
 #[track_caller]
@@ -144,43 +144,7 @@ impl PageTable {
     #[doc(hidden)]
     #[track_caller]
     pub fn static_map(&mut self, from: PhysicalAddress, to: VirtualAddress, flags: Flags, size: PageSize) {
-        size.assert_addr_aligned(from.as_usize());
-        size.assert_addr_aligned(to.as_usize());
-
-        let mut table = &mut *self.root;
-        let mut current = PageSize::top_level();
-
-        for vpn in to.vpns().into_iter().rev() {
-            let entry = &mut table.entries[vpn];
-            if current == size {
-                if entry.is_valid() {
-                    panic!("attempted to map an already-mapped virtual address: {:#p} -> {:#p}", from, to);
-                }
-
-                entry.set_flags(flags);
-                entry.set_ppn(from);
-
-                return;
-            }
-
-            match entry.kind() {
-                EntryKind::Leaf => panic!("man ionno, wtf"),
-                EntryKind::Branch(paddr) => table = unsafe { &mut *(phys2virt(paddr).as_mut_ptr().cast()) },
-                EntryKind::NotValid => {
-                    let new_subtable = Box::leak(Self::new_table());
-                    let subtable_phys = virt2phys(VirtualAddress::from_ptr(new_subtable));
-                    entry.set_flags(flags::VALID);
-                    entry.set_ppn(subtable_phys);
-
-                    table = new_subtable;
-                }
-            }
-
-            current = match current.next() {
-                Some(next) => next,
-                None => unreachable!("next level page size"),
-            };
-        }
+        map_leaking_subtables(&mut *self.root, from, to, flags, size);
     }
 
     fn with_entry_mut<T>(
@@ -188,25 +152,7 @@ impl PageTable {
         address: VirtualAddress,
         f: impl FnOnce(&mut repr::PageTableEntry, PageSize) -> T,
     ) -> Option<T> {
-        let mut table = &mut *self.root;
-        let mut current = PageSize::top_level();
-
-        for vpn in address.vpns().into_iter().rev() {
-            let entry = &mut table.entries[vpn];
-
-            match entry.kind() {
-                EntryKind::Leaf => return Some(f(entry, current)),
-                EntryKind::Branch(paddr) => table = unsafe { &mut *(phys2virt(paddr).as_mut_ptr().cast()) },
-                EntryKind::NotValid => return None,
-            }
-
-            current = match current.next() {
-                Some(next) => next,
-                None => unreachable!("next level page size"),
-            };
-        }
-
-        None
+        walk_mut(&mut self.root, address, f)
     }
 
     fn with_entry<T>(
@@ -214,25 +160,7 @@ impl PageTable {
         address: VirtualAddress,
         f: impl FnOnce(&repr::PageTableEntry, PageSize) -> T,
     ) -> Option<T> {
-        let mut table = &*self.root;
-        let mut current = PageSize::top_level();
-
-        for vpn in address.vpns().into_iter().rev() {
-            let entry = &table.entries[vpn];
-
-            match entry.kind() {
-                EntryKind::Leaf => return Some(f(entry, current)),
-                EntryKind::Branch(paddr) => table = unsafe { &*(phys2virt(paddr).as_mut_ptr().cast()) },
-                EntryKind::NotValid => return None,
-            }
-
-            current = match current.next() {
-                Some(next) => next,
-                None => unreachable!("next level page size"),
-            };
-        }
-
-        None
+        walk(&self.root, address, f)
     }
 
     fn copy_kernel_regions(&mut self) {
@@ -252,6 +180,154 @@ impl PageTable {
     }
 }
 
+/// Map a page into the kernel half of the currently active page table. Every
+/// page table shares the kernel half's subtables with the one that was active
+/// when it was created, so this is only visible to page tables created after
+/// the first page under the same top level entry was mapped.
+#[track_caller]
+pub fn map_kernel_page(from: PhysicalAddress, to: VirtualAddress, flags: Flags) {
+    assert!(to.is_kernel_region());
+
+    // Safety: `satp` always points to a valid root page table once paging is
+    // enabled
+    let root = unsafe { &mut *phys2virt(crate::csr::satp::read().root_page_table).as_mut_ptr().cast() };
+    map_leaking_subtables(root, from, to, flags, PageSize::Kilopage);
+    crate::mem::sfence(Some(to), None);
+}
+
+/// Unmap a page mapped with [`map_kernel_page`], returning the physical
+/// address it was mapped to. Only the local hart's TLB is flushed, so other
+/// harts have to be sent a shootdown before the memory is reused.
+#[track_caller]
+pub fn unmap_kernel_page(at: VirtualAddress) -> PhysicalAddress {
+    assert!(at.is_kernel_region());
+
+    // Safety: `satp` always points to a valid root page table once paging is
+    // enabled
+    let root = unsafe { &mut *phys2virt(crate::csr::satp::read().root_page_table).as_mut_ptr().cast() };
+    let paddr = walk_mut(root, at, |e, _| {
+        let paddr = e.ppn();
+        *e = repr::PageTableEntry::new();
+        paddr
+    })
+    .flatten();
+
+    crate::mem::sfence(Some(at), None);
+
+    match paddr {
+        Some(paddr) => paddr,
+        None => panic!("attempting to unmap an already unmapped kernel page: {:#p}", at),
+    }
+}
+
+#[track_caller]
+fn map_leaking_subtables(
+    root: &mut repr::PageTable,
+    from: PhysicalAddress,
+    to: VirtualAddress,
+    flags: Flags,
+    size: PageSize,
+) {
+    size.assert_addr_aligned(from.as_usize());
+    size.assert_addr_aligned(to.as_usize());
+
+    let mut table = root;
+    let mut current = PageSize::top_level();
+
+    for vpn in to.vpns().into_iter().rev() {
+        let entry = &mut table.entries[vpn];
+        if current == size {
+            if entry.is_valid() {
+                panic!("attempted to map an already-mapped virtual address: {:#p} -> {:#p}", from, to);
+            }
+
+            entry.set_flags(flags);
+            entry.set_ppn(from);
+
+            return;
+        }
+
+        match entry.kind() {
+            EntryKind::Leaf => panic!("man ionno, wtf"),
+            EntryKind::Branch(paddr) => table = unsafe { &mut *(phys2virt(paddr).as_mut_ptr().cast()) },
+            EntryKind::NotValid => {
+                let new_subtable = Box::leak(PageTable::new_table());
+                let subtable_phys = virt2phys(VirtualAddress::from_ptr(new_subtable));
+                entry.set_flags(flags::VALID);
+                entry.set_ppn(subtable_phys);
+
+                table = new_subtable;
+            }
+        }
+
+        current = match current.next() {
+            Some(next) => next,
+            None => unreachable!("next level page size"),
+        };
+    }
+}
+
+/// Look up the [`Flags`] of the page containing `address` in the currently
+/// active page table
+pub fn active_page_flags(address: VirtualAddress) -> Option<Flags> {
+    // Safety: `satp` always points to a valid root page table once paging is
+    // enabled
+    let root = unsafe { &*phys2virt(crate::csr::satp::read().root_page_table).as_ptr().cast::<repr::PageTable>() };
+    walk(root, address, |e, _| e.flags())
+}
+
+fn walk<T>(
+    root: &repr::PageTable,
+    address: VirtualAddress,
+    f: impl FnOnce(&repr::PageTableEntry, PageSize) -> T,
+) -> Option<T> {
+    let mut table = root;
+    let mut current = PageSize::top_level();
+
+    for vpn in address.vpns().into_iter().rev() {
+        let entry = &table.entries[vpn];
+
+        match entry.kind() {
+            EntryKind::Leaf => return Some(f(entry, current)),
+            EntryKind::Branch(paddr) => table = unsafe { &*(phys2virt(paddr).as_mut_ptr().cast()) },
+            EntryKind::NotValid => return None,
+        }
+
+        current = match current.next() {
+            Some(next) => next,
+            None => unreachable!("next level page size"),
+        };
+    }
+
+    None
+}
+
+fn walk_mut<T>(
+    root: &mut repr::PageTable,
+    address: VirtualAddress,
+    f: impl FnOnce(&mut repr::PageTableEntry, PageSize) -> T,
+) -> Option<T> {
+    let mut table = root;
+    let mut current = PageSize::top_level();
+
+    for vpn in address.vpns().into_iter().rev() {
+        let entry = &mut table.entries[vpn];
+
+        match entry.kind() {
+            EntryKind::Leaf => return Some(f(entry, current)),
+            EntryKind::Branch(paddr) => table = unsafe { &mut *(phys2virt(paddr).as_mut_ptr().cast()) },
+            EntryKind::NotValid => return None,
+        }
+
+        current = match current.next() {
+            Some(next) => next,
+            None => unreachable!("next level page size"),
+        };
+    }
+
+    None
+}
+
 unsafe impl Send for PageTable {}
 unsafe impl Sync for PageTable {}
 
@@ -0,0 +1,353 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{PhysicalAddress, PhysicalMemoryAllocator, PhysicalPage};
+use crate::{mem::paging::PageSize, utils::round_up_to_next, Units};
+
+/// Largest block size is 4 KiB << 18 = 1 GiB
+const MAX_ORDER: usize = 18;
+/// Marks a page state entry as the head of a free block, with the order of
+/// the block stored in the lower bits
+const FREE: u8 = 0x80;
+/// Sentinel value for the end of a free list
+const NONE: usize = usize::MAX;
+
+/// Links stored in the first bytes of each free block
+struct FreeBlock {
+    next: usize,
+    prev: usize,
+}
+
+/// Binary buddy allocator. Blocks of `4 KiB << order` bytes are always
+/// naturally aligned in physical memory, so megapage and gigapage allocations
+/// can be satisfied directly, and freed blocks are merged with their buddy
+/// whenever possible to keep large contiguous ranges available.
+///
+/// The free lists are intrusive and stored in the free memory itself, while a
+/// byte of state per page is stored at the start of the managed memory.
+pub struct BuddyAllocator {
+    free_lists: [usize; MAX_ORDER + 1],
+    states: *mut u8,
+    mem_start: usize,
+    usable_start: usize,
+    mem_end: usize,
+}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        Self {
+            free_lists: [NONE; MAX_ORDER + 1],
+            states: core::ptr::null_mut(),
+            mem_start: 0,
+            usable_start: 0,
+            mem_end: 0,
+        }
+    }
+
+    fn block_size(order: usize) -> usize {
+        4.kib() << order
+    }
+
+    fn order_for(n_pages: usize) -> usize {
+        n_pages.next_power_of_two().trailing_zeros() as usize
+    }
+
+    fn pages_in(size: PageSize) -> usize {
+        size.to_byte_size() / 4.kib()
+    }
+
+    fn state(&mut self, addr: usize) -> &mut u8 {
+        let index = (addr - self.mem_start) / 4.kib();
+        unsafe { &mut *crate::mem::phys2virt(PhysicalAddress::from_ptr(self.states.add(index))).as_mut_ptr() }
+    }
+
+    fn block(addr: usize) -> &'static mut FreeBlock {
+        unsafe { &mut *crate::mem::phys2virt(PhysicalAddress::new(addr)).as_mut_ptr().cast() }
+    }
+
+    fn contains_block(&self, addr: usize, order: usize) -> bool {
+        addr >= self.usable_start && addr.checked_add(Self::block_size(order)).map_or(false, |end| end <= self.mem_end)
+    }
+
+    fn push(&mut self, addr: usize, order: usize) {
+        let head = self.free_lists[order];
+        *Self::block(addr) = FreeBlock { next: head, prev: NONE };
+
+        if head != NONE {
+            Self::block(head).prev = addr;
+        }
+
+        self.free_lists[order] = addr;
+        *self.state(addr) = FREE | order as u8;
+    }
+
+    fn remove(&mut self, addr: usize, order: usize) {
+        let FreeBlock { next, prev } = *Self::block(addr);
+
+        match prev {
+            NONE => self.free_lists[order] = next,
+            prev => Self::block(prev).next = next,
+        }
+
+        if next != NONE {
+            Self::block(next).prev = prev;
+        }
+
+        *self.state(addr) = 0;
+    }
+
+    fn alloc_order(&mut self, order: usize) -> Option<usize> {
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != NONE)?;
+        let addr = self.free_lists[current];
+        self.remove(addr, current);
+
+        // Split the block down to the requested size, returning the upper
+        // halves to their free lists
+        while current > order {
+            current -= 1;
+            self.push(addr + Self::block_size(current), current);
+        }
+
+        Some(addr)
+    }
+
+    /// The free block containing the page at `page`, as its address and order
+    fn free_block_containing(&mut self, page: usize) -> Option<(usize, usize)> {
+        (0..=MAX_ORDER).find_map(|order| {
+            let head = page & !(Self::block_size(order) - 1);
+
+            match self.contains_block(head, order) && *self.state(head) == FREE | order as u8 {
+                true => Some((head, order)),
+                false => None,
+            }
+        })
+    }
+
+    /// Whether any page of the block at `addr` is already free, either as part
+    /// of a free block containing it or as a free block inside of it
+    fn is_partly_free(&mut self, addr: usize, order: usize) -> bool {
+        self.free_block_containing(addr).is_some()
+            || (addr..addr + Self::block_size(order)).step_by(4.kib()).any(|page| *self.state(page) & FREE != 0)
+    }
+
+    fn free_block(&mut self, mut addr: usize, mut order: usize) {
+        assert!(
+            !self.is_partly_free(addr, order),
+            "[pmalloc.allocator] BuddyAllocator::free_block: double free detected for address {:#p}",
+            addr as *const u8
+        );
+
+        while order < MAX_ORDER {
+            let buddy = addr ^ Self::block_size(order);
+
+            if !self.contains_block(buddy, order) || *self.state(buddy) != FREE | order as u8 {
+                break;
+            }
+
+            self.remove(buddy, order);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+
+        self.push(addr, order);
+    }
+
+    /// Free an arbitrary page-aligned range by splitting it into the largest
+    /// naturally aligned blocks that fit
+    fn free_range(&mut self, mut addr: usize, mut n_pages: usize) {
+        while n_pages > 0 {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&o| addr % Self::block_size(o) == 0 && 1 << o <= n_pages)
+                .expect("page aligned address");
+
+            self.free_block(addr, order);
+            addr += Self::block_size(order);
+            n_pages -= 1 << order;
+        }
+    }
+}
+
+unsafe impl PhysicalMemoryAllocator for BuddyAllocator {
+    unsafe fn init(&mut self, start: *mut u8, end: *mut u8) {
+        assert!(!start.is_null(), "null start pointer!");
+        assert_eq!(start as usize % 4096, 0, "unaligned memory start page");
+
+        let n_pages = (end as usize - start as usize) / 4.kib();
+        self.states = start;
+        self.mem_start = start as usize;
+        self.usable_start = start as usize + round_up_to_next(n_pages, 4.kib());
+        self.mem_end = self.mem_start + n_pages * 4.kib();
+        self.free_lists = [NONE; MAX_ORDER + 1];
+
+        let states = crate::mem::phys2virt(PhysicalAddress::from_ptr(self.states)).as_mut_ptr();
+        core::slice::from_raw_parts_mut(states, n_pages).fill(0);
+
+        self.free_range(self.usable_start, (self.mem_end - self.usable_start) / 4.kib());
+    }
+
+    #[track_caller]
+    unsafe fn alloc(&mut self, align_to: PageSize) -> Option<PhysicalPage> {
+        let addr = self.alloc_order(Self::order_for(Self::pages_in(align_to)))?;
+        log::trace!("Allocated {:?} at: {:#p}", align_to, addr as *const u8);
+
+        Some(PhysicalPage::from_ptr(addr as *mut u8))
+    }
+
+    #[track_caller]
+    unsafe fn alloc_contiguous(&mut self, align_to: PageSize, n: usize) -> Option<PhysicalPage> {
+        if n == 0 {
+            return None;
+        }
+
+        let n_pages = n * Self::pages_in(align_to);
+        let order = Self::order_for(n_pages).max(Self::order_for(Self::pages_in(align_to)));
+        let addr = self.alloc_order(order)?;
+
+        // Give back whatever we don't need from the end of the block
+        self.free_range(addr + n_pages * 4.kib(), (1 << order) - n_pages);
+
+        Some(PhysicalPage::from_ptr(addr as *mut u8))
+    }
+
+    #[track_caller]
+    unsafe fn dealloc(&mut self, page: PhysicalPage, size: PageSize) {
+        self.free_range(page.as_phys_address().as_usize(), Self::pages_in(size));
+    }
+
+    #[track_caller]
+    unsafe fn dealloc_contiguous(&mut self, page: PhysicalPage, size: PageSize, n: usize) {
+        self.free_range(page.as_phys_address().as_usize(), n * Self::pages_in(size));
+    }
+
+    #[track_caller]
+    unsafe fn set_used(&mut self, page: PhysicalPage) {
+        let page = page.as_phys_address().as_usize();
+
+        // Find the free block containing the page, if there is one, and split
+        // it until only the page itself is left
+        let (mut head, order) = match self.free_block_containing(page) {
+            Some(block) => block,
+            None => return,
+        };
+
+        self.remove(head, order);
+
+        for order in (0..order).rev() {
+            let upper = head + Self::block_size(order);
+
+            match page >= upper {
+                true => {
+                    self.push(head, order);
+                    head = upper;
+                }
+                false => self.push(upper, order),
+            }
+        }
+    }
+
+    #[track_caller]
+    unsafe fn set_unused(&mut self, page: PhysicalPage) {
+        self.free_block(page.as_phys_address().as_usize(), 0);
+    }
+}
+
+unsafe impl Send for BuddyAllocator {}
+unsafe impl Sync for BuddyAllocator {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::phys::PHYSICAL_MEMORY_ALLOCATOR;
+    use vanadinite_macros::test;
+
+    const N_PAGES: usize = 16;
+
+    /// Run `f` with an allocator managing `N_PAGES` free pages, starting at an
+    /// address aligned to their combined size so that they begin as one block
+    fn with_allocator(f: impl FnOnce(&mut BuddyAllocator, usize)) {
+        let backing =
+            unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc_contiguous(PageSize::Kilopage, 2 * N_PAGES) }.unwrap();
+
+        // The page before the managed pages holds their states
+        let start = round_up_to_next(backing.as_phys_address().as_usize() + 4.kib(), N_PAGES * 4.kib());
+        let mut allocator = BuddyAllocator::new();
+        unsafe { allocator.init((start - 4.kib()) as *mut u8, (start + N_PAGES * 4.kib()) as *mut u8) };
+
+        f(&mut allocator, start);
+
+        unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().dealloc_contiguous(backing, PageSize::Kilopage, 2 * N_PAGES) };
+    }
+
+    /// The heads of the free lists when each holds at most the one block given
+    fn free_lists(blocks: &[(usize, usize)]) -> [usize; MAX_ORDER + 1] {
+        let mut free_lists = [NONE; MAX_ORDER + 1];
+        for &(addr, order) in blocks {
+            free_lists[order] = addr;
+        }
+
+        free_lists
+    }
+
+    #[test]
+    fn split_and_merge() {
+        with_allocator(|allocator, start| unsafe {
+            assert_eq!(allocator.free_lists, free_lists(&[(start, 4)]));
+
+            let page = allocator.alloc(PageSize::Kilopage).unwrap();
+            assert_eq!(page.as_phys_address().as_usize(), start);
+            assert_eq!(
+                allocator.free_lists,
+                free_lists(&[(start + 4.kib(), 0), (start + 8.kib(), 1), (start + 16.kib(), 2), (start + 32.kib(), 3)])
+            );
+
+            allocator.dealloc(page, PageSize::Kilopage);
+            assert_eq!(allocator.free_lists, free_lists(&[(start, 4)]));
+        });
+    }
+
+    #[test]
+    fn contiguous_allocations_are_aligned() {
+        with_allocator(|allocator, start| unsafe {
+            let page = allocator.alloc(PageSize::Kilopage).unwrap();
+
+            // Three pages come from a naturally aligned block of four, with the
+            // last page given back
+            let pages = allocator.alloc_contiguous(PageSize::Kilopage, 3).unwrap();
+            assert_eq!(pages.as_phys_address().as_usize(), start + 16.kib());
+            assert_eq!(allocator.free_block_containing(start + 24.kib()), None);
+            assert_eq!(allocator.free_block_containing(start + 28.kib()), Some((start + 28.kib(), 0)));
+
+            allocator.dealloc_contiguous(pages, PageSize::Kilopage, 3);
+            allocator.dealloc(page, PageSize::Kilopage);
+            assert_eq!(allocator.free_lists, free_lists(&[(start, 4)]));
+        });
+    }
+
+    #[test]
+    fn empty_contiguous_allocation() {
+        with_allocator(|allocator, start| unsafe {
+            assert!(allocator.alloc_contiguous(PageSize::Kilopage, 0).is_none());
+            assert_eq!(allocator.free_lists, free_lists(&[(start, 4)]));
+        });
+    }
+
+    #[test]
+    fn free_pages_inside_blocks() {
+        with_allocator(|allocator, start| unsafe {
+            // Only the head of a merged block is marked as free
+            assert!(allocator.is_partly_free(start + 12.kib(), 0));
+
+            let pages = allocator.alloc_contiguous(PageSize::Kilopage, 4).unwrap();
+            assert_eq!(pages.as_phys_address().as_usize(), start);
+            assert!(!allocator.is_partly_free(start, 2));
+
+            allocator.dealloc(PhysicalPage::from_ptr((start + 4.kib()) as *mut u8), PageSize::Kilopage);
+            assert!(allocator.is_partly_free(start, 2));
+        });
+    }
+}
@@ -6,15 +6,20 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 pub mod bitmap;
+pub mod buddy;
+pub mod reserve;
 
 use crate::mem::paging::PhysicalAddress;
-use bitmap::BitmapAllocator;
 use sync::SpinMutex;
 
 use super::paging::PageSize;
 
-#[cfg(any(not(any(feature = "pmalloc.allocator.buddy")), feature = "pmalloc.allocator.bitmap"))]
-pub static PHYSICAL_MEMORY_ALLOCATOR: SpinMutex<BitmapAllocator> = SpinMutex::new(BitmapAllocator::new());
+#[cfg(feature = "pmalloc.allocator.bitmap")]
+pub static PHYSICAL_MEMORY_ALLOCATOR: SpinMutex<bitmap::BitmapAllocator> =
+    SpinMutex::new(bitmap::BitmapAllocator::new());
+
+#[cfg(not(feature = "pmalloc.allocator.bitmap"))]
+pub static PHYSICAL_MEMORY_ALLOCATOR: SpinMutex<buddy::BuddyAllocator> = SpinMutex::new(buddy::BuddyAllocator::new());
 
 pub unsafe trait PhysicalMemoryAllocator {
     /// # Safety
@@ -79,7 +84,8 @@ impl PhysicalPage {
 }
 
 pub fn alloc_page() -> PhysicalPage {
-    unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc(PageSize::Kilopage).expect("out of memory") }
+    let page = unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc(PageSize::Kilopage) };
+    page.or_else(reserve::take).expect("out of memory")
 }
 
 pub fn zalloc_page() -> PhysicalPage {
@@ -0,0 +1,119 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Frames held back on each hart for allocations made while handling an
+//! interrupt or fault. Those can't return an error to anyone or wait for
+//! memory to be freed, so when the main allocator runs dry they dip into the
+//! current hart's reserve instead, and the reserve is topped back up once
+//! memory is available again.
+
+use super::{PhysicalMemoryAllocator, PhysicalPage, PHYSICAL_MEMORY_ALLOCATOR};
+use crate::mem::paging::PageSize;
+use core::cell::{Cell, RefCell};
+
+/// Number of frames each hart keeps in reserve
+pub const RESERVED_FRAMES_PER_HART: usize = 8;
+
+#[thread_local]
+static RESERVE: RefCell<Reserve> = RefCell::new(Reserve::new());
+
+/// How many [`Critical`] sections the current hart is nested in
+#[thread_local]
+static CRITICAL_DEPTH: Cell<usize> = Cell::new(0);
+
+struct Reserve {
+    frames: [Option<PhysicalPage>; RESERVED_FRAMES_PER_HART],
+    len: usize,
+}
+
+impl Reserve {
+    const fn new() -> Self {
+        Self { frames: [None; RESERVED_FRAMES_PER_HART], len: 0 }
+    }
+
+    fn push(&mut self, frame: PhysicalPage) {
+        self.frames[self.len] = Some(frame);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<PhysicalPage> {
+        match self.len {
+            0 => None,
+            _ => {
+                self.len -= 1;
+                self.frames[self.len].take()
+            }
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == RESERVED_FRAMES_PER_HART
+    }
+
+    fn fill_from(&mut self, allocator: &mut impl PhysicalMemoryAllocator) {
+        while !self.is_full() {
+            match unsafe { allocator.alloc(PageSize::Kilopage) } {
+                Some(frame) => self.push(frame),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Marks the current hart as handling an interrupt or fault for as long as
+/// it's alive, allowing allocations to fall back to the hart's reserve
+#[must_use]
+pub struct Critical(());
+
+impl Critical {
+    pub fn enter() -> Self {
+        CRITICAL_DEPTH.set(CRITICAL_DEPTH.get() + 1);
+        Self(())
+    }
+}
+
+impl Drop for Critical {
+    fn drop(&mut self) {
+        let depth = CRITICAL_DEPTH.get() - 1;
+        CRITICAL_DEPTH.set(depth);
+
+        // The interrupted code may be holding the allocator lock, in which
+        // case the reserve is topped up after some later interrupt instead
+        if depth == 0 && !RESERVE.borrow().is_full() {
+            if let Some(mut allocator) = PHYSICAL_MEMORY_ALLOCATOR.try_lock() {
+                RESERVE.borrow_mut().fill_from(&mut *allocator);
+            }
+        }
+    }
+}
+
+/// Whether the current hart is inside of a [`Critical`] section
+pub fn in_critical() -> bool {
+    CRITICAL_DEPTH.get() > 0
+}
+
+/// Top the current hart's reserve back up from the main allocator, as far as
+/// it's able to
+pub fn replenish() {
+    RESERVE.borrow_mut().fill_from(&mut *PHYSICAL_MEMORY_ALLOCATOR.lock());
+}
+
+/// Take a frame from the current hart's reserve if it's inside of a
+/// [`Critical`] section
+pub fn take() -> Option<PhysicalPage> {
+    if !in_critical() {
+        return None;
+    }
+
+    let frame = RESERVE.borrow_mut().pop();
+    match frame {
+        Some(_) => log::warn!("Out of memory, using emergency reserve on hart {}", crate::HART_ID.get()),
+        None => log::error!("Emergency memory reserve exhausted on hart {}", crate::HART_ID.get()),
+    }
+
+    frame
+}
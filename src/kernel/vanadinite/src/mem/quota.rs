@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Memory each thread group can have buffered in channels which hasn't been
+/// read by the other end yet
+pub const DEFAULT_MEMORY_QUOTA: usize = 16 * 1024 * 1024;
+
+/// Limits how much memory a thread group can tie up in the kernel on behalf of
+/// other tasks, so that flooding a task which isn't keeping up can't exhaust
+/// memory that the rest of the kernel needs
+#[derive(Debug)]
+pub struct MemoryQuota {
+    used: AtomicUsize,
+    limit: usize,
+}
+
+impl MemoryQuota {
+    pub const fn new(limit: usize) -> Self {
+        Self { used: AtomicUsize::new(0), limit }
+    }
+
+    /// Charge `bytes` against the quota, which is given back once the returned
+    /// [`Charge`] is dropped. Fails if it would go over the limit.
+    pub fn charge(self: &Arc<Self>, bytes: usize) -> Option<Charge> {
+        let mut used = self.used.load(Ordering::Acquire);
+        loop {
+            let new = used.checked_add(bytes).filter(|&new| new <= self.limit)?;
+            match self.used.compare_exchange_weak(used, new, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return Some(Charge { quota: Arc::clone(self), bytes }),
+                Err(current) => used = current,
+            }
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
+/// Memory charged against a [`MemoryQuota`]
+#[derive(Debug)]
+pub struct Charge {
+    quota: Arc<MemoryQuota>,
+    bytes: usize,
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.quota.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn charges_are_returned() {
+        let quota = Arc::new(MemoryQuota::new(4096));
+
+        let first = quota.charge(3000).unwrap();
+        assert!(quota.charge(2000).is_none());
+        let second = quota.charge(1096).unwrap();
+        assert_eq!(quota.used(), quota.limit());
+
+        drop(first);
+        assert_eq!(quota.used(), 1096);
+        assert!(quota.charge(usize::MAX).is_none());
+
+        drop(second);
+        assert_eq!(quota.used(), 0);
+    }
+}
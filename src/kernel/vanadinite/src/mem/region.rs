@@ -35,6 +35,25 @@ impl MemoryRegion {
             MemoryRegion::Backed(backing) => backing.page_count(),
         }
     }
+
+    /// Split the pages of the region into smaller pages of the given
+    /// [`PageSize`], returning `false` if the region can't be split because its
+    /// physical memory is shared with other regions
+    pub fn split(&mut self, into: PageSize) -> bool {
+        match self {
+            MemoryRegion::GuardPage => true,
+            MemoryRegion::Lazy { page_size, n_pages } => {
+                *n_pages *= page_size.to_byte_size() / into.to_byte_size();
+                *page_size = into;
+                true
+            }
+            MemoryRegion::Backed(PhysicalRegion::Unique(unique)) => {
+                unique.split(into);
+                true
+            }
+            MemoryRegion::Backed(PhysicalRegion::Shared(_)) => false,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -91,35 +110,73 @@ impl UniquePhysicalRegion {
 
     #[track_caller]
     pub fn alloc_contiguous(page_size: PageSize, n_pages: usize) -> Self {
+        Self::try_alloc_contiguous(page_size, n_pages).expect("couldn't alloc contiguous region")
+    }
+
+    #[track_caller]
+    pub fn alloc_sparse(page_size: PageSize, n_pages: usize) -> Self {
+        Self::try_alloc_sparse(page_size, n_pages).expect("couldn't alloc sparse region")
+    }
+
+    /// Same as [`Self::alloc_contiguous`], but returns `None` if the physical
+    /// memory allocator can't satisfy the request
+    #[track_caller]
+    pub fn try_alloc_contiguous(page_size: PageSize, n_pages: usize) -> Option<Self> {
         // log::trace!("Allocating page for contiguous region");
         let mut lock = PHYSICAL_MEMORY_ALLOCATOR.lock();
+        let kind = PhysicalRegionKind::Contiguous(unsafe { lock.alloc_contiguous(page_size, n_pages)? });
 
-        let kind = PhysicalRegionKind::Contiguous(unsafe {
-            lock.alloc_contiguous(page_size, n_pages).expect("couldn't alloc contiguous region")
-        });
-
-        Self { kind, page_size, n_pages }
+        Some(Self { kind, page_size, n_pages })
     }
 
+    /// Same as [`Self::alloc_sparse`], but returns `None` if the physical
+    /// memory allocator can't satisfy the request
     #[track_caller]
-    pub fn alloc_sparse(page_size: PageSize, n_pages: usize) -> Self {
+    pub fn try_alloc_sparse(page_size: PageSize, n_pages: usize) -> Option<Self> {
         if n_pages == 1 {
-            return Self::alloc_contiguous(page_size, 1);
+            return Self::try_alloc_contiguous(page_size, 1);
         }
 
-        let kind = PhysicalRegionKind::Sparse(unsafe {
-            let mut allocator = PHYSICAL_MEMORY_ALLOCATOR.lock();
-            let mut pages = Vec::with_capacity(n_pages);
-
-            for _ in 0..n_pages {
-                // log::trace!("Allocating page for sparse region");
-                pages.push(allocator.alloc(page_size).expect("couldn't alloc sparse region"));
+        // The heap may need to take the allocator lock itself, so make sure
+        // not to allocate while holding it
+        let mut pages = Vec::with_capacity(n_pages);
+        let mut allocator = PHYSICAL_MEMORY_ALLOCATOR.lock();
+
+        for _ in 0..n_pages {
+            // log::trace!("Allocating page for sparse region");
+            match unsafe { allocator.alloc(page_size) } {
+                Some(page) => pages.push(page),
+                None => {
+                    for page in pages {
+                        unsafe { allocator.dealloc(page, page_size) };
+                    }
+
+                    return None;
+                }
             }
+        }
 
-            pages
-        });
+        Some(Self { kind: PhysicalRegionKind::Sparse(pages), page_size, n_pages })
+    }
+
+    /// Reinterpret the region as being made up of smaller pages of the given
+    /// [`PageSize`], covering the same physical memory
+    pub fn split(&mut self, into: PageSize) {
+        let ratio = self.page_size.to_byte_size() / into.to_byte_size();
+
+        if let PhysicalRegionKind::Sparse(pages) = &mut self.kind {
+            *pages = pages
+                .iter()
+                .flat_map(|page| {
+                    (0..ratio).map(move |i| {
+                        PhysicalPage::from_ptr(page.as_phys_address().offset(i * into.to_byte_size()).as_mut_ptr())
+                    })
+                })
+                .collect();
+        }
 
-        Self { kind, page_size, n_pages }
+        self.page_size = into;
+        self.n_pages *= ratio;
     }
 
     pub fn physical_addresses(&self) -> impl Iterator<Item = PhysicalAddress> + '_ {
@@ -150,6 +207,21 @@ impl UniquePhysicalRegion {
         }
     }
 
+    /// Copy the contents of `other`, which must be the same size, into the
+    /// region
+    pub fn copy_from(&mut self, other: &UniquePhysicalRegion) {
+        assert_eq!(
+            (self.page_size, self.n_pages),
+            (other.page_size, other.n_pages),
+            "copying between differently sized regions"
+        );
+
+        let len = self.page_size.to_byte_size();
+        for (to, from) in self.physical_addresses().zip(other.physical_addresses()) {
+            unsafe { core::ptr::copy_nonoverlapping(phys2virt(from).as_ptr(), phys2virt(to).as_mut_ptr(), len) };
+        }
+    }
+
     pub fn zero(&mut self) {
         for phys_addr in self.physical_addresses() {
             let virt_addr = phys2virt(phys_addr).as_mut_ptr();
@@ -199,6 +271,13 @@ pub struct SharedPhysicalRegion {
     region: Arc<UniquePhysicalRegion>,
 }
 
+impl SharedPhysicalRegion {
+    /// Take back sole ownership of the memory if nothing else is sharing it
+    pub fn try_into_unique(self) -> Result<UniquePhysicalRegion, Self> {
+        Arc::try_unwrap(self.region).map_err(|region| Self { region })
+    }
+}
+
 impl core::ops::Deref for SharedPhysicalRegion {
     type Target = UniquePhysicalRegion;
 
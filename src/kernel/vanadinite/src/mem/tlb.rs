@@ -0,0 +1,257 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{
+    mem::{
+        paging::{PageSize, PhysicalAddress, VirtualAddress},
+        sfence,
+    },
+    utils::SameHartDeadlockDetection,
+};
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    ops::Range,
+    sync::atomic::{self, AtomicBool, AtomicUsize, Ordering},
+};
+use sync::{Backoff, Lazy};
+
+type SpinMutex<T> = sync::SpinMutex<T, SameHartDeadlockDetection>;
+
+/// Ranges larger than this many pages flush the whole address space instead
+/// of each individual page
+const FULL_FLUSH_THRESHOLD: usize = 64;
+
+enum Flush {
+    /// `asid` being `None` flushes the range from every address space
+    Tlb { asid: Option<u16>, range: Range<VirtualAddress> },
+    /// Make instruction fetches see all stores made before the request
+    InstructionCache,
+}
+
+struct ShootdownRequest {
+    flush: Flush,
+    pending: Arc<AtomicUsize>,
+}
+
+struct HartTlbState {
+    online: AtomicBool,
+    /// Physical address of the root page table currently loaded in `satp`, or
+    /// zero if the hart isn't running a task
+    active_table: AtomicUsize,
+    active_asid: AtomicUsize,
+    requests: SpinMutex<Vec<ShootdownRequest>>,
+    /// Whether `requests` is non-empty, so spin loops can check for requests
+    /// without touching the lock
+    requested: AtomicBool,
+}
+
+/// Set once the first hart comes online, before which there's nobody to send
+/// shootdowns to
+static ANY_ONLINE: AtomicBool = AtomicBool::new(false);
+
+static HARTS: Lazy<Vec<HartTlbState>> = Lazy::new(|| {
+    let n_cpus = crate::N_CPUS.load(Ordering::Acquire);
+    let mut v = Vec::with_capacity(n_cpus);
+
+    for _ in 0..n_cpus {
+        v.push(HartTlbState {
+            online: AtomicBool::new(false),
+            active_table: AtomicUsize::new(0),
+            active_asid: AtomicUsize::new(0),
+            requests: SpinMutex::new(Vec::new()),
+            requested: AtomicBool::new(false),
+        });
+    }
+
+    v
+});
+
+/// Mark the current hart as able to service shootdown requests
+pub fn hart_online() {
+    HARTS[crate::HART_ID.get()].online.store(true, Ordering::Release);
+    ANY_ONLINE.store(true, Ordering::Release);
+
+    // A hart waiting for a lock has interrupts disabled, and the lock may be
+    // held by a hart waiting on this one to acknowledge a shootdown
+    sync::set_spin_hook(handle_shootdowns);
+}
+
+/// Record the page table and ASID that the current hart is about to switch to.
+/// This must be called before `satp` is written, so that any shootdown of the
+/// address space which starts after this hart can cache its translations
+/// includes this hart.
+pub fn set_active(root_table: PhysicalAddress, asid: u16) {
+    let state = &HARTS[crate::HART_ID.get()];
+    state.active_asid.store(asid as usize, Ordering::SeqCst);
+    state.active_table.store(root_table.as_usize(), Ordering::SeqCst);
+
+    // Pairs with the fence in `shootdown`: either it sees this hart using the
+    // table, or this hart sees its page table modifications when walking it
+    atomic::fence(Ordering::SeqCst);
+}
+
+/// Record that the current hart is no longer running any task
+pub fn clear_active() {
+    HARTS[crate::HART_ID.get()].active_table.store(0, Ordering::SeqCst);
+}
+
+/// The ASID of the task the current hart is running, if any
+pub fn active_asid() -> Option<u16> {
+    let state = &HARTS[crate::HART_ID.get()];
+    match state.active_table.load(Ordering::SeqCst) {
+        0 => None,
+        _ => Some(state.active_asid.load(Ordering::SeqCst) as u16),
+    }
+}
+
+/// Invalidate the translations for `range` in the address space rooted at
+/// `root_table` on every hart that may have them cached, waiting until all
+/// remote harts have acknowledged the flush before returning. This must be
+/// called after the page table has been modified, and before any physical
+/// memory which was unmapped is reused.
+pub fn shootdown(root_table: PhysicalAddress, range: Range<VirtualAddress>) {
+    flush(None, &range);
+
+    // Make sure the page table modifications are visible before checking which
+    // harts are using the table, otherwise a hart could switch to it after we
+    // look and still see the old entries
+    atomic::fence(Ordering::SeqCst);
+
+    request_shootdowns(|state| {
+        (state.active_table.load(Ordering::SeqCst) == root_table.as_usize())
+            .then(|| Flush::Tlb { asid: Some(state.active_asid.load(Ordering::SeqCst) as u16), range: range.clone() })
+    });
+}
+
+/// Invalidate the translations for `range` in every address space on every
+/// online hart, waiting until all remote harts have acknowledged the flush
+/// before returning. This is for kernel mappings, which every page table
+/// shares and so may be cached under any ASID.
+pub fn shootdown_kernel(range: Range<VirtualAddress>) {
+    flush(None, &range);
+
+    if !ANY_ONLINE.load(Ordering::Acquire) {
+        return;
+    }
+
+    atomic::fence(Ordering::SeqCst);
+    request_shootdowns(|_| Some(Flush::Tlb { asid: None, range: range.clone() }));
+}
+
+/// Synchronize the instruction cache of every online hart with the stores made
+/// before this call, waiting until all remote harts have done so before
+/// returning. This has to happen before any code written as data is run, since
+/// `fence.i` only affects the hart executing it, and the task may run on any
+/// hart afterwards, not just the ones running it now.
+pub fn sync_instruction_caches() {
+    fence_i();
+
+    if !ANY_ONLINE.load(Ordering::Acquire) {
+        return;
+    }
+
+    atomic::fence(Ordering::SeqCst);
+    request_shootdowns(|_| Some(Flush::InstructionCache));
+}
+
+/// Send the flush `flush_for` returns to every other online hart it returns
+/// one for, then wait for them to finish
+fn request_shootdowns(flush_for: impl Fn(&HartTlbState) -> Option<Flush>) {
+    let current_hart = crate::HART_ID.get();
+    let pending = Arc::new(AtomicUsize::new(0));
+    let mut hart_mask = sbi::HartMask::new(0);
+    let mut any_remote = false;
+
+    for (hart_id, state) in HARTS.iter().enumerate() {
+        if hart_id == current_hart || !state.online.load(Ordering::Acquire) {
+            continue;
+        }
+
+        let flush = match flush_for(state) {
+            Some(flush) => flush,
+            None => continue,
+        };
+
+        pending.fetch_add(1, Ordering::AcqRel);
+        let mut requests = state.requests.lock();
+        requests.push(ShootdownRequest { flush, pending: Arc::clone(&pending) });
+        state.requested.store(true, Ordering::Release);
+        drop(requests);
+
+        hart_mask = hart_mask.with(hart_id);
+        any_remote = true;
+    }
+
+    if !any_remote {
+        return;
+    }
+
+    log::trace!("Sending shootdown IPI");
+    if let Err(e) = sbi::ipi::send_ipi(hart_mask) {
+        log::error!("Failed to send shootdown IPI: {:?}", e);
+    }
+
+    // Another hart may be waiting on us to acknowledge its own shootdown with
+    // interrupts disabled, which the spin hook services while we wait
+    let mut backoff = Backoff::new();
+    while pending.load(Ordering::Acquire) != 0 {
+        backoff.spin();
+    }
+}
+
+/// Process any shootdown requests sent to the current hart. Besides the IPI
+/// handler, this runs from every spin loop, since the hart may be waiting with
+/// interrupts disabled on a lock held by whoever sent them.
+pub fn handle_shootdowns() {
+    if !ANY_ONLINE.load(Ordering::Acquire) {
+        return;
+    }
+
+    let state = &HARTS[crate::HART_ID.get()];
+    if !state.requested.load(Ordering::Acquire) {
+        return;
+    }
+
+    // Waiting for the lock here would recurse through the spin hook. Whoever
+    // holds it is either pushing a request, and sends an IPI once it's done,
+    // or is an interrupted call to this function, which takes the requests.
+    let requests = match state.requests.try_lock() {
+        Some(mut requests) => {
+            state.requested.store(false, Ordering::Release);
+            core::mem::take(&mut *requests)
+        }
+        None => return,
+    };
+
+    for request in requests {
+        match request.flush {
+            Flush::Tlb { asid, range } => flush(asid, &range),
+            Flush::InstructionCache => fence_i(),
+        }
+
+        request.pending.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn flush(asid: Option<u16>, range: &Range<VirtualAddress>) {
+    let start = range.start.align_down_to(PageSize::Kilopage);
+    let end = range.end.align_to_next(PageSize::Kilopage);
+    let n_pages = (end.as_usize() - start.as_usize()) / PageSize::Kilopage.to_byte_size();
+
+    if n_pages > FULL_FLUSH_THRESHOLD {
+        sfence(None, asid);
+        return;
+    }
+
+    for page in (start.as_usize()..end.as_usize()).step_by(PageSize::Kilopage.to_byte_size()) {
+        sfence(Some(VirtualAddress::new(page)), asid);
+    }
+}
+
+fn fence_i() {
+    unsafe { core::arch::asm!("fence.i") };
+}
@@ -5,7 +5,7 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::csr::sstatus::TemporaryUserMemoryAccess;
+use crate::{csr::sstatus::TemporaryUserMemoryAccess, utils::LinkerSymbol};
 
 use super::{
     manager::{InvalidRegion, MemoryManager},
@@ -14,13 +14,17 @@ use super::{
         VirtualAddress,
     },
 };
-use core::marker::PhantomData;
+use alloc::vec::Vec;
+use core::{marker::PhantomData, mem::MaybeUninit};
 
 #[derive(Debug, Clone, Copy)]
 pub enum InvalidUserPtr {
     InvalidAccess,
     NotMapped,
     Unaligned,
+    /// The memory was unmapped or had its permissions changed after being
+    /// validated
+    Faulted,
 }
 
 #[derive(Debug)]
@@ -42,13 +46,19 @@ impl<Mode: UserPtrMode, T> RawUserPtr<Mode, T> {
     /// into the current address space
     ///
     /// Validates the [`RawUserPtr`] against the specified type and access mode
-    pub unsafe fn validate(self, manager: &MemoryManager) -> Result<ValidatedUserPtr<Mode, T>, InvalidUserPtr> {
+    pub unsafe fn validate(self, manager: &mut MemoryManager) -> Result<ValidatedUserPtr<Mode, T>, InvalidUserPtr> {
         if self.addr.as_usize() % core::mem::align_of::<T>() != 0 {
             return Err(InvalidUserPtr::Unaligned);
         }
 
         let addr_range = self.addr..self.addr.add(core::mem::size_of::<T>());
 
+        // The kernel writing through the pointer can't fault to copy
+        // snapshotted memory like userspace does, so it's copied up front
+        if Mode::FLAGS & flags::WRITE {
+            manager.break_snapshots(addr_range.clone());
+        }
+
         match manager.is_user_region_valid(addr_range, |f| f & Mode::FLAGS) {
             Ok(_) => Ok(ValidatedUserPtr { addr: self.addr, typë: self.typë, mode: self.mode }),
             Err((_, InvalidRegion::NotMapped)) => Err(InvalidUserPtr::NotMapped),
@@ -77,71 +87,23 @@ pub struct ValidatedUserPtr<Mode: UserPtrMode, T> {
     mode: PhantomData<Mode>,
 }
 
-impl<T: Copy> ValidatedUserPtr<Read, T> {
-    pub fn read(&self) -> T {
-        let _guard = TemporaryUserMemoryAccess::new();
-        unsafe { *self.addr.as_ptr().cast() }
-    }
-}
-
-impl<T> ValidatedUserPtr<Read, T> {
-    pub fn with<U>(&self, f: impl FnOnce(&T) -> U) -> U {
-        let _guard = TemporaryUserMemoryAccess::new();
-        f(unsafe { &*self.addr.as_ptr().cast() })
-    }
-}
-
-impl<T: Copy> ValidatedUserPtr<Read, T> {
-    pub fn write(&mut self, value: T) {
-        let _guard = TemporaryUserMemoryAccess::new();
-        unsafe { *self.addr.as_mut_ptr().cast() = value };
-    }
-}
-
-impl<T> ValidatedUserPtr<ReadWrite, T> {
-    pub fn with<U>(&mut self, f: impl FnOnce(&mut T) -> U) -> U {
-        let _guard = TemporaryUserMemoryAccess::new();
-        f(unsafe { &mut *self.addr.as_mut_ptr().cast() })
-    }
-}
-
-impl<Mode: UserPtrMode, T> ValidatedUserPtr<Mode, T> {
-    pub fn guarded(&self) -> ValidUserPtrGuard<'_, Mode, T> {
-        ValidUserPtrGuard::new(self)
-    }
-}
-
-pub struct ValidUserPtrGuard<'a, Mode: UserPtrMode, T> {
-    valid_ptr: &'a ValidatedUserPtr<Mode, T>,
-    _guard: TemporaryUserMemoryAccess,
-    _marker: PhantomData<&'a mut ()>,
-}
-
-impl<'a, Mode: UserPtrMode, T> ValidUserPtrGuard<'a, Mode, T> {
-    fn new(valid_ptr: &'a ValidatedUserPtr<Mode, T>) -> Self {
-        Self { valid_ptr, _guard: TemporaryUserMemoryAccess::new(), _marker: PhantomData }
-    }
-}
-
-impl<T> core::ops::Deref for ValidUserPtrGuard<'_, Read, T> {
-    type Target = T;
-
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.valid_ptr.addr.as_ptr().cast() }
-    }
-}
-
-impl<T> core::ops::Deref for ValidUserPtrGuard<'_, ReadWrite, T> {
-    type Target = T;
-
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.valid_ptr.addr.as_ptr().cast() }
+impl<Mode: UserPtrMode, T: Copy> ValidatedUserPtr<Mode, T> {
+    pub fn read(&self) -> Result<T, InvalidUserPtr> {
+        let mut value = MaybeUninit::<T>::uninit();
+        match unsafe { copy(value.as_mut_ptr().cast(), self.addr.as_ptr(), core::mem::size_of::<T>()) } {
+            0 => Ok(unsafe { value.assume_init() }),
+            _ => Err(InvalidUserPtr::Faulted),
+        }
     }
 }
 
-impl<T> core::ops::DerefMut for ValidUserPtrGuard<'_, ReadWrite, T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut *self.valid_ptr.addr.as_mut_ptr().cast() }
+impl<T: Copy> ValidatedUserPtr<ReadWrite, T> {
+    pub fn write(&mut self, value: T) -> Result<(), InvalidUserPtr> {
+        let src: *const T = &value;
+        match unsafe { copy(self.addr.as_mut_ptr(), src.cast(), core::mem::size_of::<T>()) } {
+            0 => Ok(()),
+            _ => Err(InvalidUserPtr::Faulted),
+        }
     }
 }
 
@@ -180,13 +142,29 @@ impl<Mode: UserPtrMode, T> RawUserSlice<Mode, T> {
     /// Validates the [`RawUserSlice`] against the specified type and access mode
     pub unsafe fn validate(
         self,
-        manager: &MemoryManager,
+        manager: &mut MemoryManager,
     ) -> Result<ValidatedUserSlice<Mode, T>, (VirtualAddress, InvalidUserPtr)> {
+        // Nothing is ever copied through an empty slice, so it's fine for it to
+        // point anywhere, null included
+        if self.len == 0 {
+            return Ok(ValidatedUserSlice::empty());
+        }
+
         if self.addr.as_usize() % core::mem::align_of::<T>() != 0 {
             return Err((self.addr, InvalidUserPtr::Unaligned));
         }
 
-        let addr_range = self.addr..self.addr.add(core::mem::size_of::<T>() * self.len);
+        let end = core::mem::size_of::<T>().checked_mul(self.len).and_then(|size| self.addr.checked_add(size));
+        let addr_range = match end {
+            Some(end) => self.addr..end,
+            None => return Err((self.addr, InvalidUserPtr::InvalidAccess)),
+        };
+
+        // The kernel writing through the pointer can't fault to copy
+        // snapshotted memory like userspace does, so it's copied up front
+        if Mode::FLAGS & flags::WRITE {
+            manager.break_snapshots(addr_range.clone());
+        }
 
         match manager.is_user_region_valid(addr_range, |f| f & Mode::FLAGS) {
             Ok(_) => Ok(ValidatedUserSlice { addr: self.addr, len: self.len, typë: self.typë, mode: self.mode }),
@@ -230,56 +208,156 @@ pub struct ValidatedUserSlice<Mode: UserPtrMode, T> {
     mode: PhantomData<Mode>,
 }
 
-impl<T> ValidatedUserSlice<Read, T> {
-    pub fn with<U>(&self, f: impl FnOnce(&[T]) -> U) -> U {
-        let _guard = TemporaryUserMemoryAccess::new();
-        f(unsafe { core::slice::from_raw_parts(self.addr.as_ptr().cast(), self.len) })
+impl<Mode: UserPtrMode, T> ValidatedUserSlice<Mode, T> {
+    pub fn empty() -> Self {
+        Self { addr: VirtualAddress::new(0), len: 0, typë: PhantomData, mode: PhantomData }
     }
 }
 
-impl<T> ValidatedUserSlice<ReadWrite, T> {
-    pub fn with<U>(&mut self, f: impl FnOnce(&mut [T]) -> U) -> U {
-        let _guard = TemporaryUserMemoryAccess::new();
-        f(unsafe { core::slice::from_raw_parts_mut(self.addr.as_mut_ptr().cast(), self.len) })
-    }
-}
+impl<Mode: UserPtrMode, T: Copy> ValidatedUserSlice<Mode, T> {
+    /// Copy as many elements as fit into `dst`, starting with the element at
+    /// `offset`, returning how many were copied
+    pub fn copy_from_user(&self, offset: usize, dst: &mut [T]) -> Result<usize, (VirtualAddress, InvalidUserPtr)> {
+        let n = dst.len().min(self.len.saturating_sub(offset));
+        let src = self.addr.add(offset * core::mem::size_of::<T>());
+        let size = n * core::mem::size_of::<T>();
 
-impl<Mode: UserPtrMode, T> ValidatedUserSlice<Mode, T> {
-    pub fn guarded(&self) -> ValidUserSliceGuard<'_, Mode, T> {
-        ValidUserSliceGuard::new(self)
+        match unsafe { copy(dst.as_mut_ptr().cast(), src.as_ptr(), size) } {
+            0 => Ok(n),
+            remaining => Err((src.add(size - remaining), InvalidUserPtr::Faulted)),
+        }
     }
-}
 
-pub struct ValidUserSliceGuard<'a, Mode: UserPtrMode, T> {
-    valid_slice: &'a ValidatedUserSlice<Mode, T>,
-    _guard: TemporaryUserMemoryAccess,
-    _marker: PhantomData<&'a mut ()>,
-}
+    pub fn to_vec(&self) -> Result<Vec<T>, (VirtualAddress, InvalidUserPtr)> {
+        let mut vec = Vec::with_capacity(self.len);
+        let size = self.len * core::mem::size_of::<T>();
 
-impl<'a, Mode: UserPtrMode, T> ValidUserSliceGuard<'a, Mode, T> {
-    fn new(valid_slice: &'a ValidatedUserSlice<Mode, T>) -> Self {
-        Self { valid_slice, _guard: TemporaryUserMemoryAccess::new(), _marker: PhantomData }
+        match unsafe { copy(vec.as_mut_ptr().cast(), self.addr.as_ptr(), size) } {
+            0 => {
+                unsafe { vec.set_len(self.len) };
+                Ok(vec)
+            }
+            remaining => Err((self.addr.add(size - remaining), InvalidUserPtr::Faulted)),
+        }
     }
-}
 
-impl<T> core::ops::Deref for ValidUserSliceGuard<'_, Read, T> {
-    type Target = [T];
+    pub fn len(&self) -> usize {
+        self.len
+    }
 
-    fn deref(&self) -> &Self::Target {
-        unsafe { core::slice::from_raw_parts(self.valid_slice.addr.as_ptr().cast(), self.valid_slice.len) }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
     }
 }
 
-impl<T> core::ops::Deref for ValidUserSliceGuard<'_, ReadWrite, T> {
-    type Target = [T];
+unsafe impl<Mode: UserPtrMode, T> Send for ValidatedUserSlice<Mode, T> {}
+
+impl<T: Copy> ValidatedUserSlice<ReadWrite, T> {
+    /// Copy as much of `src` as fits into the slice, starting at the element at
+    /// `offset`, returning how many elements were copied
+    pub fn copy_to_user(&mut self, offset: usize, src: &[T]) -> Result<usize, (VirtualAddress, InvalidUserPtr)> {
+        let n = src.len().min(self.len.saturating_sub(offset));
+        let dst = self.addr.add(offset * core::mem::size_of::<T>());
+        let size = n * core::mem::size_of::<T>();
 
-    fn deref(&self) -> &Self::Target {
-        unsafe { core::slice::from_raw_parts(self.valid_slice.addr.as_ptr().cast(), self.valid_slice.len) }
+        match unsafe { copy(dst.as_mut_ptr(), src.as_ptr().cast(), size) } {
+            0 => Ok(n),
+            remaining => Err((dst.add(size - remaining), InvalidUserPtr::Faulted)),
+        }
     }
 }
 
-impl<T> core::ops::DerefMut for ValidUserSliceGuard<'_, ReadWrite, T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { core::slice::from_raw_parts_mut(self.valid_slice.addr.as_mut_ptr().cast(), self.valid_slice.len) }
+/// Copy `len` bytes from `src` to `dst` with user memory access enabled,
+/// returning how many bytes weren't copied because of a page fault. Validating
+/// user memory only says it was mapped at the time, another thread in the task
+/// can unmap it or change its permissions before it's copied, which faults here
+/// instead of taking down the kernel.
+///
+/// # Safety
+/// Any kernel memory being copied to or from must be valid for `len` bytes
+unsafe fn copy(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    let _guard = TemporaryUserMemoryAccess::new();
+    user_copy(dst, src, len)
+}
+
+/// Where to resume after a page fault at `pc` in the kernel, if it happened
+/// while copying to or from user memory
+pub fn fault_fixup(pc: VirtualAddress) -> Option<usize> {
+    let (start, end, fixup) =
+        unsafe { (user_copy_start.as_usize(), user_copy_end.as_usize(), user_copy_fixup.as_usize()) };
+
+    match (start..end).contains(&pc.as_usize()) {
+        true => Some(fixup),
+        false => None,
     }
 }
+
+extern "C" {
+    static user_copy_start: LinkerSymbol;
+    static user_copy_end: LinkerSymbol;
+    static user_copy_fixup: LinkerSymbol;
+}
+
+/// Only the loads and stores between `user_copy_start` and `user_copy_end` are
+/// allowed to fault, which resumes at `user_copy_fixup` with the number of
+/// bytes left to copy still in `a2`. Whole words are copied at a time when the
+/// pointers are aligned to the same offset, which a fault can't land in the
+/// middle of since an aligned word never spans two pages.
+#[naked]
+#[allow(named_asm_labels)]
+unsafe extern "C" fn user_copy(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    #[rustfmt::skip]
+    core::arch::asm!(
+        "
+            beqz a2, user_copy_fixup
+            li t1, 8
+
+        .global user_copy_start
+        user_copy_start:
+            # Only bytes can be copied if the pointers can't both be aligned
+            xor t0, a0, a1
+            andi t0, t0, 7
+            bnez t0, 3f
+
+            # Copy bytes up to the first aligned address
+        1:
+            beqz a2, user_copy_end
+            andi t0, a0, 7
+            beqz t0, 2f
+            lbu t0, 0(a1)
+            sb t0, 0(a0)
+            addi a0, a0, 1
+            addi a1, a1, 1
+            addi a2, a2, -1
+            j 1b
+
+            # Then as many words as there are left
+        2:
+            bltu a2, t1, 3f
+            ld t0, 0(a1)
+            sd t0, 0(a0)
+            addi a0, a0, 8
+            addi a1, a1, 8
+            addi a2, a2, -8
+            j 2b
+
+            # And the rest as bytes
+        3:
+            beqz a2, user_copy_end
+            lbu t0, 0(a1)
+            sb t0, 0(a0)
+            addi a0, a0, 1
+            addi a1, a1, 1
+            addi a2, a2, -1
+            j 3b
+        .global user_copy_end
+        user_copy_end:
+
+        .global user_copy_fixup
+        user_copy_fixup:
+            mv a0, a2
+            ret
+        ",
+        options(noreturn),
+    );
+}
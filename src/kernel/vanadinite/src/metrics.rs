@@ -0,0 +1,220 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Counters kept by kernel subsystems, which `metricsmgr` reads along with a
+//! few gauges using a single syscall
+//!
+//! IPC statistics are reported for every pair of tasks with a channel between
+//! them as `ipc.<tid>.<peer tid>.<stat>`, and summed up for each task as
+//! `ipc.<tid>.<stat>`:
+//!
+//! * `msgs_out`, `bytes_out`: messages (and the bytes in them) sent to the peer
+//! * `msgs_in`, `bytes_in`: messages read from the peer
+//! * `queue_full`: sends which failed because the peer's queue was full
+//! * `queue_max`: the most messages ever waiting to be read at once
+//! * `blocked_us`: time spent blocked waiting for the peer to send something
+
+use crate::{csr, scheduler::TASKS, TIMER_FREQ};
+use alloc::{
+    collections::BTreeMap,
+    format,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use librust::{
+    syscalls::system::{InfoString, KernelMetric, MetricKind},
+    task::Tid,
+    time::ticks_to_ns,
+};
+use sync::SpinMutex;
+
+pub static SYSCALLS: Counter = Counter::new("syscalls");
+pub static INTERRUPTS: Counter = Counter::new("interrupts");
+pub static PAGE_FAULTS: Counter = Counter::new("page_faults");
+pub static TIMER_TICKS: Counter = Counter::new("timer_ticks");
+pub static IPC_MESSAGES: Counter = Counter::new("ipc_messages");
+pub static IPC_BYTES: Counter = Counter::new("ipc_bytes");
+
+static COUNTERS: &[&Counter] = &[&SYSCALLS, &INTERRUPTS, &PAGE_FAULTS, &TIMER_TICKS, &IPC_MESSAGES, &IPC_BYTES];
+
+/// The statistics of every channel endpoint, which are dropped from here the
+/// next time a snapshot is taken after the endpoint is gone
+static CHANNELS: SpinMutex<Vec<Weak<ChannelStats>>> = SpinMutex::new(Vec::new());
+
+pub struct Counter {
+    name: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str) -> Self {
+        Self { name, value: AtomicU64::new(0) }
+    }
+
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// IPC statistics for one end of a channel. These are kept outside of the
+/// owning task so that snapshots don't need to lock every task, and since
+/// either end can be handed off to another task, the tasks it's between are
+/// recorded whenever it's used rather than when it's created.
+#[derive(Debug, Default)]
+pub struct ChannelStats {
+    owner: AtomicUsize,
+    peer: AtomicUsize,
+    sent_messages: AtomicU64,
+    sent_bytes: AtomicU64,
+    received_messages: AtomicU64,
+    received_bytes: AtomicU64,
+    queue_full: AtomicU64,
+    queue_max: AtomicU64,
+    blocked_ticks: AtomicU64,
+}
+
+impl ChannelStats {
+    pub fn new() -> Arc<Self> {
+        let stats = Arc::new(Self::default());
+        CHANNELS.lock().push(Arc::downgrade(&stats));
+        stats
+    }
+
+    fn attribute(&self, owner: Tid, peer: Tid) {
+        self.owner.store(owner.value(), Ordering::Relaxed);
+        self.peer.store(peer.value(), Ordering::Relaxed);
+    }
+
+    pub fn record_send(&self, owner: Tid, peer: Tid, len: usize) {
+        self.attribute(owner, peer);
+        self.sent_messages.fetch_add(1, Ordering::Relaxed);
+        self.sent_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        IPC_MESSAGES.increment();
+        IPC_BYTES.add(len as u64);
+    }
+
+    pub fn record_receive(&self, owner: Tid, peer: Tid, len: usize) {
+        self.attribute(owner, peer);
+        self.received_messages.fetch_add(1, Ordering::Relaxed);
+        self.received_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_queue_full(&self) {
+        self.queue_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the number of messages waiting to be read from this end, which
+    /// is done by the sender so the ends are attributed from its point of view
+    pub fn record_queue_len(&self, owner: Tid, peer: Tid, len: usize) {
+        self.attribute(owner, peer);
+        self.queue_max.fetch_max(len as u64, Ordering::Relaxed);
+    }
+
+    /// Record having been blocked waiting for a message since `since`, in
+    /// ticks of the `time` CSR
+    pub fn record_blocked(&self, since: u64) {
+        self.blocked_ticks.fetch_add(csr::time::read().saturating_sub(since), Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct IpcTotals {
+    sent_messages: u64,
+    sent_bytes: u64,
+    received_messages: u64,
+    received_bytes: u64,
+    queue_full: u64,
+    queue_max: u64,
+    blocked_ticks: u64,
+}
+
+impl IpcTotals {
+    fn add(&mut self, stats: &ChannelStats) {
+        self.sent_messages += stats.sent_messages.load(Ordering::Relaxed);
+        self.sent_bytes += stats.sent_bytes.load(Ordering::Relaxed);
+        self.received_messages += stats.received_messages.load(Ordering::Relaxed);
+        self.received_bytes += stats.received_bytes.load(Ordering::Relaxed);
+        self.queue_full += stats.queue_full.load(Ordering::Relaxed);
+        self.queue_max = self.queue_max.max(stats.queue_max.load(Ordering::Relaxed));
+        self.blocked_ticks += stats.blocked_ticks.load(Ordering::Relaxed);
+    }
+
+    fn push_metrics(&self, prefix: &str, metrics: &mut Vec<KernelMetric>) {
+        let blocked_us = ticks_to_ns(self.blocked_ticks, TIMER_FREQ.load(Ordering::Relaxed)) / 1000;
+        let stats = [
+            ("msgs_out", MetricKind::Counter, self.sent_messages),
+            ("bytes_out", MetricKind::Counter, self.sent_bytes),
+            ("msgs_in", MetricKind::Counter, self.received_messages),
+            ("bytes_in", MetricKind::Counter, self.received_bytes),
+            ("queue_full", MetricKind::Counter, self.queue_full),
+            ("queue_max", MetricKind::Gauge, self.queue_max),
+            ("blocked_us", MetricKind::Counter, blocked_us),
+        ];
+
+        for (name, kind, value) in stats {
+            metrics.push(KernelMetric { name: InfoString::new(&format!("{}.{}", prefix, name)), kind, value });
+        }
+    }
+}
+
+/// Sum up the statistics of each endpoint that's been used, by the pair of
+/// tasks it's between and by the task which owns it
+fn ipc_metrics(metrics: &mut Vec<KernelMetric>) {
+    let mut links: BTreeMap<(usize, usize), IpcTotals> = BTreeMap::new();
+    let mut tasks: BTreeMap<usize, IpcTotals> = BTreeMap::new();
+
+    CHANNELS.lock().retain(|stats| match stats.upgrade() {
+        Some(stats) => {
+            let owner = stats.owner.load(Ordering::Relaxed);
+            if owner != 0 {
+                let peer = stats.peer.load(Ordering::Relaxed);
+                links.entry((owner, peer)).or_default().add(&stats);
+                tasks.entry(owner).or_default().add(&stats);
+            }
+
+            true
+        }
+        None => false,
+    });
+
+    for (owner, totals) in tasks {
+        totals.push_metrics(&format!("ipc.{}", owner), metrics);
+    }
+
+    for ((owner, peer), totals) in links {
+        totals.push_metrics(&format!("ipc.{}.{}", owner, peer), metrics);
+    }
+}
+
+/// The current value of every kernel metric
+pub fn snapshot() -> Vec<KernelMetric> {
+    let mut metrics: Vec<_> = COUNTERS
+        .iter()
+        .map(|counter| KernelMetric {
+            name: InfoString::new(counter.name),
+            kind: MetricKind::Counter,
+            value: counter.get(),
+        })
+        .collect();
+
+    let mut n_tasks = 0;
+    TASKS.try_for_each(|_, _| n_tasks += 1);
+    metrics.push(KernelMetric { name: InfoString::new("tasks"), kind: MetricKind::Gauge, value: n_tasks });
+
+    ipc_metrics(&mut metrics);
+
+    metrics
+}
@@ -0,0 +1,370 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{
+    interrupts::{controllers, isr::ISR_LIMIT, rate_limit},
+    io::{ConsoleDevice, StaticConsoleDevice, CONSOLE},
+    mem::paging::{active_page_flags, flags, VirtualAddress},
+    platform::clock,
+    scheduler::{Scheduler, SCHEDULER, TASKS},
+    task::{Task, TaskState},
+    N_CPUS, TIMER_FREQ,
+};
+use alloc::sync::Arc;
+use core::{fmt::Write, num::NonZeroUsize, sync::atomic::Ordering};
+use librust::task::Tid;
+use sync::{NoCheck, SpinMutexGuard};
+
+const LINE_MAX: usize = 128;
+const DUMP_MAX: usize = 4096;
+
+/// A minimal command monitor over the kernel console, usable without
+/// interrupts or a working scheduler. Everything it inspects is locked with
+/// `try_lock`-style operations so that it can't deadlock on locks that were
+/// held at the time the monitor was entered.
+pub struct Monitor {
+    console: Console,
+    post_mortem: bool,
+}
+
+impl Monitor {
+    /// Take exclusive control of the console, if it isn't currently locked
+    pub fn attach() -> Option<Self> {
+        Some(Self { console: Console(CONSOLE.try_lock()?), post_mortem: false })
+    }
+
+    /// Run the monitor after a panic, there's no way to resume execution so
+    /// this never returns
+    pub fn run_post_mortem(&mut self) -> ! {
+        self.post_mortem = true;
+        let _ = writeln!(self.console, "\nvanadinite post-mortem monitor, type `help` for a list of commands");
+
+        loop {
+            let mut buffer = [0; LINE_MAX];
+            let line = self.console.read_line(&mut buffer);
+            let mut args = line.split_whitespace();
+
+            let _ = match args.next() {
+                Some("halt") => crate::panicking::halt(),
+                Some("reboot") => crate::panicking::reboot(),
+                Some(command) => self.command(command, args),
+                None => Ok(()),
+            };
+        }
+    }
+
+    /// Run the monitor from the console break sequence, returning once the
+    /// user resumes execution
+    pub fn run_break(&mut self) {
+        let _ = writeln!(self.console, "\nvanadinite kernel monitor, type `help` for a list of commands");
+
+        loop {
+            let mut buffer = [0; LINE_MAX];
+            let line = self.console.read_line(&mut buffer);
+            let mut args = line.split_whitespace();
+
+            let _ = match args.next() {
+                Some("continue" | "c") => return,
+                Some("schedule") => {
+                    crate::scheduler::request_reschedule();
+                    return;
+                }
+                Some("reboot") => crate::panicking::reboot(),
+                Some("migrate") => self.migrate(args.next(), args.next()),
+                Some(command) => self.command(command, args),
+                None => Ok(()),
+            };
+        }
+    }
+
+    fn command<'b>(&mut self, command: &str, mut args: impl Iterator<Item = &'b str>) -> core::fmt::Result {
+        match command {
+            "help" => self.help(),
+            "tasks" => self.tasks(),
+            "regs" => self.regs(args.next()),
+            "map" => self.map(args.next()),
+            "plic" => self.plic(),
+            "clock" => self.clock(),
+            "x" => self.examine(args.next(), args.next()),
+            _ => writeln!(self.console, "unknown command: {}", command),
+        }
+    }
+
+    fn help(&mut self) -> core::fmt::Result {
+        writeln!(self.console, "  tasks              list all tasks")?;
+        writeln!(self.console, "  regs <tid>         dump the saved registers of a task")?;
+        writeln!(self.console, "  map <tid>          dump the address map of a task")?;
+        writeln!(self.console, "  plic               dump the interrupt controller state")?;
+        writeln!(self.console, "  clock              show the timebase frequency and its drift")?;
+        writeln!(self.console, "  x <addr> [len]     hex dump kernel memory (len defaults to 64)")?;
+
+        match self.post_mortem {
+            true => writeln!(self.console, "  halt               stop this hart")?,
+            false => {
+                writeln!(self.console, "  migrate <tid> <h>  move a task to hart `h`")?;
+                writeln!(self.console, "  schedule           resume, forcing this hart to reschedule")?;
+                writeln!(self.console, "  continue           resume execution")?;
+            }
+        }
+
+        writeln!(self.console, "  reboot             reboot the system")
+    }
+
+    /// Print the ID, state, program counter, and name of each task
+    pub fn tasks(&mut self) -> core::fmt::Result {
+        let console = &mut self.console;
+        let mut result = writeln!(console, "  {:>5}  {:<8}  {:<18}  name", "tid", "state", "pc");
+
+        let unlocked = TASKS.try_for_each(|tid, task| {
+            let line = match task.try_lock() {
+                Some(task) => {
+                    let state = match task.state {
+                        TaskState::Blocked => "blocked",
+                        TaskState::Dead => "dead",
+                        TaskState::Running => "running",
+                    };
+
+                    writeln!(
+                        console,
+                        "  {:>5}  {:<8}  {:<#18x}  {}",
+                        tid.value(),
+                        state,
+                        task.context.pc,
+                        task.display_name()
+                    )
+                }
+                None => writeln!(console, "  {:>5}  <locked>", tid.value()),
+            };
+
+            result = result.and(line);
+        });
+
+        match unlocked {
+            true => result,
+            false => writeln!(self.console, "task list is locked"),
+        }
+    }
+
+    fn regs(&mut self, tid: Option<&str>) -> core::fmt::Result {
+        self.with_task(tid, |console, task| {
+            // Tasks which are running on a hart right now will show the
+            // register state from the last time they were switched out
+            writeln!(console, "pc: {:#x}", task.context.pc)?;
+            writeln!(console, "{:#x?}", task.context.gp_regs)
+        })
+    }
+
+    fn map(&mut self, tid: Option<&str>) -> core::fmt::Result {
+        self.with_task(tid, |console, task| match task.group.memory_manager.try_lock() {
+            Some(memory_manager) => writeln!(console, "{:#?}", memory_manager.address_map_debug(None)),
+            None => writeln!(console, "address space is locked"),
+        })
+    }
+
+    fn clock(&mut self) -> core::fmt::Result {
+        writeln!(self.console, "timebase frequency: {} Hz", TIMER_FREQ.load(Ordering::Relaxed))?;
+
+        match clock::drift() {
+            Some(drift) => writeln!(
+                self.console,
+                "drift against {}: {} ppm (min {}, max {}) over {} samples",
+                drift.reference, drift.current_ppm, drift.min_ppm, drift.max_ppm, drift.samples
+            ),
+            None => writeln!(self.console, "no drift measurements available"),
+        }
+    }
+
+    fn plic(&mut self) -> core::fmt::Result {
+        let controllers = controllers();
+
+        if controllers.is_empty() {
+            return writeln!(self.console, "no interrupt controllers registered");
+        }
+
+        let n_harts = N_CPUS.load(Ordering::Relaxed);
+        for registered in controllers.iter() {
+            let controller = registered.controller;
+            writeln!(
+                self.console,
+                "{} (phandle {:?}): interrupt IDs {}..={}",
+                controller.name(),
+                registered.phandle,
+                registered.base + 1,
+                registered.base + registered.n_sources,
+            )?;
+
+            for hart in 0..n_harts {
+                writeln!(self.console, "  hart {}: threshold={}", hart, controller.hart_threshold(hart))?;
+            }
+
+            writeln!(
+                self.console,
+                "  {:>6}  {:>8}  {:>7}  {:>9}  enabled on harts",
+                "id", "priority", "pending", "throttled"
+            )?;
+            for (id, source) in registered.sources().zip(1..).take_while(|(id, _)| *id < ISR_LIMIT) {
+                let priority = controller.interrupt_priority(source);
+                let pending = controller.is_pending(source);
+                let enabled = (0..n_harts).any(|hart| controller.is_enabled(hart, source));
+                let throttled = rate_limit::throttle_count(id);
+
+                if priority == 0 && !pending && !enabled && throttled == 0 {
+                    continue;
+                }
+
+                write!(self.console, "  {:>6}  {:>8}  {:>7}  {:>9} ", id, priority, pending, throttled)?;
+                for hart in (0..n_harts).filter(|&hart| controller.is_enabled(hart, source)) {
+                    write!(self.console, " {}", hart)?;
+                }
+
+                writeln!(self.console)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parse a [`Tid`] and run `f` with its locked task, printing an error if
+    /// it doesn't exist or any of the required locks are held
+    fn with_task(
+        &mut self,
+        tid: Option<&str>,
+        f: impl FnOnce(&mut Console, &Task) -> core::fmt::Result,
+    ) -> core::fmt::Result {
+        let tid = match tid.and_then(|tid| tid.parse().ok()).and_then(NonZeroUsize::new) {
+            Some(tid) => Tid::new(tid),
+            None => return writeln!(self.console, "expected a task ID"),
+        };
+
+        let mut found = None;
+        let unlocked = TASKS.try_for_each(|t, task| {
+            if t == tid {
+                found = Some(Arc::clone(task));
+            }
+        });
+
+        match (unlocked, found) {
+            (false, _) => writeln!(self.console, "task list is locked"),
+            (true, None) => writeln!(self.console, "no task with ID {}", tid.value()),
+            (true, Some(task)) => match task.try_lock() {
+                Some(task) => f(&mut self.console, &task),
+                None => writeln!(self.console, "task {} is locked", tid.value()),
+            },
+        }
+    }
+
+    fn migrate(&mut self, tid: Option<&str>, hart: Option<&str>) -> core::fmt::Result {
+        let tid = tid.and_then(|tid| tid.parse().ok()).and_then(NonZeroUsize::new).map(Tid::new);
+        let hart = hart.and_then(|hart| hart.parse::<usize>().ok());
+
+        match (tid, hart) {
+            (Some(tid), Some(hart)) => match SCHEDULER.migrate(tid, hart) {
+                true => writeln!(self.console, "migrating task {} to hart {}", tid.value(), hart),
+                false => writeln!(self.console, "no task with ID {} or no hart {}", tid.value(), hart),
+            },
+            _ => writeln!(self.console, "usage: migrate <tid> <hart>"),
+        }
+    }
+
+    fn examine(&mut self, addr: Option<&str>, len: Option<&str>) -> core::fmt::Result {
+        let addr = match addr.and_then(|addr| usize::from_str_radix(addr.trim_start_matches("0x"), 16).ok()) {
+            Some(addr) => addr & !0xF,
+            None => return writeln!(self.console, "usage: x <addr> [len]"),
+        };
+
+        let len = match len.map(str::parse::<usize>) {
+            Some(Ok(len)) => len.min(DUMP_MAX),
+            Some(Err(_)) => return writeln!(self.console, "bad length"),
+            None => 64,
+        };
+
+        for line_addr in (addr..addr.saturating_add(len)).step_by(16) {
+            // Only read from kernel pages which are mapped readable, anything
+            // else would fault
+            let readable = match active_page_flags(VirtualAddress::new(line_addr)) {
+                Some(f) => f & flags::VALID && f & flags::READ && !(f & flags::USER),
+                None => false,
+            };
+
+            if !readable {
+                return writeln!(self.console, "{:#018x}: <not readable>", line_addr);
+            }
+
+            // Safety: the page containing the line was checked to be mapped and
+            // readable above, and the line is 16-byte aligned so it can't cross
+            // into another page
+            let bytes = unsafe { core::ptr::read_volatile(line_addr as *const [u8; 16]) };
+
+            write!(self.console, "{:#018x}:", line_addr)?;
+            for byte in bytes {
+                write!(self.console, " {:02x}", byte)?;
+            }
+
+            write!(self.console, "  ")?;
+            for byte in bytes {
+                self.console.write_char(match byte {
+                    0x20..=0x7E => byte as char,
+                    _ => '.',
+                })?;
+            }
+
+            writeln!(self.console)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The locked console, translating `\n` into `\r\n` on output
+struct Console(SpinMutexGuard<'static, StaticConsoleDevice, NoCheck>);
+
+impl Console {
+    fn read_line<'a>(&mut self, buffer: &'a mut [u8; LINE_MAX]) -> &'a str {
+        let _ = write!(self, "kmon> ");
+        let mut len = 0;
+
+        loop {
+            match self.0.read() {
+                b'\r' | b'\n' => break,
+                // Backspace & delete
+                0x08 | 0x7F if len > 0 => {
+                    len -= 1;
+                    let _ = write!(self, "\x08 \x08");
+                }
+                b @ 0x20..=0x7E if len < LINE_MAX => {
+                    buffer[len] = b;
+                    len += 1;
+                    let _ = write!(self, "{}", b as char);
+                }
+                _ => {}
+            }
+        }
+
+        let _ = writeln!(self);
+
+        // Only printable ASCII is accepted above, so this can't fail
+        core::str::from_utf8(&buffer[..len]).unwrap_or_default()
+    }
+}
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.0.write(b'\r');
+            }
+
+            self.0.write(byte);
+        }
+
+        // The monitor may be running with interrupts disabled, so it can't
+        // rely on the transmit interrupt to show its output
+        self.0.flush();
+
+        Ok(())
+    }
+}
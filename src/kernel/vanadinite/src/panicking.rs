@@ -0,0 +1,151 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{csr, monitor::Monitor, platform, TIMER_FREQ};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use librust::syscalls::system::ResetReason as LastResetReason;
+
+const DEFAULT_REBOOT_DELAY_SECS: u64 = 10;
+
+static POLICY: AtomicU8 = AtomicU8::new(PanicPolicy::Halt as u8);
+static REBOOT_DELAY_SECS: AtomicU64 = AtomicU64::new(DEFAULT_REBOOT_DELAY_SECS);
+static PANICKING: AtomicBool = AtomicBool::new(false);
+static OUT_OF_MEMORY: AtomicBool = AtomicBool::new(false);
+
+/// What the kernel does after printing a panic report, selected with the
+/// `panic=` bootarg
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PanicPolicy {
+    /// Stop the panicking hart, leaving the report on the console
+    Halt = 0,
+    /// Reboot the system through the SBI `SRST` extension after a delay
+    Reboot = 1,
+    /// Drop into the built-in [`Monitor`] for post-mortem inspection
+    Debug = 2,
+    /// Shut the system down with an exit code describing the panic, see
+    /// [`platform::ExitStatus`]
+    Exit = 3,
+}
+
+impl PanicPolicy {
+    fn from_u8(n: u8) -> Self {
+        match n {
+            1 => Self::Reboot,
+            2 => Self::Debug,
+            3 => Self::Exit,
+            _ => Self::Halt,
+        }
+    }
+}
+
+/// Parse the value of the `panic=` bootarg, which is one of `halt`,
+/// `reboot[:<seconds>]`, `debug`, or `exit`
+pub fn parse_panic_policy(value: Option<&str>) {
+    let mut parts = value.unwrap_or_default().splitn(2, ':');
+    let policy = match (parts.next(), parts.next()) {
+        (Some("halt"), None) => PanicPolicy::Halt,
+        (Some("debug"), None) => PanicPolicy::Debug,
+        (Some("exit"), None) => PanicPolicy::Exit,
+        (Some("reboot"), delay) => {
+            match delay.map(str::parse::<u64>) {
+                Some(Ok(delay)) => REBOOT_DELAY_SECS.store(delay, Ordering::Relaxed),
+                Some(Err(_)) => {
+                    log::warn!("Bad reboot delay for `panic`, defaulting to {}s", DEFAULT_REBOOT_DELAY_SECS)
+                }
+                None => {}
+            }
+
+            PanicPolicy::Reboot
+        }
+        _ => {
+            log::warn!("Unknown panic policy: {:?}, defaulting to `halt`", value);
+            PanicPolicy::Halt
+        }
+    };
+
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+pub fn policy() -> PanicPolicy {
+    PanicPolicy::from_u8(POLICY.load(Ordering::Relaxed))
+}
+
+/// Record that the panic about to happen is because an allocation failed
+pub fn note_out_of_memory() {
+    OUT_OF_MEMORY.store(true, Ordering::Release);
+}
+
+/// The exit status describing the current panic
+pub fn exit_status() -> platform::ExitStatus<'static> {
+    match OUT_OF_MEMORY.load(Ordering::Acquire) {
+        true => platform::ExitStatus::OutOfMemory,
+        false => platform::ExitStatus::Panic,
+    }
+}
+
+/// Carry out the configured [`PanicPolicy`] once the panic report has been
+/// printed. Only the first hart to panic follows the policy, any others (or a
+/// nested panic from within the policy itself) simply halt.
+pub fn finish_panic() -> ! {
+    if PANICKING.swap(true, Ordering::AcqRel) {
+        halt();
+    }
+
+    platform::pstore::record(LastResetReason::Panic);
+
+    match policy() {
+        PanicPolicy::Halt => halt(),
+        PanicPolicy::Reboot => {
+            let delay = REBOOT_DELAY_SECS.load(Ordering::Relaxed);
+            crate::error!("Rebooting in {} seconds", delay);
+
+            let deadline = csr::time::read() + delay * TIMER_FREQ.load(Ordering::Relaxed);
+            while csr::time::read() < deadline {}
+
+            reboot()
+        }
+        PanicPolicy::Debug => match Monitor::attach() {
+            Some(mut monitor) => monitor.run_post_mortem(),
+            None => {
+                crate::error!("Console is locked, unable to start the monitor");
+                halt()
+            }
+        },
+        PanicPolicy::Exit => platform::exit(exit_status()),
+    }
+}
+
+/// Stop the current hart
+pub fn halt() -> ! {
+    crate::error!("Shutting hart down");
+    csr::sstatus::disable_interrupts();
+    let _ = sbi::hart_state_management::hart_stop();
+
+    loop {
+        unsafe { core::arch::asm!("wfi") };
+    }
+}
+
+/// Reboot the system through the SBI `SRST` extension, halting instead if
+/// it's unavailable
+pub fn reboot() -> ! {
+    use sbi::{
+        probe_extension,
+        system_reset::{system_reset, ResetReason, ResetType, EXTENSION_ID},
+        ExtensionAvailability,
+    };
+
+    platform::pstore::record(LastResetReason::Reboot);
+
+    if let ExtensionAvailability::Available(_) = probe_extension(EXTENSION_ID) {
+        let _ = system_reset(ResetType::ColdReboot, ResetReason::SystemFailure);
+    }
+
+    crate::error!("SBI system reset unavailable, unable to reboot");
+    halt()
+}
@@ -0,0 +1,335 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Sampling profiler built on the hardware performance counters, which are
+//! programmed through the SBI PMU extension since S-mode can't configure them
+//! itself. Each hart counts the session's event in one counter set to overflow
+//! every `period` events, and records where it was into its own ring when the
+//! overflow interrupt arrives. Without the `Sscofpmf` extension there's no
+//! overflow interrupt, so harts are sampled on each timer tick instead.
+//!
+//! Harts pick up a new session on their next timer tick rather than being
+//! interrupted for it, so the first few samples after starting one may be
+//! missing.
+
+use crate::{
+    csr,
+    interrupts::InterruptDisabler,
+    mem::{paging::VirtualAddress, tlb},
+    syscall::poll::{WaitQueue, Waitable, Waiter},
+};
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use librust::syscalls::{
+    perf::{PerfEvent, PerfSample},
+    poll::WaitEvents,
+};
+use sbi::{probe_extension, ExtensionAvailability};
+use sync::{Lazy, SpinMutex};
+
+const PMU_EXTENSION_ID: usize = 0x504D55;
+const NUM_COUNTERS_FUNCTION_ID: usize = 0;
+const CONFIG_MATCHING_FUNCTION_ID: usize = 2;
+const START_FUNCTION_ID: usize = 3;
+const STOP_FUNCTION_ID: usize = 4;
+
+const CONFIG_CLEAR_VALUE: usize = 1 << 1;
+/// Don't count while in M-mode, which is only the SBI implementation
+const CONFIG_SET_MINH: usize = 1 << 7;
+const START_SET_INIT_VALUE: usize = 1 << 0;
+const STOP_RESET: usize = 1 << 0;
+
+/// Samples kept for each hart before the oldest start being dropped
+const SAMPLES_PER_HART: usize = 1024;
+/// Fewest events allowed between samples, so that a hart isn't spending all of
+/// its time in the overflow handler
+const MIN_PERIOD: usize = 1000;
+
+static AVAILABLE: AtomicBool = AtomicBool::new(false);
+static OVERFLOW_INTERRUPTS: AtomicBool = AtomicBool::new(false);
+static N_COUNTERS: AtomicUsize = AtomicUsize::new(0);
+
+static SESSION: SpinMutex<Option<Session>> = SpinMutex::new(None);
+/// Bumped each time the session is started or stopped so that harts know to
+/// reprogram their counter, zero until the first session is started
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+/// Set when a sample is recorded so that waiters are woken on the next tick
+static PENDING: AtomicBool = AtomicBool::new(false);
+static WAITERS: WaitQueue = WaitQueue::new();
+
+static HARTS: Lazy<Vec<HartPerf>> = Lazy::new(|| {
+    let n_cpus = crate::N_CPUS.load(Ordering::Acquire);
+    let mut v = Vec::with_capacity(n_cpus);
+
+    for _ in 0..n_cpus {
+        v.push(HartPerf {
+            ring: SpinMutex::new(Ring { samples: VecDeque::with_capacity(SAMPLES_PER_HART), dropped: 0 }),
+            counter: AtomicUsize::new(usize::MAX),
+            generation: AtomicUsize::new(0),
+        });
+    }
+
+    v
+});
+
+#[derive(Debug, Clone, Copy)]
+struct Session {
+    event: PerfEvent,
+    period: usize,
+}
+
+struct HartPerf {
+    ring: SpinMutex<Ring>,
+    /// Index of the counter programmed for the session, or `usize::MAX` if
+    /// there isn't one
+    counter: AtomicUsize,
+    generation: AtomicUsize,
+}
+
+struct Ring {
+    samples: VecDeque<PerfSample>,
+    dropped: usize,
+}
+
+impl HartPerf {
+    /// Stop the hart's counter and start one for `session`, must be called on
+    /// the hart itself
+    fn reprogram(&self, session: Option<Session>) {
+        let counter = self.counter.swap(usize::MAX, Ordering::Relaxed);
+        if counter != usize::MAX {
+            let _ = pmu_call(STOP_FUNCTION_ID, [counter, 1, STOP_RESET, 0, 0]);
+        }
+
+        let session = match session {
+            Some(session) if OVERFLOW_INTERRUPTS.load(Ordering::Relaxed) => session,
+            _ => return,
+        };
+
+        let all_counters = match N_COUNTERS.load(Ordering::Relaxed) {
+            n if n >= usize::BITS as usize => usize::MAX,
+            n => (1 << n) - 1,
+        };
+
+        let counter = match pmu_call(
+            CONFIG_MATCHING_FUNCTION_ID,
+            [0, all_counters, CONFIG_CLEAR_VALUE | CONFIG_SET_MINH, session.event as usize, 0],
+        ) {
+            Ok(counter) => counter,
+            Err(e) => {
+                log::warn!("No counter for {:?} on hart {} ({})", session.event, crate::HART_ID.get(), e);
+                return;
+            }
+        };
+
+        match pmu_call(START_FUNCTION_ID, [counter, 1, START_SET_INIT_VALUE, session.period.wrapping_neg(), 0]) {
+            Ok(_) => self.counter.store(counter, Ordering::Relaxed),
+            Err(e) => log::warn!("Failed to start counter {} on hart {} ({})", counter, crate::HART_ID.get(), e),
+        }
+    }
+
+    fn record(&self, sepc: usize) {
+        let sample = PerfSample {
+            pc: sepc,
+            tid: tlb::active_asid().map(usize::from).unwrap_or(0),
+            hart: crate::HART_ID.get(),
+            kernel: VirtualAddress::new(sepc).is_kernel_region(),
+        };
+
+        let mut ring = self.ring.lock();
+        if ring.samples.len() == SAMPLES_PER_HART {
+            ring.samples.pop_front();
+            ring.dropped += 1;
+        }
+
+        ring.samples.push_back(sample);
+        PENDING.store(true, Ordering::Relaxed);
+    }
+}
+
+fn pmu_call(function_id: usize, args: [usize; 5]) -> Result<usize, isize> {
+    let error: isize;
+    let value: usize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") args[0] => error,
+            inlateout("a1") args[1] => value,
+            in("a2") args[2],
+            in("a3") args[3],
+            in("a4") args[4],
+            in("a6") function_id,
+            in("a7") PMU_EXTENSION_ID,
+        );
+    }
+
+    match error {
+        0 => Ok(value),
+        e => Err(e),
+    }
+}
+
+/// Check for the SBI PMU extension, and whether every hart has the `Sscofpmf`
+/// extension for overflow interrupts
+pub fn init(fdt: &fdt::Fdt<'_>) {
+    if !matches!(probe_extension(PMU_EXTENSION_ID), ExtensionAvailability::Available(_)) {
+        log::info!("SBI PMU extension not available, profiling is disabled");
+        return;
+    }
+
+    let n_counters = pmu_call(NUM_COUNTERS_FUNCTION_ID, [0; 5]).unwrap_or(0);
+    if n_counters == 0 {
+        log::info!("No hardware performance counters, profiling is disabled");
+        return;
+    }
+
+    let sscofpmf = fdt.cpus().all(|cpu| {
+        let in_isa = cpu
+            .properties()
+            .find(|p| p.name == "riscv,isa")
+            .and_then(|p| p.as_str())
+            .map(|isa| isa.split('_').any(|ext| ext == "sscofpmf"))
+            .unwrap_or(false);
+        let in_extensions = cpu
+            .properties()
+            .find(|p| p.name == "riscv,isa-extensions")
+            .map(|p| p.value.split(|b| *b == 0).any(|ext| ext == b"sscofpmf"))
+            .unwrap_or(false);
+
+        in_isa || in_extensions
+    });
+
+    N_COUNTERS.store(n_counters, Ordering::Relaxed);
+    OVERFLOW_INTERRUPTS.store(sscofpmf, Ordering::Relaxed);
+    AVAILABLE.store(true, Ordering::Release);
+
+    match sscofpmf {
+        true => log::info!("Profiling with {} counters using overflow interrupts", n_counters),
+        false => log::info!("Profiling with {} counters, sampling on timer ticks", n_counters),
+    }
+}
+
+pub fn available() -> bool {
+    AVAILABLE.load(Ordering::Acquire)
+}
+
+/// Start sampling `event` every `period` events on all harts, replacing the
+/// current session
+pub fn start(event: PerfEvent, period: usize) {
+    // This also allocates the rings, which has to happen before harts can see
+    // the session from interrupt context
+    for hart in HARTS.iter() {
+        let _disabler = InterruptDisabler::new();
+        let mut ring = hart.ring.lock();
+        ring.samples.clear();
+        ring.dropped = 0;
+    }
+
+    *SESSION.lock() = Some(Session { event, period: period.max(MIN_PERIOD) });
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+/// Stop sampling, leaving the samples which haven't been read yet
+pub fn stop() {
+    *SESSION.lock() = None;
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+/// Take up to `max` samples, oldest first on each hart, along with how many
+/// have been dropped since the last call
+pub fn take(max: usize) -> (Vec<PerfSample>, usize) {
+    let mut samples = Vec::new();
+    let mut dropped = 0;
+
+    if GENERATION.load(Ordering::Acquire) == 0 {
+        return (samples, dropped);
+    }
+
+    for hart in HARTS.iter() {
+        let _disabler = InterruptDisabler::new();
+        let mut ring = hart.ring.lock();
+        let n = ring.samples.len().min(max - samples.len());
+
+        samples.extend(ring.samples.drain(..n));
+        dropped += core::mem::take(&mut ring.dropped);
+    }
+
+    (samples, dropped)
+}
+
+/// Pick up session changes and sample the hart if there's no overflow
+/// interrupt to do it, called on each timer tick
+pub fn tick(sepc: usize) {
+    let generation = GENERATION.load(Ordering::Acquire);
+    if generation == 0 {
+        return;
+    }
+
+    let hart = &HARTS[crate::HART_ID.get()];
+    let session = *SESSION.lock();
+
+    if hart.generation.swap(generation, Ordering::Relaxed) != generation {
+        hart.reprogram(session);
+    }
+
+    if session.is_some() && !OVERFLOW_INTERRUPTS.load(Ordering::Relaxed) {
+        hart.record(sepc);
+    }
+
+    if PENDING.swap(false, Ordering::Relaxed) {
+        WAITERS.wake_all();
+    }
+}
+
+/// Handle a counter overflow interrupt by recording a sample and restarting
+/// the counter for the next period
+pub fn overflow(sepc: usize) {
+    csr::sip::clear_lcofip();
+
+    if GENERATION.load(Ordering::Acquire) == 0 {
+        return;
+    }
+
+    let hart = &HARTS[crate::HART_ID.get()];
+    let counter = hart.counter.load(Ordering::Relaxed);
+    let session = *SESSION.lock();
+
+    let session = match session {
+        Some(session) if counter != usize::MAX => session,
+        _ => return,
+    };
+
+    hart.record(sepc);
+
+    // Restarting the counter also clears its overflow flag, without which it
+    // won't interrupt again
+    let _ = pmu_call(STOP_FUNCTION_ID, [counter, 1, 0, 0, 0]);
+    let _ = pmu_call(START_FUNCTION_ID, [counter, 1, START_SET_INIT_VALUE, session.period.wrapping_neg(), 0]);
+}
+
+/// The profiling session capability, which is readable whenever there are
+/// samples to read
+pub struct Perf;
+
+impl Waitable for Perf {
+    fn poll(&self, interest: WaitEvents) -> WaitEvents {
+        let unread = GENERATION.load(Ordering::Acquire) != 0
+            && HARTS.iter().any(|hart| {
+                let _disabler = InterruptDisabler::new();
+                !hart.ring.lock().samples.is_empty()
+            });
+
+        match unread && interest & WaitEvents::READABLE {
+            true => WaitEvents::READABLE,
+            false => WaitEvents::NONE,
+        }
+    }
+
+    fn register(&self, waiter: &Arc<Waiter>) {
+        WAITERS.register(waiter);
+    }
+}
@@ -0,0 +1,220 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Timekeeping. Everything in the kernel counts time with the `time` CSR, but
+//! its frequency comes from the device tree, which some firmware gets wrong or
+//! leaves out entirely. When there's a real time clock to compare against, the
+//! frequency is calibrated against it at boot and the two are periodically
+//! checked for drift afterwards.
+
+use crate::{
+    device::{Device, ProbeError},
+    drivers::generic::goldfish_rtc::GoldfishRtc,
+    mem::{paging::PhysicalAddress, phys2virt},
+    TIMER_FREQ,
+};
+use core::sync::atomic::Ordering;
+use fdt::Fdt;
+use sync::SpinMutex;
+
+/// Used when the device tree doesn't have a timebase frequency, until it can be
+/// calibrated
+pub const FALLBACK_TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+/// How far off the device tree's timebase frequency can be from the calibrated
+/// one before it's replaced
+const MAX_TIMEBASE_ERROR_PPM: i64 = 10_000;
+const CALIBRATION_PERIOD_NS: u64 = 50_000_000;
+const DRIFT_SAMPLE_PERIOD_NS: u64 = 10_000_000_000;
+
+static REFERENCE: SpinMutex<Option<&'static dyn ClockSource>> = SpinMutex::new(None);
+static DRIFT: SpinMutex<DriftState> = SpinMutex::new(DriftState::new());
+
+pub trait ClockSource: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// Current value of the counter, which only ever increases
+    fn ticks(&self) -> u64;
+    /// Rate the counter increases at in Hz
+    fn frequency(&self) -> u64;
+}
+
+/// The `time` CSR, at whatever frequency it's currently believed to run at
+pub struct TimeCsr;
+
+impl ClockSource for TimeCsr {
+    fn name(&self) -> &'static str {
+        "time CSR"
+    }
+
+    fn ticks(&self) -> u64 {
+        crate::csr::time::read()
+    }
+
+    fn frequency(&self) -> u64 {
+        TIMER_FREQ.load(Ordering::Relaxed)
+    }
+}
+
+/// How far the `time` CSR has wandered from the reference clock, in parts per
+/// million. Positive values mean the `time` CSR is running fast.
+#[derive(Debug, Clone, Copy)]
+pub struct DriftReport {
+    pub reference: &'static str,
+    pub samples: u64,
+    pub current_ppm: i64,
+    pub min_ppm: i64,
+    pub max_ppm: i64,
+}
+
+struct DriftState {
+    /// Readings of the `time` CSR and the reference clock taken at the same
+    /// time, which drift is measured from
+    base: Option<(u64, u64)>,
+    last_sample: u64,
+    samples: u64,
+    current_ppm: i64,
+    min_ppm: i64,
+    max_ppm: i64,
+}
+
+impl DriftState {
+    const fn new() -> Self {
+        Self { base: None, last_sample: 0, samples: 0, current_ppm: 0, min_ppm: i64::MAX, max_ppm: i64::MIN }
+    }
+}
+
+/// Probe for the goldfish RTC, which is used as the reference clock
+pub fn probe_goldfish_rtc(_: &Fdt<'_>, device: &Device) -> Result<(), ProbeError> {
+    let region = device.regions.first().ok_or(ProbeError::Failed("missing registers"))?;
+    let rtc = unsafe { &*phys2virt(PhysicalAddress::new(region.address)).as_ptr().cast::<GoldfishRtc>() };
+
+    log::debug!("Registering goldfish RTC @ {:#p}", rtc);
+    *REFERENCE.lock() = Some(rtc);
+
+    Ok(())
+}
+
+/// Measure the frequency of the `time` CSR against the reference clock, if
+/// there is one, replacing `fdt_frequency` if it's missing or too far off
+pub fn calibrate(fdt_frequency: Option<u64>) {
+    let reference = match *REFERENCE.lock() {
+        Some(reference) => reference,
+        None => {
+            if fdt_frequency.is_none() {
+                log::warn!(
+                    "No timebase frequency in the device tree and no clock to calibrate against, assuming {} Hz",
+                    FALLBACK_TIMEBASE_FREQUENCY
+                );
+            }
+
+            return;
+        }
+    };
+
+    let period = ns_to_ticks(CALIBRATION_PERIOD_NS, reference.frequency());
+    let (time_start, reference_start) = (TimeCsr.ticks(), reference.ticks());
+    let mut reference_end = reference_start;
+    while reference_end - reference_start < period {
+        reference_end = reference.ticks();
+    }
+    let time_end = TimeCsr.ticks();
+
+    let measured = ((time_end - time_start) as u128 * reference.frequency() as u128
+        / (reference_end - reference_start) as u128) as u64;
+
+    match fdt_frequency {
+        Some(frequency) if ppm(measured, frequency).abs() <= MAX_TIMEBASE_ERROR_PPM => {
+            log::info!("Timebase frequency {} Hz agrees with {} ({} Hz)", frequency, reference.name(), measured);
+        }
+        Some(frequency) => {
+            log::warn!(
+                "Timebase frequency {} Hz from the device tree disagrees with {}, using {} Hz",
+                frequency,
+                reference.name(),
+                measured
+            );
+            TIMER_FREQ.store(measured, Ordering::Relaxed);
+        }
+        None => {
+            log::info!("Calibrated timebase frequency against {}: {} Hz", reference.name(), measured);
+            TIMER_FREQ.store(measured, Ordering::Relaxed);
+        }
+    }
+
+    *DRIFT.lock() =
+        DriftState { base: Some((time_end, reference_end)), last_sample: reference_end, ..DriftState::new() };
+
+    // The reference clocks used so far count from the Unix epoch
+    let reference_ns = (reference_end as u128 * 1_000_000_000 / reference.frequency() as u128) as u64;
+    crate::timepage::publish(Some((time_end, reference_ns)));
+}
+
+/// Compare the `time` CSR against the reference clock, called on timer
+/// interrupts. Does nothing if another hart is already doing so or it's been
+/// compared recently.
+pub fn tick() {
+    let mut state = match DRIFT.try_lock() {
+        Some(state) => state,
+        None => return,
+    };
+
+    let (time_base, reference_base) = match state.base {
+        Some(base) => base,
+        None => return,
+    };
+
+    let reference = match REFERENCE.try_lock().and_then(|reference| *reference) {
+        Some(reference) => reference,
+        None => return,
+    };
+
+    let reference_now = reference.ticks();
+    if reference_now - state.last_sample < ns_to_ticks(DRIFT_SAMPLE_PERIOD_NS, reference.frequency()) {
+        return;
+    }
+
+    let elapsed = TimeCsr.ticks() - time_base;
+    let expected =
+        ((reference_now - reference_base) as u128 * TimeCsr.frequency() as u128 / reference.frequency() as u128) as u64;
+
+    let drift = ppm(elapsed, expected);
+    state.last_sample = reference_now;
+    state.samples += 1;
+    state.current_ppm = drift;
+    state.min_ppm = state.min_ppm.min(drift);
+    state.max_ppm = state.max_ppm.max(drift);
+}
+
+/// Drift of the `time` CSR measured so far, `None` if there's no reference
+/// clock or no samples have been taken yet
+pub fn drift() -> Option<DriftReport> {
+    let state = DRIFT.try_lock()?;
+    let reference = REFERENCE.try_lock()?.as_ref()?.name();
+
+    match state.samples {
+        0 => None,
+        samples => Some(DriftReport {
+            reference,
+            samples,
+            current_ppm: state.current_ppm,
+            min_ppm: state.min_ppm,
+            max_ppm: state.max_ppm,
+        }),
+    }
+}
+
+fn ns_to_ticks(ns: u64, frequency: u64) -> u64 {
+    (ns as u128 * frequency as u128 / 1_000_000_000) as u64
+}
+
+/// How far `measured` is from `expected` in parts per million
+fn ppm(measured: u64, expected: u64) -> i64 {
+    match expected {
+        0 => 0,
+        _ => ((measured as i128 - expected as i128) * 1_000_000 / expected as i128) as i64,
+    }
+}
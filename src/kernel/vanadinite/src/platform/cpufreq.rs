@@ -0,0 +1,163 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! CPU frequency scaling through the platform's clock controller. The clock
+//! is shared by all harts, so the frequency is picked from the load across the
+//! whole system. The frequency the firmware booted the kernel at is taken to
+//! be the highest safe one.
+
+use super::sensors;
+use crate::{
+    scheduler::{Scheduler, SCHEDULER},
+    N_CPUS, TIMER_FREQ,
+};
+use core::sync::atomic::Ordering;
+use librust::syscalls::system::CpuGovernor;
+use sync::SpinMutex;
+
+/// Load percentage at which the `ondemand` governor goes to the highest
+/// frequency
+const UP_THRESHOLD: u64 = 80;
+/// How often the `ondemand` governor reevaluates the frequency, in
+/// milliseconds
+const SAMPLE_PERIOD_MS: u64 = 100;
+
+static STATE: SpinMutex<State> =
+    SpinMutex::new(State { governor: CpuGovernor::Ondemand, min: 0, max: 0, current: 0, last_sample: 0 });
+
+struct State {
+    governor: CpuGovernor,
+    min: u64,
+    max: u64,
+    current: u64,
+    last_sample: u64,
+}
+
+impl State {
+    fn enabled(&self) -> bool {
+        self.max != 0
+    }
+
+    fn set_frequency(&mut self, hz: u64) {
+        let hz = hz.clamp(self.min, self.max);
+        if hz == self.current {
+            return;
+        }
+
+        if let Some(new) = sensors::cpu_clock().and_then(|clock| clock.set_cpu_frequency(hz)) {
+            log::trace!("CPU frequency changed from {} to {} Hz", self.current, new);
+            self.current = new;
+        }
+    }
+
+    fn apply_governor(&mut self) {
+        match self.governor {
+            CpuGovernor::Ondemand => self.sample(),
+            CpuGovernor::Performance => self.set_frequency(self.max),
+            CpuGovernor::Powersave => self.set_frequency(self.min),
+            CpuGovernor::Userspace(hz) => self.set_frequency(hz),
+        }
+    }
+
+    fn sample(&mut self) {
+        let harts = N_CPUS.load(Ordering::Acquire).max(1) as u64;
+        let load = (SCHEDULER.runnable_tasks() as u64 * 100 / harts).min(100);
+        let range = self.max - self.min;
+
+        let target = match load {
+            load if load >= UP_THRESHOLD => self.max,
+            load => self.min + range * load / UP_THRESHOLD,
+        };
+
+        // Ramp up immediately, but back off gradually so that short idle
+        // periods don't cause the clock to bounce around
+        let target = target.max(self.current.saturating_sub(range / 4));
+
+        // Not worth relocking the PLL over
+        if target.abs_diff(self.current) >= range / 16 {
+            self.set_frequency(target);
+        }
+    }
+}
+
+/// Parse the value of the `cpufreq=` bootarg, which is one of `ondemand`,
+/// `performance`, `powersave`, or a fixed frequency in MHz
+pub fn parse_governor(value: Option<&str>) {
+    let mhz = value.and_then(|value| value.parse::<u64>().ok());
+    let governor = match (value, mhz) {
+        (_, Some(mhz)) => CpuGovernor::Userspace(mhz * 1_000_000),
+        (Some("ondemand"), _) => CpuGovernor::Ondemand,
+        (Some("performance"), _) => CpuGovernor::Performance,
+        (Some("powersave"), _) => CpuGovernor::Powersave,
+        _ => {
+            log::warn!("Unknown CPU frequency governor: {:?}, defaulting to `ondemand`", value);
+            CpuGovernor::Ondemand
+        }
+    };
+
+    STATE.lock().governor = governor;
+}
+
+/// Enable frequency scaling if the registered CPU clock supports it, must be
+/// called after the clock drivers have been bound by
+/// [`crate::device::probe_all`]
+pub fn init() {
+    let clock = match sensors::cpu_clock() {
+        Some(clock) => clock,
+        None => return,
+    };
+
+    let (min, max) = match (clock.min_cpu_frequency(), clock.cpu_frequency()) {
+        (Some(min), Some(max)) if min < max => (min, max),
+        _ => return,
+    };
+
+    log::info!("CPU frequency scaling enabled: {}-{} MHz", min / 1_000_000, max / 1_000_000);
+
+    let mut state = STATE.lock();
+    state.min = min;
+    state.max = max;
+    state.current = max;
+    state.apply_governor();
+}
+
+/// Reevaluate the frequency for the `ondemand` governor, called on timer
+/// interrupts. Does nothing if another hart is already doing so or it's been
+/// evaluated recently.
+pub fn tick() {
+    let mut state = match STATE.try_lock() {
+        Some(state) => state,
+        None => return,
+    };
+
+    if !state.enabled() || state.governor != CpuGovernor::Ondemand {
+        return;
+    }
+
+    let now = crate::csr::time::read();
+    if now - state.last_sample < TIMER_FREQ.load(Ordering::Relaxed) * SAMPLE_PERIOD_MS / 1000 {
+        return;
+    }
+
+    state.last_sample = now;
+    state.sample();
+}
+
+/// Switch to a new governor, returning the resulting CPU frequency or `None`
+/// if frequency scaling isn't available
+pub fn set_governor(governor: CpuGovernor) -> Option<u64> {
+    let mut state = STATE.lock();
+    if !state.enabled() {
+        return None;
+    }
+
+    log::debug!("Switching CPU frequency governor to {:?}", governor);
+    state.governor = governor;
+    state.apply_governor();
+
+    Some(state.current)
+}
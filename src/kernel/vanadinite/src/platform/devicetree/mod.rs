@@ -0,0 +1,177 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+pub mod tree;
+
+use crate::{
+    mem::{
+        kernel_patching,
+        paging::{PageSize, PhysicalAddress, VirtualAddress},
+        phys::{PhysicalMemoryAllocator, PhysicalPage, PHYSICAL_MEMORY_ALLOCATOR},
+        phys2virt,
+        region::{SharedPhysicalRegion, UniquePhysicalRegion},
+    },
+    utils::{round_up_to_next, Units},
+};
+use alloc::vec::Vec;
+use core::ops::Range;
+use fdt::Fdt;
+use sync::SpinMutex;
+use tree::DeviceTree;
+
+static RESIDENT: SpinMutex<Option<ResidentDeviceTree>> = SpinMutex::new(None);
+
+/// The kernel's copy of the device tree, which stays allocated for the
+/// lifetime of the system so that it can be shared read-only with userspace
+#[derive(Debug, Clone)]
+pub struct ResidentDeviceTree {
+    pub region: SharedPhysicalRegion,
+    pub len: usize,
+}
+
+pub fn resident() -> Option<ResidentDeviceTree> {
+    RESIDENT.lock().clone()
+}
+
+/// The physical address range of the initrd the bootloader loaded, if any
+pub fn initrd(fdt: &Fdt) -> Option<Range<usize>> {
+    let chosen = fdt.find_node("/chosen")?;
+    let start = chosen.property("linux,initrd-start")?.as_usize()?;
+    let end = chosen.property("linux,initrd-end")?.as_usize()?;
+
+    Some(start..end)
+}
+
+/// Copy the device tree passed by the bootloader into kernel-owned memory,
+/// applying any overlays (`*.dtbo` files) found in the initrd along the way.
+/// Returns a pointer to the resident copy, which should be used in place of the
+/// original from here on out.
+pub fn init(fdt: *const u8) -> *const u8 {
+    let fdt_struct = match unsafe { Fdt::from_ptr(fdt) } {
+        Ok(fdt) => fdt,
+        // Let the caller deal with reporting the error
+        Err(_) => return fdt,
+    };
+
+    let original = unsafe { core::slice::from_raw_parts(fdt, fdt_struct.total_size()) };
+    let initrd_range = initrd(&fdt_struct);
+    let initrd = initrd_range.clone().map(|range| {
+        let start = phys2virt(PhysicalAddress::new(range.start)).as_ptr();
+        unsafe { core::slice::from_raw_parts(start, range.end - range.start) }
+    });
+
+    // Only needed until the overlays have been applied
+    let decompressed = initrd.filter(|initrd| lz4::is_frame(initrd)).and_then(decompress);
+    let initrd = match &decompressed {
+        Some((region, len)) => {
+            let start = phys2virt(region.physical_addresses().next().unwrap()).as_ptr();
+            Some(unsafe { core::slice::from_raw_parts(start, *len) })
+        }
+        None => initrd,
+    };
+
+    let blob = match initrd.and_then(|initrd| apply_overlays(original, initrd)) {
+        Some(tree) => tree.to_blob(),
+        None => original.to_vec(),
+    };
+
+    // Nothing else reads the initrd, so the pages reserved for it during early
+    // boot can go back to the allocator now
+    drop(decompressed);
+    if let Some(range) = initrd_range {
+        free_initrd(range);
+    }
+
+    let mut region =
+        UniquePhysicalRegion::alloc_contiguous(PageSize::Kilopage, round_up_to_next(blob.len(), 4.kib()) / 4.kib());
+    region.zero();
+    region.copy_data_into(&blob);
+
+    let ptr = phys2virt(region.physical_addresses().next().unwrap()).as_ptr();
+    *RESIDENT.lock() = Some(ResidentDeviceTree { region: region.into_shared_region(), len: blob.len() });
+
+    ptr
+}
+
+/// Mark the pages `early_paging` set used for the initrd as unused again,
+/// skipping those below the end of the kernel image just like it does
+fn free_initrd(initrd: Range<usize>) {
+    let kernel_end =
+        unsafe { kernel_patching::kernel_section_v2p(VirtualAddress::from_ptr(kernel_patching::kernel_end())) };
+    let mut pf_alloc = PHYSICAL_MEMORY_ALLOCATOR.lock();
+
+    for page in (initrd.start & !0xFFF..initrd.end).step_by(4096) {
+        if page >= kernel_end.as_usize() {
+            unsafe { pf_alloc.set_unused(PhysicalPage::from_ptr(page as *mut _)) };
+        }
+    }
+}
+
+/// Decompress an LZ4 compressed initrd into freshly allocated memory,
+/// returning it along with the decompressed length
+fn decompress(initrd: &[u8]) -> Option<(UniquePhysicalRegion, usize)> {
+    let frame = lz4::Frame::parse(initrd).and_then(|frame| Ok((frame, frame.max_decompressed_len()?)));
+    let (frame, len) = match frame {
+        Ok(frame) => frame,
+        Err(e) => {
+            log::error!("Failed to read compressed initrd, not applying overlays: {:?}", e);
+            return None;
+        }
+    };
+
+    let n_pages = round_up_to_next(len.max(1), 4.kib()) / 4.kib();
+    let region = match UniquePhysicalRegion::try_alloc_contiguous(PageSize::Kilopage, n_pages) {
+        Some(region) => region,
+        None => {
+            log::error!("Not enough memory to decompress the {} byte initrd, not applying overlays", len);
+            return None;
+        }
+    };
+
+    let start = phys2virt(region.physical_addresses().next().unwrap()).as_mut_ptr();
+    match frame.decompress_into(unsafe { core::slice::from_raw_parts_mut(start, len) }) {
+        Ok(len) => Some((region, len)),
+        Err(e) => {
+            log::error!("Failed to decompress initrd, not applying overlays: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Returns the modified tree if there were any overlays to apply
+fn apply_overlays(original: &[u8], initrd: &[u8]) -> Option<DeviceTree> {
+    let archive = tar::Archive::new(initrd).ok()?;
+    let overlays = archive.files().filter(|file| file.metadata.file_name.ends_with(".dtbo")).collect::<Vec<_>>();
+
+    if overlays.is_empty() {
+        return None;
+    }
+
+    let mut tree = match DeviceTree::parse(original) {
+        Ok(tree) => tree,
+        Err(e) => {
+            log::error!("Failed to parse device tree, not applying overlays: {:?}", e);
+            return None;
+        }
+    };
+
+    for overlay in overlays {
+        let name = overlay.metadata.file_name;
+        // Overlays are applied to a copy so a bad one can't leave the tree
+        // half-modified
+        let mut patched = tree.clone();
+        match DeviceTree::parse(overlay.contents).and_then(|overlay| patched.apply_overlay(overlay)) {
+            Ok(()) => {
+                log::info!("Applied device tree overlay {}", name);
+                tree = patched;
+            }
+            Err(e) => log::error!("Failed to apply device tree overlay {}: {:?}", name, e),
+        }
+    }
+
+    Some(tree)
+}
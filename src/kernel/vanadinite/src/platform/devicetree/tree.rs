@@ -0,0 +1,508 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An owned, modifiable copy of a flattened device tree, which can have
+//! overlays applied to it and be flattened again
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+const HEADER_SIZE: usize = 40;
+const VERSION: u32 = 17;
+const LAST_COMPATIBLE_VERSION: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceTreeError {
+    BadMagic,
+    Truncated,
+    UnexpectedToken(u32),
+    InvalidString,
+    /// A fragment's target doesn't exist in the base tree
+    MissingTarget,
+    /// A label referenced by the overlay isn't in the base tree's `__symbols__`
+    MissingSymbol,
+    /// A `__fixups__` or `__local_fixups__` entry points somewhere that
+    /// doesn't exist
+    InvalidFixup,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Property {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub name: String,
+    pub properties: Vec<Property>,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    fn new(name: &str) -> Self {
+        Self { name: name.to_string(), properties: Vec::new(), children: Vec::new() }
+    }
+
+    pub fn property(&self, name: &str) -> Option<&Property> {
+        self.properties.iter().find(|p| p.name == name)
+    }
+
+    fn property_mut(&mut self, name: &str) -> Option<&mut Property> {
+        self.properties.iter_mut().find(|p| p.name == name)
+    }
+
+    pub fn set_property(&mut self, name: &str, value: Vec<u8>) {
+        match self.property_mut(name) {
+            Some(property) => property.value = value,
+            None => self.properties.push(Property { name: name.to_string(), value }),
+        }
+    }
+
+    /// Nodes can be referred to either by their full name, or without the unit
+    /// address if it's unambiguous
+    fn matches(&self, name: &str) -> bool {
+        self.name == name || (!name.contains('@') && self.name.split('@').next() == Some(name))
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|n| n.matches(name))
+    }
+
+    fn child_mut(&mut self, name: &str) -> Option<&mut Node> {
+        self.children.iter_mut().find(|n| n.matches(name))
+    }
+
+    fn phandle(&self) -> Option<u32> {
+        self.property("phandle").or_else(|| self.property("linux,phandle")).and_then(|p| be_u32(&p.value, 0))
+    }
+
+    fn max_phandle(&self) -> u32 {
+        self.children.iter().map(Node::max_phandle).fold(self.phandle().unwrap_or(0), u32::max)
+    }
+
+    /// Index path to the node with the given phandle
+    fn find_phandle(&self, phandle: u32) -> Option<Vec<usize>> {
+        if self.phandle() == Some(phandle) {
+            return Some(Vec::new());
+        }
+
+        self.children.iter().enumerate().find_map(|(i, child)| {
+            let mut path = child.find_phandle(phandle)?;
+            path.insert(0, i);
+            Some(path)
+        })
+    }
+
+    fn offset_phandles(&mut self, delta: u32) {
+        for property in self.properties.iter_mut().filter(|p| p.name == "phandle" || p.name == "linux,phandle") {
+            if let Some(phandle) = be_u32(&property.value, 0) {
+                property.value[..4].copy_from_slice(&(phandle + delta).to_be_bytes());
+            }
+        }
+
+        self.children.iter_mut().for_each(|child| child.offset_phandles(delta));
+    }
+
+    /// Merge the properties and children of `other` into this node,
+    /// overwriting any properties that already exist
+    fn merge(&mut self, other: Node) {
+        for property in other.properties {
+            self.set_property(&property.name, property.value);
+        }
+
+        for child in other.children {
+            match self.children.iter_mut().find(|n| n.name == child.name) {
+                Some(existing) => existing.merge(child),
+                None => self.children.push(child),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceTree {
+    pub boot_cpuid: u32,
+    /// `(address, size)` pairs from the memory reservation block
+    pub reservations: Vec<(u64, u64)>,
+    pub root: Node,
+}
+
+impl DeviceTree {
+    pub fn parse(blob: &[u8]) -> Result<Self, DeviceTreeError> {
+        let header = |n| be_u32(blob, n * 4).ok_or(DeviceTreeError::Truncated);
+
+        if header(0)? != FDT_MAGIC {
+            return Err(DeviceTreeError::BadMagic);
+        }
+
+        let total_size = header(1)? as usize;
+        let blob = blob.get(..total_size).ok_or(DeviceTreeError::Truncated)?;
+        let structs = blob.get(header(2)? as usize..).ok_or(DeviceTreeError::Truncated)?;
+        let strings = blob.get(header(3)? as usize..).ok_or(DeviceTreeError::Truncated)?;
+        let mut reservations_offset = header(4)? as usize;
+        let boot_cpuid = header(7)?;
+
+        let mut reservations = Vec::new();
+        loop {
+            let address = be_u64(blob, reservations_offset).ok_or(DeviceTreeError::Truncated)?;
+            let size = be_u64(blob, reservations_offset + 8).ok_or(DeviceTreeError::Truncated)?;
+            reservations_offset += 16;
+
+            match (address, size) {
+                (0, 0) => break,
+                reservation => reservations.push(reservation),
+            }
+        }
+
+        let mut parser = Parser { structs, strings, offset: 0 };
+        let root = match parser.next_token()? {
+            FDT_BEGIN_NODE => parser.node()?,
+            token => return Err(DeviceTreeError::UnexpectedToken(token)),
+        };
+
+        Ok(Self { boot_cpuid, reservations, root })
+    }
+
+    /// Flatten the tree back into a device tree blob
+    pub fn to_blob(&self) -> Vec<u8> {
+        let mut structs = Vec::new();
+        let mut strings = Vec::new();
+        flatten(&self.root, &mut structs, &mut strings);
+        structs.extend_from_slice(&FDT_END.to_be_bytes());
+
+        let reservations_offset = HEADER_SIZE;
+        let structs_offset = reservations_offset + (self.reservations.len() + 1) * 16;
+        let strings_offset = structs_offset + structs.len();
+        let total_size = strings_offset + strings.len();
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in [
+            FDT_MAGIC,
+            total_size as u32,
+            structs_offset as u32,
+            strings_offset as u32,
+            reservations_offset as u32,
+            VERSION,
+            LAST_COMPATIBLE_VERSION,
+            self.boot_cpuid,
+            strings.len() as u32,
+            structs.len() as u32,
+        ] {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+
+        for &(address, size) in self.reservations.iter().chain([(0, 0)].iter()) {
+            blob.extend_from_slice(&address.to_be_bytes());
+            blob.extend_from_slice(&size.to_be_bytes());
+        }
+
+        blob.extend_from_slice(&structs);
+        blob.extend_from_slice(&strings);
+
+        blob
+    }
+
+    pub fn find_node(&self, path: &str) -> Option<&Node> {
+        path.split('/').filter(|s| !s.is_empty()).try_fold(&self.root, |node, name| node.child(name))
+    }
+
+    fn find_node_mut(&mut self, path: &str) -> Option<&mut Node> {
+        path.split('/').filter(|s| !s.is_empty()).try_fold(&mut self.root, |node, name| node.child_mut(name))
+    }
+
+    /// Apply an overlay compiled with `dtc -@`, merging each of its fragments
+    /// into the node it targets. Phandles in the overlay are renumbered so they
+    /// don't collide with any in this tree, and references to labels in this
+    /// tree are resolved through its `__symbols__` node.
+    pub fn apply_overlay(&mut self, mut overlay: DeviceTree) -> Result<(), DeviceTreeError> {
+        let delta = self.root.max_phandle();
+        overlay.root.offset_phandles(delta);
+
+        if let Some(local_fixups) = overlay.root.child("__local_fixups__").cloned() {
+            apply_local_fixups(&mut overlay.root, &local_fixups, delta)?;
+        }
+
+        if let Some(fixups) = overlay.root.child("__fixups__").cloned() {
+            for property in &fixups.properties {
+                let phandle = self.phandle_for_symbol(&property.name)?;
+
+                for fixup in property.value.split(|b| *b == 0).filter(|s| !s.is_empty()) {
+                    let fixup = core::str::from_utf8(fixup).map_err(|_| DeviceTreeError::InvalidFixup)?;
+                    let mut parts = fixup.rsplitn(3, ':');
+                    let (offset, property, path) = match (parts.next(), parts.next(), parts.next()) {
+                        (Some(offset), Some(property), Some(path)) => (offset, property, path),
+                        _ => return Err(DeviceTreeError::InvalidFixup),
+                    };
+                    let offset: usize = offset.parse().map_err(|_| DeviceTreeError::InvalidFixup)?;
+
+                    let value = overlay
+                        .find_node_mut(path)
+                        .and_then(|node| node.property_mut(property))
+                        .and_then(|property| property.value.get_mut(offset..offset + 4))
+                        .ok_or(DeviceTreeError::InvalidFixup)?;
+                    value.copy_from_slice(&phandle.to_be_bytes());
+                }
+            }
+        }
+
+        let overlay_symbols = overlay.root.child("__symbols__").cloned();
+        let mut new_symbols = Vec::new();
+        for fragment in core::mem::take(&mut overlay.root.children) {
+            let contents = match fragment.child("__overlay__") {
+                Some(contents) => contents.clone(),
+                None => continue,
+            };
+
+            let target_path = match (fragment.property("target"), fragment.property("target-path")) {
+                (Some(target), _) => {
+                    let phandle = be_u32(&target.value, 0).ok_or(DeviceTreeError::MissingTarget)?;
+                    let indices = self.root.find_phandle(phandle).ok_or(DeviceTreeError::MissingTarget)?;
+                    self.path_of(&indices)
+                }
+                (None, Some(path)) => cstr(&path.value)?.to_string(),
+                (None, None) => return Err(DeviceTreeError::MissingTarget),
+            };
+
+            // Labels defined inside of the fragment are rewritten to where
+            // they'll end up in the base tree
+            let prefix = alloc::format!("/{}/__overlay__", fragment.name);
+            if let Some(symbols) = &overlay_symbols {
+                for symbol in &symbols.properties {
+                    if let Some(rest) = cstr(&symbol.value)?.strip_prefix(&prefix) {
+                        let mut path = alloc::format!("{}{}", target_path.trim_end_matches('/'), rest).into_bytes();
+                        path.push(0);
+                        new_symbols.push((symbol.name.clone(), path));
+                    }
+                }
+            }
+
+            let target = self.find_node_mut(&target_path).ok_or(DeviceTreeError::MissingTarget)?;
+            target.merge(contents);
+        }
+
+        if let Some(symbols) = self.root.child_mut("__symbols__") {
+            for (name, path) in new_symbols {
+                symbols.set_property(&name, path);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn phandle_for_symbol(&mut self, symbol: &str) -> Result<u32, DeviceTreeError> {
+        let path = self
+            .find_node("/__symbols__")
+            .and_then(|symbols| symbols.property(symbol))
+            .ok_or(DeviceTreeError::MissingSymbol)?;
+        let path = cstr(&path.value)?.to_string();
+
+        let next_phandle = self.root.max_phandle() + 1;
+        let node = self.find_node_mut(&path).ok_or(DeviceTreeError::MissingSymbol)?;
+
+        match node.phandle() {
+            Some(phandle) => Ok(phandle),
+            None => {
+                node.set_property("phandle", next_phandle.to_be_bytes().to_vec());
+                Ok(next_phandle)
+            }
+        }
+    }
+
+    fn path_of(&self, indices: &[usize]) -> String {
+        let mut path = String::new();
+        let mut node = &self.root;
+        for &i in indices {
+            node = &node.children[i];
+            path.push('/');
+            path.push_str(&node.name);
+        }
+
+        if path.is_empty() {
+            path.push('/');
+        }
+
+        path
+    }
+}
+
+/// `__local_fixups__` mirrors the structure of the overlay, with each property
+/// listing the offsets of phandle references in the property of the same name
+fn apply_local_fixups(node: &mut Node, fixups: &Node, delta: u32) -> Result<(), DeviceTreeError> {
+    for fixup in &fixups.properties {
+        let property = node.property_mut(&fixup.name).ok_or(DeviceTreeError::InvalidFixup)?;
+
+        for offset in fixup.value.chunks_exact(4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize) {
+            let value = property.value.get_mut(offset..offset + 4).ok_or(DeviceTreeError::InvalidFixup)?;
+            let phandle = u32::from_be_bytes([value[0], value[1], value[2], value[3]]);
+            value.copy_from_slice(&(phandle + delta).to_be_bytes());
+        }
+    }
+
+    for child_fixups in &fixups.children {
+        let child = node.child_mut(&child_fixups.name).ok_or(DeviceTreeError::InvalidFixup)?;
+        apply_local_fixups(child, child_fixups, delta)?;
+    }
+
+    Ok(())
+}
+
+struct Parser<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+    offset: usize,
+}
+
+impl Parser<'_> {
+    fn next_token(&mut self) -> Result<u32, DeviceTreeError> {
+        loop {
+            let token = be_u32(self.structs, self.offset).ok_or(DeviceTreeError::Truncated)?;
+            self.offset += 4;
+
+            if token != FDT_NOP {
+                break Ok(token);
+            }
+        }
+    }
+
+    /// Parses the node whose `FDT_BEGIN_NODE` token was just read
+    fn node(&mut self) -> Result<Node, DeviceTreeError> {
+        let name = cstr(self.structs.get(self.offset..).ok_or(DeviceTreeError::Truncated)?)?;
+        let mut node = Node::new(name);
+        self.offset = align4(self.offset + name.len() + 1);
+
+        loop {
+            match self.next_token()? {
+                FDT_PROP => {
+                    let len = be_u32(self.structs, self.offset).ok_or(DeviceTreeError::Truncated)? as usize;
+                    let name_offset = be_u32(self.structs, self.offset + 4).ok_or(DeviceTreeError::Truncated)?;
+                    let value =
+                        self.structs.get(self.offset + 8..self.offset + 8 + len).ok_or(DeviceTreeError::Truncated)?;
+                    let name = cstr(self.strings.get(name_offset as usize..).ok_or(DeviceTreeError::Truncated)?)?;
+
+                    node.properties.push(Property { name: name.to_string(), value: value.to_vec() });
+                    self.offset = align4(self.offset + 8 + len);
+                }
+                FDT_BEGIN_NODE => node.children.push(self.node()?),
+                FDT_END_NODE => break Ok(node),
+                token => break Err(DeviceTreeError::UnexpectedToken(token)),
+            }
+        }
+    }
+}
+
+fn flatten(node: &Node, structs: &mut Vec<u8>, strings: &mut Vec<u8>) {
+    structs.extend_from_slice(&FDT_BEGIN_NODE.to_be_bytes());
+    structs.extend_from_slice(node.name.as_bytes());
+    structs.push(0);
+    structs.resize(align4(structs.len()), 0);
+
+    for property in &node.properties {
+        let name_offset = string_offset(strings, &property.name);
+
+        structs.extend_from_slice(&FDT_PROP.to_be_bytes());
+        structs.extend_from_slice(&(property.value.len() as u32).to_be_bytes());
+        structs.extend_from_slice(&(name_offset as u32).to_be_bytes());
+        structs.extend_from_slice(&property.value);
+        structs.resize(align4(structs.len()), 0);
+    }
+
+    for child in &node.children {
+        flatten(child, structs, strings);
+    }
+
+    structs.extend_from_slice(&FDT_END_NODE.to_be_bytes());
+}
+
+/// Offset of `name` in the strings block, adding it if it isn't there yet
+fn string_offset(strings: &mut Vec<u8>, name: &str) -> usize {
+    let mut offset = 0;
+    for s in strings.split(|b| *b == 0) {
+        if s == name.as_bytes() && offset < strings.len() {
+            return offset;
+        }
+
+        offset += s.len() + 1;
+    }
+
+    let offset = strings.len();
+    strings.extend_from_slice(name.as_bytes());
+    strings.push(0);
+
+    offset
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+fn be_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn be_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+fn cstr(bytes: &[u8]) -> Result<&str, DeviceTreeError> {
+    let end = bytes.iter().position(|b| *b == 0).ok_or(DeviceTreeError::InvalidString)?;
+    core::str::from_utf8(&bytes[..end]).map_err(|_| DeviceTreeError::InvalidString)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::FDT;
+    use core::sync::atomic::Ordering;
+    use vanadinite_macros::test;
+
+    fn boot_tree() -> DeviceTree {
+        let ptr = FDT.load(Ordering::Acquire);
+        let fdt = unsafe { fdt::Fdt::from_ptr(ptr) }.unwrap();
+        DeviceTree::parse(unsafe { core::slice::from_raw_parts(ptr, fdt.total_size()) }).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let tree = boot_tree();
+        let blob = tree.to_blob();
+
+        assert_eq!(DeviceTree::parse(&blob).unwrap(), tree);
+        assert!(fdt::Fdt::new(&blob).is_ok());
+    }
+
+    #[test]
+    fn overlay_with_target_path() {
+        let mut tree = boot_tree();
+
+        let mut contents = Node::new("__overlay__");
+        contents.set_property("vanadinite,quirk", alloc::vec![0, 0, 0, 1]);
+        contents.children.push(Node::new("quirky-device@1000"));
+
+        let mut fragment = Node::new("fragment@0");
+        fragment.set_property("target-path", b"/chosen\0".to_vec());
+        fragment.children.push(contents);
+
+        let mut root = Node::new("");
+        root.children.push(fragment);
+
+        tree.apply_overlay(DeviceTree { boot_cpuid: 0, reservations: Vec::new(), root }).unwrap();
+
+        let chosen = tree.find_node("/chosen").unwrap();
+        assert_eq!(chosen.property("vanadinite,quirk").unwrap().value, [0, 0, 0, 1]);
+        assert!(chosen.child("quirky-device").is_some());
+    }
+}
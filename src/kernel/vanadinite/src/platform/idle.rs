@@ -0,0 +1,231 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Idle harts are parked with the SBI HSM `hart_suspend` call when it's
+//! available, which lets the platform put them into a lower power state than
+//! a plain `wfi` can.
+//!
+//! Idle harts don't take the regular scheduler tick. Their timer is set for
+//! the earliest thing they might need to wake up for, or not at all if there's
+//! nothing, and anything else that needs them sends an IPI. Housekeeping done
+//! on the tick, like CPU frequency and clock drift sampling, is left to the
+//! harts which are busy.
+
+use crate::{
+    csr,
+    interrupts::rate_limit,
+    mem::{kernel_patching::kernel_section_v2p, paging::VirtualAddress},
+    scheduler::{Scheduler, SCHEDULER},
+    syscall::{poll, ring},
+    trap,
+    utils::ticks_per_us,
+    TIMER_FREQ,
+};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const HSM_EXTENSION_ID: usize = 0x48534D;
+const HART_SUSPEND_FUNCTION_ID: usize = 3;
+
+const DEFAULT_RETENTIVE_SUSPEND: u32 = 0x0000_0000;
+const DEFAULT_NON_RETENTIVE_SUSPEND: u32 = 0x8000_0000;
+
+/// How often idle harts wake up while there are tasks on other harts that they
+/// could take
+const BALANCE_TICK_US: u64 = 10_000;
+
+static IDLE_MODE: AtomicU8 = AtomicU8::new(IdleMode::Retentive as u8);
+
+/// How an idle hart waits for its next interrupt, selected with the `idle=`
+/// bootarg
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum IdleMode {
+    /// Plain `wfi`, the hart stays fully powered
+    Wfi = 0,
+    /// Retentive suspend, the hart keeps all of its state and continues on as
+    /// if it had executed a `wfi`
+    Retentive = 1,
+    /// Non-retentive suspend, the hart may be powered off entirely and is
+    /// restarted at [`resume_trampoline`] with only the kernel thread control
+    /// block to go off of
+    NonRetentive = 2,
+}
+
+impl IdleMode {
+    fn from_u8(n: u8) -> Self {
+        match n {
+            1 => Self::Retentive,
+            2 => Self::NonRetentive,
+            _ => Self::Wfi,
+        }
+    }
+}
+
+/// Parse the value of the `idle=` bootarg, which is one of `wfi`,
+/// `retentive`, or `non-retentive`
+pub fn parse_idle_mode(value: Option<&str>) {
+    let mode = match value {
+        Some("wfi") => IdleMode::Wfi,
+        Some("retentive") => IdleMode::Retentive,
+        Some("non-retentive") => IdleMode::NonRetentive,
+        _ => {
+            log::warn!("Unknown idle mode: {:?}, defaulting to `retentive`", value);
+            IdleMode::Retentive
+        }
+    };
+
+    IDLE_MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+pub fn idle_mode() -> IdleMode {
+    IdleMode::from_u8(IDLE_MODE.load(Ordering::Relaxed))
+}
+
+/// Wait for interrupts forever using the configured [`IdleMode`], with the
+/// timer set for [`next_wakeup`] each time. The interrupts in `sie` must
+/// already be enabled.
+pub fn idle() -> ! {
+    loop {
+        // A pending interrupt still wakes the hart with interrupts disabled, so
+        // anything that comes in after the deadline is picked wakes the hart
+        // right back up to pick it again instead of being slept through
+        csr::sstatus::disable_interrupts();
+        sbi::timer::set_timer(next_wakeup()).unwrap();
+
+        let result = match idle_mode() {
+            IdleMode::Wfi => {
+                unsafe { core::arch::asm!("wfi") };
+                Ok(())
+            }
+            IdleMode::Retentive => hart_suspend(DEFAULT_RETENTIVE_SUSPEND, 0, 0),
+            IdleMode::NonRetentive => {
+                // The thread control block holds everything needed to get back
+                // into the kernel, so hand it to the resumed hart. This only
+                // returns if the suspend failed.
+                let resume_addr =
+                    unsafe { kernel_section_v2p(VirtualAddress::from_ptr(resume_trampoline as *const u8)) };
+                hart_suspend(DEFAULT_NON_RETENTIVE_SUSPEND, resume_addr.as_usize(), csr::sscratch::read())
+            }
+        };
+
+        if let Err(e) = result {
+            log::warn!("SBI hart suspend failed ({}), falling back to `wfi` for idle harts", e);
+            IDLE_MODE.store(IdleMode::Wfi as u8, Ordering::Relaxed);
+        }
+
+        csr::sstatus::enable_interrupts();
+    }
+}
+
+/// The time, in ticks of the `time` CSR, that an idle hart needs to wake up
+/// at, or `u64::MAX` if it can sleep until it's sent an interrupt
+fn next_wakeup() -> u64 {
+    let balance = match SCHEDULER.tasks_to_pull() {
+        false => None,
+        true => Some(csr::time::read() + ticks_per_us(BALANCE_TICK_US, TIMER_FREQ.load(Ordering::Relaxed))),
+    };
+
+    [balance, rate_limit::next_deadline(), poll::next_deadline(), ring::next_deadline()]
+        .into_iter()
+        .flatten()
+        .min()
+        .unwrap_or(u64::MAX)
+}
+
+fn hart_suspend(suspend_type: u32, resume_addr: usize, opaque: usize) -> Result<(), isize> {
+    let error: isize;
+
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") suspend_type as usize => error,
+            inlateout("a1") resume_addr => _,
+            in("a2") opaque,
+            in("a6") HART_SUSPEND_FUNCTION_ID,
+            in("a7") HSM_EXTENSION_ID,
+        );
+    }
+
+    match error {
+        0 => Ok(()),
+        e => Err(e),
+    }
+}
+
+/// Entry point for harts resuming from a non-retentive suspend. Much like
+/// `other_hart_boot`, we start with paging disabled and only two registers in
+/// a defined state:
+///   a0: hart id
+///   a1: the virtual address of the hart's `ThreadControlBlock`
+#[naked]
+#[no_mangle]
+unsafe extern "C" fn resume_trampoline() -> ! {
+    #[rustfmt::skip]
+    core::arch::asm!(
+        "
+            # Translate phys `__global_pointer$` addr to virtual
+            lla t0, __global_pointer$
+
+            lla t1, PAGE_OFFSET_VALUE
+            ld t1, (t1)
+
+            lla t2, PAGE_OFFSET
+
+            sub t0, t0, t2
+            add t0, t0, t1
+            mv gp, t0
+
+            # Translate phys `resume_entry` addr to virtual
+            lla t0, {}
+            sub t0, t0, t2
+            add t0, t0, t1
+            csrw stvec, t0
+
+            # Load bootstrap `satp` value
+            ld t0, {}
+            csrw satp, t0
+            sfence.vma
+            nop             # We fault here and fall into `resume_entry`
+        ",
+        sym resume_entry,
+        sym crate::boot::early_paging::BOOTSTRAP_SATP,
+        options(noreturn),
+    );
+}
+
+/// Restore the kernel stack, thread pointer, and `sscratch` from the thread
+/// control block now that paging is enabled
+#[naked]
+#[no_mangle]
+#[repr(align(4))]
+unsafe extern "C" fn resume_entry() -> ! {
+    #[rustfmt::skip]
+    core::arch::asm!(
+        "
+            ld sp, 0(a1)
+            ld tp, 8(a1)
+            csrw sscratch, a1
+            j {}
+        ",
+        sym kresume,
+        options(noreturn),
+    );
+}
+
+extern "C" fn kresume(hart_id: usize) -> ! {
+    csr::stvec::set(trap::stvec_trap_shim);
+    debug_assert_eq!(hart_id, crate::HART_ID.get());
+
+    csr::sstatus::set_fs(csr::sstatus::FloatingPointStatus::Initial);
+    csr::sie::enable();
+
+    log::trace!("Hart {} resumed from non-retentive suspend", hart_id);
+
+    // Whatever woke the hart up is still pending, and will be taken as soon as
+    // interrupts are enabled again by returning to userspace or idling
+    SCHEDULER.schedule()
+}
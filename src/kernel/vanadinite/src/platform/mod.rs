@@ -5,26 +5,28 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::{utils, BOOT_TIME, N_CPUS, TIMER_FREQ};
+use core::sync::atomic::Ordering;
+use librust::syscalls::system::{
+    BuildProfile, InfoString, ResetKind, ResetReason as LastResetReason, SystemInfo, MAX_TEMPERATURE_SENSORS,
+};
 use sync::AtomicConstPtr;
 
 pub static FDT: AtomicConstPtr<u8> = AtomicConstPtr::new(core::ptr::null());
 
+pub mod clock;
+pub mod cpufreq;
+pub mod devicetree;
+pub mod idle;
+pub mod pstore;
+pub mod sensors;
+pub mod storage;
+
 #[cfg(feature = "platform.virt")]
 pub mod virt;
 
 // FIXME: this is kind of hacky because contexts aren't currently standardized,
 // should look for a better way to do it in the future
-pub fn current_plic_context() -> usize {
-    #[cfg(not(feature = "platform.sifive_u"))]
-    return 1 + 2 * crate::HART_ID.get();
-
-    // first context is M-mode E51 monitor core which doesn't support S-mode so
-    // we'll always be on hart >=1 which ends up working out to remove the +1
-    // from the other fn
-    #[cfg(feature = "platform.sifive_u")]
-    return 2 * crate::HART_ID.get();
-}
-
 pub fn plic_context_for(hart_id: usize) -> usize {
     #[cfg(not(feature = "platform.sifive_u"))]
     return 1 + 2 * hart_id;
@@ -36,16 +38,135 @@ pub fn plic_context_for(hart_id: usize) -> usize {
     return 2 * hart_id;
 }
 
+pub fn system_info() -> SystemInfo {
+    let fdt = unsafe { fdt::Fdt::from_ptr(FDT.load(Ordering::Acquire)) };
+    let model = fdt.ok().and_then(|fdt| fdt.root().property("model")?.as_str());
+    let timer_freq = TIMER_FREQ.load(Ordering::Relaxed);
+    let boot_time = BOOT_TIME.load(Ordering::Relaxed);
+    let mut temperatures_mc = [0; MAX_TEMPERATURE_SENSORS];
+    let n_temperatures = sensors::temperatures(&mut temperatures_mc);
+
+    SystemInfo {
+        version: InfoString::new(env!("CARGO_PKG_VERSION")),
+        commit: InfoString::new(option_env!("VANADINITE_COMMIT").unwrap_or("unknown")),
+        profile: if cfg!(debug_assertions) { BuildProfile::Debug } else { BuildProfile::Release },
+        boot_time_us: utils::micros(boot_time, timer_freq),
+        uptime_us: utils::micros(crate::csr::time::read() - boot_time, timer_freq),
+        n_harts: N_CPUS.load(Ordering::Acquire),
+        platform: InfoString::new(model.unwrap_or("unknown")),
+        timer_freq_hz: timer_freq,
+        cpu_freq_hz: sensors::cpu_frequency().unwrap_or(0),
+        n_temperatures,
+        temperatures_mc,
+        last_reset: pstore::last_reset(),
+    }
+}
+
+/// Why the kernel is exiting. On the `virt` platform this becomes the exit
+/// code of QEMU, so whatever is running the kernel can tell what went wrong
+/// without having to pick through the console output. The process exit code
+/// only has 8 bits, so the codes are split into these ranges:
+///
+/// | Code       | Meaning                                                  |
+/// |------------|----------------------------------------------------------|
+/// | `0`        | Success                                                  |
+/// | `1`        | Any other error                                          |
+/// | `2`        | Kernel panic                                             |
+/// | `3`        | Out of memory                                            |
+/// | `4`        | Watchdog expired                                         |
+/// | `5..=15`   | Reserved                                                 |
+/// | `16..=255` | Test failure, the code minus 16 is the index of the test |
+///
+/// Test indices past the end of the range are all reported as `255`.
+#[derive(Clone, Copy)]
 pub enum ExitStatus<'a> {
     Ok,
     Error(&'a dyn core::fmt::Display),
+    Panic,
+    OutOfMemory,
+    // Nothing watches for hangs yet, but the code is set aside so the test
+    // runner doesn't need to change when something does
+    #[allow(dead_code)]
+    Watchdog,
+    /// The test at the given index in the order they were run failed
+    TestFailed(usize),
+}
+
+impl ExitStatus<'_> {
+    pub const FIRST_TEST_FAILURE_CODE: u8 = 16;
+
+    pub fn code(&self) -> u8 {
+        match self {
+            ExitStatus::Ok => 0,
+            ExitStatus::Error(_) => 1,
+            ExitStatus::Panic => 2,
+            ExitStatus::OutOfMemory => 3,
+            ExitStatus::Watchdog => 4,
+            ExitStatus::TestFailed(index) => {
+                let code = usize::from(Self::FIRST_TEST_FAILURE_CODE).saturating_add(*index);
+                code.min(usize::from(u8::MAX)) as u8
+            }
+        }
+    }
+
+    /// What the next boot reports as the reason for the reset
+    fn reset_reason(&self) -> LastResetReason {
+        match self {
+            ExitStatus::Ok | ExitStatus::TestFailed(_) => LastResetReason::Shutdown,
+            ExitStatus::Watchdog => LastResetReason::Watchdog,
+            ExitStatus::Error(_) | ExitStatus::Panic | ExitStatus::OutOfMemory => LastResetReason::Panic,
+        }
+    }
+}
+
+/// Shut down or reboot the system through the SBI `SRST` extension, falling
+/// back to the test device on the `virt` platform, and halting the current
+/// hart if neither is available
+pub fn reset(kind: ResetKind) -> ! {
+    use sbi::{
+        probe_extension,
+        system_reset::{system_reset, ResetReason, ResetType, EXTENSION_ID},
+        ExtensionAvailability,
+    };
+
+    crate::coverage::dump();
+    pstore::record(match kind {
+        ResetKind::Shutdown => LastResetReason::Shutdown,
+        ResetKind::ColdReboot | ResetKind::WarmReboot => LastResetReason::Reboot,
+    });
+
+    if let ExtensionAvailability::Available(_) = probe_extension(EXTENSION_ID) {
+        let reset_type = match kind {
+            ResetKind::Shutdown => ResetType::Shutdown,
+            ResetKind::ColdReboot => ResetType::ColdReboot,
+            ResetKind::WarmReboot => ResetType::WarmReboot,
+        };
+
+        if let Err(e) = system_reset(reset_type, ResetReason::NoReason) {
+            log::error!("SBI system reset failed: {:?}", e);
+        }
+    }
+
+    #[cfg(feature = "platform.virt")]
+    virt::exit(match kind {
+        ResetKind::Shutdown => virt::ExitStatus::Pass,
+        ResetKind::ColdReboot | ResetKind::WarmReboot => virt::ExitStatus::Reset,
+    });
+
+    #[cfg(not(feature = "platform.virt"))]
+    {
+        log::error!("No way to reset the system, halting instead");
+        crate::panicking::halt()
+    }
 }
 
 #[cfg(feature = "platform.virt")]
 pub fn exit(status: ExitStatus) -> ! {
-    virt::exit(match status {
-        ExitStatus::Ok => virt::ExitStatus::Pass,
-        ExitStatus::Error(_) => virt::ExitStatus::Fail(1),
+    crate::coverage::dump();
+    pstore::record(status.reset_reason());
+    virt::exit(match status.code() {
+        0 => virt::ExitStatus::Pass,
+        code => virt::ExitStatus::Fail(u16::from(code)),
     })
 }
 
@@ -57,12 +178,15 @@ pub fn exit(status: ExitStatus) -> ! {
         ExtensionAvailability,
     };
 
+    crate::coverage::dump();
+    pstore::record(status.reset_reason());
+
     match probe_extension(EXTENSION_ID) {
         ExtensionAvailability::Available(_) => system_reset(
             ResetType::Shutdown,
             match status {
                 ExitStatus::Ok => ResetReason::NoReason,
-                ExitStatus::Error(_) => ResetReason::SystemFailure,
+                _ => ResetReason::SystemFailure,
             },
         )
         .unwrap(),
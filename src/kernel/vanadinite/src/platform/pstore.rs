@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A record of why the system was reset, kept in a region of RAM set aside in
+//! the device tree which the platform doesn't clear on reset. This uses the
+//! same `/reserved-memory` node Linux does for `ramoops`, though only the
+//! first few words of it.
+//!
+//! At boot the record from the previous boot is read and then marked as
+//! running. Whatever path the kernel takes to shut down or reboot records why
+//! before doing so, so a record which is still marked as running means the
+//! system was reset out from under the kernel.
+
+use crate::mem::{paging::PhysicalAddress, phys2virt};
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use librust::syscalls::system::ResetReason;
+
+const COMPATIBLE: &[&str] = &["ramoops", "vanadinite,pstore"];
+const MAGIC: u64 = u64::from_le_bytes(*b"vndnPSTR");
+/// Stored while the system is up, which is never reported since it's
+/// replaced on the way down
+const RUNNING: u64 = u64::MAX;
+
+/// Virtual address of the record, or zero if there isn't a store
+static RECORD: AtomicUsize = AtomicUsize::new(0);
+static LAST_RESET: AtomicUsize = AtomicUsize::new(ResetReason::Unknown as usize);
+
+#[repr(C)]
+struct Record {
+    magic: u64,
+    reason: u64,
+    /// Bitwise inverse of `reason`, so that random RAM contents which happen
+    /// to include the magic aren't taken as a record
+    check: u64,
+}
+
+/// The physical address range of the persistent store, if the device tree
+/// sets one aside
+pub fn region(fdt: &fdt::Fdt<'_>) -> Option<Range<usize>> {
+    let node = fdt
+        .find_node("/reserved-memory")?
+        .children()
+        .find(|node| node.compatible().map(|c| c.all().any(|c| COMPATIBLE.contains(&c))).unwrap_or(false))?;
+    let reg = node.reg()?.next()?;
+    let start = reg.starting_address as usize;
+    let end = start + reg.size?;
+
+    match end - start >= core::mem::size_of::<Record>() {
+        true => Some(start..end),
+        false => None,
+    }
+}
+
+/// Read the reason the previous boot recorded, and mark this one as running
+pub fn init(fdt: &fdt::Fdt<'_>) {
+    let region = match region(fdt) {
+        Some(region) => region,
+        None => return,
+    };
+
+    let record: *mut Record = phys2virt(PhysicalAddress::new(region.start)).as_mut_ptr().cast();
+    let (magic, reason, check) = unsafe {
+        (
+            core::ptr::addr_of!((*record).magic).read_volatile(),
+            core::ptr::addr_of!((*record).reason).read_volatile(),
+            core::ptr::addr_of!((*record).check).read_volatile(),
+        )
+    };
+
+    let last_reset = match (magic, reason) {
+        (MAGIC, RUNNING) if check == !reason => ResetReason::Watchdog,
+        (MAGIC, reason) if check == !reason => ResetReason::from_raw(reason as usize).unwrap_or(ResetReason::Unknown),
+        _ => ResetReason::PowerOn,
+    };
+
+    LAST_RESET.store(last_reset as usize, Ordering::Relaxed);
+    RECORD.store(record as usize, Ordering::Release);
+    write(record, RUNNING);
+}
+
+/// Why the system was last reset
+pub fn last_reset() -> ResetReason {
+    ResetReason::from_raw(LAST_RESET.load(Ordering::Relaxed)).unwrap_or(ResetReason::Unknown)
+}
+
+/// Record why the system is about to be reset. Only the first reason recorded
+/// is kept, so e.g. a panic followed by a reboot is reported as a panic.
+pub fn record(reason: ResetReason) {
+    let record = RECORD.swap(0, Ordering::AcqRel) as *mut Record;
+    if !record.is_null() {
+        write(record, reason as u64);
+    }
+}
+
+fn write(record: *mut Record, reason: u64) {
+    unsafe {
+        core::ptr::addr_of_mut!((*record).magic).write_volatile(MAGIC);
+        core::ptr::addr_of_mut!((*record).reason).write_volatile(reason);
+        core::ptr::addr_of_mut!((*record).check).write_volatile(!reason);
+    }
+}
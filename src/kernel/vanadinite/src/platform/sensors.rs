@@ -0,0 +1,171 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Temperature and clock frequency reporting for platforms which expose them,
+//! the readings are passed on to userspace through `SystemInfo`.
+
+use crate::{
+    device::{Device, ProbeError},
+    drivers::{
+        allwinner::d1::{ccu::Ccu, ths::Ths},
+        sifive::prci::Prci,
+    },
+    mem::{paging::PhysicalAddress, phys2virt},
+};
+use alloc::{boxed::Box, vec::Vec};
+use fdt::{node::FdtNode, Fdt};
+use librust::syscalls::system::MAX_TEMPERATURE_SENSORS;
+use sync::SpinMutex;
+
+static TEMPERATURE_SENSORS: SpinMutex<Vec<&'static dyn TemperatureSensor>> = SpinMutex::new(Vec::new());
+static CPU_CLOCK: SpinMutex<Option<&'static dyn CpuClock>> = SpinMutex::new(None);
+static D1_CCU: SpinMutex<Option<&'static Ccu>> = SpinMutex::new(None);
+
+pub trait TemperatureSensor: Send + Sync {
+    /// Short name of what the sensor is measuring
+    fn name(&self) -> &'static str;
+    /// Current temperature in millidegrees Celsius, `None` if no reading is
+    /// available yet
+    fn temperature(&self) -> Option<i32>;
+}
+
+pub trait CpuClock: Send + Sync {
+    /// Current frequency of the CPU core clock in Hz, `None` if it can't be
+    /// determined
+    fn cpu_frequency(&self) -> Option<u64>;
+
+    /// Lowest frequency the CPU clock can safely be set to, `None` if the
+    /// clock can't be changed
+    fn min_cpu_frequency(&self) -> Option<u64> {
+        None
+    }
+
+    /// Change the CPU clock to as close to `hz` as possible, returning the
+    /// resulting frequency
+    fn set_cpu_frequency(&self, _hz: u64) -> Option<u64> {
+        None
+    }
+}
+
+/// Lowest core frequency used when scaling the FU740
+const PRCI_MIN_CPU_FREQ: u64 = 300_000_000;
+
+/// A [`Prci`] along with the rate of its input oscillator, which is only known
+/// from the device tree
+struct PrciClock {
+    prci: &'static Prci,
+    hfclk: u64,
+    /// Only the FU740 has a peripheral clock that's independent of the core
+    /// clock, so the FU540 is left alone
+    scalable: bool,
+}
+
+impl CpuClock for PrciClock {
+    fn cpu_frequency(&self) -> Option<u64> {
+        Some(self.prci.core_frequency(self.hfclk))
+    }
+
+    fn min_cpu_frequency(&self) -> Option<u64> {
+        match self.scalable {
+            true => Some(PRCI_MIN_CPU_FREQ),
+            false => None,
+        }
+    }
+
+    fn set_cpu_frequency(&self, hz: u64) -> Option<u64> {
+        match self.scalable {
+            true => self.prci.set_core_frequency(self.hfclk, hz),
+            false => None,
+        }
+    }
+}
+
+/// Probe for the D1 clock controller, which provides the CPU clock
+pub fn probe_d1_ccu(_: &Fdt<'_>, device: &Device) -> Result<(), ProbeError> {
+    let ccu = unsafe { map::<Ccu>(device) }.ok_or(ProbeError::Failed("missing registers"))?;
+
+    log::debug!("Registering D1 CCU @ {:#p}", ccu);
+    *CPU_CLOCK.lock() = Some(ccu);
+    *D1_CCU.lock() = Some(ccu);
+
+    Ok(())
+}
+
+/// Probe for the D1 thermal sensor, which is useless without its bus clock so
+/// waits for the CCU to be bound first
+pub fn probe_d1_ths(_: &Fdt<'_>, device: &Device) -> Result<(), ProbeError> {
+    let ccu = D1_CCU.lock().ok_or(ProbeError::Deferred)?;
+    let ths = unsafe { map::<Ths>(device) }.ok_or(ProbeError::Failed("missing registers"))?;
+
+    ccu.enable_ths();
+    ths.init();
+
+    log::debug!("Registering D1 THS @ {:#p}", ths);
+    TEMPERATURE_SENSORS.lock().push(ths);
+
+    Ok(())
+}
+
+/// Probe for the FU540/FU740 clock controller
+pub fn probe_prci(fdt: &Fdt<'_>, device: &Device) -> Result<(), ProbeError> {
+    let prci = unsafe { map::<Prci>(device) }.ok_or(ProbeError::Failed("missing registers"))?;
+    let hfclk = device
+        .node(fdt)
+        .and_then(|node| hfclk_frequency(fdt, node))
+        .ok_or(ProbeError::Failed("unable to determine input clock frequency"))?;
+    let scalable = device.is_compatible(&["sifive,fu740-c000-prci"]);
+
+    log::debug!("Registering PRCI @ {:#p} (hfclk: {} Hz)", prci, hfclk);
+    *CPU_CLOCK.lock() = Some(Box::leak(Box::new(PrciClock { prci, hfclk, scalable })));
+
+    Ok(())
+}
+
+/// Current CPU frequency in Hz, if there's a clock controller which can report
+/// it
+pub fn cpu_frequency() -> Option<u64> {
+    CPU_CLOCK.lock().and_then(|clock| clock.cpu_frequency())
+}
+
+/// The registered CPU clock controller, if any
+pub fn cpu_clock() -> Option<&'static dyn CpuClock> {
+    *CPU_CLOCK.lock()
+}
+
+/// Read every registered temperature sensor which currently has a reading,
+/// returning the number of readings written to `readings`
+pub fn temperatures(readings: &mut [i32; MAX_TEMPERATURE_SENSORS]) -> usize {
+    let sensors = TEMPERATURE_SENSORS.lock();
+    let temperatures = sensors.iter().filter_map(|sensor| sensor.temperature());
+
+    let mut n_readings = 0;
+    for (reading, temperature) in readings.iter_mut().zip(temperatures) {
+        *reading = temperature;
+        n_readings += 1;
+    }
+
+    n_readings
+}
+
+/// # Safety
+///
+/// The device's first region must have the register layout of `T`
+unsafe fn map<T>(device: &Device) -> Option<&'static T> {
+    let region = device.regions.first()?;
+    let virt = phys2virt(PhysicalAddress::new(region.address));
+
+    Some(&*virt.as_ptr().cast::<T>())
+}
+
+/// The PRCI takes `hfclk` as its first clock input
+fn hfclk_frequency(fdt: &Fdt<'_>, prci: FdtNode<'_, '_>) -> Option<u64> {
+    let clocks = prci.property("clocks")?.value;
+    let phandle = u32::from_be_bytes(clocks.get(..4)?.try_into().ok()?);
+    let hfclk = fdt.find_phandle(phandle)?;
+
+    Some(hfclk.property("clock-frequency")?.as_usize()? as u64)
+}
@@ -0,0 +1,86 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Loading `init` from the boot partition of an SD card, as laid out by
+//! `cargo xtask image`, instead of using the copy built into the kernel image.
+
+use crate::{
+    drivers::{
+        generic::sdcard::SdCard,
+        sifive::fu540_c000::spi::{SifiveSpi, SifiveSpiBus},
+        CompatibleWith,
+    },
+    io::block_device::{BlockDevice, Fat32Device, BLOCK_SIZE},
+    mem::{paging::PhysicalAddress, phys2virt},
+    platform::sensors,
+};
+use alloc::{format, string::String, vec, vec::Vec};
+use fat32::Fat32;
+use fdt::Fdt;
+
+const INIT_FILE_NAME: &str = "INIT";
+/// The SPI controllers run off of `tlclk`, which is half the core clock. If
+/// the core clock is unknown, assume the fastest it runs at so the SD card
+/// clock errs on the slow side.
+const FALLBACK_SPI_INPUT_CLOCK: u64 = 1_500_000_000 / 2;
+
+/// Find an SD card attached to a SPI controller and read `init` from the root
+/// directory of its first FAT32 partition
+pub fn load_init(fdt: &Fdt<'_>) -> Result<Vec<u8>, String> {
+    let (spi, cs) = fdt
+        .all_nodes()
+        .filter(|node| node.compatible().map_or(false, |c| c.all().any(|c| SifiveSpi::compatible_with().contains(&c))))
+        .find_map(|node| {
+            let slot = node.children().find(|child| {
+                child.compatible().map_or(false, |c| c.all().any(|c| c == "mmc-spi-slot"))
+            })?;
+
+            Some((node, slot.reg()?.next()?.starting_address as u32))
+        })
+        .ok_or_else(|| String::from("no SD card slot found"))?;
+
+    let reg = spi.reg().and_then(|mut reg| reg.next()).ok_or_else(|| String::from("SPI controller has no registers"))?;
+    let spi: &'static SifiveSpi =
+        unsafe { &*phys2virt(PhysicalAddress::from_ptr(reg.starting_address)).as_ptr().cast() };
+    spi.init(cs);
+
+    let input_hz = sensors::cpu_frequency().map_or(FALLBACK_SPI_INPUT_CLOCK, |hz| hz / 2);
+    let card = SdCard::new(SifiveSpiBus { spi, input_hz }).map_err(|e| format!("{:?}", e))?;
+    log::info!("Found SD card with {} MiB", card.block_count() * BLOCK_SIZE as u64 / 1024 / 1024);
+
+    let mut device = Fat32Device(card);
+    let mut mbr = [0; BLOCK_SIZE];
+    device.0.read_block(0, &mut mbr).map_err(|e| format!("{:?}", e))?;
+
+    // Cards formatted without a partition table have the volume start at the
+    // first sector
+    let partition = mbr[0x1BE..0x1FE].chunks_exact(16).find(|entry| matches!(entry[4], 0x0B | 0x0C));
+    let start_sector = match partition {
+        _ if &mbr[0x52..0x5A] == b"FAT32   " => 0,
+        Some(entry) => u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as u64,
+        None => 0,
+    };
+
+    let mut volume = Fat32::new(device, start_sector).map_err(|e| format!("{:?}", e))?;
+    let root = volume.root_cluster();
+    let entry = volume
+        .read_directory(root)
+        .map_err(|e| format!("{:?}", e))?
+        .into_iter()
+        .find(|entry| entry.name == INIT_FILE_NAME && !entry.is_directory())
+        .ok_or_else(|| format!("no `{}` in the root directory", INIT_FILE_NAME))?;
+
+    let cluster_size = volume.cluster_size();
+    let chain = volume.chain(entry.first_cluster).map_err(|e| format!("{:?}", e))?;
+    let mut init = vec![0; chain.len() * cluster_size];
+    for (cluster, buffer) in chain.into_iter().zip(init.chunks_exact_mut(cluster_size)) {
+        volume.read_cluster(cluster, buffer).map_err(|e| format!("{:?}", e))?;
+    }
+
+    init.truncate(entry.size as usize);
+    Ok(init)
+}
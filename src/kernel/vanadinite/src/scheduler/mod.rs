@@ -5,45 +5,176 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod priority;
 pub mod round_robin;
 
 use crate::{
-    csr,
+    csr::{self, satp::Satp},
+    mem::{self, paging::SATP_MODE},
     task::{Context, Task},
     utils::{ticks_per_us, SameHartDeadlockDetection},
 };
 use alloc::{boxed::Box, collections::BTreeMap, sync::Arc};
 use core::{
+    cell::Cell,
     num::NonZeroUsize,
-    sync::atomic::{AtomicUsize, Ordering},
+    ops::DerefMut,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
 };
-use librust::task::Tid;
+use librust::{syscalls::trace::TraceEvent, task::Tid};
 use sync::{SpinMutex, SpinRwLock};
 
-pub static SCHEDULER: round_robin::RoundRobinScheduler = round_robin::RoundRobinScheduler::new();
+pub static SCHEDULER: SelectedScheduler = SelectedScheduler;
+static ROUND_ROBIN: round_robin::RoundRobinScheduler = round_robin::RoundRobinScheduler::new();
+static PRIORITY: priority::PriorityScheduler = priority::PriorityScheduler::new();
+static SELECTED: AtomicU8 = AtomicU8::new(SchedulerKind::RoundRobin as u8);
 pub static TASKS: TaskList = TaskList::new();
 
 // Used for heuristics in schedulers if they so choose
 static N_TASKS: AtomicUsize = AtomicUsize::new(0);
 
-//pub fn init_scheduler(scheduler: Box<dyn Scheduler>) {
-//    SCHEDULER.0.write().replace(scheduler).expect("reinitialized scheduler!");
-//}
+#[thread_local]
+static RESCHEDULE_REQUESTED: Cell<bool> = Cell::new(false);
 
-pub struct WakeToken {
+/// Ask for the current hart to reschedule once it's finished handling the
+/// current interrupt
+pub fn request_reschedule() {
+    RESCHEDULE_REQUESTED.set(true);
+}
+
+/// Whether a reschedule was requested on the current hart, clearing the
+/// request
+pub fn take_reschedule_request() -> bool {
+    RESCHEDULE_REQUESTED.replace(false)
+}
+
+/// The scheduler implementations available, selected with the `scheduler=`
+/// bootarg
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SchedulerKind {
+    /// [`round_robin::RoundRobinScheduler`]
+    RoundRobin = 0,
+    /// [`priority::PriorityScheduler`]
+    Priority = 1,
+}
+
+/// Parse the value of the `scheduler=` bootarg, which is one of `round-robin`
+/// or `priority`. Must happen before any tasks are enqueued.
+pub fn parse_scheduler(value: Option<&str>) {
+    let kind = match value {
+        Some("round-robin") => SchedulerKind::RoundRobin,
+        Some("priority") => SchedulerKind::Priority,
+        _ => {
+            log::warn!("Unknown scheduler: {:?}, defaulting to `round-robin`", value);
+            SchedulerKind::RoundRobin
+        }
+    };
+
+    SELECTED.store(kind as u8, Ordering::Relaxed);
+}
+
+pub fn selected() -> SchedulerKind {
+    match SELECTED.load(Ordering::Relaxed) {
+        1 => SchedulerKind::Priority,
+        _ => SchedulerKind::RoundRobin,
+    }
+}
+
+/// Forwards to whichever scheduler was selected at boot
+pub struct SelectedScheduler;
+
+impl SelectedScheduler {
+    fn get(&self) -> &'static dyn Scheduler {
+        match selected() {
+            SchedulerKind::RoundRobin => &ROUND_ROBIN,
+            SchedulerKind::Priority => &PRIORITY,
+        }
+    }
+}
+
+impl Scheduler for SelectedScheduler {
+    fn schedule(&self) -> ! {
+        self.get().schedule()
+    }
+
+    fn enqueue(&self, task: Task) -> Tid {
+        self.get().enqueue(task)
+    }
+
+    fn dequeue(&self, tid: Tid) {
+        self.get().dequeue(tid)
+    }
+
+    #[track_caller]
+    fn block(&self, wait: WaitId) {
+        self.get().block(wait)
+    }
+
+    #[track_caller]
+    fn unblock(&self, token: WakeToken) -> bool {
+        self.get().unblock(token)
+    }
+
+    fn hand_off(&self, tid: Tid) {
+        self.get().hand_off(tid)
+    }
+
+    fn migrate(&self, tid: Tid, hart: usize) -> bool {
+        self.get().migrate(tid, hart)
+    }
+
+    fn active_on_cpu(&self) -> Option<Arc<SpinMutex<Task, SameHartDeadlockDetection>>> {
+        self.get().active_on_cpu()
+    }
+
+    fn reschedule_pending(&self) -> bool {
+        self.get().reschedule_pending()
+    }
+
+    fn tasks_to_pull(&self) -> bool {
+        self.get().tasks_to_pull()
+    }
+
+    fn runnable_tasks(&self) -> usize {
+        self.get().runnable_tasks()
+    }
+}
+
+/// One particular wait of a task, see [`Task::wait_id`]. A wait can be cut
+/// short by an event, leaving behind tokens for it in whatever the task was
+/// waiting on, so tokens are tied to a wait to keep them from waking the task
+/// up from a later one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitId {
     tid: Tid,
+    generation: u64,
+}
+
+impl WaitId {
+    pub fn new(tid: Tid, generation: u64) -> Self {
+        Self { tid, generation }
+    }
+
+    pub fn tid(self) -> Tid {
+        self.tid
+    }
+}
+
+pub struct WakeToken {
+    wait: WaitId,
     work: Box<dyn FnOnce(&mut Task) + Send>,
 }
 
 impl WakeToken {
-    pub fn new(tid: Tid, work: impl FnOnce(&mut Task) + Send + 'static) -> Self {
-        Self { tid, work: Box::new(work) }
+    pub fn new(wait: WaitId, work: impl FnOnce(&mut Task) + Send + 'static) -> Self {
+        Self { wait, work: Box::new(work) }
     }
 }
 
 impl core::fmt::Debug for WakeToken {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        f.debug_struct("WakeToken").field("tid", &self.tid).finish_non_exhaustive()
+        f.debug_struct("WakeToken").field("wait", &self.wait).finish_non_exhaustive()
     }
 }
 
@@ -85,29 +216,108 @@ impl TaskList {
     pub fn get(&self, tid: Tid) -> Option<Arc<SpinMutex<Task, SameHartDeadlockDetection>>> {
         self.map.read().get(&tid).cloned()
     }
+
+    /// Run `f` on each task in the list without blocking, returning `false` if
+    /// the list is currently locked for writing
+    pub fn try_for_each(&self, mut f: impl FnMut(Tid, &Arc<SpinMutex<Task, SameHartDeadlockDetection>>)) -> bool {
+        match self.map.try_read() {
+            Some(map) => {
+                map.iter().for_each(|(tid, task)| f(*tid, task));
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 pub trait Scheduler: Send {
     fn schedule(&self) -> !;
     fn enqueue(&self, task: Task) -> Tid;
+    /// Forget about a task which won't run again, including if it's blocked,
+    /// since nothing may ever wake it up for it to be seen to be dead
     fn dequeue(&self, tid: Tid);
-    fn block(&self, tid: Tid);
-    fn unblock(&self, token: WakeToken);
+    fn block(&self, wait: WaitId);
+    /// Wake the task up from the wait the token is for, returning `false` and
+    /// dropping the token if it isn't blocked in that wait anymore
+    fn unblock(&self, token: WakeToken) -> bool;
+    /// Run the given task next on the current hart, instead of waiting for its
+    /// turn to come around. Used to switch directly to the receiver of an RPC
+    /// call, since the caller has nothing to do until it's replied to.
+    fn hand_off(&self, tid: Tid);
+    /// Move a task to the given hart, returning `false` if the task or hart
+    /// doesn't exist. Tasks which are currently running are moved once their
+    /// hart switches away from them.
+    fn migrate(&self, tid: Tid, hart: usize) -> bool;
     fn active_on_cpu(&self) -> Option<Arc<SpinMutex<Task, SameHartDeadlockDetection>>>;
+    /// Whether the current hart should switch tasks after being kicked by
+    /// another hart
+    fn reschedule_pending(&self) -> bool;
+    /// Whether the current hart, which is idle, could take tasks waiting on
+    /// other harts. Idle harts keep waking up to look for them while this is
+    /// true, otherwise they sleep until they're kicked.
+    fn tasks_to_pull(&self) -> bool;
+    /// Total number of runnable tasks across all harts, which may only be an
+    /// estimate
+    fn runnable_tasks(&self) -> usize;
 }
 
-fn sleep() -> ! {
+/// Switch to the given task on the current hart, running the work of the token
+/// it was woken with first
+fn switch_to(mut task: impl DerefMut<Target = Task>, token: Option<WakeToken>) -> ! {
+    let root_page_table = task.group.memory_manager.lock().table_phys_address();
+    let tid = task.tid;
+
+    let previous = mem::tlb::active_asid().map(usize::from).unwrap_or(0);
+    crate::trace::record(TraceEvent::TaskSwitch, tid.value(), [previous, 0]);
+
+    // The address space has to be published before it's switched to, otherwise
+    // a shootdown could see this hart as not using it and skip it while it
+    // fills its TLB with entries that are about to be unmapped
+    mem::tlb::set_active(root_page_table, tid.value() as u16);
+
+    // FIXME: We need to switch page tables before doing work on the
+    // wake token, but this feels kinda shitty, maybe find a way to
+    // do waking that doesn't need it?
+    csr::satp::write(Satp { mode: SATP_MODE, asid: tid.value() as u16, root_page_table });
+    mem::sfence(None, None);
+
+    if let Some(token) = token {
+        (token.work)(&mut task);
+        task.wait_generation += 1;
+    }
+
+    task.deliver_pending_events();
+
+    let context = task.context.clone();
+
+    log::debug!("Scheduling {}, pc: {:#p}", task.display_name(), task.context.pc as *mut u8);
     sbi::timer::set_timer(csr::time::read() + ticks_per_us(10_000, crate::TIMER_FREQ.load(Ordering::Relaxed))).unwrap();
+
+    // !! RELEASE LOCKS BEFORE CONTEXT SWITCHING !!
+    drop(task);
+
+    unsafe { return_to_usermode(&context) }
+}
+
+/// Put the current hart to sleep until there's something to do
+fn go_idle() -> ! {
+    log::debug!("No work to do, sleeping :(");
+
+    // Shrinking the heap waits on the other harts to flush their TLBs, so it's
+    // put off until nothing is held
+    mem::heap::HEAP_ALLOCATOR.trim();
+
+    mem::tlb::clear_active();
+    mem::sfence(None, None);
+
+    sleep()
+}
+
+fn sleep() -> ! {
     csr::sie::enable();
     csr::sstatus::enable_interrupts();
 
-    #[rustfmt::skip]
-    unsafe {
-        core::arch::asm!("
-            1: wfi
-               j 1b
-        ", options(noreturn))
-    };
+    crate::platform::idle::idle()
 }
 
 #[naked]
@@ -0,0 +1,329 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{Scheduler, Task, Tid, WaitId, WakeToken, TASKS};
+use crate::{task::TaskState, utils::SameHartDeadlockDetection};
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use librust::task::MIN_NICE;
+use sync::Lazy;
+
+type SpinMutex<T> = sync::SpinMutex<T, SameHartDeadlockDetection>;
+
+/// Upper bound on a task's interactivity, which goes up each time it blocks
+/// and down each time it's preempted
+const MAX_INTERACTIVITY: u8 = 40;
+
+struct QueuedTask {
+    tid: Tid,
+    task: Arc<SpinMutex<Task>>,
+    token: Option<WakeToken>,
+    /// The wait the task is blocked in
+    wait: Option<WaitId>,
+    /// Priority and niceness of the task, which are only updated when the task
+    /// is preempted since the task may be locked when it's blocked or woken
+    priority: u8,
+    nice: i8,
+    /// How often the task blocks rather than using up its time, tasks waiting
+    /// on input or IPC score high and CPU-bound tasks score low
+    interactivity: u8,
+    /// How far ahead of the other ready tasks of the same priority the task
+    /// was placed when it was woken, zero if it wasn't boosted
+    boost: u8,
+    /// Hart the task was migrated to, if any, which it will only run on
+    pinned: Option<usize>,
+}
+
+impl QueuedTask {
+    fn new(tid: Tid, task: Arc<SpinMutex<Task>>, priority: u8, nice: i8) -> Self {
+        Self { tid, task, token: None, wait: None, priority, nice, interactivity: 0, boost: 0, pinned: None }
+    }
+
+    fn can_run_on(&self, hart: usize) -> bool {
+        self.pinned.map_or(true, |pinned| pinned == hart)
+    }
+
+    /// Interactivity beyond what the task's niceness demands. At the default
+    /// niceness a task has to have blocked 20 more times than it's been
+    /// preempted to be boosted, nicer tasks more and less nice tasks fewer.
+    fn boost_on_wake(&self) -> u8 {
+        self.interactivity.saturating_sub((self.nice - MIN_NICE) as u8)
+    }
+
+    /// Whether this task should take the hart `running` is on, either because
+    /// it's more important or it's a boosted task waiting on one which isn't
+    fn preempts(&self, running: &QueuedTask) -> bool {
+        self.priority > running.priority || (self.priority == running.priority && self.boost > 0 && running.boost == 0)
+    }
+}
+
+struct State {
+    /// Runnable tasks which aren't running, highest priority first and then in
+    /// the order they became runnable
+    ready: VecDeque<QueuedTask>,
+    /// The task running on each hart
+    running: Vec<Option<QueuedTask>>,
+    blocked: Vec<QueuedTask>,
+    /// Task each hart was handed the rest of the current task's time to
+    next: Vec<Option<Tid>>,
+}
+
+impl State {
+    /// Boosted tasks go ahead of the unboosted ones of the same priority, and
+    /// behind those with the same or more boost, so tasks which keep using up
+    /// their time still take turns with each other and are never starved by
+    /// tasks of the same priority
+    fn push_ready(&mut self, task: QueuedTask) {
+        let index = self
+            .ready
+            .iter()
+            .position(|t| t.priority < task.priority || (t.priority == task.priority && t.boost < task.boost))
+            .unwrap_or(self.ready.len());
+        self.ready.insert(index, task);
+    }
+
+    /// Take the first task the given hart can run off of the ready queue
+    fn next_runnable(&mut self, hart: usize) -> Option<QueuedTask> {
+        let mut index = 0;
+        while let Some(queued_task) = self.ready.get(index) {
+            if !queued_task.can_run_on(hart) {
+                index += 1;
+                continue;
+            }
+
+            let state = queued_task.task.lock().state;
+            match state {
+                TaskState::Dead => drop(self.ready.remove(index)),
+                TaskState::Blocked => index += 1,
+                TaskState::Running => return self.ready.remove(index),
+            }
+        }
+
+        None
+    }
+
+    /// Find the hart which should pick up a newly runnable task, either one
+    /// which is idle or one running something it preempts
+    fn hart_to_kick(&self, task: &QueuedTask) -> Option<usize> {
+        let candidates = || (0..self.running.len()).filter(|&hart| task.can_run_on(hart));
+
+        candidates()
+            .find(|&hart| self.running[hart].is_none())
+            .or_else(|| candidates().find(|&hart| self.running[hart].as_ref().map_or(false, |t| task.preempts(t))))
+    }
+}
+
+/// Every hart runs tasks from a single shared queue, always picking the highest
+/// priority task that's runnable and taking turns between tasks of the same
+/// priority. Priorities are strict, so tasks can be starved by higher priority
+/// tasks which never block.
+pub struct PriorityScheduler {
+    state: Lazy<SpinMutex<State>>,
+}
+
+impl PriorityScheduler {
+    pub const fn new() -> Self {
+        Self {
+            state: Lazy::new(|| {
+                let n_cpus = crate::N_CPUS.load(core::sync::atomic::Ordering::Acquire);
+                let mut running = Vec::with_capacity(n_cpus);
+                running.resize_with(n_cpus, || None);
+
+                SpinMutex::new(State {
+                    ready: VecDeque::with_capacity(16),
+                    running,
+                    blocked: Vec::new(),
+                    next: alloc::vec![None; n_cpus],
+                })
+            }),
+        }
+    }
+
+    /// Queue a newly runnable task, kicking another hart to run it if it's
+    /// idle or running something less important
+    fn make_ready(&self, task: QueuedTask) {
+        let mut state = self.state.lock();
+        let kick = state.hart_to_kick(&task);
+        state.push_ready(task);
+        drop(state);
+
+        match kick {
+            Some(hart) if hart == crate::HART_ID.get() => super::request_reschedule(),
+            Some(hart) => {
+                if let Err(e) = sbi::ipi::send_ipi(sbi::HartMask::new(0).with(hart)) {
+                    log::error!("Failed to kick hart {}: {:?}", hart, e);
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+impl Scheduler for PriorityScheduler {
+    fn schedule(&self) -> ! {
+        let hart = crate::HART_ID.get();
+        let mut state = self.state.lock();
+
+        if let Some(mut previous) = state.running[hart].take() {
+            let (task_state, priority, nice) = {
+                let task = previous.task.lock();
+                (task.state, task.priority, task.nice)
+            };
+
+            // The task is still runnable, so it used up its time instead of
+            // blocking and goes to the back of its priority unboosted
+            if !matches!(task_state, TaskState::Dead) {
+                previous.priority = priority;
+                previous.nice = nice;
+                previous.interactivity = previous.interactivity.saturating_sub(1);
+                previous.boost = 0;
+                state.push_ready(previous);
+            }
+        }
+
+        // A task handed the rest of the previous task's time runs next
+        // regardless of its priority
+        let handed_off = state.next[hart]
+            .take()
+            .and_then(|tid| state.ready.iter().position(|t| t.tid == tid && t.can_run_on(hart)))
+            .filter(|&index| matches!(state.ready[index].task.lock().state, TaskState::Running));
+
+        let to_run = match handed_off {
+            Some(index) => state.ready.remove(index),
+            None => state.next_runnable(hart),
+        };
+
+        match to_run {
+            Some(mut queued_task) => {
+                let task = Arc::clone(&queued_task.task);
+                let task = task.lock();
+                let token = queued_task.token.take();
+                state.running[hart] = Some(queued_task);
+
+                // !! RELEASE LOCK BEFORE CONTEXT SWITCHING !!
+                drop(state);
+
+                super::switch_to(task, token)
+            }
+            None => {
+                drop(state);
+                super::go_idle()
+            }
+        }
+    }
+
+    fn enqueue(&self, task: Task) -> Tid {
+        let (priority, nice) = (task.priority, task.nice);
+        let (tid, task) = TASKS.insert(task);
+        self.make_ready(QueuedTask::new(tid, task, priority, nice));
+
+        tid
+    }
+
+    fn dequeue(&self, tid: Tid) {
+        let mut state = self.state.lock();
+        if let Some(index) = state.ready.iter().position(|t| t.tid == tid) {
+            state.ready.remove(index);
+        }
+
+        if let Some(index) = state.blocked.iter().position(|t| t.tid == tid) {
+            state.blocked.swap_remove(index);
+        }
+    }
+
+    #[track_caller]
+    fn block(&self, wait: WaitId) {
+        let tid = wait.tid();
+        let mut state = self.state.lock();
+        let State { ready, running, blocked, .. } = &mut *state;
+
+        let mut task = match running.iter().position(|t| t.as_ref().map_or(false, |t| t.tid == tid)) {
+            Some(hart) => running[hart].take().unwrap(),
+            None => {
+                let index = ready.iter().position(|t| t.tid == tid).expect("blocking task isn't runnable");
+                ready.remove(index).unwrap()
+            }
+        };
+
+        task.interactivity = (task.interactivity + 1).min(MAX_INTERACTIVITY);
+        task.wait = Some(wait);
+        blocked.push(task);
+    }
+
+    #[track_caller]
+    fn unblock(&self, token: WakeToken) -> bool {
+        let mut state = self.state.lock();
+        let index = match state.blocked.iter().position(|t| t.wait == Some(token.wait)) {
+            Some(index) => index,
+            None => return false,
+        };
+        let mut task = state.blocked.swap_remove(index);
+        drop(state);
+
+        task.wait = None;
+        task.token = Some(token);
+        task.boost = task.boost_on_wake();
+        self.make_ready(task);
+
+        true
+    }
+
+    fn hand_off(&self, tid: Tid) {
+        self.state.lock().next[crate::HART_ID.get()] = Some(tid);
+    }
+
+    fn migrate(&self, tid: Tid, hart: usize) -> bool {
+        let mut state = self.state.lock();
+        if hart >= state.running.len() {
+            return false;
+        }
+
+        let State { ready, running, blocked, .. } = &mut *state;
+        let task =
+            ready.iter_mut().chain(running.iter_mut().flatten()).chain(blocked.iter_mut()).find(|t| t.tid == tid);
+
+        match task {
+            // The task is moved if it's currently running elsewhere once its
+            // hart next schedules
+            Some(task) => {
+                task.pinned = Some(hart);
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[track_caller]
+    fn active_on_cpu(&self) -> Option<Arc<SpinMutex<Task>>> {
+        self.state.lock().running[crate::HART_ID.get()].as_ref().map(|t| Arc::clone(&t.task))
+    }
+
+    /// Reschedule when idle and there's something to run, or when a task which
+    /// preempts the current one is waiting
+    fn reschedule_pending(&self) -> bool {
+        let hart = crate::HART_ID.get();
+        let state = self.state.lock();
+        let waiting = state.ready.iter().find(|t| t.can_run_on(hart));
+
+        match (&state.running[hart], waiting) {
+            (_, None) => false,
+            (None, Some(_)) => true,
+            (Some(running), Some(waiting)) => waiting.preempts(running),
+        }
+    }
+
+    /// Idle harts are always kicked when there's something for them to run
+    fn tasks_to_pull(&self) -> bool {
+        false
+    }
+
+    fn runnable_tasks(&self) -> usize {
+        match self.state.try_lock() {
+            Some(state) => state.ready.len() + state.running.iter().flatten().count(),
+            None => 0,
+        }
+    }
+}
@@ -7,10 +7,9 @@
 
 use core::sync::atomic::Ordering;
 
-use super::{Scheduler, Task, Tid, WakeToken, TASKS};
+use super::{Scheduler, Task, Tid, WaitId, WakeToken, TASKS};
 use crate::{
-    csr::{self, satp::Satp},
-    mem::{self, paging::SATP_MODE},
+    csr,
     task::TaskState,
     utils::{ticks_per_us, SameHartDeadlockDetection},
 };
@@ -19,17 +18,42 @@ use sync::Lazy;
 
 type SpinMutex<T> = sync::SpinMutex<T, SameHartDeadlockDetection>;
 
+/// How long after last being scheduled a task is assumed to still have a warm
+/// cache on the hart it ran on
+const CACHE_HOT_US: u64 = 20_000;
+/// How many more tasks the hart a task last ran on can have queued than the
+/// least loaded hart before a cache-hot task is moved off of it
+const IMBALANCE_THRESHOLD: usize = 2;
+
 struct QueuedTask {
     tid: Tid,
     task: Arc<SpinMutex<Task>>,
     token: Option<WakeToken>,
+    /// The wait the task is blocked in
+    wait: Option<WaitId>,
+    last_hart: Option<usize>,
+    last_ran: u64,
+}
+
+impl QueuedTask {
+    fn is_cache_hot(&self, now: u64) -> bool {
+        self.last_hart.is_some()
+            && now.saturating_sub(self.last_ran) < ticks_per_us(CACHE_HOT_US, crate::TIMER_FREQ.load(Ordering::Relaxed))
+    }
 }
 
 struct Queue {
     active: Option<Arc<SpinMutex<Task>>>,
     queue: VecDeque<QueuedTask>,
+    /// Tasks to push to other harts the next time this hart schedules
+    migrations: Vec<(Tid, usize)>,
+    /// Task which was handed the rest of the current task's time, and should
+    /// be run next regardless of its place in the queue
+    next: Option<Tid>,
 }
 
+/// Each hart has its own queue of tasks which it runs in turn, tasks are
+/// balanced between the queues as they're woken and when a hart goes idle
 pub struct RoundRobinScheduler {
     blocked: Lazy<SpinMutex<VecDeque<QueuedTask>>>,
     queues: Lazy<Vec<SpinMutex<Queue>>>,
@@ -44,7 +68,12 @@ impl RoundRobinScheduler {
                 let mut v = Vec::with_capacity(n_cpus);
 
                 for _ in 0..n_cpus {
-                    v.push(SpinMutex::new(Queue { active: None, queue: VecDeque::with_capacity(16) }));
+                    v.push(SpinMutex::new(Queue {
+                        active: None,
+                        queue: VecDeque::with_capacity(16),
+                        migrations: Vec::new(),
+                        next: None,
+                    }));
                 }
 
                 v
@@ -56,17 +85,130 @@ impl RoundRobinScheduler {
         let current_hart = crate::HART_ID.get();
         &self.queues[current_hart]
     }
+
+    /// Pick the hart a task should be queued on. Tasks stay on the hart they
+    /// last ran on when it isn't more loaded than the least loaded hart, or
+    /// within [`IMBALANCE_THRESHOLD`] of it if the task is still cache-hot, so
+    /// that waking tasks don't bounce between harts for no benefit.
+    fn select_hart(&self, task: &QueuedTask) -> usize {
+        let load = |hart: usize| self.queues[hart].lock().queue.len();
+        let least_loaded = (0..self.queues.len()).min_by_key(|&hart| load(hart)).unwrap_or(0);
+
+        match task.last_hart {
+            Some(last_hart) => {
+                let slack = match task.is_cache_hot(csr::time::read()) {
+                    true => IMBALANCE_THRESHOLD,
+                    false => 0,
+                };
+
+                match load(last_hart) <= load(least_loaded) + slack {
+                    true => last_hart,
+                    false => least_loaded,
+                }
+            }
+            None => least_loaded,
+        }
+    }
+
+    /// Queue a task on the given hart, kicking the hart if it's idle since idle
+    /// harts don't wake up on their own to check for new tasks
+    fn push_to(&self, hart: usize, task: QueuedTask) {
+        let mut queue = self.queues[hart].lock();
+        let idle = queue.active.is_none();
+        queue.queue.push_back(task);
+        drop(queue);
+
+        if !idle {
+            return;
+        }
+
+        match hart == crate::HART_ID.get() {
+            // Woken from an interrupt taken while idle
+            true => super::request_reschedule(),
+            false => {
+                if let Err(e) = sbi::ipi::send_ipi(sbi::HartMask::new(0).with(hart)) {
+                    log::error!("Failed to kick hart {}: {:?}", hart, e);
+                }
+            }
+        }
+    }
+
+    /// Push any tasks requested to migrate away from the current hart to their
+    /// new harts. The current hart isn't running a task while this happens, so
+    /// the contexts of the migrating tasks are up to date.
+    fn process_migrations(&self) {
+        let migrations = core::mem::take(&mut self.current_queue().lock().migrations);
+
+        for (tid, to) in migrations {
+            let mut queue = self.current_queue().lock();
+            let task = match queue.queue.iter().position(|t| t.tid == tid) {
+                Some(index) => queue.queue.remove(index).unwrap(),
+                None => continue,
+            };
+
+            if queue.active.as_ref().map_or(false, |active| Arc::ptr_eq(active, &task.task)) {
+                queue.active = None;
+            }
+
+            drop(queue);
+
+            log::debug!("Migrating task {} to hart {}", tid.value(), to);
+            self.push_to(to, task);
+        }
+    }
+
+    /// Pull a task from the most loaded hart onto the current hart, which would
+    /// otherwise go idle. The task which is currently running on that hart and
+    /// any tasks that are still cache-hot there are left alone.
+    fn pull(&self) -> bool {
+        let current_hart = crate::HART_ID.get();
+        let busiest = (0..self.queues.len())
+            .filter(|&hart| hart != current_hart)
+            .max_by_key(|&hart| self.queues[hart].lock().queue.len());
+
+        // Only try to lock the other hart's queue, if it's busy then it's not
+        // worth waiting around for
+        let mut queue = match busiest.and_then(|hart| self.queues[hart].try_lock()) {
+            Some(queue) if queue.queue.len() > 1 => queue,
+            _ => return false,
+        };
+
+        let now = csr::time::read();
+        let Queue { active, queue: tasks, .. } = &mut *queue;
+        let index = tasks.iter().rposition(|t| {
+            !t.is_cache_hot(now) && !active.as_ref().map_or(false, |active| Arc::ptr_eq(active, &t.task))
+        });
+
+        let task = match index.and_then(|index| tasks.remove(index)) {
+            Some(task) => task,
+            None => return false,
+        };
+
+        drop(queue);
+
+        log::debug!("Pulled task {} onto hart {}", task.tid.value(), current_hart);
+        self.current_queue().lock().queue.push_back(task);
+
+        true
+    }
 }
 
 impl Scheduler for RoundRobinScheduler {
     fn schedule(&self) -> ! {
         log::debug!("Starting scheduling");
+        self.process_migrations();
+
         let mut queue_lock = self.current_queue().lock();
-        let Queue { ref mut active, ref mut queue } = &mut *queue_lock;
+        let Queue { ref mut active, ref mut queue, ref mut next, .. } = &mut *queue_lock;
         let queue_len = queue.len();
 
-        if queue_len > 1 {
-            queue.rotate_left(1);
+        match next.take().and_then(|tid| queue.iter().position(|t| t.tid == tid)) {
+            Some(index) => {
+                let task = queue.remove(index).unwrap();
+                queue.push_front(task);
+            }
+            None if queue_len > 1 => queue.rotate_left(1),
+            None => {}
         }
 
         let to_run = loop {
@@ -91,48 +233,26 @@ impl Scheduler for RoundRobinScheduler {
             Some(queued_task) => {
                 *active = Some(Arc::clone(&queued_task.task));
                 let task = Arc::clone(&queued_task.task);
-                let mut task = task.lock();
+                let task = task.lock();
                 let token = queued_task.token.take();
+                queued_task.last_hart = Some(crate::HART_ID.get());
+                queued_task.last_ran = csr::time::read();
 
                 // Drop queue lock here in case the wake needs the scheduler for some reason?
                 drop(queue_lock);
 
-                let root_page_table = task.memory_manager.table_phys_address();
-                let tid = task.tid;
-
-                // FIXME: We need to switch page tables before doing work on the
-                // wake token, but this feels kinda shitty, maybe find a way to
-                // do waking that doesn't need it?
-                csr::satp::write(Satp { mode: SATP_MODE, asid: tid.value() as u16, root_page_table });
-                mem::sfence(None, None);
-
-                if let Some(token) = token {
-                    (token.work)(&mut task);
-                }
-
-                let context = task.context.clone();
-
-                log::debug!("Scheduling {:?}, pc: {:#p}", task.name, task.context.pc as *mut u8);
-                sbi::timer::set_timer(
-                    csr::time::read() + ticks_per_us(10_000, crate::TIMER_FREQ.load(Ordering::Relaxed)),
-                )
-                .unwrap();
-
-                // !! RELEASE LOCKS BEFORE CONTEXT SWITCHING !!
-                drop(task);
-
-                unsafe { super::return_to_usermode(&context) }
+                super::switch_to(task, token)
             }
             None => {
                 *active = None;
                 // !! RELEASE LOCK BEFORE CONTEXT SWITCHING !!
                 drop(queue_lock);
 
-                log::debug!("No work to do, sleeping :(");
-
-                mem::sfence(None, None);
+                if self.pull() {
+                    return self.schedule();
+                }
 
-                super::sleep()
+                super::go_idle()
             }
         }
     }
@@ -141,8 +261,9 @@ impl Scheduler for RoundRobinScheduler {
         let (tid, task) = TASKS.insert(task);
 
         log::debug!("Trying to enqueue task");
-        let selected = self.queues.iter().min_by_key(|queue| queue.lock().queue.len()).unwrap_or(&self.queues[0]);
-        selected.lock().queue.push_back(QueuedTask { tid, task, token: None });
+        let task = QueuedTask { tid, task, token: None, wait: None, last_hart: None, last_ran: 0 };
+        let selected = self.select_hart(&task);
+        self.push_to(selected, task);
         log::debug!("Enqueued task");
 
         tid
@@ -153,31 +274,142 @@ impl Scheduler for RoundRobinScheduler {
         if let Some(index) = queue.queue.iter().position(|t| t.tid == tid) {
             queue.queue.remove(index);
         }
+        drop(queue);
+
+        let mut blocked = self.blocked.lock();
+        if let Some(index) = blocked.iter().position(|t| t.tid == tid) {
+            blocked.remove(index);
+        }
     }
 
     #[track_caller]
-    fn block(&self, tid: Tid) {
+    fn block(&self, wait: WaitId) {
         let mut queue = self.current_queue().lock();
-        let index = queue.queue.iter().position(|t| t.tid == tid).expect("blocking task not on current hart");
-        let task = queue.queue.remove(index).unwrap();
+        let index = queue.queue.iter().position(|t| t.tid == wait.tid()).expect("blocking task not on current hart");
+        let mut task = queue.queue.remove(index).unwrap();
+        task.wait = Some(wait);
         self.blocked.lock().push_back(task);
     }
 
     #[track_caller]
-    fn unblock(&self, token: WakeToken) {
+    fn unblock(&self, token: WakeToken) -> bool {
         let mut blocked = self.blocked.lock();
-        let index = blocked.iter().position(|t| t.tid == token.tid).expect("trying to wake a non-blocked task");
+        let index = match blocked.iter().position(|t| t.wait == Some(token.wait)) {
+            Some(index) => index,
+            None => return false,
+        };
         let mut task = blocked.remove(index).unwrap();
         drop(blocked);
 
+        task.wait = None;
         task.token = Some(token);
 
-        let selected = self.queues.iter().min_by_key(|queue| queue.lock().queue.len()).unwrap_or(&self.queues[0]);
-        selected.lock().queue.push_back(task);
+        let selected = self.select_hart(&task);
+        self.push_to(selected, task);
+
+        true
+    }
+
+    fn hand_off(&self, tid: Tid) {
+        let current_hart = crate::HART_ID.get();
+
+        // The task may have just been woken onto another hart, in which case
+        // pull it over as long as it hasn't started running there. Remote
+        // queues are only `try_lock`ed since the caller may be holding task
+        // locks.
+        for (hart, queue) in self.queues.iter().enumerate().filter(|(hart, _)| *hart != current_hart) {
+            let mut queue = match queue.try_lock() {
+                Some(queue) => queue,
+                None => continue,
+            };
+
+            let Queue { active, queue: tasks, .. } = &mut *queue;
+            let index = tasks
+                .iter()
+                .position(|t| t.tid == tid && !active.as_ref().map_or(false, |active| Arc::ptr_eq(active, &t.task)));
+
+            if let Some(task) = index.and_then(|index| tasks.remove(index)) {
+                drop(queue);
+                log::debug!("Pulled task {} from hart {} for handoff", tid.value(), hart);
+                self.current_queue().lock().queue.push_back(task);
+                break;
+            }
+        }
+
+        self.current_queue().lock().next = Some(tid);
+    }
+
+    fn migrate(&self, tid: Tid, hart: usize) -> bool {
+        if hart >= self.queues.len() {
+            return false;
+        }
+
+        let current_hart = crate::HART_ID.get();
+        for (source, queue) in self.queues.iter().enumerate() {
+            let mut queue = queue.lock();
+            if !queue.queue.iter().any(|t| t.tid == tid) {
+                continue;
+            }
+
+            if source == hart {
+                return true;
+            }
+
+            // The task may be running on the source hart right now, so let the
+            // source hart push it once it has been switched out
+            queue.migrations.push((tid, hart));
+            drop(queue);
+
+            match source == current_hart {
+                true => super::request_reschedule(),
+                false => {
+                    if let Err(e) = sbi::ipi::send_ipi(sbi::HartMask::new(0).with(source)) {
+                        log::error!("Failed to send migration IPI to hart {}: {:?}", source, e);
+                    }
+                }
+            }
+
+            return true;
+        }
+
+        // Blocked tasks aren't on any hart, so make the target hart look like
+        // the one it last ran on so it gets woken there
+        match self.blocked.lock().iter_mut().find(|t| t.tid == tid) {
+            Some(task) => {
+                task.last_hart = Some(hart);
+                task.last_ran = csr::time::read();
+                true
+            }
+            None => false,
+        }
     }
 
     #[track_caller]
     fn active_on_cpu(&self) -> Option<Arc<SpinMutex<Task>>> {
         self.current_queue().lock().active.clone()
     }
+
+    /// Reschedule to process migrations, either to push tasks to other harts or
+    /// to pick up tasks pushed to this one while it was idle
+    fn reschedule_pending(&self) -> bool {
+        let queue = self.current_queue().lock();
+        !queue.migrations.is_empty() || (queue.active.is_none() && !queue.queue.is_empty())
+    }
+
+    /// Idle harts only pull from harts with more than one task queued, see
+    /// [`RoundRobinScheduler::pull`]
+    fn tasks_to_pull(&self) -> bool {
+        let current_hart = crate::HART_ID.get();
+        self.queues
+            .iter()
+            .enumerate()
+            .filter(|(hart, _)| *hart != current_hart)
+            .filter_map(|(_, queue)| queue.try_lock())
+            .any(|queue| queue.queue.len() > 1)
+    }
+
+    /// Queues which are currently locked are skipped
+    fn runnable_tasks(&self) -> usize {
+        self.queues.iter().filter_map(|queue| queue.try_lock()).map(|queue| queue.queue.len()).sum()
+    }
 }
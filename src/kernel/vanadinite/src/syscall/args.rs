@@ -0,0 +1,145 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Typed access to the raw arguments of a syscall, so that the dispatch in
+//! [`super::dispatch`] is one line per syscall with the argument types coming
+//! from the handler's signature instead of being converted by hand. User slices
+//! are validated against the calling task's address space here, before the
+//! handler runs, so handlers only ever see memory they're allowed to copy
+//! through.
+
+use crate::{
+    mem::{
+        paging::{flags, VirtualAddress},
+        user::{RawUserSlice, UserPtrMode, ValidatedUserSlice},
+    },
+    task::ThreadGroup,
+};
+use alloc::sync::Arc;
+use librust::{
+    capabilities::{CapabilityPtr, CapabilityRights},
+    error::{AccessError, KError},
+    message::CALL_PAYLOAD_LEN,
+    syscalls::{
+        allocation::{AllocationOptions, DmaAllocationOptions, MemoryPermissions},
+        channel::MessageId,
+        io::InterruptRateLimit,
+        vmspace::VmspaceObjectId,
+    },
+};
+
+/// A value which can be passed in a single argument register
+pub trait FromSyscallArg {
+    fn from_arg(raw: usize) -> Self;
+}
+
+macro_rules! from_new {
+    ($($t:ty),+) => {
+        $(
+            impl FromSyscallArg for $t {
+                fn from_arg(raw: usize) -> Self {
+                    <$t>::new(raw)
+                }
+            }
+        )+
+    };
+}
+
+from_new!(
+    VirtualAddress,
+    CapabilityPtr,
+    CapabilityRights,
+    MessageId,
+    VmspaceObjectId,
+    AllocationOptions,
+    DmaAllocationOptions,
+    MemoryPermissions
+);
+
+impl FromSyscallArg for u32 {
+    fn from_arg(raw: usize) -> Self {
+        raw as u32
+    }
+}
+
+impl FromSyscallArg for u64 {
+    fn from_arg(raw: usize) -> Self {
+        raw as u64
+    }
+}
+
+/// `usize::MAX` stands in for no capability
+impl FromSyscallArg for Option<CapabilityPtr> {
+    fn from_arg(raw: usize) -> Self {
+        match raw {
+            usize::MAX => None,
+            cptr => Some(CapabilityPtr::new(cptr)),
+        }
+    }
+}
+
+pub struct SyscallArgs {
+    raw: [usize; 12],
+    /// The calling task's thread group, whose address space user slices are
+    /// validated against
+    group: Arc<ThreadGroup>,
+}
+
+impl SyscallArgs {
+    pub fn new(raw: [usize; 12], group: Arc<ThreadGroup>) -> Self {
+        Self { raw, group }
+    }
+
+    /// The argument at `index` converted to whatever the handler takes
+    pub fn get<T: FromSyscallArg>(&self, index: usize) -> T {
+        T::from_arg(self.raw[index])
+    }
+
+    /// A user slice given as a pointer at `index` followed by its length,
+    /// validated against the calling task's address space
+    pub fn user_slice<Mode: UserPtrMode, T>(&self, index: usize) -> Result<ValidatedUserSlice<Mode, T>, KError> {
+        // Safety: the thread group is the calling task's, so the slice is
+        // validated against the address space it'll be copied through
+        match unsafe { self.raw_slice::<Mode, T>(index).validate(&mut self.group.memory_manager.lock()) } {
+            Ok(slice) => Ok(slice),
+            Err((addr, e)) => {
+                log::debug!("Bad memory from process: {:?}", e);
+                match Mode::FLAGS & flags::WRITE {
+                    true => Err(KError::InvalidAccess(AccessError::Write(addr.as_mut_ptr()))),
+                    false => Err(KError::InvalidAccess(AccessError::Read(addr.as_ptr()))),
+                }
+            }
+        }
+    }
+
+    /// A user slice given as a pointer at `index` followed by its length, for
+    /// the few handlers which have to validate it again later anyway
+    pub fn raw_slice<Mode: UserPtrMode, T>(&self, index: usize) -> RawUserSlice<Mode, T> {
+        RawUserSlice::new(VirtualAddress::new(self.raw[index]), self.raw[index + 1])
+    }
+
+    /// An interrupt rate limit given as its burst, window and mask time
+    /// starting at `index`
+    pub fn rate_limit(&self, index: usize) -> InterruptRateLimit {
+        InterruptRateLimit { max_burst: self.get(index), window_us: self.get(index + 1), mask_us: self.get(index + 2) }
+    }
+
+    /// The payload of a `Call` or `Reply`, which follows the capability
+    pub fn call_payload(&self) -> [usize; CALL_PAYLOAD_LEN] {
+        let mut payload = [0; CALL_PAYLOAD_LEN];
+        payload.copy_from_slice(&self.raw[1..][..CALL_PAYLOAD_LEN]);
+        payload
+    }
+}
+
+impl core::ops::Index<usize> for SyscallArgs {
+    type Output = usize;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.raw[index]
+    }
+}
@@ -5,88 +5,243 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use super::SyscallOutcome;
+use super::{
+    poll::{WaitQueue, Waitable, Waiter},
+    SyscallOutcome,
+};
 use crate::{
     capabilities::{Capability, CapabilityResource},
-    interrupts::PLIC,
+    csr,
     mem::{
         manager::{AddressRegionKind, FillOption, RegionDescription},
         paging::{flags, PageSize, VirtualAddress},
-        region::{MemoryRegion, PhysicalRegion},
-        user::{self, RawUserSlice},
+        quota::Charge,
+        region::{MemoryRegion, PhysicalRegion, SharedPhysicalRegion},
+        user::{self, ValidatedUserSlice},
     },
-    scheduler::{Scheduler, WakeToken, SCHEDULER, TASKS},
+    metrics::ChannelStats,
+    scheduler::{Scheduler, WaitId, WakeToken, SCHEDULER, TASKS},
     task::Task,
-    utils::{self, Units},
-    HART_ID,
-};
-use alloc::{
-    collections::{BTreeMap, VecDeque},
-    sync::Arc,
-    vec::Vec,
+    utils::{self, SameHartDeadlockDetection, Units},
 };
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
 use core::{
     ops::Range,
     sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 use librust::{
-    capabilities::{CapabilityPtr, CapabilityRights},
+    capabilities::{CapabilityPtr, CapabilityRights, ReplyCapability},
     error::KError,
-    message::{KernelNotification, Message},
-    syscalls::channel::{ChannelId, MessageId},
+    message::{KernelNotification, CALL_PAYLOAD_LEN},
+    syscalls::{
+        channel::{ChannelId, MessageId, MAX_QUEUE_DEPTH},
+        poll::WaitEvents,
+        trace::TraceEvent,
+    },
+    task::Tid,
 };
-use sync::{SpinMutex, SpinRwLock};
+use sync::{SpinMutex, SpinMutexGuard, SpinRwLock};
+
+type TaskLock = SpinMutex<Task, SameHartDeadlockDetection>;
+type TaskGuard<'a> = SpinMutexGuard<'a, Task, SameHartDeadlockDetection>;
 
 pub const MAX_CHANNEL_BYTES: usize = 4096;
+/// Number of messages which can be waiting to be read from each end of a
+/// channel, though each end can lower its own limit with `SetChannelQueueDepth`
+pub const MAX_QUEUED_MESSAGES: usize = MAX_QUEUE_DEPTH;
+/// Largest ring buffer which can be shared between the ends of a channel
+pub const MAX_CHANNEL_RING_BYTES: usize = 16 * 1024 * 1024;
 
 pub struct UserspaceChannel {
     sender: Sender,
     receiver: Receiver,
     message_id_counter: Arc<AtomicUsize>,
     mapped_regions: BTreeMap<MessageId, MappedChannelMessage>,
+    ring: ChannelRing,
+    stats: Arc<ChannelStats>,
+    /// The other end's statistics, which the high-water mark of its queue is
+    /// recorded in when sending
+    peer_stats: Arc<ChannelStats>,
+    /// Given out along with a badged capability, so rather than being one end
+    /// of a channel of its own, this only sends into the queue of the end the
+    /// badge was minted from
+    client: bool,
 }
 
 impl UserspaceChannel {
     pub fn new() -> (Self, Self) {
         let message_id_counter = Arc::new(AtomicUsize::new(1));
-        let (sender1, receiver1) = {
-            let message_queue = Arc::new(SpinRwLock::new(VecDeque::new()));
-            let alive = Arc::new(AtomicBool::new(true));
-            let wake = Arc::new(SpinMutex::new(None));
-
-            let sender =
-                Sender { inner: Arc::clone(&message_queue), alive: Arc::clone(&alive), wake: Arc::clone(&wake) };
-            let receiver = Receiver { inner: message_queue, alive, wake };
-
-            (sender, receiver)
-        };
-
-        let (sender2, receiver2) = {
-            let message_queue = Arc::new(SpinRwLock::new(VecDeque::new()));
-            let alive = Arc::new(AtomicBool::new(true));
-            let wake = Arc::new(SpinMutex::new(None));
-
-            let sender =
-                Sender { inner: Arc::clone(&message_queue), alive: Arc::clone(&alive), wake: Arc::clone(&wake) };
-            let receiver = Receiver { inner: message_queue, alive, wake };
-
-            (sender, receiver)
-        };
+        let (sender1, receiver1) = queue();
+        let (sender2, receiver2) = queue();
 
+        let (ring1, ring2) = ChannelRing::new();
+        let (stats1, stats2) = (ChannelStats::new(), ChannelStats::new());
         let first = Self {
             sender: sender1,
             receiver: receiver2,
             message_id_counter: Arc::clone(&message_id_counter),
             mapped_regions: BTreeMap::new(),
+            ring: ring1,
+            stats: Arc::clone(&stats1),
+            peer_stats: Arc::clone(&stats2),
+            client: false,
+        };
+        let second = Self {
+            sender: sender2,
+            receiver: receiver1,
+            message_id_counter,
+            mapped_regions: BTreeMap::new(),
+            ring: ring2,
+            stats: stats2,
+            peer_stats: stats1,
+            client: false,
         };
-        let second = Self { sender: sender2, receiver: receiver1, message_id_counter, mapped_regions: BTreeMap::new() };
 
         (first, second)
     }
 
+    /// Another end which sends into the same queue as a badged capability for
+    /// this end does, so that messages from every holder of a badged copy
+    /// arrive at the end it was minted from without a channel per client. The
+    /// new end has nothing to receive, replies are sent with `Call`/`Reply`.
+    fn badged_client(&self) -> UserspaceChannel {
+        // A badged capability for an end which is itself a client is passing
+        // the badge along, so it sends to wherever this one does
+        let (sender, peer_stats) = match self.client {
+            true => (self.sender.clone(), Arc::clone(&self.peer_stats)),
+            false => (self.receiver.sender(), Arc::clone(&self.stats)),
+        };
+
+        let (ring, _) = ChannelRing::new();
+
+        UserspaceChannel {
+            sender,
+            receiver: Receiver::new(),
+            message_id_counter: Arc::clone(&self.message_id_counter),
+            mapped_regions: BTreeMap::new(),
+            ring,
+            stats: ChannelStats::new(),
+            peer_stats,
+            client: true,
+        }
+    }
+
     fn next_message_id(&self) -> usize {
         self.message_id_counter.fetch_add(1, Ordering::AcqRel)
     }
+
+    /// Whether `other` is the opposite end of this channel
+    pub fn is_peer_of(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.sender.inner, &other.receiver.inner)
+    }
+
+    /// Number of messages waiting to be read from this end of the channel
+    pub fn pending_messages(&self) -> usize {
+        self.receiver.inner.read().len()
+    }
+
+    /// Forget any state tied to the task which currently owns this end of the
+    /// channel so that it can be moved to a different task. Messages which
+    /// were mapped into the previous owner's address space are lost, but any
+    /// queued messages remain. The ring buffer, if any, stays alive and can be
+    /// mapped again by the new owner.
+    pub fn detach(&mut self) {
+        self.mapped_regions.clear();
+        self.receiver.wake.lock().take();
+        self.ring.mapping = None;
+        self.ring.incoming.wake.lock().take();
+    }
+}
+
+impl Waitable for UserspaceChannel {
+    fn poll(&self, interest: WaitEvents) -> WaitEvents {
+        let mut ready = WaitEvents::NONE;
+        if interest & WaitEvents::READABLE && self.pending_messages() > 0 {
+            ready |= WaitEvents::READABLE;
+        }
+
+        if interest & WaitEvents::NOTIFIED && self.ring.incoming.rung.load(Ordering::Acquire) {
+            ready |= WaitEvents::NOTIFIED;
+        }
+
+        let open = self.sender.alive.load(Ordering::Acquire);
+        if interest & WaitEvents::WRITABLE && open && !self.sender.inner.read().is_full() {
+            ready |= WaitEvents::WRITABLE;
+        }
+
+        // Queued messages can still be read after the other end is gone. Badged
+        // clients never receive anything, so they're closed once the end they
+        // send to is.
+        if !self.receiver.alive.load(Ordering::Acquire) || (self.client && !open) {
+            ready |= WaitEvents::CLOSED;
+        }
+
+        ready
+    }
+
+    fn register(&self, waiter: &Arc<Waiter>) {
+        self.receiver.waiters.register(waiter);
+        self.ring.incoming.waiters.register(waiter);
+        self.sender.space.register(waiter);
+    }
+}
+
+/// A region of memory shared by both ends of a channel, which userspace can
+/// use as a pair of ring buffers (one per direction) to move bulk data
+/// without the kernel copying or remapping anything. The kernel only ever
+/// allocates and maps the memory; synchronization of the ring contents is
+/// left entirely to userspace, with the doorbells used to wake the other end.
+struct ChannelRing {
+    region: Arc<SpinMutex<Option<SharedPhysicalRegion>>>,
+    mapping: Option<Range<VirtualAddress>>,
+    /// Which half of the ring this end of the channel produces into
+    side: usize,
+    incoming: Arc<Doorbell>,
+    outgoing: Arc<Doorbell>,
+}
+
+impl ChannelRing {
+    fn new() -> (Self, Self) {
+        let region = Arc::new(SpinMutex::new(None));
+        let (a, b) = (Arc::new(Doorbell::new()), Arc::new(Doorbell::new()));
+
+        let first = Self {
+            region: Arc::clone(&region),
+            mapping: None,
+            side: 0,
+            incoming: Arc::clone(&a),
+            outgoing: Arc::clone(&b),
+        };
+        let second = Self { region, mapping: None, side: 1, incoming: b, outgoing: a };
+
+        (first, second)
+    }
+}
+
+#[derive(Debug)]
+struct Doorbell {
+    rung: AtomicBool,
+    wake: SpinMutex<Option<WakeToken>>,
+    /// Tasks polling for the notification with `WaitMany`, which leave it
+    /// latched for their next wait
+    waiters: WaitQueue,
+}
+
+impl Doorbell {
+    fn new() -> Self {
+        Self { rung: AtomicBool::new(false), wake: SpinMutex::new(None), waiters: WaitQueue::new() }
+    }
+
+    fn ring(&self) {
+        // Waking a task consumes the notification, otherwise it's latched until
+        // the next wait. The token may be left over from a wait which was cut
+        // short, in which case it doesn't wake anything.
+        let token = self.wake.lock().take();
+        if !token.map_or(false, |token| SCHEDULER.unblock(token)) {
+            self.rung.store(true, Ordering::Release);
+            self.waiters.wake_all();
+        }
+    }
 }
 
 enum MappedChannelMessage {
@@ -98,21 +253,132 @@ enum MappedChannelMessage {
 struct ChannelMessage {
     data: Option<(MessageId, PhysicalRegion, usize)>,
     caps: Vec<librust::capabilities::Capability>,
+    /// Badge of the capability the message was sent through
+    badge: usize,
+    /// Held against the sender's quota until the message has been read
+    charge: Charge,
+}
+
+/// Storage for the messages waiting to be read from one end of a channel,
+/// allocated along with the channel so that queueing messages never grows the
+/// kernel heap. The slots are used as a ring buffer.
+#[derive(Debug)]
+struct MessagePool {
+    slots: Box<[Option<ChannelMessage>]>,
+    head: usize,
+    len: usize,
+    /// How many messages the receiving end allows to be queued, senders get
+    /// `WouldBlock` once there are this many
+    depth: usize,
+}
+
+impl MessagePool {
+    fn new() -> Self {
+        let mut slots = Vec::with_capacity(MAX_QUEUED_MESSAGES);
+        slots.resize_with(MAX_QUEUED_MESSAGES, || None);
+
+        Self { slots: slots.into_boxed_slice(), head: 0, len: 0, depth: MAX_QUEUED_MESSAGES }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the queue has reached its depth. Only the sender checks this,
+    /// so a message it already decided to send still fits if the receiver
+    /// lowers the depth in the meantime.
+    fn is_full(&self) -> bool {
+        self.len >= self.depth
+    }
+
+    fn push_back(&mut self, message: ChannelMessage) -> Result<(), ChannelMessage> {
+        if self.len == self.slots.len() {
+            return Err(message);
+        }
+
+        let index = (self.head + self.len) % self.slots.len();
+        self.slots[index] = Some(message);
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// Put a partially read message back at the front of the queue
+    fn push_front(&mut self, message: ChannelMessage) -> Result<(), ChannelMessage> {
+        if self.len == self.slots.len() {
+            return Err(message);
+        }
+
+        self.head = (self.head + self.slots.len() - 1) % self.slots.len();
+        self.slots[self.head] = Some(message);
+        self.len += 1;
+
+        Ok(())
+    }
+
+    fn pop_front(&mut self) -> Option<ChannelMessage> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let message = self.slots[self.head].take();
+        self.head = (self.head + 1) % self.slots.len();
+        self.len -= 1;
+
+        message
+    }
+}
+
+/// A message queue along with the first sender into it
+fn queue() -> (Sender, Receiver) {
+    let receiver = Receiver::new();
+    (receiver.sender(), receiver)
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 struct Receiver {
     // FIXME: Replace these with something like a lockfree ring buffer
-    inner: Arc<SpinRwLock<VecDeque<ChannelMessage>>>,
+    inner: Arc<SpinRwLock<MessagePool>>,
     alive: Arc<AtomicBool>,
     wake: Arc<SpinMutex<Option<WakeToken>>>,
+    waiters: Arc<WaitQueue>,
+    /// Senders waiting for there to be room in the queue
+    space: Arc<WaitQueue>,
+    senders: Arc<AtomicUsize>,
 }
 
 impl Receiver {
+    fn new() -> Self {
+        Self {
+            inner: Arc::new(SpinRwLock::new(MessagePool::new())),
+            alive: Arc::new(AtomicBool::new(true)),
+            wake: Arc::new(SpinMutex::new(None)),
+            waiters: Arc::new(WaitQueue::new()),
+            space: Arc::new(WaitQueue::new()),
+            senders: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn sender(&self) -> Sender {
+        self.senders.fetch_add(1, Ordering::AcqRel);
+        Sender {
+            inner: Arc::clone(&self.inner),
+            alive: Arc::clone(&self.alive),
+            wake: Arc::clone(&self.wake),
+            waiters: Arc::clone(&self.waiters),
+            space: Arc::clone(&self.space),
+            senders: Arc::clone(&self.senders),
+        }
+    }
+
     fn try_receive(&self) -> Result<Option<ChannelMessage>, ()> {
         // TODO: is it worth trying to `.read()` then `.upgrade()` if not empty?
-        match self.inner.write().pop_front() {
-            Some(message) => Ok(Some(message)),
+        let message = self.inner.write().pop_front();
+        match message {
+            Some(message) => {
+                self.space.wake_all();
+                Ok(Some(message))
+            }
             None => match self.alive.load(Ordering::Acquire) {
                 true => Ok(None),
                 false => Err(()),
@@ -128,15 +394,21 @@ impl Receiver {
 impl Drop for Receiver {
     fn drop(&mut self) {
         self.alive.store(false, Ordering::Release);
+        self.space.wake_all();
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 struct Sender {
     // FIXME: Replace these with something like a lockfree ring buffer
-    inner: Arc<SpinRwLock<VecDeque<ChannelMessage>>>,
+    inner: Arc<SpinRwLock<MessagePool>>,
     alive: Arc<AtomicBool>,
     wake: Arc<SpinMutex<Option<WakeToken>>>,
+    waiters: Arc<WaitQueue>,
+    space: Arc<WaitQueue>,
+    /// Badged copies of a channel each have a sender into the same queue,
+    /// which stays open until the last of them is gone
+    senders: Arc<AtomicUsize>,
 }
 
 impl Sender {
@@ -145,20 +417,38 @@ impl Sender {
             return Err(message);
         }
 
-        // FIXME: set a buffer limit at some point
-        self.inner.write().push_back(message);
+        self.inner.write().push_back(message)?;
 
         if let Some(token) = self.wake.lock().take() {
             SCHEDULER.unblock(token);
         }
 
+        self.waiters.wake_all();
+
         Ok(())
     }
 }
 
+impl Clone for Sender {
+    fn clone(&self) -> Self {
+        self.senders.fetch_add(1, Ordering::AcqRel);
+        Self {
+            inner: Arc::clone(&self.inner),
+            alive: Arc::clone(&self.alive),
+            wake: Arc::clone(&self.wake),
+            waiters: Arc::clone(&self.waiters),
+            space: Arc::clone(&self.space),
+            senders: Arc::clone(&self.senders),
+        }
+    }
+}
+
 impl Drop for Sender {
     fn drop(&mut self) {
-        self.alive.store(false, Ordering::Release);
+        if self.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.alive.store(false, Ordering::Release);
+            self.waiters.wake_all();
+        }
     }
 }
 
@@ -166,7 +456,7 @@ impl Drop for Sender {
 // converted into `usize` so its a lot more clear what's what
 pub fn create_message(task: &mut Task, cptr: CapabilityPtr, size: usize) -> SyscallOutcome {
     let channel_id = match task.cspace.resolve(cptr) {
-        Some(Capability { resource: CapabilityResource::Channel(channel), rights })
+        Some(Capability { resource: CapabilityResource::Channel(channel), rights, .. })
             if *rights & CapabilityRights::WRITE =>
         {
             channel
@@ -181,7 +471,7 @@ pub fn create_message(task: &mut Task, cptr: CapabilityPtr, size: usize) -> Sysc
     let size = n_pages * 4.kib();
 
     // FIXME: does this actually need to be shared? I don't think so
-    let (region, _) = task.memory_manager.alloc_shared_region(
+    let (region, _) = task.group.memory_manager.lock().alloc_shared_region(
         None,
         RegionDescription {
             size: PageSize::Kilopage,
@@ -203,268 +493,662 @@ pub fn send_message(
     cptr: CapabilityPtr,
     message_id: MessageId,
     len: usize,
-    caps: RawUserSlice<user::Read, librust::capabilities::Capability>,
+    caps: ValidatedUserSlice<user::Read, librust::capabilities::Capability>,
 ) -> SyscallOutcome {
-    let current_tid = task.tid;
-    let channel_id = match task.cspace.resolve(cptr) {
-        Some(Capability { resource: CapabilityResource::Channel(channel), rights })
+    let (channel_id, badge) = match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Channel(channel), rights, badge })
             if *rights & CapabilityRights::WRITE =>
         {
-            *channel
+            (*channel, *badge)
         }
         _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
     };
 
-    // Fixup caps here so we can error on any invalid caps/slice and not dealloc
-    // the message region
-    let caps = match caps.len() {
-        0 => Vec::new(),
-        _ => {
-            let cap_slice = match unsafe { caps.validate(&task.memory_manager) } {
-                Ok(cap_slice) => cap_slice,
-                Err(_) => return SyscallOutcome::Err(KError::InvalidArgument(3)),
-            };
+    let (receiving_tid, channel) = task.channels.get(&channel_id).unwrap();
+    let receiving_tid = *receiving_tid;
+    let message_size = match channel.mapped_regions.get(&message_id) {
+        Some(MappedChannelMessage::Synthesized(range)) => range.end.as_usize() - range.start.as_usize(),
+        // For now we don't allow sending back received messages, but maybe that
+        // should be allowed even if its not useful?
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(1)),
+    };
 
-            let cap_slice = cap_slice.guarded();
-            let transferred_caps: Result<Vec<librust::capabilities::Capability>, KError> = cap_slice
-                .iter()
-                .copied()
-                .map(|cap| {
-                    Ok(librust::capabilities::Capability {
-                        cptr: transfer_capability(task, cptr, cap.cptr, cap.rights)?,
-                        rights: cap.rights,
-                    })
-                })
-                .collect();
+    if message_size < len {
+        return SyscallOutcome::Err(KError::InvalidArgument(2));
+    }
 
-            match transferred_caps {
-                Ok(caps) => caps,
-                Err(e) => return SyscallOutcome::Err(e),
-            }
+    // Nothing but the sender touches the other end's queue while this task is
+    // locked, so it can't fill up before the message is sent
+    if channel.sender.inner.read().is_full() {
+        channel.stats.record_queue_full();
+        return SyscallOutcome::Err(KError::WouldBlock);
+    }
+
+    let caps = match caps.to_vec() {
+        Ok(caps) => caps,
+        Err(_) => return SyscallOutcome::Err(KError::InvalidArgument(3)),
+    };
+
+    // Every task the message touches is locked before anything changes, since
+    // the syscall starts over if one of them can't be
+    let receiving_task = match TASKS.get(receiving_tid) {
+        Some(receiving_task) => receiving_task,
+        None => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+    let mut receiving_task = match super::lock_other(task, receiving_tid, &receiving_task) {
+        Some(receiving_task) => receiving_task,
+        None => return SyscallOutcome::Retry,
+    };
+
+    let peers = match channel_peers(task, receiving_tid, &caps) {
+        Ok(peers) => peers,
+        Err(e) => return SyscallOutcome::Err(e),
+    };
+    let mut peers = match lock_peers(task.tid.max(receiving_tid), &peers) {
+        Some(peers) => peers,
+        None => return SyscallOutcome::Retry,
+    };
+
+    // The message's pages and capabilities stay with the kernel until the
+    // other end reads them
+    let charge_size = message_size + caps.len() * core::mem::size_of::<librust::capabilities::Capability>();
+    let charge = match task.group.quota.charge(charge_size) {
+        Some(charge) => charge,
+        None => {
+            log::debug!("Task {} is over its channel quota", task.name);
+            return SyscallOutcome::Err(KError::OutOfMemory);
         }
     };
 
-    let (other_tid, channel) = task.channels.get_mut(&channel_id).unwrap();
+    // Fixup caps here so we can error on any invalid caps/slice and not dealloc
+    // the message region
+    let transferred_caps: Result<Vec<librust::capabilities::Capability>, KError> = caps
+        .iter()
+        .map(|cap| {
+            Ok(librust::capabilities::Capability {
+                cptr: transfer_capability(task, &mut receiving_task, &mut peers, cptr, cap.cptr, cap.rights)?,
+                rights: cap.rights,
+            })
+        })
+        .collect();
+
+    let caps = match transferred_caps {
+        Ok(caps) => caps,
+        Err(e) => return SyscallOutcome::Err(e),
+    };
+
+    let (_, channel) = task.channels.get_mut(&channel_id).unwrap();
 
     let range = match channel.mapped_regions.remove(&message_id) {
         Some(MappedChannelMessage::Synthesized(range)) => range,
-        // For now we don't allow sending back received messages, but maybe that
-        // should be allowed even if its not useful?
-        _ => return SyscallOutcome::Err(KError::InvalidArgument(1)),
+        _ => unreachable!(),
     };
 
-    if range.end.as_usize() - range.start.as_usize() < len {
-        return SyscallOutcome::Err(KError::InvalidArgument(2));
-    }
-
-    let backing = match task.memory_manager.dealloc_region(range.start) {
+    let backing = match task.group.memory_manager.lock().dealloc_region(range.start) {
         MemoryRegion::Backed(phys_region) => phys_region,
         _ => unreachable!(),
     };
 
-    let other_task = TASKS.get(*other_tid).unwrap();
-    let mut other_task = other_task.lock();
+    deliver(
+        task,
+        &mut receiving_task,
+        channel_id,
+        ChannelMessage { data: Some((message_id, backing, len)), caps, badge, charge },
+    );
 
-    // FIXME: once buffer limits exist, will need to either block or return an
-    // error and also check for broken channels
-    channel.sender.try_send(ChannelMessage { data: Some((message_id, backing, len)), caps }).unwrap();
+    SyscallOutcome::Processed(librust::message::Message::default())
+}
 
-    let other_cptr = *other_task.cspace.all().find(|(_, cap)| matches!(cap, Capability { resource: CapabilityResource::Channel(cid), .. } if other_task.channels.get(cid).unwrap().0 == current_tid)).unwrap().0;
-    other_task
-        .message_queue
-        .push(librust::message::Sender::kernel(), KernelNotification::NewChannelMessage(other_cptr).into());
+/// Share a DMA region with the task on the other end of the channel, which
+/// receives it as a message containing only the region's capability
+pub fn share_dma_region(task: &mut Task, cptr: CapabilityPtr, region_cptr: CapabilityPtr) -> SyscallOutcome {
+    let (channel_id, badge) = match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Channel(channel), rights, badge })
+            if *rights & CapabilityRights::WRITE =>
+        {
+            (*channel, *badge)
+        }
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
 
-    SyscallOutcome::Processed(librust::message::Message::default())
+    let owned = matches!(
+        task.cspace.resolve(region_cptr),
+        Some(Capability { resource: CapabilityResource::Dma(..), rights, .. }) if *rights & CapabilityRights::GRANT
+    );
+
+    if !owned {
+        return SyscallOutcome::Err(KError::InvalidArgument(1));
+    }
+
+    let (receiving_tid, channel) = task.channels.get(&channel_id).unwrap();
+    let receiving_tid = *receiving_tid;
+    if channel.sender.inner.read().is_full() {
+        channel.stats.record_queue_full();
+        return SyscallOutcome::Err(KError::WouldBlock);
+    }
+
+    let receiving_task = match TASKS.get(receiving_tid) {
+        Some(receiving_task) => receiving_task,
+        None => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+    let mut receiving_task = match super::lock_other(task, receiving_tid, &receiving_task) {
+        Some(receiving_task) => receiving_task,
+        None => return SyscallOutcome::Retry,
+    };
+
+    let charge = match task.group.quota.charge(core::mem::size_of::<librust::capabilities::Capability>()) {
+        Some(charge) => charge,
+        None => return SyscallOutcome::Err(KError::OutOfMemory),
+    };
+
+    // DMA regions never need any other task locked to be transferred
+    let rights = CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::MAP;
+    let cap = match transfer_capability(task, &mut receiving_task, &mut [], cptr, region_cptr, rights) {
+        Ok(cptr) => librust::capabilities::Capability { cptr, rights },
+        Err(e) => return SyscallOutcome::Err(e),
+    };
+
+    deliver(
+        task,
+        &mut receiving_task,
+        channel_id,
+        ChannelMessage { data: None, caps: alloc::vec![cap], badge, charge },
+    );
+
+    SyscallOutcome::processed(())
+}
+
+/// Queue a message for `other_task` on the other end of the channel and let it
+/// know there's something to read
+fn deliver(task: &mut Task, other_task: &mut Task, channel_id: ChannelId, message: ChannelMessage) {
+    let current_tid = task.tid;
+    let (other_tid, channel) = task.channels.get_mut(&channel_id).unwrap();
+
+    let len = message.data.as_ref().map_or(0, |(_, _, len)| *len);
+    crate::trace::record(TraceEvent::IpcSend, current_tid.value(), [other_tid.value(), len]);
+
+    // FIXME: check for broken channels
+    channel.sender.try_send(message).unwrap();
+    channel.stats.record_send(current_tid, *other_tid, len);
+    channel.peer_stats.record_queue_len(*other_tid, current_tid, channel.sender.inner.read().len());
+
+    // The other end of the channel may be in the middle of being handed off to
+    // a new task, in which case the notification is sent once the handoff
+    // completes
+    let other_cptr = peer_cptr(other_task, channel);
+    if let Some(other_cptr) = other_cptr {
+        other_task
+            .message_queue
+            .push(librust::message::Sender::kernel(), KernelNotification::NewChannelMessage(other_cptr).into());
+    }
+}
+
+/// The capability `other_task` holds for the end of the channel which
+/// `channel` sends to
+fn peer_cptr(other_task: &Task, channel: &UserspaceChannel) -> Option<CapabilityPtr> {
+    other_task.cspace.all().find_map(|(cptr, cap)| match cap.resource {
+        CapabilityResource::Channel(cid) if channel.is_peer_of(&other_task.channels.get(&cid).unwrap().1) => {
+            Some(*cptr)
+        }
+        _ => None,
+    })
 }
 
 pub fn read_message(
     task: &mut Task,
     cptr: CapabilityPtr,
-    cap_buffer: RawUserSlice<user::ReadWrite, librust::capabilities::Capability>,
+    cap_buffer: ValidatedUserSlice<user::ReadWrite, librust::capabilities::Capability>,
+) -> SyscallOutcome {
+    receive(task, cptr, cap_buffer, |wait_id, channel, cap_buffer| {
+        log::debug!("Registering wake for channel::read_message");
+        let (stats, blocked_at) = (Arc::clone(&channel.stats), csr::time::read());
+        channel.receiver.register_wake(WakeToken::new(wait_id, move |task| {
+            log::debug!("Waking task {:?} (TID: {:?}) for channel::read_message!", task.name, task.tid.value());
+            stats.record_blocked(blocked_at);
+            let res = read_message(task, cptr, cap_buffer);
+            match res {
+                SyscallOutcome::Processed(message) => {
+                    super::apply_message(false, librust::message::Sender::kernel(), message, &mut task.context.gp_regs)
+                }
+                _ => todo!("is this even possible?"),
+            }
+        }));
+
+        SyscallOutcome::Block
+    })
+}
+
+pub fn read_message_nb(
+    task: &mut Task,
+    cptr: CapabilityPtr,
+    cap_buffer: ValidatedUserSlice<user::ReadWrite, librust::capabilities::Capability>,
+) -> SyscallOutcome {
+    receive(task, cptr, cap_buffer, |_, _, _| SyscallOutcome::processed((0, 0, 0, 0, 0, 0)))
+}
+
+/// Take the next message queued on the channel `cptr`, mapping its data into
+/// the current task and copying as many of its capabilities as fit into
+/// `cap_buffer`. If there's nothing queued, `on_empty` decides the outcome
+/// instead, and runs with the queue still locked so that no message can arrive
+/// in between.
+fn receive(
+    task: &mut Task,
+    cptr: CapabilityPtr,
+    mut cap_buffer: ValidatedUserSlice<user::ReadWrite, librust::capabilities::Capability>,
+    on_empty: impl FnOnce(
+        WaitId,
+        &UserspaceChannel,
+        ValidatedUserSlice<user::ReadWrite, librust::capabilities::Capability>,
+    ) -> SyscallOutcome,
 ) -> SyscallOutcome {
     let channel_id = match task.cspace.resolve(cptr) {
-        Some(Capability { resource: CapabilityResource::Channel(channel), rights })
+        Some(Capability { resource: CapabilityResource::Channel(channel), rights, .. })
             if *rights & CapabilityRights::READ =>
         {
             channel
         }
         _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
     };
-    let (_, channel) = task.channels.get_mut(channel_id).unwrap();
+    let wait_id = task.wait_id();
+    let (other_tid, channel) = task.channels.get_mut(channel_id).unwrap();
 
     // TODO: need to be able to return more than just the first one
 
-    // FIXME: this probably needs the lock to make sure a message wasn't sent
-    // after the check but before the register
-
     // FIXME: check for broken channel
 
     let mut receiver = channel.receiver.inner.write();
-    match receiver.pop_front() {
-        None => {
-            log::debug!("Registering wake for channel::read_message");
-            channel.receiver.register_wake(WakeToken::new(task.tid, move |task| {
-                log::debug!("Waking task {:?} (TID: {:?}) for channel::read_message!", task.name, task.tid.value());
-                let res = read_message(task, cptr, cap_buffer);
-                match res {
-                    SyscallOutcome::Processed(message) => super::apply_message(
-                        false,
-                        librust::message::Sender::kernel(),
-                        message,
-                        &mut task.context.gp_regs,
-                    ),
-                    _ => todo!("is this even possible?"),
-                }
-            }));
-
-            SyscallOutcome::Block
-        }
-        Some(ChannelMessage { data, mut caps }) => {
-            let mut message_id = MessageId::new(0);
-            let mut region = VirtualAddress::new(0)..VirtualAddress::new(0);
-            let mut len = 0;
-
-            if let Some((mid, mregion, mlen)) = data {
-                message_id = mid;
-                len = mlen;
-
-                let mregion = match mregion {
-                    PhysicalRegion::Shared(region) => region,
-                    _ => unreachable!(),
-                };
-
-                // FIXME: make it so we can use any kind of physical region
-                region = task.memory_manager.apply_shared_region(
-                    None,
-                    flags::READ | flags::WRITE | flags::USER | flags::VALID,
-                    mregion,
-                    AddressRegionKind::Channel,
-                );
-            }
+    let ChannelMessage { data, mut caps, badge, charge } = match receiver.pop_front() {
+        Some(message) => message,
+        None => return on_empty(wait_id, channel, cap_buffer),
+    };
 
-            let (caps_written, caps_remaining) = match cap_buffer.len() {
-                0 => (0, caps.len()),
-                len => {
-                    let cap_slice = match unsafe { cap_buffer.validate(&task.memory_manager) } {
-                        Ok(cap_slice) => cap_slice,
-                        Err(_) => return SyscallOutcome::Err(KError::InvalidArgument(3)),
-                    };
-
-                    let n_caps_to_write = len.min(caps.len());
-                    let mut cap_slice = cap_slice.guarded();
-                    for (target, cap) in cap_slice.iter_mut().zip(caps.drain(..n_caps_to_write)) {
-                        *target = cap;
-                    }
+    let mut message_id = MessageId::new(0);
+    let mut region = VirtualAddress::new(0)..VirtualAddress::new(0);
+    let mut len = 0;
 
-                    (n_caps_to_write, caps.len())
-                }
-            };
+    if let Some((mid, mregion, mlen)) = data {
+        message_id = mid;
+        len = mlen;
 
-            if caps_remaining != 0 {
-                receiver.push_front(ChannelMessage { data: None, caps });
-            }
+        let mregion = match mregion {
+            PhysicalRegion::Shared(region) => region,
+            _ => unreachable!(),
+        };
+
+        // FIXME: make it so we can use any kind of physical region
+        region = task.group.memory_manager.lock().apply_shared_region(
+            None,
+            flags::READ | flags::WRITE | flags::USER | flags::VALID,
+            mregion,
+            AddressRegionKind::Channel,
+        );
+    }
 
-            SyscallOutcome::processed((message_id.value(), region.start.as_usize(), len, caps_written, caps_remaining))
+    let caps_written = match cap_buffer.copy_to_user(0, &caps) {
+        Ok(caps_written) => caps_written,
+        Err(_) => return SyscallOutcome::Err(KError::InvalidArgument(3)),
+    };
+    caps.drain(..caps_written);
+    let caps_remaining = caps.len();
+
+    crate::trace::record(TraceEvent::IpcRecv, task.tid.value(), [other_tid.value(), len]);
+    channel.stats.record_receive(task.tid, *other_tid, len);
+
+    if caps_remaining != 0 {
+        // The slot the message came out of is still free since the queue
+        // hasn't been unlocked
+        receiver.push_front(ChannelMessage { data: None, caps, badge, charge }).unwrap();
+    } else {
+        drop(receiver);
+        channel.receiver.space.wake_all();
+    }
+
+    SyscallOutcome::processed((message_id.value(), region.start.as_usize(), len, caps_written, caps_remaining, badge))
+}
+
+pub fn retire_message(task: &mut Task, cptr: CapabilityPtr, message_id: MessageId) -> SyscallOutcome {
+    let channel_id = match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Channel(channel), rights, .. })
+            if *rights & CapabilityRights::WRITE =>
+        {
+            channel
+        }
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+    let (_, channel) = task.channels.get_mut(channel_id).unwrap();
+
+    match channel.mapped_regions.remove(&message_id) {
+        Some(MappedChannelMessage::Received { region, .. }) => {
+            task.group.memory_manager.lock().dealloc_region(region.start);
+            SyscallOutcome::Processed(librust::message::Message::default())
         }
+        _ => SyscallOutcome::Err(KError::InvalidArgument(1)),
     }
 }
 
-pub fn read_message_nb(
-    task: &mut Task,
-    cptr: CapabilityPtr,
-    cap_buffer: RawUserSlice<user::ReadWrite, librust::capabilities::Capability>,
-) -> SyscallOutcome {
+/// Limit how many messages can be queued for the current task to read from the
+/// channel `cptr`
+pub fn set_queue_depth(task: &mut Task, cptr: CapabilityPtr, depth: usize) -> SyscallOutcome {
     let channel_id = match task.cspace.resolve(cptr) {
-        Some(Capability { resource: CapabilityResource::Channel(channel), rights })
+        Some(Capability { resource: CapabilityResource::Channel(channel), rights, .. })
             if *rights & CapabilityRights::READ =>
         {
             channel
         }
         _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
     };
-    let (_, channel) = task.channels.get_mut(channel_id).unwrap();
 
-    // TODO: need to be able to return more than just the first one FIXME: this
-    // probably needs the lock to make sure a message wasn't sent after the
-    // check but before the register
+    if depth == 0 || depth > MAX_QUEUED_MESSAGES {
+        return SyscallOutcome::Err(KError::InvalidArgument(1));
+    }
 
-    // FIXME: check for broken channel
+    let (_, channel) = task.channels.get(channel_id).unwrap();
+    channel.receiver.inner.write().depth = depth;
+    // The other end may be waiting for room which there now is
+    channel.receiver.space.wake_all();
 
-    let mut receiver = channel.receiver.inner.write();
-    match receiver.pop_front() {
-        None => SyscallOutcome::processed((0, 0, 0, 0, 0)),
-        Some(ChannelMessage { data, mut caps }) => {
-            let mut message_id = MessageId::new(0);
-            let mut region = VirtualAddress::new(0)..VirtualAddress::new(0);
-            let mut len = 0;
-
-            if let Some((mid, mregion, mlen)) = data {
-                message_id = mid;
-                len = mlen;
-
-                let mregion = match mregion {
-                    PhysicalRegion::Shared(region) => region,
-                    _ => unreachable!(),
-                };
-
-                // FIXME: make it so we can use any kind of physical region
-                region = task.memory_manager.apply_shared_region(
-                    None,
-                    flags::READ | flags::WRITE | flags::USER | flags::VALID,
-                    mregion,
-                    AddressRegionKind::Channel,
-                );
+    SyscallOutcome::processed(())
+}
+
+/// Mint a badged copy of an unbadged channel capability, which can then be
+/// handed out so that the other end can tell apart messages sent through each
+/// copy
+pub fn mint_badged(task: &mut Task, cptr: CapabilityPtr, rights: CapabilityRights, badge: usize) -> SyscallOutcome {
+    let channel_id = match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Channel(channel), rights: cap_rights, badge: 0 }) => {
+            if !cap_rights.is_superset(rights) {
+                return SyscallOutcome::Err(KError::InvalidArgument(1));
             }
 
-            let (caps_written, caps_remaining) = match cap_buffer.len() {
-                0 => (0, caps.len()),
-                len => {
-                    let cap_slice = match unsafe { cap_buffer.validate(&task.memory_manager) } {
-                        Ok(cap_slice) => cap_slice,
-                        Err(_) => return SyscallOutcome::Err(KError::InvalidArgument(3)),
-                    };
-
-                    let n_caps_to_write = len.min(caps.len());
-                    let mut cap_slice = cap_slice.guarded();
-                    for (target, cap) in cap_slice.iter_mut().zip(caps.drain(..n_caps_to_write)) {
-                        *target = cap;
-                    }
+            *channel
+        }
+        // Badges can't be changed once set, otherwise the holder of a badged
+        // capability could impersonate other clients
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
 
-                    (n_caps_to_write, caps.len())
-                }
-            };
+    if badge == 0 {
+        return SyscallOutcome::Err(KError::InvalidArgument(2));
+    }
 
-            if caps_remaining != 0 {
-                receiver.push_front(ChannelMessage { data: None, caps });
-            }
+    let cptr = task.cspace.mint(Capability { resource: CapabilityResource::Channel(channel_id), rights, badge });
 
-            SyscallOutcome::processed((message_id.value(), region.start.as_usize(), len, caps_written, caps_remaining))
+    SyscallOutcome::processed(cptr.value())
+}
+
+/// Map the channel's ring buffer into the task, allocating it with `size`
+/// bytes if the other end hasn't already done so. Returns the address and size
+/// of the mapping along with which half of the ring this end produces into.
+pub fn map_ring(task: &mut Task, cptr: CapabilityPtr, size: usize) -> SyscallOutcome {
+    let channel_id = match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Channel(channel), rights, .. })
+            if rights.is_superset(CapabilityRights::READ | CapabilityRights::WRITE) =>
+        {
+            channel
         }
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+    let (_, channel) = task.channels.get_mut(channel_id).unwrap();
+
+    if let Some(mapping) = &channel.ring.mapping {
+        let size = mapping.end.as_usize() - mapping.start.as_usize();
+        return SyscallOutcome::processed((mapping.start.as_usize(), size, channel.ring.side));
     }
+
+    let flags = flags::READ | flags::WRITE | flags::USER | flags::VALID;
+    let mut shared = channel.ring.region.lock();
+    let mapping = match &*shared {
+        Some(region) => task.group.memory_manager.lock().apply_shared_region(
+            None,
+            flags,
+            region.clone(),
+            AddressRegionKind::Channel,
+        ),
+        None => {
+            // Each end needs at least a page to itself
+            if size == 0 || size > MAX_CHANNEL_RING_BYTES {
+                return SyscallOutcome::Err(KError::InvalidArgument(1));
+            }
+
+            let n_pages = utils::round_up_to_next(size, 8.kib()) / 4.kib();
+            let (mapping, region) = task.group.memory_manager.lock().alloc_shared_region(
+                None,
+                RegionDescription {
+                    size: PageSize::Kilopage,
+                    len: n_pages,
+                    contiguous: false,
+                    flags,
+                    fill: FillOption::Zeroed,
+                    kind: AddressRegionKind::Channel,
+                },
+            );
+
+            *shared = Some(region);
+            mapping
+        }
+    };
+
+    drop(shared);
+    channel.ring.mapping = Some(mapping.clone());
+
+    let size = mapping.end.as_usize() - mapping.start.as_usize();
+    SyscallOutcome::processed((mapping.start.as_usize(), size, channel.ring.side))
 }
 
-pub fn retire_message(task: &mut Task, cptr: CapabilityPtr, message_id: MessageId) -> SyscallOutcome {
+/// Wake the other end of the channel if it's waiting on the ring buffer, or
+/// latch the notification for its next wait
+pub fn notify_ring(task: &mut Task, cptr: CapabilityPtr) -> SyscallOutcome {
     let channel_id = match task.cspace.resolve(cptr) {
-        Some(Capability { resource: CapabilityResource::Channel(channel), rights })
+        Some(Capability { resource: CapabilityResource::Channel(channel), rights, .. })
             if *rights & CapabilityRights::WRITE =>
         {
             channel
         }
         _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
     };
-    let (_, channel) = task.channels.get_mut(channel_id).unwrap();
+    let (_, channel) = task.channels.get(channel_id).unwrap();
 
-    match channel.mapped_regions.remove(&message_id) {
-        Some(MappedChannelMessage::Received { region, .. }) => {
-            task.memory_manager.dealloc_region(region.start);
-            SyscallOutcome::Processed(librust::message::Message::default())
+    if !channel.sender.alive.load(Ordering::Acquire) {
+        return SyscallOutcome::Err(KError::InvalidArgument(0));
+    }
+
+    channel.ring.outgoing.ring();
+
+    SyscallOutcome::Processed(librust::message::Message::default())
+}
+
+/// Block until the other end of the channel notifies this end, returning
+/// immediately if a notification arrived since the last wait
+pub fn wait_ring(task: &mut Task, cptr: CapabilityPtr) -> SyscallOutcome {
+    let channel_id = match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Channel(channel), rights, .. })
+            if *rights & CapabilityRights::READ =>
+        {
+            channel
         }
-        _ => SyscallOutcome::Err(KError::InvalidArgument(1)),
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+    let (_, channel) = task.channels.get(channel_id).unwrap();
+    let doorbell = &channel.ring.incoming;
+
+    // Hold the wake lock over the check so a notification can't slip in
+    // between it and registering the wake
+    let mut wake = doorbell.wake.lock();
+    if doorbell.rung.swap(false, Ordering::AcqRel) {
+        return SyscallOutcome::Processed(librust::message::Message::default());
+    }
+
+    // Nothing will ever ring the doorbell once the other end is gone
+    if !channel.receiver.alive.load(Ordering::Acquire) {
+        return SyscallOutcome::Err(KError::InvalidArgument(0));
     }
+
+    wake.replace(WakeToken::new(task.wait_id(), |task| {
+        super::apply_message(
+            false,
+            librust::message::Sender::kernel(),
+            librust::message::Message::default(),
+            &mut task.context.gp_regs,
+        )
+    }));
+
+    SyscallOutcome::Block
 }
 
+/// Deliver `payload` to the other end of the channel as an
+/// [`KernelNotification::IncomingCall`] along with a one-shot reply capability,
+/// then block until it's replied to. The receiver is run next on this hart so
+/// that the call doesn't have to wait for a full scheduling round. If the
+/// receiver exits without replying, the call fails with
+/// [`KError::InvalidRecipient`].
+pub fn call(task: &mut Task, cptr: CapabilityPtr, payload: [usize; CALL_PAYLOAD_LEN]) -> SyscallOutcome {
+    let current_tid = task.tid;
+    let (channel_id, badge) = match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Channel(channel), rights, badge })
+            if *rights & CapabilityRights::WRITE =>
+        {
+            (*channel, *badge)
+        }
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+
+    let (other_tid, channel) = task.channels.get(&channel_id).unwrap();
+    if !channel.sender.alive.load(Ordering::Acquire) {
+        return SyscallOutcome::Err(KError::InvalidArgument(0));
+    }
+
+    let other_tid = *other_tid;
+    let other_task = match TASKS.get(other_tid) {
+        Some(task) => task,
+        None => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+    let mut other_task = match super::lock_other(task, other_tid, &other_task) {
+        Some(other_task) => other_task,
+        None => return SyscallOutcome::Retry,
+    };
+
+    crate::trace::record(
+        TraceEvent::IpcSend,
+        current_tid.value(),
+        [other_tid.value(), CALL_PAYLOAD_LEN * core::mem::size_of::<usize>()],
+    );
+
+    // Same as with messages, the other end may be mid-handoff and not have a
+    // capability for the channel yet
+    let other_cptr = peer_cptr(&other_task, channel);
+    let other_cptr = match other_cptr {
+        Some(cptr) => cptr,
+        None => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+
+    let reply = other_task.cspace.mint(Capability {
+        resource: CapabilityResource::Reply(task.wait_id()),
+        rights: CapabilityRights::WRITE,
+        badge: 0,
+    });
+
+    other_task.message_queue.push(
+        librust::message::Sender::kernel(),
+        KernelNotification::IncomingCall { channel: other_cptr, reply: ReplyCapability::new(reply), badge, payload }
+            .into(),
+    );
+    drop(other_task);
+
+    SCHEDULER.hand_off(other_tid);
+
+    SyscallOutcome::Block
+}
+
+/// Consume a reply capability, waking up the blocked caller with `payload` as
+/// the result of its call
+pub fn reply(task: &mut Task, cptr: CapabilityPtr, payload: [usize; CALL_PAYLOAD_LEN]) -> SyscallOutcome {
+    let caller = match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Reply(caller), rights, .. })
+            if *rights & CapabilityRights::WRITE =>
+        {
+            *caller
+        }
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+
+    task.cspace.remove(cptr);
+
+    let mut contents = [0; 13];
+    contents[..CALL_PAYLOAD_LEN].copy_from_slice(&payload);
+
+    SCHEDULER.unblock(WakeToken::new(caller, move |task| {
+        super::apply_message(
+            false,
+            librust::message::Sender::kernel(),
+            librust::message::Message { contents },
+            &mut task.context.gp_regs,
+        )
+    }));
+
+    SyscallOutcome::Processed(librust::message::Message::default())
+}
+
+/// The tasks on the other end of the unbadged channels in `caps`, which
+/// transferring them to `receiving_tid` opens new channels to, in order of
+/// their IDs
+fn channel_peers(
+    task: &Task,
+    receiving_tid: Tid,
+    caps: &[librust::capabilities::Capability],
+) -> Result<Vec<(Tid, Arc<TaskLock>)>, KError> {
+    let mut peers: Vec<(Tid, Arc<TaskLock>)> = Vec::new();
+
+    for cap in caps {
+        let peer_tid = match task.cspace.resolve(cap.cptr) {
+            Some(Capability { resource: CapabilityResource::Channel(cid), badge: 0, .. }) => {
+                task.channels.get(cid).unwrap().0
+            }
+            _ => continue,
+        };
+
+        // The receiver would end up with both ends of the new channel
+        if peer_tid == receiving_tid || peer_tid == task.tid {
+            return Err(KError::InvalidArgument(1));
+        }
+
+        if peers.iter().any(|(tid, _)| *tid == peer_tid) {
+            continue;
+        }
+
+        match TASKS.get(peer_tid) {
+            Some(peer) => peers.push((peer_tid, peer)),
+            None => return Err(KError::InvalidArgument(1)),
+        }
+    }
+
+    peers.sort_unstable_by_key(|(tid, _)| *tid);
+    Ok(peers)
+}
+
+/// Lock each of `peers` while tasks up to `held` are already locked, only
+/// trying the ones which would have to be locked first, like [`super::lock_other`]
+fn lock_peers(mut held: Tid, peers: &[(Tid, Arc<TaskLock>)]) -> Option<Vec<TaskGuard<'_>>> {
+    peers
+        .iter()
+        .map(|(tid, peer)| {
+            let peer = match *tid > held {
+                true => Some(peer.lock()),
+                false => peer.try_lock(),
+            };
+
+            held = held.max(*tid);
+            peer
+        })
+        .collect()
+}
+
+/// Mint a copy of `cptr_to_send` for `receiving_task`, which is on the other end
+/// of the channel `cptr`. `peers` has to hold the tasks from [`channel_peers`]
+/// already locked.
 fn transfer_capability(
     task: &mut Task,
+    receiving_task: &mut Task,
+    peers: &mut [TaskGuard<'_>],
     cptr: CapabilityPtr,
     cptr_to_send: CapabilityPtr,
     rights: CapabilityRights,
@@ -480,7 +1164,7 @@ fn transfer_capability(
     }
 
     let channel_id = match task.cspace.resolve(cptr) {
-        Some(Capability { resource: CapabilityResource::Channel(channel), rights })
+        Some(Capability { resource: CapabilityResource::Channel(channel), rights, .. })
             if *rights & CapabilityRights::READ =>
         {
             channel
@@ -488,32 +1172,68 @@ fn transfer_capability(
         _ => return Err(KError::InvalidArgument(0)),
     };
 
-    let (receiving_tid, _) = task.channels.get(channel_id).unwrap();
+    let receiving_tid = receiving_task.tid;
+    if task.channels.get(channel_id).unwrap().0 != receiving_tid {
+        return Err(KError::InvalidArgument(0));
+    }
 
     let cap_to_send = match task.cspace.resolve(cptr_to_send) {
         Some(cap) => cap,
         None => return Err(KError::InvalidArgument(1)),
     };
 
+    if !(cap_to_send.rights & CapabilityRights::TRANSFER) {
+        return Err(KError::InvalidArgument(1));
+    }
+
     if !cap_to_send.rights.is_superset(rights) {
         return Err(KError::InvalidArgument(2));
     }
 
-    let receiving_task = match TASKS.get(*receiving_tid) {
-        Some(task) => task,
-        None => panic!("wut"),
-    };
-    let mut receiving_task = receiving_task.lock();
+    // Memory backed capabilities are mapped into the receiver as soon as they
+    // arrive, so there's no way to hand them over without also letting them
+    // be mapped
+    let memory_backed = matches!(
+        cap_to_send.resource,
+        CapabilityResource::Memory(..) | CapabilityResource::Mmio(..) | CapabilityResource::Dma(..)
+    );
+    if memory_backed && !(rights & CapabilityRights::MAP) {
+        return Err(KError::InvalidArgument(2));
+    }
+
+    super::hooks::capability_transfer(task, receiving_tid, cap_to_send, rights)?;
 
     match &cap_to_send.resource {
+        CapabilityResource::Channel(cid) if cap_to_send.badge != 0 => {
+            let (other_tid, channel) = task.channels.get(cid).unwrap();
+            let (minting_tid, open) = match channel.client {
+                true => (*other_tid, channel.sender.alive.load(Ordering::Acquire)),
+                false => (current_tid, channel.receiver.alive.load(Ordering::Acquire)),
+            };
+
+            // Sending to itself through the copy would have the minting task
+            // lock itself
+            if !open || minting_tid == receiving_tid {
+                return Err(KError::InvalidArgument(1));
+            }
+
+            let receiving_task_channel_id =
+                ChannelId::new(receiving_task.channels.last_key_value().map(|(id, _)| id.value() + 1).unwrap_or(0));
+            receiving_task.channels.insert(receiving_task_channel_id, (minting_tid, channel.badged_client()));
+
+            Ok(receiving_task.cspace.mint(Capability {
+                resource: CapabilityResource::Channel(receiving_task_channel_id),
+                rights,
+                badge: cap_to_send.badge,
+            }))
+        }
         CapabilityResource::Channel(cid) => {
             let (other_tid, _) = task.channels.get(cid).unwrap();
-            let other_task = match TASKS.get(*other_tid) {
-                Some(task) => task,
-                None => panic!("wut"),
+            let other_task = match peers.iter_mut().find(|peer| peer.tid == *other_tid) {
+                Some(other_task) => other_task,
+                None => return Err(KError::InvalidArgument(1)),
             };
 
-            let mut other_task = other_task.lock();
             if other_task.state.is_dead() {
                 return Err(KError::InvalidArgument(1));
             }
@@ -522,7 +1242,7 @@ fn transfer_capability(
                 .cspace
                 .all()
                 .find_map(|(_, cap)| match cap {
-                    Capability { resource: CapabilityResource::Channel(id), rights } => {
+                    Capability { resource: CapabilityResource::Channel(id), rights, .. } => {
                         match other_task.channels.get(id).unwrap().0 == current_tid {
                             true => Some(*rights),
                             false => None,
@@ -539,15 +1259,18 @@ fn transfer_capability(
 
             let (channel1, channel2) = UserspaceChannel::new();
             receiving_task.channels.insert(receiving_task_channel_id, (*other_tid, channel1));
-            other_task.channels.insert(other_task_channel_id, (*receiving_tid, channel2));
+            other_task.channels.insert(other_task_channel_id, (receiving_tid, channel2));
 
-            let receiving_cptr = receiving_task
-                .cspace
-                .mint(Capability { resource: CapabilityResource::Channel(receiving_task_channel_id), rights });
+            let receiving_cptr = receiving_task.cspace.mint(Capability {
+                resource: CapabilityResource::Channel(receiving_task_channel_id),
+                rights,
+                badge: 0,
+            });
 
             let other_cptr = other_task.cspace.mint(Capability {
                 resource: CapabilityResource::Channel(other_task_channel_id),
                 rights: other_rights,
+                badge: 0,
             });
 
             other_task.message_queue.push(
@@ -567,21 +1290,24 @@ fn transfer_capability(
                 (_, _) => return Err(KError::InvalidArgument(2)),
             };
 
-            let range = receiving_task.memory_manager.apply_shared_region(None, flags, phys_region.clone(), *kind);
-            let mem_cap = receiving_task
-                .cspace
-                .mint(Capability { rights, resource: CapabilityResource::Memory(phys_region.clone(), range, *kind) });
+            let range =
+                receiving_task.group.memory_manager.lock().apply_shared_region(None, flags, phys_region.clone(), *kind);
+            let mem_cap = receiving_task.cspace.mint(Capability {
+                rights,
+                resource: CapabilityResource::Memory(phys_region.clone(), range, *kind),
+                badge: 0,
+            });
 
             Ok(mem_cap)
         }
         CapabilityResource::Mmio(..) => {
             let cap = task.cspace.remove(cptr_to_send).unwrap();
-            let (vregion, interrupts) = match cap.resource {
-                CapabilityResource::Mmio(vregion, interrupts) => (vregion, interrupts),
+            let (vregion, interrupts, path) = match cap.resource {
+                CapabilityResource::Mmio(vregion, interrupts, path) => (vregion, interrupts, path),
                 _ => unreachable!(),
             };
 
-            let region = match task.memory_manager.dealloc_region(vregion.start) {
+            let region = match task.group.memory_manager.lock().dealloc_region(vregion.start) {
                 MemoryRegion::Backed(region) => region,
                 _ => unreachable!(),
             };
@@ -593,7 +1319,8 @@ fn transfer_capability(
             let start = region.physical_addresses().next().unwrap();
             // We know at this point that its been removed from the previous
             // process and MMIO caps are unique in a system
-            let vrange = unsafe { receiving_task.memory_manager.map_mmio_device(start, None, size) };
+            let vrange =
+                unsafe { receiving_task.group.memory_manager.lock().map_mmio_device(start, None, size, &path) };
 
             // We want to avoid a possible race here, we want the task to know
             // about the MMIO device capability _before_ any interrupts occur
@@ -602,43 +1329,64 @@ fn transfer_capability(
             // transferring the cap so interrupts aren't lost, but I think for
             // now that shouldn't be an issue since ideally the devices aren't
             // initialized until they're received by the final recipient
-            let receiving_cptr = receiving_task
-                .cspace
-                .mint(Capability { resource: CapabilityResource::Mmio(vrange, interrupts.clone()), rights });
-
-            let plic = PLIC.lock();
-            let plic = plic.as_ref().unwrap();
-            let receiving_tid = *receiving_tid;
-            for interrupt in interrupts {
-                // FIXME: This is copy/pasted from the `ClaimDevice` syscall, maybe
-                // refactor them both out to a function or something?
-                log::debug!(
-                    "Reregistering interrupt {} from task {} to task {}",
-                    interrupt,
-                    task.name,
-                    receiving_task.name
-                );
-                plic.enable_interrupt(crate::platform::current_plic_context(), interrupt);
-                plic.set_context_threshold(crate::platform::current_plic_context(), 0);
-                plic.set_interrupt_priority(interrupt, 7);
-                crate::interrupts::isr::register_isr(interrupt, move |plic, _, id| {
-                    plic.disable_interrupt(crate::platform::current_plic_context(), id);
-                    let task = TASKS.get(receiving_tid).unwrap();
-                    let mut task = task.lock();
-
-                    log::debug!("Interrupt {} triggered (hart: {}), notifying task {}", id, HART_ID.get(), task.name);
-
-                    task.claimed_interrupts.insert(id, HART_ID.get());
-                    task.message_queue.push(
-                        librust::message::Sender::kernel(),
-                        Message::from(KernelNotification::InterruptOccurred(id)),
-                    );
-
-                    Ok(())
-                });
-            }
+            log::debug!("Rerouting interrupts for {} from task {} to task {}", path, task.name, receiving_task.name);
+            crate::device::reassign(&path, receiving_tid);
+            let receiving_cptr = receiving_task.cspace.mint(Capability {
+                resource: CapabilityResource::Mmio(vrange, interrupts, path),
+                rights,
+                badge: 0,
+            });
 
             Ok(receiving_cptr)
         }
+        // Only the owner of a DMA region can share it, and the task it's
+        // shared with can't pass it along any further
+        CapabilityResource::Dma(dma_region, _) => {
+            if !(cap_to_send.rights & CapabilityRights::GRANT)
+                || rights & CapabilityRights::GRANT
+                || rights & CapabilityRights::TRANSFER
+            {
+                return Err(KError::InvalidArgument(2));
+            }
+
+            let mut flags = flags::USER | flags::VALID | flags::READ;
+            if rights & CapabilityRights::WRITE {
+                flags |= flags::WRITE;
+            }
+
+            let range = receiving_task.group.memory_manager.lock().apply_shared_region(
+                None,
+                flags,
+                dma_region.region.clone(),
+                AddressRegionKind::Dma,
+            );
+            dma_region.record_share(receiving_tid);
+
+            Ok(receiving_task.cspace.mint(Capability {
+                resource: CapabilityResource::Dma(Arc::clone(dma_region), range),
+                rights,
+                badge: 0,
+            }))
+        }
+        // Reply capabilities are only meaningful to the task the call was
+        // delivered to
+        CapabilityResource::Reply(_) => Err(KError::InvalidArgument(1)),
+        CapabilityResource::ConsoleStream(stream) => Ok(receiving_task.cspace.mint(Capability {
+            resource: CapabilityResource::ConsoleStream(stream.clone()),
+            rights,
+            badge: 0,
+        })),
+        CapabilityResource::SystemControl => {
+            Ok(receiving_task.cspace.mint(Capability { resource: CapabilityResource::SystemControl, rights, badge: 0 }))
+        }
+        CapabilityResource::KernelLog => {
+            Ok(receiving_task.cspace.mint(Capability { resource: CapabilityResource::KernelLog, rights, badge: 0 }))
+        }
+        CapabilityResource::Perf => {
+            Ok(receiving_task.cspace.mint(Capability { resource: CapabilityResource::Perf, rights, badge: 0 }))
+        }
+        // The group's exit notification goes to whoever created it, so it
+        // can't be shared with anyone else
+        CapabilityResource::TaskGroup(_) => Err(KError::InvalidArgument(1)),
     }
 }
@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{mem::copy_to_user, SyscallOutcome};
+use crate::{
+    capabilities::{Capability, CapabilityResource},
+    mem::user::{self, ValidatedUserSlice},
+    task::Task,
+};
+use alloc::vec::Vec;
+use librust::{
+    capabilities::{CapabilityInfo, CapabilityKind, CapabilityPtr},
+    error::KError,
+};
+
+pub fn list_capabilities(
+    task: &mut Task,
+    buffer: ValidatedUserSlice<user::ReadWrite, CapabilityInfo>,
+) -> SyscallOutcome {
+    let capabilities: Vec<_> = task.cspace.all().map(|(cptr, capability)| info(*cptr, capability)).collect();
+
+    match copy_to_user(buffer, &capabilities) {
+        Ok(n_written) => SyscallOutcome::processed((n_written, capabilities.len())),
+        Err(e) => SyscallOutcome::Err(e),
+    }
+}
+
+pub fn inspect_capability(task: &mut Task, cptr: CapabilityPtr) -> SyscallOutcome {
+    match task.cspace.resolve(cptr) {
+        Some(capability) => {
+            let info = info(cptr, capability);
+            SyscallOutcome::processed((
+                info.kind as usize,
+                info.rights.value(),
+                info.badge,
+                info.address,
+                info.len,
+                info.n_interrupts,
+            ))
+        }
+        None => SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+}
+
+fn info(cptr: CapabilityPtr, capability: &Capability) -> CapabilityInfo {
+    let mut info = CapabilityInfo {
+        cptr,
+        kind: CapabilityKind::Channel,
+        rights: capability.rights,
+        badge: capability.badge,
+        ..Default::default()
+    };
+
+    let mapped = match &capability.resource {
+        CapabilityResource::Channel(_) => None,
+        CapabilityResource::Memory(_, range, _) => {
+            info.kind = CapabilityKind::Memory;
+            Some(range)
+        }
+        CapabilityResource::Mmio(range, interrupts, _) => {
+            info.kind = CapabilityKind::Mmio;
+            info.n_interrupts = interrupts.len();
+            Some(range)
+        }
+        CapabilityResource::Reply(_) => {
+            info.kind = CapabilityKind::Reply;
+            None
+        }
+        CapabilityResource::ConsoleStream(_) => {
+            info.kind = CapabilityKind::ConsoleStream;
+            None
+        }
+        CapabilityResource::Dma(_, range) => {
+            info.kind = CapabilityKind::Dma;
+            Some(range)
+        }
+        CapabilityResource::SystemControl => {
+            info.kind = CapabilityKind::SystemControl;
+            None
+        }
+        CapabilityResource::KernelLog => {
+            info.kind = CapabilityKind::KernelLog;
+            None
+        }
+        CapabilityResource::Perf => {
+            info.kind = CapabilityKind::Perf;
+            None
+        }
+        CapabilityResource::TaskGroup(_) => {
+            info.kind = CapabilityKind::TaskGroup;
+            None
+        }
+    };
+
+    if let Some(range) = mapped {
+        info.address = range.start.as_usize();
+        info.len = range.end.as_usize() - range.start.as_usize();
+    }
+
+    info
+}
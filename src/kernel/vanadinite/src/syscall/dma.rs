@@ -0,0 +1,154 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::SyscallOutcome;
+use crate::{
+    capabilities::{Capability, CapabilityResource},
+    mem::{
+        manager::{AddressRegionKind, FillOption, RegionDescription},
+        paging::{flags, PageSize},
+        region::SharedPhysicalRegion,
+    },
+    scheduler::TASKS,
+    task::Task,
+    utils,
+};
+use alloc::{collections::BTreeSet, sync::Arc, vec::Vec};
+use core::ops::Range;
+use librust::{
+    capabilities::{CapabilityPtr, CapabilityRights},
+    error::KError,
+    syscalls::allocation::DmaAllocationOptions,
+    task::Tid,
+};
+use sync::SpinMutex;
+
+/// Physically contiguous memory a userspace driver can program devices with.
+/// Without an IOMMU nothing stops a device from reaching any memory at all, so
+/// instead the kernel keeps track of who owns the physical addresses, and
+/// drivers check with it before handing addresses they were given to a device.
+#[derive(Debug)]
+pub struct DmaRegion {
+    pub region: SharedPhysicalRegion,
+    /// Tasks the owner has shared the region with, which may have since
+    /// exited
+    shared_with: SpinMutex<BTreeSet<Tid>>,
+}
+
+impl DmaRegion {
+    pub fn physical_range(&self) -> Range<usize> {
+        let start = self.region.physical_addresses().next().unwrap().as_usize();
+        start..start + self.region.n_pages() * self.region.page_size().to_byte_size()
+    }
+
+    pub fn contains(&self, phys: usize, len: usize) -> bool {
+        let range = self.physical_range();
+        match phys.checked_add(len) {
+            Some(end) => phys >= range.start && end <= range.end,
+            None => false,
+        }
+    }
+
+    /// Record that the region was mapped into another task
+    pub fn record_share(&self, tid: Tid) {
+        self.shared_with.lock().insert(tid);
+    }
+}
+
+/// Allocate a [`DmaRegion`] owned by the task, returning its physical address,
+/// where it was mapped, and the capability for it. Only the owner's capability
+/// has [`CapabilityRights::GRANT`], which is needed to share or revoke it.
+pub fn alloc_dma_memory(task: &mut Task, size: usize, options: DmaAllocationOptions) -> SyscallOutcome {
+    let page_size = PageSize::Kilopage;
+
+    if size == 0 {
+        return SyscallOutcome::Err(KError::InvalidArgument(0));
+    }
+
+    let (allocated_at, region) = task.group.memory_manager.lock().alloc_shared_region(
+        None,
+        RegionDescription {
+            size: page_size,
+            len: utils::round_up_to_next(size, page_size.to_byte_size()) / page_size.to_byte_size(),
+            contiguous: true,
+            flags: flags::VALID | flags::USER | flags::READ | flags::WRITE,
+            fill: if options & DmaAllocationOptions::ZERO { FillOption::Zeroed } else { FillOption::Unitialized },
+            kind: AddressRegionKind::Dma,
+        },
+    );
+
+    let dma_region = Arc::new(DmaRegion { region, shared_with: SpinMutex::new(BTreeSet::new()) });
+    let phys = dma_region.physical_range().start;
+
+    log::debug!("Allocated DMA memory at {:#p} for user process", allocated_at.start);
+
+    let cptr = task.cspace.mint(Capability {
+        resource: CapabilityResource::Dma(dma_region, allocated_at.clone()),
+        rights: CapabilityRights::GRANT
+            | CapabilityRights::READ
+            | CapabilityRights::WRITE
+            | CapabilityRights::TRANSFER
+            | CapabilityRights::MAP,
+        badge: 0,
+    });
+
+    SyscallOutcome::processed((phys, allocated_at.start.as_usize(), cptr.value()))
+}
+
+/// Unmap a DMA region from every task it was shared with, so that the owner
+/// can reuse it without anyone else still programming devices with it
+pub fn revoke(task: &mut Task, cptr: CapabilityPtr) -> SyscallOutcome {
+    let dma_region = match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Dma(dma_region, _), rights, .. })
+            if *rights & CapabilityRights::GRANT =>
+        {
+            Arc::clone(dma_region)
+        }
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+
+    let shared_with = core::mem::take(&mut *dma_region.shared_with.lock());
+    for tid in shared_with {
+        let other_task = match TASKS.get(tid) {
+            Some(task) => task,
+            None => continue,
+        };
+
+        let mut other_task = other_task.lock();
+        let cptrs: Vec<CapabilityPtr> = other_task
+            .cspace
+            .all()
+            .filter(|(_, cap)| matches!(&cap.resource, CapabilityResource::Dma(other, _) if Arc::ptr_eq(other, &dma_region)))
+            .map(|(cptr, _)| *cptr)
+            .collect();
+
+        for cptr in cptrs {
+            if let Some(Capability { resource: CapabilityResource::Dma(_, range), .. }) = other_task.cspace.remove(cptr)
+            {
+                log::debug!("Revoking DMA region at {:#p} from task {}", range.start, other_task.name);
+                other_task.group.memory_manager.lock().dealloc_region(range.start);
+            }
+        }
+    }
+
+    SyscallOutcome::processed(())
+}
+
+/// Check that the physical range is inside of a DMA region the task either
+/// owns or has been shared, so that drivers can refuse to point devices at
+/// addresses they've been handed by other tasks which don't own them
+pub fn validate_range(task: &mut Task, phys: usize, len: usize) -> SyscallOutcome {
+    let owned = task.cspace.all().any(|(_, cap)| match &cap.resource {
+        CapabilityResource::Dma(dma_region, _) => dma_region.contains(phys, len),
+        _ => false,
+    });
+
+    match owned {
+        true => SyscallOutcome::processed(()),
+        false => SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+}
@@ -0,0 +1,240 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Asynchronous task events, which are similar to signals. A task receiving an
+//! event is terminated, unless it registered a handler for that event, in which
+//! case the next time it's scheduled its context is saved and it starts running
+//! the handler instead. The saved context is restored when the handler returns
+//! with `ReturnFromEvent`. Tasks which are blocked are woken up to run their
+//! handler, with whatever they were waiting on failing with
+//! [`KError::Interrupted`].
+//!
+//! Handlers can read and replace the saved context with `GetTaskContext` and
+//! `SetTaskContext`, which debuggers can also use on the tasks they hold a
+//! `GRANT` channel to.
+
+use super::{exit, SyscallOutcome};
+use crate::{
+    capabilities::{Capability, CapabilityResource},
+    mem::{
+        paging::VirtualAddress,
+        user::{RawUserPtr, Read, ReadWrite},
+    },
+    scheduler::{Scheduler, WakeToken, SCHEDULER},
+    task::{Context, EventHandler, ExitWatcher, Task, TaskState},
+    utils::SameHartDeadlockDetection,
+};
+use alloc::{sync::Arc, vec::Vec};
+use librust::{
+    capabilities::{CapabilityPtr, CapabilityRights},
+    error::{AccessError, KError},
+    task::{ExitStatus, TaskContext, TaskEvent, TaskEventMask, Tid},
+};
+use sync::SpinMutex;
+
+pub fn send_task_event(task: &mut Task, cptr: CapabilityPtr, event: usize) -> SyscallOutcome {
+    let event = match TaskEvent::from_raw(event) {
+        Some(event) => event,
+        None => return SyscallOutcome::Err(KError::InvalidArgument(1)),
+    };
+
+    match task.cspace.resolve(cptr) {
+        Some(cap) if cap.rights & CapabilityRights::WRITE => {}
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+
+    let (tid, peer) = match exit::peer_of(task, cptr) {
+        Ok(peer) => peer,
+        Err(e) => return SyscallOutcome::Err(e),
+    };
+
+    let mut peer = match super::lock_other(task, tid, &peer) {
+        Some(peer) => peer,
+        None => return SyscallOutcome::Retry,
+    };
+
+    log::debug!("Task {:?} sending {:?} event to {:?}", task.name, event, peer.name);
+    let watchers = post_event(&mut peer, event);
+    drop(peer);
+    exit::notify_watchers(ExitStatus::Killed, watchers);
+
+    SyscallOutcome::processed(())
+}
+
+/// Mark `event` as pending for `task`, interrupting whatever it's blocked on,
+/// or terminate it if the event isn't handled. The returned exit watchers need to be notified once `task` has
+/// been unlocked.
+#[must_use]
+pub fn post_event(task: &mut Task, event: TaskEvent) -> Vec<ExitWatcher> {
+    if task.state.is_dead() {
+        return Vec::new();
+    }
+
+    let handled = event != TaskEvent::Kill && task.events.handler.is_some() && task.events.mask.contains(event);
+    if handled {
+        task.events.pending = task.events.pending.with(event);
+        SCHEDULER.unblock(WakeToken::new(task.wait_id(), |task| {
+            super::report_error(KError::Interrupted, &mut task.context.gp_regs)
+        }));
+
+        return Vec::new();
+    }
+
+    log::debug!("Task {:?} terminated by {:?} event", task.name, event);
+    let watchers = task.exit(ExitStatus::Killed);
+    SCHEDULER.dequeue(task.tid);
+
+    watchers
+}
+
+/// Set or remove (if `entry` is null) the task's event handler
+pub fn set_event_handler(task: &mut Task, entry: VirtualAddress, stack: VirtualAddress, mask: u64) -> SyscallOutcome {
+    let mask = TaskEventMask::new(mask);
+    if entry.is_null() {
+        task.events.handler = None;
+        task.events.mask = TaskEventMask::NONE;
+        return SyscallOutcome::processed(());
+    }
+
+    if entry.is_kernel_region() {
+        return SyscallOutcome::Err(KError::InvalidArgument(0));
+    } else if stack.is_kernel_region() {
+        return SyscallOutcome::Err(KError::InvalidArgument(1));
+    } else if mask.contains(TaskEvent::Kill) {
+        return SyscallOutcome::Err(KError::InvalidArgument(2));
+    }
+
+    task.events.handler = Some(EventHandler { entry, stack });
+    task.events.mask = mask;
+
+    SyscallOutcome::processed(())
+}
+
+/// Restore the context the task was in before its event handler was run
+pub fn return_from_event(task: &mut Task) -> SyscallOutcome {
+    match task.events.interrupted.take() {
+        Some(context) => {
+            task.context = context;
+            SyscallOutcome::Resume
+        }
+        None => SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+}
+
+/// Copy out the saved context of the task on the other end of the `GRANT`
+/// channel `cptr`, or without one, the context the current task's event
+/// handler interrupted
+pub fn get_task_context(task: &mut Task, cptr: Option<CapabilityPtr>, ptr: VirtualAddress) -> SyscallOutcome {
+    let context = match cptr {
+        None => match &task.events.interrupted {
+            Some(context) => to_task_context(context),
+            None => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+        },
+        Some(cptr) => {
+            let (tid, other_task) = match debuggee(task, cptr) {
+                Ok(debuggee) => debuggee,
+                Err(e) => return SyscallOutcome::Err(e),
+            };
+
+            match super::lock_other(task, tid, &other_task) {
+                Some(other_task) => to_task_context(&other_task.context),
+                None => return SyscallOutcome::Retry,
+            }
+        }
+    };
+
+    let user_ptr = RawUserPtr::<ReadWrite, TaskContext>::writable(ptr);
+    let mut user_ptr = match unsafe { user_ptr.validate(&mut task.group.memory_manager.lock()) } {
+        Ok(ptr) => ptr,
+        Err(_) => return SyscallOutcome::Err(KError::InvalidAccess(AccessError::Write(ptr.as_mut_ptr()))),
+    };
+
+    match user_ptr.write(context) {
+        Ok(()) => SyscallOutcome::processed(()),
+        Err(_) => SyscallOutcome::Err(KError::InvalidAccess(AccessError::Write(ptr.as_mut_ptr()))),
+    }
+}
+
+/// Replace the saved context of the task on the other end of the `GRANT`
+/// channel `cptr`, which has to be blocked so that it isn't overwritten when
+/// the task stops running, or without one, the context the current task's
+/// event handler returns to
+pub fn set_task_context(task: &mut Task, cptr: Option<CapabilityPtr>, ptr: VirtualAddress) -> SyscallOutcome {
+    let user_ptr = RawUserPtr::<Read, TaskContext>::readable(ptr);
+    let context = match unsafe { user_ptr.validate(&mut task.group.memory_manager.lock()) }.map(|ptr| ptr.read()) {
+        Ok(Ok(context)) => context,
+        _ => return SyscallOutcome::Err(KError::InvalidAccess(AccessError::Read(ptr.as_ptr()))),
+    };
+
+    if VirtualAddress::new(context.pc).is_kernel_region() {
+        return SyscallOutcome::Err(KError::InvalidArgument(1));
+    }
+
+    match cptr {
+        None => match &mut task.events.interrupted {
+            Some(interrupted) => apply_task_context(interrupted, &context),
+            None => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+        },
+        Some(cptr) => {
+            let (tid, other_task) = match debuggee(task, cptr) {
+                Ok(debuggee) => debuggee,
+                Err(e) => return SyscallOutcome::Err(e),
+            };
+
+            let mut other_task = match super::lock_other(task, tid, &other_task) {
+                Some(other_task) => other_task,
+                None => return SyscallOutcome::Retry,
+            };
+
+            if !matches!(other_task.state, TaskState::Blocked) {
+                return SyscallOutcome::Err(KError::InvalidArgument(0));
+            }
+
+            apply_task_context(&mut other_task.context, &context);
+        }
+    }
+
+    SyscallOutcome::processed(())
+}
+
+/// The task on the other end of the `GRANT` channel `cptr`, and its ID for
+/// [`super::lock_other`]
+fn debuggee(
+    task: &Task,
+    cptr: CapabilityPtr,
+) -> Result<(Tid, Arc<SpinMutex<Task, SameHartDeadlockDetection>>), KError> {
+    match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Channel(_), rights, .. })
+            if *rights & CapabilityRights::GRANT =>
+        {
+            exit::peer_of(task, cptr)
+        }
+        _ => Err(KError::InvalidArgument(0)),
+    }
+}
+
+// Both register structs are laid out in register number order, with `fcsr`
+// following the floating point registers
+fn to_task_context(context: &Context) -> TaskContext {
+    let registers: [usize; 31] = unsafe { core::mem::transmute(context.gp_regs) };
+    let fp: [usize; 33] = unsafe { core::mem::transmute(context.fp_regs) };
+
+    let mut fp_registers = [0; 32];
+    fp_registers.copy_from_slice(&fp[..32]);
+
+    TaskContext { pc: context.pc, registers, fp_registers, fcsr: fp[32] }
+}
+
+fn apply_task_context(context: &mut Context, task_context: &TaskContext) {
+    let mut fp = [0; 33];
+    fp[..32].copy_from_slice(&task_context.fp_registers);
+    fp[32] = task_context.fcsr;
+
+    context.pc = task_context.pc;
+    context.gp_regs = unsafe { core::mem::transmute(task_context.registers) };
+    context.fp_regs = unsafe { core::mem::transmute(fp) };
+}
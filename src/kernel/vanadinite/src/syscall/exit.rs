@@ -0,0 +1,133 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::SyscallOutcome;
+use crate::{
+    capabilities::{Capability, CapabilityResource},
+    scheduler::{Scheduler, WakeToken, SCHEDULER, TASKS},
+    task::{ExitWatcher, Task},
+    utils::SameHartDeadlockDetection,
+};
+use alloc::{sync::Arc, vec::Vec};
+use librust::{
+    capabilities::CapabilityPtr,
+    error::KError,
+    message::{KernelNotification, Sender},
+    task::{ExitStatus, Tid},
+};
+use sync::SpinMutex;
+
+/// Exit the current task with the given exit code. The task isn't marked dead
+/// until the syscall handler has released it, see [`notify_watchers`].
+pub fn exit(task: &mut Task, code: usize) -> SyscallOutcome {
+    log::debug!("Active process {} exited with code {}", task.display_name(), code);
+    if let Some(tls_block) = task.tls_block.take() {
+        task.group.memory_manager.lock().dealloc_region(tls_block);
+    }
+
+    SyscallOutcome::Kill(ExitStatus::Exited(code))
+}
+
+/// Block until the task on the other end of the channel `cptr` exits,
+/// returning its exit status
+pub fn wait(task: &mut Task, cptr: CapabilityPtr) -> SyscallOutcome {
+    let (tid, peer) = match peer_of(task, cptr) {
+        Ok(peer) => peer,
+        Err(e) => return SyscallOutcome::Err(e),
+    };
+
+    let mut peer = match super::lock_other(task, tid, &peer) {
+        Some(peer) => peer,
+        None => return SyscallOutcome::Retry,
+    };
+
+    match peer.exit_status {
+        Some(status) => SyscallOutcome::processed(status.to_raw()),
+        None => {
+            peer.exit_watchers.push(ExitWatcher::Blocked(task.wait_id()));
+            SyscallOutcome::Block
+        }
+    }
+}
+
+/// Send the current task a [`KernelNotification::TaskExited`] when the task on
+/// the other end of the channel `cptr` exits
+pub fn watch_exit(task: &mut Task, cptr: CapabilityPtr) -> SyscallOutcome {
+    let (tid, peer) = match peer_of(task, cptr) {
+        Ok(peer) => peer,
+        Err(e) => return SyscallOutcome::Err(e),
+    };
+
+    let mut peer = match super::lock_other(task, tid, &peer) {
+        Some(peer) => peer,
+        None => return SyscallOutcome::Retry,
+    };
+
+    match peer.exit_status {
+        Some(status) => {
+            task.message_queue.push(Sender::kernel(), KernelNotification::TaskExited { channel: cptr, status }.into());
+        }
+        None => peer.exit_watchers.push(ExitWatcher::Notify(task.tid, cptr)),
+    }
+
+    SyscallOutcome::processed(())
+}
+
+/// Let everything watching a task know that it exited. This locks the watching
+/// tasks, so must be called after the exited task has been unlocked.
+pub fn notify_watchers(status: ExitStatus, watchers: Vec<ExitWatcher>) {
+    for watcher in watchers {
+        match watcher {
+            ExitWatcher::Blocked(wait) => {
+                SCHEDULER.unblock(WakeToken::new(wait, move |task| {
+                    super::apply_message(false, Sender::kernel(), status.to_raw(), &mut task.context.gp_regs)
+                }));
+            }
+            ExitWatcher::Caller(wait) => {
+                SCHEDULER.unblock(WakeToken::new(wait, |task| {
+                    super::report_error(KError::InvalidRecipient, &mut task.context.gp_regs)
+                }));
+            }
+            ExitWatcher::Notify(tid, channel) => {
+                if let Some(task) = TASKS.get(tid) {
+                    let mut task = task.lock();
+                    if !task.state.is_dead() {
+                        task.message_queue
+                            .push(Sender::kernel(), KernelNotification::TaskExited { channel, status }.into());
+                    }
+                }
+            }
+            ExitWatcher::TaskGroup(tid, group) => {
+                if let Some(task) = TASKS.get(tid) {
+                    let mut task = task.lock();
+                    if !task.state.is_dead() {
+                        task.message_queue.push(Sender::kernel(), KernelNotification::TaskGroupExited(group).into());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The task on the other end of the channel `cptr`, and its ID for
+/// [`super::lock_other`]
+pub(super) fn peer_of(
+    task: &Task,
+    cptr: CapabilityPtr,
+) -> Result<(Tid, Arc<SpinMutex<Task, SameHartDeadlockDetection>>), KError> {
+    let peer = match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Channel(channel_id), .. }) => {
+            task.channels.get(channel_id).map(|(peer, _)| *peer).ok_or(KError::InvalidArgument(0))?
+        }
+        _ => return Err(KError::InvalidArgument(0)),
+    };
+
+    match peer == task.tid {
+        true => Err(KError::InvalidArgument(0)),
+        false => TASKS.get(peer).map(|peer_task| (peer, peer_task)).ok_or(KError::InvalidArgument(0)),
+    }
+}
@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Forwarding syscalls in [`EXTENSION_SYSCALLS`] to a userspace server, which
+//! replies to them the same way as to a [`super::channel::call`]
+
+use super::SyscallOutcome;
+use crate::{
+    capabilities::{Capability, CapabilityResource},
+    scheduler::{Scheduler, SCHEDULER, TASKS},
+    task::Task,
+};
+use librust::{
+    capabilities::{CapabilityPtr, CapabilityRights, ReplyCapability},
+    error::KError,
+    message::{KernelNotification, Message, CALL_PAYLOAD_LEN},
+    syscalls::EXTENSION_SYSCALLS,
+    task::Tid,
+};
+use sync::SpinMutex;
+
+static SERVER: SpinMutex<Option<Tid>> = SpinMutex::new(None);
+
+/// Make the current task the syscall extension server, which needs the system
+/// control capability
+pub fn register(task: &mut Task, cptr: CapabilityPtr) -> SyscallOutcome {
+    match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::SystemControl, rights, .. })
+            if *rights & CapabilityRights::WRITE => {}
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+
+    log::info!("Task {} is now the syscall extension server", task.display_name());
+    *SERVER.lock() = Some(task.tid);
+
+    SyscallOutcome::processed(())
+}
+
+/// Stop forwarding extension syscalls to the task `tid` if it's the server,
+/// once it's exited
+pub fn release(tid: Tid) {
+    let mut server = SERVER.lock();
+    if *server == Some(tid) {
+        log::info!("Syscall extension server exited, extension syscalls are now invalid");
+        *server = None;
+    }
+}
+
+/// Send the extension syscall in `message` to the server and block the caller
+/// until it replies. If the server exits without replying, the syscall fails
+/// with [`KError::InvalidRecipient`] like any other call.
+pub fn dispatch(task: &mut Task, message: Message) -> SyscallOutcome {
+    let number = message.contents[0];
+    debug_assert!(EXTENSION_SYSCALLS.contains(&number));
+
+    // The server can't make extension syscalls itself, since it would be
+    // waiting on its own reply
+    let server = match *SERVER.lock() {
+        Some(server) if server != task.tid => server,
+        _ => return SyscallOutcome::Err(KError::InvalidSyscall(number)),
+    };
+
+    let server_task = match TASKS.get(server) {
+        Some(server_task) => server_task,
+        None => return SyscallOutcome::Err(KError::InvalidSyscall(number)),
+    };
+    let mut server_task = server_task.lock();
+
+    if server_task.state.is_dead() {
+        return SyscallOutcome::Err(KError::InvalidSyscall(number));
+    }
+
+    let reply = server_task.cspace.mint(Capability {
+        resource: CapabilityResource::Reply(task.wait_id()),
+        rights: CapabilityRights::WRITE,
+        badge: 0,
+    });
+
+    let mut args = [0; CALL_PAYLOAD_LEN];
+    args.copy_from_slice(&message.contents[1..][..CALL_PAYLOAD_LEN]);
+
+    server_task.message_queue.push(
+        librust::message::Sender::kernel(),
+        KernelNotification::ExtensionSyscall { caller: task.tid, reply: ReplyCapability::new(reply), number, args }
+            .into(),
+    );
+    drop(server_task);
+
+    SCHEDULER.hand_off(server);
+
+    SyscallOutcome::Block
+}
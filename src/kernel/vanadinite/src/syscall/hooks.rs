@@ -0,0 +1,137 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Hooks which policy modules, like auditing or rate limiting, can register to
+//! see every syscall and capability transfer, and to deny them, without
+//! patching the individual handlers. Hooks are run in the order they were
+//! registered, and the first one to deny something decides the error returned.
+//! With no hooks registered each hook point is a single load.
+
+use super::SyscallOutcome;
+use crate::{capabilities::Capability, task::Task};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use librust::{capabilities::CapabilityRights, error::KError, message::SyscallRequest, syscalls::Syscall, task::Tid};
+use sync::SpinRwLock;
+
+static HOOKS: SpinRwLock<Vec<&'static dyn SyscallHook>> = SpinRwLock::new(Vec::new());
+static ANY_HOOKS: AtomicBool = AtomicBool::new(false);
+
+/// A policy module, every method has a default which allows everything
+pub trait SyscallHook: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Called before `request` is handled, returning an error fails the
+    /// syscall with it instead
+    fn syscall_entry(&self, _task: &Task, _request: &SyscallRequest) -> Result<(), KError> {
+        Ok(())
+    }
+
+    /// Called once `syscall` has been handled, before the task sees the result
+    fn syscall_exit(&self, _task: &Task, _syscall: Syscall, _outcome: &SyscallOutcome) {}
+
+    /// Called before `capability` is sent over a channel to the task `to` with
+    /// `rights`, returning an error fails the send with it instead
+    fn capability_transfer(
+        &self,
+        _task: &Task,
+        _to: Tid,
+        _capability: &Capability,
+        _rights: CapabilityRights,
+    ) -> Result<(), KError> {
+        Ok(())
+    }
+
+    /// Called before `task` moves all of the capabilities of the dead task
+    /// `from` to the task `to`, returning an error fails the handoff with it
+    /// instead
+    fn capability_handoff(&self, _task: &Task, _from: Tid, _to: Tid) -> Result<(), KError> {
+        Ok(())
+    }
+}
+
+/// Add a hook, which is run after all of the ones already registered
+pub fn register(hook: &'static dyn SyscallHook) {
+    log::info!("Registering syscall hook `{}`", hook.name());
+    HOOKS.write().push(hook);
+    ANY_HOOKS.store(true, Ordering::Release);
+}
+
+fn run(mut f: impl FnMut(&dyn SyscallHook) -> Result<(), KError>) -> Result<(), KError> {
+    if !ANY_HOOKS.load(Ordering::Acquire) {
+        return Ok(());
+    }
+
+    HOOKS.read().iter().try_for_each(|hook| f(*hook))
+}
+
+pub fn syscall_entry(task: &Task, request: &SyscallRequest) -> Result<(), KError> {
+    run(|hook| hook.syscall_entry(task, request))
+}
+
+pub fn syscall_exit(task: &Task, syscall: Syscall, outcome: &SyscallOutcome) {
+    let _ = run(|hook| {
+        hook.syscall_exit(task, syscall, outcome);
+        Ok(())
+    });
+}
+
+pub fn capability_transfer(
+    task: &Task,
+    to: Tid,
+    capability: &Capability,
+    rights: CapabilityRights,
+) -> Result<(), KError> {
+    run(|hook| hook.capability_transfer(task, to, capability, rights))
+}
+
+pub fn capability_handoff(task: &Task, from: Tid, to: Tid) -> Result<(), KError> {
+    run(|hook| hook.capability_handoff(task, from, to))
+}
+
+/// Logs every capability transfer and failed syscall, registered with the
+/// `audit` bootarg
+pub struct Audit;
+
+impl SyscallHook for Audit {
+    fn name(&self) -> &'static str {
+        "audit"
+    }
+
+    fn syscall_exit(&self, task: &Task, syscall: Syscall, outcome: &SyscallOutcome) {
+        if let SyscallOutcome::Err(e) = outcome {
+            log::info!("audit: task {} {:?} failed: {:?}", task.display_name(), syscall, e);
+        }
+    }
+
+    fn capability_transfer(
+        &self,
+        task: &Task,
+        to: Tid,
+        capability: &Capability,
+        rights: CapabilityRights,
+    ) -> Result<(), KError> {
+        log::info!(
+            "audit: task {} sent {:?} to tid {} with rights {:?}",
+            task.display_name(),
+            capability.resource,
+            to.value(),
+            rights
+        );
+        Ok(())
+    }
+
+    fn capability_handoff(&self, task: &Task, from: Tid, to: Tid) -> Result<(), KError> {
+        log::info!(
+            "audit: task {} handed off capabilities from tid {} to tid {}",
+            task.display_name(),
+            from.value(),
+            to.value()
+        );
+        Ok(())
+    }
+}
@@ -8,18 +8,32 @@
 use super::SyscallOutcome;
 use crate::{
     capabilities::{Capability, CapabilityResource},
+    device::Region,
     mem::{
-        manager::{AddressRegionKind, FillOption, RegionDescription},
-        paging::{flags, PageSize},
+        manager::{AddressRegionKind, FillOption, MemoryManager, RegionDescription},
+        paging::{
+            flags::{self, Flags},
+            PageSize, PhysicalAddress, VirtualAddress,
+        },
+        region::{MemoryRegion, PhysicalRegion},
+        tlb,
+        user::{self, ValidatedUserSlice},
     },
+    scheduler::TASKS,
     task::Task,
     utils,
 };
+use alloc::{sync::Arc, vec::Vec};
+use core::ops::Range;
 use librust::{
     capabilities::{CapabilityPtr, CapabilityRights},
-    error::KError,
+    error::{AccessError, KError},
     message::Message,
-    syscalls::allocation::{AllocationOptions, DmaAllocationOptions, MemoryPermissions},
+    syscalls::{
+        allocation::{AllocationOptions, MemoryPermissions},
+        io::InterruptRateLimit,
+        mem::{RegionInfo, RegionKind, MAX_REGION_NAME_LEN, SNAPSHOT_MAGIC, SNAPSHOT_VERSION},
+    },
 };
 
 pub fn alloc_virtual_memory(
@@ -32,6 +46,12 @@ pub fn alloc_virtual_memory(
         return SyscallOutcome::Err(KError::InvalidArgument(2));
     }
 
+    // Code written at runtime is made executable afterwards with
+    // `finalize_executable` instead
+    if permissions & MemoryPermissions::WRITE && permissions & MemoryPermissions::EXECUTE {
+        return SyscallOutcome::Err(KError::InvalidArgument(2));
+    }
+
     let mut flags = flags::VALID | flags::USER;
 
     if permissions & MemoryPermissions::READ {
@@ -48,10 +68,14 @@ pub fn alloc_virtual_memory(
 
     let page_size = if options & AllocationOptions::LargePage { PageSize::Megapage } else { PageSize::Kilopage };
 
+    if options & AllocationOptions::JobGroupAvailable {
+        return alloc_group_memory(task, size, page_size, flags, options & AllocationOptions::Zero);
+    }
+
     match size {
         0 => SyscallOutcome::Err(KError::InvalidArgument(0)),
         _ => {
-            let allocated_at = task.memory_manager.alloc_region(
+            let allocated_at = task.group.memory_manager.lock().alloc_region(
                 None,
                 RegionDescription {
                     size: page_size,
@@ -70,40 +94,78 @@ pub fn alloc_virtual_memory(
     }
 }
 
-pub fn alloc_dma_memory(task: &mut Task, size: usize, options: DmaAllocationOptions) -> SyscallOutcome {
-    let page_size = PageSize::Kilopage;
+/// Free memory allocated with `AllocVirtualMemory`, given the address it was
+/// allocated at
+pub fn dealloc_virtual_memory(task: &mut Task, at: VirtualAddress) -> SyscallOutcome {
+    if at.is_kernel_region() {
+        return SyscallOutcome::Err(KError::InvalidArgument(0));
+    }
 
-    match size {
-        0 => SyscallOutcome::Err(KError::InvalidArgument(0)),
-        _ => {
-            let allocated_at = task.memory_manager.alloc_region(
-                None,
-                RegionDescription {
-                    size: page_size,
-                    len: utils::round_up_to_next(size, page_size.to_byte_size()) / page_size.to_byte_size(),
-                    contiguous: true,
-                    flags: flags::VALID | flags::USER | flags::READ | flags::WRITE,
-                    fill: if options & DmaAllocationOptions::ZERO {
-                        FillOption::Zeroed
-                    } else {
-                        FillOption::Unitialized
-                    },
-                    kind: AddressRegionKind::Dma,
-                },
-            );
+    let mut memory_manager = task.group.memory_manager.lock();
+    // Freed regions keep the kind they had
+    let allocated = match memory_manager.region_for(at) {
+        Some(region) if region.region.is_some() => {
+            region.span.start == at && region.kind == AddressRegionKind::UserAllocated
+        }
+        _ => false,
+    };
+
+    if !allocated {
+        return SyscallOutcome::Err(KError::InvalidArgument(0));
+    }
 
-            let phys = task.memory_manager.resolve(allocated_at.start).unwrap();
+    memory_manager.dealloc_region(at);
+    log::trace!("Freed memory at {:#p} for user process", at);
 
-            log::debug!("Allocated DMA memory at {:#p} for user process", allocated_at.start);
+    SyscallOutcome::processed(())
+}
 
-            SyscallOutcome::processed((phys.as_usize(), allocated_at.start.as_usize()))
-        }
+/// Allocate memory which is also placed in the task group's capability space,
+/// so other members can map it with `ClaimGroupCapability`
+fn alloc_group_memory(task: &mut Task, size: usize, page_size: PageSize, flags: Flags, zero: bool) -> SyscallOutcome {
+    let task_group = match &task.task_group {
+        Some(task_group) => Arc::clone(task_group),
+        None => return SyscallOutcome::Err(KError::InvalidArgument(1)),
+    };
+
+    // Shared memory isn't made executable anywhere else either
+    if flags & flags::EXECUTE {
+        return SyscallOutcome::Err(KError::InvalidArgument(2));
+    } else if size == 0 {
+        return SyscallOutcome::Err(KError::InvalidArgument(0));
     }
+
+    let (range, region) = task.group.memory_manager.lock().alloc_shared_region(
+        None,
+        RegionDescription {
+            size: page_size,
+            len: utils::round_up_to_next(size, page_size.to_byte_size()) / page_size.to_byte_size(),
+            contiguous: false,
+            flags,
+            fill: if zero { FillOption::Zeroed } else { FillOption::Unitialized },
+            kind: AddressRegionKind::UserAllocated,
+        },
+    );
+
+    let mut rights = CapabilityRights::READ | CapabilityRights::MAP;
+    if flags & flags::WRITE {
+        rights |= CapabilityRights::WRITE;
+    }
+
+    let cptr = task_group.cspace.lock().mint(Capability {
+        resource: CapabilityResource::Memory(region, range.clone(), AddressRegionKind::UserAllocated),
+        rights,
+        badge: 0,
+    });
+
+    SyscallOutcome::processed((range.start.as_usize(), cptr.value()))
 }
 
 pub fn query_mem_cap(task: &mut Task, cptr: CapabilityPtr) -> SyscallOutcome {
     match task.cspace.resolve(cptr) {
-        Some(Capability { resource: CapabilityResource::Memory(_, vmem, _), rights }) => {
+        Some(Capability { resource: CapabilityResource::Memory(_, vmem, _), rights, .. })
+            if *rights & CapabilityRights::MAP =>
+        {
             let memory_perms = match (*rights & CapabilityRights::READ, *rights & CapabilityRights::WRITE) {
                 (true, true) => MemoryPermissions::READ | MemoryPermissions::WRITE,
                 (true, false) => MemoryPermissions::READ,
@@ -120,9 +182,72 @@ pub fn query_mem_cap(task: &mut Task, cptr: CapabilityPtr) -> SyscallOutcome {
     }
 }
 
+/// Claim the device at the FDT node path `node_path`, mapping its MMIO region
+/// and routing its interrupts to the calling task
+pub fn claim_device(task: &mut Task, node_path: ValidatedUserSlice<user::Read, u8>) -> SyscallOutcome {
+    let bytes = match node_path.to_vec() {
+        Ok(bytes) => bytes,
+        Err((addr, _)) => return SyscallOutcome::Err(KError::InvalidAccess(AccessError::Read(addr.as_ptr()))),
+    };
+
+    let node_path = match core::str::from_utf8(&bytes) {
+        Ok(s) => s,
+        Err(_) => {
+            log::error!("Invalid UTF-8 in FDT node name from process");
+            return SyscallOutcome::Err(KError::InvalidArgument(0));
+        }
+    };
+
+    let (device, first_claim) = match crate::device::claim(node_path, task.tid) {
+        Ok(claimed) => claimed,
+        Err(e) => {
+            log::debug!("Task {} can't claim {}: {:?}", task.name, node_path, e);
+            return SyscallOutcome::Err(KError::InvalidArgument(0));
+        }
+    };
+
+    // FIXME: what about multiple regions?
+    let Region { address, size } = device.regions[0];
+    let map_to = unsafe {
+        task.group.memory_manager.lock().map_mmio_device(PhysicalAddress::new(address), None, size, &device.path)
+    };
+
+    let interrupts = device.interrupts();
+    let cptr = task.cspace.mint(Capability {
+        resource: CapabilityResource::Mmio(map_to, interrupts.clone(), device.path.clone()),
+        rights: CapabilityRights::GRANT
+            | CapabilityRights::READ
+            | CapabilityRights::WRITE
+            | CapabilityRights::TRANSFER
+            | CapabilityRights::MAP,
+        badge: 0,
+    });
+
+    log::debug!("Giving interrupts for {} to task {}", device.path, task.name);
+    super::route_interrupts(&device.path, interrupts, first_claim);
+
+    SyscallOutcome::processed(cptr.value())
+}
+
+/// Let the interrupt `interrupt_id`, which the calling task was notified of,
+/// occur again
+pub fn complete_interrupt(task: &mut Task, interrupt_id: usize) -> SyscallOutcome {
+    match task.claimed_interrupts.remove(&interrupt_id) {
+        None => SyscallOutcome::Err(KError::InvalidArgument(0)),
+        Some(hart) => {
+            log::debug!("Task {} completing interrupt {}", task.name, interrupt_id);
+            crate::interrupts::complete_deferred(hart, interrupt_id);
+
+            SyscallOutcome::processed(())
+        }
+    }
+}
+
 pub fn query_mmio_cap(task: &mut Task, cptr: CapabilityPtr) -> SyscallOutcome {
     match task.cspace.resolve(cptr) {
-        Some(Capability { resource: CapabilityResource::Mmio(vmem, interrupts), rights }) => {
+        Some(Capability { resource: CapabilityResource::Mmio(vmem, interrupts, _), rights, .. })
+            if *rights & CapabilityRights::MAP =>
+        {
             let memory_perms = match (*rights & CapabilityRights::READ, *rights & CapabilityRights::WRITE) {
                 (true, true) => MemoryPermissions::READ | MemoryPermissions::WRITE,
                 (true, false) => MemoryPermissions::READ,
@@ -148,3 +273,331 @@ pub fn query_mmio_cap(task: &mut Task, cptr: CapabilityPtr) -> SyscallOutcome {
         _ => SyscallOutcome::Err(KError::InvalidArgument(0)),
     }
 }
+
+/// Seal the current contents of the memory the task allocated at `at` into a
+/// read-only memory capability, which is also mapped into the task. The memory
+/// is copied the next time it's written to, so the capability's view of it
+/// never changes.
+pub fn snapshot_memory(task: &mut Task, at: VirtualAddress) -> SyscallOutcome {
+    if at.is_kernel_region() {
+        return SyscallOutcome::Err(KError::InvalidArgument(0));
+    }
+
+    let mut memory_manager = task.group.memory_manager.lock();
+    let region = match memory_manager.snapshot_region(at) {
+        Some(region) => region,
+        None => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+
+    let range = memory_manager.apply_shared_region(
+        None,
+        flags::READ | flags::USER | flags::VALID,
+        region.clone(),
+        AddressRegionKind::ReadOnly,
+    );
+    drop(memory_manager);
+
+    let cptr = task.cspace.mint(Capability {
+        resource: CapabilityResource::Memory(region, range.clone(), AddressRegionKind::ReadOnly),
+        rights: CapabilityRights::READ | CapabilityRights::MAP | CapabilityRights::TRANSFER,
+        badge: 0,
+    });
+
+    SyscallOutcome::processed((cptr.value(), range.start.as_usize(), range.end.as_usize() - range.start.as_usize()))
+}
+
+pub fn set_interrupt_rate_limit(
+    task: &mut Task,
+    cptr: CapabilityPtr,
+    interrupt_id: usize,
+    limit: InterruptRateLimit,
+) -> SyscallOutcome {
+    match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Mmio(_, interrupts, _), rights, .. })
+            if *rights & CapabilityRights::WRITE =>
+        {
+            if !interrupts.contains(&interrupt_id) {
+                return SyscallOutcome::Err(KError::InvalidArgument(1));
+            }
+
+            crate::interrupts::rate_limit::set_limit(interrupt_id, limit);
+            SyscallOutcome::processed(())
+        }
+        _ => SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+}
+
+pub fn set_memory_permissions(
+    task: &mut Task,
+    start: VirtualAddress,
+    len: usize,
+    permissions: MemoryPermissions,
+) -> SyscallOutcome {
+    let mut memory_manager = task.group.memory_manager.lock();
+    let range = match owned_range(&mut memory_manager, start, len) {
+        Ok(range) => range,
+        Err(e) => return SyscallOutcome::Err(e),
+    };
+
+    // Memory can be either writable or executable, but never both at once
+    if permissions & MemoryPermissions::WRITE && permissions & MemoryPermissions::EXECUTE {
+        return SyscallOutcome::Err(KError::InvalidArgument(2));
+    }
+
+    let mut flags = flags::READ;
+
+    if permissions & MemoryPermissions::WRITE {
+        flags |= flags::WRITE;
+    }
+
+    if permissions & MemoryPermissions::EXECUTE {
+        flags |= flags::EXECUTE;
+    }
+
+    log::debug!("Setting permissions of {:#p}-{:#p} to {:?} for task {}", range.start, range.end, flags, task.name);
+    memory_manager.modify_page_permissions(range, flags);
+
+    SyscallOutcome::processed(())
+}
+
+/// Make memory the task wrote code into read-only and executable, making sure
+/// no hart runs stale instructions from it afterwards
+pub fn finalize_executable(task: &mut Task, start: VirtualAddress, len: usize) -> SyscallOutcome {
+    let mut memory_manager = task.group.memory_manager.lock();
+    let range = match owned_range(&mut memory_manager, start, len) {
+        Ok(range) => range,
+        Err(e) => return SyscallOutcome::Err(e),
+    };
+
+    log::debug!("Finalizing {:#p}-{:#p} as executable for task {}", range.start, range.end, task.name);
+    memory_manager.modify_page_permissions(range, flags::READ | flags::EXECUTE);
+    drop(memory_manager);
+
+    // The task's threads may have written the code on any hart, and may run it
+    // on any hart, so every instruction cache needs to see it
+    tlb::sync_instruction_caches();
+
+    SyscallOutcome::processed(())
+}
+
+/// Validate `start` and `len` as a kilopage aligned range of memory which
+/// belongs to the task alone, returning the range rounded up to whole pages
+fn owned_range(
+    memory_manager: &mut MemoryManager,
+    start: VirtualAddress,
+    len: usize,
+) -> Result<Range<VirtualAddress>, KError> {
+    // Snapshotted memory is shared with the snapshot until it's copied
+    memory_manager.break_snapshot(start);
+
+    let region = match memory_manager.region_for(start) {
+        Some(region) if !start.is_kernel_region() => region,
+        _ => return Err(KError::InvalidArgument(0)),
+    };
+
+    // Only allow changing memory which is solely owned by the task, otherwise
+    // it could e.g. give itself write access to a read-only memory capability
+    match (&region.region, region.kind) {
+        (
+            Some(MemoryRegion::Backed(PhysicalRegion::Unique(_))),
+            AddressRegionKind::Data
+            | AddressRegionKind::ReadOnly
+            | AddressRegionKind::Stack
+            | AddressRegionKind::Text
+            | AddressRegionKind::Tls
+            | AddressRegionKind::UserAllocated,
+        ) => {}
+        _ => return Err(KError::InvalidArgument(0)),
+    }
+
+    // Regions backed by large pages are split up as needed, so permissions can
+    // always be changed with kilopage granularity
+    if !start.is_aligned(PageSize::Kilopage) {
+        return Err(KError::InvalidArgument(0));
+    }
+
+    match start.checked_add(utils::round_up_to_next(len, PageSize::Kilopage.to_byte_size())) {
+        Some(end) if len != 0 && end <= region.span.end => Ok(start..end),
+        _ => Err(KError::InvalidArgument(1)),
+    }
+}
+
+pub fn query_address_space(
+    task: &mut Task,
+    cptr: Option<CapabilityPtr>,
+    buffer: ValidatedUserSlice<user::ReadWrite, RegionInfo>,
+) -> SyscallOutcome {
+    let regions = match regions_for(task, cptr) {
+        Ok(regions) => regions,
+        Err(outcome) => return outcome,
+    };
+
+    match copy_to_user(buffer, &regions) {
+        Ok(n_written) => SyscallOutcome::processed((n_written, regions.len())),
+        Err(e) => SyscallOutcome::Err(e),
+    }
+}
+
+/// Serialize the occupied regions of an address space in the format described
+/// by [`librust::syscalls::mem::snapshot_address_space`]
+pub fn snapshot_address_space(
+    task: &mut Task,
+    cptr: Option<CapabilityPtr>,
+    buffer: ValidatedUserSlice<user::ReadWrite, u8>,
+) -> SyscallOutcome {
+    let snapshot = match regions_for(task, cptr) {
+        Ok(regions) => encode_snapshot(&regions),
+        Err(outcome) => return outcome,
+    };
+
+    match copy_to_user(buffer, &snapshot) {
+        Ok(n_written) => SyscallOutcome::processed((n_written, snapshot.len())),
+        Err(e) => SyscallOutcome::Err(e),
+    }
+}
+
+/// Name the region of the current task's address space containing `at`, or
+/// clear its name if it's empty
+pub fn name_region(task: &mut Task, at: VirtualAddress, name: ValidatedUserSlice<user::Read, u8>) -> SyscallOutcome {
+    if at.is_kernel_region() {
+        return SyscallOutcome::Err(KError::InvalidArgument(0));
+    }
+
+    if name.len() > MAX_REGION_NAME_LEN {
+        return SyscallOutcome::Err(KError::InvalidArgument(2));
+    }
+
+    // The name is the second and third arguments here
+    let name = match super::thread::read_name(&name) {
+        Ok(name) => Some(name).filter(|name| !name.is_empty()),
+        Err(KError::InvalidArgument(n)) => return SyscallOutcome::Err(KError::InvalidArgument(n + 1)),
+        Err(e) => return SyscallOutcome::Err(e),
+    };
+
+    match task.group.memory_manager.lock().name_region(at, name) {
+        true => SyscallOutcome::processed(()),
+        false => SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+}
+
+/// The occupied regions of the current task's address space, or of the task
+/// on the other end of the `GRANT` channel `cptr`
+fn regions_for(task: &mut Task, cptr: Option<CapabilityPtr>) -> Result<Vec<RegionInfo>, SyscallOutcome> {
+    let tid = match cptr {
+        None => task.tid,
+        Some(cptr) => match task.cspace.resolve(cptr) {
+            Some(Capability { resource: CapabilityResource::Channel(cid), rights, .. })
+                if *rights & CapabilityRights::GRANT =>
+            {
+                task.channels.get(cid).unwrap().0
+            }
+            _ => return Err(SyscallOutcome::Err(KError::InvalidArgument(0))),
+        },
+    };
+
+    // A channel to the current task leads back to its own address space, and
+    // its lock is already held
+    if tid == task.tid {
+        return Ok(region_info(&task.group.memory_manager.lock()));
+    }
+
+    let other_task = TASKS.get(tid).ok_or(SyscallOutcome::Err(KError::InvalidArgument(0)))?;
+    let group = match super::lock_other(task, tid, &other_task) {
+        Some(other_task) => Arc::clone(&other_task.group),
+        None => return Err(SyscallOutcome::Retry),
+    };
+
+    Ok(region_info(&group.memory_manager.lock()))
+}
+
+/// Copy as much of `items` as fits into `buffer`, returning how many were
+/// written
+pub(super) fn copy_to_user<T: Copy>(
+    mut buffer: ValidatedUserSlice<user::ReadWrite, T>,
+    items: &[T],
+) -> Result<usize, KError> {
+    match buffer.copy_to_user(0, items) {
+        Ok(n_written) => Ok(n_written),
+        Err((addr, _)) => Err(KError::InvalidAccess(AccessError::Write(addr.as_mut_ptr()))),
+    }
+}
+
+fn encode_snapshot(regions: &[RegionInfo]) -> Vec<u8> {
+    fn push_varint(out: &mut Vec<u8>, mut n: usize) {
+        loop {
+            let byte = (n & 0x7F) as u8;
+            n >>= 7;
+            match n {
+                0 => return out.push(byte),
+                _ => out.push(byte | 0x80),
+            }
+        }
+    }
+
+    const PAGE_SHIFT: usize = 12;
+
+    let mut out = Vec::with_capacity(8 + regions.len() * 6);
+    out.extend_from_slice(&SNAPSHOT_MAGIC);
+    out.push(SNAPSHOT_VERSION);
+    push_varint(&mut out, regions.len());
+
+    let mut previous_end = 0;
+    for region in regions {
+        let start = region.start >> PAGE_SHIFT;
+        let end = (region.start + region.len) >> PAGE_SHIFT;
+        push_varint(&mut out, start - previous_end);
+        push_varint(&mut out, end - start);
+
+        let permissions = region.readable as u8 | (region.writable as u8) << 1 | (region.executable as u8) << 2;
+        out.push(permissions | (region.kind as u8) << 3);
+
+        previous_end = end;
+    }
+
+    out
+}
+
+pub(crate) fn region_info(memory_manager: &MemoryManager) -> Vec<RegionInfo> {
+    memory_manager
+        .occupied_regions()
+        .map(|region| {
+            let page_flags = memory_manager.page_flags(region.span.start);
+            let has = |flag| page_flags.map(|f| f & flag).unwrap_or(false);
+
+            // Names given by the kernel, like device paths, can be longer
+            // than the ones tasks can give, so they're cut short
+            let mut name = [0; MAX_REGION_NAME_LEN];
+            if let Some(region_name) = &region.name {
+                let mut len = region_name.len().min(MAX_REGION_NAME_LEN);
+                while !region_name.is_char_boundary(len) {
+                    len -= 1;
+                }
+
+                name[..len].copy_from_slice(&region_name.as_bytes()[..len]);
+            }
+
+            RegionInfo {
+                start: region.span.start.as_usize(),
+                len: region.span.end.as_usize() - region.span.start.as_usize(),
+                readable: has(flags::READ),
+                writable: has(flags::WRITE),
+                executable: has(flags::EXECUTE),
+                kind: match region.kind {
+                    AddressRegionKind::Channel => RegionKind::Channel,
+                    AddressRegionKind::Data => RegionKind::Data,
+                    AddressRegionKind::Guard => RegionKind::Guard,
+                    AddressRegionKind::ReadOnly => RegionKind::ReadOnly,
+                    AddressRegionKind::Stack => RegionKind::Stack,
+                    AddressRegionKind::Text => RegionKind::Text,
+                    AddressRegionKind::Tls => RegionKind::Tls,
+                    AddressRegionKind::Unoccupied => RegionKind::Unoccupied,
+                    AddressRegionKind::UserAllocated => RegionKind::UserAllocated,
+                    AddressRegionKind::Dma => RegionKind::Dma,
+                    AddressRegionKind::Mmio => RegionKind::Mmio,
+                    AddressRegionKind::TimePage => RegionKind::TimePage,
+                },
+                name,
+            }
+        })
+        .collect()
+}
@@ -7,56 +7,572 @@
 
 use super::SyscallOutcome;
 use crate::{
-    io::{ConsoleDevice, INPUT_QUEUE},
-    mem::{paging::VirtualAddress, user::RawUserSlice},
+    capabilities::{Capability, CapabilityResource},
+    io::{
+        line_discipline,
+        mux::{self, ConsoleStream, PipeEnd},
+    },
+    mem::{
+        manager::AddressRegionKind,
+        paging::{flags, VirtualAddress},
+        user::{self, RawUserPtr, ValidatedUserSlice},
+    },
     task::Task,
+    utils::Units,
 };
 use librust::{
+    capabilities::{CapabilityPtr, CapabilityRights},
     error::{AccessError, KError},
     message::Message,
+    syscalls::{
+        io::{ConsoleMode, ConsoleStreamKind, StdioStream},
+        mem::RegionInfo,
+        perf::{PerfEvent, PerfSample},
+        system::{CpuGovernor, CrashInfo, KernelMetric, ResetKind},
+        trace::TraceRecord,
+    },
 };
 
-pub fn print(task: &mut Task, start: VirtualAddress, len: usize) -> SyscallOutcome {
-    let user_slice = RawUserSlice::readable(start, len);
-    let user_slice = match unsafe { user_slice.validate(&task.memory_manager) } {
-        Ok(slice) => slice,
-        Err((addr, e)) => {
+/// Bytes copied through the kernel at a time for the console syscalls, so that
+/// a large buffer doesn't need an equally large kernel allocation
+const CHUNK_SIZE: usize = 256;
+
+pub fn print(task: &mut Task, user_slice: ValidatedUserSlice<user::Read, u8>) -> SyscallOutcome {
+    log::trace!("Attempting to print user memory (len={})", user_slice.len());
+
+    match write_from_user(&user_slice, |bytes| task.stdout.write(bytes)) {
+        Ok(n_written) => SyscallOutcome::Processed(Message::from(n_written)),
+        Err(addr) => SyscallOutcome::Err(KError::InvalidAccess(AccessError::Read(addr.as_ptr()))),
+    }
+}
+
+pub fn read_stdin(task: &mut Task, mut user_slice: ValidatedUserSlice<user::ReadWrite, u8>) -> SyscallOutcome {
+    log::trace!("Attempting to write to user memory (len={})", user_slice.len());
+    if task.stdin.is_interactive() {
+        line_discipline::reader_foreground(task.tid);
+        line_discipline::set_mode(task.console_mode);
+    }
+
+    match read_to_user(&mut user_slice, |bytes| task.stdin.read(bytes)) {
+        Ok(0) if !user_slice.is_empty() && !task.stdin.is_closed() => SyscallOutcome::Err(KError::WouldBlock),
+        Ok(n_written) => SyscallOutcome::Processed(Message::from(n_written)),
+        Err(addr) => SyscallOutcome::Err(KError::InvalidAccess(AccessError::Write(addr.as_mut_ptr()))),
+    }
+}
+
+pub fn system_info(task: &mut Task, ptr: VirtualAddress) -> SyscallOutcome {
+    let user_ptr = RawUserPtr::writable(ptr);
+    let mut user_ptr = match unsafe { user_ptr.validate(&mut task.group.memory_manager.lock()) } {
+        Ok(ptr) => ptr,
+        Err(e) => {
             log::error!("Bad memory from process: {:?}", e);
-            return SyscallOutcome::Err(KError::InvalidAccess(AccessError::Read(addr.as_ptr())));
+            return SyscallOutcome::Err(KError::InvalidAccess(AccessError::Write(ptr.as_mut_ptr())));
         }
     };
 
-    log::trace!("Attempting to print memory at {:#p} (len={})", start, len);
+    match user_ptr.write(crate::platform::system_info()) {
+        Ok(()) => SyscallOutcome::Processed(Message::default()),
+        Err(_) => SyscallOutcome::Err(KError::InvalidAccess(AccessError::Write(ptr.as_mut_ptr()))),
+    }
+}
+
+pub fn read_kernel_metrics(_: &mut Task, buffer: ValidatedUserSlice<user::ReadWrite, KernelMetric>) -> SyscallOutcome {
+    let metrics = crate::metrics::snapshot();
+    match super::mem::copy_to_user(buffer, &metrics) {
+        Ok(n_written) => SyscallOutcome::processed((n_written, metrics.len())),
+        Err(e) => SyscallOutcome::Err(e),
+    }
+}
 
-    let mut console = crate::io::CONSOLE.lock();
-    user_slice.with(|bytes| bytes.iter().copied().for_each(|b| console.write(b)));
+pub fn get_random(_: &mut Task, mut user_slice: ValidatedUserSlice<user::ReadWrite, u8>) -> SyscallOutcome {
+    let result = read_to_user(&mut user_slice, |bytes| {
+        crate::crypto::rand::fill_bytes(bytes);
+        bytes.len()
+    });
 
-    SyscallOutcome::Processed(Message::default())
+    match result {
+        Ok(n_written) => SyscallOutcome::processed(n_written),
+        Err(addr) => SyscallOutcome::Err(KError::InvalidAccess(AccessError::Write(addr.as_mut_ptr()))),
+    }
 }
 
-pub fn read_stdin(task: &mut Task, start: VirtualAddress, len: usize) -> SyscallOutcome {
-    let user_slice = RawUserSlice::writable(start, len);
-    let mut user_slice = match unsafe { user_slice.validate(&task.memory_manager) } {
-        Ok(slice) => slice,
-        Err((addr, e)) => {
-            log::error!("Bad memory from process: {:?}", e);
-            return SyscallOutcome::Err(KError::InvalidAccess(AccessError::Write(addr.as_mut_ptr())));
-        }
+/// Override the CPU frequency governor, which needs the system control
+/// capability
+pub fn set_cpu_governor(task: &mut Task, cptr: CapabilityPtr, kind: usize, hz: usize) -> SyscallOutcome {
+    match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::SystemControl, rights, .. })
+            if *rights & CapabilityRights::WRITE => {}
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+
+    let governor = match CpuGovernor::from_raw(kind, hz) {
+        Some(governor) => governor,
+        None => return SyscallOutcome::Err(KError::InvalidArgument(1)),
     };
 
-    log::trace!("Attempting to write to memory at {:#p} (len={})", start, len);
+    match crate::platform::cpufreq::set_governor(governor) {
+        Some(hz) => SyscallOutcome::processed(hz as usize),
+        // No way to change the CPU frequency on this platform
+        None => SyscallOutcome::Err(KError::InvalidArgument(1)),
+    }
+}
 
-    let mut n_written = 0;
-    user_slice.with(|bytes| {
-        for byte in bytes {
-            let value = match INPUT_QUEUE.pop() {
-                Some(v) => v,
-                None => break,
+/// Shut down or reboot the system, which needs the system control capability
+pub fn system_reset(task: &mut Task, cptr: CapabilityPtr, kind: usize) -> SyscallOutcome {
+    match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::SystemControl, rights, .. })
+            if *rights & CapabilityRights::WRITE => {}
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+
+    let kind = match ResetKind::from_raw(kind) {
+        Some(kind) => kind,
+        None => return SyscallOutcome::Err(KError::InvalidArgument(1)),
+    };
+
+    log::info!("System reset ({:?}) requested by task {}", kind, task.display_name());
+    crate::platform::reset(kind)
+}
+
+/// Copy out the oldest recorded crash with an ID of at least `min_id`, which
+/// needs the system control capability
+pub fn read_crash_dump(
+    task: &mut Task,
+    cptr: CapabilityPtr,
+    min_id: usize,
+    info: VirtualAddress,
+    regions: ValidatedUserSlice<user::ReadWrite, RegionInfo>,
+) -> SyscallOutcome {
+    match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::SystemControl, rights, .. })
+            if *rights & CapabilityRights::READ => {}
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+
+    let crash = match crate::crash::find(min_id) {
+        Some(crash) => crash,
+        None => return SyscallOutcome::processed((0usize, 0usize, 0usize)),
+    };
+
+    let user_ptr = RawUserPtr::<user::ReadWrite, CrashInfo>::writable(info);
+    let written = match unsafe { user_ptr.validate(&mut task.group.memory_manager.lock()) } {
+        Ok(mut user_ptr) => user_ptr.write(crash.info),
+        Err(e) => Err(e),
+    };
+
+    if written.is_err() {
+        return SyscallOutcome::Err(KError::InvalidAccess(AccessError::Write(info.as_mut_ptr())));
+    }
+
+    match super::mem::copy_to_user(regions, &crash.regions) {
+        Ok(n_written) => SyscallOutcome::processed((1usize, n_written, crash.regions.len())),
+        Err(e) => SyscallOutcome::Err(e),
+    }
+}
+
+/// Start a profiling session, which needs the system control capability.
+/// Returns a capability to read the samples with.
+pub fn perf_start(task: &mut Task, cptr: CapabilityPtr, event: usize, period: usize) -> SyscallOutcome {
+    match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::SystemControl, rights, .. })
+            if *rights & (CapabilityRights::READ | CapabilityRights::WRITE) => {}
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+
+    let event = match PerfEvent::from_usize(event) {
+        Some(event) => event,
+        None => return SyscallOutcome::Err(KError::InvalidArgument(1)),
+    };
+
+    if !crate::perf::available() || period == 0 {
+        return SyscallOutcome::Err(KError::InvalidArgument(2));
+    }
+
+    log::info!("Profiling {:?} every {} events, started by task {}", event, period, task.display_name());
+    crate::perf::start(event, period);
+
+    let cptr = task.cspace.mint(Capability {
+        resource: CapabilityResource::Perf,
+        rights: CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::TRANSFER,
+        badge: 0,
+    });
+
+    SyscallOutcome::processed(cptr.value())
+}
+
+/// Copy out as many profiling samples as fit into `samples`
+pub fn perf_read(
+    task: &mut Task,
+    cptr: CapabilityPtr,
+    samples: ValidatedUserSlice<user::ReadWrite, PerfSample>,
+) -> SyscallOutcome {
+    match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Perf, rights, .. }) if *rights & CapabilityRights::READ => {}
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+
+    let (taken, dropped) = crate::perf::take(samples.len());
+    match super::mem::copy_to_user(samples, &taken) {
+        Ok(n_written) => SyscallOutcome::processed((n_written, dropped)),
+        Err(e) => SyscallOutcome::Err(e),
+    }
+}
+
+/// Copy out as many trace records as fit into `records`, which needs the
+/// system control capability
+pub fn read_trace(
+    task: &mut Task,
+    cptr: CapabilityPtr,
+    records: ValidatedUserSlice<user::ReadWrite, TraceRecord>,
+) -> SyscallOutcome {
+    match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::SystemControl, rights, .. })
+            if *rights & CapabilityRights::READ => {}
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+
+    let (taken, dropped) = crate::trace::take(records.len());
+    match super::mem::copy_to_user(records, &taken) {
+        Ok(n_written) => SyscallOutcome::processed((n_written, dropped)),
+        Err(e) => SyscallOutcome::Err(e),
+    }
+}
+
+/// Copy the kernel's edge coverage flags from index `start` onwards into
+/// `flags`, returning how many were copied and how many there are in total
+pub fn read_coverage(
+    task: &mut Task,
+    cptr: CapabilityPtr,
+    start: usize,
+    flags: ValidatedUserSlice<user::ReadWrite, u8>,
+) -> SyscallOutcome {
+    match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::SystemControl, rights, .. })
+            if *rights & CapabilityRights::READ => {}
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+
+    let read = crate::coverage::read(start, flags.len());
+    match super::mem::copy_to_user(flags, &read) {
+        Ok(n_written) => SyscallOutcome::processed((n_written, crate::coverage::len())),
+        Err(e) => SyscallOutcome::Err(e),
+    }
+}
+
+pub fn reset_coverage(task: &mut Task, cptr: CapabilityPtr) -> SyscallOutcome {
+    match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::SystemControl, rights, .. })
+            if *rights & CapabilityRights::WRITE => {}
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+
+    crate::coverage::reset();
+    SyscallOutcome::processed(())
+}
+
+pub fn perf_stop(task: &mut Task, cptr: CapabilityPtr) -> SyscallOutcome {
+    match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Perf, rights, .. }) if *rights & CapabilityRights::WRITE => {}
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+
+    crate::perf::stop();
+    SyscallOutcome::processed(())
+}
+
+/// Map the kernel log ring into the task, the control page writable so the
+/// task can update how far it's read and the log itself read-only
+pub fn map_kernel_log(task: &mut Task, cptr: CapabilityPtr) -> SyscallOutcome {
+    match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::KernelLog, rights, .. })
+            if *rights & CapabilityRights::READ => {}
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+
+    let (control, data) = match crate::io::klog::regions() {
+        Some(regions) => regions,
+        None => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+
+    let mut memory_manager = task.group.memory_manager.lock();
+    let control = memory_manager.apply_shared_region(
+        None,
+        flags::USER | flags::READ | flags::WRITE | flags::VALID,
+        control,
+        AddressRegionKind::Data,
+    );
+    let data_len = data.n_pages() * 4.kib();
+    let data = memory_manager.apply_shared_region(
+        None,
+        flags::USER | flags::READ | flags::VALID,
+        data,
+        AddressRegionKind::ReadOnly,
+    );
+
+    SyscallOutcome::processed((control.start.as_usize(), data.start.as_usize(), data_len))
+}
+
+pub fn set_console_mode(task: &mut Task, mode: usize) -> SyscallOutcome {
+    let mode = match ConsoleMode::from_raw(mode) {
+        Some(mode) => mode,
+        None => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+
+    task.console_mode = mode;
+    if line_discipline::foreground() == Some(task.tid) {
+        line_discipline::set_mode(mode);
+    }
+
+    SyscallOutcome::processed(())
+}
+
+/// Designate the task on the other end of the channel `cptr` as the console's
+/// foreground task, or the current task if `cptr` is `usize::MAX`
+pub fn set_foreground_task(task: &mut Task, cptr: usize) -> SyscallOutcome {
+    if cptr == usize::MAX {
+        line_discipline::set_foreground(task.tid);
+        line_discipline::set_mode(task.console_mode);
+        return SyscallOutcome::processed(());
+    }
+
+    let (tid, peer) = match super::exit::peer_of(task, CapabilityPtr::new(cptr)) {
+        Ok(peer) => peer,
+        Err(e) => return SyscallOutcome::Err(e),
+    };
+
+    let peer = match super::lock_other(task, tid, &peer) {
+        Some(peer) => peer,
+        None => return SyscallOutcome::Retry,
+    };
+
+    if peer.state.is_dead() {
+        return SyscallOutcome::Err(KError::InvalidArgument(0));
+    }
+
+    line_discipline::set_foreground(peer.tid);
+    line_discipline::set_mode(peer.console_mode);
+
+    SyscallOutcome::processed(())
+}
+
+pub fn create_console_stream(task: &mut Task, kind: usize, id: usize) -> SyscallOutcome {
+    let stream = match ConsoleStreamKind::from_raw(kind, id) {
+        Some(ConsoleStreamKind::Pipe) => ConsoleStream::Pipe(PipeEnd::loopback()),
+        Some(ConsoleStreamKind::Virtual(id)) => match mux::virtual_console(id) {
+            Some(vc) => ConsoleStream::Virtual(vc),
+            None => return SyscallOutcome::Err(KError::InvalidArgument(1)),
+        },
+        Some(ConsoleStreamKind::Null) => ConsoleStream::Null,
+        None => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+
+    let cptr = task.cspace.mint(Capability {
+        resource: CapabilityResource::ConsoleStream(stream),
+        rights: CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::TRANSFER,
+        badge: 0,
+    });
+
+    SyscallOutcome::processed(cptr.value())
+}
+
+/// Bind the stdin or stdout of the task on the other end of the channel `cptr`,
+/// or the current task if `cptr` is `usize::MAX`, to the console stream
+/// `stream_cptr`. A `stream_cptr` of `usize::MAX` binds it to the current
+/// task's own stream instead.
+pub fn bind_stdio(task: &mut Task, cptr: usize, which: usize, stream_cptr: usize) -> SyscallOutcome {
+    let which = match StdioStream::from_raw(which) {
+        Some(which) => which,
+        None => return SyscallOutcome::Err(KError::InvalidArgument(1)),
+    };
+
+    let stream = match stream_cptr {
+        usize::MAX => match which {
+            StdioStream::Stdin => task.stdin.clone(),
+            StdioStream::Stdout => task.stdout.clone(),
+        },
+        _ => {
+            let required = match which {
+                StdioStream::Stdin => CapabilityRights::READ,
+                StdioStream::Stdout => CapabilityRights::WRITE,
             };
-            *byte = value;
-            n_written += 1;
+
+            match task.cspace.resolve(CapabilityPtr::new(stream_cptr)) {
+                Some(Capability { resource: CapabilityResource::ConsoleStream(stream), rights, .. })
+                    if *rights & required =>
+                {
+                    stream.clone()
+                }
+                _ => return SyscallOutcome::Err(KError::InvalidArgument(2)),
+            }
+        }
+    };
+
+    if cptr == usize::MAX {
+        match which {
+            StdioStream::Stdin => task.stdin = stream,
+            StdioStream::Stdout => task.stdout = stream,
+        }
+
+        return SyscallOutcome::processed(());
+    }
+
+    let (tid, peer) = match super::exit::peer_of(task, CapabilityPtr::new(cptr)) {
+        Ok(peer) => peer,
+        Err(e) => return SyscallOutcome::Err(e),
+    };
+
+    let mut peer = match super::lock_other(task, tid, &peer) {
+        Some(peer) => peer,
+        None => return SyscallOutcome::Retry,
+    };
+
+    if peer.state.is_dead() {
+        return SyscallOutcome::Err(KError::InvalidArgument(0));
+    }
+
+    match which {
+        StdioStream::Stdin => peer.stdin = stream,
+        StdioStream::Stdout => peer.stdout = stream,
+    }
+
+    SyscallOutcome::processed(())
+}
+
+pub fn read_console_stream(
+    task: &mut Task,
+    cptr: CapabilityPtr,
+    mut user_slice: ValidatedUserSlice<user::ReadWrite, u8>,
+) -> SyscallOutcome {
+    let stream = match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::ConsoleStream(stream), rights, .. })
+            if *rights & CapabilityRights::READ =>
+        {
+            stream.clone()
         }
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+
+    // Nothing read means the end of the stream once the other end is gone, or
+    // that there's nothing to read yet otherwise
+    match read_to_user(&mut user_slice, |bytes| stream.read(bytes)) {
+        Ok(0) if !user_slice.is_empty() && !stream.is_closed() => SyscallOutcome::Err(KError::WouldBlock),
+        Ok(n_read) => SyscallOutcome::processed(n_read),
+        Err(addr) => SyscallOutcome::Err(KError::InvalidAccess(AccessError::Write(addr.as_mut_ptr()))),
+    }
+}
+
+pub fn write_console_stream(
+    task: &mut Task,
+    cptr: CapabilityPtr,
+    user_slice: ValidatedUserSlice<user::Read, u8>,
+) -> SyscallOutcome {
+    let stream = match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::ConsoleStream(stream), rights, .. })
+            if *rights & CapabilityRights::WRITE =>
+        {
+            stream.clone()
+        }
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+
+    match write_from_user(&user_slice, |bytes| stream.write(bytes)) {
+        Ok(0) if !user_slice.is_empty() && !stream.is_closed() => SyscallOutcome::Err(KError::WouldBlock),
+        Ok(n_written) => SyscallOutcome::processed(n_written),
+        Err(addr) => SyscallOutcome::Err(KError::InvalidAccess(AccessError::Read(addr.as_ptr()))),
+    }
+}
+
+/// Create a pipe, returning a capability to its read end and one to its write
+/// end. Either can be bound to a task's stdin or stdout like any other console
+/// stream.
+pub fn create_pipe(task: &mut Task) -> SyscallOutcome {
+    let (reader, writer) = PipeEnd::pair();
+    let reader = task.cspace.mint(Capability {
+        resource: CapabilityResource::ConsoleStream(ConsoleStream::Pipe(reader)),
+        rights: CapabilityRights::READ | CapabilityRights::TRANSFER,
+        badge: 0,
+    });
+    let writer = task.cspace.mint(Capability {
+        resource: CapabilityResource::ConsoleStream(ConsoleStream::Pipe(writer)),
+        rights: CapabilityRights::WRITE | CapabilityRights::TRANSFER,
+        badge: 0,
     });
 
-    SyscallOutcome::Processed(Message::from(n_written))
+    SyscallOutcome::processed((reader.value(), writer.value()))
+}
+
+/// Remove a console stream capability, which closes its end of a pipe once no
+/// other copies of it are left
+pub fn close_console_stream(task: &mut Task, cptr: CapabilityPtr) -> SyscallOutcome {
+    match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::ConsoleStream(_), .. }) => {
+            task.cspace.remove(cptr);
+            SyscallOutcome::processed(())
+        }
+        _ => SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+}
+
+pub fn push_console_input(_: &mut Task, user_slice: ValidatedUserSlice<user::Read, u8>) -> SyscallOutcome {
+    let bytes = match user_slice.to_vec() {
+        Ok(bytes) => bytes,
+        Err((addr, _)) => return SyscallOutcome::Err(KError::InvalidAccess(AccessError::Read(addr.as_ptr()))),
+    };
+
+    let result = bytes.iter().try_for_each(|&b| crate::io::receive_input(b));
+
+    match result {
+        Ok(()) => SyscallOutcome::processed(()),
+        Err(e) => {
+            log::debug!("Dropping console input: {}", e);
+            SyscallOutcome::Err(KError::InvalidArgument(1))
+        }
+    }
+}
+
+/// Hand the bytes of `user_slice` to `write` a chunk at a time, until either
+/// all of them are written or `write` takes fewer than it was given, returning
+/// how many were written
+fn write_from_user(
+    user_slice: &ValidatedUserSlice<user::Read, u8>,
+    mut write: impl FnMut(&[u8]) -> usize,
+) -> Result<usize, VirtualAddress> {
+    let mut chunk = [0; CHUNK_SIZE];
+    let mut n_written = 0;
+
+    while n_written < user_slice.len() {
+        let n = user_slice.copy_from_user(n_written, &mut chunk).map_err(|(addr, _)| addr)?;
+        let written = write(&chunk[..n]);
+        n_written += written;
+
+        if written < n {
+            break;
+        }
+    }
+
+    Ok(n_written)
+}
+
+/// Fill `user_slice` with whatever `read` gives a chunk at a time, until either
+/// it's full or `read` gives less than a whole chunk, returning how many bytes
+/// were read
+fn read_to_user(
+    user_slice: &mut ValidatedUserSlice<user::ReadWrite, u8>,
+    mut read: impl FnMut(&mut [u8]) -> usize,
+) -> Result<usize, VirtualAddress> {
+    let mut chunk = [0; CHUNK_SIZE];
+    let mut n_read = 0;
+
+    while n_read < user_slice.len() {
+        let want = (user_slice.len() - n_read).min(CHUNK_SIZE);
+        let n = read(&mut chunk[..want]);
+        user_slice.copy_to_user(n_read, &chunk[..n]).map_err(|(addr, _)| addr)?;
+        n_read += n;
+
+        if n < want {
+            break;
+        }
+    }
+
+    Ok(n_read)
 }
@@ -5,45 +5,55 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+mod args;
 pub mod channel;
+pub mod cspace;
+pub mod dma;
+pub mod events;
+pub mod exit;
+pub mod extension;
+pub mod hooks;
 pub mod mem;
 pub mod misc;
+pub mod poll;
+pub mod ring;
+pub mod taskgroup;
+pub mod thread;
 pub mod vmspace;
 
 use crate::{
-    capabilities::{Capability, CapabilityResource},
-    interrupts::PLIC,
-    io::CLAIMED_DEVICES,
-    mem::{
-        paging::{PhysicalAddress, VirtualAddress},
-        user::RawUserSlice,
-    },
-    platform::FDT,
+    csr::sstatus::{self, FloatingPointStatus},
+    interrupts::isr::{self, IsrStatus},
     scheduler::{Scheduler, WakeToken, SCHEDULER, TASKS},
     task::{Task, TaskState},
     trap::{GeneralRegisters, TrapFrame},
+    utils::SameHartDeadlockDetection,
     HART_ID,
 };
-use core::{convert::TryInto, sync::atomic::Ordering};
+use alloc::sync::Arc;
+use args::SyscallArgs;
+use core::convert::TryInto;
 use librust::{
-    capabilities::{CapabilityPtr, CapabilityRights},
-    error::{AccessError, KError},
+    error::KError,
     message::{KernelNotification, Message, Recipient, Sender, SyscallRequest},
-    syscalls::{
-        allocation::{AllocationOptions, DmaAllocationOptions, MemoryPermissions},
-        channel::MessageId,
-        vmspace::VmspaceObjectId,
-        Syscall,
-    },
-    task::Tid,
+    syscalls::{trace::TraceEvent, Syscall, EXTENSION_SYSCALLS},
+    task::{ExitStatus, Tid},
 };
+use sync::{SpinMutex, SpinMutexGuard};
 
 #[derive(Debug)]
 pub enum SyscallOutcome {
     Processed(Message),
     Err(KError),
     Block,
-    Kill,
+    Kill(ExitStatus),
+    /// The task's context was replaced, so it should be switched back to
+    /// instead of returning from the syscall
+    Resume,
+    /// Another task the syscall needs couldn't be locked without risking a
+    /// deadlock, so the syscall is made again once the task has been unlocked,
+    /// see [`lock_other`]
+    Retry,
 }
 
 impl SyscallOutcome {
@@ -63,27 +73,66 @@ pub fn handle(frame: &mut TrapFrame, sepc: usize) -> usize {
 
     match recipient {
         const { Recipient::kernel() } => {
-            match do_syscall(task, message) {
+            let (syscall, tid) = (message.contents[0], task.tid.value());
+            crate::trace::record(TraceEvent::SyscallEntry, tid, [syscall, 0]);
+
+            let result = do_syscall(task, message);
+            let status = match result.1 {
+                SyscallOutcome::Err(_) => 1,
+                SyscallOutcome::Block => 2,
+                _ => 0,
+            };
+            crate::trace::record(TraceEvent::SyscallExit, tid, [syscall, status]);
+
+            if let Some(syscall) = Syscall::from_usize(syscall) {
+                hooks::syscall_exit(task, syscall, &result.1);
+            }
+
+            match result {
                 (sender, SyscallOutcome::Processed(message)) => {
                     apply_message(false, sender, message, &mut frame.registers)
                 }
                 (_, SyscallOutcome::Err(e)) => report_error(e, &mut frame.registers),
                 (_, SyscallOutcome::Block) => {
-                    let tid = task.tid;
+                    let wait = task.wait_id();
                     log::trace!("Blocking task {:?}", task.name);
                     task.context.gp_regs = frame.registers;
 
+                    // Another task's floating point registers are loaded when
+                    // it's switched to, so they'd be lost otherwise
+                    if let FloatingPointStatus::Dirty = sstatus::fs() {
+                        crate::trap::save_fp_registers(&mut task.context.fp_regs);
+                    }
+
                     // Don't re-call the syscall after its unblocked
                     task.context.pc = sepc + 4;
 
                     drop(task_lock);
-                    SCHEDULER.block(tid);
+                    SCHEDULER.block(wait);
+                    SCHEDULER.schedule()
+                }
+                (_, SyscallOutcome::Resume) => {
+                    drop(task_lock);
                     SCHEDULER.schedule()
                 }
-                (_, SyscallOutcome::Kill) => {
-                    task.state = TaskState::Dead;
+                (_, SyscallOutcome::Retry) => {
+                    task.context.gp_regs = frame.registers;
+                    if let FloatingPointStatus::Dirty = sstatus::fs() {
+                        crate::trap::save_fp_registers(&mut task.context.fp_regs);
+                    }
+
+                    // Go back to the `ecall`, so the syscall is made again the
+                    // next time the task runs
+                    task.context.pc = sepc;
+
+                    drop(task_lock);
+                    SCHEDULER.schedule()
+                }
+                (_, SyscallOutcome::Kill(status)) => {
+                    let watchers = task.exit(status);
 
                     drop(task_lock);
+                    exit::notify_watchers(status, watchers);
                     SCHEDULER.schedule()
                 }
             }
@@ -110,37 +159,74 @@ pub fn handle(frame: &mut TrapFrame, sepc: usize) -> usize {
     sepc + 4
 }
 
+/// Lock `other`, the task with the ID `other_tid`, while `task` is already
+/// locked. Tasks are locked in order of their IDs when more than one is needed,
+/// so if `other` would have to be locked first it's only tried. `None` is
+/// returned if it's held, in which case the syscall should return
+/// [`SyscallOutcome::Retry`] rather than wait on a task which may be waiting on
+/// this one.
+pub(super) fn lock_other<'a>(
+    task: &Task,
+    other_tid: Tid,
+    other: &'a SpinMutex<Task, SameHartDeadlockDetection>,
+) -> Option<SpinMutexGuard<'a, Task, SameHartDeadlockDetection>> {
+    match other_tid > task.tid {
+        true => Some(other.lock()),
+        false => other.try_lock(),
+    }
+}
+
 fn do_syscall(task: &mut Task, msg: Message) -> (Sender, SyscallOutcome) {
     log::trace!("Doing syscall: {:?}", msg);
-
-    let mut sender = Sender::kernel();
+    crate::metrics::SYSCALLS.increment();
 
     let syscall_req = SyscallRequest {
         syscall: match Syscall::from_usize(msg.contents[0]) {
             Some(syscall) => syscall,
+            None if EXTENSION_SYSCALLS.contains(&msg.contents[0]) => {
+                return (Sender::kernel(), extension::dispatch(task, msg));
+            }
             None => return (Sender::kernel(), SyscallOutcome::Err(KError::InvalidSyscall(msg.contents[0]))),
         },
         arguments: msg.contents[1..].try_into().unwrap(),
     };
 
-    let outcome: SyscallOutcome = match syscall_req.syscall {
-        Syscall::Exit => {
-            log::debug!("Active process {:?} exited", task.name);
-            return (Sender::kernel(), SyscallOutcome::Kill);
-        }
-        Syscall::Print => misc::print(task, VirtualAddress::new(syscall_req.arguments[0]), syscall_req.arguments[1]),
-        Syscall::ReadStdin => {
-            misc::read_stdin(task, VirtualAddress::new(syscall_req.arguments[0]), syscall_req.arguments[1])
-        }
+    if let Err(e) = hooks::syscall_entry(task, &syscall_req) {
+        return (Sender::kernel(), SyscallOutcome::Err(e));
+    }
+
+    let args = SyscallArgs::new(syscall_req.arguments, Arc::clone(&task.group));
+    let mut sender = Sender::kernel();
+    let outcome = match dispatch(task, syscall_req.syscall, &args, &mut sender) {
+        Ok(outcome) => outcome,
+        Err(e) => SyscallOutcome::Err(e),
+    };
+
+    (sender, outcome)
+}
+
+/// Run the handler for `syscall`. The `Syscall` numbers are the table here, an
+/// argument which fails to convert, like a user slice which isn't mapped, fails
+/// the syscall before its handler runs.
+fn dispatch(
+    task: &mut Task,
+    syscall: Syscall,
+    args: &SyscallArgs,
+    sender: &mut Sender,
+) -> Result<SyscallOutcome, KError> {
+    let outcome = match syscall {
+        Syscall::Exit => exit::exit(task, args[0]),
+        Syscall::Print => misc::print(task, args.user_slice(0)?),
+        Syscall::ReadStdin => misc::read_stdin(task, args.user_slice(0)?),
         Syscall::ReadMessage => match task.message_queue.pop() {
             Some((sender_, msg)) => {
                 log::debug!("Message read for task {}", task.name);
-                sender = sender_;
+                *sender = sender_;
                 SyscallOutcome::Processed(msg)
             }
             None => {
                 log::debug!("Registering wake for read_message");
-                task.message_queue.register_wake(WakeToken::new(task.tid, |task| {
+                task.message_queue.register_wake(WakeToken::new(task.wait_id(), |task| {
                     log::debug!("Waking task for read_message");
                     task.state = TaskState::Running;
                     let (sender, message) = task.message_queue.pop().expect("woken but no messages in queue?");
@@ -149,181 +235,167 @@ fn do_syscall(task: &mut Task, msg: Message) -> (Sender, SyscallOutcome) {
                 SyscallOutcome::Block
             }
         },
-        Syscall::AllocVirtualMemory => mem::alloc_virtual_memory(
-            task,
-            syscall_req.arguments[0],
-            AllocationOptions::new(syscall_req.arguments[1]),
-            MemoryPermissions::new(syscall_req.arguments[2]),
-        ),
+        Syscall::AllocVirtualMemory => mem::alloc_virtual_memory(task, args[0], args.get(1), args.get(2)),
+        Syscall::DeallocVirtualMemory => mem::dealloc_virtual_memory(task, args.get(0)),
         Syscall::GetTid => SyscallOutcome::processed(task.tid.value()),
-        Syscall::CreateChannelMessage => {
-            channel::create_message(task, CapabilityPtr::new(syscall_req.arguments[0]), syscall_req.arguments[1])
-        }
-        Syscall::SendChannelMessage => channel::send_message(
-            task,
-            CapabilityPtr::new(syscall_req.arguments[0]),
-            MessageId::new(syscall_req.arguments[1]),
-            syscall_req.arguments[2],
-            RawUserSlice::new(VirtualAddress::new(syscall_req.arguments[3]), syscall_req.arguments[4]),
-        ),
-        Syscall::ReadChannel => channel::read_message(
-            task,
-            CapabilityPtr::new(syscall_req.arguments[0]),
-            RawUserSlice::new(VirtualAddress::new(syscall_req.arguments[1]), syscall_req.arguments[2]),
-        ),
-        Syscall::ReadChannelNonBlocking => channel::read_message_nb(
-            task,
-            CapabilityPtr::new(syscall_req.arguments[0]),
-            RawUserSlice::new(VirtualAddress::new(syscall_req.arguments[1]), syscall_req.arguments[2]),
-        ),
-        Syscall::RetireChannelMessage => channel::retire_message(
-            task,
-            CapabilityPtr::new(syscall_req.arguments[0]),
-            MessageId::new(syscall_req.arguments[1]),
-        ),
-        Syscall::AllocDmaMemory => {
-            mem::alloc_dma_memory(task, syscall_req.arguments[0], DmaAllocationOptions::new(syscall_req.arguments[1]))
+        Syscall::CreateChannelMessage => channel::create_message(task, args.get(0), args[1]),
+        Syscall::SendChannelMessage => {
+            channel::send_message(task, args.get(0), args.get(1), args[2], args.user_slice(3)?)
         }
+        Syscall::ReadChannel => channel::read_message(task, args.get(0), args.user_slice(1)?),
+        Syscall::ReadChannelNonBlocking => channel::read_message_nb(task, args.get(0), args.user_slice(1)?),
+        Syscall::RetireChannelMessage => channel::retire_message(task, args.get(0), args.get(1)),
+        Syscall::AllocDmaMemory => dma::alloc_dma_memory(task, args[0], args.get(1)),
         Syscall::CreateVmspace => vmspace::create_vmspace(task),
-        Syscall::QueryMemoryCapability => mem::query_mem_cap(task, CapabilityPtr::new(syscall_req.arguments[0])),
-        Syscall::AllocVmspaceObject => vmspace::alloc_vmspace_object(
-            task,
-            syscall_req.arguments[0],
-            syscall_req.arguments[1],
-            syscall_req.arguments[2],
-            syscall_req.arguments[3],
-        ),
+        Syscall::QueryMemoryCapability => mem::query_mem_cap(task, args.get(0)),
+        Syscall::AllocVmspaceObject => vmspace::alloc_vmspace_object(task, args[0], args[1], args[2], args[3]),
         Syscall::SpawnVmspace => vmspace::spawn_vmspace(
             task,
-            VmspaceObjectId::new(syscall_req.arguments[0]),
-            VirtualAddress::new(syscall_req.arguments[1]),
-            syscall_req.arguments[2],
-            syscall_req.arguments[3],
-            syscall_req.arguments[4],
-            syscall_req.arguments[5],
-            syscall_req.arguments[6],
-            syscall_req.arguments[7],
-            syscall_req.arguments[8],
+            args.get(0),
+            args.user_slice(1)?,
+            args[3],
+            args[4],
+            args[5],
+            args[6],
+            args[7],
+            args[8],
+            args[9],
+            args[10],
+            args.get(11),
         ),
-        Syscall::ClaimDevice => {
-            let start = VirtualAddress::new(syscall_req.arguments[0]);
-            let len = syscall_req.arguments[1];
-            let user_slice = RawUserSlice::readable(start, len);
-            let user_slice = match unsafe { user_slice.validate(&task.memory_manager) } {
-                Ok(slice) => slice,
-                Err((addr, e)) => {
-                    log::error!("Bad memory from process: {:?}", e);
-                    return (
-                        Sender::kernel(),
-                        SyscallOutcome::Err(KError::InvalidAccess(AccessError::Read(addr.as_ptr()))),
-                    );
-                }
-            };
+        Syscall::ClaimDevice => mem::claim_device(task, args.user_slice(0)?),
+        Syscall::CompleteInterrupt => mem::complete_interrupt(task, args[0]),
+        Syscall::QueryMmioCapability => mem::query_mmio_cap(task, args.get(0)),
+        Syscall::HandoffCapabilities => vmspace::handoff_capabilities(task, args.get(0), args.get(1)),
+        Syscall::SetMemoryPermissions => mem::set_memory_permissions(task, args.get(0), args[1], args.get(2)),
+        Syscall::SystemInfo => misc::system_info(task, args.get(0)),
+        Syscall::SetCpuGovernor => misc::set_cpu_governor(task, args.get(0), args[1], args[2]),
+        Syscall::SystemReset => misc::system_reset(task, args.get(0), args[1]),
+        Syscall::ReadCrashDump => misc::read_crash_dump(task, args.get(0), args[1], args.get(2), args.user_slice(3)?),
+        Syscall::MapKernelLog => misc::map_kernel_log(task, args.get(0)),
+        Syscall::QueryAddressSpace => mem::query_address_space(task, args.get(0), args.user_slice(1)?),
+        Syscall::SnapshotAddressSpace => mem::snapshot_address_space(task, args.get(0), args.user_slice(1)?),
+        Syscall::NameRegion => mem::name_region(task, args.get(0), args.user_slice(1)?),
+        Syscall::MapChannelRing => channel::map_ring(task, args.get(0), args[1]),
+        Syscall::NotifyChannelRing => channel::notify_ring(task, args.get(0)),
+        Syscall::WaitChannelRing => channel::wait_ring(task, args.get(0)),
+        Syscall::MintBadgedCapability => channel::mint_badged(task, args.get(0), args.get(1), args[2]),
+        Syscall::Call => channel::call(task, args.get(0), args.call_payload()),
+        Syscall::Reply => channel::reply(task, args.get(0), args.call_payload()),
+        Syscall::CreateThread => thread::create_thread(task, args.get(0), args.get(1), args[2], args[3], args.get(4)),
+        Syscall::FutexWait => thread::futex_wait(task, args.get(0), args.get(1)),
+        Syscall::FutexWake => thread::futex_wake(task, args.get(0), args[1]),
+        Syscall::Wait => exit::wait(task, args.get(0)),
+        Syscall::WatchExit => exit::watch_exit(task, args.get(0)),
+        Syscall::SendTaskEvent => events::send_task_event(task, args.get(0), args[1]),
+        Syscall::SetEventHandler => events::set_event_handler(task, args.get(0), args.get(1), args.get(2)),
+        Syscall::ReturnFromEvent => events::return_from_event(task),
+        Syscall::SetConsoleMode => misc::set_console_mode(task, args[0]),
+        Syscall::SetForegroundTask => misc::set_foreground_task(task, args[0]),
+        Syscall::SetInterruptRateLimit => mem::set_interrupt_rate_limit(task, args.get(0), args[1], args.rate_limit(2)),
+        Syscall::CreateConsoleStream => misc::create_console_stream(task, args[0], args[1]),
+        Syscall::BindStdio => misc::bind_stdio(task, args[0], args[1], args[2]),
+        Syscall::ReadConsoleStream => misc::read_console_stream(task, args.get(0), args.user_slice(1)?),
+        Syscall::WriteConsoleStream => misc::write_console_stream(task, args.get(0), args.user_slice(1)?),
+        Syscall::ReadKernelMetrics => misc::read_kernel_metrics(task, args.user_slice(0)?),
+        Syscall::PushConsoleInput => misc::push_console_input(task, args.user_slice(0)?),
+        Syscall::GetRandom => misc::get_random(task, args.user_slice(0)?),
+        Syscall::SetPriority => thread::set_priority(task, args[0]),
+        Syscall::SetNice => thread::set_nice(task, args[0]),
+        // The items are validated again whenever the task is woken, since the
+        // memory can change while it's blocked
+        Syscall::WaitMany => poll::wait_many(task, args.raw_slice(0), args[2]),
+        Syscall::SetTaskName => thread::set_task_name(task, args.user_slice(0)?),
+        Syscall::SetThreadName => thread::set_thread_name(task, args.user_slice(0)?),
+        Syscall::QueryTaskName => thread::query_task_name(task, args[0], args.user_slice(1)?, args.user_slice(3)?),
+        Syscall::ShareDmaRegion => channel::share_dma_region(task, args.get(0), args.get(1)),
+        Syscall::RevokeDmaRegion => dma::revoke(task, args.get(0)),
+        Syscall::ValidateDmaRange => dma::validate_range(task, args[0], args[1]),
+        Syscall::SnapshotMemory => mem::snapshot_memory(task, args.get(0)),
+        Syscall::PerfStart => misc::perf_start(task, args.get(0), args[1], args[2]),
+        Syscall::PerfRead => misc::perf_read(task, args.get(0), args.user_slice(1)?),
+        Syscall::PerfStop => misc::perf_stop(task, args.get(0)),
+        Syscall::ReadTrace => misc::read_trace(task, args.get(0), args.user_slice(1)?),
+        Syscall::SetupSyscallRing => ring::setup_syscall_ring(task, args[0]),
+        Syscall::RingEnter => ring::ring_enter(task, args[0]),
+        Syscall::FinalizeExecutable => mem::finalize_executable(task, args.get(0), args[1]),
+        Syscall::ListCapabilities => cspace::list_capabilities(task, args.user_slice(0)?),
+        Syscall::InspectCapability => cspace::inspect_capability(task, args.get(0)),
+        Syscall::RegisterSyscallExtension => extension::register(task, args.get(0)),
+        Syscall::GetTaskContext => events::get_task_context(task, args.get(0), args.get(1)),
+        Syscall::SetTaskContext => events::set_task_context(task, args.get(0), args.get(1)),
+        Syscall::CreateTaskGroup => taskgroup::create_task_group(task),
+        Syscall::KillTaskGroup => taskgroup::kill_task_group(task, args.get(0)),
+        Syscall::ClaimGroupCapability => taskgroup::claim_group_capability(task, args.get(0)),
+        Syscall::SetChannelQueueDepth => channel::set_queue_depth(task, args.get(0), args[1]),
+        Syscall::CreatePipe => misc::create_pipe(task),
+        Syscall::CloseConsoleStream => misc::close_console_stream(task, args.get(0)),
+        Syscall::ReadCoverage => misc::read_coverage(task, args.get(0), args[1], args.user_slice(2)?),
+        Syscall::ResetCoverage => misc::reset_coverage(task, args.get(0)),
+    };
+
+    Ok(outcome)
+}
+
+/// Enable the given global interrupts on the current hart and notify whichever
+/// task owns the device at `path` whenever one of them occurs. The interrupt
+/// stays disabled until the task completes it, or until the device is claimed
+/// again if it has no owner. Handlers only need registering the first time a
+/// device is claimed.
+fn route_interrupts(path: &str, interrupts: impl IntoIterator<Item = usize>, register_isrs: bool) {
+    let path = Arc::<str>::from(path);
+    for interrupt in interrupts {
+        let (controller, source) = match crate::interrupts::resolve(interrupt) {
+            Some(resolved) => resolved,
+            None => continue,
+        };
+
+        controller.enable_interrupt(HART_ID.get(), source);
+        controller.set_hart_threshold(HART_ID.get(), 0);
+        controller.set_interrupt_priority(source, 7);
+
+        if !register_isrs {
+            continue;
+        }
 
-            let slice = user_slice.guarded();
-            let node_path = match core::str::from_utf8(&slice) {
-                Ok(s) => s,
-                Err(_) => {
-                    log::error!("Invalid UTF-8 in FDT node name from process");
-                    return (Sender::kernel(), SyscallOutcome::Err(KError::InvalidArgument(0)));
+        let path = Arc::clone(&path);
+        isr::register_isr(interrupt, move |claim, id| {
+            claim.controller().disable_interrupt(claim.hart(), claim.source());
+            let tid = match crate::device::owner(&path) {
+                Some(tid) => tid,
+                None => {
+                    let path = Arc::clone(&path);
+                    crate::interrupts::deferred::defer(move || {
+                        log::debug!("Interrupt {} for unclaimed device {}, leaving it disabled", id, path)
+                    });
+                    return Ok(IsrStatus::Handled);
                 }
             };
 
-            // FIXME: make better errors
-            let claimed = CLAIMED_DEVICES.read();
-            if claimed.get(node_path).is_some() {
-                return (Sender::kernel(), SyscallOutcome::Err(KError::InvalidArgument(0)));
-            }
+            // Notifying the owner needs its task lock, so that's done after the
+            // interrupt has been handled
+            let hart = claim.hart();
+            crate::interrupts::deferred::defer(move || {
+                let task = match TASKS.get(tid) {
+                    Some(task) => task,
+                    // The owner exited in the meantime, so nobody is going to
+                    // complete the interrupt for it
+                    None => return crate::interrupts::complete_deferred(hart, id),
+                };
+                let mut task = task.lock();
 
-            let fdt = unsafe { fdt::Fdt::from_ptr(FDT.load(Ordering::Acquire)) }.unwrap();
-
-            // FIXME: probably should add some sanity checks for what we're
-            // mapping
-            //
-            // FIXME: `fdt` needs updated so that we can get the full node path,
-            // so work around that temporarily here
-            let mut all_nodes = fdt.all_nodes();
-            match all_nodes.find(|n| n.name == node_path) {
-                Some(node) => {
-                    // FIXME: what about multiple regions?
-                    match node.reg().into_iter().flatten().next() {
-                        Some(fdt::standard_nodes::MemoryRegion { size: Some(len), starting_address }) => {
-                            claimed.upgrade().insert(node_path.into(), task.tid);
-                            let map_to = unsafe {
-                                task.memory_manager.map_mmio_device(
-                                    PhysicalAddress::from_ptr(starting_address),
-                                    None,
-                                    len,
-                                )
-                            };
-
-                            // FIXME: this probably needs marked as `Clone` in
-                            // `fdt` or something
-                            let interrupts = node.interrupts().into_iter().flatten();
-                            let cptr = task.cspace.mint(Capability {
-                                resource: CapabilityResource::Mmio(map_to, interrupts.collect()),
-                                rights: CapabilityRights::GRANT | CapabilityRights::READ | CapabilityRights::WRITE,
-                            });
-
-                            let current_tid = task.tid;
-                            let interrupts = node.interrupts().into_iter().flatten();
-
-                            let plic = PLIC.lock();
-                            let plic = plic.as_ref().unwrap();
-                            for interrupt in interrupts {
-                                log::debug!("Giving interrupt {} to task {}", interrupt, task.name);
-                                plic.enable_interrupt(crate::platform::current_plic_context(), interrupt);
-                                plic.set_context_threshold(crate::platform::current_plic_context(), 0);
-                                plic.set_interrupt_priority(interrupt, 7);
-                                crate::interrupts::isr::register_isr(interrupt, move |plic, _, id| {
-                                    plic.disable_interrupt(crate::platform::current_plic_context(), id);
-                                    let task = TASKS.get(current_tid).unwrap();
-                                    let mut task = task.lock();
-
-                                    log::debug!(
-                                        "Interrupt {} triggered (hart: {}), notifying task {}",
-                                        id,
-                                        HART_ID.get(),
-                                        task.name
-                                    );
-
-                                    task.claimed_interrupts.insert(id, HART_ID.get());
-                                    task.message_queue.push(
-                                        Sender::kernel(),
-                                        Message::from(KernelNotification::InterruptOccurred(id)),
-                                    );
-
-                                    Ok(())
-                                });
-                            }
-
-                            SyscallOutcome::processed(cptr.value())
-                        }
-                        _ => return (Sender::kernel(), SyscallOutcome::Err(KError::InvalidArgument(0))),
-                    }
-                }
-                None => return (Sender::kernel(), SyscallOutcome::Err(KError::InvalidArgument(0))),
-            }
-        }
-        Syscall::CompleteInterrupt => {
-            let interrupt_id = syscall_req.arguments[0];
-            match task.claimed_interrupts.remove(&interrupt_id) {
-                None => SyscallOutcome::Err(KError::InvalidArgument(0)),
-                Some(hart) => {
-                    log::debug!("Task {} completing interrupt {}", task.name, interrupt_id);
-                    if let Some(plic) = &*PLIC.lock() {
-                        plic.complete(crate::platform::plic_context_for(hart), interrupt_id);
-                        plic.enable_interrupt(crate::platform::plic_context_for(hart), interrupt_id);
-                    }
+                log::debug!("Interrupt {} triggered (hart: {}), notifying task {}", id, hart, task.name);
 
-                    SyscallOutcome::processed(())
-                }
-            }
-        }
-        Syscall::QueryMmioCapability => mem::query_mmio_cap(task, CapabilityPtr::new(syscall_req.arguments[0])),
-    };
+                task.claimed_interrupts.insert(id, hart);
+                task.message_queue.push(Sender::kernel(), Message::from(KernelNotification::InterruptOccurred(id)));
+                task.interrupt_waiters.wake_all();
+            });
 
-    (sender, outcome)
+            // There's no way to tell if the device raised the interrupt without
+            // asking the task, which completes it once it's been handled
+            Ok(IsrStatus::Deferred)
+        });
+    }
 }
 
 fn get_message(frame: &TrapFrame) -> (Recipient, Message) {
@@ -0,0 +1,317 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A single way to wait on any kernel object that can become ready. Objects
+//! implement [`Waitable`] and keep a [`WaitQueue`] which they wake whenever
+//! their state changes, and `WaitMany` resolves each capability it's given to
+//! a [`Waitable`] with [`waitable`], so new object types only need to be added
+//! there to be usable alongside everything else.
+
+use super::SyscallOutcome;
+use crate::{
+    capabilities::{Capability, CapabilityResource},
+    csr,
+    io::mux::ConsoleStream,
+    mem::user::{self, RawUserSlice},
+    scheduler::{Scheduler, WakeToken, SCHEDULER},
+    task::Task,
+    utils::ticks_per_us,
+    TIMER_FREQ,
+};
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::Ordering;
+use librust::{
+    capabilities::{CapabilityPtr, CapabilityRights},
+    error::{AccessError, KError},
+    message::Sender,
+    syscalls::poll::{WaitEvents, WaitItem, MAX_WAIT_ITEMS, NO_TIMEOUT},
+    task::Tid,
+};
+use sync::SpinMutex;
+
+/// Tasks waiting in `WaitMany` with a timeout, and when they time out in ticks
+/// of the `time` CSR
+static TIMEOUTS: SpinMutex<Vec<(u64, Arc<Waiter>)>> = SpinMutex::new(Vec::new());
+
+/// A kernel object which can be waited on with `WaitMany`
+pub trait Waitable {
+    /// Which of the events in `interest` are currently ready, along with
+    /// [`WaitEvents::CLOSED`] if the object can never become ready again
+    fn poll(&self, interest: WaitEvents) -> WaitEvents;
+    /// Wake `waiter` the next time the object's state changes
+    fn register(&self, waiter: &Arc<Waiter>);
+}
+
+/// A task blocked in `WaitMany`, shared between everything it's waiting on.
+/// Only the first of them to become ready wakes the task up.
+pub struct Waiter {
+    tid: Tid,
+    token: SpinMutex<Option<WakeToken>>,
+}
+
+impl Waiter {
+    fn new(tid: Tid, token: WakeToken) -> Arc<Self> {
+        Arc::new(Self { tid, token: SpinMutex::new(Some(token)) })
+    }
+
+    /// Wake the task up, if nothing else has already
+    pub fn wake(&self) {
+        let token = self.token.lock().take();
+        if let Some(token) = token {
+            log::debug!("Waking task {:?} from wait_many", self.tid);
+            SCHEDULER.unblock(token);
+        }
+    }
+
+    /// Take back the wake token before blocking, returning `false` if the task
+    /// has already been woken
+    fn cancel(&self) -> bool {
+        self.token.lock().take().is_some()
+    }
+
+    fn is_done(&self) -> bool {
+        self.token.lock().is_none()
+    }
+}
+
+/// The tasks to wake up when an object's state changes
+pub struct WaitQueue {
+    waiters: SpinMutex<Vec<Arc<Waiter>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self { waiters: SpinMutex::new(Vec::new()) }
+    }
+
+    pub fn register(&self, waiter: &Arc<Waiter>) {
+        let mut waiters = self.waiters.lock();
+
+        // Anything woken by a different object is still around
+        waiters.retain(|waiter| !waiter.is_done());
+        waiters.push(Arc::clone(waiter));
+    }
+
+    pub fn wake_all(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        waiters.iter().for_each(|waiter| waiter.wake());
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl core::fmt::Debug for WaitQueue {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("WaitQueue").finish_non_exhaustive()
+    }
+}
+
+/// The interrupts of a device claimed by a task, which are readable while any
+/// of them is waiting for the task to complete it
+struct DeviceInterrupts<'a> {
+    task: &'a Task,
+    interrupts: &'a [usize],
+}
+
+impl Waitable for DeviceInterrupts<'_> {
+    fn poll(&self, interest: WaitEvents) -> WaitEvents {
+        let pending = self.interrupts.iter().any(|id| self.task.claimed_interrupts.contains_key(id));
+        match interest & WaitEvents::READABLE && pending {
+            true => WaitEvents::READABLE,
+            false => WaitEvents::NONE,
+        }
+    }
+
+    fn register(&self, waiter: &Arc<Waiter>) {
+        self.task.interrupt_waiters.register(waiter);
+    }
+}
+
+/// What [`waitable`] resolves a capability to, since device interrupts are
+/// tracked by the task instead of an object of their own
+enum Source<'a> {
+    Object(&'a dyn Waitable),
+    Device(DeviceInterrupts<'a>),
+}
+
+impl Waitable for Source<'_> {
+    fn poll(&self, interest: WaitEvents) -> WaitEvents {
+        match self {
+            Source::Object(object) => object.poll(interest),
+            Source::Device(device) => device.poll(interest),
+        }
+    }
+
+    fn register(&self, waiter: &Arc<Waiter>) {
+        match self {
+            Source::Object(object) => object.register(waiter),
+            Source::Device(device) => device.register(waiter),
+        }
+    }
+}
+
+/// The object behind a capability, if it's one that can be waited on
+fn waitable(task: &Task, cptr: CapabilityPtr) -> Option<Source<'_>> {
+    let object: Option<&dyn Waitable> = match task.cspace.resolve(cptr)? {
+        // Senders wait for room in the other end's queue
+        Capability { resource: CapabilityResource::Channel(channel_id), rights, .. }
+            if *rights & CapabilityRights::READ || *rights & CapabilityRights::WRITE =>
+        {
+            Some(&task.channels.get(channel_id)?.1)
+        }
+        Capability { resource: CapabilityResource::KernelLog, rights, .. } if *rights & CapabilityRights::READ => {
+            Some(&crate::io::klog::KernelLog)
+        }
+        Capability { resource: CapabilityResource::Perf, rights, .. } if *rights & CapabilityRights::READ => {
+            Some(&crate::perf::Perf)
+        }
+        Capability { resource: CapabilityResource::ConsoleStream(ConsoleStream::Pipe(pipe)), rights, .. }
+            if *rights & CapabilityRights::READ || *rights & CapabilityRights::WRITE =>
+        {
+            Some(pipe)
+        }
+        Capability { resource: CapabilityResource::Mmio(_, interrupts, _), .. } => {
+            return Some(Source::Device(DeviceInterrupts { task, interrupts }));
+        }
+        _ => None,
+    };
+
+    object.map(Source::Object)
+}
+
+/// Fill in which events are ready for each item, returning how many are ready
+fn poll_all(task: &Task, items: &mut [WaitItem]) -> Result<usize, KError> {
+    let mut n_ready = 0;
+    for (i, item) in items.iter_mut().enumerate() {
+        let waitable = waitable(task, item.cptr).ok_or(KError::InvalidArgument(i))?;
+        item.ready = waitable.poll(item.interest);
+
+        if !item.ready.is_empty() {
+            n_ready += 1;
+        }
+    }
+
+    Ok(n_ready)
+}
+
+fn read_items(task: &Task, items: &RawUserSlice<user::ReadWrite, WaitItem>) -> Result<Vec<WaitItem>, KError> {
+    let slice = RawUserSlice::<user::ReadWrite, WaitItem>::new(items.addr(), items.len());
+    match unsafe { slice.validate(&mut task.group.memory_manager.lock()) } {
+        Ok(slice) => slice.to_vec().map_err(|(addr, _)| KError::InvalidAccess(AccessError::Read(addr.as_ptr()))),
+        Err((addr, _)) => Err(KError::InvalidAccess(AccessError::Write(addr.as_mut_ptr()))),
+    }
+}
+
+fn write_items(
+    task: &Task,
+    items: &RawUserSlice<user::ReadWrite, WaitItem>,
+    polled: &[WaitItem],
+) -> Result<(), KError> {
+    let slice = RawUserSlice::<user::ReadWrite, WaitItem>::new(items.addr(), items.len());
+    match unsafe { slice.validate(&mut task.group.memory_manager.lock()) } {
+        Ok(mut slice) => match slice.copy_to_user(0, polled) {
+            Ok(_) => Ok(()),
+            Err((addr, _)) => Err(KError::InvalidAccess(AccessError::Write(addr.as_mut_ptr()))),
+        },
+        Err((addr, _)) => Err(KError::InvalidAccess(AccessError::Write(addr.as_mut_ptr()))),
+    }
+}
+
+/// Poll the items again and copy out the results, once the task is woken
+fn complete(task: &Task, items: &RawUserSlice<user::ReadWrite, WaitItem>) -> Result<usize, KError> {
+    let mut polled = read_items(task, items)?;
+    let n_ready = poll_all(task, &mut polled)?;
+    write_items(task, items, &polled)?;
+
+    Ok(n_ready)
+}
+
+pub fn wait_many(task: &mut Task, items: RawUserSlice<user::ReadWrite, WaitItem>, timeout_us: usize) -> SyscallOutcome {
+    // Nothing to wait on is only allowed as a plain sleep
+    if items.len() > MAX_WAIT_ITEMS || (items.is_empty() && timeout_us == NO_TIMEOUT) {
+        return SyscallOutcome::Err(KError::InvalidArgument(1));
+    }
+
+    let mut polled = match read_items(task, &items) {
+        Ok(polled) => polled,
+        Err(e) => return SyscallOutcome::Err(e),
+    };
+
+    let n_ready = match poll_all(task, &mut polled) {
+        Ok(n_ready) => n_ready,
+        Err(e) => return SyscallOutcome::Err(e),
+    };
+
+    if n_ready > 0 || timeout_us == 0 {
+        return match write_items(task, &items, &polled) {
+            Ok(()) => SyscallOutcome::processed(n_ready),
+            Err(e) => SyscallOutcome::Err(e),
+        };
+    }
+
+    let (addr, len) = (items.addr(), items.len());
+    let waiter = Waiter::new(
+        task.tid,
+        WakeToken::new(task.wait_id(), move |task| {
+            let items = RawUserSlice::new(addr, len);
+            match complete(task, &items) {
+                Ok(n_ready) => super::apply_message(false, Sender::kernel(), n_ready, &mut task.context.gp_regs),
+                Err(e) => super::report_error(e, &mut task.context.gp_regs),
+            }
+        }),
+    );
+
+    for item in &polled {
+        // Every item resolved above, and nothing could have changed the
+        // capability space since
+        waitable(task, item.cptr).unwrap().register(&waiter);
+    }
+
+    // Something may have become ready in between polling and registering, in
+    // which case it either already woke the waiter, or it's seen here
+    let n_ready = poll_all(task, &mut polled).unwrap();
+    if n_ready > 0 && waiter.cancel() {
+        return match write_items(task, &items, &polled) {
+            Ok(()) => SyscallOutcome::processed(n_ready),
+            Err(e) => SyscallOutcome::Err(e),
+        };
+    }
+
+    if timeout_us != NO_TIMEOUT {
+        let deadline = csr::time::read() + ticks_per_us(timeout_us as u64, TIMER_FREQ.load(Ordering::Relaxed));
+        TIMEOUTS.lock().push((deadline, waiter));
+    }
+
+    SyscallOutcome::Block
+}
+
+/// Wake up any waiting tasks which have timed out, called on each timer tick
+pub fn tick() {
+    let now = csr::time::read();
+    let expired: Vec<_> = {
+        let mut timeouts = TIMEOUTS.lock();
+        let (expired, pending) = core::mem::take(&mut *timeouts)
+            .into_iter()
+            .filter(|(_, waiter)| !waiter.is_done())
+            .partition(|(deadline, _)| *deadline <= now);
+        *timeouts = pending;
+
+        expired
+    };
+
+    expired.iter().for_each(|(_, waiter)| waiter.wake());
+}
+
+/// The earliest time, in ticks of the `time` CSR, that a waiting task times
+/// out at, so idle harts know when to wake up for it
+pub fn next_deadline() -> Option<u64> {
+    TIMEOUTS.lock().iter().filter(|(_, waiter)| !waiter.is_done()).map(|(deadline, _)| *deadline).min()
+}
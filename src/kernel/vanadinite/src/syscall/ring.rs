@@ -0,0 +1,271 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Syscall rings, see [`librust::syscalls::ring`] for the layout of the shared
+//! memory. Submissions are only read by the thread that owns the ring while
+//! it's in `RingEnter`, but completions can be posted from anywhere, e.g. from
+//! the timer interrupt for [`RingOp::Timeout`]s.
+
+use super::{channel, SyscallOutcome};
+use crate::{
+    csr,
+    mem::{
+        manager::{AddressRegionKind, FillOption, RegionDescription},
+        paging::{flags, PageSize},
+        phys2virt,
+        region::SharedPhysicalRegion,
+        user::ValidatedUserSlice,
+    },
+    scheduler::{Scheduler, WakeToken, SCHEDULER},
+    task::Task,
+    utils::{self, ticks_per_us, Units},
+    TIMER_FREQ,
+};
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::Ordering;
+use librust::{
+    capabilities::CapabilityPtr,
+    error::KError,
+    message::{Message, Sender},
+    syscalls::{
+        channel::MessageId,
+        ring::{Completion, RingHeader, RingOp, Submission, MAX_RING_ENTRIES},
+    },
+};
+use sync::SpinMutex;
+
+/// Pending [`RingOp::Timeout`]s, when they expire in ticks of the `time` CSR,
+/// and the `user_data` to complete them with
+static TIMEOUTS: SpinMutex<Vec<(u64, Arc<SyscallRing>, usize)>> = SpinMutex::new(Vec::new());
+
+pub struct SyscallRing {
+    region: SharedPhysicalRegion,
+    /// The kernel's copy of the layout, since the task can scribble over the
+    /// header
+    sq_entries: usize,
+    cq_entries: usize,
+    sq_offset: usize,
+    cq_offset: usize,
+    state: SpinMutex<RingState>,
+}
+
+struct RingState {
+    /// The kernel's own copies of the positions it writes
+    sq_head: usize,
+    cq_tail: usize,
+    /// Operations which have been started but haven't completed yet
+    in_flight: usize,
+    /// The owner blocked in `RingEnter`, and how many unread completions it's
+    /// waiting for
+    waiter: Option<(usize, WakeToken)>,
+}
+
+impl SyscallRing {
+    fn base(&self) -> *mut u8 {
+        phys2virt(self.region.physical_addresses().next().unwrap()).as_mut_ptr()
+    }
+
+    fn header(&self) -> &RingHeader {
+        unsafe { &*self.base().cast() }
+    }
+
+    /// Completions posted which the task hasn't read yet. A task which moved
+    /// `cq_head` somewhere nonsensical just sees a full queue.
+    fn unread(&self, state: &RingState) -> usize {
+        state.cq_tail.wrapping_sub(self.header().cq_head.load(Ordering::Acquire)).min(self.cq_entries)
+    }
+
+    /// Take the next submission if there is one and there's room for its
+    /// completion
+    fn next_submission(&self) -> Option<Submission> {
+        let mut state = self.state.lock();
+        let header = self.header();
+
+        let queued = header.sq_tail.load(Ordering::Acquire).wrapping_sub(state.sq_head);
+        if queued == 0 || queued > self.sq_entries || state.in_flight + self.unread(&state) >= self.cq_entries {
+            return None;
+        }
+
+        let submission = unsafe {
+            self.base().add(self.sq_offset).cast::<Submission>().add(state.sq_head % self.sq_entries).read_volatile()
+        };
+
+        state.sq_head = state.sq_head.wrapping_add(1);
+        state.in_flight += 1;
+        header.sq_head.store(state.sq_head, Ordering::Release);
+
+        Some(submission)
+    }
+
+    /// Post the completion of an operation that was started, waking the owner
+    /// if it's been waiting for it
+    fn complete(&self, user_data: usize, result: Result<Message, KError>) {
+        let (is_err, message) = match result {
+            Ok(message) => (0, message),
+            Err(e) => (1, Message::from(e)),
+        };
+
+        let mut value = [0; 3];
+        value.copy_from_slice(&message.contents[..3]);
+
+        let mut state = self.state.lock();
+        let header = self.header();
+        unsafe {
+            self.base()
+                .add(self.cq_offset)
+                .cast::<Completion>()
+                .add(state.cq_tail % self.cq_entries)
+                .write_volatile(Completion { user_data, is_err, value })
+        };
+
+        state.cq_tail = state.cq_tail.wrapping_add(1);
+        state.in_flight -= 1;
+        header.cq_tail.store(state.cq_tail, Ordering::Release);
+
+        let token = match state.waiter {
+            Some((wanted, _)) if self.unread(&state) >= wanted => state.waiter.take().map(|(_, token)| token),
+            _ => None,
+        };
+
+        drop(state);
+        if let Some(token) = token {
+            SCHEDULER.unblock(token);
+        }
+    }
+}
+
+/// Create the task's ring with room for `entries` submissions, returning the
+/// address it's mapped at
+pub fn setup_syscall_ring(task: &mut Task, entries: usize) -> SyscallOutcome {
+    if task.syscall_ring.is_some() || !entries.is_power_of_two() || entries > MAX_RING_ENTRIES {
+        return SyscallOutcome::Err(KError::InvalidArgument(0));
+    }
+
+    let cq_entries = entries * 2;
+    let sq_offset = core::mem::size_of::<RingHeader>();
+    let cq_offset = sq_offset + entries * core::mem::size_of::<Submission>();
+    let size = cq_offset + cq_entries * core::mem::size_of::<Completion>();
+
+    let (mapping, region) = task.group.memory_manager.lock().alloc_shared_region(
+        None,
+        RegionDescription {
+            size: PageSize::Kilopage,
+            len: utils::round_up_to_next(size, 4.kib()) / 4.kib(),
+            contiguous: true,
+            flags: flags::USER | flags::READ | flags::WRITE | flags::VALID,
+            fill: FillOption::Zeroed,
+            kind: AddressRegionKind::Data,
+        },
+    );
+
+    let ring = SyscallRing {
+        region,
+        sq_entries: entries,
+        cq_entries,
+        sq_offset,
+        cq_offset,
+        state: SpinMutex::new(RingState { sq_head: 0, cq_tail: 0, in_flight: 0, waiter: None }),
+    };
+
+    let header = ring.base().cast::<RingHeader>();
+    unsafe {
+        (*header).sq_entries = entries;
+        (*header).cq_entries = cq_entries;
+        (*header).sq_offset = sq_offset;
+        (*header).cq_offset = cq_offset;
+    }
+
+    task.syscall_ring = Some(Arc::new(ring));
+    SyscallOutcome::processed(mapping.start.as_usize())
+}
+
+/// Start everything in the submission queue, then block until `min_complete`
+/// completions are unread, returning how many operations were started
+pub fn ring_enter(task: &mut Task, min_complete: usize) -> SyscallOutcome {
+    let ring = match &task.syscall_ring {
+        Some(ring) => Arc::clone(ring),
+        None => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+
+    let mut started = 0;
+    while let Some(submission) = ring.next_submission() {
+        start(task, &ring, submission);
+        started += 1;
+    }
+
+    let mut state = ring.state.lock();
+    let unread = ring.unread(&state);
+    if unread >= min_complete {
+        return SyscallOutcome::processed(started);
+    }
+
+    // Nothing would ever wake the task
+    if unread + state.in_flight < min_complete {
+        return SyscallOutcome::Err(KError::InvalidArgument(0));
+    }
+
+    let token = WakeToken::new(task.wait_id(), move |task| {
+        super::apply_message(false, Sender::kernel(), started, &mut task.context.gp_regs)
+    });
+    state.waiter = Some((min_complete, token));
+
+    SyscallOutcome::Block
+}
+
+fn start(task: &mut Task, ring: &Arc<SyscallRing>, submission: Submission) {
+    let Submission { user_data, args, .. } = submission;
+    let outcome = match RingOp::from_usize(submission.op) {
+        Some(RingOp::Nop) => SyscallOutcome::processed(()),
+        Some(RingOp::Send) => channel::send_message(
+            task,
+            CapabilityPtr::new(args[0]),
+            MessageId::new(args[1]),
+            args[2],
+            ValidatedUserSlice::empty(),
+        ),
+        Some(RingOp::Timeout) => {
+            let deadline = csr::time::read() + ticks_per_us(args[0] as u64, TIMER_FREQ.load(Ordering::Relaxed));
+            TIMEOUTS.lock().push((deadline, Arc::clone(ring), user_data));
+            return;
+        }
+        Some(RingOp::NotifyChannelRing) => channel::notify_ring(task, CapabilityPtr::new(args[0])),
+        None => SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+
+    match outcome {
+        SyscallOutcome::Processed(message) => ring.complete(user_data, Ok(message)),
+        SyscallOutcome::Err(e) => ring.complete(user_data, Err(e)),
+        // Starting over isn't possible once the submission has been taken, so
+        // the task has to resubmit it
+        SyscallOutcome::Retry => ring.complete(user_data, Err(KError::WouldBlock)),
+        // None of the operations block or replace the task's context
+        outcome => unreachable!("ring operation {:?} returned {:?}", submission.op, outcome),
+    }
+}
+
+/// Complete any timeouts which have expired, called on each timer tick
+pub fn tick() {
+    let now = csr::time::read();
+    let expired = {
+        let mut timeouts = TIMEOUTS.lock();
+        let (expired, pending): (Vec<_>, Vec<_>) =
+            core::mem::take(&mut *timeouts).into_iter().partition(|(deadline, ..)| *deadline <= now);
+        *timeouts = pending;
+
+        expired
+    };
+
+    for (_, ring, user_data) in expired {
+        ring.complete(user_data, Ok(Message::default()));
+    }
+}
+
+/// The earliest time, in ticks of the `time` CSR, that a timeout expires at,
+/// so idle harts know when to wake up for it
+pub fn next_deadline() -> Option<u64> {
+    TIMEOUTS.lock().iter().map(|(deadline, ..)| *deadline).min()
+}
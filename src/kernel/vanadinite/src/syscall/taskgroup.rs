@@ -0,0 +1,228 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Task groups, which collect whole tasks under the supervisor that created the
+//! group so they can be killed together. Not to be confused with
+//! [`ThreadGroup`](crate::task::ThreadGroup)s, which are the threads sharing a
+//! single address space; every thread of a task is in its task group.
+//!
+//! Tasks join a group when they're spawned into it, and tasks and threads
+//! spawned by a member join the same group. Members leave when they exit, and
+//! once the last one has the supervisor is sent a
+//! [`KernelNotification::TaskGroupExited`].
+
+use super::{events, exit, SyscallOutcome};
+use crate::{
+    capabilities::{Capability, CapabilityResource, CapabilitySpace},
+    mem::paging::flags,
+    scheduler::TASKS,
+    task::{ExitWatcher, Task},
+};
+use alloc::{collections::BTreeSet, sync::Arc, vec::Vec};
+use librust::{
+    capabilities::{CapabilityPtr, CapabilityRights},
+    error::KError,
+    message::{KernelNotification, Sender},
+    task::{ExitStatus, TaskEvent, Tid},
+};
+use sync::SpinMutex;
+
+#[derive(Debug)]
+pub struct TaskGroup {
+    /// The task to notify once the group is empty, along with its capability
+    /// to the group
+    supervisor: SpinMutex<Option<(Tid, CapabilityPtr)>>,
+    members: SpinMutex<Members>,
+    /// Capabilities any member can claim a copy of, which currently only holds
+    /// memory allocated with `AllocationOptions::JobGroupAvailable`
+    pub cspace: SpinMutex<CapabilitySpace>,
+}
+
+#[derive(Debug, Default)]
+struct Members {
+    tids: BTreeSet<Tid>,
+    /// Tasks are added once they have a TID, by which point they may have
+    /// already run and exited, so their exit is remembered until they join
+    departed: BTreeSet<Tid>,
+    /// Set once the group has been killed, after which tasks can't be spawned
+    /// into it
+    killed: bool,
+}
+
+impl TaskGroup {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            supervisor: SpinMutex::new(None),
+            members: SpinMutex::new(Members::default()),
+            cspace: SpinMutex::new(CapabilitySpace::new()),
+        })
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.members.lock().killed
+    }
+
+    /// Add the newly created task `tid` to the group, returning the
+    /// supervisor to notify if it already exited and the group is empty
+    #[must_use]
+    pub fn join(&self, tid: Tid) -> Option<ExitWatcher> {
+        let mut members = self.members.lock();
+        if !members.departed.remove(&tid) {
+            members.tids.insert(tid);
+            return None;
+        }
+
+        let empty = members.tids.is_empty();
+        drop(members);
+        self.supervisor_if(empty)
+    }
+
+    /// Remove `tid` from the group, returning the supervisor to notify if it
+    /// was the last member
+    #[must_use]
+    pub fn leave(&self, tid: Tid) -> Option<ExitWatcher> {
+        let mut members = self.members.lock();
+        if !members.tids.remove(&tid) {
+            members.departed.insert(tid);
+            return None;
+        }
+
+        let empty = members.tids.is_empty();
+        drop(members);
+        self.supervisor_if(empty)
+    }
+
+    /// Move supervision of the group from `old` to `new` if `old` was the
+    /// supervisor, for when capabilities are handed off between tasks
+    pub fn replace_supervisor(&self, old: Tid, new: Tid, cptr: CapabilityPtr) {
+        let mut supervisor = self.supervisor.lock();
+        if matches!(*supervisor, Some((tid, _)) if tid == old) {
+            *supervisor = Some((new, cptr));
+        }
+    }
+
+    fn supervisor_if(&self, empty: bool) -> Option<ExitWatcher> {
+        match empty {
+            true => self.supervisor.lock().map(|(tid, cptr)| ExitWatcher::TaskGroup(tid, cptr)),
+            false => None,
+        }
+    }
+}
+
+/// Create a new task group supervised by the current task
+pub fn create_task_group(task: &mut Task) -> SyscallOutcome {
+    let group = TaskGroup::new();
+    let cptr = task.cspace.mint(Capability {
+        resource: CapabilityResource::TaskGroup(Arc::clone(&group)),
+        rights: CapabilityRights::READ | CapabilityRights::WRITE,
+        badge: 0,
+    });
+
+    *group.supervisor.lock() = Some((task.tid, cptr));
+
+    SyscallOutcome::processed(cptr.value())
+}
+
+/// Kill every member of the group `cptr`, returning how many were killed. The
+/// current task is killed last if it's a member.
+pub fn kill_task_group(task: &mut Task, cptr: CapabilityPtr) -> SyscallOutcome {
+    let group = match resolve(task, cptr) {
+        Ok(group) => group,
+        Err(e) => return SyscallOutcome::Err(e),
+    };
+
+    group.members.lock().killed = true;
+
+    // Members which were spawning a task when the snapshot was taken only
+    // finish once they're unlocked, by which point the new task has joined,
+    // so keep going until no members other than the current task are left
+    let mut killed = 0;
+    loop {
+        let members: Vec<Tid> = group.members.lock().tids.iter().copied().filter(|&tid| tid != task.tid).collect();
+        if members.is_empty() {
+            break;
+        }
+
+        for tid in members {
+            // Killed members leave the group on their way out
+            let watchers = match TASKS.get(tid) {
+                Some(member) => {
+                    let mut member = member.lock();
+                    killed += usize::from(!member.state.is_dead());
+                    events::post_event(&mut member, TaskEvent::Kill)
+                }
+                None => group.leave(tid).into_iter().collect(),
+            };
+
+            notify_watchers(task, watchers);
+        }
+    }
+
+    match matches!(&task.task_group, Some(member_of) if Arc::ptr_eq(member_of, &group)) {
+        true => SyscallOutcome::Kill(ExitStatus::Killed),
+        false => SyscallOutcome::processed(killed),
+    }
+}
+
+/// Copy `cptr` from the current task group's capability space into the
+/// current task's, mapping it if it's memory
+pub fn claim_group_capability(task: &mut Task, cptr: CapabilityPtr) -> SyscallOutcome {
+    let group = match &task.task_group {
+        Some(group) => Arc::clone(group),
+        None => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+
+    let cspace = group.cspace.lock();
+    let (region, kind, rights) = match cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Memory(region, _, kind), rights, .. }) => {
+            (region.clone(), *kind, *rights)
+        }
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+    drop(cspace);
+
+    let mut flags = flags::USER | flags::VALID | flags::READ;
+    if rights & CapabilityRights::WRITE {
+        flags |= flags::WRITE;
+    }
+
+    let range = task.group.memory_manager.lock().apply_shared_region(None, flags, region.clone(), kind);
+    let cptr =
+        task.cspace.mint(Capability { resource: CapabilityResource::Memory(region, range, kind), rights, badge: 0 });
+
+    SyscallOutcome::processed(cptr.value())
+}
+
+/// The task group `cptr` refers to, if the capability allows changing it
+pub(super) fn resolve(task: &Task, cptr: CapabilityPtr) -> Result<Arc<TaskGroup>, KError> {
+    match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::TaskGroup(group), rights, .. })
+            if *rights & CapabilityRights::WRITE =>
+        {
+            Ok(Arc::clone(group))
+        }
+        _ => Err(KError::InvalidArgument(0)),
+    }
+}
+
+/// Like [`exit::notify_watchers`], except the current task is already locked
+/// so it's notified directly
+fn notify_watchers(task: &mut Task, watchers: Vec<ExitWatcher>) {
+    let (ours, others): (Vec<_>, Vec<_>) = watchers.into_iter().partition(|watcher| watcher.tid() == task.tid);
+    for watcher in ours {
+        let notification = match watcher {
+            ExitWatcher::Notify(_, channel) => KernelNotification::TaskExited { channel, status: ExitStatus::Killed },
+            ExitWatcher::TaskGroup(_, group) => KernelNotification::TaskGroupExited(group),
+            // The current task is running, so can't be blocked waiting
+            ExitWatcher::Blocked(_) | ExitWatcher::Caller(_) => continue,
+        };
+
+        task.message_queue.push(Sender::kernel(), notification.into());
+    }
+
+    exit::notify_watchers(ExitStatus::Killed, others);
+}
@@ -0,0 +1,319 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{poll::WaitQueue, SyscallOutcome};
+use crate::{
+    capabilities::CapabilitySpace,
+    mem::{
+        paging::{flags, PhysicalAddress, VirtualAddress},
+        phys2virt,
+        user::{self, RawUserPtr, Read, ValidatedUserSlice},
+    },
+    scheduler::{Scheduler, WakeToken, SCHEDULER, TASKS},
+    task::{Context, MessageQueue, Task, TaskState},
+    trap::GeneralRegisters,
+};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+};
+use core::{
+    num::NonZeroUsize,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use librust::{
+    error::{AccessError, KError},
+    message::{Message, Sender},
+    syscalls::thread::THREAD_EXITED,
+    task::{ExitStatus, Tid, MAX_NAME_LEN, MAX_NICE, MIN_NICE},
+};
+use sync::SpinMutex;
+
+/// Tasks waiting on a futex, keyed by the physical address of the futex word
+/// so that they work across shared memory too
+static FUTEXES: SpinMutex<BTreeMap<PhysicalAddress, VecDeque<WakeToken>>> = SpinMutex::new(BTreeMap::new());
+
+/// Create a new thread in the same address space as `task`, starting at
+/// `entry` with the given stack pointer and `arg` in `a0`. If `tls` is zero, a
+/// new TLS block is created for the thread from the task's executable,
+/// otherwise it's used as the thread pointer as-is. Unless it's zero, the
+/// futex at `exit_futex` is set to [`THREAD_EXITED`] and woken once the thread
+/// exits, however it exits.
+pub fn create_thread(
+    task: &mut Task,
+    entry: VirtualAddress,
+    stack: VirtualAddress,
+    tls: usize,
+    arg: usize,
+    exit_futex: VirtualAddress,
+) -> SyscallOutcome {
+    if entry.is_kernel_region() {
+        return SyscallOutcome::Err(KError::InvalidArgument(0));
+    } else if stack.is_kernel_region() {
+        return SyscallOutcome::Err(KError::InvalidArgument(1));
+    } else if exit_futex.is_kernel_region() || exit_futex.as_usize() % core::mem::align_of::<u32>() != 0 {
+        return SyscallOutcome::Err(KError::InvalidArgument(4));
+    }
+
+    let (tls_block, tp) = match (tls, &task.group.tls) {
+        (0, Some(template)) => {
+            let (block, tp) = template.instantiate(&mut task.group.memory_manager.lock());
+            (Some(block), tp.as_usize())
+        }
+        (tls, _) => (None, tls),
+    };
+
+    let thread = Task {
+        tid: Tid::new(NonZeroUsize::new(usize::MAX).unwrap()),
+        name: task.name.clone(),
+        thread_name: None,
+        context: Context {
+            pc: entry.as_usize(),
+            gp_regs: GeneralRegisters { a0: arg, sp: stack.as_usize(), tp, ..Default::default() },
+            fp_regs: Default::default(),
+        },
+        group: Arc::clone(&task.group),
+        task_group: task.task_group.clone(),
+        tls_block,
+        exit_futex: Some(exit_futex).filter(|addr| !addr.is_null()),
+        state: TaskState::Running,
+        wait_generation: 0,
+        priority: task.priority,
+        nice: task.nice,
+        message_queue: MessageQueue::new(),
+        promiscuous: true,
+        incoming_channel_request: Default::default(),
+        channels: Default::default(),
+        vmspace_next_id: 0,
+        vmspace_objects: Default::default(),
+        cspace: CapabilitySpace::new(),
+        claimed_interrupts: BTreeMap::new(),
+        interrupt_waiters: WaitQueue::new(),
+        exit_status: None,
+        exit_watchers: Default::default(),
+        events: Default::default(),
+        console_mode: task.console_mode,
+        stdin: task.stdin.clone(),
+        stdout: task.stdout.clone(),
+        syscall_ring: None,
+    };
+
+    let task_group = thread.task_group.clone();
+    let tid = SCHEDULER.enqueue(thread);
+    // The thread may have already exited, in which case it was the last
+    // member and the status doesn't matter, since the supervisor isn't told it
+    if let Some(watcher) = task_group.and_then(|task_group| task_group.join(tid)) {
+        super::exit::notify_watchers(ExitStatus::Killed, alloc::vec![watcher]);
+    }
+
+    log::debug!("Created thread {} for task {}", tid.value(), task.name);
+
+    SyscallOutcome::processed(tid.value())
+}
+
+/// Block until woken by [`futex_wake`], as long as the futex word at `addr`
+/// still holds `expected`
+pub fn futex_wait(task: &mut Task, addr: VirtualAddress, expected: u32) -> SyscallOutcome {
+    let (phys, value) = match read_futex(task, addr) {
+        Ok(futex) => futex,
+        Err(e) => return SyscallOutcome::Err(e),
+    };
+
+    // Keep the futex table locked between checking the value and registering
+    // the wake, otherwise a wake could be missed
+    let mut futexes = FUTEXES.lock();
+    if value != expected {
+        return SyscallOutcome::Processed(Message::default());
+    }
+
+    futexes.entry(phys).or_default().push_back(WakeToken::new(task.wait_id(), |task| {
+        super::apply_message(false, Sender::kernel(), Message::default(), &mut task.context.gp_regs)
+    }));
+
+    SyscallOutcome::Block
+}
+
+/// Wake up to `count` tasks waiting on the futex at `addr`, returning how many
+/// were woken
+pub fn futex_wake(task: &mut Task, addr: VirtualAddress, count: usize) -> SyscallOutcome {
+    let (phys, _) = match read_futex(task, addr) {
+        Ok(futex) => futex,
+        Err(e) => return SyscallOutcome::Err(e),
+    };
+
+    SyscallOutcome::processed(wake(phys, count))
+}
+
+/// Let anything joining `task` know that it's exited by setting its exit futex
+/// to [`THREAD_EXITED`] and waking it, see [`create_thread`]. The thread may
+/// not be the one running on this hart, so the futex is written through its
+/// physical address.
+pub fn wake_exit_futex(task: &mut Task) {
+    let addr = match task.exit_futex.take() {
+        Some(addr) => addr,
+        None => return,
+    };
+
+    let mut memory_manager = task.group.memory_manager.lock();
+    let range = addr..addr.add(core::mem::size_of::<u32>());
+    memory_manager.break_snapshots(range.clone());
+
+    // Nothing can be waiting on it anymore if the thread unmapped it
+    if memory_manager.is_user_region_valid(range, |f| f & flags::WRITE).is_err() {
+        return;
+    }
+
+    // Validated above as a writable, aligned word of user memory, which can't be
+    // unmapped while the memory manager is locked. Releasing makes everything
+    // the thread wrote before exiting visible to whoever sees that it has.
+    let phys = memory_manager.resolve(addr).unwrap();
+    unsafe { (*phys2virt(phys).as_ptr().cast::<AtomicU32>()).store(THREAD_EXITED, Ordering::Release) };
+    drop(memory_manager);
+
+    wake(phys, usize::MAX);
+}
+
+/// Wake up to `count` tasks waiting on the futex at `phys`
+fn wake(phys: PhysicalAddress, count: usize) -> usize {
+    let mut futexes = FUTEXES.lock();
+    let waiters = match futexes.get_mut(&phys) {
+        Some(waiters) => waiters,
+        None => return 0,
+    };
+
+    // Tokens left over from waits which were cut short don't count towards
+    // the tasks woken
+    let mut n_woken = 0;
+    while n_woken < count {
+        match waiters.pop_front() {
+            Some(token) => n_woken += SCHEDULER.unblock(token) as usize,
+            None => break,
+        }
+    }
+
+    if waiters.is_empty() {
+        futexes.remove(&phys);
+    }
+
+    n_woken
+}
+
+fn read_futex(task: &mut Task, addr: VirtualAddress) -> Result<(PhysicalAddress, u32), KError> {
+    let mut memory_manager = task.group.memory_manager.lock();
+    let futex = RawUserPtr::<Read, u32>::readable(addr);
+    let futex = match unsafe { futex.validate(&mut memory_manager) } {
+        Ok(futex) => futex,
+        Err(e) => {
+            log::error!("Bad futex address from process: {:?}", e);
+            return Err(KError::InvalidAccess(AccessError::Read(addr.as_ptr())));
+        }
+    };
+
+    // Validated above, so the address must be mapped
+    let phys = memory_manager.resolve(addr).unwrap();
+
+    match futex.read() {
+        Ok(value) => Ok((phys, value)),
+        Err(_) => Err(KError::InvalidAccess(AccessError::Read(addr.as_ptr()))),
+    }
+}
+
+/// Change the scheduling priority of the calling thread, which takes effect the
+/// next time it's preempted
+pub fn set_priority(task: &mut Task, priority: usize) -> SyscallOutcome {
+    match u8::try_from(priority) {
+        Ok(priority) => {
+            task.priority = priority;
+            SyscallOutcome::processed(())
+        }
+        Err(_) => SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+}
+
+/// Change the niceness of the calling thread, which takes effect the next time
+/// it's preempted
+pub fn set_nice(task: &mut Task, nice: usize) -> SyscallOutcome {
+    match i8::try_from(nice as isize) {
+        Ok(nice) if (MIN_NICE..=MAX_NICE).contains(&nice) => {
+            task.nice = nice;
+            SyscallOutcome::processed(())
+        }
+        _ => SyscallOutcome::Err(KError::InvalidArgument(0)),
+    }
+}
+
+/// Rename the calling thread's task. Other threads in the task keep the name
+/// they had, threads created afterwards take the new one.
+pub fn set_task_name(task: &mut Task, name: ValidatedUserSlice<Read, u8>) -> SyscallOutcome {
+    match read_name(&name) {
+        Ok(name) if name.is_empty() => SyscallOutcome::Err(KError::InvalidArgument(1)),
+        Ok(name) => {
+            log::debug!("Task {} renamed to {}", task.name, name);
+            task.name = name;
+            SyscallOutcome::processed(())
+        }
+        Err(e) => SyscallOutcome::Err(e),
+    }
+}
+
+/// Name the calling thread, or clear its name if `len` is zero
+pub fn set_thread_name(task: &mut Task, name: ValidatedUserSlice<Read, u8>) -> SyscallOutcome {
+    match read_name(&name) {
+        Ok(name) => {
+            task.thread_name = Some(name).filter(|name| !name.is_empty());
+            SyscallOutcome::processed(())
+        }
+        Err(e) => SyscallOutcome::Err(e),
+    }
+}
+
+/// Copy the task and thread names of `tid`, or the calling thread if it's
+/// zero, returning the full length of each
+pub fn query_task_name(
+    task: &mut Task,
+    tid: usize,
+    name: ValidatedUserSlice<user::ReadWrite, u8>,
+    thread_name: ValidatedUserSlice<user::ReadWrite, u8>,
+) -> SyscallOutcome {
+    // The calling thread is already locked
+    let (task_name, task_thread_name) = match tid {
+        tid if tid == 0 || tid == task.tid.value() => (task.name.clone(), task.thread_name.clone()),
+        tid => match NonZeroUsize::new(tid).and_then(|tid| TASKS.get(Tid::new(tid))) {
+            Some(other) => {
+                let other = other.lock();
+                (other.name.clone(), other.thread_name.clone())
+            }
+            None => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+        },
+    };
+
+    let task_thread_name = task_thread_name.unwrap_or_default();
+    let copied = super::mem::copy_to_user(name, task_name.as_bytes())
+        .and_then(|_| super::mem::copy_to_user(thread_name, task_thread_name.as_bytes()));
+
+    match copied {
+        Ok(_) => SyscallOutcome::processed((task_name.len(), task_thread_name.len())),
+        Err(e) => SyscallOutcome::Err(e),
+    }
+}
+
+pub(super) fn read_name(name: &ValidatedUserSlice<Read, u8>) -> Result<Box<str>, KError> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(KError::InvalidArgument(1));
+    }
+
+    let bytes = match name.to_vec() {
+        Ok(bytes) => bytes,
+        Err((addr, _)) => return Err(KError::InvalidAccess(AccessError::Read(addr.as_ptr()))),
+    };
+
+    match core::str::from_utf8(&bytes) {
+        Ok(name) => Ok(Box::from(name)),
+        Err(_) => Err(KError::InvalidArgument(0)),
+    }
+}
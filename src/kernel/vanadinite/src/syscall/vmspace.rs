@@ -9,23 +9,26 @@ use core::num::NonZeroUsize;
 
 use crate::{
     capabilities::{Capability, CapabilityResource, CapabilitySpace},
+    io::mux::ConsoleStream,
     mem::{
         manager::{AddressRegionKind, FillOption, MemoryManager, RegionDescription},
         paging::{flags, PageSize, VirtualAddress},
-        user::RawUserSlice,
+        region::{MemoryRegion, PhysicalRegion, SharedPhysicalRegion},
+        user::{self, ValidatedUserSlice},
     },
-    scheduler::{Scheduler, SCHEDULER},
-    syscall::channel::UserspaceChannel,
-    task::{Context, MessageQueue, Task},
+    scheduler::{Scheduler, SCHEDULER, TASKS},
+    syscall::{channel::UserspaceChannel, dma::DmaRegion, poll::WaitQueue, taskgroup::TaskGroup},
+    task::{Context, MessageQueue, Task, ThreadGroup, TlsTemplate},
     trap::GeneralRegisters,
     utils::{self, Units},
 };
-use alloc::{collections::BTreeMap, vec::Vec};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
 use librust::{
-    capabilities::CapabilityRights,
+    capabilities::{CapabilityPtr, CapabilityRights},
     error::{AccessError, KError},
+    message::{KernelNotification, Sender},
     syscalls::{allocation::MemoryPermissions, channel::ChannelId, vmspace::VmspaceObjectId},
-    task::Tid,
+    task::{ExitStatus, Tid, DEFAULT_PRIORITY},
 };
 
 use super::SyscallOutcome;
@@ -86,11 +89,11 @@ pub fn alloc_vmspace_object(
     }
 
     let kind = match (flags & flags::READ, flags & flags::WRITE, flags & flags::EXECUTE) {
-        (true, true, true) => AddressRegionKind::UserAllocated,
         (true, true, false) => AddressRegionKind::Data,
         (true, false, false) => AddressRegionKind::ReadOnly,
         (true, false, true) | (false, false, true) => AddressRegionKind::Text,
-        (false, false, false) | (false, true, true) | (false, true, false) => {
+        // Memory can be either writable or executable, but never both at once
+        (_, true, true) | (false, false, false) | (false, true, false) => {
             return SyscallOutcome::Err(KError::InvalidArgument(3))
         }
     };
@@ -114,7 +117,7 @@ pub fn alloc_vmspace_object(
     );
 
     // log::info!("Mapping region at {:#p} for task {}", at.start, task.name);
-    let range = task.memory_manager.apply_shared_region(
+    let range = task.group.memory_manager.lock().apply_shared_region(
         None,
         flags::USER | flags::VALID | flags::READ | flags::WRITE,
         region,
@@ -131,33 +134,40 @@ pub fn alloc_vmspace_object(
 pub fn spawn_vmspace(
     task: &mut Task,
     id: VmspaceObjectId,
-    name: VirtualAddress,
-    len: usize,
+    name: ValidatedUserSlice<user::Read, u8>,
     pc: usize,
     a0: usize,
     a1: usize,
     a2: usize,
     sp: usize,
     tp: usize,
+    tls_size: usize,
+    tls_align: usize,
+    task_group: Option<CapabilityPtr>,
 ) -> SyscallOutcome {
     let current_tid = task.tid;
 
+    // New tasks stay in the same group as the task spawning them unless
+    // they're spawned into a different one
+    let task_group = match task_group {
+        Some(cptr) => match super::taskgroup::resolve(task, cptr) {
+            Ok(group) if !group.is_killed() => Some(group),
+            _ => return SyscallOutcome::Err(KError::InvalidArgument(11)),
+        },
+        None => task.task_group.clone(),
+    };
+
     let object = match task.vmspace_objects.remove(&id) {
         Some(map) => map,
         None => return SyscallOutcome::Err(KError::InvalidArgument(0)),
     };
 
-    let user_slice = RawUserSlice::readable(name, len);
-    let user_slice = match unsafe { user_slice.validate(&task.memory_manager) } {
-        Ok(slice) => slice,
-        Err((addr, e)) => {
-            log::error!("Bad memory from process: {:?}", e);
-            return SyscallOutcome::Err(KError::InvalidAccess(AccessError::Read(addr.as_ptr())));
-        }
+    let bytes = match name.to_vec() {
+        Ok(bytes) => bytes,
+        Err((addr, _)) => return SyscallOutcome::Err(KError::InvalidAccess(AccessError::Read(addr.as_ptr()))),
     };
 
-    let slice = user_slice.guarded();
-    let task_name = match core::str::from_utf8(&slice) {
+    let task_name = match core::str::from_utf8(&bytes) {
         Ok(s) => s,
         Err(_) => {
             log::error!("Invalid UTF-8 in FDT node name from process");
@@ -176,16 +186,35 @@ pub fn spawn_vmspace(
     );
     log::debug!("Memory map:\n{:#?}", object.memory_manager.address_map_debug(None));
 
+    if tls_align != 0 && !tls_align.is_power_of_two() {
+        return SyscallOutcome::Err(KError::InvalidArgument(10));
+    }
+
+    let tls = match (tp, tls_size) {
+        (0, _) | (_, 0) => None,
+        _ => match TlsTemplate::from_memory(&object.memory_manager, VirtualAddress::new(tp), tls_size, tls_align) {
+            Some(tls) => Some(tls),
+            None => return SyscallOutcome::Err(KError::InvalidArgument(9)),
+        },
+    };
+
     let mut new_task = Task {
         tid: Tid::new(NonZeroUsize::new(usize::MAX).unwrap()),
         name: alloc::string::String::from(task_name).into_boxed_str(),
+        thread_name: None,
         context: Context {
             pc,
             gp_regs: GeneralRegisters { a0, a1, a2, sp, tp, ..Default::default() },
             fp_regs: Default::default(),
         },
-        memory_manager: object.memory_manager,
+        group: ThreadGroup::new(object.memory_manager, tls),
+        task_group: task_group.clone(),
+        tls_block: None,
+        exit_futex: None,
         state: crate::task::TaskState::Running,
+        wait_generation: 0,
+        priority: DEFAULT_PRIORITY,
+        nice: 0,
         message_queue: MessageQueue::new(),
         promiscuous: true,
         incoming_channel_request: Default::default(),
@@ -194,6 +223,14 @@ pub fn spawn_vmspace(
         vmspace_objects: Default::default(),
         cspace: CapabilitySpace::new(),
         claimed_interrupts: BTreeMap::new(),
+        interrupt_waiters: WaitQueue::new(),
+        exit_status: None,
+        exit_watchers: Vec::new(),
+        events: Default::default(),
+        console_mode: Default::default(),
+        stdin: task.stdin.clone(),
+        stdout: task.stdout.clone(),
+        syscall_ring: None,
     };
 
     let this_new_channel_id = ChannelId::new(task.channels.last_key_value().map(|(id, _)| id.value() + 1).unwrap_or(0));
@@ -201,23 +238,249 @@ pub fn spawn_vmspace(
     new_task.channels.insert(ChannelId::new(0), (current_tid, channel1));
     new_task.cspace.mint(Capability {
         resource: CapabilityResource::Channel(ChannelId::new(0)),
-        rights: CapabilityRights::GRANT | CapabilityRights::READ | CapabilityRights::WRITE,
+        rights: CapabilityRights::GRANT | CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::TRANSFER,
+        badge: 0,
     });
 
     for region in object.inprocess_mappings {
-        task.memory_manager.dealloc_region(region);
+        task.group.memory_manager.lock().dealloc_region(region);
     }
 
     // FIXME: this is gross, should have a way to reserve a TID (or ideally not
     // need it at all) so we don't have to lock the task after insertion
 
     let tid = SCHEDULER.enqueue(new_task);
+    // See `create_thread`, the new task may have exited already
+    if let Some(watcher) = task_group.and_then(|task_group| task_group.join(tid)) {
+        super::exit::notify_watchers(ExitStatus::Killed, alloc::vec![watcher]);
+    }
 
     task.channels.insert(this_new_channel_id, (tid, channel2));
     let cptr = task.cspace.mint(Capability {
         resource: CapabilityResource::Channel(this_new_channel_id),
-        rights: CapabilityRights::GRANT | CapabilityRights::READ | CapabilityRights::WRITE,
+        rights: CapabilityRights::GRANT | CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::TRANSFER,
+        badge: 0,
     });
 
     SyscallOutcome::processed((tid.value(), cptr.value()))
 }
+
+pub fn handoff_capabilities(task: &mut Task, from: CapabilityPtr, to: CapabilityPtr) -> SyscallOutcome {
+    let current_tid = task.tid;
+
+    // Only a task which holds `GRANT` channels to both the old and new task
+    // (e.g. the one that spawned them) is allowed to move capabilities between
+    // them
+    let granting_peer = |cptr: CapabilityPtr| match task.cspace.resolve(cptr) {
+        Some(Capability { resource: CapabilityResource::Channel(cid), rights, .. })
+            if *rights & CapabilityRights::GRANT =>
+        {
+            task.channels.get(cid).map(|(tid, _)| *tid)
+        }
+        _ => None,
+    };
+
+    let old_tid = match granting_peer(from) {
+        Some(tid) => tid,
+        None => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+
+    let new_tid = match granting_peer(to) {
+        Some(tid) if tid != old_tid => tid,
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(1)),
+    };
+
+    let (old_task, new_task) = match (TASKS.get(old_tid), TASKS.get(new_tid)) {
+        (Some(old_task), Some(new_task)) => (old_task, new_task),
+        _ => return SyscallOutcome::Err(KError::InvalidArgument(0)),
+    };
+
+    if new_task.lock().state.is_dead() {
+        return SyscallOutcome::Err(KError::InvalidArgument(1));
+    }
+
+    if let Err(e) = super::hooks::capability_handoff(task, old_tid, new_tid) {
+        return SyscallOutcome::Err(e);
+    }
+
+    // Strip everything from the old task first, without holding any other
+    // locks, since tasks sending over a channel lock the receiving task while
+    // holding their own lock
+    let mut old = old_task.lock();
+    if !old.state.is_dead() {
+        return SyscallOutcome::Err(KError::InvalidArgument(0));
+    }
+
+    let old_name = old.name.clone();
+    let mut old_channels = core::mem::take(&mut old.channels);
+    let mut caps = Vec::new();
+    for (_, cap) in core::mem::replace(&mut old.cspace, CapabilitySpace::new()) {
+        match cap.resource {
+            CapabilityResource::Channel(cid) => match old_channels.remove(&cid) {
+                Some((peer, _)) if peer == current_tid || peer == new_tid => {}
+                Some((peer, mut channel)) => {
+                    channel.detach();
+                    caps.push(HandoffResource::Channel(peer, channel, cap.rights, cap.badge));
+                }
+                None => {}
+            },
+            CapabilityResource::Memory(region, _, kind) => caps.push(HandoffResource::Memory(region, kind, cap.rights)),
+            CapabilityResource::Mmio(vrange, interrupts, path) => {
+                let region = match old.group.memory_manager.lock().dealloc_region(vrange.start) {
+                    MemoryRegion::Backed(region) => region,
+                    _ => unreachable!(),
+                };
+
+                caps.push(HandoffResource::Mmio(region, interrupts, path, cap.rights));
+            }
+            // Callers waiting on the old task were failed when it exited
+            CapabilityResource::Reply(_) => {}
+            CapabilityResource::ConsoleStream(stream) => caps.push(HandoffResource::ConsoleStream(stream, cap.rights)),
+            CapabilityResource::Dma(dma_region, _) => caps.push(HandoffResource::Dma(dma_region, cap.rights)),
+            CapabilityResource::SystemControl => caps.push(HandoffResource::SystemControl(cap.rights)),
+            CapabilityResource::KernelLog => caps.push(HandoffResource::KernelLog(cap.rights)),
+            CapabilityResource::Perf => caps.push(HandoffResource::Perf(cap.rights)),
+            CapabilityResource::TaskGroup(group) => caps.push(HandoffResource::TaskGroup(group, cap.rights)),
+        }
+    }
+
+    // The old task can't complete any interrupts it was notified of, so do it
+    // on its behalf to keep the devices from going quiet
+    let claimed_interrupts = core::mem::take(&mut old.claimed_interrupts);
+    drop(old);
+
+    for (interrupt_id, hart) in claimed_interrupts {
+        crate::interrupts::complete_deferred(hart, interrupt_id);
+    }
+
+    // Point the other end of each channel at the new task before it can be
+    // notified of anything, any messages sent in the meantime stay queued and
+    // are announced below
+    for cap in &caps {
+        if let HandoffResource::Channel(peer, channel, ..) = cap {
+            if let Some(peer) = TASKS.get(*peer) {
+                let mut peer = peer.lock();
+                for (tid, peer_channel) in peer.channels.values_mut() {
+                    if *tid == old_tid && peer_channel.is_peer_of(channel) {
+                        *tid = new_tid;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut new = new_task.lock();
+    if new.state.is_dead() {
+        return SyscallOutcome::Err(KError::InvalidArgument(1));
+    }
+
+    log::debug!("Handing off {} capabilities from task {} to task {}", caps.len(), old_name, new.name);
+
+    let n_caps = caps.len();
+    for cap in caps {
+        match cap {
+            HandoffResource::Channel(peer, channel, rights, badge) => {
+                let channel_id =
+                    ChannelId::new(new.channels.last_key_value().map(|(id, _)| id.value() + 1).unwrap_or(0));
+                let pending = channel.pending_messages();
+                new.channels.insert(channel_id, (peer, channel));
+                let cptr =
+                    new.cspace.mint(Capability { resource: CapabilityResource::Channel(channel_id), rights, badge });
+
+                for _ in 0..pending {
+                    new.message_queue.push(Sender::kernel(), KernelNotification::NewChannelMessage(cptr).into());
+                }
+            }
+            HandoffResource::Memory(region, kind, rights) => {
+                let mut flags = flags::USER | flags::VALID | flags::READ;
+                if rights & CapabilityRights::WRITE {
+                    flags |= flags::WRITE;
+                }
+
+                let range = new.group.memory_manager.lock().apply_shared_region(None, flags, region.clone(), kind);
+                new.cspace.mint(Capability {
+                    resource: CapabilityResource::Memory(region, range, kind),
+                    rights,
+                    badge: 0,
+                });
+            }
+            HandoffResource::Mmio(region, interrupts, path, rights) => {
+                // The old task released the device when it exited, so it needs
+                // claiming again unless another task has beaten us to it
+                let first_claim = match crate::device::claim(&path, new_tid) {
+                    Ok((_, first_claim)) => first_claim,
+                    Err(e) => {
+                        log::warn!("Couldn't hand {} over to task {}: {:?}", path, new.name, e);
+                        continue;
+                    }
+                };
+
+                let size = region.page_count() * 4.kib();
+                let start = region.physical_addresses().next().unwrap();
+                // The region was unmapped from the old task above, and MMIO
+                // caps are unique in the system
+                let vrange = unsafe { new.group.memory_manager.lock().map_mmio_device(start, None, size, &path) };
+                new.cspace.mint(Capability {
+                    resource: CapabilityResource::Mmio(vrange, interrupts.clone(), path.clone()),
+                    rights,
+                    badge: 0,
+                });
+                super::route_interrupts(&path, interrupts, first_claim);
+            }
+            HandoffResource::ConsoleStream(stream, rights) => {
+                new.cspace.mint(Capability { resource: CapabilityResource::ConsoleStream(stream), rights, badge: 0 });
+            }
+            HandoffResource::SystemControl(rights) => {
+                new.cspace.mint(Capability { resource: CapabilityResource::SystemControl, rights, badge: 0 });
+            }
+            HandoffResource::KernelLog(rights) => {
+                new.cspace.mint(Capability { resource: CapabilityResource::KernelLog, rights, badge: 0 });
+            }
+            HandoffResource::Perf(rights) => {
+                new.cspace.mint(Capability { resource: CapabilityResource::Perf, rights, badge: 0 });
+            }
+            HandoffResource::TaskGroup(group, rights) => {
+                let cptr = new.cspace.mint(Capability {
+                    resource: CapabilityResource::TaskGroup(Arc::clone(&group)),
+                    rights,
+                    badge: 0,
+                });
+                group.replace_supervisor(old_tid, new_tid, cptr);
+            }
+            HandoffResource::Dma(dma_region, rights) => {
+                let mut flags = flags::USER | flags::VALID | flags::READ;
+                if rights & CapabilityRights::WRITE {
+                    flags |= flags::WRITE;
+                }
+
+                let range = new.group.memory_manager.lock().apply_shared_region(
+                    None,
+                    flags,
+                    dma_region.region.clone(),
+                    AddressRegionKind::Dma,
+                );
+
+                // Only shares need tracking, the owner can't be revoked from
+                if !(rights & CapabilityRights::GRANT) {
+                    dma_region.record_share(new_tid);
+                }
+
+                new.cspace.mint(Capability { resource: CapabilityResource::Dma(dma_region, range), rights, badge: 0 });
+            }
+        }
+    }
+
+    SyscallOutcome::processed(n_caps)
+}
+
+enum HandoffResource {
+    Channel(Tid, UserspaceChannel, CapabilityRights, usize),
+    Memory(SharedPhysicalRegion, AddressRegionKind, CapabilityRights),
+    Mmio(PhysicalRegion, Vec<usize>, String, CapabilityRights),
+    ConsoleStream(ConsoleStream, CapabilityRights),
+    Dma(Arc<DmaRegion>, CapabilityRights),
+    SystemControl(CapabilityRights),
+    KernelLog(CapabilityRights),
+    Perf(CapabilityRights),
+    TaskGroup(Arc<TaskGroup>, CapabilityRights),
+}
@@ -8,32 +8,38 @@
 use core::num::NonZeroUsize;
 
 use crate::{
-    capabilities::CapabilitySpace,
+    capabilities::{Capability, CapabilityResource, CapabilitySpace},
+    io::mux::ConsoleStream,
     mem::{
         manager::{AddressRegionKind, FillOption, MemoryManager, RegionDescription},
         paging::{
             flags::{EXECUTE, READ, USER, VALID, WRITE},
             PageSize, VirtualAddress,
         },
+        quota::{MemoryQuota, DEFAULT_MEMORY_QUOTA},
+    },
+    platform::devicetree,
+    scheduler::{Scheduler, WaitId, WakeToken, SCHEDULER},
+    syscall::{
+        channel::UserspaceChannel, poll::WaitQueue, ring::SyscallRing, taskgroup::TaskGroup, vmspace::VmspaceObject,
     },
-    platform::FDT,
-    scheduler::{Scheduler, WakeToken, SCHEDULER},
-    syscall::{channel::UserspaceChannel, vmspace::VmspaceObject},
     trap::{FloatingPointRegisters, GeneralRegisters},
-    utils::{round_up_to_next, Units},
+    utils::{round_up_to_next, SameHartDeadlockDetection, Units},
 };
 use alloc::{
     boxed::Box,
     collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::Arc,
     vec::Vec,
 };
 use elf64::{Elf, ProgramSegmentType, Relocation};
-use fdt::Fdt;
 use librust::{
+    capabilities::{CapabilityPtr, CapabilityRights},
     message::{Message, Sender},
-    syscalls::{channel::ChannelId, vmspace::VmspaceObjectId},
-    task::Tid,
+    syscalls::{channel::ChannelId, io::ConsoleMode, vmspace::VmspaceObjectId},
+    task::{ExitStatus, TaskEventMask, Tid, DEFAULT_PRIORITY},
 };
+use sync::SpinMutex;
 
 #[derive(Debug)]
 #[repr(C)]
@@ -45,6 +51,9 @@ pub struct ThreadControlBlock {
     pub saved_tp: usize,
     pub saved_gp: usize,
     pub kernel_stack_size: usize,
+    /// Top of a separate stack that traps are handled on when the kernel stack
+    /// has overflowed into its guard page
+    pub scratch_stack: *mut u8,
 }
 
 impl ThreadControlBlock {
@@ -57,6 +66,7 @@ impl ThreadControlBlock {
             saved_tp: 0,
             saved_gp: 0,
             kernel_stack_size: 0,
+            scratch_stack: core::ptr::null_mut(),
         }
     }
 
@@ -103,17 +113,153 @@ impl MessageQueue {
         self.queue.pop_front()
     }
 
+    /// Any token already registered is left over from a wait which was cut
+    /// short, so can't wake the task anymore
     pub fn register_wake(&mut self, token: WakeToken) {
-        assert!(self.wake.replace(token).is_none(), "replacing an already blocked message queue");
+        self.wake = Some(token);
+    }
+}
+
+/// State shared between all of the threads of a task. Each thread is its own
+/// [`Task`] as far as the scheduler is concerned, but they all run in the same
+/// address space. Capabilities, channels, and the message queue still belong
+/// to the individual threads.
+pub struct ThreadGroup {
+    /// Always lock the [`Task`] before the memory manager if both are needed.
+    ///
+    /// User pointers validated against the memory manager can be unmapped by
+    /// another thread in the group once it's unlocked, so user memory is only
+    /// ever accessed through [`crate::mem::user`], which fails the copy rather
+    /// than faulting the kernel when that happens.
+    pub memory_manager: SpinMutex<MemoryManager, SameHartDeadlockDetection>,
+    /// Used to create the TLS block of new threads, if the executable has any
+    /// thread-local storage
+    pub tls: Option<TlsTemplate>,
+    /// Charged for messages sent by any thread in the group until they're read
+    pub quota: Arc<MemoryQuota>,
+}
+
+impl ThreadGroup {
+    pub fn new(memory_manager: MemoryManager, tls: Option<TlsTemplate>) -> Arc<Self> {
+        Arc::new(Self {
+            memory_manager: SpinMutex::new(memory_manager),
+            tls,
+            quota: Arc::new(MemoryQuota::new(DEFAULT_MEMORY_QUOTA)),
+        })
+    }
+}
+
+/// Size of the thread control block and dynamic thread vector which come
+/// directly before the thread pointer. Userspace finds its TLS block for
+/// `__tls_get_addr` through them, but since there's no dynamic loading the
+/// vector only ever has a single module.
+const TLS_HEADER_SIZE: usize = 24;
+
+/// The initial contents of each thread's TLS block, from the `PT_TLS` segment
+/// of the executable
+pub struct TlsTemplate {
+    /// Initialized data, the rest of the block is zeroed
+    data: Box<[u8]>,
+    size: usize,
+    align: usize,
+}
+
+impl TlsTemplate {
+    pub fn new(data: &[u8], size: usize, align: usize) -> Self {
+        Self { data: Box::from(data), size: size.max(data.len()), align: align.max(8) }
+    }
+
+    /// Snapshot a TLS block that was set up by a userspace loader before its
+    /// thread has run, so it can be used as the template for more threads
+    pub fn from_memory(memory_manager: &MemoryManager, tp: VirtualAddress, size: usize, align: usize) -> Option<Self> {
+        let mut data = Vec::with_capacity(size);
+        let mut addr = tp;
+
+        while data.len() < size {
+            let page_remaining = 4.kib() - addr.as_usize() % 4.kib();
+            let len = page_remaining.min(size - data.len());
+            if !memory_manager.page_flags(addr).map(|flags| flags & USER).unwrap_or(false) {
+                return None;
+            }
+
+            let phys = memory_manager.resolve(addr)?;
+            let bytes = unsafe { core::slice::from_raw_parts(crate::mem::phys2virt(phys).as_ptr(), len) };
+
+            data.extend_from_slice(bytes);
+            addr = addr.add(len);
+        }
+
+        Some(Self::new(&data, size, align))
+    }
+
+    /// Allocate and initialize a new TLS block, returning the start of its
+    /// memory region and the thread pointer for it
+    pub fn instantiate(&self, memory_manager: &mut MemoryManager) -> (VirtualAddress, VirtualAddress) {
+        let tp_offset = round_up_to_next(TLS_HEADER_SIZE, self.align);
+        let n_pages = round_up_to_next(tp_offset + self.size, 4.kib()) / 4.kib();
+        let base = memory_manager.find_free_region(PageSize::Kilopage, n_pages);
+        let tp = base.add(tp_offset);
+
+        let mut contents = alloc::vec![0; n_pages * 4.kib()];
+        let header = &mut contents[tp_offset - TLS_HEADER_SIZE..tp_offset];
+        // Thread control block, pointing to the dynamic thread vector right
+        // after it
+        header[0..8].copy_from_slice(&(tp.as_usize() - 16).to_le_bytes());
+        // DTV generation, then the TLS block of the only module
+        header[8..16].copy_from_slice(&0usize.to_le_bytes());
+        header[16..24].copy_from_slice(&tp.as_usize().to_le_bytes());
+        contents[tp_offset..][..self.data.len()].copy_from_slice(&self.data);
+
+        memory_manager.alloc_region(
+            Some(base),
+            RegionDescription {
+                size: PageSize::Kilopage,
+                len: n_pages,
+                contiguous: false,
+                flags: USER | READ | WRITE | VALID,
+                fill: FillOption::Data(&contents),
+                kind: AddressRegionKind::Tls,
+            },
+        );
+
+        (base, tp)
+    }
+}
+
+pub struct DisplayName<'a>(&'a Task);
+
+impl core::fmt::Display for DisplayName<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match &self.0.thread_name {
+            Some(thread_name) => write!(f, "{}/{}", self.0.name, thread_name),
+            None => write!(f, "{}", self.0.name),
+        }
     }
 }
 
 pub struct Task {
     pub tid: Tid,
+    /// Name of the task, which threads start out with when they're created
     pub name: Box<str>,
+    /// Name of this thread in particular, if it's been given one
+    pub thread_name: Option<Box<str>>,
     pub context: Context,
-    pub memory_manager: MemoryManager,
+    pub group: Arc<ThreadGroup>,
+    /// The group of tasks this one is managed along with, if any
+    pub task_group: Option<Arc<TaskGroup>>,
+    /// TLS block allocated by the kernel when this thread was created, which
+    /// is freed when it exits
+    pub tls_block: Option<VirtualAddress>,
+    /// Set and woken when the thread exits, see
+    /// [`crate::syscall::thread::create_thread`]
+    pub exit_futex: Option<VirtualAddress>,
     pub state: TaskState,
+    /// Bumped each time the task is woken up, see [`Task::wait_id`]
+    pub wait_generation: u64,
+    /// Only used by [`crate::scheduler::priority::PriorityScheduler`]
+    pub priority: u8,
+    /// Only used by [`crate::scheduler::priority::PriorityScheduler`]
+    pub nice: i8,
     pub message_queue: MessageQueue,
     pub promiscuous: bool,
     pub incoming_channel_request: BTreeSet<Tid>,
@@ -122,16 +268,108 @@ pub struct Task {
     pub vmspace_next_id: usize,
     pub cspace: CapabilitySpace,
     pub claimed_interrupts: BTreeMap<usize, usize>,
+    /// Woken whenever one of the task's devices raises an interrupt
+    pub interrupt_waiters: WaitQueue,
+    /// Set once the task has exited
+    pub exit_status: Option<ExitStatus>,
+    pub exit_watchers: Vec<ExitWatcher>,
+    pub events: TaskEvents,
+    /// How console input is processed when this task reads it
+    pub console_mode: ConsoleMode,
+    pub stdin: ConsoleStream,
+    pub stdout: ConsoleStream,
+    /// Created on request by `SetupSyscallRing`, and only ever entered by
+    /// this thread
+    pub syscall_ring: Option<Arc<SyscallRing>>,
 }
 
 impl Task {
+    /// The task name followed by the thread name if there is one, for showing
+    /// to people trying to tell tasks apart
+    pub fn display_name(&self) -> DisplayName<'_> {
+        DisplayName(self)
+    }
+
+    /// The wait the task is blocked in, or will block in next if it's running,
+    /// which the wake tokens it hands out during a syscall are for
+    pub fn wait_id(&self) -> WaitId {
+        WaitId::new(self.tid, self.wait_generation)
+    }
+
+    /// Mark the task as dead, releasing any devices it claimed, and return the
+    /// tasks watching for it to exit. They need to be told with
+    /// [`crate::syscall::exit::notify_watchers`] once this task has been
+    /// unlocked.
+    #[must_use]
+    pub fn exit(&mut self, status: ExitStatus) -> Vec<ExitWatcher> {
+        self.state = TaskState::Dead;
+        self.exit_status = Some(status);
+
+        crate::device::release(self.tid);
+        crate::syscall::extension::release(self.tid);
+        crate::syscall::thread::wake_exit_futex(self);
+
+        // Any interrupts the task was notified of but never completed would
+        // otherwise keep the devices quiet for whoever claims them next
+        for (interrupt_id, hart) in core::mem::take(&mut self.claimed_interrupts) {
+            crate::interrupts::complete_deferred(hart, interrupt_id);
+        }
+
+        let mut watchers = core::mem::take(&mut self.exit_watchers);
+        if let Some(task_group) = &self.task_group {
+            watchers.extend(task_group.leave(self.tid));
+        }
+
+        // The reply capabilities the task holds are the calls it hasn't
+        // replied to, which it now never will
+        watchers.extend(self.cspace.all().filter_map(|(_, cap)| match cap.resource {
+            CapabilityResource::Reply(caller) => Some(ExitWatcher::Caller(caller)),
+            _ => None,
+        }));
+
+        watchers
+    }
+
+    /// If the task has a pending event and isn't already handling one, save its
+    /// current context and switch it to the event handler. Must only be called
+    /// while the task isn't running, so that its saved context is up to date.
+    pub fn deliver_pending_events(&mut self) {
+        if self.events.interrupted.is_some() {
+            return;
+        }
+
+        let event = match self.events.pending.first() {
+            Some(event) => event,
+            None => return,
+        };
+
+        self.events.pending = self.events.pending.without(event);
+
+        // The handler was removed after the event was sent
+        let handler = match self.events.handler {
+            Some(handler) => handler,
+            None => return,
+        };
+
+        let gp_regs = GeneralRegisters {
+            a0: event.to_raw(),
+            sp: handler.stack.as_usize(),
+            gp: self.context.gp_regs.gp,
+            tp: self.context.gp_regs.tp,
+            ..Default::default()
+        };
+        let handler_context = Context { gp_regs, fp_regs: self.context.fp_regs.clone(), pc: handler.entry.as_usize() };
+
+        self.events.interrupted = Some(core::mem::replace(&mut self.context, handler_context));
+    }
+
     pub fn load<'a, I>(name: &str, elf: &Elf, args: I) -> Self
     where
         I: Iterator<Item = &'a str> + Clone,
     {
         let mut memory_manager = MemoryManager::new();
 
-        let cspace = CapabilitySpace::new();
+        let mut cspace = CapabilitySpace::new();
 
         let relocations = elf
             .relocations()
@@ -256,38 +494,9 @@ impl Task {
         }
 
         let tls = elf.program_headers().find(|header| header.r#type == elf64::ProgramSegmentType::Tls).map(|header| {
-            let n_pages_needed = round_up_to_next(header.memory_size as usize + 8 + 16, 4.kib()) / 4.kib();
-            let tls_base = memory_manager.find_free_region(PageSize::Kilopage, n_pages_needed);
-
-            let segment_len = header.file_size as usize + 8 + 16;
-
-            if segment_data.len() < segment_len {
-                segment_data.resize(segment_len, 0);
-            }
-
-            let segment_file_size = header.file_size as usize;
-            let tls_base_addr = tls_base.as_usize();
-            segment_data[0..][..8].copy_from_slice(&(tls_base_addr + 8).to_le_bytes()[..]); // ->|
-            segment_data[8..][..8].copy_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]); // <-|
-            segment_data[16..][..8].copy_from_slice(&(tls_base_addr + 24).to_le_bytes()[..]);
-
-            segment_data[24..][..segment_file_size].copy_from_slice(elf.program_segment_data(&header));
-            segment_data[segment_file_size..segment_len].fill(0);
-
-            memory_manager.alloc_region(
-                Some(tls_base),
-                RegionDescription {
-                    size: PageSize::Kilopage,
-                    len: n_pages_needed,
-                    contiguous: false,
-                    flags: USER | READ | WRITE | VALID,
-                    fill: FillOption::Data(&segment_data[..segment_len]),
-                    kind: AddressRegionKind::Tls,
-                },
-            );
-
-            tls_base_addr + 24
+            TlsTemplate::new(elf.program_segment_data(&header), header.memory_size as usize, header.align as usize)
         });
+        let tp = tls.as_ref().map(|tls| tls.instantiate(&mut memory_manager).1);
 
         // We guard the stack on both ends, though a stack underflow is
         // unlikely, but better to be safe than sorry!
@@ -302,22 +511,31 @@ impl Task {
             })
             .add(16.kib());
 
-        let fdt_ptr = FDT.load(core::sync::atomic::Ordering::Acquire);
-        let fdt_loc = {
-            let fdt = unsafe { Fdt::from_ptr(fdt_ptr) }.unwrap();
-            let slice = unsafe { core::slice::from_raw_parts(fdt_ptr, fdt.total_size()) };
-            memory_manager.alloc_region(
-                None,
-                RegionDescription {
-                    size: PageSize::Kilopage,
-                    len: round_up_to_next(fdt.total_size(), 4.kib()) / 4.kib(),
-                    contiguous: false,
-                    flags: USER | READ | VALID,
-                    fill: FillOption::Data(slice),
-                    kind: AddressRegionKind::Data,
-                },
-            )
-        };
+        // The device tree is mapped directly instead of copied, and `init`
+        // receives a read-only capability to it so that it can be passed along
+        // to whichever servers need it
+        let device_tree = devicetree::resident().expect("device tree was never made resident");
+        let fdt_loc = memory_manager.apply_shared_region(
+            None,
+            USER | READ | VALID,
+            device_tree.region.clone(),
+            AddressRegionKind::ReadOnly,
+        );
+        cspace.mint(Capability {
+            resource: CapabilityResource::Memory(device_tree.region, fdt_loc.clone(), AddressRegionKind::ReadOnly),
+            rights: CapabilityRights::READ | CapabilityRights::TRANSFER | CapabilityRights::MAP,
+            badge: 0,
+        });
+        cspace.mint(Capability {
+            resource: CapabilityResource::SystemControl,
+            rights: CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::TRANSFER,
+            badge: 0,
+        });
+        cspace.mint(Capability {
+            resource: CapabilityResource::KernelLog,
+            rights: CapabilityRights::READ | CapabilityRights::TRANSFER,
+            badge: 0,
+        });
 
         let arg_count = args.clone().count();
         let (a0, a1) = match arg_count {
@@ -356,7 +574,7 @@ impl Task {
             pc: pc.as_usize(),
             gp_regs: GeneralRegisters {
                 sp: sp.as_usize(),
-                tp: tls.unwrap_or(0),
+                tp: tp.map(VirtualAddress::as_usize).unwrap_or(0),
                 a0,
                 a1,
                 a2: fdt_loc.start.as_usize(),
@@ -368,9 +586,16 @@ impl Task {
         Self {
             tid: Tid::new(NonZeroUsize::new(usize::MAX).unwrap()),
             name: Box::from(name),
+            thread_name: None,
             context,
-            memory_manager,
+            group: ThreadGroup::new(memory_manager, tls),
+            task_group: None,
+            tls_block: None,
+            exit_futex: None,
             state: TaskState::Running,
+            wait_generation: 0,
+            priority: DEFAULT_PRIORITY,
+            nice: 0,
             promiscuous: true,
             incoming_channel_request: BTreeSet::new(),
             channels: BTreeMap::new(),
@@ -379,6 +604,57 @@ impl Task {
             vmspace_next_id: 0,
             cspace,
             claimed_interrupts: BTreeMap::new(),
+            interrupt_waiters: WaitQueue::new(),
+            exit_status: None,
+            exit_watchers: Vec::new(),
+            events: TaskEvents::default(),
+            console_mode: ConsoleMode::default(),
+            stdin: ConsoleStream::default(),
+            stdout: ConsoleStream::default(),
+            syscall_ring: None,
+        }
+    }
+}
+
+/// Asynchronous event state of a task, see [`crate::syscall::events`]
+#[derive(Debug, Default)]
+pub struct TaskEvents {
+    pub handler: Option<EventHandler>,
+    /// Events which are handled by `handler` instead of terminating the task
+    pub mask: TaskEventMask,
+    /// Events which have been sent but not delivered to the handler yet
+    pub pending: TaskEventMask,
+    /// Context of the task from before it was diverted to the event handler
+    pub interrupted: Option<Context>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EventHandler {
+    pub entry: VirtualAddress,
+    pub stack: VirtualAddress,
+}
+
+/// A task waiting for another to exit
+#[derive(Debug, Clone, Copy)]
+pub enum ExitWatcher {
+    /// Blocked in the `Wait` syscall
+    Blocked(WaitId),
+    /// Waiting for a [`librust::message::KernelNotification::TaskExited`] about the task on the
+    /// other end of the channel
+    Notify(Tid, CapabilityPtr),
+    /// Supervising the task group the task is in, and waiting for a
+    /// [`librust::message::KernelNotification::TaskGroupExited`] once it's empty
+    TaskGroup(Tid, CapabilityPtr),
+    /// Blocked in a call the task holds the reply capability for, which fails
+    /// with [`librust::error::KError::InvalidRecipient`]
+    Caller(WaitId),
+}
+
+impl ExitWatcher {
+    pub fn tid(&self) -> Tid {
+        match self {
+            Self::Blocked(wait) | Self::Caller(wait) => wait.tid(),
+            Self::Notify(tid, _) | Self::TaskGroup(tid, _) => *tid,
         }
     }
 }
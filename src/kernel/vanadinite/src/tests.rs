@@ -10,22 +10,26 @@
 #![allow(clippy::not_unsafe_ptr_arg_deref)]
 
 use crate::{
-    cpu_local, csr,
-    drivers::{generic::plic::Plic, CompatibleWith},
-    interrupts,
+    cpu_local, csr, interrupts,
     mem::{self, paging::PhysicalAddress, phys2virt},
     platform::{self, ExitStatus},
     task, trap,
     utils::Units,
-    HART_ID, N_CPUS, TIMER_FREQ,
+    BOOT_TIME, HART_ID, N_CPUS, TIMER_FREQ,
 };
 use alloc::boxed::Box;
-use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use fdt::Fdt;
 
+/// Index of the test currently running, so a failure can be reported in the
+/// exit code
+static CURRENT_TEST: AtomicUsize = AtomicUsize::new(NO_TEST);
+const NO_TEST: usize = usize::MAX;
+
 #[no_mangle]
 #[repr(align(4))]
 pub extern "C" fn ktest(hart_id: usize, fdt: *const u8) -> ! {
+    BOOT_TIME.store(csr::time::read(), Ordering::Relaxed);
     csr::stvec::set(trap::stvec_trap_shim);
 
     unsafe { cpu_local::init_thread_locals() };
@@ -33,8 +37,10 @@ pub extern "C" fn ktest(hart_id: usize, fdt: *const u8) -> ! {
 
     crate::io::logging::init_logging();
 
-    mem::heap::HEAP_ALLOCATOR.init(64.mib());
+    mem::heap::HEAP_ALLOCATOR.init(8.mib());
 
+    let fdt = platform::devicetree::init(fdt);
+    platform::FDT.store(fdt, Ordering::Release);
     let fdt: Fdt<'static> = match unsafe { Fdt::from_ptr(fdt) } {
         Ok(fdt) => fdt,
         Err(e) => crate::platform::exit(crate::platform::ExitStatus::Error(&e)),
@@ -44,6 +50,9 @@ pub extern "C" fn ktest(hart_id: usize, fdt: *const u8) -> ! {
     let timebase_frequency = current_cpu.timebase_frequency();
     TIMER_FREQ.store(timebase_frequency as u64, Ordering::Relaxed);
 
+    crate::crypto::rand::init(&fdt);
+    crate::device::init(&fdt);
+
     let stdout = fdt.chosen().stdout();
     if let Some((_, reg, compatible)) = stdout.and_then(|n| Some((n, n.reg()?.next()?, n.compatible()?))) {
         let stdout_addr = reg.starting_address as *mut u8;
@@ -59,37 +68,8 @@ pub extern "C" fn ktest(hart_id: usize, fdt: *const u8) -> ! {
     let n_cpus = fdt.cpus().count();
     N_CPUS.store(n_cpus, Ordering::Release);
 
-    if let Some(ic) = fdt.find_compatible(Plic::compatible_with()) {
-        let reg = ic.reg().unwrap().next().unwrap();
-        let ic_phys = PhysicalAddress::from_ptr(reg.starting_address);
-        let ic_virt = phys2virt(ic_phys);
-
-        // Number of interrupts available
-        let ndevs = ic
-            .properties()
-            .find(|p| p.name == "riscv,ndev")
-            .and_then(|p| p.as_usize())
-            .expect("missing number of interrupts");
-
-        // Find harts which have S-mode available
-        let contexts = fdt
-            .cpus()
-            .filter(|cpu| {
-                cpu.properties()
-                    .find(|p| p.name == "riscv,isa")
-                    .and_then(|p| p.as_str()?.chars().find(|c| *c == 's'))
-                    .is_some()
-            })
-            .map(|cpu| platform::plic_context_for(cpu.ids().first()));
-
-        let plic = unsafe { &*ic_virt.as_ptr().cast::<Plic>() };
-
-        plic.init(ndevs, contexts);
-        plic.set_context_threshold(platform::current_plic_context(), 0);
-
-        log::debug!("Registering PLIC @ {:#p}", ic_virt);
-        interrupts::register_plic(plic);
-    }
+    interrupts::probe(&fdt);
+    interrupts::set_hart_threshold(0);
 
     let ptr = Box::leak(Box::new(task::ThreadControlBlock {
         kernel_stack: mem::alloc_kernel_stack(8.kib()),
@@ -99,6 +79,7 @@ pub extern "C" fn ktest(hart_id: usize, fdt: *const u8) -> ! {
         saved_tp: 0,
         saved_gp: 0,
         kernel_stack_size: 8.kib(),
+        scratch_stack: mem::alloc_kernel_stack(mem::SCRATCH_STACK_SIZE),
     }));
 
     csr::sscratch::write(ptr as *mut _ as usize);
@@ -111,10 +92,26 @@ pub extern "C" fn ktest(hart_id: usize, fdt: *const u8) -> ! {
 
 #[cfg(test)]
 pub fn test_runner(tests: &[&dyn Fn()]) {
-    crate::println!("\nRunning {} tests", tests.len());
-    for test in tests {
+    let info = platform::system_info();
+    crate::println!("\nvanadinite {} ({}, {}) on {}", info.version, info.commit, info.profile.as_str(), info.platform);
+
+    // The test runner passes along the commit it built so we can make sure it
+    // isn't running a stale kernel
+    let fdt = unsafe { Fdt::from_ptr(platform::FDT.load(Ordering::Acquire)) }.unwrap();
+    let expected_commit =
+        fdt.chosen().bootargs().and_then(|args| args.split(' ').find_map(|arg| arg.strip_prefix("expected-commit=")));
+
+    if let Some(expected_commit) = expected_commit {
+        assert_eq!(info.commit.as_str(), expected_commit, "kernel was built from a different commit");
+    }
+
+    crate::println!("Running {} tests", tests.len());
+    for (index, test) in tests.iter().enumerate() {
+        CURRENT_TEST.store(index, Ordering::Release);
         test();
     }
+
+    CURRENT_TEST.store(NO_TEST, Ordering::Release);
 }
 
 #[test]
@@ -126,5 +123,13 @@ fn it_works() {
 pub fn panic(info: &core::panic::PanicInfo) -> ! {
     crate::println!("{}failed{}: {}", crate::io::terminal::RED, crate::io::terminal::CLEAR, info);
 
-    platform::exit(ExitStatus::Error(&"test failed"))
+    // Anything going wrong outside of a test is treated like any other kernel
+    // panic
+    let status = match (crate::panicking::exit_status(), CURRENT_TEST.load(Ordering::Acquire)) {
+        (ExitStatus::Panic, NO_TEST) => ExitStatus::Panic,
+        (ExitStatus::Panic, index) => ExitStatus::TestFailed(index),
+        (status, _) => status,
+    };
+
+    platform::exit(status)
 }
@@ -0,0 +1,90 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The time page, which every address space maps read-only at
+//! [`TIME_PAGE_ADDRESS`] so that tasks can read the time without a syscall.
+//! See [`librust::time`] for its layout and how it's read.
+
+use crate::{
+    csr,
+    mem::{
+        paging::PageSize,
+        phys2virt,
+        region::{SharedPhysicalRegion, UniquePhysicalRegion},
+    },
+    BOOT_TIME, N_CPUS, TIMER_FREQ,
+};
+use core::sync::atomic::{fence, AtomicPtr, Ordering};
+use librust::time::{ticks_to_ns, TimePage};
+use sync::SpinMutex;
+
+pub use librust::time::TIME_PAGE_ADDRESS;
+
+/// Also serializes updates to the page
+static REGION: SpinMutex<Option<SharedPhysicalRegion>> = SpinMutex::new(None);
+static PAGE: AtomicPtr<TimePage> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Allocate the page, which has to happen before any address spaces are
+/// created and after the random number generator is seeded, and let userspace
+/// read the `time` CSR on this hart
+pub fn init() {
+    let mut region = UniquePhysicalRegion::alloc_contiguous(PageSize::Kilopage, 1);
+    region.zero();
+
+    let page: *mut TimePage = phys2virt(region.physical_addresses().next().unwrap()).as_mut_ptr().cast();
+
+    let mut stack_guard = [0; 8];
+    crate::crypto::rand::fill_bytes(&mut stack_guard);
+    stack_guard[0] = 0;
+    unsafe { (*page).stack_guard.store(u64::from_le_bytes(stack_guard), Ordering::Relaxed) };
+
+    *REGION.lock() = Some(region.into_shared_region());
+    PAGE.store(page, Ordering::Release);
+
+    publish(None);
+    csr::scounteren::enable_time();
+}
+
+/// The page to map into a new address space, `None` before [`init`]
+pub fn region() -> Option<SharedPhysicalRegion> {
+    REGION.lock().clone()
+}
+
+/// Update the page with the current timebase frequency, along with the time
+/// since the Unix epoch if it's known as `(time CSR, nanoseconds)` read
+/// together
+pub fn publish(realtime: Option<(u64, u64)>) {
+    let _region = REGION.lock();
+    let page = match unsafe { PAGE.load(Ordering::Acquire).as_ref() } {
+        Some(page) => page,
+        None => return,
+    };
+
+    let frequency = TIMER_FREQ.load(Ordering::Relaxed);
+    let boot_ticks = BOOT_TIME.load(Ordering::Relaxed);
+    let realtime_offset_ns = match realtime {
+        Some((ticks, ns)) => ns.saturating_sub(ticks_to_ns(ticks - boot_ticks, frequency)),
+        None => page.realtime_offset_ns.load(Ordering::Relaxed),
+    };
+
+    page.sequence.fetch_add(1, Ordering::Relaxed);
+    fence(Ordering::Release);
+
+    page.timebase_frequency.store(frequency, Ordering::Relaxed);
+    page.boot_ticks.store(boot_ticks, Ordering::Relaxed);
+    page.realtime_offset_ns.store(realtime_offset_ns, Ordering::Relaxed);
+    page.n_harts.store(N_CPUS.load(Ordering::Acquire), Ordering::Relaxed);
+
+    page.sequence.fetch_add(1, Ordering::Release);
+}
+
+/// Advance the scheduler epoch, called on each timer interrupt
+pub fn tick() {
+    if let Some(page) = unsafe { PAGE.load(Ordering::Acquire).as_ref() } {
+        page.scheduler_epoch.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,107 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Tracepoints in the scheduler, syscall, IPC, and page fault paths which
+//! write timestamped [`TraceRecord`]s into a ring for each hart, for measuring
+//! latencies that logging would throw off. Events are only recorded when
+//! enabled with the `trace=` bootarg, otherwise a tracepoint is a single load.
+
+use crate::csr;
+use alloc::{collections::VecDeque, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use librust::syscalls::trace::{TraceEvent, TraceRecord};
+use sync::{Lazy, SpinMutex};
+
+/// Records kept for each hart before the oldest start being dropped
+const RECORDS_PER_HART: usize = 2048;
+
+/// Mask of [`TraceEvent::bit`]s to record
+static ENABLED: AtomicUsize = AtomicUsize::new(0);
+
+static HARTS: Lazy<Vec<SpinMutex<Ring>>> = Lazy::new(|| {
+    let n_cpus = crate::N_CPUS.load(Ordering::Acquire);
+    let mut v = Vec::with_capacity(n_cpus);
+
+    for _ in 0..n_cpus {
+        v.push(SpinMutex::new(Ring { records: VecDeque::with_capacity(RECORDS_PER_HART), dropped: 0 }));
+    }
+
+    v
+});
+
+struct Ring {
+    records: VecDeque<TraceRecord>,
+    dropped: usize,
+}
+
+/// Parse the value of the `trace=` bootarg, a comma separated list of event
+/// names, with everything traced if no value is given
+pub fn parse_trace_events(value: Option<&str>) {
+    let mask = match value {
+        None | Some("all") => TraceEvent::ALL.into_iter().fold(0, |mask, event| mask | event.bit()),
+        Some(names) => names.split(',').fold(0, |mask, name| {
+            match TraceEvent::ALL.into_iter().find(|event| event.name() == name) {
+                Some(event) => mask | event.bit(),
+                None => {
+                    log::warn!("Unknown trace event: `{}`", name);
+                    mask
+                }
+            }
+        }),
+    };
+
+    ENABLED.store(mask, Ordering::Relaxed);
+}
+
+/// Allocate the rings if anything is being traced, which must happen once the
+/// number of harts is known and before any tracepoints are hit
+pub fn init() {
+    if ENABLED.load(Ordering::Relaxed) != 0 {
+        let _ = HARTS.len();
+        log::info!("Tracing enabled, keeping {} records per hart", RECORDS_PER_HART);
+    }
+}
+
+/// Record `event` for the task `tid` on the current hart
+#[inline]
+pub fn record(event: TraceEvent, tid: usize, args: [usize; 2]) {
+    if ENABLED.load(Ordering::Relaxed) & event.bit() == 0 {
+        return;
+    }
+
+    let hart = crate::HART_ID.get();
+    let record = TraceRecord { timestamp: csr::time::read(), event: event as usize, hart, tid, args };
+
+    let mut ring = HARTS[hart].lock();
+    if ring.records.len() == RECORDS_PER_HART {
+        ring.records.pop_front();
+        ring.dropped += 1;
+    }
+
+    ring.records.push_back(record);
+}
+
+/// Take up to `max` records, oldest first on each hart, along with how many
+/// have been dropped since the last call
+pub fn take(max: usize) -> (Vec<TraceRecord>, usize) {
+    let mut records = Vec::new();
+    let mut dropped = 0;
+
+    if ENABLED.load(Ordering::Relaxed) == 0 {
+        return (records, dropped);
+    }
+
+    for ring in HARTS.iter() {
+        let mut ring = ring.lock();
+        let n = ring.records.len().min(max - records.len());
+
+        records.extend(ring.records.drain(..n));
+        dropped += core::mem::take(&mut ring.dropped);
+    }
+
+    (records, dropped)
+}
@@ -7,7 +7,7 @@
 
 use crate::{
     csr::sstatus,
-    interrupts::{isr::invoke_isr, PLIC},
+    interrupts::isr::invoke_isr,
     mem::{
         manager::AddressRegion,
         paging::{flags, VirtualAddress},
@@ -15,8 +15,9 @@ use crate::{
     },
     scheduler::{Scheduler, SCHEDULER},
     syscall,
-    task::TaskState,
 };
+use core::{cell::SyncUnsafeCell, fmt::Write, sync::atomic::AtomicUsize};
+use librust::{syscalls::trace::TraceEvent, task::ExitStatus};
 
 #[derive(Debug, Clone, Copy, Default)]
 #[repr(C)]
@@ -126,6 +127,9 @@ pub enum Trap {
     SupervisorExternalInterrupt = INTERRUPT_BIT | 9,
     MachineExternalInterrupt = INTERRUPT_BIT | 11,
 
+    // Counter overflow, from the `Sscofpmf` extension
+    SupervisorCounterOverflowInterrupt = INTERRUPT_BIT | 13,
+
     // General faults/ecalls
     InstructionAddressMisaligned = 0,
     InstructionAccessFault = 1,
@@ -162,6 +166,8 @@ impl Trap {
             0x8000000000000009 => SupervisorExternalInterrupt,
             0x800000000000000B => MachineExternalInterrupt,
 
+            0x800000000000000D => SupervisorCounterOverflowInterrupt,
+
             0 => InstructionAddressMisaligned,
             1 => InstructionAccessFault,
             2 => IllegalInstruction,
@@ -190,57 +196,126 @@ pub extern "C" fn trap_handler(regs: &mut TrapFrame, sepc: usize, scause: usize,
     let trap_kind = Trap::from_cause(scause);
     match trap_kind {
         Trap::SupervisorTimerInterrupt => {
-            if let Some(lock) = SCHEDULER.active_on_cpu() {
-                let mut lock = lock.lock();
+            crate::metrics::TIMER_TICKS.increment();
 
-                lock.context.pc = sepc;
-                lock.context.gp_regs = regs.registers;
-
-                if let sstatus::FloatingPointStatus::Dirty = sstatus::fs() {
-                    save_fp_registers(&mut lock.context.fp_regs);
-                }
+            // `sscratch` holds the hart's thread control block while in the
+            // trap handler
+            let tcb = unsafe { &*(crate::csr::sscratch::read() as *const crate::task::ThreadControlBlock) };
+            if !crate::mem::kernel_stack_intact(tcb.kernel_stack, tcb.kernel_stack_size) {
+                panic!("kernel stack overflow on hart {}", crate::HART_ID.get());
             }
 
-            SCHEDULER.schedule()
+            crate::platform::cpufreq::tick();
+            crate::platform::clock::tick();
+            crate::interrupts::rate_limit::tick();
+            crate::syscall::poll::tick();
+            crate::syscall::ring::tick();
+            crate::io::klog::tick();
+            crate::timepage::tick();
+            crate::perf::tick(sepc);
+            preempt(regs, sepc)
         }
         Trap::UserModeEnvironmentCall => syscall::handle(regs, sepc),
+        Trap::SupervisorSoftwareInterrupt => {
+            crate::csr::sip::clear_ssip();
+            crate::mem::tlb::handle_shootdowns();
+
+            // Harts are also kicked when tasks are migrated to or away from
+            // them, or when a task that should preempt the current one wakes
+            match crate::scheduler::take_reschedule_request() || SCHEDULER.reschedule_pending() {
+                true => preempt(regs, sepc),
+                false => sepc,
+            }
+        }
+        Trap::SupervisorCounterOverflowInterrupt => {
+            crate::perf::overflow(sepc);
+            sepc
+        }
         Trap::SupervisorExternalInterrupt => {
-            // FIXME: there has to be a better way
-            if let Some(plic) = &*PLIC.lock() {
-                if let Some(claimed) = plic.claim(crate::platform::current_plic_context()) {
+            crate::crypto::rand::add_interrupt_timing();
+
+            // ISRs can't wait for memory to be freed, so let them fall back on
+            // the hart's reserve. This has to end before possibly preempting,
+            // which never returns.
+            let critical = crate::mem::phys::reserve::Critical::enter();
+            for controller in crate::interrupts::controllers() {
+                if let Some(claimed) = controller.claim(crate::HART_ID.get()) {
                     log::debug!("External interrupt for: {:?}", claimed);
 
                     let interrupt_id = claimed.interrupt_id();
-                    match invoke_isr(plic, claimed, interrupt_id) {
+                    match invoke_isr(claimed) {
                         Ok(_) => log::trace!("ISR (interrupt ID: {}) completed successfully", interrupt_id),
                         Err(e) => log::error!("Error during ISR: {}", e),
                     }
                 }
             }
 
-            sepc
+            // Everything claimed has been completed or handed off, so the work
+            // the ISRs deferred can run without holding up the controllers
+            crate::interrupts::deferred::run_pending();
+            drop(critical);
+
+            match crate::scheduler::take_reschedule_request() {
+                true => preempt(regs, sepc),
+                false => sepc,
+            }
         }
         Trap::LoadPageFault | Trap::StorePageFault | Trap::InstructionPageFault => {
+            crate::metrics::PAGE_FAULTS.increment();
             let sepc = VirtualAddress::new(sepc);
             let stval = VirtualAddress::new(stval);
             match sepc.is_kernel_region() {
                 // We should always have marked memory regions up front from the initial mapping
                 true => {
+                    if let Some(fixup) = crate::mem::user::fault_fixup(sepc) {
+                        log::debug!("Fault copying user memory @ stval={:#p}", stval);
+                        return fixup;
+                    }
+
+                    // Another hart may have grown the heap after this one
+                    // cached the page as unmapped
+                    if crate::mem::heap::in_heap_region(stval)
+                        && crate::mem::paging::active_page_flags(stval).map_or(false, |f| f & flags::VALID)
+                    {
+                        crate::mem::sfence(Some(stval), None);
+                        return sepc.as_usize();
+                    }
+
+                    let tcb = unsafe { &*(crate::csr::sscratch::read() as *const crate::task::ThreadControlBlock) };
+                    let guard = crate::mem::kernel_stack_guard(tcb.kernel_stack, tcb.kernel_stack_size);
+                    if guard.contains(&stval.as_usize()) {
+                        crate::backtrace::set_trapped_context(sepc.as_usize(), regs.registers.s0);
+                        panic!(
+                            "kernel stack overflow on hart {} @ pc={:#p}: sp={:#x}",
+                            crate::HART_ID.get(),
+                            sepc,
+                            regs.registers.sp
+                        );
+                    }
+
                     let active = SCHEDULER.active_on_cpu().unwrap();
 
+                    // The fault may have happened while accessing user memory
+                    // with the memory manager locked
                     match active.try_lock() {
-                        Some(active) => log::error!(
-                            "Process memory map during error:\n{:#?}",
-                            active.memory_manager.address_map_debug(Some(stval))
-                        ),
+                        Some(active) => match active.group.memory_manager.try_lock() {
+                            Some(memory_manager) => log::error!(
+                                "Process memory map during error:\n{:#?}",
+                                memory_manager.address_map_debug(Some(stval))
+                            ),
+                            None => log::error!("Deadlock would have occurred for process map printing"),
+                        },
                         None => log::error!("Deadlock would have occurred for process map printing"),
                     }
+                    crate::backtrace::set_trapped_context(sepc.as_usize(), regs.registers.s0);
                     panic!("[KERNEL BUG] {:?} @ pc={:#p}: stval={:#p} regs={:x?}", trap_kind, sepc, stval, regs);
                 }
                 false => {
+                    let critical = crate::mem::phys::reserve::Critical::enter();
                     let active_task_lock = SCHEDULER.active_on_cpu().unwrap();
-                    let mut active_task = active_task_lock.lock();
-                    let memory_manager = &mut active_task.memory_manager;
+                    let active_task = active_task_lock.lock();
+                    crate::trace::record(TraceEvent::PageFault, active_task.tid.value(), [stval.as_usize(), scause]);
+                    let mut memory_manager = active_task.group.memory_manager.lock();
 
                     //log::info!("{:#?}", memory_manager.region_for(stval));
 
@@ -261,6 +336,9 @@ pub extern "C" fn trap_handler(regs: &mut TrapFrame, sepc: usize, scause: usize,
                                 }
                             }
                             Trap::StorePageFault => match memory_manager.page_flags(stval) {
+                                Some(flags) if !(flags & flags::WRITE) => {
+                                    memory_manager.is_copy_on_write(stval) && memory_manager.break_snapshot(stval)
+                                }
                                 Some(flags) => {
                                     (flags & flags::WRITE)
                                         && memory_manager
@@ -274,13 +352,14 @@ pub extern "C" fn trap_handler(regs: &mut TrapFrame, sepc: usize, scause: usize,
 
                     match valid {
                         true => {
+                            drop(memory_manager);
                             crate::mem::sfence(Some(stval), None);
                             sepc.as_usize()
                         }
                         false => {
                             log::error!(
                                 "Process {} died to a {:?} @ {:#p} (PC: {:#p})",
-                                active_task.name,
+                                active_task.display_name(),
                                 trap_kind,
                                 stval,
                                 sepc,
@@ -292,42 +371,131 @@ pub extern "C" fn trap_handler(regs: &mut TrapFrame, sepc: usize, scause: usize,
                             //     log::error!("{:#p}: {:#x}", sp, unsafe { *sp });
                             //     sp = unsafe { sp.offset(1) };
                             // }
-                            log::error!(
-                                "Memory map:\n{:#?}",
-                                active_task.memory_manager.address_map_debug(Some(stval))
-                            );
-                            active_task.state = TaskState::Dead;
-
+                            log::error!("Memory map:\n{:#?}", memory_manager.address_map_debug(Some(stval)));
+                            drop(memory_manager);
                             drop(active_task);
                             drop(active_task_lock);
+                            drop(critical);
 
-                            SCHEDULER.schedule()
+                            kill_faulted_task(regs, scause, sepc.as_usize(), stval.as_usize())
                         }
                     }
                 }
             }
         }
-        trap => panic!("Ignoring trap: {:?}, sepc: {:#x}, stval: {:#x}", trap, sepc, stval),
+        Trap::InstructionAddressMisaligned
+        | Trap::InstructionAccessFault
+        | Trap::IllegalInstruction
+        | Trap::Breakpoint
+        | Trap::LoadAddressMisaligned
+        | Trap::LoadAccessFault
+        | Trap::StoreAddressMisaligned
+        | Trap::StoreAccessFault
+            if !VirtualAddress::new(sepc).is_kernel_region() =>
+        {
+            log::error!(
+                "Process {} died to a {:?} (PC: {:#p}, stval: {:#x})",
+                SCHEDULER.active_on_cpu().unwrap().lock().display_name(),
+                trap_kind,
+                sepc as *const u8,
+                stval,
+            );
+            log::error!("Register dump:\n{:#x?}", regs);
+
+            kill_faulted_task(regs, scause, sepc, stval)
+        }
+        trap => {
+            if VirtualAddress::new(sepc).is_kernel_region() {
+                crate::backtrace::set_trapped_context(sepc, regs.registers.s0);
+            }
+
+            panic!("Ignoring trap: {:?}, sepc: {:#x}, stval: {:#x}", trap, sepc, stval)
+        }
+    }
+}
+
+/// Kill the active task after a fault it can't recover from, keeping a crash
+/// dump of it around for anything that wants to inspect it later
+fn kill_faulted_task(regs: &TrapFrame, scause: usize, sepc: usize, stval: usize) -> ! {
+    let critical = crate::mem::phys::reserve::Critical::enter();
+    let active_task_lock = SCHEDULER.active_on_cpu().unwrap();
+    let mut active_task = active_task_lock.lock();
+
+    let crash_id = crate::crash::record(&active_task, &regs.registers, scause, sepc, stval);
+    let status = ExitStatus::Faulted { crash_id };
+    let watchers = active_task.exit(status);
+
+    drop(active_task);
+    drop(active_task_lock);
+    drop(critical);
+
+    syscall::exit::notify_watchers(status, watchers);
+    SCHEDULER.schedule()
+}
+
+/// Save the state of the active task (if any) and schedule the next one
+fn preempt(regs: &TrapFrame, sepc: usize) -> ! {
+    if let Some(lock) = SCHEDULER.active_on_cpu() {
+        let mut lock = lock.lock();
+
+        lock.context.pc = sepc;
+        lock.context.gp_regs = regs.registers;
+
+        if let sstatus::FloatingPointStatus::Dirty = sstatus::fs() {
+            save_fp_registers(&mut lock.context.fp_regs);
+        }
     }
+
+    SCHEDULER.schedule()
 }
 
+/// Offset into [`stvec_trap_shim`] of the jump to [`stvec_double_fault_shim`].
+/// The shim is aligned so that this bit of its address is clear, which lets
+/// `stvec` be switched between the two by setting and clearing it without
+/// needing a free register.
+const DOUBLE_FAULT_ENTRY: usize = 16;
+
 /// # Safety
 /// nice try
 #[naked]
 #[no_mangle]
-#[repr(align(4))]
+#[repr(align(32))]
 pub unsafe extern "C" fn stvec_trap_shim() -> ! {
     #[rustfmt::skip]
     core::arch::asm!("
+        j 2f
+        .balign {double_fault_entry}
+        j {double_fault_shim}
+
+    2:
         # Disable interrupts
         csrci sstatus, 2
         csrrw s0, sscratch, s0
 
+        # Anything that faults before the trap frame is saved goes to the
+        # double fault handler instead of back here
+        csrsi stvec, {double_fault_entry}
+
         sd sp, 24(s0)
         sd tp, 32(s0)
         sd gp, 40(s0)
 
+        # If the stack pointer is in the guard page below the kernel stack, the
+        # stack overflowed, so put the trap frame on the scratch stack instead
+        # of over what's left of the kernel stack. `tp` and `gp` are free to
+        # use until they're loaded.
+        ld tp, 0(s0)
+        ld gp, 48(s0)
+        sub tp, tp, gp
+        sub gp, tp, sp
+        addi gp, gp, -1
+        li tp, 4096
+
         ld sp, 0(s0)
+        bgeu gp, tp, 1f
+        ld sp, 56(s0)
+
+    1:
         ld tp, 8(s0)
         ld gp, 16(s0)
 
@@ -385,6 +553,9 @@ pub unsafe extern "C" fn stvec_trap_shim() -> ! {
         sd x30, 232(sp)
         sd x31, 240(sp)
 
+        # The trap frame is safely saved, so faults can go through here again
+        csrci stvec, {double_fault_entry}
+
         mv a0, sp
         csrr a1, sepc
         csrr a2, scause
@@ -436,11 +607,111 @@ pub unsafe extern "C" fn stvec_trap_shim() -> ! {
 
         # gtfo
         sret
-    ", options(noreturn));
+    ",
+        double_fault_entry = const DOUBLE_FAULT_ENTRY,
+        double_fault_shim = sym stvec_double_fault_shim,
+        options(noreturn),
+    );
+}
+
+/// Number of harts which can double fault and still report it, any after that
+/// are parked silently
+const EMERGENCY_STACKS: usize = 8;
+const EMERGENCY_STACK_SIZE: usize = 1 << 13;
+
+#[repr(C, align(16))]
+struct EmergencyStack([u8; EMERGENCY_STACK_SIZE]);
+
+static EMERGENCY_STACK_MEMORY: SyncUnsafeCell<[EmergencyStack; EMERGENCY_STACKS]> =
+    SyncUnsafeCell::new([const { EmergencyStack([0; EMERGENCY_STACK_SIZE]) }; EMERGENCY_STACKS]);
+static EMERGENCY_STACKS_CLAIMED: AtomicUsize = AtomicUsize::new(0);
+
+/// Jumped to from [`stvec_trap_shim`] while it's saving the trap frame, so
+/// that a bad `sscratch` or kernel stack pointer ends up here instead of
+/// faulting in the shim forever. Nothing the shim set up can be trusted, so
+/// this claims one of the emergency stacks and reports the fault.
+///
+/// # Safety
+/// nice try
+#[naked]
+#[no_mangle]
+#[repr(align(4))]
+pub unsafe extern "C" fn stvec_double_fault_shim() -> ! {
+    #[rustfmt::skip]
+    core::arch::asm!("
+        csrr a0, sepc
+        csrr a1, scause
+        csrr a2, stval
+        mv a3, sp
+        # The thread control block pointer the shim was using
+        mv a4, s0
+
+        lla t0, {claimed}
+        li t1, 1
+        amoadd.d t1, t1, (t0)
+        li t2, {n_stacks}
+        bgeu t1, t2, 1f
+
+        # Stacks grow down, so start at the end of the claimed one
+        addi t1, t1, 1
+        li t2, {stack_size}
+        mul t1, t1, t2
+        lla sp, {stacks}
+        add sp, sp, t1
+
+        .option push
+        .option norelax
+        lla gp, __global_pointer$
+        .option pop
+
+        # Stop backtraces here
+        li s0, 0
+        call {handler}
+
+    1:
+        wfi
+        j 1b
+    ",
+        claimed = sym EMERGENCY_STACKS_CLAIMED,
+        stacks = sym EMERGENCY_STACK_MEMORY,
+        n_stacks = const EMERGENCY_STACKS,
+        stack_size = const EMERGENCY_STACK_SIZE,
+        handler = sym double_fault,
+        options(noreturn),
+    );
+}
+
+/// Report a fault in the trap shim through the SBI console, which doesn't
+/// depend on any kernel state, then park the hart
+extern "C" fn double_fault(sepc: usize, scause: usize, stval: usize, sp: usize, tcb: usize) -> ! {
+    struct SbiConsole;
+
+    impl core::fmt::Write for SbiConsole {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            s.bytes().for_each(sbi::legacy::console_putchar);
+            Ok(())
+        }
+    }
+
+    let _ = writeln!(
+        SbiConsole,
+        "\r\n[DOUBLE FAULT] {:?} in the trap handler @ pc={:#x}: stval={:#x} sp={:#x} sscratch={:#x}\r",
+        Trap::from_cause(scause),
+        sepc,
+        stval,
+        sp,
+        tcb
+    );
+    let _ = writeln!(SbiConsole, "[DOUBLE FAULT] Parking hart\r");
+
+    let _ = sbi::hart_state_management::hart_stop();
+    loop {
+        unsafe { core::arch::asm!("wfi") };
+    }
 }
 
 #[rustfmt::skip]
-extern "C" fn save_fp_registers(fp_regs: &mut FloatingPointRegisters) {
+pub(crate) extern "C" fn save_fp_registers(fp_regs: &mut FloatingPointRegisters) {
     unsafe {
         core::arch::asm!("
                 fsd f0, 0({regs})
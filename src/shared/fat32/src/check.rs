@@ -0,0 +1,330 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{BlockDevice, Error, Fat32, BAD_CLUSTER, END_OF_CHAIN, SECTOR_SIZE};
+use alloc::{string::String, vec, vec::Vec};
+
+/// Owner of a cluster which isn't part of any file but was already reported
+/// as lost
+const LOST: u32 = u32::MAX;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The FAT copies differ, only the first one is used
+    FatMismatch { sector: u32 },
+    /// Allocated clusters that no file or directory refers to
+    LostChain { first: u32, len: u32 },
+    /// A cluster belongs to more than one file
+    CrossLinked { cluster: u32, path: String, other: String },
+    /// A chain runs into a free, bad, or nonexistent cluster, or back into
+    /// itself, after `cluster`
+    BrokenChain { path: String, cluster: u32 },
+    /// A file's size doesn't match the length of its chain
+    SizeMismatch { path: String, size: u32, clusters: u32 },
+}
+
+impl core::fmt::Display for Problem {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Problem::FatMismatch { sector } => write!(f, "FAT copies differ in sector {}", sector),
+            Problem::LostChain { first, len } => write!(f, "{} lost cluster(s) starting at {}", len, first),
+            Problem::CrossLinked { cluster, path, other } => {
+                write!(f, "{} is cross-linked with {} at cluster {}", path, other, cluster)
+            }
+            Problem::BrokenChain { path, cluster } => {
+                write!(f, "{} has a broken chain after cluster {}", path, cluster)
+            }
+            Problem::SizeMismatch { path, size, clusters } => {
+                write!(f, "{} is {} bytes but has {} cluster(s)", path, size, clusters)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+    pub files: u32,
+    pub directories: u32,
+    pub used_clusters: u32,
+    /// Whether the problems which can be fixed were
+    pub repaired: bool,
+}
+
+impl<B: BlockDevice> Fat32<B> {
+    /// Check the consistency of the volume. With `repair`, the FAT copies are
+    /// synchronized, lost clusters are freed, and broken chains are
+    /// terminated, while cross-linked clusters and size mismatches are only
+    /// reported.
+    pub fn check(&mut self, repair: bool) -> Result<Report, Error<B::Error>> {
+        let mut checker = Checker {
+            fs: self,
+            repair,
+            owners: Vec::new(),
+            paths: Vec::new(),
+            report: Report { repaired: repair, ..Report::default() },
+        };
+
+        checker.owners = vec![0; checker.fs.cluster_count() as usize + 2];
+        checker.compare_fats()?;
+        checker.walk_tree()?;
+        checker.find_lost()?;
+
+        Ok(checker.report)
+    }
+}
+
+struct Checker<'a, B: BlockDevice> {
+    fs: &'a mut Fat32<B>,
+    repair: bool,
+    /// Index into `paths` plus one of the file each cluster belongs to, zero
+    /// if it hasn't been seen yet
+    owners: Vec<u32>,
+    paths: Vec<String>,
+    report: Report,
+}
+
+impl<B: BlockDevice> Checker<'_, B> {
+    fn compare_fats(&mut self) -> Result<(), Error<B::Error>> {
+        let bpb = self.fs.bpb;
+        let mut first = [0; SECTOR_SIZE];
+        let mut copy = [0; SECTOR_SIZE];
+
+        for sector in 0..bpb.sectors_per_fat {
+            let (first_sector, _) = self.fs.fat_sector(0, sector * (SECTOR_SIZE as u32 / 4));
+            self.fs.device.read_sector(first_sector, &mut first).map_err(Error::Device)?;
+
+            for fat in 1..bpb.num_fats {
+                let (copy_sector, _) = self.fs.fat_sector(fat, sector * (SECTOR_SIZE as u32 / 4));
+                self.fs.device.read_sector(copy_sector, &mut copy).map_err(Error::Device)?;
+
+                if first != copy {
+                    self.report.problems.push(Problem::FatMismatch { sector });
+                    if self.repair {
+                        self.fs.device.write_sector(copy_sector, &first).map_err(Error::Device)?;
+                    }
+                }
+            }
+        }
+
+        // The FAT was changed behind the cache's back
+        self.fs.fat_cache = None;
+
+        Ok(())
+    }
+
+    fn walk_tree(&mut self) -> Result<(), Error<B::Error>> {
+        let mut directories = vec![(self.fs.root_cluster(), String::from("/"))];
+
+        while let Some((first, path)) = directories.pop() {
+            self.report.directories += 1;
+            let clusters = self.walk_chain(first, path.clone())?;
+
+            for entry in self.fs.read_directory_clusters(&clusters)? {
+                let entry_path = alloc::format!("{}{}", path, entry.name);
+
+                if entry.is_directory() {
+                    // Directories without clusters are already reported as
+                    // broken by walking their (empty) chain
+                    directories.push((entry.first_cluster, entry_path + "/"));
+                    continue;
+                }
+
+                self.report.files += 1;
+                if entry.first_cluster == 0 && entry.size == 0 {
+                    continue;
+                }
+
+                let clusters = self.walk_chain(entry.first_cluster, entry_path.clone())?.len() as u32;
+                let cluster_size = self.fs.cluster_size() as u32;
+                if clusters != entry.size.div_ceil(cluster_size) {
+                    self.report.problems.push(Problem::SizeMismatch { path: entry_path, size: entry.size, clusters });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Claim the clusters in the chain for the file at `path`, returning the
+    /// ones which weren't already claimed by something else
+    fn walk_chain(&mut self, first: u32, path: String) -> Result<Vec<u32>, Error<B::Error>> {
+        self.paths.push(path);
+        let owner = self.paths.len() as u32;
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+
+        loop {
+            if !self.fs.is_valid_cluster(cluster) {
+                self.broken_chain(owner, clusters.last().copied())?;
+                break;
+            }
+
+            match self.owners[cluster as usize] {
+                0 => self.owners[cluster as usize] = owner,
+                existing if existing == owner => {
+                    self.broken_chain(owner, clusters.last().copied())?;
+                    break;
+                }
+                existing => {
+                    let other = self.paths[existing as usize - 1].clone();
+                    let path = self.paths[owner as usize - 1].clone();
+                    self.report.problems.push(Problem::CrossLinked { cluster, path, other });
+                    break;
+                }
+            }
+
+            clusters.push(cluster);
+            self.report.used_clusters += 1;
+
+            match self.fs.fat_entry(cluster)? {
+                next if next >= END_OF_CHAIN => break,
+                next => cluster = next,
+            }
+        }
+
+        Ok(clusters)
+    }
+
+    fn broken_chain(&mut self, owner: u32, last: Option<u32>) -> Result<(), Error<B::Error>> {
+        let path = self.paths[owner as usize - 1].clone();
+        self.report.problems.push(Problem::BrokenChain { path, cluster: last.unwrap_or(0) });
+
+        match last {
+            Some(last) if self.repair => self.fs.set_fat_entry(last, END_OF_CHAIN),
+            _ => Ok(()),
+        }
+    }
+
+    /// Find allocated clusters which weren't reached from the root directory,
+    /// reporting them as chains where possible
+    fn find_lost(&mut self) -> Result<(), Error<B::Error>> {
+        let end = self.owners.len() as u32;
+        let mut lost = Vec::new();
+        let mut targets = vec![false; self.owners.len()];
+
+        for cluster in 2..end {
+            let entry = self.fs.fat_entry(cluster)?;
+            if entry != 0 && entry != BAD_CLUSTER && self.owners[cluster as usize] == 0 {
+                lost.push(cluster);
+                if self.fs.is_valid_cluster(entry) {
+                    targets[entry as usize] = true;
+                }
+            }
+        }
+
+        // Report chains from their heads first, anything left over is part of
+        // a loop
+        let heads = lost.iter().filter(|&&cluster| !targets[cluster as usize]);
+        for &first in heads.chain(lost.iter()).collect::<Vec<_>>() {
+            if self.owners[first as usize] != 0 {
+                continue;
+            }
+
+            let mut len = 0;
+            let mut cluster = first;
+            while self.fs.is_valid_cluster(cluster) && self.owners[cluster as usize] == 0 {
+                let next = self.fs.fat_entry(cluster)?;
+                if next == 0 || next == BAD_CLUSTER {
+                    break;
+                }
+
+                self.owners[cluster as usize] = LOST;
+                len += 1;
+                cluster = next;
+            }
+
+            self.report.problems.push(Problem::LostChain { first, len });
+        }
+
+        if self.repair {
+            for cluster in lost {
+                self.fs.set_fat_entry(cluster, 0)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{add_file, format};
+
+    #[test]
+    fn clean_volume() {
+        let mut fs = format();
+        add_file(&mut fs, b"HELLO   TXT", 0, &[3, 4], 1000);
+        add_file(&mut fs, b"EMPTY      ", 0, &[], 0);
+
+        let report = fs.check(false).unwrap();
+        assert_eq!(report.problems, []);
+        assert_eq!((report.files, report.directories, report.used_clusters), (2, 1, 3));
+    }
+
+    #[test]
+    fn lost_and_cross_linked_clusters() {
+        let mut fs = format();
+        add_file(&mut fs, b"FIRST      ", 0, &[3, 4], 1024);
+        add_file(&mut fs, b"SECOND     ", 0, &[5], 512);
+        fs.set_fat_entry(5, 4).unwrap();
+        fs.set_fat_entry(10, 11).unwrap();
+        fs.set_fat_entry(11, END_OF_CHAIN).unwrap();
+        // A loop with no way into it
+        fs.set_fat_entry(20, 21).unwrap();
+        fs.set_fat_entry(21, 20).unwrap();
+
+        let report = fs.check(true).unwrap();
+        assert_eq!(
+            report.problems,
+            [
+                Problem::CrossLinked { cluster: 4, path: "/SECOND".into(), other: "/FIRST".into() },
+                Problem::LostChain { first: 10, len: 2 },
+                Problem::LostChain { first: 20, len: 2 },
+            ]
+        );
+
+        // Only the cross-link can't be repaired
+        let report = fs.check(false).unwrap();
+        assert_eq!(
+            report.problems,
+            [Problem::CrossLinked { cluster: 4, path: "/SECOND".into(), other: "/FIRST".into() }]
+        );
+        assert_eq!(fs.fat_entry(10).unwrap(), 0);
+    }
+
+    #[test]
+    fn broken_chains_and_sizes() {
+        let mut fs = format();
+        add_file(&mut fs, b"BROKEN     ", 0, &[3, 4], 1024);
+        add_file(&mut fs, b"SHORT      ", 0, &[5], 2048);
+        fs.set_fat_entry(4, 0).unwrap();
+
+        let report = fs.check(true).unwrap();
+        assert_eq!(
+            report.problems,
+            [
+                Problem::BrokenChain { path: "/BROKEN".into(), cluster: 4 },
+                Problem::SizeMismatch { path: "/SHORT".into(), size: 2048, clusters: 1 },
+            ]
+        );
+
+        assert_eq!(fs.fat_entry(4).unwrap(), END_OF_CHAIN);
+    }
+
+    #[test]
+    fn fat_mismatch() {
+        let mut fs = format();
+        let second_fat = 33 * SECTOR_SIZE;
+        fs.device().0[second_fat + 12] = 0xFF;
+
+        let report = fs.check(true).unwrap();
+        assert_eq!(report.problems, [Problem::FatMismatch { sector: 0 }]);
+        assert_eq!(fs.check(false).unwrap().problems, []);
+    }
+}
@@ -0,0 +1,330 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A write-ahead log of whole sectors, which can be layered under a filesystem
+//! so related metadata updates are applied all at once or not at all.
+//!
+//! Writes made between [`Journal::begin`] and [`Journal::commit`] are held in
+//! memory. Committing first writes them to the log along with a header listing
+//! where they belong, then writes them in place, then clears the header. If
+//! the system goes down before the header is written nothing happened, and if
+//! it goes down after, the writes are replayed from the log by
+//! [`Journal::open`]. Writes outside of a transaction go straight to the
+//! device.
+
+use super::{BlockDevice, SECTOR_SIZE};
+use alloc::collections::BTreeMap;
+
+const MAGIC: [u8; 8] = *b"VJOURNAL";
+const HEADER_LEN: usize = 28;
+/// Number of sector numbers which fit in the header after its fixed fields
+pub const MAX_TRANSACTION_LEN: usize = (SECTOR_SIZE - HEADER_LEN) / 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalError<E> {
+    Device(E),
+    /// The transaction writes to more sectors than fit in the log
+    TransactionFull,
+}
+
+pub struct Journal<B: BlockDevice> {
+    device: B,
+    log_start: u64,
+    capacity: usize,
+    sequence: u64,
+    /// Writes in the open transaction by sector, `None` if there isn't one
+    pending: Option<BTreeMap<u64, [u8; SECTOR_SIZE]>>,
+}
+
+impl<B: BlockDevice> Journal<B> {
+    /// Use the `log_len` sectors starting at `log_start` for the log, replaying
+    /// any transaction which was committed but not completely written. A log
+    /// too small to hold a transaction disables journaling, and all writes go
+    /// straight to the device.
+    pub fn open(device: B, log_start: u64, log_len: u64) -> Result<Self, JournalError<B::Error>> {
+        let capacity = (log_len.saturating_sub(1) as usize).min(MAX_TRANSACTION_LEN);
+        let mut journal = Self { device, log_start, capacity, sequence: 0, pending: None };
+        if capacity > 0 {
+            journal.replay()?;
+        }
+
+        Ok(journal)
+    }
+
+    pub fn device(&mut self) -> &mut B {
+        &mut self.device
+    }
+
+    pub fn into_inner(self) -> B {
+        self.device
+    }
+
+    /// Start buffering writes, they aren't visible on the device until
+    /// [`Journal::commit`]
+    pub fn begin(&mut self) {
+        if self.capacity > 0 {
+            self.pending.get_or_insert_with(BTreeMap::new);
+        }
+    }
+
+    /// Drop the writes made since [`Journal::begin`]
+    pub fn abort(&mut self) {
+        self.pending = None;
+    }
+
+    pub fn commit(&mut self) -> Result<(), JournalError<B::Error>> {
+        let pending = match self.pending.take() {
+            Some(pending) if !pending.is_empty() => pending,
+            _ => return Ok(()),
+        };
+
+        let mut checksum = Checksum::new();
+        for (i, (&sector, data)) in pending.iter().enumerate() {
+            checksum.update(&sector.to_le_bytes());
+            checksum.update(data);
+            self.write(self.log_start + 1 + i as u64, data)?;
+        }
+
+        self.sequence += 1;
+        let mut header = [0; SECTOR_SIZE];
+        header[0..8].copy_from_slice(&MAGIC);
+        header[8..16].copy_from_slice(&self.sequence.to_le_bytes());
+        header[16..20].copy_from_slice(&(pending.len() as u32).to_le_bytes());
+        header[20..28].copy_from_slice(&checksum.finish().to_le_bytes());
+        for (slot, &sector) in header[HEADER_LEN..].chunks_exact_mut(8).zip(pending.keys()) {
+            slot.copy_from_slice(&sector.to_le_bytes());
+        }
+
+        // Once this is written the transaction will be replayed if anything
+        // after it fails
+        self.write(self.log_start, &header)?;
+
+        for (&sector, data) in &pending {
+            self.write(sector, data)?;
+        }
+
+        self.clear()
+    }
+
+    fn replay(&mut self) -> Result<(), JournalError<B::Error>> {
+        let mut header = [0; SECTOR_SIZE];
+        self.read(self.log_start, &mut header)?;
+
+        if header[0..8] != MAGIC {
+            return Ok(());
+        }
+
+        self.sequence = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let len = u32::from_le_bytes(header[16..20].try_into().unwrap()) as usize;
+        let expected = u64::from_le_bytes(header[20..28].try_into().unwrap());
+        if len == 0 || len > self.capacity {
+            return Ok(());
+        }
+
+        let sectors = header[HEADER_LEN..].chunks_exact(8).take(len).map(|s| u64::from_le_bytes(s.try_into().unwrap()));
+        let mut writes = BTreeMap::new();
+        let mut checksum = Checksum::new();
+        for (i, sector) in sectors.enumerate() {
+            let mut data = [0; SECTOR_SIZE];
+            self.read(self.log_start + 1 + i as u64, &mut data)?;
+            checksum.update(&sector.to_le_bytes());
+            checksum.update(&data);
+            writes.insert(sector, data);
+        }
+
+        // The header is only written after the data, so this means it was
+        // damaged rather than the transaction being incomplete
+        if checksum.finish() != expected {
+            return self.clear();
+        }
+
+        for (sector, data) in &writes {
+            self.write(*sector, data)?;
+        }
+
+        self.clear()
+    }
+
+    /// Mark the log as empty, keeping the sequence number
+    fn clear(&mut self) -> Result<(), JournalError<B::Error>> {
+        let mut header = [0; SECTOR_SIZE];
+        header[0..8].copy_from_slice(&MAGIC);
+        header[8..16].copy_from_slice(&self.sequence.to_le_bytes());
+        self.write(self.log_start, &header)
+    }
+
+    fn read(&mut self, sector: u64, buffer: &mut [u8; SECTOR_SIZE]) -> Result<(), JournalError<B::Error>> {
+        self.device.read_sector(sector, buffer).map_err(JournalError::Device)
+    }
+
+    fn write(&mut self, sector: u64, buffer: &[u8; SECTOR_SIZE]) -> Result<(), JournalError<B::Error>> {
+        self.device.write_sector(sector, buffer).map_err(JournalError::Device)
+    }
+}
+
+impl<B: BlockDevice> BlockDevice for Journal<B> {
+    type Error = JournalError<B::Error>;
+
+    fn read_sector(&mut self, sector: u64, buffer: &mut [u8; SECTOR_SIZE]) -> Result<(), Self::Error> {
+        match self.pending.as_ref().and_then(|pending| pending.get(&sector)) {
+            Some(data) => {
+                *buffer = *data;
+                Ok(())
+            }
+            None => self.read(sector, buffer),
+        }
+    }
+
+    fn write_sector(&mut self, sector: u64, buffer: &[u8; SECTOR_SIZE]) -> Result<(), Self::Error> {
+        match &mut self.pending {
+            Some(pending) if pending.len() < self.capacity || pending.contains_key(&sector) => {
+                pending.insert(sector, *buffer);
+                Ok(())
+            }
+            Some(_) => Err(JournalError::TransactionFull),
+            None => self.write(sector, buffer),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.device.flush().map_err(JournalError::Device)
+    }
+}
+
+/// FNV-1a, which is plenty for telling a torn header from a complete one
+struct Checksum(u64);
+
+impl Checksum {
+    fn new() -> Self {
+        Self(0xCBF2_9CE4_8422_2325)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01B3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::MemoryDevice;
+    use alloc::vec;
+
+    /// A device which stops working after a number of writes, like a system
+    /// going down in the middle of a commit
+    struct FailingDevice {
+        inner: MemoryDevice,
+        writes_left: usize,
+    }
+
+    impl BlockDevice for FailingDevice {
+        type Error = ();
+
+        fn read_sector(&mut self, sector: u64, buffer: &mut [u8; SECTOR_SIZE]) -> Result<(), Self::Error> {
+            self.inner.read_sector(sector, buffer).map_err(drop)
+        }
+
+        fn write_sector(&mut self, sector: u64, buffer: &[u8; SECTOR_SIZE]) -> Result<(), Self::Error> {
+            match self.writes_left.checked_sub(1) {
+                Some(left) => self.writes_left = left,
+                None => return Err(()),
+            }
+
+            self.inner.write_sector(sector, buffer).map_err(drop)
+        }
+    }
+
+    const LOG_START: u64 = 10;
+
+    fn sector(device: &mut MemoryDevice, sector: u64) -> u8 {
+        device.0[sector as usize * SECTOR_SIZE]
+    }
+
+    /// Commit writes of 1 and 2 to sectors 1 and 2, with the device failing
+    /// after `writes` writes, then reopen the journal
+    fn crash_during_commit(writes: usize) -> MemoryDevice {
+        let device = FailingDevice { inner: MemoryDevice(vec![0; 32 * SECTOR_SIZE]), writes_left: usize::MAX };
+        let mut journal = Journal::open(device, LOG_START, 8).unwrap();
+
+        journal.begin();
+        journal.write_sector(1, &[1; SECTOR_SIZE]).unwrap();
+        journal.write_sector(2, &[2; SECTOR_SIZE]).unwrap();
+        journal.device().writes_left = writes;
+        let _ = journal.commit();
+
+        let inner = journal.into_inner().inner;
+        Journal::open(inner, LOG_START, 8).unwrap().into_inner()
+    }
+
+    #[test]
+    fn transactions_are_buffered() {
+        let mut journal = Journal::open(MemoryDevice(vec![0; 32 * SECTOR_SIZE]), LOG_START, 8).unwrap();
+        let mut buffer = [0; SECTOR_SIZE];
+
+        journal.begin();
+        journal.write_sector(1, &[1; SECTOR_SIZE]).unwrap();
+        journal.read_sector(1, &mut buffer).unwrap();
+        assert_eq!(buffer, [1; SECTOR_SIZE]);
+        assert_eq!(sector(journal.device(), 1), 0);
+
+        journal.abort();
+        journal.read_sector(1, &mut buffer).unwrap();
+        assert_eq!(buffer, [0; SECTOR_SIZE]);
+
+        journal.begin();
+        journal.write_sector(1, &[1; SECTOR_SIZE]).unwrap();
+        journal.commit().unwrap();
+        assert_eq!(sector(journal.device(), 1), 1);
+    }
+
+    #[test]
+    fn transactions_are_limited_to_the_log_size() {
+        let mut journal = Journal::open(MemoryDevice(vec![0; 32 * SECTOR_SIZE]), LOG_START, 3).unwrap();
+
+        journal.begin();
+        journal.write_sector(1, &[1; SECTOR_SIZE]).unwrap();
+        journal.write_sector(2, &[2; SECTOR_SIZE]).unwrap();
+        journal.write_sector(1, &[3; SECTOR_SIZE]).unwrap();
+        assert_eq!(journal.write_sector(3, &[3; SECTOR_SIZE]), Err(JournalError::TransactionFull));
+    }
+
+    #[test]
+    fn empty_log_disables_journaling() {
+        let mut journal = Journal::open(MemoryDevice(vec![0; 32 * SECTOR_SIZE]), LOG_START, 0).unwrap();
+
+        journal.begin();
+        journal.write_sector(1, &[1; SECTOR_SIZE]).unwrap();
+        assert_eq!(sector(journal.device(), 1), 1);
+        journal.commit().unwrap();
+        assert_eq!(sector(journal.device(), LOG_START), 0);
+    }
+
+    #[test]
+    fn crash_before_header_is_written() {
+        // Only the data made it to the log
+        for writes in 0..=2 {
+            let mut device = crash_during_commit(writes);
+            assert_eq!((sector(&mut device, 1), sector(&mut device, 2)), (0, 0));
+        }
+    }
+
+    #[test]
+    fn crash_after_header_is_written() {
+        // The header and possibly some of the writes in place made it to the
+        // device
+        for writes in 3..=6 {
+            let mut device = crash_during_commit(writes);
+            assert_eq!((sector(&mut device, 1), sector(&mut device, 2)), (1, 2));
+        }
+    }
+}
@@ -0,0 +1,481 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2021 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! FAT32 volumes on top of any [`BlockDevice`] with 512 byte sectors.
+//!
+//! Volumes track whether they were unmounted cleanly with the clean shutdown
+//! bit in the second FAT entry. The bit is cleared before the first write, and
+//! set again by [`Fat32::mark_clean`] once everything has been written, so a
+//! volume which isn't clean when it's mounted should be checked with
+//! [`Fat32::check`]. Metadata updates can be made atomic by mounting the volume
+//! on top of a [`journal::Journal`].
+
+#![no_std]
+
+extern crate alloc;
+
+mod check;
+pub mod journal;
+
+pub use check::{Problem, Report};
+
+use alloc::{string::String, vec, vec::Vec};
+
+pub const SECTOR_SIZE: usize = 512;
+
+const BOOT_SIGNATURE: u16 = 0xAA55;
+const CLUSTER_MASK: u32 = 0x0FFF_FFFF;
+const BAD_CLUSTER: u32 = 0x0FFF_FFF7;
+/// Entries at or above this value mark the end of a cluster chain
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+/// Set in the second FAT entry when the volume was unmounted cleanly
+const CLEAN_SHUTDOWN: u32 = 0x0800_0000;
+
+const DIRECTORY_ENTRY_SIZE: usize = 32;
+const ATTRIBUTE_VOLUME_ID: u8 = 0x08;
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+const ATTRIBUTE_LONG_NAME: u8 = 0x0F;
+const ENTRY_FREE: u8 = 0xE5;
+const ENTRY_END: u8 = 0x00;
+
+pub trait BlockDevice {
+    type Error;
+
+    fn read_sector(&mut self, sector: u64, buffer: &mut [u8; SECTOR_SIZE]) -> Result<(), Self::Error>;
+    fn write_sector(&mut self, sector: u64, buffer: &[u8; SECTOR_SIZE]) -> Result<(), Self::Error>;
+
+    /// Make sure every completed write has reached stable storage, for devices
+    /// which cache writes
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error<E> {
+    Device(E),
+    /// The volume isn't FAT32, or uses something which isn't supported
+    InvalidVolume(&'static str),
+    /// A cluster outside of the volume was accessed
+    InvalidCluster(u32),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BiosParameterBlock {
+    sectors_per_cluster: u8,
+    reserved_sectors: u16,
+    num_fats: u8,
+    total_sectors: u32,
+    sectors_per_fat: u32,
+    root_cluster: u32,
+}
+
+impl BiosParameterBlock {
+    fn parse(sector: &[u8; SECTOR_SIZE]) -> Result<Self, &'static str> {
+        let u16_at = |offset: usize| u16::from_le_bytes([sector[offset], sector[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([sector[offset], sector[offset + 1], sector[offset + 2], sector[offset + 3]])
+        };
+
+        let bpb = Self {
+            sectors_per_cluster: sector[0x0D],
+            reserved_sectors: u16_at(0x0E),
+            num_fats: sector[0x10],
+            total_sectors: u32_at(0x20),
+            sectors_per_fat: u32_at(0x24),
+            root_cluster: u32_at(0x2C),
+        };
+
+        match () {
+            _ if u16_at(0x1FE) != BOOT_SIGNATURE => Err("missing boot signature"),
+            _ if usize::from(u16_at(0x0B)) != SECTOR_SIZE => Err("sectors aren't 512 bytes"),
+            // FAT12 and FAT16 have a fixed size root directory instead
+            _ if u16_at(0x11) != 0 || bpb.sectors_per_fat == 0 => Err("not a FAT32 volume"),
+            _ if !bpb.sectors_per_cluster.is_power_of_two() => Err("invalid sectors per cluster"),
+            _ if bpb.num_fats == 0 => Err("no FATs"),
+            _ if bpb.root_cluster < 2 => Err("invalid root cluster"),
+            _ if bpb.total_sectors <= bpb.first_data_sector() => Err("no data region"),
+            _ => Ok(bpb),
+        }
+    }
+
+    fn first_data_sector(&self) -> u32 {
+        u32::from(self.reserved_sectors) + u32::from(self.num_fats) * self.sectors_per_fat
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryEntry {
+    /// Short name in `NAME.EXT` form
+    pub name: String,
+    pub attributes: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+}
+
+impl DirectoryEntry {
+    pub fn is_directory(&self) -> bool {
+        self.attributes & ATTRIBUTE_DIRECTORY != 0
+    }
+
+    /// Parse a raw entry, returning `None` for free slots and entries which
+    /// aren't files or directories
+    fn parse(raw: &[u8]) -> Option<Self> {
+        let attributes = raw[0x0B];
+        if raw[0] == ENTRY_FREE || attributes & ATTRIBUTE_LONG_NAME == ATTRIBUTE_LONG_NAME {
+            return None;
+        }
+
+        if attributes & ATTRIBUTE_VOLUME_ID != 0 {
+            return None;
+        }
+
+        let trim = |bytes: &[u8]| String::from_utf8_lossy(bytes).trim_end().into();
+        let (base, extension): (String, String) = (trim(&raw[..8]), trim(&raw[8..11]));
+        let name = match extension.is_empty() {
+            true => base,
+            false => alloc::format!("{}.{}", base, extension),
+        };
+
+        if name == "." || name == ".." {
+            return None;
+        }
+
+        let high = u16::from_le_bytes([raw[0x14], raw[0x15]]);
+        let low = u16::from_le_bytes([raw[0x1A], raw[0x1B]]);
+
+        Some(Self {
+            name,
+            attributes,
+            first_cluster: (u32::from(high) << 16 | u32::from(low)) & CLUSTER_MASK,
+            size: u32::from_le_bytes([raw[0x1C], raw[0x1D], raw[0x1E], raw[0x1F]]),
+        })
+    }
+}
+
+pub struct Fat32<B: BlockDevice> {
+    device: B,
+    start_sector: u64,
+    bpb: BiosParameterBlock,
+    /// Whether the clean shutdown bit has been cleared since the volume was
+    /// mounted or last marked clean
+    dirty: bool,
+    /// The most recently accessed FAT sector, since the FAT is usually walked
+    /// an entry at a time
+    fat_cache: Option<(u64, [u8; SECTOR_SIZE])>,
+}
+
+impl<B: BlockDevice> Fat32<B> {
+    /// Mount the volume starting at `start_sector` of the device
+    pub fn new(mut device: B, start_sector: u64) -> Result<Self, Error<B::Error>> {
+        let mut sector = [0; SECTOR_SIZE];
+        device.read_sector(start_sector, &mut sector).map_err(Error::Device)?;
+        let bpb = BiosParameterBlock::parse(&sector).map_err(Error::InvalidVolume)?;
+
+        Ok(Self { device, start_sector, bpb, dirty: false, fat_cache: None })
+    }
+
+    pub fn device(&mut self) -> &mut B {
+        &mut self.device
+    }
+
+    pub fn root_cluster(&self) -> u32 {
+        self.bpb.root_cluster
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        usize::from(self.bpb.sectors_per_cluster) * SECTOR_SIZE
+    }
+
+    /// Number of clusters in the data region, which are numbered starting
+    /// from 2
+    pub fn cluster_count(&self) -> u32 {
+        let data_sectors = self.bpb.total_sectors - self.bpb.first_data_sector();
+        let by_size = data_sectors / u32::from(self.bpb.sectors_per_cluster);
+        let by_fat = self.bpb.sectors_per_fat * (SECTOR_SIZE as u32 / 4) - 2;
+
+        by_size.min(by_fat)
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count() + 2).contains(&cluster)
+    }
+
+    /// Whether the volume was unmounted cleanly
+    pub fn is_clean(&mut self) -> Result<bool, Error<B::Error>> {
+        Ok(self.read_fat_entry(0, 1)? & CLEAN_SHUTDOWN != 0)
+    }
+
+    /// Set the clean shutdown bit, which should be done once all writes have
+    /// completed
+    pub fn mark_clean(&mut self) -> Result<(), Error<B::Error>> {
+        let entry = self.read_fat_entry(0, 1)?;
+        self.write_fat_entries(1, entry | CLEAN_SHUTDOWN)?;
+        self.dirty = false;
+
+        Ok(())
+    }
+
+    /// Mark the volume clean and flush the device, so that what's been written
+    /// so far survives the system going down. The next write marks the volume
+    /// dirty again.
+    pub fn sync(&mut self) -> Result<(), Error<B::Error>> {
+        self.mark_clean()?;
+        self.device.flush().map_err(Error::Device)
+    }
+
+    /// Sync the volume, after which nothing else should be written until the
+    /// volume is mounted again
+    pub fn unmount(&mut self) -> Result<(), Error<B::Error>> {
+        self.sync()
+    }
+
+    fn mark_dirty(&mut self) -> Result<(), Error<B::Error>> {
+        if !self.dirty {
+            let entry = self.read_fat_entry(0, 1)?;
+            self.write_fat_entries(1, entry & !CLEAN_SHUTDOWN)?;
+            self.dirty = true;
+        }
+
+        Ok(())
+    }
+
+    /// The FAT entry for the cluster, which is the next cluster in its chain,
+    /// zero if it's free, or an end of chain marker
+    pub fn fat_entry(&mut self, cluster: u32) -> Result<u32, Error<B::Error>> {
+        match self.is_valid_cluster(cluster) {
+            true => Ok(self.read_fat_entry(0, cluster)? & CLUSTER_MASK),
+            false => Err(Error::InvalidCluster(cluster)),
+        }
+    }
+
+    /// Set the FAT entry for the cluster in every copy of the FAT
+    pub fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), Error<B::Error>> {
+        if !self.is_valid_cluster(cluster) {
+            return Err(Error::InvalidCluster(cluster));
+        }
+
+        self.mark_dirty()?;
+
+        // The upper bits are reserved and must be preserved
+        let entry = self.read_fat_entry(0, cluster)?;
+        self.write_fat_entries(cluster, (entry & !CLUSTER_MASK) | (value & CLUSTER_MASK))
+    }
+
+    fn fat_sector(&self, fat: u8, entry: u32) -> (u64, usize) {
+        let fat_start = self.start_sector
+            + u64::from(self.bpb.reserved_sectors)
+            + u64::from(fat) * u64::from(self.bpb.sectors_per_fat);
+        let offset = entry as usize * 4;
+
+        (fat_start + (offset / SECTOR_SIZE) as u64, offset % SECTOR_SIZE)
+    }
+
+    fn read_fat_entry(&mut self, fat: u8, entry: u32) -> Result<u32, Error<B::Error>> {
+        let (sector, offset) = self.fat_sector(fat, entry);
+        let bytes = self.read_fat_sector(sector)?;
+
+        Ok(u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()))
+    }
+
+    fn read_fat_sector(&mut self, sector: u64) -> Result<[u8; SECTOR_SIZE], Error<B::Error>> {
+        match self.fat_cache {
+            Some((cached, bytes)) if cached == sector => Ok(bytes),
+            _ => {
+                let mut bytes = [0; SECTOR_SIZE];
+                self.device.read_sector(sector, &mut bytes).map_err(Error::Device)?;
+                self.fat_cache = Some((sector, bytes));
+
+                Ok(bytes)
+            }
+        }
+    }
+
+    fn write_fat_entries(&mut self, entry: u32, value: u32) -> Result<(), Error<B::Error>> {
+        for fat in 0..self.bpb.num_fats {
+            let (sector, offset) = self.fat_sector(fat, entry);
+            let mut bytes = self.read_fat_sector(sector)?;
+
+            bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            self.device.write_sector(sector, &bytes).map_err(Error::Device)?;
+            self.fat_cache = Some((sector, bytes));
+        }
+
+        Ok(())
+    }
+
+    /// Read the sectors of a cluster into `buffer`, which must be
+    /// [`Fat32::cluster_size`] bytes long
+    pub fn read_cluster(&mut self, cluster: u32, buffer: &mut [u8]) -> Result<(), Error<B::Error>> {
+        if !self.is_valid_cluster(cluster) {
+            return Err(Error::InvalidCluster(cluster));
+        }
+
+        let first_sector = self.start_sector
+            + u64::from(self.bpb.first_data_sector())
+            + u64::from(cluster - 2) * u64::from(self.bpb.sectors_per_cluster);
+
+        for (i, chunk) in buffer.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+            let chunk: &mut [u8; SECTOR_SIZE] = chunk.try_into().unwrap();
+            self.device.read_sector(first_sector + i as u64, chunk).map_err(Error::Device)?;
+        }
+
+        Ok(())
+    }
+
+    /// The clusters in the chain starting at `first`, stopping early if the
+    /// chain is broken or loops
+    pub fn chain(&mut self, first: u32) -> Result<Vec<u32>, Error<B::Error>> {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+
+        while self.is_valid_cluster(cluster) && clusters.len() < self.cluster_count() as usize {
+            clusters.push(cluster);
+            cluster = self.fat_entry(cluster)?;
+        }
+
+        Ok(clusters)
+    }
+
+    /// The entries of the directory whose chain starts at `first`
+    pub fn read_directory(&mut self, first: u32) -> Result<Vec<DirectoryEntry>, Error<B::Error>> {
+        let clusters = self.chain(first)?;
+        self.read_directory_clusters(&clusters)
+    }
+
+    fn read_directory_clusters(&mut self, clusters: &[u32]) -> Result<Vec<DirectoryEntry>, Error<B::Error>> {
+        let mut entries = Vec::new();
+        let mut buffer = vec![0; self.cluster_size()];
+
+        for &cluster in clusters {
+            self.read_cluster(cluster, &mut buffer)?;
+            for raw in buffer.chunks_exact(DIRECTORY_ENTRY_SIZE) {
+                if raw[0] == ENTRY_END {
+                    return Ok(entries);
+                }
+
+                entries.extend(DirectoryEntry::parse(raw));
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    pub struct MemoryDevice(pub Vec<u8>);
+
+    impl BlockDevice for MemoryDevice {
+        type Error = core::convert::Infallible;
+
+        fn read_sector(&mut self, sector: u64, buffer: &mut [u8; SECTOR_SIZE]) -> Result<(), Self::Error> {
+            let start = sector as usize * SECTOR_SIZE;
+            buffer.copy_from_slice(&self.0[start..start + SECTOR_SIZE]);
+            Ok(())
+        }
+
+        fn write_sector(&mut self, sector: u64, buffer: &[u8; SECTOR_SIZE]) -> Result<(), Self::Error> {
+            let start = sector as usize * SECTOR_SIZE;
+            self.0[start..start + SECTOR_SIZE].copy_from_slice(buffer);
+            Ok(())
+        }
+    }
+
+    const RESERVED_SECTORS: usize = 32;
+    const TOTAL_SECTORS: usize = RESERVED_SECTORS + 2 + 64;
+    const ROOT_DIRECTORY: usize = RESERVED_SECTORS + 2;
+
+    /// A volume with two FATs of one sector each, 64 single sector clusters,
+    /// and an empty root directory in cluster 2
+    pub fn format() -> Fat32<MemoryDevice> {
+        let mut image = vec![0; TOTAL_SECTORS * SECTOR_SIZE];
+        image[0x0B..0x0D].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+        image[0x0D] = 1;
+        image[0x0E..0x10].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+        image[0x10] = 2;
+        image[0x20..0x24].copy_from_slice(&(TOTAL_SECTORS as u32).to_le_bytes());
+        image[0x24..0x28].copy_from_slice(&1u32.to_le_bytes());
+        image[0x2C..0x30].copy_from_slice(&2u32.to_le_bytes());
+        image[0x1FE..0x200].copy_from_slice(&BOOT_SIGNATURE.to_le_bytes());
+
+        for fat in [RESERVED_SECTORS, RESERVED_SECTORS + 1] {
+            let fat = &mut image[fat * SECTOR_SIZE..];
+            fat[0..4].copy_from_slice(&0x0FFF_FFF8u32.to_le_bytes());
+            fat[4..8].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+            fat[8..12].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+        }
+
+        Fat32::new(MemoryDevice(image), 0).unwrap()
+    }
+
+    /// Add an entry to the root directory, with its clusters chained in order
+    pub fn add_file(fs: &mut Fat32<MemoryDevice>, name: &[u8; 11], attributes: u8, clusters: &[u32], size: u32) {
+        let directory = &mut fs.device.0[ROOT_DIRECTORY * SECTOR_SIZE..(ROOT_DIRECTORY + 1) * SECTOR_SIZE];
+        let raw = directory.chunks_exact_mut(DIRECTORY_ENTRY_SIZE).find(|raw| raw[0] == ENTRY_END).unwrap();
+        let first = clusters.first().copied().unwrap_or(0);
+
+        raw[..11].copy_from_slice(name);
+        raw[0x0B] = attributes;
+        raw[0x14..0x16].copy_from_slice(&((first >> 16) as u16).to_le_bytes());
+        raw[0x1A..0x1C].copy_from_slice(&(first as u16).to_le_bytes());
+        raw[0x1C..0x20].copy_from_slice(&size.to_le_bytes());
+
+        for pair in clusters.windows(2) {
+            fs.set_fat_entry(pair[0], pair[1]).unwrap();
+        }
+
+        if let Some(&last) = clusters.last() {
+            fs.set_fat_entry(last, END_OF_CHAIN).unwrap();
+        }
+    }
+
+    #[test]
+    fn reads_directories() {
+        let mut fs = format();
+        add_file(&mut fs, b"HELLO   TXT", 0, &[3, 4], 1000);
+        add_file(&mut fs, b"EMPTY      ", 0, &[], 0);
+
+        let entries = fs.read_directory(fs.root_cluster()).unwrap();
+        let names: Vec<_> = entries.iter().map(|entry| (&*entry.name, entry.first_cluster, entry.size)).collect();
+        assert_eq!(names, [("HELLO.TXT", 3, 1000), ("EMPTY", 0, 0)]);
+        assert_eq!(fs.chain(3).unwrap(), [3, 4]);
+    }
+
+    #[test]
+    fn clean_shutdown_bit() {
+        let mut fs = format();
+        assert!(fs.is_clean().unwrap());
+
+        fs.set_fat_entry(3, END_OF_CHAIN).unwrap();
+        assert!(!fs.is_clean().unwrap());
+
+        // Every copy of the FAT is updated
+        let mut fs = Fat32::new(MemoryDevice(fs.device.0), 0).unwrap();
+        assert_eq!(fs.read_fat_entry(1, 1).unwrap() & CLEAN_SHUTDOWN, 0);
+        assert_eq!(fs.read_fat_entry(1, 3).unwrap(), END_OF_CHAIN);
+
+        fs.mark_clean().unwrap();
+        assert!(fs.is_clean().unwrap());
+        assert_ne!(fs.read_fat_entry(1, 1).unwrap() & CLEAN_SHUTDOWN, 0);
+    }
+
+    #[test]
+    fn unmount_marks_clean() {
+        let mut fs = format();
+        fs.set_fat_entry(3, END_OF_CHAIN).unwrap();
+        fs.unmount().unwrap();
+
+        let mut fs = Fat32::new(MemoryDevice(fs.device.0), 0).unwrap();
+        assert!(fs.is_clean().unwrap());
+        assert_eq!(fs.fat_entry(3).unwrap(), END_OF_CHAIN);
+    }
+}
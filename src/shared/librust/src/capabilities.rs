@@ -19,8 +19,24 @@ impl CapabilityPtr {
     }
 }
 
+/// A one-shot capability handed to the receiver of a call, used to reply to
+/// the blocked caller. It's consumed by the reply and can't be transferred.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
+pub struct ReplyCapability(CapabilityPtr);
+
+impl ReplyCapability {
+    pub fn new(cptr: CapabilityPtr) -> Self {
+        Self(cptr)
+    }
+
+    pub fn cptr(self) -> CapabilityPtr {
+        self.0
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
 pub struct CapabilityRights(usize);
 
 impl CapabilityRights {
@@ -28,11 +44,17 @@ impl CapabilityRights {
     pub const WRITE: Self = Self(2);
     pub const EXECUTE: Self = Self(4);
     pub const GRANT: Self = Self(8);
+    /// The capability can be sent onward to another task over a channel.
+    /// Without it the holder can use the capability, but not delegate it.
+    pub const TRANSFER: Self = Self(16);
+    /// The memory backing the capability can be mapped into the holder's
+    /// address space
+    pub const MAP: Self = Self(32);
 }
 
 impl CapabilityRights {
     pub fn new(value: usize) -> Self {
-        Self(value & 0xF)
+        Self(value & 0x3F)
     }
 
     pub fn is_superset(self, other: Self) -> bool {
@@ -66,6 +88,95 @@ impl core::ops::BitAnd for CapabilityRights {
     }
 }
 
+impl core::fmt::Debug for CapabilityRights {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        const NAMES: [(CapabilityRights, &str); 6] = [
+            (CapabilityRights::READ, "READ"),
+            (CapabilityRights::WRITE, "WRITE"),
+            (CapabilityRights::EXECUTE, "EXECUTE"),
+            (CapabilityRights::GRANT, "GRANT"),
+            (CapabilityRights::TRANSFER, "TRANSFER"),
+            (CapabilityRights::MAP, "MAP"),
+        ];
+
+        let mut names = NAMES.iter().filter(|(right, _)| *self & *right).map(|(_, name)| name);
+        match names.next() {
+            Some(first) => {
+                write!(f, "{}", first)?;
+                names.try_for_each(|name| write!(f, " | {}", name))
+            }
+            None => write!(f, "(none)"),
+        }
+    }
+}
+
+/// The kind of kernel object a capability refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum CapabilityKind {
+    Channel = 0,
+    Memory = 1,
+    /// Registers of a claimed device along with its interrupts
+    Mmio = 2,
+    Reply = 3,
+    ConsoleStream = 4,
+    Dma = 5,
+    SystemControl = 6,
+    KernelLog = 7,
+    Perf = 8,
+    TaskGroup = 9,
+}
+
+impl CapabilityKind {
+    pub fn from_usize(n: usize) -> Option<Self> {
+        match n {
+            0 => Some(Self::Channel),
+            1 => Some(Self::Memory),
+            2 => Some(Self::Mmio),
+            3 => Some(Self::Reply),
+            4 => Some(Self::ConsoleStream),
+            5 => Some(Self::Dma),
+            6 => Some(Self::SystemControl),
+            7 => Some(Self::KernelLog),
+            8 => Some(Self::Perf),
+            9 => Some(Self::TaskGroup),
+            _ => None,
+        }
+    }
+}
+
+/// Description of a capability in the current task's capability space, see
+/// [`crate::syscalls::capabilities`]
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct CapabilityInfo {
+    pub cptr: CapabilityPtr,
+    pub kind: CapabilityKind,
+    pub rights: CapabilityRights,
+    /// Zero if the capability is unbadged
+    pub badge: usize,
+    /// Where the memory is mapped for memory, MMIO, and DMA capabilities,
+    /// otherwise zero
+    pub address: usize,
+    pub len: usize,
+    /// Number of interrupts belonging to an MMIO capability
+    pub n_interrupts: usize,
+}
+
+impl Default for CapabilityInfo {
+    fn default() -> Self {
+        Self {
+            cptr: CapabilityPtr::new(0),
+            kind: CapabilityKind::Channel,
+            rights: CapabilityRights::new(0),
+            badge: 0,
+            address: 0,
+            len: 0,
+            n_interrupts: 0,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct Capability {
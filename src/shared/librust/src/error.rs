@@ -13,6 +13,9 @@ pub const INVALID_RECIPIENT: usize = 3;
 pub const INVALID_SYSCALL: usize = 4;
 pub const INVALID_ARGUMENT: usize = 5;
 pub const NO_MESSAGES: usize = 6;
+pub const OUT_OF_MEMORY: usize = 7;
+pub const WOULD_BLOCK: usize = 8;
+pub const INTERRUPTED: usize = 9;
 
 pub const IS_KERROR: usize = 1;
 
@@ -24,6 +27,14 @@ pub enum KError {
     InvalidSyscall(usize),
     InvalidArgument(usize),
     NoMessages,
+    /// The task has too much memory tied up in the kernel already
+    OutOfMemory,
+    /// The operation can't complete without blocking, e.g. the queue on the
+    /// other end of a channel is full
+    WouldBlock,
+    /// The task was woken up from waiting to handle an event before the
+    /// operation could complete
+    Interrupted,
 }
 
 impl From<Message> for KError {
@@ -39,6 +50,9 @@ impl From<Message> for KError {
                 _ => unreachable!(),
             }),
             const { NO_MESSAGES } => Self::NoMessages,
+            const { OUT_OF_MEMORY } => Self::OutOfMemory,
+            const { WOULD_BLOCK } => Self::WouldBlock,
+            const { INTERRUPTED } => Self::Interrupted,
             _ => unreachable!(),
         }
     }
@@ -28,3 +28,4 @@ pub mod message;
 pub mod syscalls;
 pub mod task;
 pub mod taskgroup;
+pub mod time;
@@ -6,9 +6,10 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 use crate::{
+    capabilities::CapabilityPtr,
     error::KError,
     message::SyscallResult,
-    syscalls::allocation::{alloc_dma_memory, DmaAllocationOptions},
+    syscalls::allocation::{alloc_dma_memory, dma_revoke, dma_share, DmaAllocationOptions},
 };
 use core::{mem::MaybeUninit, ptr::Pointee};
 
@@ -69,25 +70,37 @@ impl core::fmt::Pointer for PhysicalAddress {
 pub struct DmaRegion<T: ?Sized> {
     phys: PhysicalAddress,
     virt: *mut T,
+    cptr: CapabilityPtr,
 }
 
 impl<T: Sized> DmaRegion<[MaybeUninit<T>]> {
     pub fn new_many(n_elements: usize) -> SyscallResult<Self, KError> {
-        alloc_dma_memory(n_elements * core::mem::size_of::<T>(), DmaAllocationOptions::NONE)
-            .map(|(phys, virt)| Self { phys, virt: core::ptr::slice_from_raw_parts_mut(virt.cast(), n_elements) })
+        alloc_dma_memory(n_elements * core::mem::size_of::<T>(), DmaAllocationOptions::NONE).map(
+            |(phys, virt, cptr)| Self {
+                phys,
+                virt: core::ptr::slice_from_raw_parts_mut(virt.cast(), n_elements),
+                cptr,
+            },
+        )
     }
 
     pub unsafe fn zeroed_many(n_elements: usize) -> SyscallResult<Self, KError> {
-        alloc_dma_memory(n_elements * core::mem::size_of::<T>(), DmaAllocationOptions::ZERO)
-            .map(|(phys, virt)| Self { phys, virt: core::ptr::slice_from_raw_parts_mut(virt.cast(), n_elements) })
+        alloc_dma_memory(n_elements * core::mem::size_of::<T>(), DmaAllocationOptions::ZERO).map(
+            |(phys, virt, cptr)| Self {
+                phys,
+                virt: core::ptr::slice_from_raw_parts_mut(virt.cast(), n_elements),
+                cptr,
+            },
+        )
     }
 
     pub unsafe fn assume_init(self) -> DmaRegion<[T]> {
         let phys = self.phys;
         let virt = self.virt;
+        let cptr = self.cptr;
         core::mem::forget(self);
 
-        DmaRegion { phys, virt: core::ptr::slice_from_raw_parts_mut(virt.as_mut_ptr().cast(), virt.len()) }
+        DmaRegion { phys, virt: core::ptr::slice_from_raw_parts_mut(virt.as_mut_ptr().cast(), virt.len()), cptr }
     }
 }
 
@@ -110,14 +123,33 @@ impl<T: ?Sized> DmaRegion<T> {
         let size = core::mem::size_of_val_raw::<T>(core::ptr::from_raw_parts(core::ptr::null(), metadata));
         let opts = if zero { DmaAllocationOptions::ZERO } else { DmaAllocationOptions::NONE };
 
-        alloc_dma_memory(size, opts)
-            .map(|(phys, virt)| Self { phys, virt: core::ptr::from_raw_parts_mut(virt.cast(), metadata) })
+        alloc_dma_memory(size, opts).map(|(phys, virt, cptr)| Self {
+            phys,
+            virt: core::ptr::from_raw_parts_mut(virt.cast(), metadata),
+            cptr,
+        })
     }
 
     pub fn physical_address(&self) -> PhysicalAddress {
         self.phys
     }
 
+    /// The capability which owns the region
+    pub fn capability(&self) -> CapabilityPtr {
+        self.cptr
+    }
+
+    /// Let the task on the other end of `channel` use the region, e.g. to have
+    /// a driver DMA directly into it
+    pub fn share(&self, channel: CapabilityPtr) -> SyscallResult<(), KError> {
+        dma_share(channel, self.cptr)
+    }
+
+    /// Take the region back from every task it was shared with
+    pub fn revoke(&self) -> SyscallResult<(), KError> {
+        dma_revoke(self.cptr)
+    }
+
     pub fn get_mut(&mut self) -> &mut T {
         unsafe { &mut *self.virt }
     }
@@ -128,24 +160,25 @@ impl<T> DmaRegion<MaybeUninit<T>> {
     where
         T: Pointee<Metadata = ()>,
     {
-        let (phys, virt) = alloc_dma_memory(core::mem::size_of::<T>(), DmaAllocationOptions::NONE)?;
-        SyscallResult::Ok(Self { phys, virt: core::ptr::from_raw_parts_mut(virt.cast(), ()) })
+        let (phys, virt, cptr) = alloc_dma_memory(core::mem::size_of::<T>(), DmaAllocationOptions::NONE)?;
+        SyscallResult::Ok(Self { phys, virt: core::ptr::from_raw_parts_mut(virt.cast(), ()), cptr })
     }
 
     pub unsafe fn zeroed() -> SyscallResult<Self, KError>
     where
         T: Pointee<Metadata = ()>,
     {
-        let (phys, virt) = alloc_dma_memory(core::mem::size_of::<T>(), DmaAllocationOptions::ZERO)?;
-        SyscallResult::Ok(Self { phys, virt: core::ptr::from_raw_parts_mut(virt.cast(), ()) })
+        let (phys, virt, cptr) = alloc_dma_memory(core::mem::size_of::<T>(), DmaAllocationOptions::ZERO)?;
+        SyscallResult::Ok(Self { phys, virt: core::ptr::from_raw_parts_mut(virt.cast(), ()), cptr })
     }
 
     pub unsafe fn assume_init(self) -> DmaRegion<T> {
         let phys = self.phys;
         let virt = self.virt;
+        let cptr = self.cptr;
         core::mem::forget(self);
 
-        DmaRegion { phys, virt: virt.cast() }
+        DmaRegion { phys, virt: virt.cast(), cptr }
     }
 }
 
@@ -6,10 +6,10 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 use crate::{
-    capabilities::CapabilityPtr,
+    capabilities::{CapabilityPtr, ReplyCapability},
     error::{self, AccessError, KError},
     syscalls::Syscall,
-    task::Tid,
+    task::{ExitStatus, Tid},
 };
 use core::{convert::TryInto, num::NonZeroUsize};
 
@@ -144,6 +144,9 @@ impl From<KError> for Message {
                 Self { contents: [error::INVALID_ARGUMENT, idx, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] }
             }
             KError::NoMessages => Self { contents: [error::NO_MESSAGES, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+            KError::OutOfMemory => Self { contents: [error::OUT_OF_MEMORY, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+            KError::WouldBlock => Self { contents: [error::WOULD_BLOCK, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+            KError::Interrupted => Self { contents: [error::INTERRUPTED, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
         }
     }
 }
@@ -369,13 +372,50 @@ pub enum KernelNotification {
     ChannelRequestDenied,
     InterruptOccurred(usize),
     NewChannelMessage(CapabilityPtr),
+    /// Sent to every task when a filesystem sync is requested from the kernel
+    /// console, which the `filesystem` server handles by flushing its volume
+    SyncRequested,
+    /// A call was made on `channel`, the caller is blocked until a reply is
+    /// sent with `reply`
+    IncomingCall {
+        channel: CapabilityPtr,
+        reply: ReplyCapability,
+        badge: usize,
+        payload: [usize; CALL_PAYLOAD_LEN],
+    },
+    /// The task on the other end of `channel` exited, sent to tasks which
+    /// asked to be notified with `watch_exit`
+    TaskExited {
+        channel: CapabilityPtr,
+        status: ExitStatus,
+    },
+    /// `caller` made the syscall `number` from the extension range, sent to
+    /// the syscall extension server. The caller is blocked until a reply is
+    /// sent with `reply`, the payload of which is the syscall's result.
+    ExtensionSyscall {
+        caller: Tid,
+        reply: ReplyCapability,
+        number: usize,
+        args: [usize; CALL_PAYLOAD_LEN],
+    },
+    /// The last member of the task group `group` exited, sent to the task
+    /// which created the group
+    TaskGroupExited(CapabilityPtr),
 }
 
+/// Number of words which can be passed in a call or its reply
+pub const CALL_PAYLOAD_LEN: usize = 8;
+
 pub const NOTIFICATION_CHANNEL_REQUEST: usize = 0;
 pub const NOTIFICATION_CHANNEL_OPENED: usize = 1;
 pub const NOTIFICATION_CHANNEL_REQUEST_DENIED: usize = 2;
 pub const NOTIFICATION_INTERRUPT_OCCURRED: usize = 3;
 pub const NOTIFICATION_NEW_CHANNEL_MESSAGE: usize = 4;
+pub const NOTIFICATION_SYNC_REQUESTED: usize = 5;
+pub const NOTIFICATION_INCOMING_CALL: usize = 6;
+pub const NOTIFICATION_TASK_EXITED: usize = 7;
+pub const NOTIFICATION_EXTENSION_SYSCALL: usize = 8;
+pub const NOTIFICATION_TASK_GROUP_EXITED: usize = 9;
 
 impl From<Message> for KernelNotification {
     fn from(message: Message) -> Self {
@@ -389,6 +429,36 @@ impl From<Message> for KernelNotification {
             NOTIFICATION_NEW_CHANNEL_MESSAGE => {
                 KernelNotification::NewChannelMessage(CapabilityPtr::new(message.contents[1]))
             }
+            NOTIFICATION_SYNC_REQUESTED => KernelNotification::SyncRequested,
+            NOTIFICATION_INCOMING_CALL => {
+                let mut payload = [0; CALL_PAYLOAD_LEN];
+                payload.copy_from_slice(&message.contents[4..][..CALL_PAYLOAD_LEN]);
+
+                KernelNotification::IncomingCall {
+                    channel: CapabilityPtr::new(message.contents[1]),
+                    reply: ReplyCapability::new(CapabilityPtr::new(message.contents[2])),
+                    badge: message.contents[3],
+                    payload,
+                }
+            }
+            NOTIFICATION_TASK_EXITED => KernelNotification::TaskExited {
+                channel: CapabilityPtr::new(message.contents[1]),
+                status: ExitStatus::from_raw(message.contents[2], message.contents[3]).unwrap(),
+            },
+            NOTIFICATION_EXTENSION_SYSCALL => {
+                let mut args = [0; CALL_PAYLOAD_LEN];
+                args.copy_from_slice(&message.contents[4..][..CALL_PAYLOAD_LEN]);
+
+                KernelNotification::ExtensionSyscall {
+                    caller: Tid::new(message.contents[1].try_into().unwrap()),
+                    reply: ReplyCapability::new(CapabilityPtr::new(message.contents[2])),
+                    number: message.contents[3],
+                    args,
+                }
+            }
+            NOTIFICATION_TASK_GROUP_EXITED => {
+                KernelNotification::TaskGroupExited(CapabilityPtr::new(message.contents[1]))
+            }
             _ => unreachable!("bad KernelNotification or used this impl one something that wasn't "),
         }
     }
@@ -418,6 +488,34 @@ impl From<KernelNotification> for Message {
                 contents[0] = NOTIFICATION_NEW_CHANNEL_MESSAGE;
                 contents[1] = id.value();
             }
+            KernelNotification::SyncRequested => {
+                contents[0] = NOTIFICATION_SYNC_REQUESTED;
+            }
+            KernelNotification::IncomingCall { channel, reply, badge, payload } => {
+                contents[0] = NOTIFICATION_INCOMING_CALL;
+                contents[1] = channel.value();
+                contents[2] = reply.cptr().value();
+                contents[3] = badge;
+                contents[4..][..CALL_PAYLOAD_LEN].copy_from_slice(&payload);
+            }
+            KernelNotification::TaskExited { channel, status } => {
+                let (kind, code) = status.to_raw();
+                contents[0] = NOTIFICATION_TASK_EXITED;
+                contents[1] = channel.value();
+                contents[2] = kind;
+                contents[3] = code;
+            }
+            KernelNotification::ExtensionSyscall { caller, reply, number, args } => {
+                contents[0] = NOTIFICATION_EXTENSION_SYSCALL;
+                contents[1] = caller.value();
+                contents[2] = reply.cptr().value();
+                contents[3] = number;
+                contents[4..][..CALL_PAYLOAD_LEN].copy_from_slice(&args);
+            }
+            KernelNotification::TaskGroupExited(group) => {
+                contents[0] = NOTIFICATION_TASK_GROUP_EXITED;
+                contents[1] = group.value();
+            }
         }
 
         Self { contents }
@@ -6,9 +6,21 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 pub mod allocation;
+pub mod capabilities;
 pub mod channel;
+pub mod coverage;
+pub mod extension;
 pub mod io;
+pub mod klog;
 pub mod mem;
+pub mod perf;
+pub mod poll;
+pub mod ring;
+pub mod system;
+pub mod task;
+pub mod taskgroup;
+pub mod thread;
+pub mod trace;
 pub mod vmspace;
 
 use crate::{
@@ -18,7 +30,11 @@ use crate::{
 };
 use core::{convert::TryInto, num::NonZeroUsize};
 
-#[derive(Debug)]
+/// Syscall numbers which the kernel forwards to the registered syscall
+/// extension server, see [`extension`]
+pub const EXTENSION_SYSCALLS: core::ops::Range<usize> = 0x1000..0x2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(usize)]
 pub enum Syscall {
     Exit = 0,
@@ -40,6 +56,71 @@ pub enum Syscall {
     CompleteInterrupt = 21,
     QueryMmioCapability = 22,
     ReadChannelNonBlocking = 23,
+    HandoffCapabilities = 24,
+    SetMemoryPermissions = 25,
+    SystemInfo = 26,
+    QueryAddressSpace = 27,
+    MapChannelRing = 28,
+    NotifyChannelRing = 29,
+    WaitChannelRing = 30,
+    MintBadgedCapability = 31,
+    Call = 32,
+    Reply = 33,
+    SetCpuGovernor = 34,
+    CreateThread = 35,
+    FutexWait = 36,
+    FutexWake = 37,
+    Wait = 38,
+    WatchExit = 39,
+    SendTaskEvent = 40,
+    SetEventHandler = 41,
+    ReturnFromEvent = 42,
+    SetConsoleMode = 43,
+    SetForegroundTask = 44,
+    SetInterruptRateLimit = 45,
+    CreateConsoleStream = 46,
+    BindStdio = 47,
+    ReadConsoleStream = 48,
+    WriteConsoleStream = 49,
+    ReadKernelMetrics = 50,
+    PushConsoleInput = 51,
+    GetRandom = 52,
+    SetPriority = 53,
+    ShareDmaRegion = 54,
+    RevokeDmaRegion = 55,
+    ValidateDmaRange = 56,
+    SnapshotAddressSpace = 57,
+    SetNice = 58,
+    SetTaskName = 59,
+    SetThreadName = 60,
+    QueryTaskName = 61,
+    SystemReset = 62,
+    WaitMany = 63,
+    ReadCrashDump = 64,
+    MapKernelLog = 65,
+    SnapshotMemory = 66,
+    PerfStart = 67,
+    PerfRead = 68,
+    PerfStop = 69,
+    ReadTrace = 70,
+    SetupSyscallRing = 71,
+    RingEnter = 72,
+    FinalizeExecutable = 73,
+    ListCapabilities = 74,
+    InspectCapability = 75,
+    RegisterSyscallExtension = 76,
+    GetTaskContext = 77,
+    SetTaskContext = 78,
+    CreateTaskGroup = 79,
+    KillTaskGroup = 80,
+    ClaimGroupCapability = 81,
+    SetChannelQueueDepth = 82,
+    CreatePipe = 83,
+    CloseConsoleStream = 84,
+    ReadCoverage = 85,
+    ResetCoverage = 86,
+    NameRegion = 87,
+    DeallocVirtualMemory = 88,
 }
 
 impl Syscall {
@@ -64,6 +145,71 @@ impl Syscall {
             21 => Some(Self::CompleteInterrupt),
             22 => Some(Self::QueryMmioCapability),
             23 => Some(Self::ReadChannelNonBlocking),
+            24 => Some(Self::HandoffCapabilities),
+            25 => Some(Self::SetMemoryPermissions),
+            26 => Some(Self::SystemInfo),
+            27 => Some(Self::QueryAddressSpace),
+            28 => Some(Self::MapChannelRing),
+            29 => Some(Self::NotifyChannelRing),
+            30 => Some(Self::WaitChannelRing),
+            31 => Some(Self::MintBadgedCapability),
+            32 => Some(Self::Call),
+            33 => Some(Self::Reply),
+            34 => Some(Self::SetCpuGovernor),
+            35 => Some(Self::CreateThread),
+            36 => Some(Self::FutexWait),
+            37 => Some(Self::FutexWake),
+            38 => Some(Self::Wait),
+            39 => Some(Self::WatchExit),
+            40 => Some(Self::SendTaskEvent),
+            41 => Some(Self::SetEventHandler),
+            42 => Some(Self::ReturnFromEvent),
+            43 => Some(Self::SetConsoleMode),
+            44 => Some(Self::SetForegroundTask),
+            45 => Some(Self::SetInterruptRateLimit),
+            46 => Some(Self::CreateConsoleStream),
+            47 => Some(Self::BindStdio),
+            48 => Some(Self::ReadConsoleStream),
+            49 => Some(Self::WriteConsoleStream),
+            50 => Some(Self::ReadKernelMetrics),
+            51 => Some(Self::PushConsoleInput),
+            52 => Some(Self::GetRandom),
+            53 => Some(Self::SetPriority),
+            54 => Some(Self::ShareDmaRegion),
+            55 => Some(Self::RevokeDmaRegion),
+            56 => Some(Self::ValidateDmaRange),
+            57 => Some(Self::SnapshotAddressSpace),
+            58 => Some(Self::SetNice),
+            59 => Some(Self::SetTaskName),
+            60 => Some(Self::SetThreadName),
+            61 => Some(Self::QueryTaskName),
+            62 => Some(Self::SystemReset),
+            63 => Some(Self::WaitMany),
+            64 => Some(Self::ReadCrashDump),
+            65 => Some(Self::MapKernelLog),
+            66 => Some(Self::SnapshotMemory),
+            67 => Some(Self::PerfStart),
+            68 => Some(Self::PerfRead),
+            69 => Some(Self::PerfStop),
+            70 => Some(Self::ReadTrace),
+            71 => Some(Self::SetupSyscallRing),
+            72 => Some(Self::RingEnter),
+            73 => Some(Self::FinalizeExecutable),
+            74 => Some(Self::ListCapabilities),
+            75 => Some(Self::InspectCapability),
+            76 => Some(Self::RegisterSyscallExtension),
+            77 => Some(Self::GetTaskContext),
+            78 => Some(Self::SetTaskContext),
+            79 => Some(Self::CreateTaskGroup),
+            80 => Some(Self::KillTaskGroup),
+            81 => Some(Self::ClaimGroupCapability),
+            82 => Some(Self::SetChannelQueueDepth),
+            83 => Some(Self::CreatePipe),
+            84 => Some(Self::CloseConsoleStream),
+            85 => Some(Self::ReadCoverage),
+            86 => Some(Self::ResetCoverage),
+            87 => Some(Self::NameRegion),
+            88 => Some(Self::DeallocVirtualMemory),
             _ => None,
         }
     }
@@ -124,9 +270,14 @@ pub fn syscall<T: Into<Message>, U: From<Message>, E: From<Message>>(
     }
 }
 
+/// Exit the current task with the given exit code, which is reported to any
+/// tasks waiting on it with [`task::wait`]
 #[inline(always)]
-pub fn exit() -> ! {
-    let _ = syscall::<_, (), ()>(Recipient::kernel(), SyscallRequest { syscall: Syscall::Exit, arguments: [0; 12] });
+pub fn exit(code: usize) -> ! {
+    let _ = syscall::<_, (), ()>(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::Exit, arguments: [code, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+    );
 
     unreachable!()
 }
@@ -143,6 +294,9 @@ pub fn print(value: &[u8]) -> SyscallResult<(), KError> {
     .1
 }
 
+/// Read whatever is available from stdin without blocking, returning the
+/// number of bytes read. Returns [`KError::WouldBlock`] if there's nothing to
+/// read yet, and `0` once stdin is bound to a pipe whose write end has closed.
 #[inline]
 pub fn read_stdin(buffer: &mut [u8]) -> SyscallResult<usize, KError> {
     syscall(
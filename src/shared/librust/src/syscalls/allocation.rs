@@ -7,6 +7,7 @@
 
 use super::{syscall, Syscall};
 use crate::{
+    capabilities::CapabilityPtr,
     error::KError,
     mem::PhysicalAddress,
     message::{Recipient, SyscallRequest, SyscallResult},
@@ -57,6 +58,8 @@ impl AllocationOptions {
     pub const Zero: Self = Self(1 << 1);
     pub const ZeroOnDrop: Self = Self(1 << 2);
     pub const Lazy: Self = Self(1 << 3);
+    /// Place the memory in the current task group's capability space as well,
+    /// see [`super::taskgroup::alloc_group_memory`]
     pub const JobGroupAvailable: Self = Self(1 << 4);
 
     pub fn new(flags: usize) -> Self {
@@ -84,6 +87,9 @@ impl core::ops::BitAnd for AllocationOptions {
     }
 }
 
+/// Allocate `size_in_bytes` of memory, rounded up to the page size. Memory
+/// can't be allocated both writable and executable, code written at runtime is
+/// made executable afterwards with [`super::mem::finalize_executable`].
 #[inline]
 pub fn alloc_virtual_memory(
     size_in_bytes: usize,
@@ -100,6 +106,19 @@ pub fn alloc_virtual_memory(
     .1
 }
 
+/// Free memory allocated with [`alloc_virtual_memory`], which must be given the
+/// pointer it returned
+pub fn dealloc_virtual_memory(ptr: *mut u8) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::DeallocVirtualMemory,
+            arguments: [ptr as usize, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
 pub struct DmaAllocationOptions(usize);
 
 impl DmaAllocationOptions {
@@ -131,10 +150,13 @@ impl core::ops::BitAnd for DmaAllocationOptions {
     }
 }
 
+/// Allocate physically contiguous memory for programming devices with,
+/// returning its physical address, where it was mapped, and the capability
+/// which owns it
 pub fn alloc_dma_memory(
     size_in_bytes: usize,
     options: DmaAllocationOptions,
-) -> SyscallResult<(PhysicalAddress, *mut u8), KError> {
+) -> SyscallResult<(PhysicalAddress, *mut u8, CapabilityPtr), KError> {
     syscall(
         Recipient::kernel(),
         SyscallRequest {
@@ -143,5 +165,43 @@ pub fn alloc_dma_memory(
         },
     )
     .1
-    .map(|(phys, virt)| (PhysicalAddress::new(phys), virt as *mut u8))
+    .map(|(phys, virt, cptr)| (PhysicalAddress::new(phys), virt as *mut u8, CapabilityPtr::new(cptr)))
+}
+
+/// Share a DMA region owned by this task with the task on the other end of
+/// `channel`, which receives the region's capability as a channel message
+pub fn dma_share(channel: CapabilityPtr, region: CapabilityPtr) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::ShareDmaRegion,
+            arguments: [channel.value(), region.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Unmap a DMA region owned by this task from every task it was shared with
+pub fn dma_revoke(region: CapabilityPtr) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::RevokeDmaRegion,
+            arguments: [region.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Check that the physical range lies within a DMA region this task owns or
+/// has been shared, before pointing a device at it
+pub fn validate_dma_range(phys: PhysicalAddress, len: usize) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::ValidateDmaRange,
+            arguments: [phys.as_usize(), len, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
 }
@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Looking at what's in the current task's capability space, e.g. to check
+//! what a capability is before using it, or to list them for debugging
+
+use super::{syscall, Syscall};
+use crate::{
+    capabilities::{CapabilityInfo, CapabilityKind, CapabilityPtr, CapabilityRights},
+    error::KError,
+    message::{Recipient, SyscallRequest, SyscallResult},
+};
+
+/// Fill `capabilities` with the capabilities the current task holds, ordered
+/// by [`CapabilityPtr`]. Returns the number written and the total number of
+/// capabilities held.
+pub fn list_capabilities(capabilities: &mut [CapabilityInfo]) -> SyscallResult<(usize, usize), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::ListCapabilities,
+            arguments: [capabilities.as_mut_ptr() as usize, capabilities.len(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Describe the capability `cptr`, which fails with
+/// [`KError::InvalidArgument`] if the current task doesn't hold it
+pub fn inspect_capability(cptr: CapabilityPtr) -> SyscallResult<CapabilityInfo, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::InspectCapability,
+            arguments: [cptr.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+    .map(|(kind, rights, badge, address, len, n_interrupts)| CapabilityInfo {
+        cptr,
+        // The kernel only ever returns kinds it knows about
+        kind: CapabilityKind::from_usize(kind).unwrap(),
+        rights: CapabilityRights::new(rights),
+        badge,
+        address,
+        len,
+        n_interrupts,
+    })
+}
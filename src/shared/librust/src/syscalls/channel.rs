@@ -6,9 +6,9 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 use crate::{
-    capabilities::{Capability, CapabilityPtr},
+    capabilities::{Capability, CapabilityPtr, CapabilityRights, ReplyCapability},
     error::KError,
-    message::{Recipient, SyscallRequest, SyscallResult},
+    message::{Message, Recipient, SyscallRequest, SyscallResult, CALL_PAYLOAD_LEN},
     syscalls::{syscall, Syscall},
 };
 
@@ -18,6 +18,9 @@ pub struct ChannelMessage {
     pub id: MessageId,
     pub ptr: *mut u8,
     pub len: usize,
+    /// Badge of the capability the message was sent through, `0` if it was
+    /// unbadged or the message was created locally
+    pub badge: usize,
 }
 
 unsafe impl Send for ChannelMessage {}
@@ -51,6 +54,10 @@ impl MessageId {
     }
 }
 
+/// Most messages which can be waiting to be read from one end of a channel,
+/// which is also how many each end starts out allowing
+pub const MAX_QUEUE_DEPTH: usize = 64;
+
 pub fn create_message(cptr: CapabilityPtr, size: usize) -> SyscallResult<ChannelMessage, KError> {
     syscall(
         Recipient::kernel(),
@@ -60,9 +67,13 @@ pub fn create_message(cptr: CapabilityPtr, size: usize) -> SyscallResult<Channel
         },
     )
     .1
-    .map(|(id, ptr, len)| ChannelMessage { id: MessageId::new(id), ptr: ptr as *mut u8, len })
+    .map(|(id, ptr, len)| ChannelMessage { id: MessageId::new(id), ptr: ptr as *mut u8, len, badge: 0 })
 }
 
+/// Send a message created with [`create_message`]. If the other end's queue
+/// is full this fails with [`KError::WouldBlock`] without consuming the
+/// message, which can be sent again once the channel is
+/// [`WRITABLE`](super::poll::WaitEvents::WRITABLE).
 pub fn send_message(
     cptr: CapabilityPtr,
     message: MessageId,
@@ -104,8 +115,8 @@ pub fn read_message(
         },
     )
     .1
-    .map(|(id, ptr, len, written_caps, caps_remaining)| {
-        (ChannelMessage { id: MessageId::new(id), ptr: ptr as *mut u8, len }, written_caps, caps_remaining)
+    .map(|(id, ptr, len, written_caps, caps_remaining, badge)| {
+        (ChannelMessage { id: MessageId::new(id), ptr: ptr as *mut u8, len, badge }, written_caps, caps_remaining)
     })
 }
 
@@ -122,10 +133,12 @@ pub fn read_message_non_blocking(
     )
     .1
     .map(|vals| match vals {
-        (0, 0, 0, 0, 0) => None,
-        (id, ptr, len, written_caps, caps_remaining) => {
-            Some((ChannelMessage { id: MessageId::new(id), ptr: ptr as *mut u8, len }, written_caps, caps_remaining))
-        }
+        (0, 0, 0, 0, 0, 0) => None,
+        (id, ptr, len, written_caps, caps_remaining, badge) => Some((
+            ChannelMessage { id: MessageId::new(id), ptr: ptr as *mut u8, len, badge },
+            written_caps,
+            caps_remaining,
+        )),
     })
 }
 
@@ -139,3 +152,112 @@ pub fn retire_message(cptr: CapabilityPtr, message: MessageId) -> SyscallResult<
     )
     .1
 }
+
+/// The ring buffer region shared between both ends of a channel
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelRing {
+    pub ptr: *mut u8,
+    pub len: usize,
+    /// Which half of the region this end of the channel produces into, the
+    /// other half is consumed from
+    pub side: usize,
+}
+
+unsafe impl Send for ChannelRing {}
+unsafe impl Sync for ChannelRing {}
+
+pub fn map_ring(cptr: CapabilityPtr, size: usize) -> SyscallResult<ChannelRing, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::MapChannelRing,
+            arguments: [cptr.value(), size, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+    .map(|(ptr, len, side)| ChannelRing { ptr: ptr as *mut u8, len, side })
+}
+
+pub fn notify_ring(cptr: CapabilityPtr) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::NotifyChannelRing,
+            arguments: [cptr.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+pub fn wait_ring(cptr: CapabilityPtr) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::WaitChannelRing,
+            arguments: [cptr.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Limit how many messages can be waiting to be read from this end of the
+/// channel, up to [`MAX_QUEUE_DEPTH`]. Sends from the other end fail with
+/// [`KError::WouldBlock`] while that many are queued.
+pub fn set_queue_depth(cptr: CapabilityPtr, depth: usize) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SetChannelQueueDepth,
+            arguments: [cptr.value(), depth, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Mint a copy of an unbadged channel capability with the given rights and a
+/// non-zero badge, which is delivered with every message sent through the copy
+pub fn mint_badged(
+    cptr: CapabilityPtr,
+    rights: CapabilityRights,
+    badge: usize,
+) -> SyscallResult<CapabilityPtr, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::MintBadgedCapability,
+            arguments: [cptr.value(), rights.value(), badge, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+    .map(CapabilityPtr::new)
+}
+
+/// Send `payload` to the other end of the channel as an
+/// [`IncomingCall`](crate::message::KernelNotification::IncomingCall) and
+/// block until it replies, returning the reply payload
+pub fn call(
+    cptr: CapabilityPtr,
+    payload: [usize; CALL_PAYLOAD_LEN],
+) -> SyscallResult<[usize; CALL_PAYLOAD_LEN], KError> {
+    let mut arguments = [0; 12];
+    arguments[0] = cptr.value();
+    arguments[1..][..CALL_PAYLOAD_LEN].copy_from_slice(&payload);
+
+    syscall::<_, Message, _>(Recipient::kernel(), SyscallRequest { syscall: Syscall::Call, arguments }).1.map(
+        |message| {
+            let mut reply = [0; CALL_PAYLOAD_LEN];
+            reply.copy_from_slice(&message.contents[..CALL_PAYLOAD_LEN]);
+            reply
+        },
+    )
+}
+
+/// Reply to a call, waking up the caller. The reply capability is consumed
+/// even if the caller has since gone away.
+pub fn reply(reply: ReplyCapability, payload: [usize; CALL_PAYLOAD_LEN]) -> SyscallResult<(), KError> {
+    let mut arguments = [0; 12];
+    arguments[0] = reply.cptr().value();
+    arguments[1..][..CALL_PAYLOAD_LEN].copy_from_slice(&payload);
+
+    syscall(Recipient::kernel(), SyscallRequest { syscall: Syscall::Reply, arguments }).1
+}
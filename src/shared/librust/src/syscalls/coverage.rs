@@ -0,0 +1,39 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{syscall, Syscall};
+use crate::{
+    capabilities::CapabilityPtr,
+    error::KError,
+    message::{Recipient, SyscallRequest, SyscallResult},
+};
+
+/// Copy the kernel's edge coverage flags, one byte per edge which is non-zero
+/// if the edge has been taken, starting from the flag at index `start`. `cap`
+/// must be the system control capability with `READ` rights. Returns the
+/// number of flags copied, and the total number of flags, which is zero if the
+/// kernel wasn't built with coverage.
+pub fn read_coverage(cap: CapabilityPtr, start: usize, flags: &mut [u8]) -> SyscallResult<(usize, usize), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::ReadCoverage,
+            arguments: [cap.value(), start, flags.as_mut_ptr() as usize, flags.len(), 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Clear the kernel's edge coverage flags, so only edges taken from now on are
+/// reported. `cap` must be the system control capability with `WRITE` rights.
+pub fn reset_coverage(cap: CapabilityPtr) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::ResetCoverage, arguments: [cap.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+    )
+    .1
+}
@@ -0,0 +1,58 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Syscalls implemented in userspace, for trying out new APIs without
+//! rebuilding the kernel. A task holding the system control capability can
+//! register itself as the syscall extension server, after which syscalls with
+//! a number in [`EXTENSION_SYSCALLS`] are sent to it as
+//! [`KernelNotification::ExtensionSyscall`](crate::message::KernelNotification::ExtensionSyscall)s.
+//! The caller stays blocked until the server replies with
+//! [`reply`](super::channel::reply), and gets the reply's payload back as the
+//! result, so any errors need to be encoded in the payload by the server.
+//! Without a server, extension syscalls fail with [`KError::InvalidSyscall`]
+//! like any other unknown syscall.
+
+use super::{syscall, Syscall, EXTENSION_SYSCALLS};
+use crate::{
+    capabilities::CapabilityPtr,
+    error::KError,
+    message::{Message, Recipient, SyscallRequest, SyscallResult, CALL_PAYLOAD_LEN},
+};
+
+/// Become the syscall extension server, replacing any previous one. `cptr`
+/// must be the system control capability.
+pub fn register_syscall_extension(cptr: CapabilityPtr) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::RegisterSyscallExtension,
+            arguments: [cptr.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Make the extension syscall `number`, which has to be in
+/// [`EXTENSION_SYSCALLS`], returning the payload the server replied with
+pub fn extension_syscall(
+    number: usize,
+    args: [usize; CALL_PAYLOAD_LEN],
+) -> SyscallResult<[usize; CALL_PAYLOAD_LEN], KError> {
+    if !EXTENSION_SYSCALLS.contains(&number) {
+        return SyscallResult::Err(KError::InvalidSyscall(number));
+    }
+
+    let mut contents = [0; 13];
+    contents[0] = number;
+    contents[1..][..CALL_PAYLOAD_LEN].copy_from_slice(&args);
+
+    syscall::<_, Message, _>(Recipient::kernel(), Message { contents }).1.map(|message| {
+        let mut reply = [0; CALL_PAYLOAD_LEN];
+        reply.copy_from_slice(&message.contents[..CALL_PAYLOAD_LEN]);
+        reply
+    })
+}
@@ -37,6 +37,301 @@ pub fn complete_interrupt(interrupt_id: usize) -> SyscallResult<(), KError> {
     .1
 }
 
+/// How console input is processed before it's read with
+/// [`read_stdin`](super::read_stdin). The mode belongs to each reader, and takes
+/// effect whenever that task reads from the console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConsoleMode {
+    /// Input is echoed and only becomes readable a line at a time, with
+    /// backspace, `Ctrl-U`, and `Ctrl-W` editing the current line. `Ctrl-C`
+    /// discards the line and sends [`TaskEvent::Interrupt`] to the foreground
+    /// task.
+    ///
+    /// [`TaskEvent::Interrupt`]: crate::task::TaskEvent::Interrupt
+    #[default]
+    Cooked,
+    /// Every byte is passed through as-is with no echo
+    Raw,
+}
+
+impl ConsoleMode {
+    pub fn from_raw(mode: usize) -> Option<Self> {
+        match mode {
+            0 => Some(Self::Cooked),
+            1 => Some(Self::Raw),
+            _ => None,
+        }
+    }
+
+    pub fn to_raw(self) -> usize {
+        match self {
+            Self::Cooked => 0,
+            Self::Raw => 1,
+        }
+    }
+}
+
+#[inline]
+pub fn set_console_mode(mode: ConsoleMode) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SetConsoleMode,
+            arguments: [mode.to_raw(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Make the task on the other end of the channel `cptr` the foreground task of
+/// the console, which receives [`TaskEvent::Interrupt`] when `Ctrl-C` is
+/// pressed. Passing `None` makes the current task the foreground task. Until a
+/// foreground task is designated, it's whichever task last read from the
+/// console.
+///
+/// [`TaskEvent::Interrupt`]: crate::task::TaskEvent::Interrupt
+#[inline]
+pub fn set_foreground_task(cptr: Option<CapabilityPtr>) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SetForegroundTask,
+            arguments: [cptr.map_or(usize::MAX, |cptr| cptr.value()), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Virtual consoles are numbered from `1` to this, inclusive
+pub const MAX_VIRTUAL_CONSOLES: usize = 8;
+
+/// A new stream that a task's stdin or stdout can be bound to with
+/// [`bind_stdio`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleStreamKind {
+    /// A buffer which is written to by one task and read by another, for
+    /// capturing the output of a task or feeding it input
+    Pipe,
+    /// One of the virtual consoles, which are shown on the physical console
+    /// one at a time
+    Virtual(usize),
+    /// Discards everything written to it and never has anything to read
+    Null,
+}
+
+impl ConsoleStreamKind {
+    pub fn from_raw(kind: usize, id: usize) -> Option<Self> {
+        match kind {
+            0 => Some(Self::Pipe),
+            1 => Some(Self::Virtual(id)),
+            2 => Some(Self::Null),
+            _ => None,
+        }
+    }
+
+    pub fn to_raw(self) -> (usize, usize) {
+        match self {
+            Self::Pipe => (0, 0),
+            Self::Virtual(id) => (1, id),
+            Self::Null => (2, 0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StdioStream {
+    Stdin,
+    Stdout,
+}
+
+impl StdioStream {
+    pub fn from_raw(stream: usize) -> Option<Self> {
+        match stream {
+            0 => Some(Self::Stdin),
+            1 => Some(Self::Stdout),
+            _ => None,
+        }
+    }
+
+    pub fn to_raw(self) -> usize {
+        match self {
+            Self::Stdin => 0,
+            Self::Stdout => 1,
+        }
+    }
+}
+
+/// Create a console stream, returning a capability which can be read from and
+/// written to
+#[inline]
+pub fn create_console_stream(kind: ConsoleStreamKind) -> SyscallResult<CapabilityPtr, KError> {
+    let (kind, id) = kind.to_raw();
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::CreateConsoleStream, arguments: [kind, id, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+    )
+    .1
+    .map(CapabilityPtr::new)
+}
+
+/// Bind the stdin or stdout of the task on the other end of the channel `task`
+/// (or the current task if `None`) to the console stream capability `stream`.
+/// Binding stdin requires the `READ` right, and stdout the `WRITE` right.
+/// Passing `None` for `stream` binds it to the current task's own stream, which
+/// is how tasks start out.
+#[inline]
+pub fn bind_stdio(
+    task: Option<CapabilityPtr>,
+    which: StdioStream,
+    stream: Option<CapabilityPtr>,
+) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::BindStdio,
+            arguments: [
+                task.map_or(usize::MAX, |cptr| cptr.value()),
+                which.to_raw(),
+                stream.map_or(usize::MAX, |cptr| cptr.value()),
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+        },
+    )
+    .1
+}
+
+/// Read whatever is available from a console stream without blocking,
+/// returning the number of bytes read. Returns [`KError::WouldBlock`] if there's
+/// nothing to read yet, and `0` once the other end of a pipe has closed.
+#[inline]
+pub fn read_console_stream(cptr: CapabilityPtr, buffer: &mut [u8]) -> SyscallResult<usize, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::ReadConsoleStream,
+            arguments: [cptr.value(), buffer.as_mut_ptr() as usize, buffer.len(), 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Write as much of `bytes` to a console stream as will fit without blocking,
+/// returning the number of bytes written. Returns [`KError::WouldBlock`] if a
+/// pipe is full, and `0` once every read end of it has closed.
+#[inline]
+pub fn write_console_stream(cptr: CapabilityPtr, bytes: &[u8]) -> SyscallResult<usize, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::WriteConsoleStream,
+            arguments: [cptr.value(), bytes.as_ptr() as usize, bytes.len(), 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Create a pipe, returning capabilities to its read end and its write end.
+/// Both are console streams, so either can be bound to a task's stdin or
+/// stdout with [`bind_stdio`], and waited on with
+/// [`wait_many`](super::poll::wait_many).
+#[inline]
+pub fn create_pipe() -> SyscallResult<(CapabilityPtr, CapabilityPtr), KError> {
+    syscall(Recipient::kernel(), SyscallRequest { syscall: Syscall::CreatePipe, arguments: [0; 12] })
+        .1
+        .map(|(reader, writer)| (CapabilityPtr::new(reader), CapabilityPtr::new(writer)))
+}
+
+/// Drop a console stream capability. A pipe's end is closed once every copy of
+/// it is gone, including any task's stdin or stdout it's bound to.
+#[inline]
+pub fn close_console_stream(cptr: CapabilityPtr) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::CloseConsoleStream,
+            arguments: [cptr.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Feed bytes to the console as if they had been typed on it, for keyboards
+/// driven from userspace. They go through the same line discipline and escape
+/// handling as input from the console device.
+#[inline]
+pub fn push_console_input(bytes: &[u8]) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::PushConsoleInput,
+            arguments: [bytes.as_ptr() as usize, bytes.len(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Protects the system from interrupt storms. When an interrupt fires more
+/// than `max_burst` times within `window_us` microseconds, it's masked for
+/// `mask_us` microseconds, after which any work the device queued up in the
+/// meantime is handled from a single interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptRateLimit {
+    /// Zero disables rate limiting
+    pub max_burst: u32,
+    pub window_us: u64,
+    pub mask_us: u64,
+}
+
+impl InterruptRateLimit {
+    pub const DEFAULT: Self = Self { max_burst: 2000, window_us: 10_000, mask_us: 10_000 };
+    pub const UNLIMITED: Self = Self { max_burst: 0, window_us: 0, mask_us: 0 };
+}
+
+impl Default for InterruptRateLimit {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Change the rate limit of one of the interrupts of the MMIO capability
+/// `cptr`
+#[inline]
+pub fn set_interrupt_rate_limit(
+    cptr: CapabilityPtr,
+    interrupt_id: usize,
+    limit: InterruptRateLimit,
+) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SetInterruptRateLimit,
+            arguments: [
+                cptr.value(),
+                interrupt_id,
+                limit.max_burst as usize,
+                limit.window_us as usize,
+                limit.mask_us as usize,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+        },
+    )
+    .1
+}
+
 unsafe impl Send for MmioCapabilityInfo {}
 unsafe impl Sync for MmioCapabilityInfo {}
 
@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{syscall, Syscall};
+use crate::{
+    capabilities::CapabilityPtr,
+    error::KError,
+    message::{Recipient, SyscallRequest, SyscallResult},
+};
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+
+/// The page at the start of the kernel log mapping, which is the only part of
+/// it the logger can write to. Positions are free-running byte counters, the
+/// kernel only ever writes `reserved` and `head`, and the logger only ever
+/// writes `tail`. The kernel never waits for the logger, so anything older
+/// than `capacity` bytes behind `head` has been overwritten.
+#[repr(C)]
+pub struct KernelLogControl {
+    /// Size of the data region in bytes
+    pub capacity: usize,
+    /// End of the record the kernel is currently writing, bytes behind this
+    /// by more than `capacity` may be getting overwritten
+    pub reserved: AtomicUsize,
+    /// End of the last complete record
+    pub head: AtomicUsize,
+    /// How far the logger has read
+    pub tail: AtomicUsize,
+}
+
+/// The kernel log ring mapped into this task by [`map_kernel_log`]. Each
+/// record is a single line of text ending in `\n`.
+#[derive(Debug)]
+pub struct KernelLog {
+    control: *const KernelLogControl,
+    data: *const u8,
+    capacity: usize,
+}
+
+unsafe impl Send for KernelLog {}
+
+impl KernelLog {
+    fn control(&self) -> &KernelLogControl {
+        unsafe { &*self.control }
+    }
+
+    /// Whether the kernel has logged anything that hasn't been read yet
+    pub fn has_unread(&self) -> bool {
+        self.control().head.load(Ordering::Acquire) != self.control().tail.load(Ordering::Relaxed)
+    }
+
+    /// Copy as much of the unread log as fits into `buffer`. Returns the number
+    /// of bytes read, and the number of bytes which were overwritten before
+    /// they could be read, in which case the first line read may be partial.
+    pub fn read(&mut self, buffer: &mut [u8]) -> (usize, usize) {
+        let control = self.control();
+        let tail = control.tail.load(Ordering::Relaxed);
+        let head = control.head.load(Ordering::Acquire);
+
+        let mut start = tail.max(head.saturating_sub(self.capacity));
+        let mut n = buffer.len().min(head - start);
+        self.copy_out(start, &mut buffer[..n]);
+
+        // Anything the kernel started overwriting while it was being copied
+        // can't be trusted
+        fence(Ordering::Acquire);
+        let overwritten = control.reserved.load(Ordering::Relaxed).saturating_sub(self.capacity);
+        if overwritten > start {
+            let bad = n.min(overwritten - start);
+            buffer.copy_within(bad..n, 0);
+            n -= bad;
+            start += bad;
+        }
+
+        control.tail.store(start + n, Ordering::Release);
+        (n, start - tail)
+    }
+
+    fn copy_out(&self, position: usize, buffer: &mut [u8]) {
+        let start = position % self.capacity;
+        let first = buffer.len().min(self.capacity - start);
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.data.add(start), buffer.as_mut_ptr(), first);
+            core::ptr::copy_nonoverlapping(self.data, buffer.as_mut_ptr().add(first), buffer.len() - first);
+        }
+    }
+}
+
+/// Map the kernel log ring into the current task, which needs the kernel log
+/// capability the kernel gives to `init` after the system control capability.
+/// Each call creates a new mapping, so this should only be done once. The
+/// capability can be waited on with [`super::poll::wait_many`], and becomes
+/// readable when there's unread log.
+pub fn map_kernel_log(cap: CapabilityPtr) -> SyscallResult<KernelLog, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::MapKernelLog, arguments: [cap.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+    )
+    .1
+    .map(|(control, data, capacity): (usize, usize, usize)| KernelLog {
+        control: control as *const KernelLogControl,
+        data: data as *const u8,
+        capacity,
+    })
+}
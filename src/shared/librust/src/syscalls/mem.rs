@@ -23,3 +23,182 @@ pub fn query_memory_capability(cptr: CapabilityPtr) -> SyscallResult<(*mut u8, u
     .1
     .map(|(ptr, len, perms)| (ptr as *mut u8, len, MemoryPermissions::new(perms)))
 }
+
+/// Change the permissions of already allocated memory, which must be page
+/// aligned and owned solely by the current task. Memory can't be made both
+/// writable and executable.
+pub fn set_memory_permissions(ptr: *const u8, len: usize, perms: MemoryPermissions) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SetMemoryPermissions,
+            arguments: [ptr as usize, len, perms.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Make memory the current task has written code into executable, which also
+/// makes it read-only, so the code can't be changed once it can be run. This
+/// is the only way for allocated memory to become executable, and the
+/// instruction caches of every hart are synchronized with the new code before
+/// this returns. The same requirements as [`set_memory_permissions`] apply to
+/// `ptr` and `len`.
+pub fn finalize_executable(ptr: *const u8, len: usize) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::FinalizeExecutable,
+            arguments: [ptr as usize, len, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Seal the current contents of the memory allocated at `ptr` into a new
+/// read-only memory capability, which can be sent to any number of readers.
+/// The memory stays writable, the next write copies it first so the snapshot
+/// never changes. Returns the capability along with where the snapshot is
+/// mapped into the current task and its size.
+pub fn snapshot_memory(ptr: *const u8) -> SyscallResult<(CapabilityPtr, *const u8, usize), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::SnapshotMemory, arguments: [ptr as usize, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+    )
+    .1
+    .map(|(cptr, ptr, len)| (CapabilityPtr::new(cptr), ptr as *const u8, len))
+}
+
+/// What an occupied region of an address space is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum RegionKind {
+    Channel = 0,
+    Data = 1,
+    Guard = 2,
+    ReadOnly = 3,
+    Stack = 4,
+    Text = 5,
+    Tls = 6,
+    Unoccupied = 7,
+    UserAllocated = 8,
+    Dma = 9,
+    Mmio = 10,
+    TimePage = 11,
+}
+
+impl Default for RegionKind {
+    fn default() -> Self {
+        Self::Unoccupied
+    }
+}
+
+/// Description of an occupied region of an address space
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct RegionInfo {
+    pub start: usize,
+    pub len: usize,
+    pub readable: bool,
+    pub writable: bool,
+    pub executable: bool,
+    pub kind: RegionKind,
+    /// The name given to the region, if any, padded out with zeroes
+    pub name: [u8; MAX_REGION_NAME_LEN],
+}
+
+impl RegionInfo {
+    /// The name given to the region, which is empty if it doesn't have one
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+}
+
+/// Longest name a region can be given with [`name_region`]
+pub const MAX_REGION_NAME_LEN: usize = 32;
+
+/// Name the region of the current task's address space containing `ptr`, which
+/// is shown alongside it by [`query_address_space`]. An empty name clears it.
+pub fn name_region(ptr: *const u8, name: &str) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::NameRegion,
+            arguments: [ptr as usize, name.as_ptr() as usize, name.len(), 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Fill `regions` with the occupied regions of the current task's address
+/// space, or the address space of the task on the other end of the `GRANT`
+/// channel `task`, ordered by address. Returns the number of regions written
+/// and the total number of occupied regions.
+pub fn query_address_space(
+    task: Option<CapabilityPtr>,
+    regions: &mut [RegionInfo],
+) -> SyscallResult<(usize, usize), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::QueryAddressSpace,
+            arguments: [
+                task.map(CapabilityPtr::value).unwrap_or(usize::MAX),
+                regions.as_mut_ptr() as usize,
+                regions.len(),
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+        },
+    )
+    .1
+}
+
+/// Identifies an address space snapshot, followed by [`SNAPSHOT_VERSION`]
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"VASS";
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+/// Serialize the occupied regions of the current task's address space, or the
+/// address space of the task on the other end of the `GRANT` channel `task`,
+/// into `buffer`. Returns the number of bytes written and the size of the whole
+/// snapshot, so a buffer that's too small can be resized and the call retried.
+///
+/// Snapshots are meant to be compared against each other over time off of the
+/// device, see `cargo xtask map_diff`. After [`SNAPSHOT_MAGIC`] and
+/// [`SNAPSHOT_VERSION`] comes the number of regions, then for each region in
+/// address order: the number of pages between it and the end of the previous
+/// region (or its starting page number for the first), its length in pages,
+/// and a byte holding its readable, writable, and executable bits in the low
+/// three bits and its [`RegionKind`] above them. Every number other than that
+/// final byte is an unsigned LEB128 varint, and pages are 4 KiB.
+pub fn snapshot_address_space(task: Option<CapabilityPtr>, buffer: &mut [u8]) -> SyscallResult<(usize, usize), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SnapshotAddressSpace,
+            arguments: [
+                task.map(CapabilityPtr::value).unwrap_or(usize::MAX),
+                buffer.as_mut_ptr() as usize,
+                buffer.len(),
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+        },
+    )
+    .1
+}
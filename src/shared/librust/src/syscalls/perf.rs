@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{syscall, Syscall};
+use crate::{
+    capabilities::CapabilityPtr,
+    error::KError,
+    message::{Recipient, SyscallRequest, SyscallResult},
+};
+
+/// Hardware events the profiler can sample on, numbered the same as the SBI
+/// PMU extension's hardware general events
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum PerfEvent {
+    Cycles = 1,
+    Instructions = 2,
+    CacheReferences = 3,
+    CacheMisses = 4,
+    BranchInstructions = 5,
+    BranchMisses = 6,
+}
+
+impl PerfEvent {
+    pub const ALL: [Self; 6] = [
+        Self::Cycles,
+        Self::Instructions,
+        Self::CacheReferences,
+        Self::CacheMisses,
+        Self::BranchInstructions,
+        Self::BranchMisses,
+    ];
+
+    pub fn from_usize(n: usize) -> Option<Self> {
+        Self::ALL.into_iter().find(|event| *event as usize == n)
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|event| event.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Cycles => "cycles",
+            Self::Instructions => "instructions",
+            Self::CacheReferences => "cache-references",
+            Self::CacheMisses => "cache-misses",
+            Self::BranchInstructions => "branches",
+            Self::BranchMisses => "branch-misses",
+        }
+    }
+}
+
+/// Where a hart was when it was sampled
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct PerfSample {
+    pub pc: usize,
+    /// The task running on the hart, or `0` if it was idle or the task
+    /// couldn't be determined
+    pub tid: usize,
+    pub hart: usize,
+    /// Whether `pc` is in the kernel
+    pub kernel: bool,
+}
+
+/// Start sampling `event` on every hart, recording a sample each time a hart
+/// counts `period` of them, which replaces any session that's already running.
+/// Without counter overflow interrupts (the `Sscofpmf` extension), harts are
+/// sampled on each timer tick instead. Returns a capability to read the
+/// samples with, which can be waited on until there are samples to read.
+pub fn perf_start(system: CapabilityPtr, event: PerfEvent, period: usize) -> SyscallResult<CapabilityPtr, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::PerfStart,
+            arguments: [system.value(), event as usize, period, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+    .map(CapabilityPtr::new)
+}
+
+/// Move as many samples as fit into `samples`, oldest first on each hart.
+/// Returns the number of samples read, and the number dropped since the last
+/// read because the kernel ran out of room for them.
+pub fn perf_read(session: CapabilityPtr, samples: &mut [PerfSample]) -> SyscallResult<(usize, usize), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::PerfRead,
+            arguments: [session.value(), samples.as_mut_ptr() as usize, samples.len(), 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Stop sampling, any samples that haven't been read yet can still be read
+pub fn perf_stop(session: CapabilityPtr) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::PerfStop, arguments: [session.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+    )
+    .1
+}
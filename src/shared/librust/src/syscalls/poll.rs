@@ -0,0 +1,112 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{syscall, Syscall};
+use crate::{
+    capabilities::CapabilityPtr,
+    error::KError,
+    message::{Recipient, SyscallRequest, SyscallResult},
+};
+
+/// Maximum number of capabilities that can be waited on at once
+pub const MAX_WAIT_ITEMS: usize = 64;
+
+/// Wait forever in [`wait_many`]
+pub const NO_TIMEOUT: usize = usize::MAX;
+
+/// Conditions a capability can be waited on for with [`wait_many`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct WaitEvents(usize);
+
+impl WaitEvents {
+    pub const NONE: Self = Self(0);
+    /// There's something to be read, e.g. a queued channel message
+    pub const READABLE: Self = Self(1);
+    /// The object was signalled, e.g. the other end of a channel rang the
+    /// ring buffer doorbell
+    pub const NOTIFIED: Self = Self(2);
+    /// The other end of the object has gone away. This is always reported,
+    /// whether or not it was asked for.
+    pub const CLOSED: Self = Self(4);
+    /// There's room to send without getting [`KError::WouldBlock`], e.g. the
+    /// queue on the other end of a channel isn't full
+    pub const WRITABLE: Self = Self(8);
+}
+
+impl WaitEvents {
+    pub fn new(value: usize) -> Self {
+        Self(value & 0xF)
+    }
+
+    pub fn value(self) -> usize {
+        self.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl core::ops::BitOr for WaitEvents {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        WaitEvents(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitOrAssign for WaitEvents {
+    fn bitor_assign(&mut self, rhs: Self) {
+        *self = WaitEvents(self.0 | rhs.0);
+    }
+}
+
+impl core::ops::BitAnd for WaitEvents {
+    type Output = bool;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        (self.0 & rhs.0) == rhs.0
+    }
+}
+
+/// A capability to wait on, and the events that were ready for it once
+/// [`wait_many`] returns
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct WaitItem {
+    pub cptr: CapabilityPtr,
+    pub interest: WaitEvents,
+    /// Filled in by the kernel
+    pub ready: WaitEvents,
+}
+
+impl WaitItem {
+    pub fn new(cptr: CapabilityPtr, interest: WaitEvents) -> Self {
+        Self { cptr, interest, ready: WaitEvents::NONE }
+    }
+}
+
+/// Block until at least one of the `items` is ready, or `timeout_us`
+/// microseconds have passed, filling in [`WaitItem::ready`] for each of them.
+/// A timeout of zero polls without blocking, and [`NO_TIMEOUT`] waits forever.
+/// Returns the number of items which are ready, which is zero if the wait
+/// timed out. Any capability type that can become ready can be mixed in a
+/// single call: channels, pipes, the kernel log, profiling sessions, and
+/// claimed devices, which are readable while one of their interrupts is
+/// waiting to be completed. With no items it sleeps for `timeout_us`, which
+/// can't be [`NO_TIMEOUT`].
+pub fn wait_many(items: &mut [WaitItem], timeout_us: usize) -> SyscallResult<usize, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::WaitMany,
+            arguments: [items.as_mut_ptr() as usize, items.len(), timeout_us, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
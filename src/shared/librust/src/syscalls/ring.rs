@@ -0,0 +1,184 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The syscall ring, a submission and completion queue pair shared between a
+//! task and the kernel. The task queues up [`Submission`]s and hands them all
+//! to the kernel with one [`ring_enter`], and the kernel posts a
+//! [`Completion`] for each once it's finished, which for operations like
+//! [`RingOp::Timeout`] may be long after `ring_enter` returns.
+//!
+//! Positions are free-running counters, the task only ever writes `sq_tail`
+//! and `cq_head`, and the kernel only ever writes `sq_head` and `cq_tail`. The
+//! kernel doesn't take submissions it couldn't post a completion for, so the
+//! completion queue never overflows.
+
+use super::{syscall, Syscall};
+use crate::{
+    error::KError,
+    message::{Message, Recipient, SyscallRequest, SyscallResult},
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Most submissions a ring can hold, the completion queue holds twice as many
+pub const MAX_RING_ENTRIES: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum RingOp {
+    /// Completes straight away, for measuring the overhead of the ring
+    Nop = 0,
+    /// Send the message `args[1]` of length `args[2]` over the channel
+    /// `args[0]`, like [`super::channel::send_message`] without capabilities
+    Send = 1,
+    /// Completes once `args[0]` microseconds have passed
+    Timeout = 2,
+    /// Notify the other end of the channel `args[0]`'s ring buffer, like
+    /// [`super::channel::notify_ring`]
+    NotifyChannelRing = 3,
+}
+
+impl RingOp {
+    pub fn from_usize(n: usize) -> Option<Self> {
+        match n {
+            0 => Some(Self::Nop),
+            1 => Some(Self::Send),
+            2 => Some(Self::Timeout),
+            3 => Some(Self::NotifyChannelRing),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Submission {
+    pub op: usize,
+    /// Copied to the operation's completion as is
+    pub user_data: usize,
+    pub args: [usize; 4],
+}
+
+impl Submission {
+    pub fn new(op: RingOp, user_data: usize, args: [usize; 4]) -> Self {
+        Self { op: op as usize, user_data, args }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Completion {
+    pub user_data: usize,
+    pub is_err: usize,
+    /// The first words of what the operation would have returned as a syscall
+    pub value: [usize; 3],
+}
+
+impl Completion {
+    pub fn result(&self) -> SyscallResult<[usize; 3], KError> {
+        let mut message = Message::default();
+        message.contents[..3].copy_from_slice(&self.value);
+
+        match self.is_err {
+            0 => SyscallResult::Ok(self.value),
+            _ => SyscallResult::Err(KError::from(message)),
+        }
+    }
+}
+
+/// The start of the ring mapping, followed by the submission queue at
+/// `sq_offset` and the completion queue at `cq_offset`
+#[repr(C)]
+pub struct RingHeader {
+    pub sq_entries: usize,
+    pub cq_entries: usize,
+    pub sq_offset: usize,
+    pub cq_offset: usize,
+    pub sq_head: AtomicUsize,
+    pub sq_tail: AtomicUsize,
+    pub cq_head: AtomicUsize,
+    pub cq_tail: AtomicUsize,
+}
+
+/// The ring mapped into this task by [`setup_syscall_ring`]
+#[derive(Debug)]
+pub struct SyscallRing {
+    header: *const RingHeader,
+}
+
+unsafe impl Send for SyscallRing {}
+
+impl SyscallRing {
+    fn header(&self) -> &RingHeader {
+        unsafe { &*self.header }
+    }
+
+    fn submissions(&self) -> *mut Submission {
+        unsafe { self.header.cast::<u8>().add(self.header().sq_offset) as *mut Submission }
+    }
+
+    fn completions(&self) -> *const Completion {
+        unsafe { self.header.cast::<u8>().add(self.header().cq_offset).cast() }
+    }
+
+    /// Queue an operation, returning `false` if the submission queue is full
+    pub fn push(&mut self, submission: Submission) -> bool {
+        let header = self.header();
+        let tail = header.sq_tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(header.sq_head.load(Ordering::Acquire)) == header.sq_entries {
+            return false;
+        }
+
+        unsafe { self.submissions().add(tail % header.sq_entries).write_volatile(submission) };
+        header.sq_tail.store(tail.wrapping_add(1), Ordering::Release);
+
+        true
+    }
+
+    /// Take the oldest completion, if there is one
+    pub fn pop(&mut self) -> Option<Completion> {
+        let header = self.header();
+        let head = header.cq_head.load(Ordering::Relaxed);
+        if head == header.cq_tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let completion = unsafe { self.completions().add(head % header.cq_entries).read_volatile() };
+        header.cq_head.store(head.wrapping_add(1), Ordering::Release);
+
+        Some(completion)
+    }
+
+    /// Hand everything queued to the kernel, see [`ring_enter`]
+    pub fn enter(&mut self, min_complete: usize) -> SyscallResult<usize, KError> {
+        ring_enter(min_complete)
+    }
+}
+
+/// Create the current thread's syscall ring with room for `entries`
+/// submissions, which must be a power of two no more than
+/// [`MAX_RING_ENTRIES`]. A thread only has one ring.
+pub fn setup_syscall_ring(entries: usize) -> SyscallResult<SyscallRing, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::SetupSyscallRing, arguments: [entries, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+    )
+    .1
+    .map(|address: usize| SyscallRing { header: address as *const RingHeader })
+}
+
+/// Start every operation waiting in the submission queue, then block until at
+/// least `min_complete` completions are ready to be read. Returns the number
+/// of operations started. Operations are left queued if there wouldn't be room
+/// for their completions, and `min_complete` can't be more than the number of
+/// completions unread or still to come.
+pub fn ring_enter(min_complete: usize) -> SyscallResult<usize, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::RingEnter, arguments: [min_complete, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+    )
+    .1
+}
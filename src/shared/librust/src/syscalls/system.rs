@@ -0,0 +1,400 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{mem::RegionInfo, syscall, Syscall};
+use crate::{
+    capabilities::CapabilityPtr,
+    error::KError,
+    message::{Recipient, SyscallRequest, SyscallResult},
+};
+use core::mem::MaybeUninit;
+
+/// Maximum number of temperature readings reported in [`SystemInfo`]
+pub const MAX_TEMPERATURE_SENSORS: usize = 4;
+
+/// Information about the running kernel and the machine it's running on
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SystemInfo {
+    /// Kernel version
+    pub version: InfoString<16>,
+    /// Git commit the kernel was built from, or `unknown`
+    pub commit: InfoString<40>,
+    pub profile: BuildProfile,
+    /// Time since the platform timer started that the kernel booted, in
+    /// microseconds
+    pub boot_time_us: u64,
+    /// Time since the kernel booted, in microseconds
+    pub uptime_us: u64,
+    /// Number of harts in the system
+    pub n_harts: usize,
+    /// Platform model name as given by the device tree
+    pub platform: InfoString<64>,
+    /// Frequency of the platform timer, in Hz
+    pub timer_freq_hz: u64,
+    /// Current CPU core clock frequency in Hz, or `0` if the platform doesn't
+    /// expose it
+    pub cpu_freq_hz: u64,
+    /// Number of valid readings in `temperatures_mc`
+    pub n_temperatures: usize,
+    /// On-chip temperature readings, in millidegrees Celsius
+    pub temperatures_mc: [i32; MAX_TEMPERATURE_SENSORS],
+    /// Why the system was last reset
+    pub last_reset: ResetReason,
+}
+
+impl SystemInfo {
+    /// The valid temperature readings, in millidegrees Celsius
+    pub fn temperatures(&self) -> &[i32] {
+        &self.temperatures_mc[..self.n_temperatures.min(MAX_TEMPERATURE_SENSORS)]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum BuildProfile {
+    Debug = 0,
+    Release = 1,
+}
+
+impl BuildProfile {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BuildProfile::Debug => "debug",
+            BuildProfile::Release => "release",
+        }
+    }
+}
+
+/// Why the system was last reset, as recorded by the previous boot in the
+/// platform's persistent store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum ResetReason {
+    /// There's no persistent store, or its contents couldn't be read
+    Unknown = 0,
+    /// The persistent store was cleared, the system was powered on from cold
+    PowerOn = 1,
+    /// The previous boot shut down cleanly
+    Shutdown = 2,
+    /// The previous boot rebooted cleanly
+    Reboot = 3,
+    /// The previous boot panicked
+    Panic = 4,
+    /// The previous boot stopped without recording why, which is usually a
+    /// watchdog resetting a hung system
+    Watchdog = 5,
+}
+
+impl ResetReason {
+    pub fn from_raw(n: usize) -> Option<Self> {
+        match n {
+            0 => Some(Self::Unknown),
+            1 => Some(Self::PowerOn),
+            2 => Some(Self::Shutdown),
+            3 => Some(Self::Reboot),
+            4 => Some(Self::Panic),
+            5 => Some(Self::Watchdog),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResetReason::Unknown => "unknown",
+            ResetReason::PowerOn => "power-on",
+            ResetReason::Shutdown => "shutdown",
+            ResetReason::Reboot => "reboot",
+            ResetReason::Panic => "panic",
+            ResetReason::Watchdog => "watchdog",
+        }
+    }
+}
+
+/// A fixed-capacity UTF-8 string, truncated if the source string is too long
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct InfoString<const N: usize> {
+    len: usize,
+    bytes: [u8; N],
+}
+
+impl<const N: usize> InfoString<N> {
+    pub fn new(s: &str) -> Self {
+        let mut len = s.len().min(N);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        let mut bytes = [0; N];
+        bytes[..len].copy_from_slice(&s.as_bytes()[..len]);
+
+        Self { len, bytes }
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len.min(N)]).unwrap_or_default()
+    }
+}
+
+impl<const N: usize> Default for InfoString<N> {
+    fn default() -> Self {
+        Self::new("")
+    }
+}
+
+impl<const N: usize> core::fmt::Debug for InfoString<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl<const N: usize> core::fmt::Display for InfoString<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+pub fn system_info() -> SyscallResult<SystemInfo, KError> {
+    let mut info = MaybeUninit::<SystemInfo>::uninit();
+
+    syscall::<_, (), KError>(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SystemInfo,
+            arguments: [info.as_mut_ptr() as usize, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+    .map(|_| unsafe { info.assume_init() })
+}
+
+/// Policy used by the kernel to pick the CPU clock frequency on platforms
+/// where it can be changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuGovernor {
+    /// Scale the frequency with the number of runnable tasks
+    Ondemand,
+    /// Always run at the highest frequency
+    Performance,
+    /// Always run at the lowest frequency
+    Powersave,
+    /// Run at a fixed frequency in Hz, clamped to what the platform supports
+    Userspace(u64),
+}
+
+impl CpuGovernor {
+    pub fn from_raw(kind: usize, hz: usize) -> Option<Self> {
+        match kind {
+            0 => Some(Self::Ondemand),
+            1 => Some(Self::Performance),
+            2 => Some(Self::Powersave),
+            3 => Some(Self::Userspace(hz as u64)),
+            _ => None,
+        }
+    }
+
+    pub fn to_raw(self) -> (usize, usize) {
+        match self {
+            Self::Ondemand => (0, 0),
+            Self::Performance => (1, 0),
+            Self::Powersave => (2, 0),
+            Self::Userspace(hz) => (3, hz as usize),
+        }
+    }
+}
+
+/// Override the kernel's CPU frequency governor, returning the CPU frequency
+/// in Hz after the change. `cap` must be the system control capability with
+/// `WRITE` rights, see [`shutdown`]. Fails if the platform doesn't support
+/// frequency scaling.
+pub fn set_cpu_governor(cap: CapabilityPtr, governor: CpuGovernor) -> SyscallResult<u64, KError> {
+    let (kind, hz) = governor.to_raw();
+
+    syscall::<_, usize, KError>(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SetCpuGovernor,
+            arguments: [cap.value(), kind, hz, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+    .map(|hz| hz as u64)
+}
+
+/// How the system is reset by [`shutdown`] and [`reboot`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum ResetKind {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+impl ResetKind {
+    pub fn from_raw(n: usize) -> Option<Self> {
+        match n {
+            0 => Some(Self::Shutdown),
+            1 => Some(Self::ColdReboot),
+            2 => Some(Self::WarmReboot),
+            _ => None,
+        }
+    }
+}
+
+/// Power off the system. `cap` must be the system control capability, which
+/// the kernel gives to `init` right after the device tree capability, with
+/// `WRITE` rights. Only returns if the capability is invalid.
+pub fn shutdown(cap: CapabilityPtr) -> SyscallResult<(), KError> {
+    system_reset(cap, ResetKind::Shutdown)
+}
+
+/// Reboot the system, see [`shutdown`] for the capability needed
+pub fn reboot(cap: CapabilityPtr) -> SyscallResult<(), KError> {
+    system_reset(cap, ResetKind::ColdReboot)
+}
+
+pub fn system_reset(cap: CapabilityPtr, kind: ResetKind) -> SyscallResult<(), KError> {
+    syscall::<_, (), KError>(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SystemReset,
+            arguments: [cap.value(), kind as usize, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// A userspace task that was killed by a fault, as recorded by the kernel
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct CrashInfo {
+    /// Increases by one with every crash, starting from 1
+    pub id: usize,
+    pub tid: usize,
+    pub name: InfoString<32>,
+    /// `scause` of the fault
+    pub cause: usize,
+    pub pc: usize,
+    /// `stval` of the fault, e.g. the address that was accessed for page
+    /// faults
+    pub fault_address: usize,
+    /// `x1` through `x31` at the time of the fault
+    pub registers: [usize; 31],
+}
+
+impl CrashInfo {
+    pub fn cause_name(&self) -> &'static str {
+        match self.cause {
+            0 => "instruction address misaligned",
+            1 => "instruction access fault",
+            2 => "illegal instruction",
+            3 => "breakpoint",
+            4 => "load address misaligned",
+            5 => "load access fault",
+            6 => "store address misaligned",
+            7 => "store access fault",
+            12 => "instruction page fault",
+            13 => "load page fault",
+            15 => "store page fault",
+            _ => "unknown fault",
+        }
+    }
+}
+
+/// Read the oldest crash the kernel still has recorded with an ID of at least
+/// `min_id` into `info`, along with as much of the crashed task's memory map
+/// as fits into `regions`. The kernel only keeps the most recent few crashes.
+/// `cap` must be the system control capability with `READ` rights. Returns
+/// `None` if there's no such crash, otherwise the number of regions written
+/// and the total number of regions.
+pub fn read_crash_dump(
+    cap: CapabilityPtr,
+    min_id: usize,
+    info: &mut CrashInfo,
+    regions: &mut [RegionInfo],
+) -> SyscallResult<Option<(usize, usize)>, KError> {
+    syscall::<_, (usize, usize, usize), KError>(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::ReadCrashDump,
+            arguments: [
+                cap.value(),
+                min_id,
+                info as *mut CrashInfo as usize,
+                regions.as_mut_ptr() as usize,
+                regions.len(),
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+        },
+    )
+    .1
+    .map(|(found, written, total)| match found {
+        0 => None,
+        _ => Some((written, total)),
+    })
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(usize)]
+pub enum MetricKind {
+    /// A value which only ever increases, e.g. the number of syscalls made
+    #[default]
+    Counter = 0,
+    /// A value which can go up and down, e.g. the number of running tasks
+    Gauge = 1,
+}
+
+impl MetricKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+        }
+    }
+}
+
+/// A named counter or gauge kept by the kernel
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct KernelMetric {
+    pub name: InfoString<32>,
+    pub kind: MetricKind,
+    pub value: u64,
+}
+
+/// Fill `metrics` with the current value of the kernel's metrics. Returns the
+/// number of metrics written and the total number of metrics.
+pub fn read_kernel_metrics(metrics: &mut [KernelMetric]) -> SyscallResult<(usize, usize), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::ReadKernelMetrics,
+            arguments: [metrics.as_mut_ptr() as usize, metrics.len(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Fill `buffer` with random bytes from the kernel's CSPRNG, returning the
+/// number of bytes written
+pub fn get_random(buffer: &mut [u8]) -> SyscallResult<usize, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::GetRandom,
+            arguments: [buffer.as_mut_ptr() as usize, buffer.len(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
@@ -0,0 +1,241 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{syscall, Syscall};
+use crate::{
+    capabilities::CapabilityPtr,
+    error::KError,
+    message::{Recipient, SyscallRequest, SyscallResult},
+    task::{ExitStatus, TaskContext, TaskEvent, TaskEventMask, Tid},
+};
+
+/// Block until the task on the other end of the channel `task` exits, and
+/// return its exit status. Returns immediately if the task has already exited.
+pub fn wait(task: CapabilityPtr) -> SyscallResult<ExitStatus, KError> {
+    syscall::<_, (usize, usize), KError>(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::Wait, arguments: [task.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+    )
+    .1
+    .map(|(kind, code)| ExitStatus::from_raw(kind, code).unwrap())
+}
+
+/// Request a [`crate::message::KernelNotification::TaskExited`] notification
+/// once the task on the other end of the channel `task` exits, without
+/// blocking. If the task has already exited, the notification is sent right
+/// away.
+pub fn watch_exit(task: CapabilityPtr) -> SyscallResult<(), KError> {
+    syscall::<_, (), KError>(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::WatchExit, arguments: [task.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+    )
+    .1
+}
+
+/// Send an event to the task on the other end of the channel `task`, which
+/// requires the capability to have [`CapabilityRights::WRITE`]. If the task has
+/// no handler for the event, it's terminated.
+///
+/// [`CapabilityRights::WRITE`]: crate::capabilities::CapabilityRights::WRITE
+pub fn send_task_event(task: CapabilityPtr, event: TaskEvent) -> SyscallResult<(), KError> {
+    syscall::<_, (), KError>(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SendTaskEvent,
+            arguments: [task.value(), event.to_raw(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Handle the events in `mask` by running `handler` on the given stack instead
+/// of being terminated. The handler receives the raw value of the event (see
+/// [`TaskEvent::from_raw`]) and must finish by calling [`return_from_event`],
+/// other events are held until then. [`TaskEvent::Kill`] can't be handled.
+/// Passing `None` removes the handler.
+///
+/// # Safety
+///
+/// `stack` must point to the top of a region of memory which is only used by
+/// the handler
+pub unsafe fn set_event_handler(
+    handler: Option<extern "C" fn(usize) -> !>,
+    stack: *mut u8,
+    mask: TaskEventMask,
+) -> SyscallResult<(), KError> {
+    syscall::<_, (), KError>(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SetEventHandler,
+            arguments: [
+                handler.map_or(0, |handler| handler as usize),
+                stack as usize,
+                mask.value() as usize,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+        },
+    )
+    .1
+}
+
+/// Resume whatever the task was doing before its event handler was run
+pub fn return_from_event() -> ! {
+    let _ = syscall::<_, (), KError>(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::ReturnFromEvent, arguments: [0; 12] },
+    );
+
+    unreachable!("returned from an event when not handling one")
+}
+
+/// Read the saved context of the task on the other end of the `GRANT` channel
+/// `task`, which is up to date as long as the task isn't running, or with
+/// `None`, the context the current task was in before the event it's handling
+/// interrupted it
+pub fn get_task_context(task: Option<CapabilityPtr>) -> SyscallResult<TaskContext, KError> {
+    let mut context = TaskContext::default();
+    syscall::<_, (), KError>(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::GetTaskContext,
+            arguments: [
+                task.map(CapabilityPtr::value).unwrap_or(usize::MAX),
+                &mut context as *mut TaskContext as usize,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+        },
+    )
+    .1
+    .map(|()| context)
+}
+
+/// Replace the saved context of the task on the other end of the `GRANT`
+/// channel `task`, which has to be blocked, or with `None`, the context the
+/// current task resumes once its event handler returns. The new context takes
+/// effect the next time the task runs.
+pub fn set_task_context(task: Option<CapabilityPtr>, context: &TaskContext) -> SyscallResult<(), KError> {
+    syscall::<_, (), KError>(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SetTaskContext,
+            arguments: [
+                task.map(CapabilityPtr::value).unwrap_or(usize::MAX),
+                context as *const TaskContext as usize,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+        },
+    )
+    .1
+}
+
+/// Set the scheduling priority of the current thread, see
+/// [`crate::task::DEFAULT_PRIORITY`]
+pub fn set_priority(priority: u8) -> SyscallResult<(), KError> {
+    syscall::<_, (), KError>(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SetPriority,
+            arguments: [usize::from(priority), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Set the name of the current task, at most [`crate::task::MAX_NAME_LEN`]
+/// bytes. Only the calling thread is renamed, threads created afterwards take
+/// the new name.
+pub fn set_task_name(name: &str) -> SyscallResult<(), KError> {
+    syscall::<_, (), KError>(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SetTaskName,
+            arguments: [name.as_ptr() as usize, name.len(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Set the name of the current thread, which is shown alongside the task name.
+/// An empty name clears it.
+pub fn set_thread_name(name: &str) -> SyscallResult<(), KError> {
+    syscall::<_, (), KError>(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SetThreadName,
+            arguments: [name.as_ptr() as usize, name.len(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Copy the task and thread names of `tid`, or the current thread if `None`,
+/// into the given buffers. Returns the full lengths of both names, which may
+/// be longer than what was copied, and a thread name length of zero if the
+/// thread hasn't been named.
+pub fn query_task_name(
+    tid: Option<Tid>,
+    name: &mut [u8],
+    thread_name: &mut [u8],
+) -> SyscallResult<(usize, usize), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::QueryTaskName,
+            arguments: [
+                tid.map_or(0, Tid::value),
+                name.as_mut_ptr() as usize,
+                name.len(),
+                thread_name.as_mut_ptr() as usize,
+                thread_name.len(),
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+        },
+    )
+    .1
+}
+
+/// Set the niceness of the current thread, between
+/// [`crate::task::MIN_NICE`] and [`crate::task::MAX_NICE`]
+pub fn set_nice(nice: i8) -> SyscallResult<(), KError> {
+    syscall::<_, (), KError>(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::SetNice, arguments: [nice as usize, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+    )
+    .1
+}
@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Task groups, which let a supervisor manage a set of tasks as a unit. Tasks
+//! are placed into a group when they're spawned with
+//! [`spawn_vmspace`](super::vmspace::spawn_vmspace), and anything they spawn
+//! themselves joins the same group, as do their threads. The whole group can
+//! be torn down with [`kill_task_group`], and once its last member exits the
+//! task which created it receives a
+//! [`KernelNotification::TaskGroupExited`](crate::message::KernelNotification::TaskGroupExited).
+//!
+//! Each group also has its own capability space, which memory allocated with
+//! [`alloc_group_memory`] is placed in. Its capability pointers can be passed
+//! around freely between members, which can map the memory with
+//! [`claim_group_capability`].
+
+use super::{
+    allocation::{AllocationOptions, MemoryPermissions},
+    syscall, Syscall,
+};
+use crate::{
+    capabilities::CapabilityPtr,
+    error::KError,
+    message::{Recipient, SyscallRequest, SyscallResult},
+};
+
+/// Create a new, empty task group, returning the capability to it
+pub fn create_task_group() -> SyscallResult<CapabilityPtr, KError> {
+    syscall(Recipient::kernel(), SyscallRequest { syscall: Syscall::CreateTaskGroup, arguments: [0; 12] })
+        .1
+        .map(CapabilityPtr::new)
+}
+
+/// Kill every task in the group, returning how many were killed. Nothing can
+/// join the group afterwards. If the current task is a member it's killed
+/// last, and the syscall doesn't return.
+pub fn kill_task_group(group: CapabilityPtr) -> SyscallResult<usize, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::KillTaskGroup, arguments: [group.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+    )
+    .1
+}
+
+/// Allocate memory which every member of the current task's group can map,
+/// returning where it was mapped and its capability pointer in the group's
+/// capability space
+pub fn alloc_group_memory(
+    size_in_bytes: usize,
+    options: AllocationOptions,
+    perms: MemoryPermissions,
+) -> SyscallResult<(*mut u8, CapabilityPtr), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::AllocVirtualMemory,
+            arguments: [
+                size_in_bytes,
+                (options | AllocationOptions::JobGroupAvailable).value(),
+                perms.value(),
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+        },
+    )
+    .1
+    .map(|(ptr, cptr): (usize, usize)| (ptr as *mut u8, CapabilityPtr::new(cptr)))
+}
+
+/// Copy the capability `group_cptr` from the current task group's capability
+/// space into the current task's, mapping it if it's memory
+pub fn claim_group_capability(group_cptr: CapabilityPtr) -> SyscallResult<CapabilityPtr, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::ClaimGroupCapability,
+            arguments: [group_cptr.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+    .map(CapabilityPtr::new)
+}
@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{syscall, Syscall};
+use crate::{
+    error::KError,
+    message::{Recipient, SyscallRequest, SyscallResult},
+    task::Tid,
+};
+use core::{num::NonZeroUsize, sync::atomic::AtomicU32};
+
+/// What the exit futex passed to [`create_thread`] is set to once the thread
+/// has exited
+pub const THREAD_EXITED: u32 = u32::MAX;
+
+/// Create a new thread sharing the current address space, which begins
+/// executing at `entry` with `arg` as its first argument. `stack` is the
+/// initial stack pointer of the new thread, and `tls` its thread pointer. If
+/// `tls` is null, the kernel creates a new TLS block for the thread from the
+/// `PT_TLS` segment of the executable. Unless `exit_futex` is null, it's set to
+/// [`THREAD_EXITED`] and woken once the thread exits, whether it exited itself
+/// or was killed, after which its stack is no longer in use.
+///
+/// # Safety
+///
+/// `entry` must be a function which never returns, and `stack` must point to
+/// the top of a region of memory which is not used by anything else for the
+/// duration of the thread. `exit_futex` must stay valid until the thread exits.
+pub unsafe fn create_thread(
+    entry: extern "C" fn(usize) -> !,
+    stack: *mut u8,
+    tls: *mut u8,
+    arg: usize,
+    exit_futex: *const AtomicU32,
+) -> SyscallResult<Tid, KError> {
+    syscall::<_, usize, KError>(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::CreateThread,
+            arguments: [entry as usize, stack as usize, tls as usize, arg, exit_futex as usize, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+    .map(|tid| Tid::new(NonZeroUsize::new(tid).unwrap()))
+}
+
+/// Block the current thread until woken by [`futex_wake`] on the same futex,
+/// unless the value of `futex` is no longer `expected`
+pub fn futex_wait(futex: &AtomicU32, expected: u32) -> SyscallResult<(), KError> {
+    syscall::<_, (), KError>(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::FutexWait,
+            arguments: [futex as *const AtomicU32 as usize, expected as usize, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Wake up to `count` threads waiting on `futex`, returning the number of
+/// threads woken
+pub fn futex_wake(futex: &AtomicU32, count: usize) -> SyscallResult<usize, KError> {
+    syscall::<_, usize, KError>(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::FutexWake,
+            arguments: [futex as *const AtomicU32 as usize, count, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{syscall, Syscall};
+use crate::{
+    capabilities::CapabilityPtr,
+    error::KError,
+    message::{Recipient, SyscallRequest, SyscallResult},
+};
+
+/// Points in the kernel which can record a [`TraceRecord`], enabled with the
+/// `trace=` bootarg
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum TraceEvent {
+    /// A hart started running a task, `args[0]` is the task it was running
+    /// before or `0` if it was idle
+    TaskSwitch = 1,
+    /// `args[0]` is the syscall number
+    SyscallEntry = 2,
+    /// `args[0]` is the syscall number, `args[1]` is `1` if the syscall
+    /// returned an error, and `2` if the task blocked
+    SyscallExit = 3,
+    /// A channel message or call was sent, `args[0]` is the receiving task and
+    /// `args[1]` the length of the message
+    IpcSend = 4,
+    /// A channel message was read, `args[0]` is the sending task and `args[1]`
+    /// the length of the message
+    IpcRecv = 5,
+    /// A task page faulted, `args[0]` is the faulting address and `args[1]`
+    /// the `scause` value
+    PageFault = 6,
+}
+
+impl TraceEvent {
+    pub const ALL: [Self; 6] =
+        [Self::TaskSwitch, Self::SyscallEntry, Self::SyscallExit, Self::IpcSend, Self::IpcRecv, Self::PageFault];
+
+    pub fn from_usize(n: usize) -> Option<Self> {
+        Self::ALL.into_iter().find(|event| *event as usize == n)
+    }
+
+    /// The event's bit in an event mask
+    pub fn bit(self) -> usize {
+        1 << self as usize
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::TaskSwitch => "switch",
+            Self::SyscallEntry => "syscall-entry",
+            Self::SyscallExit => "syscall-exit",
+            Self::IpcSend => "ipc-send",
+            Self::IpcRecv => "ipc-recv",
+            Self::PageFault => "fault",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct TraceRecord {
+    /// Value of the `time` CSR when the event happened
+    pub timestamp: u64,
+    pub event: usize,
+    pub hart: usize,
+    /// The task the event happened to
+    pub tid: usize,
+    /// Event specific, see [`TraceEvent`]
+    pub args: [usize; 2],
+}
+
+impl TraceRecord {
+    pub fn event(&self) -> Option<TraceEvent> {
+        TraceEvent::from_usize(self.event)
+    }
+}
+
+/// Move as many trace records as fit into `records`, oldest first on each
+/// hart. `cap` must be the system control capability with `READ` rights.
+/// Returns the number of records read, and the number dropped since the last
+/// read because the kernel ran out of room for them.
+pub fn read_trace(cap: CapabilityPtr, records: &mut [TraceRecord]) -> SyscallResult<(usize, usize), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::ReadTrace,
+            arguments: [cap.value(), records.as_mut_ptr() as usize, records.len(), 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
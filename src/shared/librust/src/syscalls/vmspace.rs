@@ -79,12 +79,20 @@ pub struct VmspaceSpawnEnv {
     pub a2: usize,
     pub sp: usize,
     pub tp: usize,
+    /// Size and alignment of the TLS block that `tp` points to, so that the
+    /// kernel can create TLS blocks for any threads the task spawns. The block
+    /// must be fully initialized before the task is spawned.
+    pub tls_size: usize,
+    pub tls_align: usize,
 }
 
+/// Spawn a new task from the vmspace `id`. Without `task_group` the new task
+/// joins the current task's group, if it's in one.
 pub fn spawn_vmspace(
     id: VmspaceObjectId,
     name: &str,
     env: VmspaceSpawnEnv,
+    task_group: Option<CapabilityPtr>,
 ) -> SyscallResult<(Tid, CapabilityPtr), KError> {
     crate::syscalls::syscall(
         Recipient::kernel(),
@@ -100,12 +108,28 @@ pub fn spawn_vmspace(
                 env.a2,
                 env.sp,
                 env.tp,
-                0,
-                0,
-                0,
+                env.tls_size,
+                env.tls_align,
+                task_group.map(CapabilityPtr::value).unwrap_or(usize::MAX),
             ],
         },
     )
     .1
     .map(|(n, cptr)| (Tid::new(NonZeroUsize::new(n).unwrap()), CapabilityPtr::new(cptr)))
 }
+
+/// Move all of the capabilities held by the dead task on the other end of
+/// `from` to the task on the other end of `to`, returning the number of
+/// capabilities that were moved. Channels keep their connections to any other
+/// tasks, so those tasks don't need to reconnect. The caller must hold channel
+/// capabilities with `GRANT` rights to both tasks.
+pub fn handoff_capabilities(from: CapabilityPtr, to: CapabilityPtr) -> SyscallResult<usize, KError> {
+    crate::syscalls::syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::HandoffCapabilities,
+            arguments: [from.value(), to.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
@@ -23,3 +23,146 @@ impl Tid {
         todo!("get tid")
     }
 }
+
+/// Priority given to tasks which haven't set their own, higher priorities run
+/// first when the kernel is using the priority scheduler and are otherwise
+/// ignored
+pub const DEFAULT_PRIORITY: u8 = 128;
+
+/// Range of niceness values a task can set, lower values make a task more
+/// likely to be boosted ahead of others of the same priority when it's woken.
+/// Only used by the priority scheduler.
+pub const MIN_NICE: i8 = -20;
+pub const MAX_NICE: i8 = 19;
+
+/// Longest task or thread name, in bytes, that can be set
+pub const MAX_NAME_LEN: usize = 64;
+
+/// How a task stopped running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The task exited on its own with the given exit code
+    Exited(usize),
+    /// The task was killed, e.g. from the kernel console
+    Killed,
+    /// The task was killed by the kernel after an unrecoverable fault, such as
+    /// an invalid memory access. The crash dump the kernel recorded for it can
+    /// be read with [`crate::syscalls::system::read_crash_dump`] until newer
+    /// crashes push it out.
+    Faulted { crash_id: usize },
+}
+
+const EXIT_STATUS_EXITED: usize = 0;
+const EXIT_STATUS_KILLED: usize = 1;
+const EXIT_STATUS_FAULTED: usize = 2;
+
+impl ExitStatus {
+    pub fn success(self) -> bool {
+        matches!(self, ExitStatus::Exited(0))
+    }
+
+    pub fn to_raw(self) -> (usize, usize) {
+        match self {
+            ExitStatus::Exited(code) => (EXIT_STATUS_EXITED, code),
+            ExitStatus::Killed => (EXIT_STATUS_KILLED, 0),
+            ExitStatus::Faulted { crash_id } => (EXIT_STATUS_FAULTED, crash_id),
+        }
+    }
+
+    pub fn from_raw(kind: usize, code: usize) -> Option<Self> {
+        match kind {
+            EXIT_STATUS_EXITED => Some(ExitStatus::Exited(code)),
+            EXIT_STATUS_KILLED => Some(ExitStatus::Killed),
+            EXIT_STATUS_FAULTED => Some(ExitStatus::Faulted { crash_id: code }),
+            _ => None,
+        }
+    }
+}
+
+/// Asynchronous events which can be sent to a task, see
+/// [`crate::syscalls::task::send_task_event`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskEvent {
+    /// Always terminates the task, it can't be handled
+    Kill,
+    /// Asks the task to stop what it's doing, e.g. Ctrl-C was pressed on the
+    /// console
+    Interrupt,
+    /// Event with a meaning agreed on by the sender and receiver, from `0` to
+    /// [`TaskEvent::MAX_USER`]
+    User(u8),
+}
+
+impl TaskEvent {
+    pub const MAX_USER: u8 = 61;
+
+    pub fn to_raw(self) -> usize {
+        match self {
+            TaskEvent::Kill => 0,
+            TaskEvent::Interrupt => 1,
+            TaskEvent::User(n) => 2 + n as usize,
+        }
+    }
+
+    pub fn from_raw(raw: usize) -> Option<Self> {
+        match raw {
+            0 => Some(TaskEvent::Kill),
+            1 => Some(TaskEvent::Interrupt),
+            n if n - 2 <= Self::MAX_USER as usize => Some(TaskEvent::User((n - 2) as u8)),
+            _ => None,
+        }
+    }
+}
+
+/// The registers a task was stopped with, as saved by the kernel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct TaskContext {
+    /// Where the task resumes
+    pub pc: usize,
+    /// `x1` through `x31`
+    pub registers: [usize; 31],
+    /// `f0` through `f31`
+    pub fp_registers: [usize; 32],
+    pub fcsr: usize,
+}
+
+/// Set of [`TaskEvent`]s
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TaskEventMask(u64);
+
+impl TaskEventMask {
+    pub const NONE: Self = Self(0);
+
+    pub fn new(value: u64) -> Self {
+        Self(value)
+    }
+
+    pub fn value(self) -> u64 {
+        self.0
+    }
+
+    pub fn with(self, event: TaskEvent) -> Self {
+        Self(self.0 | 1 << event.to_raw())
+    }
+
+    pub fn without(self, event: TaskEvent) -> Self {
+        Self(self.0 & !(1 << event.to_raw()))
+    }
+
+    pub fn contains(self, event: TaskEvent) -> bool {
+        self.0 & 1 << event.to_raw() != 0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// The event with the lowest number in the set
+    pub fn first(self) -> Option<TaskEvent> {
+        match self.0 {
+            0 => None,
+            n => TaskEvent::from_raw(n.trailing_zeros() as usize),
+        }
+    }
+}
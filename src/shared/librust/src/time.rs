@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reading the time without a syscall. The kernel maps a read-only
+//! [`TimePage`] into every task at [`TIME_PAGE_ADDRESS`] and lets tasks read
+//! the `time` CSR directly, so converting it to nanoseconds is a few loads and
+//! some arithmetic.
+
+use core::sync::atomic::{fence, AtomicU64, AtomicUsize, Ordering};
+
+/// Where the [`TimePage`] is mapped in every task, the last page of the
+/// smallest address space the kernel supports
+pub const TIME_PAGE_ADDRESS: usize = (1 << 38) - 4096;
+
+/// Kernel maintained timekeeping state. The kernel makes `sequence` odd while
+/// it updates the timebase fields, so [`TimePage::timebase`] retries if it
+/// changes while they're being read.
+#[repr(C)]
+pub struct TimePage {
+    pub sequence: AtomicUsize,
+    /// Rate the `time` CSR increases at in Hz
+    pub timebase_frequency: AtomicU64,
+    /// Value of the `time` CSR when the kernel booted
+    pub boot_ticks: AtomicU64,
+    /// Nanoseconds since the Unix epoch when the kernel booted, or zero if
+    /// there's no real time clock
+    pub realtime_offset_ns: AtomicU64,
+    /// Number of timer interrupts taken across all harts, which changes
+    /// whenever any task may have been preempted
+    pub scheduler_epoch: AtomicU64,
+    pub n_harts: AtomicUsize,
+    /// Random value picked at boot for stack smashing protection canaries,
+    /// see [`stack_guard`]
+    pub stack_guard: AtomicU64,
+}
+
+/// A consistent snapshot of the fields needed to convert the `time` CSR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timebase {
+    pub frequency: u64,
+    pub boot_ticks: u64,
+    pub realtime_offset_ns: u64,
+}
+
+impl TimePage {
+    pub fn timebase(&self) -> Timebase {
+        loop {
+            let start = self.sequence.load(Ordering::Acquire);
+            let timebase = Timebase {
+                frequency: self.timebase_frequency.load(Ordering::Relaxed),
+                boot_ticks: self.boot_ticks.load(Ordering::Relaxed),
+                realtime_offset_ns: self.realtime_offset_ns.load(Ordering::Relaxed),
+            };
+
+            fence(Ordering::Acquire);
+            if start % 2 == 0 && self.sequence.load(Ordering::Relaxed) == start {
+                return timebase;
+            }
+
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// The page the kernel mapped into this task
+pub fn time_page() -> &'static TimePage {
+    unsafe { &*(TIME_PAGE_ADDRESS as *const TimePage) }
+}
+
+/// Current value of the `time` CSR
+#[inline]
+pub fn ticks() -> u64 {
+    let value: u64;
+    unsafe { core::arch::asm!("csrr {}, time", out(reg) value) };
+    value
+}
+
+pub fn ticks_to_ns(ticks: u64, frequency: u64) -> u64 {
+    match frequency {
+        0 => 0,
+        _ => (ticks as u128 * 1_000_000_000 / frequency as u128) as u64,
+    }
+}
+
+/// Nanoseconds since the kernel booted
+pub fn monotonic_ns() -> u64 {
+    let timebase = time_page().timebase();
+    ticks_to_ns(ticks().saturating_sub(timebase.boot_ticks), timebase.frequency)
+}
+
+/// Nanoseconds since the Unix epoch, if there's a real time clock to know it
+/// from
+pub fn realtime_ns() -> Option<u64> {
+    let timebase = time_page().timebase();
+    match timebase.realtime_offset_ns {
+        0 => None,
+        offset => Some(offset + ticks_to_ns(ticks().saturating_sub(timebase.boot_ticks), timebase.frequency)),
+    }
+}
+
+/// See [`TimePage::scheduler_epoch`]
+pub fn scheduler_epoch() -> u64 {
+    time_page().scheduler_epoch.load(Ordering::Relaxed)
+}
+
+pub fn n_harts() -> usize {
+    time_page().n_harts.load(Ordering::Relaxed)
+}
+
+/// The canary to place between a function's stack buffers and its return
+/// address. Its lowest byte is always zero so string functions overflowing a
+/// buffer can't copy the canary back out. It's the same for every task until
+/// the next boot.
+pub fn stack_guard() -> u64 {
+    time_page().stack_guard.load(Ordering::Relaxed)
+}
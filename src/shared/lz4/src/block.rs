@@ -0,0 +1,164 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The LZ4 block format: a series of sequences, each a run of literal bytes
+//! followed by a match copying earlier output, with the last sequence being
+//! only literals.
+
+use crate::DecompressError;
+use alloc::{vec, vec::Vec};
+
+const MIN_MATCH: usize = 4;
+/// The last match has to start at least this many bytes before the end of the
+/// block
+const MF_LIMIT: usize = 12;
+/// The last this many bytes of a block are always literals
+const LAST_LITERALS: usize = 5;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 12;
+
+/// Decompress `src` into `out` starting at `pos`, returning the position after
+/// the last byte written. Matches can reach back before `pos`, to support
+/// blocks which depend on the ones before them.
+pub fn decompress(src: &[u8], out: &mut [u8], mut pos: usize) -> Result<usize, DecompressError> {
+    let mut i = 0;
+
+    loop {
+        let token = *src.get(i).ok_or(DecompressError::Truncated)?;
+        i += 1;
+
+        let mut literals = usize::from(token >> 4);
+        if literals == 15 {
+            literals += read_length(src, &mut i)?;
+        }
+
+        let end = i.checked_add(literals).ok_or(DecompressError::Truncated)?;
+        let literals = src.get(i..end).ok_or(DecompressError::Truncated)?;
+        let out_end = pos.checked_add(literals.len()).ok_or(DecompressError::OutputTooSmall)?;
+        out.get_mut(pos..out_end).ok_or(DecompressError::OutputTooSmall)?.copy_from_slice(literals);
+        i = end;
+        pos = out_end;
+
+        if i == src.len() {
+            return Ok(pos);
+        }
+
+        let offset = match src.get(i..i + 2) {
+            Some(&[low, high]) => usize::from(u16::from_le_bytes([low, high])),
+            _ => return Err(DecompressError::Truncated),
+        };
+        i += 2;
+
+        if offset == 0 || offset > pos {
+            return Err(DecompressError::InvalidOffset);
+        }
+
+        let mut length = usize::from(token & 0xF) + MIN_MATCH;
+        if token & 0xF == 0xF {
+            length += read_length(src, &mut i)?;
+        }
+
+        let out_end = pos.checked_add(length).ok_or(DecompressError::OutputTooSmall)?;
+        if out_end > out.len() {
+            return Err(DecompressError::OutputTooSmall);
+        }
+
+        // Matches can overlap the bytes they produce, e.g. an offset of one
+        // repeats the last byte, so this has to go byte by byte
+        for n in pos..out_end {
+            out[n] = out[n - offset];
+        }
+
+        pos = out_end;
+    }
+}
+
+/// Compress `src` as a single independent block, appending it to `dst`
+pub fn compress(src: &[u8], dst: &mut Vec<u8>) {
+    // Positions plus one of the last 4 bytes seen with each hash, zero meaning
+    // none yet
+    let mut table = vec![0u32; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut i = 0;
+
+    if src.len() > MF_LIMIT {
+        while i < src.len() - MF_LIMIT {
+            let sequence = read_u32(src, i);
+            let hash = hash(sequence);
+            let candidate = table[hash] as usize;
+            table[hash] = i as u32 + 1;
+
+            if let Some(candidate) = candidate.checked_sub(1) {
+                if i - candidate <= MAX_OFFSET && read_u32(src, candidate) == sequence {
+                    let max = src.len() - LAST_LITERALS - i;
+                    let mut length = MIN_MATCH;
+                    while length < max && src[candidate + length] == src[i + length] {
+                        length += 1;
+                    }
+
+                    write_sequence(dst, &src[anchor..i], Some((i - candidate, length)));
+                    i += length;
+                    anchor = i;
+                    continue;
+                }
+            }
+
+            i += 1;
+        }
+    }
+
+    write_sequence(dst, &src[anchor..], None);
+}
+
+fn write_sequence(dst: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_length = matched.map(|(_, length)| length - MIN_MATCH).unwrap_or(0);
+    dst.push((literals.len().min(15) as u8) << 4 | match_length.min(15) as u8);
+
+    if literals.len() >= 15 {
+        write_length(dst, literals.len() - 15);
+    }
+    dst.extend_from_slice(literals);
+
+    if let Some((offset, _)) = matched {
+        dst.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_length >= 15 {
+            write_length(dst, match_length - 15);
+        }
+    }
+}
+
+/// Lengths too long for the token continue in bytes which are added to it,
+/// with a byte of 255 meaning there's another
+fn read_length(src: &[u8], i: &mut usize) -> Result<usize, DecompressError> {
+    let mut length = 0usize;
+    loop {
+        let byte = *src.get(*i).ok_or(DecompressError::Truncated)?;
+        *i += 1;
+        length = length.checked_add(usize::from(byte)).ok_or(DecompressError::Truncated)?;
+
+        if byte != 255 {
+            return Ok(length);
+        }
+    }
+}
+
+fn write_length(dst: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        dst.push(255);
+        length -= 255;
+    }
+
+    dst.push(length as u8);
+}
+
+fn read_u32(src: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([src[at], src[at + 1], src[at + 2], src[at + 3]])
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
@@ -0,0 +1,315 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! LZ4 compression using the standard frame format, so anything compressed
+//! with the `lz4` command line tool can be decompressed here and vice versa.
+//! Decompression is fast and needs no memory beyond the output, which makes it
+//! a good fit for unpacking the initfs at boot.
+//!
+//! Dictionaries aren't supported, and block checksums are skipped over without
+//! being checked, though the header and content checksums are.
+
+#![no_std]
+
+extern crate alloc;
+
+mod block;
+mod xxhash;
+
+use alloc::{vec, vec::Vec};
+
+pub use xxhash::xxh32;
+
+/// The first four bytes of every frame, little endian
+pub const MAGIC: u32 = 0x184D2204;
+
+const VERSION: u8 = 0b01 << 6;
+const FLAG_BLOCK_INDEPENDENCE: u8 = 1 << 5;
+const FLAG_BLOCK_CHECKSUM: u8 = 1 << 4;
+const FLAG_CONTENT_SIZE: u8 = 1 << 3;
+const FLAG_CONTENT_CHECKSUM: u8 = 1 << 2;
+const FLAG_DICTIONARY_ID: u8 = 1 << 0;
+/// Set in a block's size when its data is stored as is
+const UNCOMPRESSED: u32 = 1 << 31;
+
+/// The size blocks are split into when compressing
+const BLOCK_SIZE_ID: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecompressError {
+    NotAFrame,
+    /// Uses a feature this implementation doesn't support, e.g. dictionaries
+    Unsupported,
+    HeaderChecksum,
+    ContentChecksum,
+    /// Ended partway through, or a length ran off the end of the data
+    Truncated,
+    /// A match refers to data before the start of the output
+    InvalidOffset,
+    /// A block decompressed to more than its maximum size, or the frame to
+    /// more than its content size
+    OutputTooSmall,
+}
+
+/// Whether `data` starts with a frame
+pub fn is_frame(data: &[u8]) -> bool {
+    data.get(..4).map(|magic| magic == MAGIC.to_le_bytes()).unwrap_or(false)
+}
+
+/// A frame whose header has been checked, ready to decompress
+#[derive(Debug, Clone, Copy)]
+pub struct Frame<'a> {
+    /// Size of the decompressed data, if the frame records it
+    pub content_size: Option<usize>,
+    flags: u8,
+    block_max: usize,
+    blocks: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, DecompressError> {
+        if !is_frame(data) {
+            return Err(DecompressError::NotAFrame);
+        }
+
+        let (flags, block_descriptor) = match data.get(4..6) {
+            Some(&[flags, block_descriptor]) => (flags, block_descriptor),
+            _ => return Err(DecompressError::Truncated),
+        };
+
+        if flags & 0b1100_0000 != VERSION || flags & FLAG_DICTIONARY_ID != 0 {
+            return Err(DecompressError::Unsupported);
+        }
+
+        let block_max = match (block_descriptor >> 4) & 0b111 {
+            id @ 4..=7 => 1 << (8 + 2 * id),
+            _ => return Err(DecompressError::Unsupported),
+        };
+
+        let descriptor_end = match flags & FLAG_CONTENT_SIZE {
+            0 => 6,
+            _ => 14,
+        };
+
+        let descriptor = data.get(4..descriptor_end).ok_or(DecompressError::Truncated)?;
+        let checksum = *data.get(descriptor_end).ok_or(DecompressError::Truncated)?;
+        if (xxh32(descriptor, 0) >> 8) as u8 != checksum {
+            return Err(DecompressError::HeaderChecksum);
+        }
+
+        let content_size = match flags & FLAG_CONTENT_SIZE {
+            0 => None,
+            _ => Some(read_u64(&descriptor[2..]) as usize),
+        };
+
+        Ok(Self { content_size, flags, block_max, blocks: &data[descriptor_end + 1..] })
+    }
+
+    /// The most the frame can decompress to, which is exact if it records its
+    /// content size
+    pub fn max_decompressed_len(&self) -> Result<usize, DecompressError> {
+        if let Some(size) = self.content_size {
+            return Ok(size);
+        }
+
+        let mut len = 0usize;
+        self.blocks(|_, _| {
+            len = len.saturating_add(self.block_max);
+            Ok(())
+        })?;
+
+        Ok(len)
+    }
+
+    /// Decompress into `out`, returning how many bytes were written
+    pub fn decompress_into(&self, out: &mut [u8]) -> Result<usize, DecompressError> {
+        let mut pos = 0;
+        let trailer = self.blocks(|data, uncompressed| {
+            let block_end = out.len().min(pos + self.block_max);
+            pos = match uncompressed {
+                true => {
+                    let end = pos + data.len();
+                    out.get_mut(pos..end)
+                        .filter(|_| end <= block_end)
+                        .ok_or(DecompressError::OutputTooSmall)?
+                        .copy_from_slice(data);
+                    end
+                }
+                false => block::decompress(data, &mut out[..block_end], pos)?,
+            };
+
+            Ok(())
+        })?;
+
+        if matches!(self.content_size, Some(size) if size != pos) {
+            return Err(DecompressError::Truncated);
+        }
+
+        if self.flags & FLAG_CONTENT_CHECKSUM != 0 {
+            let checksum = trailer.get(..4).ok_or(DecompressError::Truncated)?;
+            if read_u32(checksum) != xxh32(&out[..pos], 0) {
+                return Err(DecompressError::ContentChecksum);
+            }
+        }
+
+        Ok(pos)
+    }
+
+    /// Call `f` with the data of each block and whether it's stored
+    /// uncompressed, returning what follows the end mark
+    fn blocks(
+        &self,
+        mut f: impl FnMut(&'a [u8], bool) -> Result<(), DecompressError>,
+    ) -> Result<&'a [u8], DecompressError> {
+        let checksum_len = match self.flags & FLAG_BLOCK_CHECKSUM {
+            0 => 0,
+            _ => 4,
+        };
+
+        let mut rest = self.blocks;
+        loop {
+            let size = read_u32(rest.get(..4).ok_or(DecompressError::Truncated)?);
+            rest = &rest[4..];
+
+            if size == 0 {
+                return Ok(rest);
+            }
+
+            let len = (size & !UNCOMPRESSED) as usize;
+            let data = rest.get(..len).ok_or(DecompressError::Truncated)?;
+            rest = rest.get(len + checksum_len..).ok_or(DecompressError::Truncated)?;
+
+            f(data, size & UNCOMPRESSED != 0)?;
+        }
+    }
+}
+
+/// Decompress a whole frame
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, DecompressError> {
+    let frame = Frame::parse(data)?;
+    let mut out = vec![0; frame.max_decompressed_len()?];
+    let len = frame.decompress_into(&mut out)?;
+    out.truncate(len);
+
+    Ok(out)
+}
+
+/// Compress `data` into a frame with independent blocks, recording its size
+/// and checksum
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let flags = VERSION | FLAG_BLOCK_INDEPENDENCE | FLAG_CONTENT_SIZE | FLAG_CONTENT_CHECKSUM;
+
+    let mut out = Vec::with_capacity(data.len() / 2);
+    out.extend_from_slice(&MAGIC.to_le_bytes());
+    out.extend_from_slice(&[flags, BLOCK_SIZE_ID << 4]);
+    out.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    out.push((xxh32(&out[4..], 0) >> 8) as u8);
+
+    let mut compressed = Vec::new();
+    for chunk in data.chunks(1 << (8 + 2 * BLOCK_SIZE_ID)) {
+        compressed.clear();
+        block::compress(chunk, &mut compressed);
+
+        match compressed.len() < chunk.len() {
+            true => {
+                out.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+                out.extend_from_slice(&compressed);
+            }
+            false => {
+                out.extend_from_slice(&(chunk.len() as u32 | UNCOMPRESSED).to_le_bytes());
+                out.extend_from_slice(chunk);
+            }
+        }
+    }
+
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&xxh32(data, 0).to_le_bytes());
+
+    out
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Vec<u8> {
+        let mut data = Vec::new();
+        for n in 0..20_000u32 {
+            data.extend_from_slice(b"vanadinite ");
+            data.extend_from_slice(&(n % 97).to_le_bytes());
+        }
+
+        data
+    }
+
+    #[test]
+    fn xxh32_known_values() {
+        assert_eq!(xxh32(b"", 0), 0x02CC5D05);
+        assert_eq!(xxh32(b"abc", 0), 0x32D153FF);
+        assert_eq!(xxh32(b"Nobody inspects the spammish repetition", 0), 0xE2293B2F);
+    }
+
+    #[test]
+    fn round_trips() {
+        for data in [Vec::new(), b"short".to_vec(), (0..=255).collect(), sample()] {
+            let compressed = compress(&data);
+            assert!(is_frame(&compressed));
+            assert_eq!(decompress(&compressed).unwrap(), data);
+        }
+
+        assert!(compress(&sample()).len() < sample().len() / 4);
+    }
+
+    #[test]
+    fn decompresses_reference_frame() {
+        // One literal run, one match overlapping its own output, and the
+        // trailing literals
+        let data = b"hello hello hello hello hello\n".to_vec();
+        let mut block = vec![0x6F];
+        block.extend_from_slice(b"hello ");
+        block.extend_from_slice(&[6, 0, 0, 0x50]);
+        block.extend_from_slice(b"ello\n");
+
+        let mut frame = MAGIC.to_le_bytes().to_vec();
+        frame.extend_from_slice(&[VERSION | FLAG_BLOCK_INDEPENDENCE | FLAG_CONTENT_SIZE, 4 << 4]);
+        frame.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        frame.push((xxh32(&frame[4..], 0) >> 8) as u8);
+        frame.extend_from_slice(&(block.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&block);
+        frame.extend_from_slice(&[0; 4]);
+
+        assert_eq!(decompress(&frame).unwrap(), data);
+    }
+
+    #[test]
+    fn rejects_corruption() {
+        let data = sample();
+        let compressed = compress(&data);
+
+        assert_eq!(decompress(&compressed[1..]), Err(DecompressError::NotAFrame));
+
+        let mut bad_header = compressed.clone();
+        bad_header[6] ^= 1;
+        assert_eq!(decompress(&bad_header), Err(DecompressError::HeaderChecksum));
+
+        let truncated = &compressed[..compressed.len() - 8];
+        assert_eq!(decompress(truncated), Err(DecompressError::Truncated));
+
+        let mut bad_content = compressed.clone();
+        let last = bad_content.len() - 1;
+        bad_content[last] ^= 1;
+        assert_eq!(decompress(&bad_content), Err(DecompressError::ContentChecksum));
+    }
+}
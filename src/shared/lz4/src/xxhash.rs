@@ -0,0 +1,66 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! 32-bit xxHash, which frames use for their header and content checksums
+
+const PRIME1: u32 = 2654435761;
+const PRIME2: u32 = 2246822519;
+const PRIME3: u32 = 3266489917;
+const PRIME4: u32 = 668265263;
+const PRIME5: u32 = 374761393;
+
+pub fn xxh32(data: &[u8], seed: u32) -> u32 {
+    let mut stripes = data.chunks_exact(16);
+    let mut hash = match data.len() >= 16 {
+        true => {
+            let mut lanes = [
+                seed.wrapping_add(PRIME1).wrapping_add(PRIME2),
+                seed.wrapping_add(PRIME2),
+                seed,
+                seed.wrapping_sub(PRIME1),
+            ];
+
+            for stripe in &mut stripes {
+                for (lane, word) in lanes.iter_mut().zip(stripe.chunks_exact(4)) {
+                    *lane = round(*lane, read_u32(word));
+                }
+            }
+
+            lanes[0]
+                .rotate_left(1)
+                .wrapping_add(lanes[1].rotate_left(7))
+                .wrapping_add(lanes[2].rotate_left(12))
+                .wrapping_add(lanes[3].rotate_left(18))
+        }
+        false => seed.wrapping_add(PRIME5),
+    };
+
+    hash = hash.wrapping_add(data.len() as u32);
+
+    let mut words = stripes.remainder().chunks_exact(4);
+    for word in &mut words {
+        hash = hash.wrapping_add(read_u32(word).wrapping_mul(PRIME3)).rotate_left(17).wrapping_mul(PRIME4);
+    }
+
+    for &byte in words.remainder() {
+        hash = hash.wrapping_add(u32::from(byte).wrapping_mul(PRIME5)).rotate_left(11).wrapping_mul(PRIME1);
+    }
+
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(PRIME2);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(PRIME3);
+    hash ^ (hash >> 16)
+}
+
+fn round(lane: u32, input: u32) -> u32 {
+    lane.wrapping_add(input.wrapping_mul(PRIME2)).rotate_left(13).wrapping_mul(PRIME1)
+}
+
+fn read_u32(word: &[u8]) -> u32 {
+    u32::from_le_bytes([word[0], word[1], word[2], word[3]])
+}
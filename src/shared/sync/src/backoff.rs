@@ -0,0 +1,71 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// Largest number of hints, as a power of two, spun for between attempts
+const MAX_STEP: u32 = 6;
+
+static SPIN_HOOK: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Run `hook` every time a [`Backoff`] spins, which the kernel uses to service
+/// requests from other harts while waiting with interrupts disabled, since the
+/// hart it's waiting on may in turn be waiting for them to be serviced. The
+/// hook mustn't wait on anything itself.
+pub fn set_spin_hook(hook: fn()) {
+    SPIN_HOOK.store(hook as *mut (), Ordering::Release);
+}
+
+/// Hint to the hart that it's in a spin loop, so it can spend less power or
+/// give its resources to another hart sharing the core. This is the
+/// `Zihintpause` `pause` instruction, which is encoded as a `fence` that
+/// orders nothing, so harts without the extension run it as a no-op.
+#[inline(always)]
+pub fn pause() {
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        core::arch::asm!(".word 0x0100000F", options(nomem, nostack))
+    };
+
+    #[cfg(not(target_arch = "riscv64"))]
+    core::hint::spin_loop();
+}
+
+/// Exponential backoff for spin loops, which doubles the number of
+/// [`pause`] hints between each attempt up to a limit, so that contending
+/// harts stop hammering the cache line they're waiting on
+#[derive(Debug, Default)]
+pub struct Backoff {
+    step: u32,
+}
+
+impl Backoff {
+    pub const fn new() -> Self {
+        Self { step: 0 }
+    }
+
+    pub fn spin(&mut self) {
+        let hook = SPIN_HOOK.load(Ordering::Acquire);
+        if !hook.is_null() {
+            unsafe { core::mem::transmute::<*mut (), fn()>(hook)() };
+        }
+
+        for _ in 0..1 << self.step {
+            pause();
+        }
+
+        if self.step < MAX_STEP {
+            self.step += 1;
+        }
+    }
+
+    /// Whether backoff has reached its limit, meaning whatever is being waited
+    /// on has taken a while
+    pub fn is_saturated(&self) -> bool {
+        self.step == MAX_STEP
+    }
+}
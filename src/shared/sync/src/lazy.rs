@@ -5,6 +5,7 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::Backoff;
 use core::{
     cell::UnsafeCell,
     mem::MaybeUninit,
@@ -45,9 +46,9 @@ impl<T, F: Fn() -> T> Lazy<T, F> {
     }
 
     fn wait_for_init(&self) {
+        let mut backoff = Backoff::new();
         while self.init_state.load(Ordering::Acquire) != 0b10 {
-            // TODO: maybe add ability to specify instruction for stalling?
-            // crate::asm::pause();
+            backoff.spin();
         }
     }
 
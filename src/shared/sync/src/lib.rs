@@ -7,17 +7,19 @@
 
 #![no_std]
 
+mod backoff;
 mod lazy;
 mod mutex;
 mod rwlock;
 
+pub use backoff::{pause, set_spin_hook, Backoff};
 use core::{
     marker::PhantomData,
     sync::atomic::{AtomicPtr, Ordering},
 };
 pub use lazy::Lazy;
-pub use mutex::SpinMutex;
-pub use rwlock::SpinRwLock;
+pub use mutex::{SpinMutex, SpinMutexGuard};
+pub use rwlock::{ReadGuard, SpinRwLock, WriteGuard};
 
 #[repr(transparent)]
 pub struct AtomicConstPtr<T>(AtomicPtr<T>, PhantomData<T>);
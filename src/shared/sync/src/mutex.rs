@@ -5,18 +5,27 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::{DeadlockDetection, NoCheck};
+use crate::{Backoff, DeadlockDetection, NoCheck};
 use core::{
     cell::UnsafeCell,
     marker::PhantomData,
+    panic::Location,
     sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
+/// Stored as the owner while the lock isn't held, so a contending hart can't
+/// mistake the last holder for the current one
+const NO_OWNER: usize = usize::MAX;
+
 pub struct SpinMutex<T: Send, D: DeadlockDetection = NoCheck> {
     lock: AtomicBool,
     data: UnsafeCell<T>,
     deadlock_detection: PhantomData<D>,
-    deadlock_metadata: AtomicUsize,
+    /// Gathered by `D` from whichever hart holds the lock, e.g. its hart ID
+    owner: AtomicUsize,
+    /// Where the lock was taken, for reporting deadlocks
+    #[cfg(debug_assertions)]
+    held_at: core::sync::atomic::AtomicPtr<Location<'static>>,
 }
 
 impl<T: Send, D: DeadlockDetection> SpinMutex<T, D> {
@@ -25,10 +34,13 @@ impl<T: Send, D: DeadlockDetection> SpinMutex<T, D> {
             lock: AtomicBool::new(false),
             data: UnsafeCell::new(data),
             deadlock_detection: PhantomData,
-            deadlock_metadata: AtomicUsize::new(0),
+            owner: AtomicUsize::new(NO_OWNER),
+            #[cfg(debug_assertions)]
+            held_at: core::sync::atomic::AtomicPtr::new(core::ptr::null_mut()),
         }
     }
 
+    #[track_caller]
     pub fn with_lock<U>(&self, f: impl FnOnce(&mut T) -> U) -> U {
         self.acquire_lock();
         let ret = f(unsafe { &mut *self.data.get() });
@@ -43,10 +55,11 @@ impl<T: Send, D: DeadlockDetection> SpinMutex<T, D> {
         SpinMutexGuard { lock: self }
     }
 
+    #[track_caller]
     pub fn try_lock(&self) -> Option<SpinMutexGuard<'_, T, D>> {
         match self.lock.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed) {
             Ok(_) => {
-                self.deadlock_metadata.store(D::gather_metadata(), Ordering::Release);
+                self.set_owner(Location::caller());
                 Some(SpinMutexGuard { lock: self })
             }
             Err(_) => None,
@@ -55,20 +68,43 @@ impl<T: Send, D: DeadlockDetection> SpinMutex<T, D> {
 
     #[track_caller]
     fn acquire_lock(&self) {
-        let mut spin_check_count = 100;
+        let mut backoff = Backoff::new();
 
         while self.lock.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
-            if spin_check_count != 0 && D::would_deadlock(self.deadlock_metadata.load(Ordering::Acquire)) {
-                panic!("Deadlock detected");
+            // Wait for the lock to look free before trying to take it again,
+            // so contending harts only read the cache line. Most holds are
+            // short, so the owner is only checked once one has taken a while.
+            while self.lock.load(Ordering::Relaxed) {
+                if backoff.is_saturated() && D::would_deadlock(self.owner.load(Ordering::Acquire)) {
+                    self.deadlock(Location::caller());
+                }
+
+                backoff.spin();
             }
+        }
 
-            spin_check_count -= 1;
+        self.set_owner(Location::caller());
+    }
+
+    fn set_owner(&self, _location: &'static Location<'static>) {
+        self.owner.store(D::gather_metadata(), Ordering::Release);
+        #[cfg(debug_assertions)]
+        self.held_at.store(_location as *const _ as *mut _, Ordering::Release);
+    }
+
+    #[cold]
+    #[track_caller]
+    fn deadlock(&self, acquiring_at: &'static Location<'static>) -> ! {
+        #[cfg(debug_assertions)]
+        if let Some(held_at) = unsafe { self.held_at.load(Ordering::Acquire).as_ref() } {
+            panic!("Deadlock detected: lock taken at {} is being acquired again at {}", held_at, acquiring_at);
         }
 
-        self.deadlock_metadata.store(D::gather_metadata(), Ordering::Release);
+        panic!("Deadlock detected: lock is being acquired again at {}", acquiring_at);
     }
 
     fn unlock(&self) {
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
         self.lock.store(false, Ordering::Release);
     }
 }
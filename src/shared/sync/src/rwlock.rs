@@ -5,6 +5,7 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::Backoff;
 use core::{
     cell::UnsafeCell,
     sync::atomic::{AtomicUsize, Ordering},
@@ -31,10 +32,17 @@ impl<T: Send> SpinRwLock<T> {
         WriteGuard { lock: self }
     }
 
+    pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+        match self.try_lock_shared() {
+            true => Some(ReadGuard { lock: self }),
+            false => None,
+        }
+    }
+
     fn lock_shared(&self) {
+        let mut backoff = Backoff::new();
         while !self.try_lock_shared() {
-            // TODO: maybe add ability to specify instruction for stalling?
-            // crate::asm::pause();
+            backoff.spin();
         }
     }
 
@@ -65,9 +73,9 @@ impl<T: Send> SpinRwLock<T> {
     }
 
     fn lock_exclusive(&self) {
+        let mut backoff = Backoff::new();
         while !self.try_lock_exclusive() {
-            // TODO: maybe add ability to specify instruction for stalling?
-            // crate::asm::pause();
+            backoff.spin();
         }
     }
 
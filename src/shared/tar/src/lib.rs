@@ -42,6 +42,31 @@ impl<'a> Archive<'a> {
 
         None
     }
+
+    /// Iterate over every entry in the archive, in the order they appear
+    pub fn files(&self) -> Files<'a> {
+        Files { data: self.data, index: 0 }
+    }
+}
+
+pub struct Files<'a> {
+    data: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Iterator for Files<'a> {
+    type Item = File<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let metadata = self.data.get(self.index..).and_then(FileHeader::from_bytes)?;
+
+        let content_start = self.index + 512;
+        let content_end = content_start + metadata.file_size;
+        let contents = self.data.get(content_start..content_end)?;
+        self.index = content_start + (metadata.file_size + 511) / 512 * 512;
+
+        Some(File { metadata, contents })
+    }
 }
 
 #[derive(Debug)]
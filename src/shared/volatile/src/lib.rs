@@ -43,6 +43,11 @@ impl<T: Copy + 'static> Volatile<T, ReadWrite> {
     pub fn write(&self, val: T) {
         unsafe { self.0.get().write_volatile(val) }
     }
+
+    /// Read the current value, then write back the value returned by `f`
+    pub fn modify(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()))
+    }
 }
 
 impl<T: Copy, const N: usize> core::ops::Index<usize> for Volatile<[T; N], Read> {
@@ -62,3 +67,125 @@ impl<T: Copy, const N: usize> core::ops::Index<usize> for Volatile<[T; N], ReadW
         unsafe { &core::mem::transmute::<_, &[Volatile<T>; N]>(self)[index] }
     }
 }
+
+/// Define a block of memory-mapped registers with each register placed at a
+/// fixed byte offset from the start of the block, instead of laying them out
+/// with padding fields. The layout is checked at compile time, so registers
+/// which are misaligned, overlap, or extend past the end of the block are a
+/// build error.
+///
+/// Each register gets an accessor method returning a reference to it, and the
+/// block is meant to be used through a reference created from the base address
+/// of the device, e.g.
+///
+/// ```rust,ignore
+/// register_block! {
+///     /// A very simple UART
+///     pub struct Uart [0x08] {
+///         0x00 => pub data: Volatile<u8>,
+///         0x05 => pub line_status: Volatile<u8, Read>,
+///     }
+/// }
+///
+/// let uart = unsafe { &*(base as *const Uart) };
+/// uart.data().write(b'!');
+/// ```
+#[macro_export]
+macro_rules! register_block {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident [$size:expr] {
+            $(
+                $(#[$field_attr:meta])*
+                $offset:literal => $field_vis:vis $field:ident : $ty:ty
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        #[repr(C)]
+        $vis struct $name {
+            _registers: $crate::Volatile<[u8; $size]>,
+        }
+
+        impl $name {
+            /// Size of the register block in bytes
+            #[allow(dead_code)]
+            pub const SIZE: usize = $size;
+
+            $(
+                $(#[$field_attr])*
+                #[inline(always)]
+                #[allow(dead_code)]
+                $field_vis fn $field(&self) -> &$ty {
+                    // SAFETY: the offset is checked to be in bounds and aligned
+                    // for the register type below
+                    unsafe { &*(self as *const Self).cast::<u8>().add($offset).cast::<$ty>() }
+                }
+            )*
+        }
+
+        impl core::fmt::Debug for $name {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                f.debug_struct(stringify!($name)).finish_non_exhaustive()
+            }
+        }
+
+        const _: () = $crate::check_register_layout(
+            &[$(($offset, core::mem::size_of::<$ty>(), core::mem::align_of::<$ty>())),*],
+            $size,
+        );
+    };
+}
+
+/// Checks the `(offset, size, align)` of each register in a block, which must
+/// be in order of increasing offset
+#[doc(hidden)]
+pub const fn check_register_layout(registers: &[(usize, usize, usize)], block_size: usize) {
+    let mut end = 0;
+    let mut i = 0;
+
+    while i < registers.len() {
+        let (offset, size, align) = registers[i];
+
+        assert!(offset % align == 0, "register offset is not aligned for its type");
+        assert!(offset >= end, "register overlaps with the previous register");
+        assert!(offset + size <= block_size, "register extends past the end of the block");
+
+        end = offset + size;
+        i += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    register_block! {
+        struct TestBlock [0x20] {
+            0x00 => first: Volatile<u8>,
+            0x04 => second: Volatile<u32, Read>,
+            0x10 => array: Volatile<[u32; 4], Write>,
+        }
+    }
+
+    #[test]
+    fn register_offsets() {
+        let memory = [0u32; 8];
+        let block = unsafe { &*memory.as_ptr().cast::<TestBlock>() };
+        let base = memory.as_ptr() as usize;
+
+        assert_eq!(block.first() as *const _ as usize - base, 0x00);
+        assert_eq!(block.second() as *const _ as usize - base, 0x04);
+        assert_eq!(block.array() as *const _ as usize - base, 0x10);
+        assert_eq!(core::mem::size_of::<TestBlock>(), TestBlock::SIZE);
+    }
+
+    #[test]
+    fn modify() {
+        let mut memory = [0x0Fu32];
+        let register = unsafe { &*memory.as_mut_ptr().cast::<Volatile<u32>>() };
+
+        register.modify(|value| value | 0xF0);
+        assert_eq!(register.read(), 0xFF);
+    }
+}
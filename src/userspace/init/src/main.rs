@@ -5,91 +5,610 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+mod manifest;
+
+use json_rpc::CallContext;
 use librust::{
     self,
-    capabilities::{CapabilityPtr, CapabilityRights},
-    syscalls::allocation::MemoryPermissions,
+    capabilities::{Capability, CapabilityPtr, CapabilityRights},
+    message::SyscallResult,
+    syscalls::{
+        allocation::{alloc_virtual_memory, AllocationOptions, MemoryPermissions},
+        io::{create_console_stream, read_console_stream, ConsoleStreamKind},
+        mem::{query_memory_capability, snapshot_memory},
+        poll::{wait_many, WaitEvents, WaitItem, NO_TIMEOUT},
+        system::{read_crash_dump, system_reset, CrashInfo, ResetKind},
+    },
+    time::monotonic_ns,
+};
+use lifecycle::protocol;
+use manifest::Service;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ipc::IpcChannel,
+    process::ExitStatus,
+    registry,
 };
 
-static SERVERS: &[u8] = include_bytes!("../../../../build/initfs.tar");
-
-static INIT_ORDER: &str = r#"{
-    "servers": [
-        {
-            "name": "devicemgr",
-            "caps": ["fdt"],
-        },
-        {
-            "name": "stdio",
-            "caps": ["devicemgr"],
-        },
-        {
-            "name": "virtiomgr",
-            "caps": ["devicemgr", "stdio"],
-        },
-        {
-            "name": "filesystem",
-            "caps": ["virtiomgr", "stdio"],
-        },
-        {
-            "name": "network",
-            "caps": ["virtiomgr", "stdio"],
-        },
-        {
-            "name": "servicemgr",
-            "caps": ["devicemgr", "stdio", "network"],
-        },
-        {
-            "name": "echonet",
-            "caps": ["stdio", "network"],
-        },
-    ]
-}"#;
-
-json::derive! {
-    Deserialize,
-    struct InitOrder {
-        servers: Vec<Server>,
+/// Most output kept for each service logged by `init`, past which the oldest
+/// is dropped
+const LOG_CAPACITY: usize = 64 * 1024;
+
+/// The initfs, compressed with LZ4 to keep the kernel image small
+static SERVERS: &[u8] = include_bytes!("../../../../build/initfs.tar.lz4");
+
+/// Output kept from a service whose stdout is bound to a pipe `init` reads
+/// from. This would be a file once there's a filesystem to put it in.
+struct ServiceLog {
+    pipe: CapabilityPtr,
+    contents: VecDeque<u8>,
+}
+
+impl ServiceLog {
+    /// Move everything written to the pipe so far into the log
+    fn drain(&mut self) {
+        let mut buffer = [0; 256];
+        while let SyscallResult::Ok(n @ 1..) = read_console_stream(self.pipe, &mut buffer) {
+            let overflow = (self.contents.len() + n).saturating_sub(LOG_CAPACITY);
+            self.contents.drain(..overflow.min(self.contents.len()));
+            self.contents.extend(&buffer[..n]);
+        }
     }
 }
 
-json::derive! {
-    Deserialize,
-    struct Server {
-        name: String,
-        caps: Vec<String>,
+#[derive(Default)]
+struct Lifecycle {
+    /// Set once a shutdown has been requested, to whether it's a reboot
+    requested: Option<bool>,
+    stopped: bool,
+    logs: BTreeMap<String, ServiceLog>,
+    /// The channel to the service being waited on for an initfs
+    awaiting_initfs: Option<CapabilityPtr>,
+    /// Decompressed initfs provided by that service
+    initfs: Option<Vec<u8>>,
+    /// Read-only snapshot of the initfs the services were loaded from, for
+    /// whoever asks for it, and its length
+    loaded_initfs: Option<(CapabilityPtr, usize)>,
+}
+
+impl protocol::Server for Lifecycle {
+    fn shutdown(&mut self, _: &mut CallContext, reboot: bool) {
+        self.requested.get_or_insert(reboot);
+    }
+
+    fn stopped(&mut self, _: &mut CallContext) {
+        self.stopped = true;
+    }
+
+    fn read_log(&mut self, _: &mut CallContext, service: String) -> Option<String> {
+        let log = self.logs.get_mut(&service)?;
+        log.drain();
+
+        let (front, back) = log.contents.as_slices();
+        Some(String::from_utf8_lossy(&[front, back].concat()).into_owned())
+    }
+
+    fn provide_initfs(&mut self, cx: &mut CallContext, len: usize) -> bool {
+        if self.awaiting_initfs != Some(cx.channel()) {
+            return false;
+        }
+
+        let image = match cx.capabilities().first().map(|cap| query_memory_capability(cap.cptr)) {
+            Some(SyscallResult::Ok((ptr, size, _))) if len <= size => unsafe { core::slice::from_raw_parts(ptr, len) },
+            _ => return false,
+        };
+
+        let image = match lz4::is_frame(image) {
+            true => match lz4::decompress(image) {
+                Ok(image) => image,
+                Err(e) => {
+                    println!("[init] Failed to decompress the provided initfs: {:?}", e);
+                    return false;
+                }
+            },
+            false => image.to_vec(),
+        };
+
+        if let Err(e) = tar::Archive::new(&image) {
+            println!("[init] Provided initfs isn't a tar archive: {:?}", e);
+            return false;
+        }
+
+        self.initfs = Some(image);
+        self.awaiting_initfs = None;
+        true
+    }
+
+    fn initfs(&mut self, cx: &mut CallContext) -> Option<usize> {
+        let (cptr, len) = self.loaded_initfs?;
+        cx.reply_with(&[Capability::new(cptr, CapabilityRights::READ | CapabilityRights::MAP)]);
+        Some(len)
     }
 }
 
-fn main() {
-    let fdt_ptr = std::env::a2() as *const u8;
-    let fdt = unsafe { fdt::Fdt::from_ptr(fdt_ptr).unwrap() };
-    let fdt_size = fdt.total_size();
-    let tar = tar::Archive::new(SERVERS).unwrap();
-
-    let mut caps = std::collections::BTreeMap::<String, CapabilityPtr>::new();
-    let init_order: InitOrder = json::deserialize(INIT_ORDER.as_bytes()).unwrap();
-
-    for server in init_order.servers {
-        let file = tar.file(&server.name).unwrap();
-        let (mut space, mut env) = loadelf::load_elf(&server.name, &loadelf::Elf::new(file.contents).unwrap()).unwrap();
-
-        for cap in server.caps {
-            if cap == "fdt" {
-                let mut fdt_obj = space.create_object(core::ptr::null(), fdt_size, MemoryPermissions::READ).unwrap();
-                fdt_obj.as_slice()[..fdt_size]
-                    .copy_from_slice(unsafe { core::slice::from_raw_parts(fdt_ptr, fdt_size) });
-                env.a2 = fdt_obj.vmspace_address() as usize;
-                continue;
+/// Delay before restarting a service which has just exited, doubled for each
+/// time in a row it's restarted
+const RESTART_BACKOFF_MIN_MS: u64 = 100;
+/// Longest a service is left stopped before it's restarted
+const RESTART_BACKOFF_MAX_MS: u64 = 30_000;
+/// How long a restarted service has to stay up before it's considered to have
+/// recovered, so that the next restart starts from the shortest delay again
+const RESTART_STABLE_MS: u64 = 60_000;
+
+/// A service which is running, along with how many times in a row it's been
+/// restarted
+struct Running {
+    service: Service,
+    started_ns: u64,
+    restarts: u32,
+}
+
+/// A service which exited and is waiting out its backoff before it's started
+/// again in place of the task on the other end of `previous`
+struct Restart {
+    service: Service,
+    previous: CapabilityPtr,
+    at_ns: u64,
+    restarts: u32,
+}
+
+/// Starts the services in the manifest, and starts them again with
+/// exponential backoff when they exit, if their restart policy says to
+struct Supervisor {
+    /// Channels to the services which have been started, by name
+    caps: BTreeMap<String, CapabilityPtr>,
+    running: BTreeMap<CapabilityPtr, Running>,
+    pending: Vec<Restart>,
+    fdt_cap: CapabilityPtr,
+    system_cap: CapabilityPtr,
+    klog_cap: CapabilityPtr,
+}
+
+impl Supervisor {
+    /// Load `service` from the ELF `binary` and spawn it with the capabilities
+    /// the manifest lists for it, replacing the dead task on the other end of
+    /// `previous` if it's being restarted. Returns the channel to it, and the
+    /// stream its stdout is bound to if it isn't the console.
+    fn spawn(
+        &self,
+        service: &Service,
+        binary: &[u8],
+        previous: Option<CapabilityPtr>,
+    ) -> Result<(CapabilityPtr, Option<(ConsoleStreamKind, CapabilityPtr)>), String> {
+        let elf = loadelf::Elf::new(binary).ok_or_else(|| format!("{} isn't an ELF", service.binary()))?;
+        let (mut space, mut env) =
+            loadelf::load_elf(&service.name, &elf).map_err(|_| format!("failed to load {}", service.binary()))?;
+
+        // Output only goes to the stream the service's stdout is bound to when
+        // it has no channel to the `stdio` server
+        let stdout = match service.stdout.as_deref() {
+            None | Some("console") => None,
+            Some("log") => Some(ConsoleStreamKind::Pipe),
+            Some("discard") => Some(ConsoleStreamKind::Null),
+            Some(other) => {
+                println!("[init] Unknown stdout `{}` for {}, using the console", other, service.name);
+                None
             }
+        };
+
+        let stream = match stdout.map(create_console_stream) {
+            Some(SyscallResult::Ok(stream)) => Some(stream),
+            Some(SyscallResult::Err(e)) => return Err(format!("failed to create its stdout: {:?}", e)),
+            None => None,
+        };
+
+        if let Some(stream) = stream {
+            space.bind_stdout(stream);
+        }
+
+        let transfer = match service.spawns_programs {
+            Some(true) => CapabilityRights::TRANSFER,
+            _ => CapabilityRights::new(0),
+        };
+
+        for cap in &service.caps {
+            let (cptr, rights) = match cap.as_str() {
+                "stdio" if stream.is_some() => continue,
+                "fdt" => (self.fdt_cap, CapabilityRights::READ | CapabilityRights::MAP),
+                "system" => (self.system_cap, CapabilityRights::READ | CapabilityRights::WRITE | transfer),
+                "klog" => (self.klog_cap, CapabilityRights::READ),
+                _ => match self.caps.get(cap) {
+                    Some(&cptr) => (cptr, CapabilityRights::READ | CapabilityRights::WRITE | transfer),
+                    None => return Err(format!("it needs {}, which isn't running", cap)),
+                },
+            };
 
-            let cptr = *caps.get(&cap).unwrap();
-            space.grant(&cap, cptr, CapabilityRights::READ | CapabilityRights::WRITE);
+            space.grant(cap, cptr, rights);
+        }
+
+        // Every service can find the others through the registry, and can
+        // attach the channels it registers
+        if let Some(&registry) = self.caps.get(registry::SERVICE_NAME) {
+            let rights = CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::GRANT | transfer;
+            space.grant(registry::SERVICE_NAME, registry, rights);
         }
 
         env.a0 = 0;
         env.a1 = 0;
-        let (_, cap) = space.spawn(env).unwrap();
-        caps.insert(server.name, cap);
+        let spawned = match previous {
+            Some(previous) => space.spawn_replacing(env, previous),
+            None => space.spawn(env),
+        };
+
+        let (_, cap) = spawned.map_err(|e| format!("failed to spawn: {:?}", e))?;
+        Ok((cap, stdout.zip(stream)))
+    }
+
+    /// The service on the other end of `cptr` has exited, so report how and
+    /// schedule it to be restarted if it should be
+    fn exited(&mut self, cptr: CapabilityPtr) {
+        let Running { service, started_ns, restarts } = match self.running.remove(&cptr) {
+            Some(running) => running,
+            None => return,
+        };
+
+        let status = std::process::wait(cptr).unwrap_or(ExitStatus::Killed);
+        self.report_exit(&service.name, status);
+        if !service.restart_policy().should_restart(status) {
+            return;
+        }
+
+        let now = monotonic_ns();
+        let restarts = match now - started_ns >= RESTART_STABLE_MS * 1_000_000 {
+            true => 0,
+            false => restarts,
+        };
+
+        self.schedule_restart(service, cptr, restarts);
+    }
+
+    fn report_exit(&self, name: &str, status: ExitStatus) {
+        let crash_id = match status {
+            ExitStatus::Faulted { crash_id } => crash_id,
+            status => return println!("[init] {} exited: {:?}", name, status),
+        };
+
+        let mut info = CrashInfo::default();
+        match read_crash_dump(self.system_cap, crash_id, &mut info, &mut []) {
+            SyscallResult::Ok(Some(_)) if info.id == crash_id => println!(
+                "[init] {} crashed: {} at {:#x} (address {:#x}), see crash {}",
+                name,
+                info.cause_name(),
+                info.pc,
+                info.fault_address,
+                crash_id
+            ),
+            _ => println!("[init] {} crashed, see crash {}", name, crash_id),
+        }
+    }
+
+    fn schedule_restart(&mut self, service: Service, previous: CapabilityPtr, restarts: u32) {
+        let delay_ms = (RESTART_BACKOFF_MIN_MS << restarts.min(16)).min(RESTART_BACKOFF_MAX_MS);
+        println!("[init] Restarting {} in {}ms", service.name, delay_ms);
+
+        let at_ns = monotonic_ns() + delay_ms * 1_000_000;
+        self.pending.push(Restart { service, previous, at_ns, restarts: restarts + 1 });
+    }
+
+    /// How long until the next restart is due
+    fn restart_timeout_us(&self) -> usize {
+        match self.pending.iter().map(|restart| restart.at_ns).min() {
+            Some(at_ns) => (at_ns.saturating_sub(monotonic_ns()) / 1000) as usize,
+            None => NO_TIMEOUT,
+        }
+    }
+
+    /// Restart the services whose backoff has passed. Services are restarted
+    /// from `initfs`, which drivers are loaded from and has all of them.
+    fn restart_due(&mut self, initfs: &tar::Archive, lifecycle: &mut Lifecycle) {
+        let now = monotonic_ns();
+        let (due, waiting): (Vec<_>, Vec<_>) =
+            core::mem::take(&mut self.pending).into_iter().partition(|restart| restart.at_ns <= now);
+        self.pending = waiting;
+
+        for Restart { service, previous, restarts, .. } in due {
+            let file = match initfs.file(service.binary()) {
+                Some(file) => file,
+                None => {
+                    println!("[init] Not restarting {}: no {} in the initfs", service.name, service.binary());
+                    continue;
+                }
+            };
+
+            // The new instance takes over the capabilities the old one held,
+            // including any devices it had claimed, so clients stay connected
+            let (cap, stdout) = match self.spawn(&service, file.contents, Some(previous)) {
+                Ok(spawned) => spawned,
+                Err(e) => {
+                    println!("[init] Failed to restart {}: {}", service.name, e);
+                    self.schedule_restart(service, previous, restarts);
+                    continue;
+                }
+            };
+
+            println!("[init] Restarted {}", service.name);
+
+            // The registry forgets services which have exited once they're
+            // looked up, and a new registry doesn't know about any of them
+            register(&service.name, cap);
+            if service.name == registry::SERVICE_NAME {
+                for (&cptr, running) in &self.running {
+                    register(&running.service.name, cptr);
+                }
+            }
+
+            if service.name == "configmgr" {
+                send_config(initfs, cap);
+            }
+
+            if let (Some((ConsoleStreamKind::Pipe, pipe)), Some(log)) = (stdout, lifecycle.logs.get_mut(&service.name))
+            {
+                log.drain();
+                log.pipe = pipe;
+            }
+
+            self.caps.insert(service.name.clone(), cap);
+            self.running.insert(cap, Running { service, started_ns: monotonic_ns(), restarts });
+        }
+    }
+}
+
+/// Make `cap` the channel the registry hands out for `name`
+fn register(name: &str, cap: CapabilityPtr) {
+    match name == registry::SERVICE_NAME {
+        true => std::env::register_capability(registry::SERVICE_NAME, cap),
+        false => {
+            if let Err(e) = std::env::register_service_channel(name, cap) {
+                println!("[init] Failed to register {}: {:?}", name, e);
+            }
+        }
+    }
+}
+
+fn main() {
+    // The kernel hands us a read-only capability to its copy of the device
+    // tree before anything else, followed by the capability to shut down or
+    // reboot the system and read crash dumps, and the one to map the kernel
+    // log
+    let mut supervisor = Supervisor {
+        caps: BTreeMap::new(),
+        running: BTreeMap::new(),
+        pending: Vec::new(),
+        fdt_cap: CapabilityPtr::new(0),
+        system_cap: CapabilityPtr::new(1),
+        klog_cap: CapabilityPtr::new(2),
+    };
+    let system_cap = supervisor.system_cap;
+    let servers = lz4::decompress(SERVERS).unwrap();
+    let tar = tar::Archive::new(&servers).unwrap();
+
+    let manifest = manifest::load(&tar);
+    let mut stoppable = Vec::new();
+    let mut lifecycle = Lifecycle::default();
+
+    // Services started after one which provides an initfs are loaded from it,
+    // unless it doesn't have them
+    let mut provided: Option<Vec<u8>> = None;
+
+    for server in manifest.servers {
+        let provided_tar = provided.as_deref().and_then(|image| tar::Archive::new(image).ok());
+        let file = match provided_tar.and_then(|tar| tar.file(server.binary())).or_else(|| tar.file(server.binary())) {
+            Some(file) => file,
+            None => {
+                println!("[init] Failed to start {}: no {} in the initfs", server.name, server.binary());
+                continue;
+            }
+        };
+
+        let (cap, stdout) = match supervisor.spawn(&server, file.contents, None) {
+            Ok(spawned) => spawned,
+            Err(e) => {
+                println!("[init] Failed to start {}: {}", server.name, e);
+                continue;
+            }
+        };
+
+        register(&server.name, cap);
+        if server.name == "configmgr" {
+            send_config(&tar, cap);
+        }
+
+        if let Some(timeout_ms) = server.stop_timeout_ms {
+            stoppable.push((server.name.clone(), timeout_ms));
+        }
+
+        if let Some((ConsoleStreamKind::Pipe, pipe)) = stdout {
+            lifecycle.logs.insert(server.name.clone(), ServiceLog { pipe, contents: VecDeque::new() });
+        }
+
+        if server.provides_initfs == Some(true) {
+            if let Some(image) = wait_for_initfs(&mut lifecycle, &server.name, cap) {
+                provided = Some(image);
+            }
+        }
+
+        supervisor.caps.insert(server.name.clone(), cap);
+        supervisor.running.insert(cap, Running { service: server, started_ns: monotonic_ns(), restarts: 0 });
+    }
+
+    // Drivers and programs run from the shell are loaded from the same initfs
+    // as the services started last
+    let image = provided.as_deref().filter(|image| tar::Archive::new(image).is_ok()).unwrap_or(&servers);
+    let tar = tar::Archive::new(image).unwrap();
+    lifecycle.loaded_initfs = snapshot_initfs(image);
+
+    // Drivers packaged as bundles are loaded by `devicemgr`, which is sent a
+    // channel to each of the services a bundle's manifest lists along with it
+    let devicemgr = supervisor.caps["devicemgr"];
+    for file in tar.files().filter(|file| file.metadata.file_name.ends_with(driver_bundle::EXTENSION)) {
+        let manifest = match driver_bundle::Bundle::manifest(file.contents) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                println!("[init] Invalid driver bundle {}: {:?}", file.metadata.file_name, e);
+                continue;
+            }
+        };
+
+        let services = manifest
+            .services()
+            .iter()
+            .filter_map(|name| supervisor.caps.get(name))
+            .map(|&cptr| {
+                Capability::new(cptr, CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::TRANSFER)
+            })
+            .collect::<Vec<_>>();
+
+        if let Err(e) = IpcChannel::new(devicemgr).send_bytes(file.contents, &services) {
+            println!("[init] Failed to send driver bundle {} to devicemgr: {:?}", file.metadata.file_name, e);
+        }
+    }
+
+    // Requests only come over the channels to the services `init` started,
+    // which are waited on along with the pipes of the services being logged
+    // so that their output is read as it's written, until the next service
+    // waiting to be restarted is due
+    let reboot = loop {
+        let channels = supervisor.running.keys().copied().collect::<Vec<_>>();
+        let mut items = channels
+            .iter()
+            .chain(lifecycle.logs.values().map(|log| &log.pipe))
+            .map(|&cptr| WaitItem::new(cptr, WaitEvents::READABLE))
+            .collect::<Vec<_>>();
+
+        wait_many(&mut items, supervisor.restart_timeout_us()).unwrap();
+        lifecycle.logs.values_mut().for_each(ServiceLog::drain);
+
+        for item in &items[..channels.len()] {
+            if !(item.ready & WaitEvents::READABLE) {
+                continue;
+            }
+
+            let mut channel = IpcChannel::new(item.cptr);
+            let (message, caps) = match channel.read_with_all_caps() {
+                Ok(read) => read,
+                Err(_) => continue,
+            };
+
+            if let Err(e) = protocol::dispatch(&mut lifecycle, &mut channel, message.as_bytes(), caps) {
+                println!("[init] Error handling request: {:?}", e);
+            }
+        }
+
+        // Services which have exited can't send anything else
+        for item in &items[..channels.len()] {
+            if item.ready & WaitEvents::CLOSED && !(item.ready & WaitEvents::READABLE) {
+                supervisor.exited(item.cptr);
+            }
+        }
+
+        if let Some(reboot) = lifecycle.requested {
+            break reboot;
+        }
+
+        supervisor.restart_due(&tar, &mut lifecycle);
+    };
+
+    // Services are stopped in the reverse order they were started in, so
+    // nothing is stopped before the services that depend on it
+    for (name, timeout_ms) in stoppable.into_iter().rev() {
+        if let Some(&cap) = supervisor.caps.get(&name) {
+            stop(&mut lifecycle, &name, cap, timeout_ms);
+        }
+    }
+
+    let kind = match reboot {
+        true => ResetKind::ColdReboot,
+        false => ResetKind::Shutdown,
+    };
+
+    if let SyscallResult::Err(e) = system_reset(system_cap, kind) {
+        println!("[init] Failed to power off: {:?}", e);
+    }
+}
+
+/// Send `configmgr` the config file from the initfs. Something is always sent,
+/// since `configmgr` waits for it.
+fn send_config(initfs: &tar::Archive, cap: CapabilityPtr) {
+    let config_file = initfs.file("config").map(|file| file.contents).unwrap_or(&b"# no config file\n"[..]);
+    if let Err(e) = IpcChannel::new(cap).send_bytes(config_file, &[]) {
+        println!("[init] Failed to send configmgr its config: {:?}", e);
+    }
+}
+
+/// Copy the initfs into memory of its own, and seal it into a snapshot that
+/// can be handed out read-only
+fn snapshot_initfs(image: &[u8]) -> Option<(CapabilityPtr, usize)> {
+    let permissions = MemoryPermissions::READ | MemoryPermissions::WRITE;
+    let memory = match alloc_virtual_memory(image.len(), AllocationOptions::None, permissions) {
+        SyscallResult::Ok(memory) => memory,
+        SyscallResult::Err(e) => {
+            println!("[init] Failed to allocate memory for the initfs: {:?}", e);
+            return None;
+        }
+    };
+
+    unsafe { core::ptr::copy_nonoverlapping(image.as_ptr(), memory, image.len()) };
+    match snapshot_memory(memory) {
+        SyscallResult::Ok((cptr, ..)) => Some((cptr, image.len())),
+        SyscallResult::Err(e) => {
+            println!("[init] Failed to snapshot the initfs: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Serve requests from a service which may provide an initfs until it either
+/// has or it exits, returning the initfs
+fn wait_for_initfs(lifecycle: &mut Lifecycle, name: &str, cap: CapabilityPtr) -> Option<Vec<u8>> {
+    let mut channel = IpcChannel::new(cap);
+    lifecycle.awaiting_initfs = Some(cap);
+
+    while lifecycle.awaiting_initfs.is_some() {
+        let mut items = [WaitItem::new(cap, WaitEvents::READABLE)];
+        match wait_many(&mut items, NO_TIMEOUT) {
+            SyscallResult::Ok(_) if items[0].ready & WaitEvents::READABLE => {}
+            _ => break,
+        }
+
+        let (message, caps) = match channel.read_with_all_caps() {
+            Ok(read) => read,
+            Err(_) => break,
+        };
+
+        if let Err(e) = protocol::dispatch(lifecycle, &mut channel, message.as_bytes(), caps) {
+            println!("[init] Error handling request from {}: {:?}", name, e);
+        }
+    }
+
+    lifecycle.awaiting_initfs = None;
+    lifecycle.initfs.take()
+}
+
+/// Send the shutdown notification to a service and wait until it reports it
+/// has stopped, it exits, or `timeout_ms` passes without hearing from it
+fn stop(lifecycle: &mut Lifecycle, name: &str, cap: CapabilityPtr, timeout_ms: usize) {
+    let mut channel = IpcChannel::new(cap);
+    if json_rpc::notify(&mut channel, lifecycle::SHUTDOWN, ()).is_err() {
+        return;
+    }
+
+    lifecycle.stopped = false;
+    while !lifecycle.stopped {
+        let mut items = [WaitItem::new(cap, WaitEvents::READABLE)];
+        match wait_many(&mut items, timeout_ms * 1000) {
+            SyscallResult::Ok(0) => return println!("[init] {} didn't stop within {}ms", name, timeout_ms),
+            SyscallResult::Ok(_) if !(items[0].ready & WaitEvents::READABLE) => return,
+            SyscallResult::Ok(_) => {}
+            SyscallResult::Err(_) => return,
+        }
+
+        let (message, caps) = match channel.read_with_all_caps() {
+            Ok(read) => read,
+            Err(_) => return,
+        };
+
+        if let Err(e) = protocol::dispatch(lifecycle, &mut channel, message.as_bytes(), caps) {
+            println!("[init] Error handling request from {}: {:?}", name, e);
+        }
     }
 }
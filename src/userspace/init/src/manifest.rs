@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The manifest of services `init` starts, in the order they're started in.
+//!
+//! One is built into `init` from `manifest.json`, and can be replaced without
+//! rebuilding `init` by putting an `init.json` in the initfs, e.g. from the
+//! `initfs/` directory. Besides the other services, the capabilities a service
+//! can be given are the ones the kernel gives `init`: `fdt`, `system`, and
+//! `klog`.
+
+use tar::Archive;
+
+/// Name of the file in the initfs which replaces the built in manifest
+pub const INITFS_FILE: &str = "init.json";
+
+static BUILT_IN: &str = include_str!("../manifest.json");
+
+json::derive! {
+    Deserialize,
+    pub struct Manifest {
+        pub servers: Vec<Service>,
+    }
+}
+
+json::derive! {
+    Deserialize,
+    pub struct Service {
+        /// What the service is registered as, and what the services started
+        /// after it list in their `caps` to be given a channel to it
+        pub name: String,
+        /// File in the initfs the service is loaded from, which defaults to
+        /// its name
+        pub binary: Option<String>,
+        pub caps: Vec<String>,
+        /// Services with a stop timeout are sent a shutdown notification
+        /// before the system powers off, and are waited on for this long
+        pub stop_timeout_ms: Option<usize>,
+        /// Where the service's output goes: `console` (the default) through
+        /// the `stdio` server, `log` to a log which can be read back with
+        /// `read_log`, or `discard`
+        pub stdout: Option<String>,
+        /// The service may fetch an initfs to load the services started after
+        /// it from, which is waited on until it's either provided one or
+        /// exited
+        pub provides_initfs: Option<bool>,
+        /// The service runs programs of its own, so its capabilities are
+        /// granted with `TRANSFER` to let it pass them on
+        pub spawns_programs: Option<bool>,
+        /// When the service is started again after it exits: `never` (the
+        /// default), `on-failure`, or `always`. Services which keep exiting
+        /// are restarted with exponential backoff.
+        pub restart: Option<String>,
+    }
+}
+
+impl Service {
+    pub fn binary(&self) -> &str {
+        self.binary.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn restart_policy(&self) -> RestartPolicy {
+        match self.restart.as_deref() {
+            None | Some("never") => RestartPolicy::Never,
+            Some("on-failure") => RestartPolicy::OnFailure,
+            Some("always") => RestartPolicy::Always,
+            Some(other) => {
+                println!("[init] Unknown restart policy `{}` for {}, never restarting it", other, self.name);
+                RestartPolicy::Never
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    Never,
+    /// Restart the service if it didn't exit successfully, including being
+    /// killed
+    OnFailure,
+    Always,
+}
+
+impl RestartPolicy {
+    pub fn should_restart(self, status: std::process::ExitStatus) -> bool {
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => !status.success(),
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+/// Load the manifest from `initfs` if it has one, falling back to the built
+/// in manifest if it doesn't or it's invalid
+pub fn load(initfs: &Archive) -> Manifest {
+    if let Some(file) = initfs.file(INITFS_FILE) {
+        match json::deserialize(file.contents) {
+            Ok(manifest) => return manifest,
+            Err(e) => println!("[init] Invalid {}, using the built in manifest: {:?}", INITFS_FILE, e),
+        }
+    }
+
+    json::deserialize(BUILT_IN.as_bytes()).unwrap()
+}
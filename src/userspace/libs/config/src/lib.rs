@@ -0,0 +1,87 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use json_rpc::RpcError;
+use std::ipc::IpcChannel;
+
+/// Name of the notification sent to watchers when a setting changes
+pub const CHANGED: &str = "changed";
+
+json_rpc::rpc! {
+    pub service protocol {
+        /// Retrieve the value of a setting
+        fn get(key: String) -> Option<String>;
+        /// Set the value of a setting, notifying any watchers
+        fn set(key: String, value: String);
+        /// Remove a setting, returning its previous value
+        fn remove(key: String) -> Option<String>;
+        /// List all of the setting keys starting with `prefix`
+        fn list(prefix: String) -> Vec<String>;
+        /// Be notified of changes to any setting starting with `prefix`
+        fn watch(prefix: String);
+    }
+}
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    pub struct Change {
+        pub key: String,
+        pub value: Option<String>,
+    }
+}
+
+/// Connection to the `configmgr` service
+pub struct Config {
+    client: protocol::Client,
+}
+
+impl Config {
+    /// Connect to `configmgr` if the current task was given a capability to
+    /// it
+    pub fn connect() -> Option<Self> {
+        let cptr = std::env::lookup_capability("configmgr")?;
+        Some(Self { client: protocol::Client::new(IpcChannel::new(cptr)) })
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<String>, RpcError> {
+        self.client.get(key.into())
+    }
+
+    /// Retrieve a setting, parsing its value as a `bool`. Keys that are present
+    /// without a value (e.g. a bare `foo` on the command line) are `true`.
+    pub fn get_bool(&mut self, key: &str) -> Result<Option<bool>, RpcError> {
+        Ok(self.get(key)?.map(|value| matches!(&*value, "" | "true" | "yes" | "on" | "1")))
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), RpcError> {
+        self.client.set(key.into(), value.into())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<Option<String>, RpcError> {
+        self.client.remove(key.into())
+    }
+
+    pub fn list(&mut self, prefix: &str) -> Result<Vec<String>, RpcError> {
+        self.client.list(prefix.into())
+    }
+
+    /// Watch for changes to settings starting with `prefix`, which can then be
+    /// received with [`Config::next_change`]
+    pub fn watch(&mut self, prefix: &str) -> Result<(), RpcError> {
+        self.client.watch(prefix.into())
+    }
+
+    /// Block until a watched setting changes
+    pub fn next_change(&mut self) -> Result<Change, RpcError> {
+        loop {
+            let (method, change) = self.client.next_notification::<Change>()?;
+            if method == CHANGED {
+                return Ok(change);
+            }
+        }
+    }
+}
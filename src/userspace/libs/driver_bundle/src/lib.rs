@@ -0,0 +1,189 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Driver bundles, which pair a driver's binary with a manifest of everything
+//! it needs to run: the devices it drives, whether it handles their
+//! interrupts, how much DMA memory it uses, and the services it talks to.
+//! `devicemgr` loads a bundle by checking its manifest against policy and then
+//! spawning the driver with exactly the capabilities the manifest lists, so
+//! reading the manifest is enough to know what a driver can touch.
+//!
+//! A bundle is a tar archive holding the driver's ELF binary as [`BINARY`] and
+//! its manifest as [`MANIFEST`], e.g.
+//!
+//! ```json
+//! {
+//!     "name": "virtio-rng",
+//!     "devices": [
+//!         { "compatible": ["virtio,mmio"], "interrupts": true, "max": 1 },
+//!     ],
+//!     "dma_bytes": 4096,
+//!     "services": ["stdio"],
+//! }
+//! ```
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use json::deser::DeserializeError;
+
+/// File name of the driver's binary within a bundle
+pub const BINARY: &str = "driver";
+/// File name of the manifest within a bundle
+pub const MANIFEST: &str = "manifest.json";
+/// File extension bundles are given in the initfs
+pub const EXTENSION: &str = ".bundle";
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    pub struct Manifest {
+        pub name: String,
+        pub devices: Vec<DeviceRequest>,
+        // Most DMA memory the driver allocates at once, in bytes
+        pub dma_bytes: Option<usize>,
+        // Names of the services the driver is given channels to
+        pub services: Option<Vec<String>>,
+    }
+}
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    pub struct DeviceRequest {
+        // Device tree `compatible` strings, any of which match
+        pub compatible: Vec<String>,
+        // Whether the driver handles the devices' interrupts, which has to be
+        // declared to be given any device that has them
+        pub interrupts: Option<bool>,
+        // Most matching devices to give the driver, or all of them if not
+        // given
+        pub max: Option<usize>,
+    }
+}
+
+impl Manifest {
+    pub fn services(&self) -> &[String] {
+        self.services.as_deref().unwrap_or_default()
+    }
+}
+
+impl DeviceRequest {
+    pub fn matches<'a>(&self, mut compatible: impl Iterator<Item = &'a str>) -> bool {
+        compatible.any(|c| self.compatible.iter().any(|wanted| wanted == c))
+    }
+
+    pub fn interrupts(&self) -> bool {
+        self.interrupts.unwrap_or(false)
+    }
+}
+
+#[derive(Debug)]
+pub enum BundleError {
+    NotAnArchive,
+    MissingBinary,
+    MissingManifest,
+    InvalidManifest(DeserializeError),
+}
+
+#[derive(Debug)]
+pub struct Bundle<'a> {
+    pub manifest: Manifest,
+    pub binary: &'a [u8],
+}
+
+impl<'a> Bundle<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, BundleError> {
+        let archive = tar::Archive::new(data).map_err(|_| BundleError::NotAnArchive)?;
+
+        let mut binary = None;
+        let mut manifest = None;
+        for file in archive.files() {
+            match file.metadata.file_name {
+                BINARY => binary = Some(file.contents),
+                MANIFEST => manifest = Some(file.contents),
+                _ => {}
+            }
+        }
+
+        let binary = binary.ok_or(BundleError::MissingBinary)?;
+        let manifest = manifest.ok_or(BundleError::MissingManifest)?;
+        let manifest = json::deserialize(manifest).map_err(BundleError::InvalidManifest)?;
+
+        Ok(Self { manifest, binary })
+    }
+
+    /// Only read the manifest, for when the binary isn't needed
+    pub fn manifest(data: &[u8]) -> Result<Manifest, BundleError> {
+        Bundle::parse(data).map(|bundle| bundle.manifest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    fn entry(archive: &mut Vec<u8>, name: &str, contents: &[u8]) {
+        let mut header = [0; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+
+        for (offset, field) in
+            [(100, "0000644"), (108, "0000000"), (116, "0000000"), (329, "0000000"), (337, "0000000")]
+        {
+            header[offset..][..field.len()].copy_from_slice(field.as_bytes());
+        }
+
+        let size = std::format!("{:011o}", contents.len());
+        header[124..][..11].copy_from_slice(size.as_bytes());
+        header[136..][..11].copy_from_slice(b"00000000000");
+        header[148..][..7].copy_from_slice(b"0000000");
+        header[156] = b'0';
+        header[257..][..6].copy_from_slice(b"ustar\0");
+
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(contents);
+        archive.resize(archive.len() + (512 - archive.len() % 512) % 512, 0);
+    }
+
+    #[test]
+    fn parses_bundle() {
+        let mut archive = Vec::new();
+        entry(&mut archive, BINARY, b"\x7fELF");
+        entry(
+            &mut archive,
+            MANIFEST,
+            br#"{ "name": "rng", "devices": [{ "compatible": ["virtio,mmio"], "max": 1 }], "services": ["stdio"] }"#,
+        );
+        archive.extend_from_slice(&[0; 1024]);
+
+        let bundle = Bundle::parse(&archive).unwrap();
+        assert_eq!(bundle.binary, b"\x7fELF");
+        assert_eq!(bundle.manifest.name, "rng");
+        assert_eq!(bundle.manifest.services(), ["stdio"]);
+        assert_eq!(bundle.manifest.dma_bytes, None);
+
+        let device = &bundle.manifest.devices[0];
+        assert!(device.matches(["sifive,uart0", "virtio,mmio"].into_iter()));
+        assert!(!device.matches(["sifive,uart0"].into_iter()));
+        assert!(!device.interrupts());
+        assert_eq!(device.max, Some(1));
+    }
+
+    #[test]
+    fn needs_binary_and_manifest() {
+        let mut archive = Vec::new();
+        entry(&mut archive, MANIFEST, br#"{ "name": "rng", "devices": [] }"#);
+        assert!(matches!(Bundle::parse(&archive), Err(BundleError::MissingBinary)));
+
+        let mut archive = Vec::new();
+        entry(&mut archive, BINARY, b"\x7fELF");
+        assert!(matches!(Bundle::parse(&archive), Err(BundleError::MissingManifest)));
+
+        assert!(matches!(Bundle::parse(&[0; 1024]), Err(BundleError::NotAnArchive)));
+    }
+}
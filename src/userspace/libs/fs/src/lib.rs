@@ -0,0 +1,48 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use json_rpc::RpcError;
+use std::ipc::IpcChannel;
+
+json_rpc::rpc! {
+    pub service protocol {
+        /// Check the consistency of the mounted volume, fixing what can be
+        /// fixed if `repair` is set
+        fn check(repair: bool) -> CheckReport;
+    }
+}
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    pub struct CheckReport {
+        /// Whether the volume was marked as cleanly unmounted before the check
+        pub was_clean: bool,
+        pub problems: Vec<String>,
+        pub files: u32,
+        pub directories: u32,
+        pub used_clusters: u32,
+        pub repaired: bool,
+    }
+}
+
+/// Connection to the `filesystem` service
+pub struct Filesystem {
+    client: protocol::Client,
+}
+
+impl Filesystem {
+    /// Connect to `filesystem` if the current task was given a capability to
+    /// it
+    pub fn connect() -> Option<Self> {
+        let cptr = std::env::lookup_capability("filesystem")?;
+        Some(Self { client: protocol::Client::new(IpcChannel::new(cptr)) })
+    }
+
+    pub fn check(&mut self, repair: bool) -> Result<CheckReport, RpcError> {
+        self.client.check(repair)
+    }
+}
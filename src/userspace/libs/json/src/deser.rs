@@ -184,6 +184,12 @@ impl<S: Serializer> Serialize<S> for usize {
     }
 }
 
+impl<S: Serializer> Serialize<S> for () {
+    fn serialize(&self, serializer: &mut S) {
+        serializer.serialize_null();
+    }
+}
+
 impl<S: Serializer, T: Serialize<S>> Serialize<S> for alloc::vec::Vec<T> {
     fn serialize(&self, serializer: &mut S) {
         serializer.serialize_list(self.iter().map(|t| t as &dyn Serialize<S>))
@@ -399,6 +405,12 @@ impl Deserialize for usize {
     }
 }
 
+impl Deserialize for () {
+    fn deserialize<'a, D: Deserializer<'a>>(deserializer: &mut D) -> Result<Self, DeserializeError> {
+        deserializer.deserialize_null()
+    }
+}
+
 impl<T: Deserialize> Deserialize for alloc::vec::Vec<T> {
     fn deserialize<'a, D: Deserializer<'a>>(deserializer: &mut D) -> Result<Self, DeserializeError> {
         let mut this = alloc::vec::Vec::new();
@@ -17,29 +17,29 @@ use core::ops::{Deref, Index};
 
 #[macro_export]
 macro_rules! derive {
-    ($(#[$($attr:meta),+])? struct $name:ident$(<$($g:ident),+$(,)?>)? { $($field:ident: $t:ty),+ $(,)? }) => {
+    ($(#[$($attr:meta),+])? $vis:vis struct $name:ident$(<$($g:ident),+$(,)?>)? { $($(#[$fattr:meta])* $fvis:vis $field:ident: $t:ty),+ $(,)? }) => {
         $(#[$($attr),+])?
-        struct $name$(<$($g),+>)? {
-            $($field: $t),+
+        $vis struct $name$(<$($g),+>)? {
+            $($(#[$fattr])* $fvis $field: $t),+
         }
 
         $crate::derive!(@deser struct $name$(<$($g),+>)? { $($field: $t),+ });
         $crate::derive!(@ser struct $name$(<$($g),+>)? { $($field: $t),+ });
     };
 
-    (Serialize, $(#[$($attr:meta),+])? struct $name:ident$(<$($g:ident),+$(,)?>)? { $($field:ident: $t:ty),+ $(,)? }) => {
+    (Serialize, $(#[$($attr:meta),+])? $vis:vis struct $name:ident$(<$($g:ident),+$(,)?>)? { $($(#[$fattr:meta])* $fvis:vis $field:ident: $t:ty),+ $(,)? }) => {
         $(#[$($attr),+])?
-        struct $name$(<$($g),+>)? {
-            $($field: $t),+
+        $vis struct $name$(<$($g),+>)? {
+            $($(#[$fattr])* $fvis $field: $t),+
         }
 
         $crate::derive!(@ser struct $name$(<$($g),+>)? { $($field: $t),+ });
     };
 
-    (Deserialize, $(#[$($attr:meta),+])? struct $name:ident$(<$($g:ident),+$(,)?>)? { $($field:ident: $t:ty),+ $(,)? }) => {
+    (Deserialize, $(#[$($attr:meta),+])? $vis:vis struct $name:ident$(<$($g:ident),+$(,)?>)? { $($(#[$fattr:meta])* $fvis:vis $field:ident: $t:ty),+ $(,)? }) => {
         $(#[$($attr),+])?
-        struct $name$(<$($g),+>)? {
-            $($field: $t),+
+        $vis struct $name$(<$($g),+>)? {
+            $($(#[$fattr])* $fvis $field: $t),+
         }
 
         $crate::derive!(@deser struct $name$(<$($g),+>)? { $($field: $t),+ });
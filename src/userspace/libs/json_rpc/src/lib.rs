@@ -5,21 +5,447 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-#![no_std]
-
 extern crate alloc;
 
-use json::deser::{Deserialize, Serialize, Serializer};
+use alloc::collections::VecDeque;
+
+use json::deser::{Deserialize, DeserializeError, Deserializer, Serialize, Serializer};
+use librust::{
+    capabilities::{Capability, CapabilityPtr},
+    error::KError,
+};
+use std::ipc::IpcChannel;
+
+#[doc(hidden)]
+pub mod __private {
+    pub use json;
+    pub use librust::capabilities::Capability;
+    pub use std::ipc::IpcChannel;
+}
 
+/// Describe a channel-based RPC interface and generate the client proxy,
+/// server trait, and dispatch loop for it. The interface is placed in a module
+/// named after the service:
+///
+/// ```rust,ignore
+/// json_rpc::rpc! {
+///     pub service config {
+///         fn get(key: String) -> Option<String>;
+///         fn set(key: String, value: String);
+///     }
+/// }
+///
+/// // Client side
+/// let mut client = config::Client::new(IpcChannel::new(cptr));
+/// let value = client.get(String::from("log-level"))?;
+///
+/// // Server side
+/// impl config::Server for Config { ... }
+/// config::serve(&mut Config::new(), &mut channel)?;
+/// ```
+///
+/// Parameters and return values are serialized as JSON, so they must implement
+/// `json::deser::{Serialize, Deserialize}`. Capabilities travel alongside the
+/// message: clients attach them with [`RpcClient::attach_capabilities`] before
+/// a call and collect any returned with [`RpcClient::take_capabilities`] after,
+/// while servers see both directions through their [`CallContext`].
+///
+/// Servers can also push unsolicited [`notify`] messages to a client, which
+/// are queued up on the client side until read with
+/// [`RpcClient::next_notification`].
 #[macro_export]
 macro_rules! rpc {
-    ($service:ident, { $(fn $f:ident($($arg:ident: $t:ty),*)? $(-> $ret:ty)?);+ }) => {
-        trait $service {
-            $(fn $f:ident($($arg:ident: $t:ty),*)? $(-> $ret:ty)?);+
+    (@ret $ret:ty) => { $ret };
+    (@ret) => { () };
+
+    (
+        $(#[$sattr:meta])*
+        $vis:vis service $service:ident {
+            $(
+                $(#[$attr:meta])*
+                fn $f:ident($($arg:ident: $t:ty),* $(,)?) $(-> $ret:ty)?;
+            )+
+        }
+    ) => {
+        $(#[$sattr])*
+        $vis mod $service {
+            #![allow(unused_imports)]
+
+            use super::*;
+
+            #[allow(non_camel_case_types)]
+            pub enum Request {
+                $($f { $($arg: $t),* },)+
+            }
+
+            impl $crate::RpcRequest for Request {
+                fn method(&self) -> &'static str {
+                    match self {
+                        $(Self::$f { .. } => stringify!($f),)+
+                    }
+                }
+
+                fn serialize_params<S: $crate::__private::json::deser::Serializer>(&self, serializer: &mut S) {
+                    match self {
+                        $(
+                            Self::$f { $($arg),* } => {
+                                let members: &[(&str, &dyn $crate::__private::json::deser::Serialize<S>)] = &[
+                                    $((stringify!($arg), $arg as &dyn $crate::__private::json::deser::Serialize<S>)),*
+                                ];
+                                serializer.serialize_object(members.iter().copied());
+                            }
+                        )+
+                    }
+                }
+
+                fn deserialize_params<'a, D: $crate::__private::json::deser::Deserializer<'a>>(
+                    method: &str,
+                    deserializer: &mut D,
+                ) -> Result<Self, $crate::__private::json::deser::DeserializeError> {
+                    match method {
+                        $(
+                            stringify!($f) => {
+                                $(let mut $arg = <$t as $crate::__private::json::deser::Deserialize>::init();)*
+
+                                deserializer.deserialize_object(|name, deserializer| {
+                                    Ok(match name {
+                                        $(
+                                            stringify!($arg) => {
+                                                $arg = Some(<$t as $crate::__private::json::deser::Deserialize>::deserialize(deserializer)?)
+                                            }
+                                        )*
+                                        _ => core::mem::drop(deserializer.deserialize_value()?),
+                                    })
+                                })?;
+
+                                Ok(Self::$f {
+                                    $($arg: $arg.ok_or($crate::__private::json::deser::DeserializeError::MissingField(stringify!($arg)))?),*
+                                })
+                            }
+                        )+
+                        _ => Err($crate::__private::json::deser::DeserializeError::UnknownVariantValue),
+                    }
+                }
+            }
+
+            pub trait Server {
+                $(
+                    $(#[$attr])*
+                    fn $f(&mut self, cx: &mut $crate::CallContext, $($arg: $t),*) -> $crate::rpc!(@ret $($ret)?);
+                )+
+            }
+
+            pub struct Client($crate::RpcClient);
+
+            impl Client {
+                pub fn new(channel: $crate::__private::IpcChannel) -> Self {
+                    Self($crate::RpcClient::new(channel))
+                }
+
+                $(
+                    $(#[$attr])*
+                    pub fn $f(&mut self, $($arg: $t),*) -> Result<$crate::rpc!(@ret $($ret)?), $crate::RpcError> {
+                        self.0.call(&Request::$f { $($arg),* })
+                    }
+                )+
+            }
+
+            impl core::ops::Deref for Client {
+                type Target = $crate::RpcClient;
+
+                fn deref(&self) -> &Self::Target {
+                    &self.0
+                }
+            }
+
+            impl core::ops::DerefMut for Client {
+                fn deref_mut(&mut self) -> &mut Self::Target {
+                    &mut self.0
+                }
+            }
+
+            /// Handle a single request that has already been read off of
+            /// `channel`, sending the response back over the same channel
+            pub fn dispatch<S: Server>(
+                server: &mut S,
+                channel: &mut $crate::__private::IpcChannel,
+                message: &[u8],
+                capabilities: Vec<$crate::__private::Capability>,
+            ) -> Result<(), $crate::RpcError> {
+                let (request, id) = match $crate::decode_request::<Request>(message) {
+                    Ok(request) => request,
+                    Err(e) => return $crate::reply_error(channel, e),
+                };
+
+                let mut cx = $crate::CallContext::new(channel.cptr(), capabilities);
+                match request {
+                    $(
+                        Request::$f { $($arg),* } => {
+                            let ret = server.$f(&mut cx, $($arg),*);
+                            $crate::reply(channel, stringify!($f), id, ret, cx.reply_capabilities())
+                        }
+                    )+
+                }
+            }
+
+            /// Serve requests from `channel` until an IPC error occurs
+            pub fn serve<S: Server>(server: &mut S, channel: &mut $crate::__private::IpcChannel) -> Result<(), $crate::RpcError> {
+                loop {
+                    let (message, capabilities) = channel.read_with_all_caps()?;
+                    dispatch(server, channel, message.as_bytes(), capabilities)?;
+                }
+            }
         }
     };
 }
 
+/// Implemented by the request type generated by [`rpc!`], describing how to
+/// move a method's parameters on and off the wire
+pub trait RpcRequest: Sized {
+    fn method(&self) -> &'static str;
+    fn serialize_params<S: Serializer>(&self, serializer: &mut S);
+    fn deserialize_params<'a, D: Deserializer<'a>>(
+        method: &str,
+        deserializer: &mut D,
+    ) -> Result<Self, DeserializeError>;
+}
+
+#[derive(Debug)]
+pub enum RpcError {
+    /// The underlying channel operation failed
+    Ipc(KError),
+    /// The response could not be deserialized
+    Deserialize(DeserializeError),
+    /// The response was for a different request than the one sent
+    IdMismatch,
+    /// The server could not process the request
+    Remote(alloc::string::String),
+}
+
+impl From<KError> for RpcError {
+    fn from(e: KError) -> Self {
+        Self::Ipc(e)
+    }
+}
+
+impl From<DeserializeError> for RpcError {
+    fn from(e: DeserializeError) -> Self {
+        Self::Deserialize(e)
+    }
+}
+
+/// Client half of an RPC connection, wrapped by each generated `Client`
+#[derive(Debug)]
+pub struct RpcClient {
+    channel: IpcChannel,
+    next_id: i64,
+    outgoing_caps: Vec<Capability>,
+    incoming_caps: Vec<Capability>,
+    notifications: VecDeque<Vec<u8>>,
+}
+
+impl RpcClient {
+    pub fn new(channel: IpcChannel) -> Self {
+        Self {
+            channel,
+            next_id: 0,
+            outgoing_caps: Vec::new(),
+            incoming_caps: Vec::new(),
+            notifications: VecDeque::new(),
+        }
+    }
+
+    /// The channel to the server, for waiting on it alongside others
+    pub fn channel(&self) -> &IpcChannel {
+        &self.channel
+    }
+
+    /// Capabilities to send along with the next call
+    pub fn attach_capabilities(&mut self, caps: &[Capability]) -> &mut Self {
+        self.outgoing_caps.extend_from_slice(caps);
+        self
+    }
+
+    /// Capabilities returned by the server in response to the last call
+    pub fn take_capabilities(&mut self) -> Vec<Capability> {
+        core::mem::take(&mut self.incoming_caps)
+    }
+
+    pub fn call<R: RpcRequest, T: Deserialize>(&mut self, request: &R) -> Result<T, RpcError> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let request = Request {
+            method: alloc::string::String::from(request.method()),
+            params: Some(Params(request)),
+            id: Some(id),
+        };
+        let caps = core::mem::take(&mut self.outgoing_caps);
+        self.channel.send_bytes(json::to_bytes(&request), &caps)?;
+
+        let response: Response<T, alloc::string::String> = loop {
+            let (message, caps) = self.channel.read_with_all_caps()?;
+
+            // Notifications can arrive while waiting for the response, so hold
+            // on to them until they're asked for
+            if is_notification(message.as_bytes())? {
+                self.notifications.push_back(message.as_bytes().to_vec());
+                continue;
+            }
+
+            self.incoming_caps = caps;
+            break json::deserialize(message.as_bytes())?;
+        };
+        match response.id {
+            Some(response_id) if response_id == id => {}
+            None => {}
+            Some(_) => return Err(RpcError::IdMismatch),
+        }
+
+        match response.result {
+            CallResult::Ok(t) => Ok(t),
+            CallResult::Err(e) => Err(RpcError::Remote(e)),
+        }
+    }
+
+    /// Wait for the next notification sent by the server, returning the
+    /// notification name along with its parameters
+    pub fn next_notification<T: Deserialize>(&mut self) -> Result<(alloc::string::String, T), RpcError> {
+        let notification: Notification<T> = match self.notifications.pop_front() {
+            Some(bytes) => json::deserialize(&bytes)?,
+            None => json::deserialize(self.channel.read_with_all_caps()?.0.as_bytes())?,
+        };
+
+        Ok((notification.method, notification.params))
+    }
+}
+
+/// Per-call state handed to server methods
+#[derive(Debug)]
+pub struct CallContext {
+    channel: CapabilityPtr,
+    caps: Vec<Capability>,
+    reply_caps: Vec<Capability>,
+}
+
+impl CallContext {
+    #[doc(hidden)]
+    pub fn new(channel: CapabilityPtr, caps: Vec<Capability>) -> Self {
+        Self { channel, caps, reply_caps: Vec::new() }
+    }
+
+    /// The channel the request arrived on, which can be held on to for sending
+    /// [`notify`] messages to the client later
+    pub fn channel(&self) -> CapabilityPtr {
+        self.channel
+    }
+
+    /// Capabilities the client sent with this request
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.caps
+    }
+
+    pub fn take_capabilities(&mut self) -> Vec<Capability> {
+        core::mem::take(&mut self.caps)
+    }
+
+    /// Capabilities to send back to the client with the response
+    pub fn reply_with(&mut self, caps: &[Capability]) {
+        self.reply_caps.extend_from_slice(caps);
+    }
+
+    #[doc(hidden)]
+    pub fn reply_capabilities(&self) -> &[Capability] {
+        &self.reply_caps
+    }
+}
+
+/// Whether `bytes` is a notification rather than a response to a call, which
+/// is told apart by it having a method but no `id` member. Responses always
+/// carry one, even if it's `null`.
+fn is_notification(bytes: &[u8]) -> Result<bool, DeserializeError> {
+    let mut parser = json::parser::Parser::new(bytes);
+    let mut method = false;
+    let mut id = false;
+
+    parser.deserialize_object(|name, deserializer| {
+        match name {
+            "method" => method = true,
+            "id" => id = true,
+            _ => {}
+        }
+
+        core::mem::drop(deserializer.deserialize_value()?);
+        Ok(())
+    })?;
+
+    Ok(method && !id)
+}
+
+#[doc(hidden)]
+pub fn decode_request<R: RpcRequest>(bytes: &[u8]) -> Result<(R, Option<i64>), DeserializeError> {
+    let mut parser = json::parser::Parser::new(bytes);
+    let mut method = None;
+    let mut request = None;
+    let mut id = None;
+
+    // Requests are always serialized with the method name first, so by the time
+    // the parameters show up we know what shape they should be
+    parser.deserialize_object(|name, deserializer| {
+        match name {
+            "method" => method = Some(alloc::string::String::from(deserializer.deserialize_str()?)),
+            "params" => match &method {
+                Some(method) => request = Some(R::deserialize_params(method, deserializer)?),
+                None => return Err(DeserializeError::MissingField("method")),
+            },
+            "id" => id = Option::<i64>::deserialize(deserializer)?,
+            _ => core::mem::drop(deserializer.deserialize_value()?),
+        }
+
+        Ok(())
+    })?;
+
+    Ok((request.ok_or(DeserializeError::MissingField("params"))?, id))
+}
+
+#[doc(hidden)]
+pub fn reply<T: Serialize<Vec<u8>>>(
+    channel: &mut IpcChannel,
+    method: &str,
+    id: Option<i64>,
+    result: T,
+    caps: &[Capability],
+) -> Result<(), RpcError> {
+    let response = Response::<T, alloc::string::String> { method: method.into(), result: CallResult::Ok(result), id };
+    channel.send_bytes(json::to_bytes(&response), caps)?;
+    Ok(())
+}
+
+#[doc(hidden)]
+pub fn reply_error(channel: &mut IpcChannel, error: DeserializeError) -> Result<(), RpcError> {
+    let response = Response::<(), alloc::string::String> {
+        method: alloc::string::String::new(),
+        result: CallResult::Err(alloc::format!("{:?}", error)),
+        id: None,
+    };
+    channel.send_bytes(json::to_bytes(&response), &[])?;
+    Ok(())
+}
+
+/// Send an unsolicited message to the client on the other end of `channel`
+pub fn notify<T: Serialize<Vec<u8>>>(channel: &mut IpcChannel, method: &str, params: T) -> Result<(), RpcError> {
+    channel.send_bytes(json::to_bytes(&Notification { method: method.into(), params }), &[])?;
+    Ok(())
+}
+
+struct Params<'a, R: RpcRequest>(&'a R);
+
+impl<S: Serializer, R: RpcRequest> Serialize<S> for Params<'_, R> {
+    fn serialize(&self, serializer: &mut S) {
+        self.0.serialize_params(serializer);
+    }
+}
+
 json::derive! {
     struct Request<T> {
         method: alloc::string::String,
@@ -36,6 +462,13 @@ json::derive! {
     }
 }
 
+json::derive! {
+    struct Notification<T> {
+        method: alloc::string::String,
+        params: T,
+    }
+}
+
 enum CallResult<T, E> {
     Ok(T),
     Err(E),
@@ -0,0 +1,103 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use json_rpc::RpcError;
+use librust::{
+    capabilities::{Capability, CapabilityPtr, CapabilityRights},
+    message::SyscallResult,
+    syscalls::mem::query_memory_capability,
+};
+use std::ipc::IpcChannel;
+
+/// Name of the notification `init` sends to services which have to finish up
+/// before the system powers off
+pub const SHUTDOWN: &str = "shutdown";
+
+json_rpc::rpc! {
+    pub service protocol {
+        /// Stop every service in the reverse order they were started, then
+        /// power off or reboot
+        fn shutdown(reboot: bool);
+        /// Tell `init` this service has finished handling a shutdown
+        /// notification and can be powered off
+        fn stopped();
+        /// The output `init` has kept for a service whose stdout goes to a
+        /// log, if it has one
+        fn read_log(service: String) -> Option<String>;
+        /// Hand `init` the initfs to load the rest of the services from, as
+        /// the first `len` bytes of the memory capability sent along with the
+        /// request, returning whether it was accepted. Only the service `init`
+        /// is waiting on for an initfs can provide one.
+        fn provide_initfs(len: usize) -> bool;
+        /// A read-only copy of the initfs the services were loaded from, as a
+        /// memory capability sent along with the reply, returning its length
+        fn initfs() -> Option<usize>;
+    }
+}
+
+/// Connection to `init` over the `parent` channel it gives the services it
+/// starts
+pub struct Init {
+    client: protocol::Client,
+}
+
+impl Init {
+    /// Connect to `init`, which is only reachable from the services it started
+    pub fn connect() -> Option<Self> {
+        let cptr = std::env::lookup_capability("parent")?;
+        Some(Self { client: protocol::Client::new(IpcChannel::new(cptr)) })
+    }
+
+    /// The channel `init` sends the [`SHUTDOWN`] notification over, for
+    /// services which wait on more than one channel
+    pub fn cptr(&self) -> CapabilityPtr {
+        self.client.channel().cptr()
+    }
+
+    pub fn shutdown(&mut self, reboot: bool) -> Result<(), RpcError> {
+        self.client.shutdown(reboot)
+    }
+
+    pub fn stopped(&mut self) -> Result<(), RpcError> {
+        self.client.stopped()
+    }
+
+    pub fn read_log(&mut self, service: &str) -> Result<Option<String>, RpcError> {
+        self.client.read_log(service.into())
+    }
+
+    /// Send `init` an initfs (a tar archive, which can be LZ4 compressed) held
+    /// in the first `len` bytes of the memory capability `image`
+    pub fn provide_initfs(&mut self, image: CapabilityPtr, len: usize) -> Result<bool, RpcError> {
+        self.client.attach_capabilities(&[Capability::new(image, CapabilityRights::READ | CapabilityRights::MAP)]);
+        self.client.provide_initfs(len)
+    }
+
+    /// Map a read-only copy of the initfs the services were loaded from, a
+    /// tar archive, for finding the binaries of programs to run
+    pub fn initfs(&mut self) -> Result<Option<&'static [u8]>, RpcError> {
+        let len = match self.client.initfs()? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+
+        let image = self.client.take_capabilities().first().map(|cap| query_memory_capability(cap.cptr));
+        match image {
+            Some(SyscallResult::Ok((ptr, size, _))) if len <= size => {
+                Ok(Some(unsafe { core::slice::from_raw_parts(ptr, len) }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Read the next message `init` sent, returning whether it's asking this
+    /// service to shut down
+    pub fn read_shutdown(&mut self) -> Result<bool, RpcError> {
+        let (method, ()) = self.client.next_notification::<()>()?;
+        Ok(method == SHUTDOWN)
+    }
+}
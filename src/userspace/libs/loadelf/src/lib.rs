@@ -115,35 +115,37 @@ pub fn load_elf(name: &str, elf: &Elf) -> Result<(Vmspace, VmspaceSpawnEnv), ()>
     }
 
     let tls = elf.program_headers().find(|header| header.r#type == elf64::ProgramSegmentType::Tls).map(|header| {
+        // The thread pointer must be aligned for the TLS block
+        let tls_align = usize::max(header.align as usize, 8);
+        // This represents the fact that the first 8 bytes represent the
+        // Thread Control Block of the TLS, which is necessary for
+        // `__tls_get_addr`, and the following 16 represent the dynamic thread
+        // vector (dtv) entries which are composed of a generation and an array
+        // of modules which contain pointers to the various TLS blocks, but
+        // seeing as how we don't have module loading of any kind, we should
+        // just need a single pointer
+        let tp_offset = round_up_to_next(8 + 16, tls_align);
+
         let mut tls_base = vmspace
             .create_object(
                 core::ptr::null(),
-                header.memory_size as usize
-                // This +8 represents the fact that the first 8 bytes represent
-                // the Thread Control Block of the TLS, which is necessary for
-                // `__tls_get_addr`
-                + 8
-                // This +16 represents the dynamic thread vector (dtv) entries
-                // which are composed of a generation and an array of modules
-                // which contain pointers to the various TLS blocks, but seeing
-                // as how we don't have module loading of any kind, we should
-                // just need a single pointer
-                + 16,
+                tp_offset + header.memory_size as usize,
                 MemoryPermissions::READ | MemoryPermissions::WRITE,
             )
             .unwrap();
 
         let segment_file_size = header.file_size as usize;
         let tls_base_addr = tls_base.vmspace_address() as usize;
+        let tp = tls_base_addr + tp_offset;
         let data = tls_base.as_slice();
-        data[0..][..8].copy_from_slice(&(tls_base_addr + 8).to_le_bytes()[..]);
-        data[8..][..8].copy_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]);
-        data[16..][..8].copy_from_slice(&(tls_base_addr + 24).to_le_bytes()[..]);
+        data.fill(0);
+        data[tp_offset - 24..][..8].copy_from_slice(&(tp - 16).to_le_bytes()[..]);
+        data[tp_offset - 16..][..8].copy_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]);
+        data[tp_offset - 8..][..8].copy_from_slice(&tp.to_le_bytes()[..]);
 
-        data[24..][..segment_file_size].copy_from_slice(elf.program_segment_data(&header));
-        data[segment_file_size..].fill(0);
+        data[tp_offset..][..segment_file_size].copy_from_slice(elf.program_segment_data(&header));
 
-        tls_base_addr + 24
+        (tp, header.memory_size as usize, tls_align)
     });
 
     let sp = vmspace
@@ -151,7 +153,9 @@ pub fn load_elf(name: &str, elf: &Elf) -> Result<(Vmspace, VmspaceSpawnEnv), ()>
         .unwrap();
     let sp = sp.vmspace_address() as usize + 16 * PAGE_SIZE;
 
-    Ok((vmspace, VmspaceSpawnEnv { pc, a0: 0, a1: 0, a2: 0, tp: tls.unwrap_or(0), sp }))
+    let (tp, tls_size, tls_align) = tls.unwrap_or((0, 0, 0));
+
+    Ok((vmspace, VmspaceSpawnEnv { pc, a0: 0, a1: 0, a2: 0, sp, tp, tls_size, tls_align }))
 }
 
 pub fn round_up_to_next(n: usize, size: usize) -> usize {
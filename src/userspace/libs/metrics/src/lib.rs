@@ -0,0 +1,76 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use json_rpc::RpcError;
+use std::ipc::IpcChannel;
+
+/// Prefix of the metrics `metricsmgr` reads from the kernel
+pub const KERNEL_PREFIX: &str = "kernel.";
+
+json_rpc::rpc! {
+    pub service protocol {
+        /// Register a counter, which only ever increases
+        fn register_counter(name: String);
+        /// Register a gauge, which can be set to any value
+        fn register_gauge(name: String);
+        /// Add `delta` to a counter
+        fn add(name: String, delta: u64);
+        /// Set the value of a gauge
+        fn set(name: String, value: u64);
+        /// The current value of every metric starting with `prefix`, ordered
+        /// by name
+        fn query(prefix: String) -> Vec<Sample>;
+    }
+}
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    pub struct Sample {
+        pub name: String,
+        /// Either `counter` or `gauge`
+        pub kind: String,
+        pub value: u64,
+    }
+}
+
+/// Connection to the `metricsmgr` service
+pub struct Metrics {
+    client: protocol::Client,
+}
+
+impl Metrics {
+    /// Connect to `metricsmgr` if the current task was given a capability to
+    /// it
+    pub fn connect() -> Option<Self> {
+        let cptr = std::env::lookup_capability("metricsmgr")?;
+        Some(Self { client: protocol::Client::new(IpcChannel::new(cptr)) })
+    }
+
+    pub fn register_counter(&mut self, name: &str) -> Result<(), RpcError> {
+        self.client.register_counter(name.into())
+    }
+
+    pub fn register_gauge(&mut self, name: &str) -> Result<(), RpcError> {
+        self.client.register_gauge(name.into())
+    }
+
+    pub fn add(&mut self, name: &str, delta: u64) -> Result<(), RpcError> {
+        self.client.add(name.into(), delta)
+    }
+
+    pub fn increment(&mut self, name: &str) -> Result<(), RpcError> {
+        self.add(name, 1)
+    }
+
+    pub fn set(&mut self, name: &str, value: u64) -> Result<(), RpcError> {
+        self.client.set(name.into(), value)
+    }
+
+    pub fn query(&mut self, prefix: &str) -> Result<Vec<Sample>, RpcError> {
+        self.client.query(prefix.into())
+    }
+}
@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Running blocking code alongside async tasks. [`spawn`] and [`run`] run a
+//! closure on a [`Fiber`], and from inside it [`wait`] waits for a future by
+//! suspending the fiber until the future is ready. Other tasks keep running in
+//! the meantime, instead of the whole thread blocking.
+
+use crate::join::JoinHandle;
+use core::{
+    cell::Cell,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use std::fiber::Fiber;
+
+/// The context of the task whose fiber is running, for [`wait`] to poll with
+#[thread_local]
+static CONTEXT: Cell<*mut Context<'static>> = Cell::new(core::ptr::null_mut());
+
+/// A future which runs a closure on a fiber, returned by [`run`]
+pub struct FiberTask<T> {
+    fiber: Fiber<T>,
+}
+
+// The executor polls every task on the thread running it, so the fiber is
+// never resumed on a different thread than it was created on
+unsafe impl<T: Send> Send for FiberTask<T> {}
+unsafe impl<T: Send> Sync for FiberTask<T> {}
+
+impl<T> Future for FiberTask<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // `wait` only uses the context while the fiber is being resumed here
+        let previous = CONTEXT.replace(cx as *mut Context<'_> as *mut Context<'static>);
+        let result = self.fiber.resume();
+        CONTEXT.set(previous);
+
+        result
+    }
+}
+
+/// Run `f` on a fiber, as a future which finishes with what `f` returns
+pub fn run<F, T>(f: F) -> FiberTask<T>
+where
+    F: FnOnce() -> T + 'static,
+    T: 'static,
+{
+    FiberTask { fiber: Fiber::new(f) }
+}
+
+/// Spawn `f` on a fiber as a new task
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    crate::spawn(run(f))
+}
+
+/// Wait for `future` to finish, suspending the current fiber so the executor
+/// can run other tasks until it's ready
+///
+/// Panics if not called from a fiber started by [`spawn`] or [`run`]
+pub fn wait<F: Future>(future: F) -> F::Output {
+    assert!(!CONTEXT.get().is_null(), "present::fiber::wait called outside of a fiber task");
+
+    // The future stays on the fiber's stack, which is never reused unless the
+    // fiber finishes
+    crate::pin!(future);
+    loop {
+        // Each resume may come from a different poll
+        let cx = unsafe { &mut *CONTEXT.get() };
+        match future.as_mut().poll(cx) {
+            Poll::Ready(value) => return value,
+            Poll::Pending => std::fiber::yield_now(),
+        }
+    }
+}
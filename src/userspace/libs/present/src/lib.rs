@@ -7,6 +7,7 @@
 
 #![feature(const_btree_new, thread_local)]
 
+pub mod fiber;
 pub mod interrupt;
 pub mod ipc;
 pub mod join;
@@ -23,7 +23,7 @@ unsafe extern "C" fn _start(argc: isize, argv: *const *const u8) -> ! {
     ");
 
     main(argc, argv);
-    librust::syscalls::exit()
+    librust::syscalls::exit(0)
 }
 
 extern "C" {
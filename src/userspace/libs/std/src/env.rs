@@ -6,9 +6,18 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 use alloc::{collections::BTreeMap, string::String};
-use librust::capabilities::CapabilityPtr;
+use librust::{
+    capabilities::{Capability, CapabilityPtr, CapabilityRights},
+    error::KError,
+    message::SyscallResult,
+    syscalls::mem::query_memory_capability,
+};
 
-use crate::sync::SyncRefCell;
+use crate::{
+    ipc::IpcChannel,
+    registry::{self, Request, Response},
+    sync::SyncRefCell,
+};
 
 #[no_mangle]
 static mut ARGS: [usize; 2] = [0; 2];
@@ -39,3 +48,62 @@ pub fn lookup_capability(service: &str) -> Option<CapabilityPtr> {
 pub fn register_capability(service: &str, cptr: CapabilityPtr) {
     CAP_MAP.borrow_mut().insert(service.into(), cptr);
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistryError {
+    /// The task wasn't given a channel to the `registry` service
+    NoRegistry,
+    NotFound,
+    /// The registry didn't understand the request, e.g. for an empty name
+    Invalid,
+    Ipc(KError),
+}
+
+/// Register the current task with the `registry` service as `service`, so
+/// that other tasks can get a channel to it with [`lookup_service`]
+pub fn register_service(service: &str) -> Result<(), RegistryError> {
+    registry_request(Request::Register(service), &[]).map(drop)
+}
+
+/// Register the channel `cptr` with the `registry` service as `service`, which
+/// needs the channel to be transferable
+pub fn register_service_channel(service: &str, cptr: CapabilityPtr) -> Result<(), RegistryError> {
+    let rights = CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::TRANSFER;
+    registry_request(Request::Register(service), &[Capability::new(cptr, rights)]).map(drop)
+}
+
+/// Get a channel to `service`, which is one of the capabilities the task was
+/// spawned with if it has it, otherwise a new channel from the `registry`
+/// service that's remembered for the next lookup
+pub fn lookup_service(service: &str) -> Result<CapabilityPtr, RegistryError> {
+    if let Some(cptr) = lookup_capability(service) {
+        return Ok(cptr);
+    }
+
+    let cptr = registry_request(Request::Lookup(service), &[])?.ok_or(RegistryError::Invalid)?;
+    register_capability(service, cptr);
+
+    Ok(cptr)
+}
+
+fn registry_request(request: Request<'_>, caps: &[Capability]) -> Result<Option<CapabilityPtr>, RegistryError> {
+    let mut channel = IpcChannel::new(lookup_capability(registry::SERVICE_NAME).ok_or(RegistryError::NoRegistry)?);
+    channel.send_bytes(request.to_bytes(), caps).map_err(RegistryError::Ipc)?;
+
+    let mut cap = [Capability::default()];
+    let response = channel.read(&mut cap).map_err(RegistryError::Ipc)?;
+    match Response::parse(response.message.as_bytes()) {
+        Some(Response::Ok) => Ok((response.caps_read > 0).then(|| cap[0].cptr)),
+        Some(Response::NotFound) => Err(RegistryError::NotFound),
+        Some(Response::Invalid) | None => Err(RegistryError::Invalid),
+    }
+}
+
+/// Pointer to the system's flattened device tree, if the task was granted the
+/// read-only `fdt` capability
+pub fn device_tree() -> Option<*const u8> {
+    match query_memory_capability(lookup_capability("fdt")?) {
+        SyscallResult::Ok((ptr, _, _)) => Some(ptr as *const u8),
+        SyscallResult::Err(_) => None,
+    }
+}
@@ -0,0 +1,332 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Fibers, which run code on their own stack but are switched to and from
+//! explicitly on the current thread instead of being scheduled by the kernel.
+//! They're far cheaper than threads, so services can have one per client or
+//! request without tying up a kernel thread for each.
+//!
+//! A fiber runs when it's [resumed](Fiber::resume), until it finishes or calls
+//! [`yield_now`], which returns control to whoever resumed it. Fibers can
+//! resume other fibers, and always yield back to the one that resumed them.
+//! Blocking syscalls made from a fiber block the whole thread, so code which
+//! needs to wait should run on a fiber driven by `present`, which can suspend
+//! it until what it's waiting on is ready.
+//!
+//! Each stack has a read-only guard page below it, so overflowing the stack
+//! faults instead of overwriting whatever memory comes before it.
+
+use alloc::{boxed::Box, rc::Rc, vec::Vec};
+use core::{cell::Cell, task::Poll};
+use librust::{
+    error::KError,
+    message::SyscallResult,
+    syscalls::{
+        allocation::{alloc_virtual_memory, AllocationOptions, MemoryPermissions},
+        mem::set_memory_permissions,
+    },
+};
+use sync::SpinMutex;
+
+/// Size of the stack allocated for each fiber, not counting its guard page
+pub const STACK_SIZE: usize = 64 * 1024;
+
+const PAGE_SIZE: usize = 4096;
+
+/// Stacks of fibers which have finished, kept to be reused since memory can't
+/// be freed
+static FREE_STACKS: SpinMutex<Vec<Stack>> = SpinMutex::new(Vec::new());
+
+/// The fiber running on the current thread, if any
+#[thread_local]
+static CURRENT: Cell<*mut Inner> = Cell::new(core::ptr::null_mut());
+
+/// A function running on its own stack, see the [module level
+/// documentation](self)
+pub struct Fiber<T> {
+    inner: Box<Inner>,
+    result: Rc<Cell<Option<T>>>,
+}
+
+impl<T: 'static> Fiber<T> {
+    /// Create a fiber which runs `f` once it's first resumed
+    pub fn new<F>(f: F) -> Self
+    where
+        F: FnOnce() -> T + 'static,
+    {
+        Self::with_stack_size(STACK_SIZE, f)
+    }
+
+    /// Create a fiber with a stack of at least `size` bytes, which runs `f`
+    /// once it's first resumed
+    pub fn with_stack_size<F>(size: usize, f: F) -> Self
+    where
+        F: FnOnce() -> T + 'static,
+    {
+        let stack = match Stack::new(size) {
+            Ok(stack) => stack,
+            Err(e) => panic!("failed to allocate fiber stack: {:?}", e),
+        };
+
+        let result = Rc::new(Cell::new(None));
+        let their_result = Rc::clone(&result);
+        let main: Box<dyn FnOnce()> = Box::new(move || their_result.set(Some(f())));
+
+        let mut inner = Box::new(Inner {
+            registers: Registers::default(),
+            resumer: Registers::default(),
+            main: Some(main),
+            finished: false,
+            parent: core::ptr::null_mut(),
+            stack: None,
+        });
+
+        // The first switch to the fiber "returns" to `fiber_entry` with the
+        // fiber in `s0`, which it passes on to `fiber_main`
+        inner.registers.ra = fiber_entry as usize;
+        inner.registers.sp = stack.top() as usize;
+        inner.registers.gp = stack.gp();
+        inner.registers.s[0] = &mut *inner as *mut Inner as usize;
+        inner.stack = Some(stack);
+
+        Self { inner, result }
+    }
+}
+
+impl<T> Fiber<T> {
+    /// Run the fiber until it yields or finishes, returning what it returned
+    /// once it has finished
+    ///
+    /// Panics if the fiber has already finished
+    pub fn resume(&mut self) -> Poll<T> {
+        assert!(!self.inner.finished, "resumed a fiber which already finished");
+
+        let inner: *mut Inner = &mut *self.inner;
+        unsafe {
+            (*inner).parent = CURRENT.replace(inner);
+            switch(&mut (*inner).resumer, &(*inner).registers);
+            CURRENT.set((*inner).parent);
+        }
+
+        match self.inner.finished {
+            true => Poll::Ready(self.result.take().unwrap()),
+            false => Poll::Pending,
+        }
+    }
+
+    /// Whether the fiber has finished running
+    pub fn is_finished(&self) -> bool {
+        self.inner.finished
+    }
+}
+
+impl<T> Drop for Fiber<T> {
+    fn drop(&mut self) {
+        // Anything still on the stack of an unfinished fiber is leaked, since
+        // something may point to it
+        if let (true, Some(stack)) = (self.inner.finished, self.inner.stack.take()) {
+            FREE_STACKS.lock().push(stack);
+        }
+    }
+}
+
+impl<T> core::fmt::Debug for Fiber<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Fiber").field("finished", &self.inner.finished).finish_non_exhaustive()
+    }
+}
+
+/// Suspend the current fiber, returning from the [`Fiber::resume`] call which
+/// ran it. The fiber carries on from here when it's next resumed.
+///
+/// Panics if not called from a fiber
+pub fn yield_now() {
+    let inner = CURRENT.get();
+    assert!(!inner.is_null(), "yield_now called outside of a fiber");
+
+    unsafe { switch(&mut (*inner).registers, &(*inner).resumer) };
+}
+
+/// Whether the current code is running on a fiber
+pub fn in_fiber() -> bool {
+    !CURRENT.get().is_null()
+}
+
+struct Inner {
+    /// The fiber's registers while it's suspended
+    registers: Registers,
+    /// The registers of whoever resumed the fiber while it's running
+    resumer: Registers,
+    main: Option<Box<dyn FnOnce()>>,
+    finished: bool,
+    /// The fiber which was running when this one was resumed
+    parent: *mut Inner,
+    stack: Option<Stack>,
+}
+
+/// The registers preserved across function calls, which are all that need to
+/// be saved since fibers are only ever switched by calling [`switch`]
+#[derive(Default)]
+#[repr(C)]
+struct Registers {
+    ra: usize,
+    sp: usize,
+    /// The shadow call stack pointer, when enabled
+    gp: usize,
+    s: [usize; 12],
+    fs: [u64; 12],
+}
+
+/// Save the current registers to `from` and load the ones in `to`, returning
+/// to wherever `to` was saved from
+#[naked]
+unsafe extern "C" fn switch(_from: *mut Registers, _to: *const Registers) {
+    #[rustfmt::skip]
+    core::arch::asm!("
+        sd ra, 0(a0)
+        sd sp, 8(a0)
+        sd gp, 16(a0)
+        sd s0, 24(a0)
+        sd s1, 32(a0)
+        sd s2, 40(a0)
+        sd s3, 48(a0)
+        sd s4, 56(a0)
+        sd s5, 64(a0)
+        sd s6, 72(a0)
+        sd s7, 80(a0)
+        sd s8, 88(a0)
+        sd s9, 96(a0)
+        sd s10, 104(a0)
+        sd s11, 112(a0)
+        fsd fs0, 120(a0)
+        fsd fs1, 128(a0)
+        fsd fs2, 136(a0)
+        fsd fs3, 144(a0)
+        fsd fs4, 152(a0)
+        fsd fs5, 160(a0)
+        fsd fs6, 168(a0)
+        fsd fs7, 176(a0)
+        fsd fs8, 184(a0)
+        fsd fs9, 192(a0)
+        fsd fs10, 200(a0)
+        fsd fs11, 208(a0)
+
+        ld ra, 0(a1)
+        ld sp, 8(a1)
+        ld gp, 16(a1)
+        ld s0, 24(a1)
+        ld s1, 32(a1)
+        ld s2, 40(a1)
+        ld s3, 48(a1)
+        ld s4, 56(a1)
+        ld s5, 64(a1)
+        ld s6, 72(a1)
+        ld s7, 80(a1)
+        ld s8, 88(a1)
+        ld s9, 96(a1)
+        ld s10, 104(a1)
+        ld s11, 112(a1)
+        fld fs0, 120(a1)
+        fld fs1, 128(a1)
+        fld fs2, 136(a1)
+        fld fs3, 144(a1)
+        fld fs4, 152(a1)
+        fld fs5, 160(a1)
+        fld fs6, 168(a1)
+        fld fs7, 176(a1)
+        fld fs8, 184(a1)
+        fld fs9, 192(a1)
+        fld fs10, 200(a1)
+        fld fs11, 208(a1)
+        ret
+    ", options(noreturn));
+}
+
+#[naked]
+unsafe extern "C" fn fiber_entry() -> ! {
+    #[rustfmt::skip]
+    core::arch::asm!("
+        mv a0, s0
+        j {}
+    ", sym fiber_main, options(noreturn));
+}
+
+extern "C" fn fiber_main(inner: *mut Inner) -> ! {
+    unsafe {
+        let main = (*inner).main.take().unwrap();
+        main();
+
+        (*inner).finished = true;
+        switch(&mut (*inner).registers, &(*inner).resumer);
+    }
+
+    unreachable!("resumed a fiber which already finished")
+}
+
+/// A fiber's stack, with a read-only guard page below it
+struct Stack {
+    /// Start of the guard page
+    base: *mut u8,
+    size: usize,
+    #[cfg(feature = "shadow-call-stack")]
+    shadow_stack: *mut u8,
+}
+
+// Stacks are only shared once their fiber has finished
+unsafe impl Send for Stack {}
+
+impl Stack {
+    fn new(size: usize) -> Result<Self, KError> {
+        let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+        let mut free = FREE_STACKS.lock();
+        if let Some(i) = free.iter().position(|stack| stack.size == size) {
+            return Ok(free.swap_remove(i));
+        }
+        drop(free);
+
+        let base = match alloc_virtual_memory(
+            PAGE_SIZE + size,
+            AllocationOptions::None,
+            MemoryPermissions::READ | MemoryPermissions::WRITE,
+        ) {
+            SyscallResult::Ok(base) => base,
+            SyscallResult::Err(e) => return Err(e),
+        };
+
+        // Memory is always readable, so the guard page only catches writes,
+        // which is enough since overflowing functions store to their frame
+        if let SyscallResult::Err(e) = set_memory_permissions(base, PAGE_SIZE, MemoryPermissions::READ) {
+            return Err(e);
+        }
+
+        Ok(Self {
+            base,
+            size,
+            #[cfg(feature = "shadow-call-stack")]
+            shadow_stack: crate::stack_protection::alloc_shadow_stack(),
+        })
+    }
+
+    fn top(&self) -> *mut u8 {
+        unsafe { self.base.add(PAGE_SIZE + self.size) }
+    }
+
+    /// What `gp` should be while running on this stack
+    fn gp(&self) -> usize {
+        #[cfg(feature = "shadow-call-stack")]
+        return self.shadow_stack as usize;
+
+        // Otherwise it's the global pointer, which is the same everywhere
+        #[cfg(not(feature = "shadow-call-stack"))]
+        {
+            let gp: usize;
+            unsafe { core::arch::asm!("mv {}, gp", out(reg) gp) };
+            gp
+        }
+    }
+}
@@ -5,16 +5,232 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-pub(crate) struct Stdout;
+//! Blocking byte streams: the console, and pipes which connect the output of
+//! one task to the input of another
+
+use alloc::vec::Vec;
+use librust::{
+    capabilities::CapabilityPtr,
+    error::KError,
+    message::SyscallResult,
+    syscalls::{
+        io::{close_console_stream, create_pipe, read_console_stream, write_console_stream},
+        poll::{wait_many, WaitEvents, WaitItem, NO_TIMEOUT},
+        read_stdin,
+    },
+};
+
+/// How long [`Stdin`] sleeps before checking for input again, since console
+/// input can't be waited on
+const STDIN_POLL_US: usize = 1000;
+
+pub trait Read {
+    /// Read into `buffer`, blocking until at least one byte is available, and
+    /// return how many bytes were read. Zero means the stream has ended.
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, KError>;
+
+    /// Read until the end of the stream, appending to `buffer` and returning
+    /// how many bytes were read
+    fn read_to_end(&mut self, buffer: &mut Vec<u8>) -> Result<usize, KError> {
+        let mut chunk = [0; 256];
+        let mut total = 0;
+
+        loop {
+            match self.read(&mut chunk)? {
+                0 => return Ok(total),
+                n => {
+                    buffer.extend_from_slice(&chunk[..n]);
+                    total += n;
+                }
+            }
+        }
+    }
+
+    /// Fill the whole of `buffer`, failing if the stream ends first
+    fn read_exact(&mut self, mut buffer: &mut [u8]) -> Result<(), KError> {
+        while !buffer.is_empty() {
+            match self.read(buffer)? {
+                0 => return Err(KError::InvalidArgument(0)),
+                n => buffer = &mut buffer[n..],
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub trait Write {
+    /// Write as much of `bytes` as possible, blocking until there's room for
+    /// at least one byte, and return how many bytes were written. Zero means
+    /// nothing is left to read them.
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, KError>;
+
+    /// Write all of `bytes`, failing if the other end goes away first
+    fn write_all(&mut self, mut bytes: &[u8]) -> Result<(), KError> {
+        while !bytes.is_empty() {
+            match self.write(bytes)? {
+                0 => return Err(KError::InvalidArgument(0)),
+                n => bytes = &bytes[n..],
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), KError> {
+        Ok(())
+    }
+}
+
+/// The task's stdin, which is the console unless it's been bound to a pipe
+#[derive(Debug, Clone, Copy)]
+pub struct Stdin;
+
+pub fn stdin() -> Stdin {
+    Stdin
+}
+
+impl Read for Stdin {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, KError> {
+        loop {
+            match read_stdin(buffer) {
+                SyscallResult::Ok(n) => return Ok(n),
+                SyscallResult::Err(KError::WouldBlock) => {
+                    let _ = wait_many(&mut [], STDIN_POLL_US);
+                }
+                SyscallResult::Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// The task's stdout, which is the `stdio` server if the task was given a
+/// channel to it, or whatever the kernel has it bound to otherwise
+#[derive(Debug, Clone, Copy)]
+pub struct Stdout;
+
+pub fn stdout() -> Stdout {
+    Stdout
+}
+
+impl Stdout {
+    fn write_bytes(&self, bytes: &[u8]) {
+        // Tasks without a channel to the `stdio` server write to whatever
+        // their stdout is bound to instead, which is how their output gets
+        // redirected
+        match crate::env::lookup_capability("stdio") {
+            Some(stdio) => {
+                let _ = crate::ipc::IpcChannel::new(stdio).send_bytes(bytes, &[]);
+            }
+            None => {
+                let _ = librust::syscalls::print(bytes);
+            }
+        }
+    }
+}
 
 impl core::fmt::Write for Stdout {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        let mut stdio = match crate::env::lookup_capability("stdio") {
-            Some(stdio) => crate::ipc::IpcChannel::new(stdio),
-            None => return Ok(()),
-        };
-        let _ = stdio.send_bytes(s, &[]);
-        // let _ = librust::syscalls::print(s.as_bytes());
+        self.write_bytes(s.as_bytes());
         Ok(())
     }
 }
+
+impl Write for Stdout {
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, KError> {
+        self.write_bytes(bytes);
+        Ok(bytes.len())
+    }
+}
+
+/// Create a pipe, returning its read end and its write end. Either can be
+/// bound to the stdin or stdout of a task being spawned, after which this
+/// task's copy can be dropped so that the other end sees it close when that
+/// task exits.
+pub fn pipe() -> Result<(PipeReader, PipeWriter), KError> {
+    match create_pipe() {
+        SyscallResult::Ok((reader, writer)) => Ok((PipeReader { cptr: reader }, PipeWriter { cptr: writer })),
+        SyscallResult::Err(e) => Err(e),
+    }
+}
+
+/// The read end of a pipe, which is closed when dropped
+#[derive(Debug)]
+pub struct PipeReader {
+    cptr: CapabilityPtr,
+}
+
+impl PipeReader {
+    /// Take ownership of the read end of a pipe, e.g. one received from
+    /// another task
+    pub fn new(cptr: CapabilityPtr) -> Self {
+        Self { cptr }
+    }
+
+    pub fn cptr(&self) -> CapabilityPtr {
+        self.cptr
+    }
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, KError> {
+        loop {
+            match read_console_stream(self.cptr, buffer) {
+                SyscallResult::Ok(n) => return Ok(n),
+                SyscallResult::Err(KError::WouldBlock) => wait(self.cptr, WaitEvents::READABLE)?,
+                SyscallResult::Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        let _ = close_console_stream(self.cptr);
+    }
+}
+
+/// The write end of a pipe, which is closed when dropped
+#[derive(Debug)]
+pub struct PipeWriter {
+    cptr: CapabilityPtr,
+}
+
+impl PipeWriter {
+    /// Take ownership of the write end of a pipe, e.g. one received from
+    /// another task
+    pub fn new(cptr: CapabilityPtr) -> Self {
+        Self { cptr }
+    }
+
+    pub fn cptr(&self) -> CapabilityPtr {
+        self.cptr
+    }
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, KError> {
+        loop {
+            match write_console_stream(self.cptr, bytes) {
+                SyscallResult::Ok(n) => return Ok(n),
+                SyscallResult::Err(KError::WouldBlock) => wait(self.cptr, WaitEvents::WRITABLE)?,
+                SyscallResult::Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        let _ = close_console_stream(self.cptr);
+    }
+}
+
+/// Block until `cptr` is ready for `interest`, or its other end has closed, in
+/// which case trying again reports it
+fn wait(cptr: CapabilityPtr, interest: WaitEvents) -> Result<(), KError> {
+    match wait_many(&mut [WaitItem::new(cptr, interest)], NO_TIMEOUT) {
+        SyscallResult::Ok(_) => Ok(()),
+        SyscallResult::Err(e) => Err(e),
+    }
+}
@@ -5,11 +5,15 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+use core::sync::atomic::{AtomicUsize, Ordering};
 use librust::{
-    capabilities::{Capability, CapabilityPtr},
+    capabilities::{Capability, CapabilityPtr, CapabilityRights},
     error::KError,
-    message::SyscallResult,
-    syscalls::channel::{self, ChannelMessage},
+    message::{SyscallResult, CALL_PAYLOAD_LEN},
+    syscalls::{
+        channel::{self, ChannelMessage},
+        poll::{wait_many, WaitEvents, WaitItem, NO_TIMEOUT},
+    },
 };
 
 #[derive(Debug)]
@@ -22,6 +26,10 @@ impl IpcChannel {
         Self { cptr }
     }
 
+    pub fn cptr(&self) -> CapabilityPtr {
+        self.cptr
+    }
+
     // FIXME: use a real error
     #[allow(clippy::result_unit_err)]
     pub fn new_message(&mut self, size: usize) -> Result<NewMessage<'_>, KError> {
@@ -40,6 +48,15 @@ impl IpcChannel {
         chan_msg.send(caps)
     }
 
+    /// Like [`IpcChannel::send_bytes`], but waits for room in the other end's
+    /// queue on the thread's [`task`](crate::task) executor
+    pub async fn send_bytes_async<T: AsRef<[u8]>>(&mut self, msg: T, caps: &[Capability]) -> Result<(), KError> {
+        let msg = msg.as_ref();
+        let mut chan_msg = self.new_message(msg.len())?;
+        chan_msg.write(msg);
+        chan_msg.send_async(caps).await
+    }
+
     // FIXME: use a real error
     #[allow(clippy::result_unit_err)]
     pub fn read(&self, cap_buffer: &mut [Capability]) -> Result<ReadChannelMessage, KError> {
@@ -51,6 +68,25 @@ impl IpcChannel {
         }
     }
 
+    /// Like [`IpcChannel::read`], but waits for a message on the thread's
+    /// [`task`](crate::task) executor instead of blocking the whole thread
+    pub async fn read_async(&self, cap_buffer: &mut [Capability]) -> Result<ReadChannelMessage, KError> {
+        loop {
+            match channel::read_message_non_blocking(self.cptr, cap_buffer) {
+                SyscallResult::Ok(Some((m, caps_read, caps_left))) => {
+                    return Ok(ReadChannelMessage { message: Message(self.cptr, m), caps_read, caps_left })
+                }
+                SyscallResult::Ok(None) => {
+                    let ready = crate::task::readable(self.cptr).await?;
+                    if ready & WaitEvents::CLOSED && !(ready & WaitEvents::READABLE) {
+                        return Err(KError::InvalidArgument(0));
+                    }
+                }
+                SyscallResult::Err(e) => return Err(e),
+            }
+        }
+    }
+
     pub fn read_with_all_caps(&self) -> Result<(Message, Vec<Capability>), KError> {
         let mut caps = Vec::new();
         let ReadChannelMessage { message, caps_left, .. } = self.read(&mut caps[..])?;
@@ -63,13 +99,79 @@ impl IpcChannel {
         Ok((message, caps))
     }
 
+    /// Mint a copy of this channel's capability with the given badge, which is
+    /// delivered with each message sent through the copy. Once sent to another
+    /// task, messages sent through the copy arrive at this end, so handing a
+    /// different badged copy to each client lets it tell its clients apart
+    /// without a channel per client. Clients are replied to by making calls
+    /// with [`Self::call`].
+    pub fn mint_badged(&self, rights: CapabilityRights, badge: usize) -> Result<CapabilityPtr, KError> {
+        match channel::mint_badged(self.cptr, rights, badge) {
+            SyscallResult::Ok(cptr) => Ok(cptr),
+            SyscallResult::Err(e) => Err(e),
+        }
+    }
+
+    /// Make a call to the other end of the channel, blocking until it replies
+    /// with [`librust::syscalls::channel::reply`]
+    pub fn call(&self, payload: [usize; CALL_PAYLOAD_LEN]) -> Result<[usize; CALL_PAYLOAD_LEN], KError> {
+        match channel::call(self.cptr, payload) {
+            SyscallResult::Ok(reply) => Ok(reply),
+            SyscallResult::Err(e) => Err(e),
+        }
+    }
+
+    /// Map the ring buffer shared with the other end of the channel, creating
+    /// it with `size` bytes if the other end hasn't mapped it yet
+    pub fn ring(&self, size: usize) -> Result<RingBuffer, KError> {
+        match channel::map_ring(self.cptr, size) {
+            SyscallResult::Ok(ring) => Ok(unsafe { RingBuffer::new(self.cptr, ring) }),
+            SyscallResult::Err(e) => Err(e),
+        }
+    }
+
+    /// Limit how many messages can be waiting to be read from this end of the
+    /// channel, up to [`channel::MAX_QUEUE_DEPTH`]. Senders block once the
+    /// queue is full, until messages have been read.
+    pub fn set_queue_depth(&self, depth: usize) -> Result<(), KError> {
+        match channel::set_queue_depth(self.cptr, depth) {
+            SyscallResult::Ok(()) => Ok(()),
+            SyscallResult::Err(e) => Err(e),
+        }
+    }
+
     fn send(&mut self, msg: ChannelMessage, written_len: usize, caps: &[Capability]) -> Result<(), KError> {
-        if let SyscallResult::Err(e) = channel::send_message(self.cptr, msg.id, written_len, caps) {
-            return Err(e);
+        loop {
+            match channel::send_message(self.cptr, msg.id, written_len, caps) {
+                SyscallResult::Ok(()) => return Ok(()),
+                // The other end's queue is full, so wait for it to read some
+                SyscallResult::Err(KError::WouldBlock) => {
+                    let mut items = [WaitItem::new(self.cptr, WaitEvents::WRITABLE)];
+                    if let SyscallResult::Err(e) = wait_many(&mut items, NO_TIMEOUT) {
+                        return Err(e);
+                    }
+
+                    if items[0].ready & WaitEvents::CLOSED {
+                        return Err(KError::InvalidArgument(0));
+                    }
+                }
+                SyscallResult::Err(e) => return Err(e),
+            }
         }
+    }
 
-        // FIXME: check for failure
-        Ok(())
+    async fn send_async(&mut self, msg: ChannelMessage, written_len: usize, caps: &[Capability]) -> Result<(), KError> {
+        loop {
+            match channel::send_message(self.cptr, msg.id, written_len, caps) {
+                SyscallResult::Ok(()) => return Ok(()),
+                SyscallResult::Err(KError::WouldBlock) => {
+                    if crate::task::writable(self.cptr).await? & WaitEvents::CLOSED {
+                        return Err(KError::InvalidArgument(0));
+                    }
+                }
+                SyscallResult::Err(e) => return Err(e),
+            }
+        }
     }
 }
 
@@ -94,6 +196,12 @@ impl Message {
             &[]
         }
     }
+
+    /// The badge of the capability this message was sent through, or `0` if
+    /// it was unbadged
+    pub fn badge(&self) -> usize {
+        self.1.badge
+    }
 }
 
 impl core::fmt::Debug for Message {
@@ -119,8 +227,12 @@ impl NewMessage<'_> {
         self.channel.send(self.message, self.cursor, caps)
     }
 
+    pub async fn send_async(self, caps: &[Capability]) -> Result<(), KError> {
+        self.channel.send_async(self.message, self.cursor, caps).await
+    }
+
     pub fn write(&mut self, buffer: &[u8]) {
-        assert!(self.cursor + buffer.len() < self.message.len);
+        assert!(self.cursor + buffer.len() <= self.message.len);
         let slice = unsafe {
             core::slice::from_raw_parts_mut(self.message.ptr.add(self.cursor), self.message.len - self.cursor)
         };
@@ -142,3 +254,153 @@ pub enum OpenChannelError {
 
 #[derive(Debug)]
 pub enum SendMessageError {}
+
+/// Bytes reserved at the start of each half of the ring for its [`RingHeader`]
+const RING_HEADER_SIZE: usize = 64;
+
+/// Positions are free-running byte counters, the producer only ever writes
+/// `head` and the consumer only ever writes `tail`
+#[repr(C)]
+struct RingHeader {
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+struct RingHalf {
+    header: *const RingHeader,
+    data: *mut u8,
+    capacity: usize,
+}
+
+impl RingHalf {
+    unsafe fn new(base: *mut u8, len: usize) -> Self {
+        Self { header: base.cast(), data: base.add(RING_HEADER_SIZE), capacity: len - RING_HEADER_SIZE }
+    }
+
+    fn header(&self) -> &RingHeader {
+        unsafe { &*self.header }
+    }
+
+    fn produce(&self, bytes: &[u8]) -> usize {
+        let head = self.header().head.load(Ordering::Relaxed);
+        let tail = self.header().tail.load(Ordering::Acquire);
+        let n = bytes.len().min(self.capacity - head.wrapping_sub(tail));
+
+        let start = head % self.capacity;
+        let first = n.min(self.capacity - start);
+        unsafe {
+            core::ptr::copy_nonoverlapping(bytes.as_ptr(), self.data.add(start), first);
+            core::ptr::copy_nonoverlapping(bytes.as_ptr().add(first), self.data, n - first);
+        }
+
+        self.header().head.store(head.wrapping_add(n), Ordering::Release);
+        n
+    }
+
+    fn consume(&self, buffer: &mut [u8]) -> usize {
+        let tail = self.header().tail.load(Ordering::Relaxed);
+        let head = self.header().head.load(Ordering::Acquire);
+        let n = buffer.len().min(head.wrapping_sub(tail));
+
+        let start = tail % self.capacity;
+        let first = n.min(self.capacity - start);
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.data.add(start), buffer.as_mut_ptr(), first);
+            core::ptr::copy_nonoverlapping(self.data, buffer.as_mut_ptr().add(first), n - first);
+        }
+
+        self.header().tail.store(tail.wrapping_add(n), Ordering::Release);
+        n
+    }
+}
+
+/// A pair of single-producer single-consumer byte rings living in memory
+/// shared with the other end of an [`IpcChannel`]. Data is copied straight
+/// into the other task's view of the ring, the kernel is only involved to
+/// wake up the other end when there's something for it to do.
+pub struct RingBuffer {
+    cptr: CapabilityPtr,
+    tx: RingHalf,
+    rx: RingHalf,
+}
+
+impl RingBuffer {
+    /// # Safety
+    ///
+    /// `ring` must be a ring mapped for the channel `cptr`, which isn't in use
+    /// by any other [`RingBuffer`] in this task
+    pub unsafe fn new(cptr: CapabilityPtr, ring: channel::ChannelRing) -> Self {
+        let half = ring.len / 2;
+        let (ours, theirs) = match ring.side {
+            0 => (ring.ptr, ring.ptr.add(half)),
+            _ => (ring.ptr.add(half), ring.ptr),
+        };
+
+        Self { cptr, tx: RingHalf::new(ours, half), rx: RingHalf::new(theirs, half) }
+    }
+
+    /// Write as much of `bytes` as currently fits, returning the number of
+    /// bytes written
+    pub fn try_write(&mut self, bytes: &[u8]) -> Result<usize, KError> {
+        let n = self.tx.produce(bytes);
+        if n > 0 {
+            self.notify()?;
+        }
+
+        Ok(n)
+    }
+
+    /// Write all of `bytes`, waiting for the other end to make room as needed
+    pub fn write_all(&mut self, mut bytes: &[u8]) -> Result<(), KError> {
+        while !bytes.is_empty() {
+            match self.try_write(bytes)? {
+                0 => self.wait()?,
+                n => bytes = &bytes[n..],
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read whatever is available without blocking, returning the number of
+    /// bytes read
+    pub fn try_read(&mut self, buffer: &mut [u8]) -> Result<usize, KError> {
+        let n = self.rx.consume(buffer);
+        if n > 0 {
+            self.notify()?;
+        }
+
+        Ok(n)
+    }
+
+    /// Read at least one byte, waiting for the other end to write if the ring
+    /// is empty
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, KError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            match self.try_read(buffer)? {
+                0 => self.wait()?,
+                n => return Ok(n),
+            }
+        }
+    }
+
+    fn notify(&self) -> Result<(), KError> {
+        match channel::notify_ring(self.cptr) {
+            SyscallResult::Ok(()) => Ok(()),
+            SyscallResult::Err(e) => Err(e),
+        }
+    }
+
+    fn wait(&self) -> Result<(), KError> {
+        match channel::wait_ring(self.cptr) {
+            SyscallResult::Ok(()) => Ok(()),
+            SyscallResult::Err(e) => Err(e),
+        }
+    }
+}
+
+unsafe impl Send for RingBuffer {}
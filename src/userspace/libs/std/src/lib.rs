@@ -8,10 +8,13 @@
 #![feature(
     allocator_api,
     alloc_error_handler,
+    allow_internal_unstable,
     const_btree_new,
     extern_types,
     inline_const,
     lang_items,
+    naked_functions,
+    no_sanitize,
     prelude_import,
     thread_local
 )]
@@ -24,15 +27,20 @@ extern crate alloc;
 extern crate rt0;
 
 pub mod env;
+pub mod fiber;
 pub mod heap;
 pub mod io;
 pub mod ipc;
 pub mod prelude;
+pub mod process;
 pub mod rc;
+pub mod registry;
 pub mod rt;
+mod stack_protection;
 pub mod sync;
 pub mod task;
 mod task_local;
+pub mod thread;
 pub mod vmspace;
 
 pub use alloc::collections;
@@ -73,7 +81,7 @@ pub fn _print(args: core::fmt::Arguments) {
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     println!("PANIC: {}", info);
-    librust::syscalls::exit()
+    process::exit(process::PANIC_EXIT_CODE)
 }
 
 #[alloc_error_handler]
@@ -6,7 +6,7 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 pub mod rust_2021 {
-    pub use crate::{dbg, print, println};
+    pub use crate::{dbg, print, println, thread_local};
     pub use alloc::{
         boxed::Box,
         collections::VecDeque,
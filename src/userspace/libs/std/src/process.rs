@@ -0,0 +1,35 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use librust::{capabilities::CapabilityPtr, error::KError, message::SyscallResult, syscalls::task};
+
+pub use librust::task::ExitStatus;
+
+/// Exit code used when a task panics, same as the Rust standard library
+pub const PANIC_EXIT_CODE: usize = 101;
+
+pub fn exit(code: usize) -> ! {
+    librust::syscalls::exit(code)
+}
+
+/// Block until the task on the other end of the channel `task`, such as one
+/// returned from [`crate::vmspace::Vmspace::spawn`], exits
+pub fn wait(task: CapabilityPtr) -> Result<ExitStatus, KError> {
+    match task::wait(task) {
+        SyscallResult::Ok(status) => Ok(status),
+        SyscallResult::Err(e) => Err(e),
+    }
+}
+
+/// Receive a [`librust::message::KernelNotification::TaskExited`] once the
+/// task on the other end of the channel `task` exits, instead of blocking
+pub fn watch_exit(task: CapabilityPtr) -> Result<(), KError> {
+    match task::watch_exit(task) {
+        SyscallResult::Ok(()) => Ok(()),
+        SyscallResult::Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The protocol spoken with the `registry` service, which keeps track of which
+//! channel each service name refers to so that services can be found after
+//! they've started, not only when a task is spawned. The client side is
+//! [`crate::env::register_service`] and [`crate::env::lookup_service`].
+//!
+//! Requests are text, one per message:
+//!
+//! * `register <name>` makes `<name>` refer to the channel attached to the
+//!   request, or to the sender if nothing is attached
+//! * `lookup <name>` asks for a new channel to whatever `<name>` refers to
+//!
+//! Each is answered with a [`Response`], with the channel attached when a
+//! lookup succeeds. Registering a name again replaces it, so a service which
+//! is restarted can register itself again.
+
+/// The name the registry is given to each task under
+pub const SERVICE_NAME: &str = "registry";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request<'a> {
+    Register(&'a str),
+    Lookup(&'a str),
+}
+
+impl<'a> Request<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Option<Self> {
+        match core::str::from_utf8(bytes).ok()?.split_once(' ')? {
+            (_, "") => None,
+            ("register", name) => Some(Self::Register(name)),
+            ("lookup", name) => Some(Self::Lookup(name)),
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        match self {
+            Self::Register(name) => format!("register {}", name).into_bytes(),
+            Self::Lookup(name) => format!("lookup {}", name).into_bytes(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Response {
+    Ok,
+    NotFound,
+    /// The request wasn't understood, e.g. it had an empty name
+    Invalid,
+}
+
+impl Response {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            b"ok" => Some(Self::Ok),
+            b"not found" => Some(Self::NotFound),
+            b"invalid" => Some(Self::Invalid),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::NotFound => "not found",
+            Self::Invalid => "invalid",
+        }
+    }
+}
@@ -6,21 +6,35 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 use crate::ipc::ReadChannelMessage;
-use librust::capabilities::Capability;
+use librust::{
+    capabilities::{Capability, CapabilityInfo, CapabilityKind, CapabilityPtr},
+    message::SyscallResult,
+    syscalls::capabilities::inspect_capability,
+};
 
 #[no_mangle]
+#[no_sanitize(shadow_call_stack)]
 unsafe extern "C" fn _start(argc: isize, argv: *const *const u8, a2: usize) -> ! {
     extern "C" {
         fn main(_: isize, _: *const *const u8) -> isize;
     }
 
+    #[cfg(not(feature = "shadow-call-stack"))]
     #[rustfmt::skip]
     core::arch::asm!("
             .option push
             .option norelax
             lla gp, __global_pointer$
             .option pop
+    ");
 
+    #[cfg(feature = "shadow-call-stack")]
+    crate::stack_protection::enter_shadow_stack(
+        core::ptr::addr_of_mut!(crate::stack_protection::MAIN_SHADOW_STACK).cast(),
+    );
+
+    #[rustfmt::skip]
+    core::arch::asm!("
             lla {bss_start}, __bss_start
             lla {bss_end}, end
             1:
@@ -33,9 +47,11 @@ unsafe extern "C" fn _start(argc: isize, argv: *const *const u8, a2: usize) -> !
     );
 
     A2 = a2;
+    crate::stack_protection::init_stack_guard();
 
     main(argc, argv);
-    librust::syscalls::exit()
+    crate::thread::run_local_destructors();
+    librust::syscalls::exit(0)
 }
 
 extern "C" {
@@ -48,25 +64,33 @@ fn lang_start<T>(main: fn() -> T, argc: isize, argv: *const *const u8) -> isize
     unsafe { ARGS = [argc as usize, argv as usize] };
 
     let mut map = crate::env::CAP_MAP.borrow_mut();
-    let channel = crate::ipc::IpcChannel::new(librust::capabilities::CapabilityPtr::new(0));
-    let mut cap = [Capability::default()];
-
-    // FIXME: Wowie is this some awful code!
-    while let Ok(ReadChannelMessage { message: msg, .. }) = channel.read(&mut cap[..]) {
-        let _ = librust::syscalls::receive_message();
-        let name = match core::str::from_utf8(msg.as_bytes()) {
-            Ok(name) => name,
-            Err(_) => break,
-        };
-
-        if name == "done" {
-            break;
+
+    // Tasks spawned by another task get a channel to it as their first
+    // capability, which the names of the rest are sent over. Tasks the kernel
+    // starts itself, like `init`, don't have a parent.
+    let parent = CapabilityPtr::new(0);
+    if let SyscallResult::Ok(CapabilityInfo { kind: CapabilityKind::Channel, .. }) = inspect_capability(parent) {
+        let channel = crate::ipc::IpcChannel::new(parent);
+        let mut cap = [Capability::default()];
+
+        // FIXME: Wowie is this some awful code!
+        while let Ok(ReadChannelMessage { message: msg, .. }) = channel.read(&mut cap[..]) {
+            let _ = librust::syscalls::receive_message();
+            let name = match core::str::from_utf8(msg.as_bytes()) {
+                Ok(name) => name,
+                Err(_) => break,
+            };
+
+            if name == "done" {
+                break;
+            }
+
+            map.insert(name.into(), cap[0].cptr);
         }
 
-        map.insert(name.into(), cap[0].cptr);
+        map.insert("parent".into(), parent);
     }
 
-    map.insert("parent".into(), librust::capabilities::CapabilityPtr::new(0));
     drop(map);
 
     main();
@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Runtime support for the compiler's stack smashing protection, which all of
+//! userspace is built with, and for the optional shadow call stack.
+//!
+//! Functions with arrays on their stack place a canary between them and the
+//! saved return address, and check it before returning, so overflowing one
+//! aborts the task instead of returning to wherever the overflow pointed. The
+//! canary is the random [`librust::time::stack_guard`] the kernel publishes.
+//!
+//! Building userspace with `cargo xtask build userspace --shadow-call-stack`
+//! enables the `shadow-call-stack` feature, which has every function also push
+//! its return address to a separate stack pointed to by `gp` and return to the
+//! copy from there, so overwriting the one on the normal stack has no effect.
+//! `gp` isn't used as the global pointer then, which is fine since the linker
+//! doesn't relax accesses to be relative to it anyway.
+
+#[cfg(feature = "shadow-call-stack")]
+use librust::{
+    message::SyscallResult,
+    syscalls::allocation::{alloc_virtual_memory, AllocationOptions, MemoryPermissions},
+};
+
+/// Size of the shadow call stack of each spawned thread, each call takes up 8
+/// bytes of it
+#[cfg(feature = "shadow-call-stack")]
+pub const SHADOW_STACK_SIZE: usize = 16 * 1024;
+
+/// The main thread's shadow call stack, which can't be allocated since calling
+/// anything needs one already
+#[cfg(feature = "shadow-call-stack")]
+pub(crate) static mut MAIN_SHADOW_STACK: [usize; SHADOW_STACK_SIZE / 8] = [0; SHADOW_STACK_SIZE / 8];
+
+#[no_mangle]
+static mut __stack_chk_guard: usize = 0;
+
+#[no_mangle]
+extern "C" fn __stack_chk_fail() -> ! {
+    println!("PANIC: stack smashing detected");
+    crate::process::exit(crate::process::PANIC_EXIT_CODE)
+}
+
+/// Load the canary from the time page, which has to happen before any
+/// function that checks it is called
+#[inline(always)]
+pub(crate) unsafe fn init_stack_guard() {
+    __stack_chk_guard = librust::time::stack_guard() as usize;
+}
+
+/// Allocate a shadow call stack for a new thread
+#[cfg(feature = "shadow-call-stack")]
+pub(crate) fn alloc_shadow_stack() -> *mut u8 {
+    match alloc_virtual_memory(
+        SHADOW_STACK_SIZE,
+        AllocationOptions::None,
+        MemoryPermissions::READ | MemoryPermissions::WRITE,
+    ) {
+        SyscallResult::Ok(stack) => stack,
+        SyscallResult::Err(e) => panic!("failed to allocate shadow call stack: {:?}", e),
+    }
+}
+
+/// Switch to the shadow call stack starting at `stack`, which grows upwards.
+/// This has to be inlined into a function which doesn't use the shadow call
+/// stack itself, since it would return to whatever is on the new one.
+#[cfg(feature = "shadow-call-stack")]
+#[inline(always)]
+pub(crate) unsafe fn enter_shadow_stack(stack: *mut u8) {
+    core::arch::asm!("mv gp, {}", in(reg) stack);
+}
@@ -5,5 +5,291 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+//! A single-threaded executor for `async` code, built on [`wait_many`].
+//!
+//! Futures wait on kernel objects with [`readable`] and [`writable`] and on
+//! time with [`sleep`], which register what they're waiting for with the
+//! executor. Once every task is waiting, the executor blocks in a single
+//! `wait_many` call on all of it, with a timeout for the nearest timer, and
+//! wakes the tasks whose objects became ready. Each thread has its own
+//! executor, started by [`block_on`], and tasks never move between threads.
+//!
+//! Tasks can also be woken from other threads, which is noticed straight away
+//! if the executor is idle, but only once its current wait ends otherwise.
+
 pub use alloc::task::Wake;
 pub use core::task::*;
+
+use crate::sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    Arc,
+};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    vec::Vec,
+};
+use core::{cell::RefCell, future::Future, pin::Pin, time::Duration};
+use librust::{
+    capabilities::CapabilityPtr,
+    error::KError,
+    message::SyscallResult,
+    syscalls::{
+        poll::{wait_many, WaitEvents, WaitItem, MAX_WAIT_ITEMS, NO_TIMEOUT},
+        thread::{futex_wait, futex_wake},
+    },
+    time::monotonic_ns,
+};
+use sync::SpinMutex;
+
+/// The current thread's executor, created the first time it's used
+#[thread_local]
+static EXECUTOR: RefCell<Option<Executor>> = RefCell::new(None);
+
+type LocalTask = Pin<Box<dyn Future<Output = ()>>>;
+
+struct Executor {
+    /// Spawned tasks which aren't being polled right now
+    tasks: BTreeMap<usize, LocalTask>,
+    next_id: usize,
+    woken: Arc<Woken>,
+    /// Objects tasks are waiting on, and the waker to call once they're ready
+    waits: Vec<(CapabilityPtr, WaitEvents, Waker)>,
+    /// When tasks are waiting until, in nanoseconds of [`monotonic_ns`]
+    timers: Vec<(u64, Waker)>,
+}
+
+/// The tasks which have been woken since they were last polled
+struct Woken {
+    ids: SpinMutex<VecDeque<usize>>,
+    /// Futex the executor sleeps on when there's nothing to wait for but
+    /// wakers from other threads
+    notify: AtomicU32,
+}
+
+impl Woken {
+    fn push(&self, id: usize) {
+        self.ids.lock().push_back(id);
+        self.notify.store(1, Ordering::Release);
+        let _ = futex_wake(&self.notify, 1);
+    }
+}
+
+struct TaskWaker {
+    id: usize,
+    woken: Arc<Woken>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.woken.push(self.id);
+    }
+}
+
+/// Wakes [`block_on`] to poll the future it was given again
+struct MainWaker {
+    woken: AtomicBool,
+    executor: Arc<Woken>,
+}
+
+impl Wake for MainWaker {
+    fn wake(self: Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        self.executor.notify.store(1, Ordering::Release);
+        let _ = futex_wake(&self.executor.notify, 1);
+    }
+}
+
+fn with_executor<R>(f: impl FnOnce(&mut Executor) -> R) -> R {
+    let mut executor = EXECUTOR.borrow_mut();
+    f(executor.get_or_insert_with(|| Executor {
+        tasks: BTreeMap::new(),
+        next_id: 0,
+        woken: Arc::new(Woken { ids: SpinMutex::new(VecDeque::new()), notify: AtomicU32::new(0) }),
+        waits: Vec::new(),
+        timers: Vec::new(),
+    }))
+}
+
+/// Spawn `future` as a new task on the current thread's executor, which runs
+/// while the thread is in [`block_on`]
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + 'static,
+{
+    with_executor(|executor| {
+        let id = executor.next_id;
+        executor.next_id += 1;
+        executor.tasks.insert(id, Box::pin(future));
+        executor.woken.push(id);
+    });
+}
+
+/// Run the current thread's executor until `future` finishes, returning its
+/// output. Spawned tasks are run alongside it, and any which haven't finished
+/// by then carry on the next time the thread calls `block_on`.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let main = Arc::new(MainWaker { woken: AtomicBool::new(true), executor: with_executor(|e| Arc::clone(&e.woken)) });
+    let waker = Waker::from(Arc::clone(&main));
+
+    loop {
+        if main.woken.swap(false, Ordering::AcqRel) {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut Context::from_waker(&waker)) {
+                return output;
+            }
+        }
+
+        if !run_woken() && !main.woken.load(Ordering::Acquire) {
+            wait();
+        }
+    }
+}
+
+/// Poll every task that's been woken, returning whether there were any
+fn run_woken() -> bool {
+    let woken = with_executor(|executor| Arc::clone(&executor.woken));
+    // Cleared first, so a wake that comes in after taking the queue isn't lost
+    woken.notify.store(0, Ordering::Release);
+    let ids: Vec<usize> = core::mem::take(&mut *woken.ids.lock()).into();
+
+    for &id in &ids {
+        // Tasks can be woken more than once, or after they've finished
+        let mut task = match with_executor(|executor| executor.tasks.remove(&id)) {
+            Some(task) => task,
+            None => continue,
+        };
+
+        let waker = Waker::from(Arc::new(TaskWaker { id, woken: Arc::clone(&woken) }));
+        if task.as_mut().poll(&mut Context::from_waker(&waker)).is_pending() {
+            with_executor(|executor| executor.tasks.insert(id, task));
+        }
+    }
+
+    !ids.is_empty()
+}
+
+/// Block until something a task is waiting on is ready, and wake it
+fn wait() {
+    let (waits, timers, woken) = with_executor(|executor| {
+        (core::mem::take(&mut executor.waits), core::mem::take(&mut executor.timers), Arc::clone(&executor.woken))
+    });
+
+    if waits.is_empty() && timers.is_empty() {
+        // Only a waker on another thread can make progress now
+        let _ = futex_wait(&woken.notify, 0);
+        return;
+    }
+
+    // One item per object, waiting for everything anyone is interested in
+    let mut items: Vec<WaitItem> = Vec::new();
+    for (cptr, interest, _) in &waits {
+        match items.iter_mut().find(|item| item.cptr == *cptr) {
+            Some(item) => item.interest = item.interest | *interest,
+            None => items.push(WaitItem::new(*cptr, *interest)),
+        }
+    }
+
+    // Anything past the limit is woken up to register again, which makes the
+    // executor poll until enough of them are done waiting
+    let overflow = items.len() > MAX_WAIT_ITEMS;
+    items.truncate(MAX_WAIT_ITEMS);
+
+    let timeout_us = match timers.iter().map(|(deadline, _)| *deadline).min() {
+        _ if overflow => 0,
+        Some(deadline) => match deadline.saturating_sub(monotonic_ns()) {
+            0 => 0,
+            ns => (ns as usize + 999) / 1000,
+        },
+        None => NO_TIMEOUT,
+    };
+
+    let failed = matches!(wait_many(&mut items, timeout_us), SyscallResult::Err(_));
+    for (cptr, interest, waker) in waits {
+        let ready = match items.iter().find(|item| item.cptr == cptr) {
+            Some(item) => item.ready.value() & (interest | WaitEvents::CLOSED).value() != 0,
+            None => true,
+        };
+
+        // Tasks find out about errors when they poll the object themselves
+        if ready || failed {
+            waker.wake();
+        } else {
+            with_executor(|executor| executor.waits.push((cptr, interest, waker)));
+        }
+    }
+
+    let now = monotonic_ns();
+    for (deadline, waker) in timers {
+        if deadline <= now {
+            waker.wake();
+        } else {
+            with_executor(|executor| executor.timers.push((deadline, waker)));
+        }
+    }
+}
+
+/// Wait until `cptr` is ready for any of the events in `interest`, returning
+/// which events are ready. [`WaitEvents::CLOSED`] is always reported.
+pub fn ready(cptr: CapabilityPtr, interest: WaitEvents) -> Ready {
+    Ready { cptr, interest }
+}
+
+/// Wait until there's something to read from `cptr`
+pub fn readable(cptr: CapabilityPtr) -> Ready {
+    ready(cptr, WaitEvents::READABLE)
+}
+
+/// Wait until there's room to send to `cptr`
+pub fn writable(cptr: CapabilityPtr) -> Ready {
+    ready(cptr, WaitEvents::WRITABLE)
+}
+
+/// A future which waits for a kernel object to become ready, returned by
+/// [`ready`]
+pub struct Ready {
+    cptr: CapabilityPtr,
+    interest: WaitEvents,
+}
+
+impl Future for Ready {
+    type Output = Result<WaitEvents, KError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut items = [WaitItem::new(self.cptr, self.interest)];
+        match wait_many(&mut items, 0) {
+            SyscallResult::Ok(0) => {
+                let waker = cx.waker().clone();
+                with_executor(|executor| executor.waits.push((self.cptr, self.interest, waker)));
+                Poll::Pending
+            }
+            SyscallResult::Ok(_) => Poll::Ready(Ok(items[0].ready)),
+            SyscallResult::Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+/// Wait for `duration` to pass
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep { deadline: monotonic_ns().saturating_add(duration.as_nanos() as u64) }
+}
+
+/// A future which waits until a point in time, returned by [`sleep`]
+pub struct Sleep {
+    /// In nanoseconds of [`monotonic_ns`]
+    deadline: u64,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if monotonic_ns() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        let waker = cx.waker().clone();
+        with_executor(|executor| executor.timers.push((self.deadline, waker)));
+        Poll::Pending
+    }
+}
@@ -0,0 +1,273 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+use alloc::{boxed::Box, vec::Vec};
+use core::{cell::UnsafeCell, marker::PhantomData};
+use librust::{
+    message::SyscallResult,
+    syscalls::{
+        allocation::{self, AllocationOptions, MemoryPermissions},
+        thread::{self, THREAD_EXITED},
+    },
+    task::Tid,
+};
+
+/// Size of the stack allocated for each spawned thread
+pub const STACK_SIZE: usize = 64 * 1024;
+
+const RUNNING: u32 = 0;
+
+/// Spawn a new thread running `f` in the current address space
+///
+/// Spawned threads share the heap and any memory of the task, but not its
+/// capabilities or channels, so IPC should be done from the thread which owns
+/// the capabilities. Each thread gets its own copy of any [`thread_local!`]
+/// statics.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = Arc::new(Packet { state: AtomicU32::new(RUNNING), result: UnsafeCell::new(None) });
+    let their_packet = Arc::clone(&packet);
+
+    let main: Box<dyn FnOnce()> = Box::new(move || {
+        let result = f();
+        unsafe { *their_packet.result.get() = Some(result) };
+    });
+
+    let stack = match allocation::alloc_virtual_memory(
+        STACK_SIZE,
+        AllocationOptions::None,
+        MemoryPermissions::READ | MemoryPermissions::WRITE,
+    ) {
+        SyscallResult::Ok(stack) => stack,
+        SyscallResult::Err(e) => panic!("failed to allocate thread stack: {:?}", e),
+    };
+
+    #[cfg(feature = "shadow-call-stack")]
+    let shadow_stack = crate::stack_protection::alloc_shadow_stack();
+    let arg = Box::into_raw(Box::new(ThreadStart {
+        main,
+        #[cfg(feature = "shadow-call-stack")]
+        shadow_stack,
+    }));
+    let tid = match unsafe {
+        thread::create_thread(thread_start, stack.add(STACK_SIZE), core::ptr::null_mut(), arg as usize, &packet.state)
+    } {
+        SyscallResult::Ok(tid) => tid,
+        SyscallResult::Err(e) => {
+            drop(unsafe { Box::from_raw(arg) });
+            panic!("failed to create thread: {:?}", e);
+        }
+    };
+
+    JoinHandle {
+        tid,
+        packet,
+        stack,
+        #[cfg(feature = "shadow-call-stack")]
+        shadow_stack,
+    }
+}
+
+struct ThreadStart {
+    main: Box<dyn FnOnce()>,
+    #[cfg(feature = "shadow-call-stack")]
+    shadow_stack: *mut u8,
+}
+
+#[no_sanitize(shadow_call_stack)]
+extern "C" fn thread_start(arg: usize) -> ! {
+    let start = arg as *mut ThreadStart;
+
+    // Nothing can be called before switching to the thread's own shadow call
+    // stack, since `gp` starts out as zero
+    #[cfg(feature = "shadow-call-stack")]
+    unsafe {
+        crate::stack_protection::enter_shadow_stack((*start).shadow_stack)
+    };
+
+    let ThreadStart { main, .. } = *unsafe { Box::from_raw(start) };
+    main();
+    unsafe { run_local_destructors() };
+    librust::syscalls::exit(0)
+}
+
+struct Packet<T> {
+    /// Set to [`THREAD_EXITED`] by the kernel once the thread has exited
+    state: AtomicU32,
+    result: UnsafeCell<Option<T>>,
+}
+
+// The result is only written by the spawned thread before it exits, and only
+// read by the joining thread after the kernel has set `state`
+unsafe impl<T: Send> Sync for Packet<T> {}
+
+/// The thread exited without returning a value, e.g. because it panicked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JoinError;
+
+/// A handle to a spawned thread which can be used to wait for it to finish
+///
+/// Dropping the handle detaches the thread. The thread's stack is freed if it
+/// has already exited, otherwise it can't be and is leaked.
+pub struct JoinHandle<T> {
+    tid: Tid,
+    packet: Arc<Packet<T>>,
+    stack: *mut u8,
+    #[cfg(feature = "shadow-call-stack")]
+    shadow_stack: *mut u8,
+}
+
+// The stacks are only ever freed by the handle
+unsafe impl<T: Send> Send for JoinHandle<T> {}
+unsafe impl<T: Send> Sync for JoinHandle<T> {}
+
+impl<T> JoinHandle<T> {
+    /// The [`Tid`] of the spawned thread
+    pub fn tid(&self) -> Tid {
+        self.tid
+    }
+
+    /// Whether the thread has finished running
+    pub fn is_finished(&self) -> bool {
+        self.packet.state.load(Ordering::Acquire) == THREAD_EXITED
+    }
+
+    /// Wait for the thread to exit, returning the value it returned, or
+    /// [`JoinError`] if it panicked or was killed
+    pub fn join(self) -> Result<T, JoinError> {
+        while self.packet.state.load(Ordering::Acquire) != THREAD_EXITED {
+            let _ = thread::futex_wait(&self.packet.state, RUNNING);
+        }
+
+        unsafe { (*self.packet.result.get()).take() }.ok_or(JoinError)
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        if !self.is_finished() {
+            // The kernel writes to the packet when the thread exits, so it has
+            // to outlive the handle
+            core::mem::forget(Arc::clone(&self.packet));
+            return;
+        }
+
+        let _ = allocation::dealloc_virtual_memory(self.stack);
+        #[cfg(feature = "shadow-call-stack")]
+        let _ = allocation::dealloc_virtual_memory(self.shadow_stack);
+    }
+}
+
+/// Declare one or more thread-local statics, each of which is a [`LocalKey`]
+/// that lazily initializes a separate copy of its value for each thread the
+/// first time that thread accesses it
+///
+/// Each thread's values are dropped when it returns, in the reverse of the
+/// order they were initialized in, but not if it panics or is killed
+#[macro_export]
+#[allow_internal_unstable(thread_local)]
+macro_rules! thread_local {
+    () => {};
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => {
+        $crate::thread_local!($(#[$attr])* $vis static $name: $t = $init);
+        $crate::thread_local!($($rest)*);
+    };
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr) => {
+        $(#[$attr])*
+        $vis const $name: $crate::thread::LocalKey<$t> = {
+            fn __get() -> *const $t {
+                #[thread_local]
+                static VALUE: $crate::thread::LocalCell<$t> = $crate::thread::LocalCell::new();
+                VALUE.get_or_init(|| $init)
+            }
+
+            unsafe { $crate::thread::LocalKey::new(__get) }
+        };
+    };
+}
+
+/// A thread-local value declared with [`thread_local!`]
+pub struct LocalKey<T: 'static> {
+    get: fn() -> *const T,
+    // Can't be sent to or accessed from other threads
+    _marker: PhantomData<*const T>,
+}
+
+unsafe impl<T> Sync for LocalKey<T> {}
+
+impl<T: 'static> LocalKey<T> {
+    /// # Safety
+    /// `get` must return a pointer to a value that lives as long as the
+    /// current thread
+    #[doc(hidden)]
+    pub const unsafe fn new(get: fn() -> *const T) -> Self {
+        Self { get, _marker: PhantomData }
+    }
+
+    /// Access the current thread's copy of the value, initializing it if this
+    /// is the first access on this thread
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        f(unsafe { &*(self.get)() })
+    }
+}
+
+#[doc(hidden)]
+pub struct LocalCell<T>(UnsafeCell<Option<T>>);
+
+unsafe impl<T> Sync for LocalCell<T> {}
+
+impl<T> LocalCell<T> {
+    pub const fn new() -> Self {
+        Self(UnsafeCell::new(None))
+    }
+
+    pub fn get_or_init(&self, init: impl FnOnce() -> T) -> *const T {
+        // Only ever accessed from the thread which owns this copy of the cell,
+        // and the value is never replaced once initialized
+        unsafe {
+            if (*self.0.get()).is_none() {
+                let value = init();
+                // `init` may have initialized it already by accessing the key
+                if (*self.0.get()).is_none() {
+                    *self.0.get() = Some(value);
+                    if core::mem::needs_drop::<T>() {
+                        (*DESTRUCTORS.get()).push((self as *const Self).cast(), destroy::<T>);
+                    }
+                }
+            }
+
+            (*self.0.get()).as_ref().unwrap()
+        }
+    }
+}
+
+/// The current thread's initialized [`LocalCell`]s which need dropping
+#[thread_local]
+static DESTRUCTORS: UnsafeCell<Vec<(*const u8, unsafe fn(*const u8))>> = UnsafeCell::new(Vec::new());
+
+unsafe fn destroy<T>(cell: *const u8) {
+    drop((*(*cell.cast::<LocalCell<T>>()).0.get()).take());
+}
+
+/// Drop the current thread's thread-local values. Destructors can access other
+/// thread-local values, which are initialized again if they were already
+/// dropped, and dropped in turn.
+///
+/// # Safety
+/// Must only be called once the thread is done running anything else
+pub(crate) unsafe fn run_local_destructors() {
+    while let Some((cell, destroy)) = (*DESTRUCTORS.get()).pop() {
+        destroy(cell);
+    }
+}
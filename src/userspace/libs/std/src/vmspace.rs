@@ -13,15 +13,20 @@ use librust::{
     message::SyscallResult,
     syscalls::{
         allocation::MemoryPermissions,
+        io::{self, StdioStream},
+        task,
         vmspace::{self, VmspaceObjectId, VmspaceObjectMapping, VmspaceSpawnEnv},
     },
-    task::Tid,
+    task::{TaskEvent, Tid},
 };
 
 pub struct Vmspace {
     name: String,
     id: VmspaceObjectId,
     caps_to_send: Vec<(String, CapabilityPtr, CapabilityRights)>,
+    stdin: Option<CapabilityPtr>,
+    stdout: Option<CapabilityPtr>,
+    task_group: Option<CapabilityPtr>,
 }
 
 impl Vmspace {
@@ -29,7 +34,7 @@ impl Vmspace {
     pub fn new(name: &str) -> Self {
         let id = vmspace::create_vmspace().unwrap();
 
-        Self { name: name.to_string(), id, caps_to_send: Vec::new() }
+        Self { name: name.to_string(), id, caps_to_send: Vec::new(), stdin: None, stdout: None, task_group: None }
     }
 
     pub fn create_object<'b>(
@@ -49,30 +54,135 @@ impl Vmspace {
     }
 
     pub fn spawn(self, env: VmspaceSpawnEnv) -> Result<(Tid, CapabilityPtr), KError> {
-        let (tid, cptr) = match vmspace::spawn_vmspace(self.id, &self.name, env) {
+        self.spawn_inner(env, None)
+    }
+
+    /// Spawn the vmspace as a replacement for the dead task on the other end of
+    /// `previous`, moving all of the capabilities it held to the new task
+    /// before it begins running. Clients connected to the previous task stay
+    /// connected to the new one, and will show up as messages on channels the
+    /// new task wasn't explicitly granted.
+    pub fn spawn_replacing(
+        self,
+        env: VmspaceSpawnEnv,
+        previous: CapabilityPtr,
+    ) -> Result<(Tid, CapabilityPtr), KError> {
+        self.spawn_inner(env, Some(previous))
+    }
+
+    fn spawn_inner(
+        self,
+        env: VmspaceSpawnEnv,
+        previous: Option<CapabilityPtr>,
+    ) -> Result<(Tid, CapabilityPtr), KError> {
+        let (tid, cptr) = match vmspace::spawn_vmspace(self.id, &self.name, env, self.task_group) {
             SyscallResult::Ok((tid, cptr)) => (tid, cptr),
             SyscallResult::Err(e) => return Err(e),
         };
 
+        // The new task would otherwise be left waiting for its capabilities
+        // forever, so it's killed if they can't all be sent
+        match Self::start(cptr, previous, self.stdin, self.stdout, self.caps_to_send) {
+            Ok(()) => Ok((tid, cptr)),
+            Err(e) => {
+                let _ = task::send_task_event(cptr, TaskEvent::Kill);
+                Err(e)
+            }
+        }
+    }
+
+    /// Bind the stdio of the new task on the other end of `cptr` and send it
+    /// its initial capabilities, after which it starts running
+    fn start(
+        cptr: CapabilityPtr,
+        previous: Option<CapabilityPtr>,
+        stdin: Option<CapabilityPtr>,
+        stdout: Option<CapabilityPtr>,
+        caps_to_send: Vec<(String, CapabilityPtr, CapabilityRights)>,
+    ) -> Result<(), KError> {
+        // Binding stdio can fail on a bad stream, so it's done before the
+        // handoff, which can't be undone by killing the new task
+        if let Some(stream) = stdin {
+            if let SyscallResult::Err(e) = io::bind_stdio(Some(cptr), StdioStream::Stdin, Some(stream)) {
+                return Err(e);
+            }
+        }
+
+        if let Some(stream) = stdout {
+            if let SyscallResult::Err(e) = io::bind_stdio(Some(cptr), StdioStream::Stdout, Some(stream)) {
+                return Err(e);
+            }
+        }
+
+        // The new task doesn't start handling messages until it receives all
+        // of its initial capabilities, so do the handoff first
+        if let Some(previous) = previous {
+            if let SyscallResult::Err(e) = vmspace::handoff_capabilities(previous, cptr) {
+                return Err(e);
+            }
+        }
+
         let mut channel = crate::ipc::IpcChannel::new(cptr);
 
-        for (name, cap, rights) in self.caps_to_send {
-            let mut message = channel.new_message(name.len()).unwrap();
+        for (name, cap, rights) in caps_to_send {
+            let mut message = channel.new_message(name.len())?;
             message.write(name.as_bytes());
-            message.send(&[Capability::new(cap, rights)]).unwrap();
+            message.send(&[Capability::new(cap, rights)])?;
         }
 
         const DONE: &str = "done";
-        let mut message = channel.new_message(DONE.len()).unwrap();
+        let mut message = channel.new_message(DONE.len())?;
         message.write(DONE.as_bytes());
-        message.send(&[]).unwrap();
+        message.send(&[])
+    }
+
+    /// Copy `args` into the new task, returning the `argc` and `argv` to spawn
+    /// it with in `a0` and `a1`, which is where [`crate::env::args`] finds them
+    pub fn create_args(&self, args: &[&str]) -> Result<(usize, usize), KError> {
+        if args.is_empty() {
+            return Ok((0, 0));
+        }
+
+        // The `&str`s come first, each a pointer to the bytes of the argument
+        // after them and its length
+        let table_len = args.len() * 16;
+        let size = table_len + args.iter().map(|arg| arg.len()).sum::<usize>();
+        let mut object = self.create_object(core::ptr::null(), size, MemoryPermissions::READ)?;
+        let argv = object.vmspace_address() as usize;
+        let memory = object.as_slice();
+
+        let mut offset = table_len;
+        for (i, arg) in args.iter().enumerate() {
+            memory[i * 16..][..8].copy_from_slice(&(argv + offset).to_le_bytes());
+            memory[i * 16 + 8..][..8].copy_from_slice(&arg.len().to_le_bytes());
+            memory[offset..][..arg.len()].copy_from_slice(arg.as_bytes());
+            offset += arg.len();
+        }
 
-        Ok((tid, cptr))
+        Ok((args.len(), argv))
     }
 
     pub fn grant(&mut self, name: &str, cptr: CapabilityPtr, rights: CapabilityRights) {
         self.caps_to_send.push((name.into(), cptr, rights));
     }
+
+    /// Bind the new task's stdin to the console stream `stream` before it
+    /// begins running, e.g. the read end of a [`pipe`](crate::io::pipe)
+    pub fn bind_stdin(&mut self, stream: CapabilityPtr) {
+        self.stdin = Some(stream);
+    }
+
+    /// Bind the new task's stdout to the console stream `stream` before it
+    /// begins running, so none of its output goes anywhere else
+    pub fn bind_stdout(&mut self, stream: CapabilityPtr) {
+        self.stdout = Some(stream);
+    }
+
+    /// Spawn the new task into the task group `group` instead of the current
+    /// task's, see [`librust::syscalls::taskgroup`]
+    pub fn join_task_group(&mut self, group: CapabilityPtr) {
+        self.task_group = Some(group);
+    }
 }
 
 #[derive(Debug)]
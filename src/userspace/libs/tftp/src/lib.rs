@@ -0,0 +1,319 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The client side of TFTP (RFC 1350) downloads, along with the block size
+//! and transfer size options (RFC 2348, RFC 2349). This only deals with
+//! packets, sending them and retransmitting on timeouts is up to the caller:
+//! [`Download::new`] gives the request to send to [`PORT`] on the server, each
+//! packet received is passed to [`Download::receive`], and
+//! [`Download::last_packet`] is sent again whenever a reply takes too long.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+/// Port servers listen for requests on
+pub const PORT: u16 = 69;
+/// Block size used when the server doesn't accept the `blksize` option
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+/// Block size asked for, the most that fits in an unfragmented UDP packet on
+/// Ethernet
+pub const BLOCK_SIZE: usize = 1432;
+
+const READ_REQUEST: u16 = 1;
+const DATA: u16 = 3;
+const ACK: u16 = 4;
+const ERROR: u16 = 5;
+const OPTION_ACK: u16 = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TftpError {
+    /// The server ended the transfer with an error
+    Remote { code: u16, message: String },
+    /// A packet which isn't valid TFTP was received
+    Malformed,
+    /// The file is bigger than the most the download was allowed to take
+    TooLarge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Packet<'a> {
+    Data { block: u16, data: &'a [u8] },
+    Ack { block: u16 },
+    Error { code: u16, message: &'a [u8] },
+    OptionAck { options: &'a [u8] },
+}
+
+impl<'a> Packet<'a> {
+    fn parse(packet: &'a [u8]) -> Result<Self, TftpError> {
+        let (opcode, rest) = split_u16(packet)?;
+        match opcode {
+            DATA => {
+                let (block, data) = split_u16(rest)?;
+                Ok(Self::Data { block, data })
+            }
+            ACK => Ok(Self::Ack { block: split_u16(rest)?.0 }),
+            ERROR => {
+                let (code, message) = split_u16(rest)?;
+                Ok(Self::Error { code, message: message.strip_suffix(&[0]).unwrap_or(message) })
+            }
+            OPTION_ACK => Ok(Self::OptionAck { options: rest }),
+            _ => Err(TftpError::Malformed),
+        }
+    }
+}
+
+/// Outcome of handling a packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Progress {
+    /// Send this back to the server and wait for the next packet
+    Reply(Vec<u8>),
+    /// Send this back to the server, which finishes the download
+    Finished(Vec<u8>),
+    /// Nothing to do, e.g. for a duplicate of a block already received
+    Ignore,
+}
+
+/// A download in progress
+#[derive(Debug)]
+pub struct Download {
+    data: Vec<u8>,
+    max_size: usize,
+    block_size: usize,
+    /// The block that's expected next, which wraps around for files with more
+    /// than 65535 blocks
+    next_block: u16,
+    /// The port the server sends from, which it picks when it replies to the
+    /// request and which identifies the transfer from then on
+    server_port: Option<u16>,
+    last_packet: Vec<u8>,
+    finished: bool,
+}
+
+impl Download {
+    /// Start downloading `filename`, refusing files larger than `max_size`.
+    /// Returns the request to send.
+    pub fn new(filename: &str, max_size: usize) -> (Self, Vec<u8>) {
+        let mut request = Vec::new();
+        request.extend_from_slice(&READ_REQUEST.to_be_bytes());
+        for field in [filename, "octet", "blksize", &BLOCK_SIZE.to_string(), "tsize", "0"] {
+            request.extend_from_slice(field.as_bytes());
+            request.push(0);
+        }
+
+        let download = Self {
+            data: Vec::new(),
+            max_size,
+            block_size: DEFAULT_BLOCK_SIZE,
+            next_block: 1,
+            server_port: None,
+            last_packet: request.clone(),
+            finished: false,
+        };
+
+        (download, request)
+    }
+
+    /// The port replies should be sent to, which is [`PORT`] until the server
+    /// has replied
+    pub fn server_port(&self) -> u16 {
+        self.server_port.unwrap_or(PORT)
+    }
+
+    /// The last packet sent, to send again if the server doesn't reply
+    pub fn last_packet(&self) -> &[u8] {
+        &self.last_packet
+    }
+
+    /// How much of the file has been received so far
+    pub fn received(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// The downloaded file, once the download has finished
+    pub fn into_data(self) -> Option<Vec<u8>> {
+        match self.finished {
+            true => Some(self.data),
+            false => None,
+        }
+    }
+
+    /// Handle a packet received from the server's `port`
+    pub fn receive(&mut self, port: u16, packet: &[u8]) -> Result<Progress, TftpError> {
+        match self.server_port {
+            // Packets from anywhere else belong to some other transfer
+            Some(server_port) if server_port != port => return Ok(Progress::Ignore),
+            _ if self.finished => return Ok(Progress::Ignore),
+            _ => {}
+        }
+
+        let packet = Packet::parse(packet)?;
+        self.server_port.get_or_insert(port);
+
+        match packet {
+            Packet::Error { code, message } => {
+                Err(TftpError::Remote { code, message: String::from_utf8_lossy(message).into_owned() })
+            }
+            // Only sent in place of the first block
+            Packet::OptionAck { options } if self.next_block == 1 && self.data.is_empty() => {
+                self.accept_options(options)?;
+                Ok(self.acknowledge(0, false))
+            }
+            Packet::Data { block, data } if block == self.next_block => {
+                if data.len() > self.block_size {
+                    return Err(TftpError::Malformed);
+                }
+
+                if self.data.len() + data.len() > self.max_size {
+                    return Err(TftpError::TooLarge);
+                }
+
+                self.data.extend_from_slice(data);
+                self.next_block = self.next_block.wrapping_add(1);
+
+                Ok(self.acknowledge(block, data.len() < self.block_size))
+            }
+            // The server didn't see our acknowledgement and sent the previous
+            // block again
+            Packet::Data { block, .. } if block == self.next_block.wrapping_sub(1) => {
+                Ok(Progress::Reply(self.last_packet.clone()))
+            }
+            Packet::Data { .. } | Packet::OptionAck { .. } | Packet::Ack { .. } => Ok(Progress::Ignore),
+        }
+    }
+
+    fn accept_options(&mut self, options: &[u8]) -> Result<(), TftpError> {
+        let mut fields = options.split(|&b| b == 0).map(core::str::from_utf8);
+        while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+            let (name, value) = (name.map_err(|_| TftpError::Malformed)?, value.map_err(|_| TftpError::Malformed)?);
+            let value: usize = value.parse().map_err(|_| TftpError::Malformed)?;
+
+            if name.eq_ignore_ascii_case("blksize") {
+                if value == 0 || value > BLOCK_SIZE {
+                    return Err(TftpError::Malformed);
+                }
+
+                self.block_size = value;
+            } else if name.eq_ignore_ascii_case("tsize") {
+                if value > self.max_size {
+                    return Err(TftpError::TooLarge);
+                }
+
+                self.data.reserve_exact(value);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn acknowledge(&mut self, block: u16, finished: bool) -> Progress {
+        let mut ack = Vec::with_capacity(4);
+        ack.extend_from_slice(&ACK.to_be_bytes());
+        ack.extend_from_slice(&block.to_be_bytes());
+        self.last_packet = ack.clone();
+        self.finished = finished;
+
+        match finished {
+            true => Progress::Finished(ack),
+            false => Progress::Reply(ack),
+        }
+    }
+}
+
+/// An error packet, for telling the server to give up on the transfer
+pub fn error(code: u16, message: &str) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(5 + message.len());
+    packet.extend_from_slice(&ERROR.to_be_bytes());
+    packet.extend_from_slice(&code.to_be_bytes());
+    packet.extend_from_slice(message.as_bytes());
+    packet.push(0);
+
+    packet
+}
+
+fn split_u16(bytes: &[u8]) -> Result<(u16, &[u8]), TftpError> {
+    match bytes {
+        [high, low, rest @ ..] => Ok((u16::from_be_bytes([*high, *low]), rest)),
+        _ => Err(TftpError::Malformed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use alloc::vec;
+
+    fn data(block: u16, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0, 3];
+        packet.extend_from_slice(&block.to_be_bytes());
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    fn ack(block: u16) -> Vec<u8> {
+        let mut packet = vec![0, 4];
+        packet.extend_from_slice(&block.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn request_asks_for_options() {
+        let (_, request) = Download::new("initfs.tar.lz4", 1024);
+        assert_eq!(request, b"\0\x01initfs.tar.lz4\0octet\0blksize\x001432\0tsize\x000\0");
+    }
+
+    #[test]
+    fn downloads_with_default_block_size() {
+        let (mut download, _) = Download::new("file", usize::MAX);
+        let file = (0..1000).map(|n| n as u8).collect::<Vec<_>>();
+
+        assert_eq!(download.receive(4000, &data(1, &file[..512])), Ok(Progress::Reply(ack(1))));
+        assert_eq!(download.server_port(), 4000);
+        assert!(!download.is_finished());
+
+        // A retransmitted block is acknowledged again but not added twice
+        assert_eq!(download.receive(4000, &data(1, &file[..512])), Ok(Progress::Reply(ack(1))));
+        assert_eq!(download.receive(4000, &data(2, &file[512..])), Ok(Progress::Finished(ack(2))));
+
+        assert_eq!(download.into_data().unwrap(), file);
+    }
+
+    #[test]
+    fn negotiates_options() {
+        let (mut download, _) = Download::new("file", 4096);
+        let file = [7; 2000];
+
+        assert_eq!(download.receive(4000, b"\0\x06blksize\x001432\0tsize\x002000\0"), Ok(Progress::Reply(ack(0))));
+        assert_eq!(download.receive(4000, &data(1, &file[..1432])), Ok(Progress::Reply(ack(1))));
+        assert_eq!(download.receive(4000, &data(2, &file[1432..])), Ok(Progress::Finished(ack(2))));
+        assert_eq!(download.into_data().unwrap(), file);
+    }
+
+    #[test]
+    fn rejects_other_transfers_and_errors() {
+        let (mut download, _) = Download::new("file", 600);
+        assert_eq!(download.receive(4000, &data(1, &[0; 512])), Ok(Progress::Reply(ack(1))));
+
+        assert_eq!(download.receive(5000, &data(2, &[0; 10])), Ok(Progress::Ignore));
+        assert_eq!(download.receive(4000, &data(2, &[0; 100])), Err(TftpError::TooLarge));
+        assert_eq!(
+            download.receive(4000, b"\0\x05\0\x01File not found\0"),
+            Err(TftpError::Remote { code: 1, message: "File not found".into() })
+        );
+        assert_eq!(download.receive(4000, b"\0"), Err(TftpError::Malformed));
+    }
+}
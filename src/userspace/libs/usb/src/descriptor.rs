@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::Direction;
+
+pub const DEVICE: u8 = 1;
+pub const CONFIGURATION: u8 = 2;
+pub const INTERFACE: u8 = 4;
+pub const ENDPOINT: u8 = 5;
+
+pub const DEVICE_DESCRIPTOR_SIZE: usize = 18;
+pub const CONFIGURATION_DESCRIPTOR_SIZE: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceDescriptor {
+    /// BCD encoded USB version
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub max_packet_size: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub configurations: u8,
+}
+
+impl DeviceDescriptor {
+    pub fn parse(raw: &[u8]) -> Option<Self> {
+        if raw.len() < DEVICE_DESCRIPTOR_SIZE || raw[1] != DEVICE {
+            return None;
+        }
+
+        Some(Self {
+            usb_version: u16::from_le_bytes([raw[2], raw[3]]),
+            class: raw[4],
+            subclass: raw[5],
+            protocol: raw[6],
+            max_packet_size: raw[7],
+            vendor_id: u16::from_le_bytes([raw[8], raw[9]]),
+            product_id: u16::from_le_bytes([raw[10], raw[11]]),
+            configurations: raw[17],
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigurationDescriptor {
+    /// Value passed to `SET_CONFIGURATION` to select the configuration
+    pub value: u8,
+    pub interfaces: Vec<Interface>,
+}
+
+impl ConfigurationDescriptor {
+    /// Parse a configuration descriptor along with the interface and endpoint
+    /// descriptors following it, skipping over any class-specific descriptors
+    /// and alternate settings other than the default
+    pub fn parse(raw: &[u8]) -> Option<Self> {
+        if raw.len() < CONFIGURATION_DESCRIPTOR_SIZE || raw[1] != CONFIGURATION {
+            return None;
+        }
+
+        let mut configuration = Self { value: raw[5], interfaces: Vec::new() };
+        let mut in_alternate = false;
+        let mut offset = usize::from(raw[0]);
+
+        while offset + 2 <= raw.len() {
+            let length = usize::from(raw[offset]);
+            let descriptor = raw.get(offset..offset + length)?;
+            if length < 2 {
+                return None;
+            }
+
+            match descriptor[1] {
+                INTERFACE if length >= 9 => {
+                    in_alternate = descriptor[3] != 0;
+                    if !in_alternate {
+                        configuration.interfaces.push(Interface {
+                            number: descriptor[2],
+                            class: descriptor[5],
+                            subclass: descriptor[6],
+                            protocol: descriptor[7],
+                            endpoints: Vec::new(),
+                        });
+                    }
+                }
+                ENDPOINT if length >= 7 && !in_alternate => {
+                    let interface = configuration.interfaces.last_mut()?;
+                    interface.endpoints.push(Endpoint {
+                        number: descriptor[2] & 0xF,
+                        direction: match descriptor[2] & 0x80 {
+                            0 => Direction::Out,
+                            _ => Direction::In,
+                        },
+                        kind: match descriptor[3] & 0b11 {
+                            0 => TransferKind::Control,
+                            1 => TransferKind::Isochronous,
+                            2 => TransferKind::Bulk,
+                            _ => TransferKind::Interrupt,
+                        },
+                        max_packet_size: u16::from_le_bytes([descriptor[4], descriptor[5]]) & 0x7FF,
+                        interval: descriptor[6],
+                    });
+                }
+                _ => {}
+            }
+
+            offset += length;
+        }
+
+        Some(configuration)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    pub number: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub endpoints: Vec<Endpoint>,
+}
+
+impl Interface {
+    pub fn find_endpoint(&self, kind: TransferKind, direction: Direction) -> Option<&Endpoint> {
+        self.endpoints.iter().find(|endpoint| endpoint.kind == kind && endpoint.direction == direction)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferKind {
+    Control,
+    Isochronous,
+    Bulk,
+    Interrupt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Endpoint {
+    pub number: u8,
+    pub direction: Direction,
+    pub kind: TransferKind,
+    pub max_packet_size: u16,
+    /// Polling interval, in frames or as an exponent of microframes depending
+    /// on the device speed
+    pub interval: u8,
+}
+
+impl Endpoint {
+    /// The endpoint number with the direction in the top bit, as used in
+    /// requests
+    pub fn address(&self) -> u8 {
+        match self.direction {
+            Direction::Out => self.number,
+            Direction::In => self.number | 0x80,
+        }
+    }
+}
@@ -0,0 +1,617 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! EHCI (USB 2.0) host controllers. Only high speed devices are handled on the
+//! root hub ports, low and full speed devices plugged into them are handed to
+//! the companion OHCI controller, but they're reached through the transaction
+//! translator of any high speed hub.
+//!
+//! Control and bulk transfers go through a single queue head on the
+//! asynchronous schedule which is rewritten for each transfer, with the data
+//! toggles tracked here. Each interrupt endpoint gets its own queue head on
+//! the periodic schedule, polled every frame.
+
+use crate::{
+    descriptor::{Endpoint, TransferKind},
+    poll_until, DeviceInfo, Direction, HostController, SetupPacket, Speed, UsbError,
+};
+use librust::mem::DmaRegion;
+use std::collections::BTreeMap;
+use volatile::{Read, Volatile};
+
+const FRAME_LIST_SIZE: usize = 1024;
+const MAX_INTERRUPT_ENDPOINTS: usize = 8;
+/// Size of the bounce buffer used for control and bulk transfers, which is the
+/// most a single transfer descriptor can hold with the buffer starting on a
+/// page boundary
+const BUFFER_SIZE: usize = 4 * 4096;
+const INTERRUPT_BUFFER_SIZE: usize = 64;
+
+/// Port resets must be held for at least 50ms for root ports
+const PORT_RESET_MS: u64 = 50;
+
+volatile::register_block! {
+    struct CapabilityRegisters [0x0C] {
+        0x00 => length: Volatile<u8, Read>,
+        0x04 => structural_parameters: Volatile<u32, Read>,
+        0x08 => capability_parameters: Volatile<u32, Read>,
+    }
+}
+
+volatile::register_block! {
+    struct OperationalRegisters [0x80] {
+        0x00 => command: Volatile<u32>,
+        0x04 => status: Volatile<u32>,
+        0x08 => interrupt_enable: Volatile<u32>,
+        0x10 => segment: Volatile<u32>,
+        0x14 => periodic_list_base: Volatile<u32>,
+        0x18 => async_list_address: Volatile<u32>,
+        0x40 => configure_flag: Volatile<u32>,
+        0x44 => ports: Volatile<[u32; 15]>,
+    }
+}
+
+mod command {
+    pub const RUN: u32 = 1 << 0;
+    pub const RESET: u32 = 1 << 1;
+    pub const PERIODIC_ENABLE: u32 = 1 << 4;
+    pub const ASYNC_ENABLE: u32 = 1 << 5;
+    /// Interrupt threshold of 8 microframes
+    pub const DEFAULT_THRESHOLD: u32 = 8 << 16;
+}
+
+mod status {
+    pub const ASYNC_ACTIVE: u32 = 1 << 15;
+    pub const HALTED: u32 = 1 << 12;
+}
+
+mod port {
+    pub const CONNECTED: u32 = 1 << 0;
+    pub const CONNECT_CHANGE: u32 = 1 << 1;
+    pub const ENABLED: u32 = 1 << 2;
+    pub const ENABLE_CHANGE: u32 = 1 << 3;
+    pub const OVER_CURRENT_CHANGE: u32 = 1 << 5;
+    pub const RESET: u32 = 1 << 8;
+    pub const LINE_STATUS: u32 = 0b11 << 10;
+    /// Line status of a low speed device
+    pub const K_STATE: u32 = 0b01 << 10;
+    pub const POWER: u32 = 1 << 12;
+    pub const OWNER: u32 = 1 << 13;
+    /// Bits which are cleared by writing a one
+    pub const CHANGE_BITS: u32 = CONNECT_CHANGE | ENABLE_CHANGE | OVER_CURRENT_CHANGE;
+}
+
+mod link {
+    pub const TERMINATE: u32 = 1;
+    pub const QUEUE_HEAD: u32 = 1 << 1;
+}
+
+mod token {
+    pub const ACTIVE: u32 = 1 << 7;
+    pub const HALTED: u32 = 1 << 6;
+    pub const BUFFER_ERROR: u32 = 1 << 5;
+    pub const BABBLE: u32 = 1 << 4;
+    pub const TRANSACTION_ERROR: u32 = 1 << 3;
+    pub const PID_OUT: u32 = 0 << 8;
+    pub const PID_IN: u32 = 1 << 8;
+    pub const PID_SETUP: u32 = 2 << 8;
+    /// Retry up to 3 times on errors
+    pub const ERROR_COUNT: u32 = 3 << 10;
+    pub const TOGGLE: u32 = 1 << 31;
+}
+
+/// Transfer descriptors must be 32 byte aligned, which they are since they're
+/// only ever allocated in arrays starting on a page boundary
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct TransferDescriptor {
+    next: u32,
+    alternate_next: u32,
+    token: u32,
+    buffers: [u32; 5],
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C, align(32))]
+struct QueueHead {
+    horizontal_link: u32,
+    characteristics: u32,
+    capabilities: u32,
+    current: u32,
+    overlay: TransferDescriptor,
+    /// Keeps queue heads in an array 32 byte aligned
+    _padding: [u32; 4],
+}
+
+const _: () = assert!(core::mem::size_of::<TransferDescriptor>() == 32);
+const _: () = assert!(core::mem::size_of::<QueueHead>() == 64);
+
+/// Descriptors used for control and bulk transfers
+mod td {
+    pub const SETUP: usize = 0;
+    pub const DATA: usize = 1;
+    pub const STATUS: usize = 2;
+    /// First descriptor used by interrupt endpoints, one each
+    pub const INTERRUPT: usize = 3;
+}
+
+/// The asynchronous schedule's only queue head, the rest belong to interrupt
+/// endpoints
+const ASYNC_QUEUE_HEAD: usize = 0;
+
+struct InterruptEndpoint {
+    index: usize,
+    length: usize,
+}
+
+pub struct Ehci {
+    registers: &'static OperationalRegisters,
+    ports: u8,
+    frame_list: DmaRegion<[u32]>,
+    queue_heads: DmaRegion<[QueueHead]>,
+    transfers: DmaRegion<[TransferDescriptor]>,
+    buffer: DmaRegion<[u8]>,
+    interrupt_buffers: DmaRegion<[[u8; INTERRUPT_BUFFER_SIZE]]>,
+    interrupt_endpoints: BTreeMap<(u8, u8), InterruptEndpoint>,
+    /// Next data toggle of each bulk endpoint, by address and endpoint address
+    toggles: BTreeMap<(u8, u8), bool>,
+    next_address: u8,
+}
+
+impl Ehci {
+    /// Reset the controller and start both schedules, routing every port to
+    /// this controller
+    ///
+    /// # Safety
+    ///
+    /// `base` must be a mapping of the controller's capability registers,
+    /// followed by its operational registers
+    pub unsafe fn new(base: *mut u8) -> Result<Self, UsbError> {
+        let capabilities = &*base.cast::<CapabilityRegisters>();
+        let registers = &*base.add(usize::from(capabilities.length().read())).cast::<OperationalRegisters>();
+        let ports = (capabilities.structural_parameters().read() & 0xF) as u8;
+
+        registers.command().modify(|c| c & !command::RUN);
+        poll_until(|| (registers.status().read() & status::HALTED != 0).then(|| ()))?;
+        registers.command().write(command::RESET);
+        poll_until(|| (registers.command().read() & command::RESET == 0).then(|| ()))?;
+
+        let mut ehci = Self {
+            registers,
+            ports,
+            frame_list: DmaRegion::zeroed_many(FRAME_LIST_SIZE).unwrap().assume_init(),
+            queue_heads: DmaRegion::zeroed_many(1 + MAX_INTERRUPT_ENDPOINTS).unwrap().assume_init(),
+            transfers: DmaRegion::zeroed_many(td::INTERRUPT + MAX_INTERRUPT_ENDPOINTS).unwrap().assume_init(),
+            buffer: DmaRegion::zeroed_many(BUFFER_SIZE).unwrap().assume_init(),
+            interrupt_buffers: DmaRegion::zeroed_many(MAX_INTERRUPT_ENDPOINTS).unwrap().assume_init(),
+            interrupt_endpoints: BTreeMap::new(),
+            toggles: BTreeMap::new(),
+            next_address: 1,
+        };
+
+        // The asynchronous queue head points at itself until it's needed, and
+        // the periodic schedule starts out empty
+        let async_head = ehci.queue_head_address(ASYNC_QUEUE_HEAD);
+        ehci.write_queue_head(
+            ASYNC_QUEUE_HEAD,
+            QueueHead {
+                horizontal_link: async_head | link::QUEUE_HEAD,
+                // Head of the reclamation list
+                characteristics: 1 << 15,
+                overlay: TransferDescriptor {
+                    next: link::TERMINATE,
+                    alternate_next: link::TERMINATE,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        for entry in ehci.frame_list.iter_mut() {
+            *entry = link::TERMINATE;
+        }
+
+        if capabilities.capability_parameters().read() & 1 != 0 {
+            registers.segment().write(0);
+        }
+        registers.interrupt_enable().write(0);
+        registers.periodic_list_base().write(ehci.frame_list.physical_address().as_usize() as u32);
+        registers.async_list_address().write(async_head);
+        registers.command().write(command::DEFAULT_THRESHOLD | command::RUN | command::PERIODIC_ENABLE);
+        registers.configure_flag().write(1);
+
+        for port in 0..usize::from(ports) {
+            registers.ports()[port].modify(|p| (p & !port::CHANGE_BITS) | port::POWER);
+        }
+        crate::delay_ms(20);
+
+        Ok(ehci)
+    }
+
+    fn queue_head_address(&self, index: usize) -> u32 {
+        (self.queue_heads.physical_address().as_usize() + index * core::mem::size_of::<QueueHead>()) as u32
+    }
+
+    fn transfer_address(&self, index: usize) -> u32 {
+        (self.transfers.physical_address().as_usize() + index * core::mem::size_of::<TransferDescriptor>()) as u32
+    }
+
+    fn write_queue_head(&mut self, index: usize, queue_head: QueueHead) {
+        unsafe { core::ptr::write_volatile(&mut self.queue_heads[index], queue_head) };
+    }
+
+    fn write_transfer(&mut self, index: usize, transfer: TransferDescriptor) {
+        unsafe { core::ptr::write_volatile(&mut self.transfers[index], transfer) };
+    }
+
+    fn transfer_token(&self, index: usize) -> u32 {
+        unsafe { core::ptr::read_volatile(&self.transfers[index].token) }
+    }
+
+    /// A transfer descriptor moving `length` bytes starting at physical address
+    /// `buffer`
+    fn transfer(next: u32, pid: u32, toggle: bool, buffer: u32, length: usize) -> TransferDescriptor {
+        let mut buffers = [0; 5];
+        buffers[0] = buffer;
+        for (i, page) in buffers.iter_mut().enumerate().skip(1) {
+            *page = (buffer & !0xFFF) + (i as u32) * 0x1000;
+        }
+
+        let toggle = if toggle { token::TOGGLE } else { 0 };
+        TransferDescriptor {
+            next,
+            alternate_next: link::TERMINATE,
+            token: toggle | (length as u32) << 16 | token::ERROR_COUNT | pid | token::ACTIVE,
+            buffers,
+        }
+    }
+
+    /// Endpoint characteristics and capabilities words of a queue head for the
+    /// endpoint
+    fn endpoint_words(device: &DeviceInfo, endpoint: u8, max_packet_size: u16, control: bool) -> (u32, u32) {
+        let speed = match device.speed {
+            Speed::Full => 0,
+            Speed::Low => 1,
+            Speed::High | Speed::Super => 2,
+        };
+
+        let mut characteristics =
+            u32::from(device.address) | u32::from(endpoint) << 8 | speed << 12 | u32::from(max_packet_size) << 16;
+        // Toggles come from the transfer descriptors rather than the overlay
+        characteristics |= 1 << 14;
+        if control && device.speed != Speed::High {
+            characteristics |= 1 << 27;
+        }
+
+        // One transaction per microframe, split transactions start in
+        // microframe 0 and complete in 2 through 4
+        let mut capabilities = 1 << 30 | 0x01;
+        if let Some((hub, port)) = device.transaction_translator {
+            capabilities |= 0x1C << 8 | u32::from(hub) << 16 | u32::from(port) << 23;
+        }
+
+        (characteristics, capabilities)
+    }
+
+    /// Run the chain of transfer descriptors starting at `first` on the
+    /// asynchronous queue head, waiting for `last` to finish
+    fn run_async(
+        &mut self,
+        characteristics: u32,
+        capabilities: u32,
+        first: usize,
+        last: usize,
+    ) -> Result<(), UsbError> {
+        self.set_async_schedule(false)?;
+
+        let head = self.queue_head_address(ASYNC_QUEUE_HEAD);
+        let next = self.transfer_address(first);
+        self.write_queue_head(
+            ASYNC_QUEUE_HEAD,
+            QueueHead {
+                horizontal_link: head | link::QUEUE_HEAD,
+                characteristics: characteristics | 1 << 15,
+                capabilities,
+                overlay: TransferDescriptor { next, alternate_next: link::TERMINATE, ..Default::default() },
+                ..Default::default()
+            },
+        );
+        librust::mem::fence(librust::mem::FenceMode::Write);
+
+        self.set_async_schedule(true)?;
+        let result = poll_until(|| {
+            // An error halts the queue before reaching the last descriptor
+            let halted = (first..=last).any(|i| self.transfer_token(i) & token::HALTED != 0);
+            (self.transfer_token(last) & token::ACTIVE == 0 || halted).then(|| ())
+        });
+        self.set_async_schedule(false)?;
+        result?;
+
+        (first..=last).try_for_each(|i| check_token(self.transfer_token(i)))
+    }
+
+    fn set_async_schedule(&mut self, enabled: bool) -> Result<(), UsbError> {
+        match enabled {
+            true => self.registers.command().modify(|c| c | command::ASYNC_ENABLE),
+            false => self.registers.command().modify(|c| c & !command::ASYNC_ENABLE),
+        }
+
+        let registers = self.registers;
+        poll_until(|| ((registers.status().read() & status::ASYNC_ACTIVE != 0) == enabled).then(|| ()))
+    }
+
+    fn buffer_address(&self, offset: usize) -> u32 {
+        (self.buffer.physical_address().as_usize() + offset) as u32
+    }
+}
+
+// The registers and DMA memory are only touched through `&mut self`
+unsafe impl Send for Ehci {}
+
+impl HostController for Ehci {
+    fn name(&self) -> &'static str {
+        "ehci"
+    }
+
+    fn port_count(&self) -> u8 {
+        self.ports
+    }
+
+    fn port_connected(&mut self, port: u8) -> bool {
+        self.registers.ports()[usize::from(port) - 1].read() & port::CONNECTED != 0
+    }
+
+    fn reset_port(&mut self, port: u8) -> Result<Speed, UsbError> {
+        let register = &self.registers.ports()[usize::from(port) - 1];
+        let status = register.read();
+        if status & port::CONNECTED == 0 {
+            return Err(UsbError::NotConnected);
+        }
+
+        // Low speed devices can be identified before the reset
+        if status & port::LINE_STATUS == port::K_STATE {
+            register.write((status & !port::CHANGE_BITS) | port::OWNER);
+            return Err(UsbError::NotConnected);
+        }
+
+        register.write((status & !(port::CHANGE_BITS | port::ENABLED)) | port::RESET);
+        crate::delay_ms(PORT_RESET_MS);
+        register.modify(|p| p & !(port::CHANGE_BITS | port::RESET));
+        poll_until(|| (register.read() & port::RESET == 0).then(|| ()))?;
+        crate::delay_ms(10);
+
+        // Full speed devices don't get enabled by the reset
+        let status = register.read();
+        if status & port::ENABLED == 0 {
+            register.write((status & !port::CHANGE_BITS) | port::OWNER);
+            return Err(UsbError::NotConnected);
+        }
+
+        register.write(status);
+        Ok(Speed::High)
+    }
+
+    fn address_device(&mut self, mut device: DeviceInfo) -> Result<DeviceInfo, UsbError> {
+        let address = self.next_address;
+        if address > 127 {
+            return Err(UsbError::NoResources);
+        }
+
+        self.control_transfer(&device, SetupPacket::set_address(address), &mut [])?;
+        self.next_address += 1;
+        crate::delay_ms(2);
+
+        device.address = address;
+        Ok(device)
+    }
+
+    fn set_max_packet_size(&mut self, device: &mut DeviceInfo, max_packet_size: u16) -> Result<(), UsbError> {
+        device.max_packet_size = max_packet_size;
+        Ok(())
+    }
+
+    fn configure_endpoint(&mut self, device: &DeviceInfo, endpoint: &Endpoint) -> Result<(), UsbError> {
+        match endpoint.kind {
+            TransferKind::Bulk => drop(self.toggles.insert((device.address, endpoint.address()), false)),
+            TransferKind::Interrupt if endpoint.direction == Direction::In => {
+                let index = self.interrupt_endpoints.len();
+                if index == MAX_INTERRUPT_ENDPOINTS {
+                    return Err(UsbError::NoResources);
+                }
+
+                let length = usize::from(endpoint.max_packet_size).min(INTERRUPT_BUFFER_SIZE);
+                let (characteristics, capabilities) =
+                    Self::endpoint_words(device, endpoint.number, endpoint.max_packet_size, false);
+                let queue_head = 1 + index;
+                let transfer = td::INTERRUPT + index;
+                let buffer = self.interrupt_buffers.physical_address().as_usize() + index * INTERRUPT_BUFFER_SIZE;
+
+                self.write_transfer(
+                    transfer,
+                    Self::transfer(link::TERMINATE, token::PID_IN, false, buffer as u32, length),
+                );
+
+                // Queue heads are pushed onto the front of the periodic list,
+                // and manage their own toggles
+                let previous = self.frame_list[0];
+                self.write_queue_head(
+                    queue_head,
+                    QueueHead {
+                        horizontal_link: previous,
+                        characteristics: characteristics & !(1 << 14),
+                        capabilities,
+                        overlay: TransferDescriptor {
+                            next: self.transfer_address(transfer),
+                            alternate_next: link::TERMINATE,
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    },
+                );
+                librust::mem::fence(librust::mem::FenceMode::Write);
+
+                let entry = self.queue_head_address(queue_head) | link::QUEUE_HEAD;
+                for slot in self.frame_list.iter_mut() {
+                    unsafe { core::ptr::write_volatile(slot, entry) };
+                }
+
+                self.interrupt_endpoints
+                    .insert((device.address, endpoint.address()), InterruptEndpoint { index, length });
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn control_transfer(
+        &mut self,
+        device: &DeviceInfo,
+        setup: SetupPacket,
+        data: &mut [u8],
+    ) -> Result<usize, UsbError> {
+        // The setup packet lives at the start of the buffer, and the data after
+        // it
+        let data_offset = 64;
+        let length = usize::from(setup.length).min(data.len());
+        if length > BUFFER_SIZE - data_offset {
+            return Err(UsbError::Controller("transfer too large"));
+        }
+
+        self.buffer[..8].copy_from_slice(&setup.to_bytes());
+        let direction = setup.direction();
+        if direction == Direction::Out {
+            self.buffer[data_offset..data_offset + length].copy_from_slice(&data[..length]);
+        }
+
+        let (data_pid, status_pid) = match direction {
+            Direction::In => (token::PID_IN, token::PID_OUT),
+            Direction::Out => (token::PID_OUT, token::PID_IN),
+        };
+        // A request without a data stage always has its status stage IN
+        let status_pid = if length == 0 { token::PID_IN } else { status_pid };
+
+        let status = Self::transfer(link::TERMINATE, status_pid, true, 0, 0);
+        self.write_transfer(td::STATUS, status);
+        let after_setup = match length {
+            0 => self.transfer_address(td::STATUS),
+            _ => {
+                let next = self.transfer_address(td::STATUS);
+                let transfer = Self::transfer(next, data_pid, true, self.buffer_address(data_offset), length);
+                self.write_transfer(td::DATA, transfer);
+                self.transfer_address(td::DATA)
+            }
+        };
+        let setup_transfer = Self::transfer(after_setup, token::PID_SETUP, false, self.buffer_address(0), 8);
+        self.write_transfer(td::SETUP, setup_transfer);
+
+        let (characteristics, capabilities) = Self::endpoint_words(device, 0, device.max_packet_size, true);
+        // The data stage is skipped over when there isn't one, but it's
+        // simpler to check every descriptor
+        if length == 0 {
+            self.write_transfer(td::DATA, TransferDescriptor::default());
+        }
+        self.run_async(characteristics, capabilities, td::SETUP, td::STATUS)?;
+
+        let transferred = match length {
+            0 => 0,
+            _ => length - ((self.transfer_token(td::DATA) >> 16) & 0x7FFF) as usize,
+        };
+
+        if direction == Direction::In {
+            data[..transferred].copy_from_slice(&self.buffer[data_offset..data_offset + transferred]);
+        }
+
+        Ok(transferred)
+    }
+
+    fn bulk_transfer(&mut self, device: &DeviceInfo, endpoint: &Endpoint, data: &mut [u8]) -> Result<usize, UsbError> {
+        if data.len() > BUFFER_SIZE {
+            return Err(UsbError::Controller("transfer too large"));
+        }
+
+        let key = (device.address, endpoint.address());
+        let toggle = self.toggles.get(&key).copied().unwrap_or(false);
+        if endpoint.direction == Direction::Out {
+            self.buffer[..data.len()].copy_from_slice(data);
+        }
+
+        let pid = match endpoint.direction {
+            Direction::In => token::PID_IN,
+            Direction::Out => token::PID_OUT,
+        };
+        let transfer = Self::transfer(link::TERMINATE, pid, toggle, self.buffer_address(0), data.len());
+        self.write_transfer(td::DATA, transfer);
+
+        let (characteristics, capabilities) =
+            Self::endpoint_words(device, endpoint.number, endpoint.max_packet_size, false);
+        let result = self.run_async(characteristics, capabilities, td::DATA, td::DATA);
+
+        // The controller flips the toggle in the descriptor after each packet,
+        // and a stall resets it
+        let finished = self.transfer_token(td::DATA);
+        let toggle = match result {
+            Err(UsbError::Stall) => false,
+            _ => finished & token::TOGGLE != 0,
+        };
+        self.toggles.insert(key, toggle);
+        result?;
+
+        let transferred = data.len() - ((finished >> 16) & 0x7FFF) as usize;
+        if endpoint.direction == Direction::In {
+            data[..transferred].copy_from_slice(&self.buffer[..transferred]);
+        }
+
+        Ok(transferred)
+    }
+
+    fn poll_interrupt(
+        &mut self,
+        device: &DeviceInfo,
+        endpoint: &Endpoint,
+        data: &mut [u8],
+    ) -> Result<Option<usize>, UsbError> {
+        let (index, length) = match self.interrupt_endpoints.get(&(device.address, endpoint.address())) {
+            Some(endpoint) => (endpoint.index, endpoint.length),
+            None => return Err(UsbError::Controller("interrupt endpoint not configured")),
+        };
+
+        let transfer = td::INTERRUPT + index;
+        let finished = self.transfer_token(transfer);
+        if finished & token::ACTIVE != 0 {
+            return Ok(None);
+        }
+
+        let result = check_token(finished);
+        let transferred = length - ((finished >> 16) & 0x7FFF) as usize;
+        let received = &self.interrupt_buffers[index][..transferred];
+        let copied = transferred.min(data.len());
+        data[..copied].copy_from_slice(&received[..copied]);
+
+        // Requeue the descriptor, the queue head picks it back up since its
+        // overlay still points to it
+        let buffer = self.interrupt_buffers.physical_address().as_usize() + index * INTERRUPT_BUFFER_SIZE;
+        self.write_transfer(transfer, Self::transfer(link::TERMINATE, token::PID_IN, false, buffer as u32, length));
+        let queue_head = 1 + index;
+        let mut overlay = unsafe { core::ptr::read_volatile(&self.queue_heads[queue_head].overlay) };
+        overlay.next = self.transfer_address(transfer);
+        // Keep the toggle the queue head has been tracking
+        overlay.token &= token::TOGGLE;
+        unsafe { core::ptr::write_volatile(&mut self.queue_heads[queue_head].overlay, overlay) };
+
+        result.map(|_| Some(copied))
+    }
+}
+
+fn check_token(value: u32) -> Result<(), UsbError> {
+    match value {
+        _ if value & token::HALTED == 0 => Ok(()),
+        _ if value & (token::BABBLE | token::TRANSACTION_ERROR | token::BUFFER_ERROR) != 0 => {
+            Err(UsbError::TransactionError)
+        }
+        _ => Err(UsbError::Stall),
+    }
+}
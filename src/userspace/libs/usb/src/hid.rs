@@ -0,0 +1,293 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Keyboards using the HID boot protocol, which every keyboard supports and
+//! which has a fixed report format, so no report descriptor parsing is needed.
+//! Reports are turned into key events, and those into the bytes a terminal
+//! would send for them with a US layout.
+
+use crate::{
+    descriptor::{Endpoint, TransferKind},
+    request_type, Bus, Device, Direction, SetupPacket, UsbError,
+};
+
+pub const CLASS: u8 = 0x03;
+pub const SUBCLASS_BOOT: u8 = 0x01;
+pub const PROTOCOL_KEYBOARD: u8 = 0x01;
+
+const SET_REPORT: u8 = 0x09;
+const SET_IDLE: u8 = 0x0A;
+const SET_PROTOCOL: u8 = 0x0B;
+const BOOT_PROTOCOL: u16 = 0;
+const OUTPUT_REPORT: u16 = 2 << 8;
+
+const REPORT_SIZE: usize = 8;
+/// Reported in every key slot when too many keys are held down
+const ROLLOVER_ERROR: u8 = 0x01;
+
+/// How long a key is held before it starts repeating
+const REPEAT_DELAY_US: u64 = 500_000;
+const REPEAT_INTERVAL_US: u64 = 33_000;
+
+pub mod modifier {
+    pub const LEFT_CTRL: u8 = 1 << 0;
+    pub const LEFT_SHIFT: u8 = 1 << 1;
+    pub const LEFT_ALT: u8 = 1 << 2;
+    pub const LEFT_GUI: u8 = 1 << 3;
+    pub const RIGHT_CTRL: u8 = 1 << 4;
+    pub const RIGHT_SHIFT: u8 = 1 << 5;
+    pub const RIGHT_ALT: u8 = 1 << 6;
+    pub const RIGHT_GUI: u8 = 1 << 7;
+
+    pub const CTRL: u8 = LEFT_CTRL | RIGHT_CTRL;
+    pub const SHIFT: u8 = LEFT_SHIFT | RIGHT_SHIFT;
+    pub const ALT: u8 = LEFT_ALT | RIGHT_ALT;
+}
+
+mod led {
+    pub const CAPS_LOCK: u8 = 1 << 1;
+}
+
+/// HID usage IDs from the keyboard usage page which aren't printable
+pub mod usage {
+    pub const ENTER: u8 = 0x28;
+    pub const ESCAPE: u8 = 0x29;
+    pub const BACKSPACE: u8 = 0x2A;
+    pub const TAB: u8 = 0x2B;
+    pub const CAPS_LOCK: u8 = 0x39;
+    pub const INSERT: u8 = 0x49;
+    pub const HOME: u8 = 0x4A;
+    pub const PAGE_UP: u8 = 0x4B;
+    pub const DELETE: u8 = 0x4C;
+    pub const END: u8 = 0x4D;
+    pub const PAGE_DOWN: u8 = 0x4E;
+    pub const RIGHT: u8 = 0x4F;
+    pub const LEFT: u8 = 0x50;
+    pub const DOWN: u8 = 0x51;
+    pub const UP: u8 = 0x52;
+}
+
+/// Unshifted and shifted characters for the printable usages starting at
+/// `0x2C` (space)
+const PUNCTUATION: &[(u8, u8)] = &[
+    (b' ', b' '),
+    (b'-', b'_'),
+    (b'=', b'+'),
+    (b'[', b'{'),
+    (b']', b'}'),
+    (b'\\', b'|'),
+    // Non-US `#`, which is where `\` is on US keyboards
+    (b'\\', b'|'),
+    (b';', b':'),
+    (b'\'', b'"'),
+    (b'`', b'~'),
+    (b',', b'<'),
+    (b'.', b'>'),
+    (b'/', b'?'),
+];
+
+const DIGITS_SHIFTED: &[u8; 10] = b"!@#$%^&*()";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub usage: u8,
+    pub pressed: bool,
+    /// Modifier keys held at the time of the event, see [`modifier`]
+    pub modifiers: u8,
+}
+
+impl KeyEvent {
+    /// The bytes a terminal would send for the key being pressed, if any
+    pub fn to_bytes(self, caps_lock: bool) -> Vec<u8> {
+        if !self.pressed {
+            return Vec::new();
+        }
+
+        let shift = self.modifiers & modifier::SHIFT != 0;
+        let ctrl = self.modifiers & modifier::CTRL != 0;
+        let alt = self.modifiers & modifier::ALT != 0;
+
+        let byte = match self.usage {
+            0x04..=0x1D => {
+                let letter = b'a' + (self.usage - 0x04);
+                match (ctrl, shift != caps_lock) {
+                    (true, _) => letter & 0x1F,
+                    (false, true) => letter.to_ascii_uppercase(),
+                    (false, false) => letter,
+                }
+            }
+            // 1 through 9 and then 0
+            0x1E..=0x27 => {
+                let index = usize::from(self.usage - 0x1E);
+                match shift {
+                    true => DIGITS_SHIFTED[index],
+                    false => b"1234567890"[index],
+                }
+            }
+            0x2C..=0x38 => {
+                let (normal, shifted) = PUNCTUATION[usize::from(self.usage - 0x2C)];
+                match (ctrl, shift) {
+                    // `Ctrl-[`, `Ctrl-\`, and `Ctrl-]`
+                    (true, _) if (b'['..=b']').contains(&normal) => normal & 0x1F,
+                    (_, true) => shifted,
+                    (_, false) => normal,
+                }
+            }
+            usage::ENTER => b'\r',
+            usage::ESCAPE => 0x1B,
+            usage::BACKSPACE => 0x7F,
+            usage::TAB => b'\t',
+            usage => {
+                let sequence: &[u8] = match usage {
+                    usage::UP => b"\x1B[A",
+                    usage::DOWN => b"\x1B[B",
+                    usage::RIGHT => b"\x1B[C",
+                    usage::LEFT => b"\x1B[D",
+                    usage::HOME => b"\x1B[H",
+                    usage::END => b"\x1B[F",
+                    usage::INSERT => b"\x1B[2~",
+                    usage::DELETE => b"\x1B[3~",
+                    usage::PAGE_UP => b"\x1B[5~",
+                    usage::PAGE_DOWN => b"\x1B[6~",
+                    _ => b"",
+                };
+
+                return sequence.to_vec();
+            }
+        };
+
+        // Alt sends the key prefixed with an escape, like most terminals
+        match alt {
+            true => vec![0x1B, byte],
+            false => vec![byte],
+        }
+    }
+}
+
+/// A boot protocol keyboard on the bus at index `device`
+pub struct Keyboard {
+    pub device: usize,
+    interface: u8,
+    endpoint: Endpoint,
+    previous: [u8; REPORT_SIZE],
+    caps_lock: bool,
+    /// The last key pressed and when it should next repeat
+    repeat: Option<(KeyEvent, u64)>,
+}
+
+impl Keyboard {
+    /// Whether the device has a boot keyboard interface
+    pub fn supports(device: &Device) -> bool {
+        device.find_interface(CLASS, Some(SUBCLASS_BOOT), Some(PROTOCOL_KEYBOARD)).is_some()
+    }
+
+    /// Switch the keyboard at index `device` on the bus to the boot protocol
+    pub fn new(bus: &mut Bus, device: usize) -> Result<Self, UsbError> {
+        let interface = bus.devices[device]
+            .find_interface(CLASS, Some(SUBCLASS_BOOT), Some(PROTOCOL_KEYBOARD))
+            .ok_or(UsbError::InvalidDescriptor)?;
+        let endpoint =
+            *interface.find_endpoint(TransferKind::Interrupt, Direction::In).ok_or(UsbError::InvalidDescriptor)?;
+
+        let keyboard = Self {
+            device,
+            interface: interface.number,
+            endpoint,
+            previous: [0; REPORT_SIZE],
+            caps_lock: false,
+            repeat: None,
+        };
+
+        keyboard.class_request(bus, SET_PROTOCOL, BOOT_PROTOCOL, &mut [])?;
+        // Only report when something changes, key repeat is done here instead.
+        // Some keyboards don't support this, which is harmless.
+        let _ = keyboard.class_request(bus, SET_IDLE, 0, &mut []);
+        keyboard.set_leds(bus)?;
+
+        Ok(keyboard)
+    }
+
+    /// Check for a new report from the keyboard, returning the bytes for any
+    /// keys pressed since the last poll, including repeats of the key being held
+    pub fn poll(&mut self, bus: &mut Bus) -> Result<Vec<u8>, UsbError> {
+        let info = bus.devices[self.device].info;
+        let mut report = [0; REPORT_SIZE];
+        let mut bytes = Vec::new();
+
+        if let Some(read) = bus.controller.poll_interrupt(&info, &self.endpoint, &mut report)? {
+            if read >= 3 && report[2] != ROLLOVER_ERROR {
+                for event in self.events(&report) {
+                    if event.pressed && event.usage == usage::CAPS_LOCK {
+                        self.caps_lock = !self.caps_lock;
+                        self.set_leds(bus)?;
+                    }
+
+                    self.repeat = match (event.pressed, self.repeat) {
+                        (true, _) => Some((event, crate::uptime_us() + REPEAT_DELAY_US)),
+                        (false, Some((held, _))) if held.usage == event.usage => None,
+                        (false, repeat) => repeat,
+                    };
+
+                    bytes.extend(event.to_bytes(self.caps_lock));
+                }
+
+                self.previous = report;
+            }
+        }
+
+        if let Some((event, at)) = &mut self.repeat {
+            let now = crate::uptime_us();
+            if now >= *at {
+                *at = now + REPEAT_INTERVAL_US;
+                bytes.extend(event.to_bytes(self.caps_lock));
+            }
+        }
+
+        Ok(bytes)
+    }
+
+    /// Compare a report against the last one to find which keys were pressed
+    /// and released
+    fn events(&self, report: &[u8; REPORT_SIZE]) -> Vec<KeyEvent> {
+        let modifiers = report[0];
+        let held = |keys: &[u8], usage: u8| keys.contains(&usage);
+
+        let released = self.previous[2..].iter().filter(|&&usage| usage > ROLLOVER_ERROR && !held(&report[2..], usage));
+        let pressed = report[2..].iter().filter(|&&usage| usage > ROLLOVER_ERROR && !held(&self.previous[2..], usage));
+
+        released
+            .map(|&usage| KeyEvent { usage, pressed: false, modifiers })
+            .chain(pressed.map(|&usage| KeyEvent { usage, pressed: true, modifiers }))
+            .collect()
+    }
+
+    fn set_leds(&self, bus: &mut Bus) -> Result<(), UsbError> {
+        let mut leds = [match self.caps_lock {
+            true => led::CAPS_LOCK,
+            false => 0,
+        }];
+
+        self.class_request(bus, SET_REPORT, OUTPUT_REPORT, &mut leds)
+    }
+
+    fn class_request(&self, bus: &mut Bus, request: u8, value: u16, data: &mut [u8]) -> Result<(), UsbError> {
+        let info = bus.devices[self.device].info;
+        bus.controller
+            .control_transfer(
+                &info,
+                SetupPacket {
+                    request_type: request_type::CLASS | request_type::TO_INTERFACE,
+                    request,
+                    value,
+                    index: u16::from(self.interface),
+                    length: data.len() as u16,
+                },
+                data,
+            )
+            .map(drop)
+    }
+}
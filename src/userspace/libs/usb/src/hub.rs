@@ -0,0 +1,156 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! USB 2.0 hubs. Ports are powered and enumerated once when the hub is
+//! attached, devices plugged in later aren't noticed.
+
+use crate::{delay_ms, request, request_type, Bus, DeviceInfo, SetupPacket, Speed, UsbError};
+
+pub const CLASS: u8 = 0x09;
+const HUB_DESCRIPTOR: u8 = 0x29;
+
+mod feature {
+    pub const PORT_RESET: u16 = 4;
+    pub const PORT_POWER: u16 = 8;
+    pub const C_PORT_CONNECTION: u16 = 16;
+    pub const C_PORT_RESET: u16 = 20;
+}
+
+mod status {
+    pub const CONNECTION: u32 = 1 << 0;
+    pub const ENABLE: u32 = 1 << 1;
+    pub const LOW_SPEED: u32 = 1 << 9;
+    pub const HIGH_SPEED: u32 = 1 << 10;
+    pub const C_RESET: u32 = 1 << 20;
+}
+
+/// Hubs can be at most 5 tiers deep, which is all the route string has room
+/// for
+const MAX_DEPTH: u32 = 5;
+/// Time after the reset signal ends before the device must respond
+const RESET_RECOVERY_MS: u64 = 10;
+
+/// Power every port of the hub at `index` in the bus' device list and attach
+/// whatever is connected to them
+pub fn enumerate(bus: &mut Bus, index: usize) -> Result<(), UsbError> {
+    let hub = bus.devices[index].info;
+    if hub.speed == Speed::Super {
+        println!("[usb] {}: super speed hubs aren't supported", bus.controller.name());
+        return Ok(());
+    }
+
+    let mut descriptor = [0; 8];
+    bus.controller.control_transfer(
+        &hub,
+        SetupPacket {
+            request_type: request_type::DEVICE_TO_HOST | request_type::CLASS,
+            request: request::GET_DESCRIPTOR,
+            value: u16::from(HUB_DESCRIPTOR) << 8,
+            index: 0,
+            length: descriptor.len() as u16,
+        },
+        &mut descriptor,
+    )?;
+
+    let ports = descriptor[2];
+    // Given in units of 2ms
+    let power_on_delay = u64::from(descriptor[5]) * 2;
+    bus.controller.configure_hub(&hub, ports)?;
+
+    for port in 1..=ports {
+        port_feature(bus, &hub, request::SET_FEATURE, port, feature::PORT_POWER)?;
+    }
+    delay_ms(power_on_delay.max(100));
+
+    for port in 1..=ports {
+        if port_status(bus, &hub, port)? & status::CONNECTION == 0 {
+            continue;
+        }
+
+        port_feature(bus, &hub, request::CLEAR_FEATURE, port, feature::C_PORT_CONNECTION)?;
+        port_feature(bus, &hub, request::SET_FEATURE, port, feature::PORT_RESET)?;
+        let status = crate::poll_until(|| match port_status(bus, &hub, port) {
+            Ok(status) if status & status::C_RESET != 0 => Some(Ok(status)),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        })??;
+        port_feature(bus, &hub, request::CLEAR_FEATURE, port, feature::C_PORT_RESET)?;
+        delay_ms(RESET_RECOVERY_MS);
+
+        if status & status::ENABLE == 0 {
+            continue;
+        }
+
+        if hub.depth() >= MAX_DEPTH {
+            println!("[usb] {}: hubs nested too deeply, ignoring port {}", bus.controller.name(), port);
+            continue;
+        }
+
+        let speed = match status {
+            _ if status & status::LOW_SPEED != 0 => Speed::Low,
+            _ if status & status::HIGH_SPEED != 0 => Speed::High,
+            _ => Speed::Full,
+        };
+
+        // Low and full speed devices behind a high speed hub are reached
+        // through that hub's transaction translator, otherwise they use the
+        // same one as the hub itself
+        let transaction_translator = match (hub.speed, speed) {
+            (Speed::High, Speed::Low | Speed::Full) => Some((hub.address, port)),
+            _ => hub.transaction_translator,
+        };
+
+        let device = DeviceInfo {
+            address: 0,
+            speed,
+            root_port: hub.root_port,
+            route: hub.route | u32::from(port.min(15)) << (4 * hub.depth()),
+            transaction_translator,
+            max_packet_size: speed.default_max_packet_size(),
+        };
+
+        if let Err(e) = bus.attach(device) {
+            println!("[usb] {}: failed to enumerate device on hub port {}: {:?}", bus.controller.name(), port, e);
+        }
+    }
+
+    Ok(())
+}
+
+fn port_feature(bus: &mut Bus, hub: &DeviceInfo, request: u8, port: u8, feature: u16) -> Result<(), UsbError> {
+    bus.controller
+        .control_transfer(
+            hub,
+            SetupPacket {
+                request_type: request_type::CLASS | request_type::TO_OTHER,
+                request,
+                value: feature,
+                index: u16::from(port),
+                length: 0,
+            },
+            &mut [],
+        )
+        .map(drop)
+}
+
+/// The port status in the low half and what changed in the high half
+fn port_status(bus: &mut Bus, hub: &DeviceInfo, port: u8) -> Result<u32, UsbError> {
+    let mut status = [0; 4];
+    bus.controller.control_transfer(
+        hub,
+        SetupPacket {
+            request_type: request_type::DEVICE_TO_HOST | request_type::CLASS | request_type::TO_OTHER,
+            request: request::GET_STATUS,
+            value: 0,
+            index: u16::from(port),
+            length: 4,
+        },
+        &mut status,
+    )?;
+
+    Ok(u32::from_le_bytes(status))
+}
@@ -0,0 +1,429 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A USB host stack. Host controller drivers implement [`HostController`],
+//! which [`Bus`] uses to enumerate the devices behind the root hub ports and
+//! any hubs plugged into them, and class drivers are built on top of the
+//! enumerated [`Device`]s. Everything is polled: transfers spin until the
+//! controller reports that they've finished.
+
+pub mod descriptor;
+pub mod ehci;
+pub mod hid;
+pub mod hub;
+pub mod mass_storage;
+pub mod ohci;
+pub mod xhci;
+
+pub use descriptor::{ConfigurationDescriptor, DeviceDescriptor, Endpoint, Interface, TransferKind};
+
+use json_rpc::RpcError;
+use librust::message::SyscallResult;
+use std::ipc::IpcChannel;
+
+/// How long to wait for a transfer to finish before giving up on it
+pub const TRANSFER_TIMEOUT_MS: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Speed {
+    Low,
+    Full,
+    High,
+    Super,
+}
+
+impl Speed {
+    /// Max packet size of the default control endpoint to use until the real
+    /// one is read from the device descriptor
+    pub fn default_max_packet_size(self) -> u16 {
+        match self {
+            Speed::Low => 8,
+            Speed::Full | Speed::High => 64,
+            Speed::Super => 512,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Direction {
+    Out,
+    In,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbError {
+    /// The device stalled the endpoint
+    Stall,
+    /// The transfer didn't finish within [`TRANSFER_TIMEOUT_MS`]
+    Timeout,
+    /// CRC, bit stuffing, babble, or some other error on the bus
+    TransactionError,
+    /// The controller is out of device addresses or slots
+    NoResources,
+    /// The port has nothing connected to it, or it was disconnected
+    NotConnected,
+    /// The device returned a descriptor which doesn't make sense
+    InvalidDescriptor,
+    /// The controller failed in a way specific to it
+    Controller(&'static str),
+}
+
+/// The 8 byte packet starting every control transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+pub mod request {
+    pub const GET_STATUS: u8 = 0;
+    pub const CLEAR_FEATURE: u8 = 1;
+    pub const SET_FEATURE: u8 = 3;
+    pub const SET_ADDRESS: u8 = 5;
+    pub const GET_DESCRIPTOR: u8 = 6;
+    pub const SET_CONFIGURATION: u8 = 9;
+    pub const SET_INTERFACE: u8 = 11;
+}
+
+pub mod request_type {
+    pub const DEVICE_TO_HOST: u8 = 0x80;
+    pub const CLASS: u8 = 0x20;
+    pub const TO_INTERFACE: u8 = 0x01;
+    pub const TO_ENDPOINT: u8 = 0x02;
+    pub const TO_OTHER: u8 = 0x03;
+}
+
+impl SetupPacket {
+    pub fn get_descriptor(ty: u8, index: u8, length: u16) -> Self {
+        Self {
+            request_type: request_type::DEVICE_TO_HOST,
+            request: request::GET_DESCRIPTOR,
+            value: u16::from(ty) << 8 | u16::from(index),
+            index: 0,
+            length,
+        }
+    }
+
+    pub fn set_address(address: u8) -> Self {
+        Self { request_type: 0, request: request::SET_ADDRESS, value: u16::from(address), index: 0, length: 0 }
+    }
+
+    pub fn set_configuration(value: u8) -> Self {
+        Self { request_type: 0, request: request::SET_CONFIGURATION, value: u16::from(value), index: 0, length: 0 }
+    }
+
+    /// Clear the halt condition on an endpoint after it stalled
+    pub fn clear_halt(endpoint: &Endpoint) -> Self {
+        Self {
+            request_type: request_type::TO_ENDPOINT,
+            request: request::CLEAR_FEATURE,
+            value: 0,
+            index: u16::from(endpoint.address()),
+            length: 0,
+        }
+    }
+
+    /// Direction of the data stage, if there is one
+    pub fn direction(&self) -> Direction {
+        match self.request_type & request_type::DEVICE_TO_HOST {
+            0 => Direction::Out,
+            _ => Direction::In,
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; 8] {
+        let [value0, value1] = self.value.to_le_bytes();
+        let [index0, index1] = self.index.to_le_bytes();
+        let [length0, length1] = self.length.to_le_bytes();
+
+        [self.request_type, self.request, value0, value1, index0, index1, length0, length1]
+    }
+}
+
+/// How to reach a device, which controllers need to address it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// Zero until the controller has given the device its address
+    pub address: u8,
+    pub speed: Speed,
+    /// The root hub port (starting at 1) the device is on or behind
+    pub root_port: u8,
+    /// The downstream hub port at each tier below the root hub, 4 bits per
+    /// tier starting from the least significant bits, as xHCI expects
+    pub route: u32,
+    /// For low and full speed devices behind a high speed hub, the address and
+    /// port of the hub doing the transaction translation
+    pub transaction_translator: Option<(u8, u8)>,
+    pub max_packet_size: u16,
+}
+
+impl DeviceInfo {
+    /// A device plugged directly into a root hub port
+    pub fn root(port: u8, speed: Speed) -> Self {
+        Self {
+            address: 0,
+            speed,
+            root_port: port,
+            route: 0,
+            transaction_translator: None,
+            max_packet_size: speed.default_max_packet_size(),
+        }
+    }
+
+    /// Number of hubs between the device and the root hub
+    pub fn depth(&self) -> u32 {
+        (32 - self.route.leading_zeros() + 3) / 4
+    }
+}
+
+/// Controllers are `Send` so a bus can be shared between the thread serving
+/// requests and one polling interrupt endpoints
+pub trait HostController: Send {
+    fn name(&self) -> &'static str;
+
+    /// Number of root hub ports, which are numbered starting at 1
+    fn port_count(&self) -> u8;
+    fn port_connected(&mut self, port: u8) -> bool;
+    /// Reset a root hub port, returning the speed of the device connected to
+    /// it once the port is enabled
+    fn reset_port(&mut self, port: u8) -> Result<Speed, UsbError>;
+
+    /// Move a newly reset device from the default address to a unique one,
+    /// returning it with its address filled in
+    fn address_device(&mut self, device: DeviceInfo) -> Result<DeviceInfo, UsbError>;
+    /// Update the max packet size of the default control endpoint once it's
+    /// been read from the device descriptor
+    fn set_max_packet_size(&mut self, device: &mut DeviceInfo, max_packet_size: u16) -> Result<(), UsbError>;
+    /// Prepare the controller to transfer to a non-control endpoint of the
+    /// active configuration
+    fn configure_endpoint(&mut self, device: &DeviceInfo, endpoint: &Endpoint) -> Result<(), UsbError>;
+    /// Tell the controller that a device is a hub, which xHCI needs to know
+    /// before it can route to devices behind it
+    fn configure_hub(&mut self, _device: &DeviceInfo, _ports: u8) -> Result<(), UsbError> {
+        Ok(())
+    }
+
+    /// Run a control transfer on the default endpoint, returning the number of
+    /// bytes transferred in the data stage
+    fn control_transfer(&mut self, device: &DeviceInfo, setup: SetupPacket, data: &mut [u8])
+        -> Result<usize, UsbError>;
+    /// Run a bulk transfer in the direction of `endpoint`, returning the number
+    /// of bytes transferred
+    fn bulk_transfer(&mut self, device: &DeviceInfo, endpoint: &Endpoint, data: &mut [u8]) -> Result<usize, UsbError>;
+    /// Poll an interrupt endpoint without blocking. A transfer is kept queued
+    /// on the endpoint between calls, and `Ok(None)` is returned until the
+    /// device has responded to it.
+    fn poll_interrupt(
+        &mut self,
+        device: &DeviceInfo,
+        endpoint: &Endpoint,
+        data: &mut [u8],
+    ) -> Result<Option<usize>, UsbError>;
+}
+
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub info: DeviceInfo,
+    pub descriptor: DeviceDescriptor,
+    pub configuration: ConfigurationDescriptor,
+}
+
+impl Device {
+    /// Find the first interface with the given class, subclass, and protocol,
+    /// where `None` matches anything
+    pub fn find_interface(&self, class: u8, subclass: Option<u8>, protocol: Option<u8>) -> Option<&Interface> {
+        self.configuration.interfaces.iter().find(|interface| {
+            interface.class == class
+                && subclass.map_or(true, |s| s == interface.subclass)
+                && protocol.map_or(true, |p| p == interface.protocol)
+        })
+    }
+}
+
+/// A host controller and every device enumerated on it
+pub struct Bus {
+    pub controller: Box<dyn HostController>,
+    pub devices: Vec<Device>,
+}
+
+impl Bus {
+    pub fn new(controller: Box<dyn HostController>) -> Self {
+        Self { controller, devices: Vec::new() }
+    }
+
+    /// Reset every connected root hub port and enumerate the devices on them,
+    /// including everything behind any hubs. Devices which fail to enumerate
+    /// are skipped.
+    pub fn enumerate(&mut self) {
+        for port in 1..=self.controller.port_count() {
+            if !self.controller.port_connected(port) {
+                continue;
+            }
+
+            let speed = match self.controller.reset_port(port) {
+                Ok(speed) => speed,
+                Err(e) => {
+                    println!("[usb] {}: failed to reset port {}: {:?}", self.controller.name(), port, e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = self.attach(DeviceInfo::root(port, speed)) {
+                println!("[usb] {}: failed to enumerate device on port {}: {:?}", self.controller.name(), port, e);
+            }
+        }
+    }
+
+    /// Address, identify, and configure a newly reset device, recursing into
+    /// it if it's a hub
+    pub fn attach(&mut self, info: DeviceInfo) -> Result<(), UsbError> {
+        let mut info = self.controller.address_device(info)?;
+
+        // Only the first 8 bytes can be read safely before the max packet size
+        // is known
+        let mut header = [0; 8];
+        self.controller.control_transfer(&info, SetupPacket::get_descriptor(descriptor::DEVICE, 0, 8), &mut header)?;
+        let max_packet_size = match info.speed {
+            // Super speed devices give it as an exponent
+            Speed::Super => 1 << header[7],
+            _ => u16::from(header[7]),
+        };
+        self.controller.set_max_packet_size(&mut info, max_packet_size)?;
+
+        let mut raw = [0; descriptor::DEVICE_DESCRIPTOR_SIZE];
+        self.controller.control_transfer(
+            &info,
+            SetupPacket::get_descriptor(descriptor::DEVICE, 0, raw.len() as u16),
+            &mut raw,
+        )?;
+        let device_descriptor = DeviceDescriptor::parse(&raw).ok_or(UsbError::InvalidDescriptor)?;
+
+        let mut header = [0; descriptor::CONFIGURATION_DESCRIPTOR_SIZE];
+        self.controller.control_transfer(
+            &info,
+            SetupPacket::get_descriptor(descriptor::CONFIGURATION, 0, header.len() as u16),
+            &mut header,
+        )?;
+        let total_length = u16::from_le_bytes([header[2], header[3]]);
+        let mut raw = vec![0; usize::from(total_length)];
+        let read = self.controller.control_transfer(
+            &info,
+            SetupPacket::get_descriptor(descriptor::CONFIGURATION, 0, total_length),
+            &mut raw,
+        )?;
+        let configuration = ConfigurationDescriptor::parse(&raw[..read]).ok_or(UsbError::InvalidDescriptor)?;
+
+        self.controller.control_transfer(&info, SetupPacket::set_configuration(configuration.value), &mut [])?;
+        for endpoint in configuration.interfaces.iter().flat_map(|interface| &interface.endpoints) {
+            self.controller.configure_endpoint(&info, endpoint)?;
+        }
+
+        println!(
+            "[usb] {}: {:04x}:{:04x} (class {:#x}) at address {}, {:?} speed",
+            self.controller.name(),
+            device_descriptor.vendor_id,
+            device_descriptor.product_id,
+            device_descriptor.class,
+            info.address,
+            info.speed,
+        );
+
+        let is_hub =
+            device_descriptor.class == hub::CLASS || configuration.interfaces.iter().any(|i| i.class == hub::CLASS);
+        self.devices.push(Device { info, descriptor: device_descriptor, configuration });
+
+        if is_hub {
+            hub::enumerate(self, self.devices.len() - 1)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Microseconds since boot
+pub(crate) fn uptime_us() -> u64 {
+    match librust::syscalls::system::system_info() {
+        SyscallResult::Ok(info) => info.uptime_us,
+        SyscallResult::Err(_) => 0,
+    }
+}
+
+/// Spin for at least `ms` milliseconds, for the delays the USB spec requires
+/// around resets and power changes
+pub fn delay_ms(ms: u64) {
+    let start = uptime_us();
+    while uptime_us() < start + ms * 1000 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Spin until `done` returns something, giving up after
+/// [`TRANSFER_TIMEOUT_MS`]
+pub(crate) fn poll_until<T>(mut done: impl FnMut() -> Option<T>) -> Result<T, UsbError> {
+    let start = uptime_us();
+    loop {
+        if let Some(result) = done() {
+            return Ok(result);
+        }
+
+        if uptime_us() > start + TRANSFER_TIMEOUT_MS * 1000 {
+            return Err(UsbError::Timeout);
+        }
+
+        core::hint::spin_loop();
+    }
+}
+
+json_rpc::rpc! {
+    pub service protocol {
+        /// The mass storage devices attached to every controller
+        fn block_devices() -> Vec<BlockDeviceInfo>;
+        /// Read a 512 byte block, returning `None` if the device failed to
+        /// read it
+        fn read_block(device: u32, block: u64) -> Option<Vec<u8>>;
+        /// Write a 512 byte block, returning whether it was written
+        fn write_block(device: u32, block: u64, data: Vec<u8>) -> bool;
+    }
+}
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    pub struct BlockDeviceInfo {
+        pub id: u32,
+        pub vendor: String,
+        pub product: String,
+        pub blocks: u64,
+    }
+}
+
+/// Connection to the `usbmgr` service
+pub struct Usbmgr {
+    client: protocol::Client,
+}
+
+impl Usbmgr {
+    /// Connect to `usbmgr` if the current task was given a capability to it
+    pub fn connect() -> Option<Self> {
+        let cptr = std::env::lookup_capability("usbmgr")?;
+        Some(Self { client: protocol::Client::new(IpcChannel::new(cptr)) })
+    }
+
+    pub fn block_devices(&mut self) -> Result<Vec<BlockDeviceInfo>, RpcError> {
+        self.client.block_devices()
+    }
+
+    pub fn read_block(&mut self, device: u32, block: u64) -> Result<Option<Vec<u8>>, RpcError> {
+        self.client.read_block(device, block)
+    }
+
+    pub fn write_block(&mut self, device: u32, block: u64, data: &[u8]) -> Result<bool, RpcError> {
+        self.client.write_block(device, block, data.to_vec())
+    }
+}
@@ -0,0 +1,263 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Mass storage devices using the bulk-only transport and the SCSI transparent
+//! command set, which covers practically every USB flash drive and card reader
+
+use crate::{
+    descriptor::{Endpoint, TransferKind},
+    request_type, Bus, Device, Direction, SetupPacket, UsbError,
+};
+
+pub const CLASS: u8 = 0x08;
+pub const SUBCLASS_SCSI: u8 = 0x06;
+pub const PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+pub const BLOCK_SIZE: usize = 512;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CBW_SIZE: usize = 31;
+const CSW_SIZE: usize = 13;
+
+const BULK_ONLY_RESET: u8 = 0xFF;
+
+mod scsi {
+    pub const TEST_UNIT_READY: u8 = 0x00;
+    pub const REQUEST_SENSE: u8 = 0x03;
+    pub const INQUIRY: u8 = 0x12;
+    pub const READ_CAPACITY_10: u8 = 0x25;
+    pub const READ_10: u8 = 0x28;
+    pub const WRITE_10: u8 = 0x2A;
+}
+
+/// Number of times to wait for the unit to become ready, media in card readers
+/// can take a while to spin up
+const READY_ATTEMPTS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MassStorageError {
+    Usb(UsbError),
+    /// The device returned a malformed command status wrapper
+    InvalidStatus,
+    /// The command failed, the sense data says why
+    CommandFailed {
+        sense_key: u8,
+    },
+    /// The device's logical blocks aren't 512 bytes
+    UnsupportedBlockSize(u32),
+    BlockOutOfRange(u64),
+}
+
+impl From<UsbError> for MassStorageError {
+    fn from(e: UsbError) -> Self {
+        Self::Usb(e)
+    }
+}
+
+/// A logical unit of a bulk-only mass storage device on the bus at index
+/// `device`
+pub struct MassStorage {
+    pub device: usize,
+    interface: u8,
+    bulk_in: Endpoint,
+    bulk_out: Endpoint,
+    next_tag: u32,
+    pub vendor: String,
+    pub product: String,
+    pub blocks: u64,
+}
+
+impl MassStorage {
+    /// Whether the device has a bulk-only SCSI interface this driver can use
+    pub fn supports(device: &Device) -> bool {
+        device.find_interface(CLASS, Some(SUBCLASS_SCSI), Some(PROTOCOL_BULK_ONLY)).is_some()
+    }
+
+    /// Identify the device at index `device` on the bus and read its capacity
+    pub fn new(bus: &mut Bus, device: usize) -> Result<Self, MassStorageError> {
+        let interface = bus.devices[device]
+            .find_interface(CLASS, Some(SUBCLASS_SCSI), Some(PROTOCOL_BULK_ONLY))
+            .ok_or(MassStorageError::Usb(UsbError::InvalidDescriptor))?;
+        let bulk_in = *interface.find_endpoint(TransferKind::Bulk, Direction::In).ok_or(UsbError::InvalidDescriptor)?;
+        let bulk_out =
+            *interface.find_endpoint(TransferKind::Bulk, Direction::Out).ok_or(UsbError::InvalidDescriptor)?;
+
+        let mut storage = Self {
+            device,
+            interface: interface.number,
+            bulk_in,
+            bulk_out,
+            next_tag: 1,
+            vendor: String::new(),
+            product: String::new(),
+            blocks: 0,
+        };
+
+        let mut inquiry = [0; 36];
+        storage.command(bus, &[scsi::INQUIRY, 0, 0, 0, inquiry.len() as u8, 0], Direction::In, &mut inquiry)?;
+        let trim = |bytes: &[u8]| String::from_utf8_lossy(bytes).trim().to_string();
+        storage.vendor = trim(&inquiry[8..16]);
+        storage.product = trim(&inquiry[16..32]);
+
+        let mut attempts = 0;
+        while let Err(e) = storage.command(bus, &[scsi::TEST_UNIT_READY, 0, 0, 0, 0, 0], Direction::Out, &mut []) {
+            attempts += 1;
+            if attempts == READY_ATTEMPTS {
+                return Err(e);
+            }
+
+            crate::delay_ms(100);
+        }
+
+        let mut capacity = [0; 8];
+        storage.command(bus, &[scsi::READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0], Direction::In, &mut capacity)?;
+        let last_block = u32::from_be_bytes([capacity[0], capacity[1], capacity[2], capacity[3]]);
+        let block_size = u32::from_be_bytes([capacity[4], capacity[5], capacity[6], capacity[7]]);
+        if block_size as usize != BLOCK_SIZE {
+            return Err(MassStorageError::UnsupportedBlockSize(block_size));
+        }
+
+        storage.blocks = u64::from(last_block) + 1;
+        Ok(storage)
+    }
+
+    pub fn read_block(
+        &mut self,
+        bus: &mut Bus,
+        block: u64,
+        buffer: &mut [u8; BLOCK_SIZE],
+    ) -> Result<(), MassStorageError> {
+        let command = self.rw_command(scsi::READ_10, block)?;
+        self.command(bus, &command, Direction::In, buffer)
+    }
+
+    pub fn write_block(
+        &mut self,
+        bus: &mut Bus,
+        block: u64,
+        buffer: &[u8; BLOCK_SIZE],
+    ) -> Result<(), MassStorageError> {
+        let command = self.rw_command(scsi::WRITE_10, block)?;
+        let mut data = *buffer;
+        self.command(bus, &command, Direction::Out, &mut data)
+    }
+
+    fn rw_command(&self, opcode: u8, block: u64) -> Result<[u8; 10], MassStorageError> {
+        if block >= self.blocks {
+            return Err(MassStorageError::BlockOutOfRange(block));
+        }
+
+        let [b0, b1, b2, b3] = (block as u32).to_be_bytes();
+        // A single block
+        Ok([opcode, 0, b0, b1, b2, b3, 0, 0, 1, 0])
+    }
+
+    /// Run a SCSI command through a command block wrapper, transferring `data`
+    /// in `direction`, and check its status wrapper
+    fn command(
+        &mut self,
+        bus: &mut Bus,
+        command: &[u8],
+        direction: Direction,
+        data: &mut [u8],
+    ) -> Result<(), MassStorageError> {
+        let tag = self.next_tag;
+        self.next_tag = self.next_tag.wrapping_add(1);
+
+        let mut cbw = [0; CBW_SIZE];
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        cbw[12] = match direction {
+            Direction::In => 0x80,
+            Direction::Out => 0,
+        };
+        cbw[14] = command.len() as u8;
+        cbw[15..15 + command.len()].copy_from_slice(command);
+
+        let info = bus.devices[self.device].info;
+        if let Err(e) = bus.controller.bulk_transfer(&info, &self.bulk_out, &mut cbw) {
+            self.reset_recovery(bus)?;
+            return Err(e.into());
+        }
+
+        if !data.is_empty() {
+            let endpoint = match direction {
+                Direction::In => self.bulk_in,
+                Direction::Out => self.bulk_out,
+            };
+
+            // A stall in the data stage still has a status wrapper following
+            // it once the endpoint is cleared
+            match bus.controller.bulk_transfer(&info, &endpoint, data) {
+                Ok(_) => {}
+                Err(UsbError::Stall) => self.clear_halt(bus, &endpoint)?,
+                Err(e) => {
+                    self.reset_recovery(bus)?;
+                    return Err(e.into());
+                }
+            }
+        }
+
+        let mut csw = [0; CSW_SIZE];
+        let read = match bus.controller.bulk_transfer(&info, &self.bulk_in, &mut csw) {
+            Err(UsbError::Stall) => {
+                self.clear_halt(bus, &self.bulk_in)?;
+                bus.controller.bulk_transfer(&info, &self.bulk_in, &mut csw)?
+            }
+            result => result?,
+        };
+
+        let signature = u32::from_le_bytes([csw[0], csw[1], csw[2], csw[3]]);
+        let csw_tag = u32::from_le_bytes([csw[4], csw[5], csw[6], csw[7]]);
+        if read != CSW_SIZE || signature != CSW_SIGNATURE || csw_tag != tag {
+            self.reset_recovery(bus)?;
+            return Err(MassStorageError::InvalidStatus);
+        }
+
+        match csw[12] {
+            0 => Ok(()),
+            1 if command[0] != scsi::REQUEST_SENSE => {
+                let mut sense = [0; 18];
+                self.command(bus, &[scsi::REQUEST_SENSE, 0, 0, 0, sense.len() as u8, 0], Direction::In, &mut sense)?;
+                Err(MassStorageError::CommandFailed { sense_key: sense[2] & 0xF })
+            }
+            1 => Err(MassStorageError::CommandFailed { sense_key: 0 }),
+            _ => {
+                self.reset_recovery(bus)?;
+                Err(MassStorageError::InvalidStatus)
+            }
+        }
+    }
+
+    fn clear_halt(&self, bus: &mut Bus, endpoint: &Endpoint) -> Result<(), MassStorageError> {
+        let info = bus.devices[self.device].info;
+        bus.controller.control_transfer(&info, SetupPacket::clear_halt(endpoint), &mut [])?;
+        Ok(())
+    }
+
+    /// Reset the device's bulk-only transport and clear both endpoints, which
+    /// is how the device is brought back after a phase error
+    fn reset_recovery(&self, bus: &mut Bus) -> Result<(), MassStorageError> {
+        let info = bus.devices[self.device].info;
+        bus.controller.control_transfer(
+            &info,
+            SetupPacket {
+                request_type: request_type::CLASS | request_type::TO_INTERFACE,
+                request: BULK_ONLY_RESET,
+                value: 0,
+                index: u16::from(self.interface),
+                length: 0,
+            },
+            &mut [],
+        )?;
+
+        self.clear_halt(bus, &self.bulk_in)?;
+        self.clear_halt(bus, &self.bulk_out)
+    }
+}
@@ -0,0 +1,627 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! OHCI (USB 1.1) host controllers, which handle low and full speed devices.
+//! On SoCs like the D1 they sit alongside an EHCI controller and get the ports
+//! it gives up on.
+//!
+//! There's one endpoint descriptor each for control and bulk transfers, which
+//! are rewritten for each transfer while their list is stopped, and one per
+//! interrupt endpoint in the periodic schedule. Every endpoint descriptor's
+//! queue ends in an empty dummy transfer descriptor, so the queue is empty once
+//! its head reaches its tail.
+
+use crate::{
+    descriptor::{Endpoint, TransferKind},
+    poll_until, DeviceInfo, Direction, HostController, SetupPacket, Speed, UsbError,
+};
+use librust::mem::DmaRegion;
+use std::collections::BTreeMap;
+use volatile::{Read, Volatile};
+
+const MAX_INTERRUPT_ENDPOINTS: usize = 8;
+/// Transfer descriptors can only cross a single page boundary
+const BUFFER_SIZE: usize = 2 * 4096;
+const INTERRUPT_BUFFER_SIZE: usize = 64;
+const INTERRUPT_TABLE_SIZE: usize = 32;
+
+const PORT_RESET_TIMEOUT_MS: u64 = 100;
+
+volatile::register_block! {
+    struct Registers [0x58] {
+        0x00 => revision: Volatile<u32, Read>,
+        0x04 => control: Volatile<u32>,
+        0x08 => command_status: Volatile<u32>,
+        0x0C => interrupt_status: Volatile<u32>,
+        0x14 => interrupt_disable: Volatile<u32>,
+        0x18 => hcca: Volatile<u32>,
+        0x20 => control_head: Volatile<u32>,
+        0x24 => control_current: Volatile<u32>,
+        0x28 => bulk_head: Volatile<u32>,
+        0x2C => bulk_current: Volatile<u32>,
+        0x34 => frame_interval: Volatile<u32>,
+        0x3C => frame_number: Volatile<u32, Read>,
+        0x40 => periodic_start: Volatile<u32>,
+        0x48 => root_hub_a: Volatile<u32>,
+        0x50 => root_hub_status: Volatile<u32>,
+    }
+}
+
+/// Offset of the first port status register, there's one for every port the
+/// root hub has
+const PORT_STATUS_BASE: usize = 0x54;
+
+mod control {
+    /// Four control transfers are serviced for each bulk transfer
+    pub const CONTROL_BULK_RATIO: u32 = 0b11;
+    pub const PERIODIC_ENABLE: u32 = 1 << 2;
+    pub const CONTROL_ENABLE: u32 = 1 << 4;
+    pub const BULK_ENABLE: u32 = 1 << 5;
+    pub const STATE_OPERATIONAL: u32 = 0b10 << 6;
+    /// Set when firmware (SMM or the like) still owns the controller
+    pub const INTERRUPT_ROUTING: u32 = 1 << 8;
+}
+
+mod command_status {
+    pub const RESET: u32 = 1 << 0;
+    pub const CONTROL_FILLED: u32 = 1 << 1;
+    pub const BULK_FILLED: u32 = 1 << 2;
+    pub const OWNERSHIP_CHANGE: u32 = 1 << 3;
+}
+
+mod port {
+    pub const CONNECTED: u32 = 1 << 0;
+    pub const ENABLED: u32 = 1 << 1;
+    /// Writing starts a reset
+    pub const RESET: u32 = 1 << 4;
+    /// Writing turns on port power
+    pub const POWER: u32 = 1 << 8;
+    pub const LOW_SPEED: u32 = 1 << 9;
+    pub const CONNECT_CHANGE: u32 = 1 << 16;
+    pub const RESET_CHANGE: u32 = 1 << 20;
+}
+
+/// Set global power in the root hub status register
+const ROOT_HUB_SET_POWER: u32 = 1 << 16;
+/// Ports are always powered, or power switching is per port
+const ROOT_HUB_NO_POWER_SWITCHING: u32 = 1 << 9;
+
+mod ed {
+    pub const SKIP: u32 = 1 << 14;
+    pub const LOW_SPEED: u32 = 1 << 13;
+    pub const HALTED: u32 = 1 << 0;
+    pub const TOGGLE_CARRY: u32 = 1 << 1;
+}
+
+mod td {
+    pub const ROUNDING: u32 = 1 << 18;
+    pub const PID_SETUP: u32 = 0b00 << 19;
+    pub const PID_OUT: u32 = 0b01 << 19;
+    pub const PID_IN: u32 = 0b10 << 19;
+    /// Don't interrupt when the descriptor is retired
+    pub const NO_INTERRUPT: u32 = 0b111 << 21;
+    pub const TOGGLE_DATA0: u32 = 0b10 << 24;
+    pub const TOGGLE_DATA1: u32 = 0b11 << 24;
+    pub const NOT_ACCESSED: u32 = 0xF << 28;
+
+    pub const CC_NO_ERROR: u32 = 0;
+    pub const CC_STALL: u32 = 4;
+    pub const CC_DEVICE_NOT_RESPONDING: u32 = 5;
+    pub const CC_DATA_UNDERRUN: u32 = 9;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C, align(16))]
+struct EndpointDescriptor {
+    control: u32,
+    tail: u32,
+    head: u32,
+    next: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C, align(16))]
+struct TransferDescriptor {
+    control: u32,
+    current_buffer: u32,
+    next: u32,
+    buffer_end: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(256))]
+struct Hcca {
+    interrupt_table: [u32; INTERRUPT_TABLE_SIZE],
+    frame_number: u16,
+    _pad: u16,
+    done_head: u32,
+    _reserved: [u8; 116],
+}
+
+const _: () = assert!(core::mem::size_of::<Hcca>() == 256);
+
+/// Endpoint descriptors for control and bulk transfers, the rest belong to
+/// interrupt endpoints
+const CONTROL_ED: usize = 0;
+const BULK_ED: usize = 1;
+const FIRST_INTERRUPT_ED: usize = 2;
+
+/// Transfer descriptors for control and bulk transfers, the last is the dummy
+/// both queues end in. Interrupt endpoints get two each after these, which
+/// take turns being the dummy.
+const SETUP_TD: usize = 0;
+const DATA_TD: usize = 1;
+const STATUS_TD: usize = 2;
+const DUMMY_TD: usize = 3;
+const FIRST_INTERRUPT_TD: usize = 4;
+
+struct InterruptEndpoint {
+    index: usize,
+    length: usize,
+    /// Which of the endpoint's two transfer descriptors is queued
+    queued: usize,
+}
+
+pub struct Ohci {
+    registers: &'static Registers,
+    base: *mut u8,
+    ports: u8,
+    hcca: DmaRegion<Hcca>,
+    endpoints: DmaRegion<[EndpointDescriptor]>,
+    transfers: DmaRegion<[TransferDescriptor]>,
+    buffer: DmaRegion<[u8]>,
+    interrupt_buffers: DmaRegion<[[u8; INTERRUPT_BUFFER_SIZE]]>,
+    interrupt_endpoints: BTreeMap<(u8, u8), InterruptEndpoint>,
+    /// Next data toggle of each bulk endpoint, by address and endpoint address
+    toggles: BTreeMap<(u8, u8), bool>,
+    next_address: u8,
+}
+
+impl Ohci {
+    /// Take the controller from firmware if needed, reset it, and start it
+    /// with empty control, bulk, and periodic lists
+    ///
+    /// # Safety
+    ///
+    /// `base` must be a mapping of the controller's registers
+    pub unsafe fn new(base: *mut u8) -> Result<Self, UsbError> {
+        let registers = &*base.cast::<Registers>();
+
+        if registers.control().read() & control::INTERRUPT_ROUTING != 0 {
+            registers.command_status().write(command_status::OWNERSHIP_CHANGE);
+            poll_until(|| (registers.control().read() & control::INTERRUPT_ROUTING == 0).then(|| ()))?;
+        }
+
+        // The frame interval is lost on reset
+        let frame_interval = registers.frame_interval().read() & 0x3FFF;
+        registers.command_status().write(command_status::RESET);
+        poll_until(|| (registers.command_status().read() & command_status::RESET == 0).then(|| ()))?;
+
+        let mut ohci = Self {
+            registers,
+            base,
+            ports: (registers.root_hub_a().read() & 0xFF) as u8,
+            hcca: DmaRegion::zeroed().unwrap().assume_init(),
+            endpoints: DmaRegion::zeroed_many(FIRST_INTERRUPT_ED + MAX_INTERRUPT_ENDPOINTS).unwrap().assume_init(),
+            transfers: DmaRegion::zeroed_many(FIRST_INTERRUPT_TD + 2 * MAX_INTERRUPT_ENDPOINTS).unwrap().assume_init(),
+            buffer: DmaRegion::zeroed_many(BUFFER_SIZE).unwrap().assume_init(),
+            interrupt_buffers: DmaRegion::zeroed_many(MAX_INTERRUPT_ENDPOINTS).unwrap().assume_init(),
+            interrupt_endpoints: BTreeMap::new(),
+            toggles: BTreeMap::new(),
+            next_address: 1,
+        };
+
+        let dummy = ohci.transfer_address(DUMMY_TD);
+        for index in [CONTROL_ED, BULK_ED] {
+            ohci.write_endpoint(index, EndpointDescriptor { control: ed::SKIP, tail: dummy, head: dummy, next: 0 });
+        }
+
+        registers.hcca().write(ohci.hcca.physical_address().as_usize() as u32);
+        registers.control_head().write(ohci.endpoint_address(CONTROL_ED));
+        registers.bulk_head().write(ohci.endpoint_address(BULK_ED));
+        registers.interrupt_disable().write(u32::MAX);
+
+        // Largest data packet is the frame interval minus bit stuffing and
+        // overhead, and periodic transfers start 90% of the way through the
+        // frame
+        let largest_packet = (6 * (frame_interval - 210)) / 7;
+        registers.frame_interval().write(largest_packet << 16 | frame_interval);
+        registers.periodic_start().write(frame_interval * 9 / 10);
+        registers.control().write(
+            control::CONTROL_BULK_RATIO
+                | control::PERIODIC_ENABLE
+                | control::CONTROL_ENABLE
+                | control::BULK_ENABLE
+                | control::STATE_OPERATIONAL,
+        );
+
+        let root_hub_a = registers.root_hub_a().read();
+        if root_hub_a & ROOT_HUB_NO_POWER_SWITCHING == 0 {
+            registers.root_hub_status().write(ROOT_HUB_SET_POWER);
+            for port in 1..=ohci.ports {
+                ohci.port_register(port).write(port::POWER);
+            }
+        }
+
+        // Power on to power good time, in units of 2ms
+        crate::delay_ms(u64::from(root_hub_a >> 24) * 2);
+
+        Ok(ohci)
+    }
+
+    fn port_register(&self, port: u8) -> &Volatile<u32> {
+        unsafe { &*self.base.add(PORT_STATUS_BASE + 4 * (usize::from(port) - 1)).cast::<Volatile<u32>>() }
+    }
+
+    fn endpoint_address(&self, index: usize) -> u32 {
+        (self.endpoints.physical_address().as_usize() + index * core::mem::size_of::<EndpointDescriptor>()) as u32
+    }
+
+    fn transfer_address(&self, index: usize) -> u32 {
+        (self.transfers.physical_address().as_usize() + index * core::mem::size_of::<TransferDescriptor>()) as u32
+    }
+
+    fn write_endpoint(&mut self, index: usize, endpoint: EndpointDescriptor) {
+        unsafe { core::ptr::write_volatile(&mut self.endpoints[index], endpoint) };
+    }
+
+    fn read_endpoint(&self, index: usize) -> EndpointDescriptor {
+        unsafe { core::ptr::read_volatile(&self.endpoints[index]) }
+    }
+
+    fn write_transfer(&mut self, index: usize, transfer: TransferDescriptor) {
+        unsafe { core::ptr::write_volatile(&mut self.transfers[index], transfer) };
+    }
+
+    fn read_transfer(&self, index: usize) -> TransferDescriptor {
+        unsafe { core::ptr::read_volatile(&self.transfers[index]) }
+    }
+
+    /// A transfer descriptor moving `length` bytes starting at physical address
+    /// `buffer`, where short packets aren't an error
+    fn transfer(next: u32, flags: u32, buffer: u32, length: usize) -> TransferDescriptor {
+        let (current_buffer, buffer_end) = match length {
+            0 => (0, 0),
+            _ => (buffer, buffer + length as u32 - 1),
+        };
+
+        TransferDescriptor {
+            control: td::NOT_ACCESSED | td::NO_INTERRUPT | td::ROUNDING | flags,
+            current_buffer,
+            next,
+            buffer_end,
+        }
+    }
+
+    /// Endpoint descriptor control word for an endpoint, with the direction
+    /// taken from the transfer descriptors
+    fn endpoint_control(device: &DeviceInfo, endpoint: u8, max_packet_size: u16) -> u32 {
+        let low_speed = if device.speed == Speed::Low { ed::LOW_SPEED } else { 0 };
+        u32::from(device.address) | u32::from(endpoint) << 7 | low_speed | u32::from(max_packet_size) << 16
+    }
+
+    /// Wait for the start of the next frame, after which the controller is
+    /// done with any descriptors it was looking at when a list was stopped
+    fn wait_frame(&self) -> Result<(), UsbError> {
+        let frame = self.registers.frame_number().read() & 0xFFFF;
+        poll_until(|| (self.registers.frame_number().read() & 0xFFFF != frame).then(|| ()))
+    }
+
+    /// Queue the transfer descriptors from `first` up to the dummy on the
+    /// control or bulk endpoint descriptor and wait for them to be retired
+    fn run(&mut self, list: usize, control: u32, toggle: bool, first: usize) -> Result<(), UsbError> {
+        let (enable, filled) = match list {
+            CONTROL_ED => (control::CONTROL_ENABLE, command_status::CONTROL_FILLED),
+            _ => (control::BULK_ENABLE, command_status::BULK_FILLED),
+        };
+
+        self.registers.control().modify(|c| c & !enable);
+        self.wait_frame()?;
+
+        let dummy = self.transfer_address(DUMMY_TD);
+        let carry = if toggle { ed::TOGGLE_CARRY } else { 0 };
+        let next = self.read_endpoint(list).next;
+        self.write_endpoint(
+            list,
+            EndpointDescriptor { control, tail: dummy, head: self.transfer_address(first) | carry, next },
+        );
+        librust::mem::fence(librust::mem::FenceMode::Write);
+
+        match list {
+            CONTROL_ED => self.registers.control_current().write(0),
+            _ => self.registers.bulk_current().write(0),
+        }
+        self.registers.control().modify(|c| c | enable);
+        self.registers.command_status().write(filled);
+
+        let result = poll_until(|| {
+            let endpoint = self.read_endpoint(list);
+            (endpoint.head & !0xF == dummy || endpoint.head & ed::HALTED != 0).then(|| ())
+        });
+
+        // Leave the endpoint descriptor skipped and empty again
+        let mut endpoint = self.read_endpoint(list);
+        endpoint.control |= ed::SKIP;
+        endpoint.head = dummy | (endpoint.head & ed::TOGGLE_CARRY);
+        self.write_endpoint(list, endpoint);
+        result
+    }
+
+    fn buffer_address(&self, offset: usize) -> u32 {
+        (self.buffer.physical_address().as_usize() + offset) as u32
+    }
+
+    /// Number of bytes a retired descriptor transferred, given how many it was
+    /// asked to
+    fn transferred(&self, index: usize, length: usize, buffer: u32) -> usize {
+        match self.read_transfer(index).current_buffer {
+            0 => length,
+            current => (current - buffer) as usize,
+        }
+    }
+}
+
+// The registers and DMA memory are only touched through `&mut self`
+unsafe impl Send for Ohci {}
+
+impl HostController for Ohci {
+    fn name(&self) -> &'static str {
+        "ohci"
+    }
+
+    fn port_count(&self) -> u8 {
+        self.ports
+    }
+
+    fn port_connected(&mut self, port: u8) -> bool {
+        self.port_register(port).read() & port::CONNECTED != 0
+    }
+
+    fn reset_port(&mut self, port: u8) -> Result<Speed, UsbError> {
+        let register = self.port_register(port);
+        if register.read() & port::CONNECTED == 0 {
+            return Err(UsbError::NotConnected);
+        }
+
+        register.write(port::CONNECT_CHANGE);
+        register.write(port::RESET);
+
+        let start = crate::uptime_us();
+        while register.read() & port::RESET_CHANGE == 0 {
+            if crate::uptime_us() > start + PORT_RESET_TIMEOUT_MS * 1000 {
+                return Err(UsbError::Timeout);
+            }
+        }
+
+        register.write(port::RESET_CHANGE);
+        crate::delay_ms(10);
+
+        match register.read() {
+            status if status & port::ENABLED == 0 => Err(UsbError::NotConnected),
+            status if status & port::LOW_SPEED != 0 => Ok(Speed::Low),
+            _ => Ok(Speed::Full),
+        }
+    }
+
+    fn address_device(&mut self, mut device: DeviceInfo) -> Result<DeviceInfo, UsbError> {
+        let address = self.next_address;
+        if address > 127 {
+            return Err(UsbError::NoResources);
+        }
+
+        self.control_transfer(&device, SetupPacket::set_address(address), &mut [])?;
+        self.next_address += 1;
+        crate::delay_ms(2);
+
+        device.address = address;
+        Ok(device)
+    }
+
+    fn set_max_packet_size(&mut self, device: &mut DeviceInfo, max_packet_size: u16) -> Result<(), UsbError> {
+        device.max_packet_size = max_packet_size;
+        Ok(())
+    }
+
+    fn configure_endpoint(&mut self, device: &DeviceInfo, endpoint: &Endpoint) -> Result<(), UsbError> {
+        match endpoint.kind {
+            TransferKind::Bulk => drop(self.toggles.insert((device.address, endpoint.address()), false)),
+            TransferKind::Interrupt if endpoint.direction == Direction::In => {
+                let index = self.interrupt_endpoints.len();
+                if index == MAX_INTERRUPT_ENDPOINTS {
+                    return Err(UsbError::NoResources);
+                }
+
+                let length = usize::from(endpoint.max_packet_size).min(INTERRUPT_BUFFER_SIZE);
+                let queued = FIRST_INTERRUPT_TD + 2 * index;
+                let dummy = self.transfer_address(queued + 1);
+                let buffer = self.interrupt_buffers.physical_address().as_usize() + index * INTERRUPT_BUFFER_SIZE;
+                self.write_transfer(queued, Self::transfer(dummy, td::PID_IN, buffer as u32, length));
+
+                // Endpoint descriptors are pushed onto the front of the
+                // periodic list, polled every frame
+                let previous = self.hcca.interrupt_table[0];
+                self.write_endpoint(
+                    FIRST_INTERRUPT_ED + index,
+                    EndpointDescriptor {
+                        control: Self::endpoint_control(device, endpoint.number, endpoint.max_packet_size),
+                        tail: dummy,
+                        head: self.transfer_address(queued),
+                        next: previous,
+                    },
+                );
+                librust::mem::fence(librust::mem::FenceMode::Write);
+
+                let entry = self.endpoint_address(FIRST_INTERRUPT_ED + index);
+                for slot in self.hcca.interrupt_table.iter_mut() {
+                    unsafe { core::ptr::write_volatile(slot, entry) };
+                }
+
+                self.interrupt_endpoints
+                    .insert((device.address, endpoint.address()), InterruptEndpoint { index, length, queued });
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn control_transfer(
+        &mut self,
+        device: &DeviceInfo,
+        setup: SetupPacket,
+        data: &mut [u8],
+    ) -> Result<usize, UsbError> {
+        let data_offset = 64;
+        let length = usize::from(setup.length).min(data.len());
+        if length > BUFFER_SIZE - data_offset {
+            return Err(UsbError::Controller("transfer too large"));
+        }
+
+        self.buffer[..8].copy_from_slice(&setup.to_bytes());
+        let direction = setup.direction();
+        if direction == Direction::Out {
+            self.buffer[data_offset..data_offset + length].copy_from_slice(&data[..length]);
+        }
+
+        let (data_pid, status_pid) = match (direction, length) {
+            (_, 0) => (td::PID_IN, td::PID_IN),
+            (Direction::In, _) => (td::PID_IN, td::PID_OUT),
+            (Direction::Out, _) => (td::PID_OUT, td::PID_IN),
+        };
+
+        let dummy = self.transfer_address(DUMMY_TD);
+        self.write_transfer(DUMMY_TD, TransferDescriptor::default());
+        self.write_transfer(STATUS_TD, Self::transfer(dummy, status_pid | td::TOGGLE_DATA1, 0, 0));
+        let after_setup = match length {
+            0 => self.transfer_address(STATUS_TD),
+            _ => {
+                let next = self.transfer_address(STATUS_TD);
+                let buffer = self.buffer_address(data_offset);
+                self.write_transfer(DATA_TD, Self::transfer(next, data_pid | td::TOGGLE_DATA1, buffer, length));
+                self.transfer_address(DATA_TD)
+            }
+        };
+        let setup_buffer = self.buffer_address(0);
+        self.write_transfer(SETUP_TD, Self::transfer(after_setup, td::PID_SETUP | td::TOGGLE_DATA0, setup_buffer, 8));
+
+        let control = Self::endpoint_control(device, 0, device.max_packet_size);
+        self.run(CONTROL_ED, control, false, SETUP_TD)?;
+
+        let stages: &[usize] = match length {
+            0 => &[SETUP_TD, STATUS_TD],
+            _ => &[SETUP_TD, DATA_TD, STATUS_TD],
+        };
+        stages.iter().try_for_each(|&stage| check_condition(self.read_transfer(stage).control))?;
+
+        let transferred = match length {
+            0 => 0,
+            _ => self.transferred(DATA_TD, length, self.buffer_address(data_offset)),
+        };
+
+        if direction == Direction::In {
+            data[..transferred].copy_from_slice(&self.buffer[data_offset..data_offset + transferred]);
+        }
+
+        Ok(transferred)
+    }
+
+    fn bulk_transfer(&mut self, device: &DeviceInfo, endpoint: &Endpoint, data: &mut [u8]) -> Result<usize, UsbError> {
+        if data.len() > BUFFER_SIZE {
+            return Err(UsbError::Controller("transfer too large"));
+        }
+
+        let key = (device.address, endpoint.address());
+        let toggle = self.toggles.get(&key).copied().unwrap_or(false);
+        if endpoint.direction == Direction::Out {
+            self.buffer[..data.len()].copy_from_slice(data);
+        }
+
+        let pid = match endpoint.direction {
+            Direction::In => td::PID_IN,
+            Direction::Out => td::PID_OUT,
+        };
+
+        let dummy = self.transfer_address(DUMMY_TD);
+        let buffer = self.buffer_address(0);
+        self.write_transfer(DUMMY_TD, TransferDescriptor::default());
+        self.write_transfer(DATA_TD, Self::transfer(dummy, pid, buffer, data.len()));
+
+        let control = Self::endpoint_control(device, endpoint.number, endpoint.max_packet_size);
+        let result = self
+            .run(BULK_ED, control, toggle, DATA_TD)
+            .and_then(|_| check_condition(self.read_transfer(DATA_TD).control));
+
+        // A stall resets the toggle, otherwise the controller carried it over
+        let toggle = match result {
+            Err(UsbError::Stall) => false,
+            _ => self.read_endpoint(BULK_ED).head & ed::TOGGLE_CARRY != 0,
+        };
+        self.toggles.insert(key, toggle);
+        result?;
+
+        let transferred = self.transferred(DATA_TD, data.len(), buffer);
+        if endpoint.direction == Direction::In {
+            data[..transferred].copy_from_slice(&self.buffer[..transferred]);
+        }
+
+        Ok(transferred)
+    }
+
+    fn poll_interrupt(
+        &mut self,
+        device: &DeviceInfo,
+        endpoint: &Endpoint,
+        data: &mut [u8],
+    ) -> Result<Option<usize>, UsbError> {
+        let (index, length, queued) = match self.interrupt_endpoints.get(&(device.address, endpoint.address())) {
+            Some(endpoint) => (endpoint.index, endpoint.length, endpoint.queued),
+            None => return Err(UsbError::Controller("interrupt endpoint not configured")),
+        };
+
+        let ed_index = FIRST_INTERRUPT_ED + index;
+        let mut queue = self.read_endpoint(ed_index);
+        let retired = queue.head & !0xF == queue.tail;
+        if !retired && queue.head & ed::HALTED == 0 {
+            return Ok(None);
+        }
+
+        let buffer = (self.interrupt_buffers.physical_address().as_usize() + index * INTERRUPT_BUFFER_SIZE) as u32;
+        let result = check_condition(self.read_transfer(queued).control);
+        let transferred = self.transferred(queued, length, buffer).min(data.len());
+        data[..transferred].copy_from_slice(&self.interrupt_buffers[index][..transferred]);
+
+        // The old dummy gets the next transfer, and the retired descriptor
+        // becomes the new dummy
+        let first = FIRST_INTERRUPT_TD + 2 * index;
+        let (next_queued, next_dummy) = if queued == first { (first + 1, first) } else { (first, first + 1) };
+        let dummy = self.transfer_address(next_dummy);
+        self.write_transfer(next_dummy, TransferDescriptor::default());
+        self.write_transfer(next_queued, Self::transfer(dummy, td::PID_IN, buffer, length));
+        librust::mem::fence(librust::mem::FenceMode::Write);
+
+        queue.tail = dummy;
+        if queue.head & ed::HALTED != 0 {
+            queue.head = self.transfer_address(next_queued);
+        }
+        self.write_endpoint(ed_index, queue);
+
+        if let Some(endpoint) = self.interrupt_endpoints.get_mut(&(device.address, endpoint.address())) {
+            endpoint.queued = next_queued;
+        }
+
+        result.map(|_| Some(transferred))
+    }
+}
+
+fn check_condition(control: u32) -> Result<(), UsbError> {
+    match control >> 28 {
+        td::CC_NO_ERROR | td::CC_DATA_UNDERRUN => Ok(()),
+        td::CC_STALL => Err(UsbError::Stall),
+        td::CC_DEVICE_NOT_RESPONDING => Err(UsbError::Timeout),
+        _ => Err(UsbError::TransactionError),
+    }
+}
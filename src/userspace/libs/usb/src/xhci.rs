@@ -0,0 +1,849 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! xHCI host controllers, which handle every speed themselves and do most of
+//! the work of managing devices, e.g. assigning addresses and tracking data
+//! toggles. Commands and transfers are placed on rings of transfer request
+//! blocks (TRBs), and their completions are read from a single event ring by
+//! polling it. Device addresses handed out by this driver are the device's
+//! slot ID.
+
+use crate::{
+    descriptor::{Endpoint, TransferKind},
+    DeviceInfo, Direction, HostController, SetupPacket, Speed, UsbError,
+};
+use librust::mem::DmaRegion;
+use std::collections::BTreeMap;
+use volatile::{Read, Volatile};
+
+const COMMAND_RING_SIZE: usize = 64;
+const TRANSFER_RING_SIZE: usize = 64;
+const EVENT_RING_SIZE: usize = 256;
+/// A page, so a buffer never crosses a 64 KiB boundary and fits in a single
+/// TRB
+const BUFFER_SIZE: usize = 4096;
+const INTERRUPT_BUFFER_SIZE: usize = 64;
+const MAX_INTERRUPT_ENDPOINTS: usize = 8;
+const PAGE_SIZE: usize = 4096;
+
+volatile::register_block! {
+    struct CapabilityRegisters [0x20] {
+        0x00 => length: Volatile<u8, Read>,
+        0x04 => structural_parameters1: Volatile<u32, Read>,
+        0x08 => structural_parameters2: Volatile<u32, Read>,
+        0x10 => capability_parameters1: Volatile<u32, Read>,
+        0x14 => doorbell_offset: Volatile<u32, Read>,
+        0x18 => runtime_offset: Volatile<u32, Read>,
+    }
+}
+
+volatile::register_block! {
+    struct OperationalRegisters [0x40] {
+        0x00 => command: Volatile<u32>,
+        0x04 => status: Volatile<u32>,
+        0x18 => command_ring: Volatile<u64>,
+        0x30 => device_contexts: Volatile<u64>,
+        0x38 => configure: Volatile<u32>,
+    }
+}
+
+volatile::register_block! {
+    /// Registers of the first interrupter, which is the only one used
+    struct InterrupterRegisters [0x20] {
+        0x00 => management: Volatile<u32>,
+        0x08 => event_ring_segment_table_size: Volatile<u32>,
+        0x10 => event_ring_segment_table: Volatile<u64>,
+        0x18 => event_ring_dequeue: Volatile<u64>,
+    }
+}
+
+/// Offset of the port registers from the operational registers, each port's
+/// status and control register is followed by three others
+const PORT_REGISTERS: usize = 0x400;
+/// Offset of the first interrupter from the runtime registers
+const INTERRUPTER_0: usize = 0x20;
+
+mod command {
+    pub const RUN: u32 = 1 << 0;
+    pub const RESET: u32 = 1 << 1;
+}
+
+mod status {
+    pub const HALTED: u32 = 1 << 0;
+    pub const NOT_READY: u32 = 1 << 11;
+}
+
+mod port {
+    pub const CONNECTED: u32 = 1 << 0;
+    pub const ENABLED: u32 = 1 << 1;
+    pub const RESET: u32 = 1 << 4;
+    pub const POWER: u32 = 1 << 9;
+    pub const SPEED_SHIFT: u32 = 10;
+    pub const SPEED_MASK: u32 = 0xF;
+    pub const RESET_CHANGE: u32 = 1 << 21;
+    /// Every bit which is cleared by writing a one to it
+    pub const CHANGE_BITS: u32 = 0x7F << 17;
+}
+
+mod trb {
+    pub const CYCLE: u32 = 1 << 0;
+    /// For link TRBs, flip the cycle bit when following the link
+    pub const TOGGLE_CYCLE: u32 = 1 << 1;
+    pub const INTERRUPT_ON_SHORT_PACKET: u32 = 1 << 2;
+    pub const INTERRUPT_ON_COMPLETION: u32 = 1 << 5;
+    pub const IMMEDIATE_DATA: u32 = 1 << 6;
+    /// For data and status stage TRBs
+    pub const DIRECTION_IN: u32 = 1 << 16;
+
+    pub const NORMAL: u32 = 1;
+    pub const SETUP_STAGE: u32 = 2;
+    pub const DATA_STAGE: u32 = 3;
+    pub const STATUS_STAGE: u32 = 4;
+    pub const LINK: u32 = 6;
+    pub const ENABLE_SLOT: u32 = 9;
+    pub const ADDRESS_DEVICE: u32 = 11;
+    pub const CONFIGURE_ENDPOINT: u32 = 12;
+    pub const EVALUATE_CONTEXT: u32 = 13;
+    pub const RESET_ENDPOINT: u32 = 14;
+    pub const SET_DEQUEUE_POINTER: u32 = 16;
+    pub const TRANSFER_EVENT: u32 = 32;
+    pub const COMMAND_COMPLETION: u32 = 33;
+
+    /// Transfer type of a setup stage TRB
+    pub const TRANSFER_NONE: u32 = 0 << 16;
+    pub const TRANSFER_OUT: u32 = 2 << 16;
+    pub const TRANSFER_IN: u32 = 3 << 16;
+}
+
+mod completion {
+    pub const SUCCESS: u32 = 1;
+    pub const BABBLE: u32 = 3;
+    pub const TRANSACTION_ERROR: u32 = 4;
+    pub const STALL: u32 = 6;
+    pub const SHORT_PACKET: u32 = 13;
+}
+
+mod endpoint_type {
+    pub const BULK_OUT: u32 = 2;
+    pub const CONTROL: u32 = 4;
+    pub const BULK_IN: u32 = 6;
+    pub const INTERRUPT_IN: u32 = 7;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C, align(16))]
+struct Trb {
+    parameter: u64,
+    status: u32,
+    control: u32,
+}
+
+impl Trb {
+    fn kind(&self) -> u32 {
+        (self.control >> 10) & 0x3F
+    }
+
+    fn slot(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+
+    /// Device context index of the endpoint a transfer event is for
+    fn endpoint(&self) -> u8 {
+        ((self.control >> 16) & 0x1F) as u8
+    }
+
+    fn completion_code(&self) -> u32 {
+        self.status >> 24
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct EventRingSegment {
+    base: u64,
+    size: u32,
+    _reserved: u32,
+}
+
+/// A command or transfer ring, which ends with a link TRB back to its start
+struct Ring {
+    trbs: DmaRegion<[Trb]>,
+    enqueue: usize,
+    cycle: bool,
+}
+
+impl Ring {
+    fn new(size: usize) -> Self {
+        let mut ring =
+            Self { trbs: unsafe { DmaRegion::zeroed_many(size).unwrap().assume_init() }, enqueue: 0, cycle: true };
+        let start = ring.address(0);
+        ring.trbs[size - 1] = Trb { parameter: start, status: 0, control: trb::LINK << 10 | trb::TOGGLE_CYCLE };
+        ring
+    }
+
+    fn address(&self, index: usize) -> u64 {
+        (self.trbs.physical_address().as_usize() + index * core::mem::size_of::<Trb>()) as u64
+    }
+
+    /// Address of the next TRB along with its cycle state, as the controller
+    /// expects a dequeue pointer
+    fn dequeue_pointer(&self) -> u64 {
+        self.address(self.enqueue) | u64::from(self.cycle)
+    }
+
+    /// Place a TRB on the ring, returning its address. The cycle bit is filled
+    /// in here, and is written last so the controller never sees a partial
+    /// TRB.
+    fn push(&mut self, entry: Trb) -> u64 {
+        let address = self.address(self.enqueue);
+        let cycle = self.cycle as u32;
+
+        let slot = &mut self.trbs[self.enqueue];
+        unsafe {
+            core::ptr::write_volatile(&mut slot.parameter, entry.parameter);
+            core::ptr::write_volatile(&mut slot.status, entry.status);
+            librust::mem::fence(librust::mem::FenceMode::Write);
+            core::ptr::write_volatile(&mut slot.control, (entry.control & !trb::CYCLE) | cycle);
+        }
+
+        self.enqueue += 1;
+        if self.enqueue == self.trbs.len() - 1 {
+            let link = &mut self.trbs[self.enqueue];
+            let control = (link.control & !trb::CYCLE) | cycle;
+            unsafe { core::ptr::write_volatile(&mut link.control, control) };
+            self.enqueue = 0;
+            self.cycle = !self.cycle;
+        }
+
+        address
+    }
+}
+
+struct InterruptEndpoint {
+    index: usize,
+    length: usize,
+    /// Address of the TRB currently queued on the endpoint
+    queued: u64,
+}
+
+struct Slot {
+    input: DmaRegion<[u32]>,
+    /// Owned by the controller once the slot is addressed
+    #[allow(dead_code)]
+    output: DmaRegion<[u32]>,
+    rings: BTreeMap<u8, Ring>,
+}
+
+pub struct Xhci {
+    registers: &'static OperationalRegisters,
+    interrupter: &'static InterrupterRegisters,
+    doorbells: *mut u32,
+    ports: u8,
+    /// Contexts are either 32 or 64 bytes, in dwords
+    context_dwords: usize,
+    device_contexts: DmaRegion<[u64]>,
+    #[allow(dead_code)]
+    scratchpad: Option<(DmaRegion<[u64]>, DmaRegion<[[u8; PAGE_SIZE]]>)>,
+    commands: Ring,
+    events: DmaRegion<[Trb]>,
+    #[allow(dead_code)]
+    event_segments: DmaRegion<[EventRingSegment]>,
+    event_dequeue: usize,
+    event_cycle: bool,
+    /// Transfer events read while waiting for something else, by slot and
+    /// endpoint
+    pending: BTreeMap<(u8, u8), Vec<Trb>>,
+    slots: BTreeMap<u8, Slot>,
+    buffer: DmaRegion<[u8]>,
+    interrupt_buffers: DmaRegion<[[u8; INTERRUPT_BUFFER_SIZE]]>,
+    interrupt_endpoints: BTreeMap<(u8, u8), InterruptEndpoint>,
+}
+
+impl Xhci {
+    /// Reset the controller, set up its command and event rings, and start it
+    ///
+    /// # Safety
+    ///
+    /// `base` must be a mapping of all of the controller's registers
+    pub unsafe fn new(base: *mut u8) -> Result<Self, UsbError> {
+        let capabilities = &*base.cast::<CapabilityRegisters>();
+        let registers = &*base.add(usize::from(capabilities.length().read())).cast::<OperationalRegisters>();
+        let runtime = base.add(capabilities.runtime_offset().read() as usize & !0x1F);
+        let interrupter = &*runtime.add(INTERRUPTER_0).cast::<InterrupterRegisters>();
+        let doorbells = base.add(capabilities.doorbell_offset().read() as usize & !0b11).cast::<u32>();
+
+        let parameters1 = capabilities.structural_parameters1().read();
+        let max_slots = (parameters1 & 0xFF) as u8;
+        let ports = (parameters1 >> 24) as u8;
+        let context_dwords = match capabilities.capability_parameters1().read() & (1 << 2) {
+            0 => 8,
+            _ => 16,
+        };
+
+        registers.command().modify(|c| c & !command::RUN);
+        crate::poll_until(|| (registers.status().read() & status::HALTED != 0).then(|| ()))?;
+        registers.command().write(command::RESET);
+        crate::poll_until(|| {
+            (registers.command().read() & command::RESET == 0 && registers.status().read() & status::NOT_READY == 0)
+                .then(|| ())
+        })?;
+
+        let mut device_contexts: DmaRegion<[u64]> =
+            DmaRegion::zeroed_many(usize::from(max_slots) + 1).unwrap().assume_init();
+
+        // The controller may want some memory of its own, pointed to by the
+        // first device context entry
+        let parameters2 = capabilities.structural_parameters2().read();
+        let scratchpad_count = ((parameters2 >> 21) & 0x1F) << 5 | (parameters2 >> 27);
+        let scratchpad = match scratchpad_count {
+            0 => None,
+            count => {
+                let mut array: DmaRegion<[u64]> = DmaRegion::zeroed_many(count as usize).unwrap().assume_init();
+                let pages: DmaRegion<[[u8; PAGE_SIZE]]> = DmaRegion::zeroed_many(count as usize).unwrap().assume_init();
+                for (i, entry) in array.iter_mut().enumerate() {
+                    *entry = (pages.physical_address().as_usize() + i * PAGE_SIZE) as u64;
+                }
+
+                device_contexts[0] = array.physical_address().as_usize() as u64;
+                Some((array, pages))
+            }
+        };
+
+        let commands = Ring::new(COMMAND_RING_SIZE);
+        let events: DmaRegion<[Trb]> = DmaRegion::zeroed_many(EVENT_RING_SIZE).unwrap().assume_init();
+        let mut event_segments: DmaRegion<[EventRingSegment]> = DmaRegion::zeroed_many(1).unwrap().assume_init();
+        event_segments[0] = EventRingSegment {
+            base: events.physical_address().as_usize() as u64,
+            size: EVENT_RING_SIZE as u32,
+            _reserved: 0,
+        };
+
+        registers.configure().write(u32::from(max_slots));
+        registers.device_contexts().write(device_contexts.physical_address().as_usize() as u64);
+        registers.command_ring().write(commands.dequeue_pointer());
+
+        interrupter.event_ring_segment_table_size().write(1);
+        interrupter.event_ring_dequeue().write(events.physical_address().as_usize() as u64);
+        interrupter.event_ring_segment_table().write(event_segments.physical_address().as_usize() as u64);
+        // Events are polled, so leave interrupts off
+        interrupter.management().write(0);
+
+        registers.command().write(command::RUN);
+        crate::poll_until(|| (registers.status().read() & status::HALTED == 0).then(|| ()))?;
+
+        let xhci = Self {
+            registers,
+            interrupter,
+            doorbells,
+            ports,
+            context_dwords,
+            device_contexts,
+            scratchpad,
+            commands,
+            events,
+            event_segments,
+            event_dequeue: 0,
+            event_cycle: true,
+            pending: BTreeMap::new(),
+            slots: BTreeMap::new(),
+            buffer: DmaRegion::zeroed_many(BUFFER_SIZE).unwrap().assume_init(),
+            interrupt_buffers: DmaRegion::zeroed_many(MAX_INTERRUPT_ENDPOINTS).unwrap().assume_init(),
+            interrupt_endpoints: BTreeMap::new(),
+        };
+
+        for port in 1..=ports {
+            let register = xhci.port_register(port);
+            if register.read() & port::POWER == 0 {
+                register.write(port::POWER);
+            }
+        }
+        crate::delay_ms(20);
+
+        Ok(xhci)
+    }
+
+    fn port_register(&self, port: u8) -> &Volatile<u32> {
+        let registers = self.registers as *const OperationalRegisters as *const u8;
+        unsafe { &*registers.add(PORT_REGISTERS + 0x10 * (usize::from(port) - 1)).cast::<Volatile<u32>>() }
+    }
+
+    fn ring_doorbell(&self, slot: u8, target: u8) {
+        librust::mem::fence(librust::mem::FenceMode::Write);
+        unsafe { self.doorbells.add(usize::from(slot)).write_volatile(u32::from(target)) };
+    }
+
+    /// Pop the next event off of the event ring, if the controller has written
+    /// one
+    fn next_event(&mut self) -> Option<Trb> {
+        let event = unsafe { core::ptr::read_volatile(&self.events[self.event_dequeue]) };
+        if (event.control & trb::CYCLE != 0) != self.event_cycle {
+            return None;
+        }
+
+        librust::mem::fence(librust::mem::FenceMode::Full);
+        self.event_dequeue += 1;
+        if self.event_dequeue == self.events.len() {
+            self.event_dequeue = 0;
+            self.event_cycle = !self.event_cycle;
+        }
+
+        // Bit 3 clears the event handler busy flag
+        let dequeue = self.events.physical_address().as_usize() + self.event_dequeue * core::mem::size_of::<Trb>();
+        self.interrupter.event_ring_dequeue().write(dequeue as u64 | 1 << 3);
+
+        Some(event)
+    }
+
+    /// Read events until one matches, stashing transfer events that don't
+    fn wait_event(&mut self, mut matches: impl FnMut(&Trb) -> bool) -> Result<Trb, UsbError> {
+        let start = crate::uptime_us();
+        loop {
+            match self.next_event() {
+                Some(event) if matches(&event) => return Ok(event),
+                Some(event) if event.kind() == trb::TRANSFER_EVENT => {
+                    self.pending.entry((event.slot(), event.endpoint())).or_default().push(event)
+                }
+                Some(_) => {}
+                None if crate::uptime_us() > start + crate::TRANSFER_TIMEOUT_MS * 1000 => {
+                    return Err(UsbError::Timeout)
+                }
+                None => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    /// Run a command and wait for it to complete, returning the completion
+    /// event
+    fn command(&mut self, command: Trb) -> Result<Trb, UsbError> {
+        let address = self.commands.push(command);
+        self.ring_doorbell(0, 0);
+
+        let event = self.wait_event(|event| event.kind() == trb::COMMAND_COMPLETION && event.parameter == address)?;
+        match event.completion_code() {
+            completion::SUCCESS => Ok(event),
+            _ => Err(UsbError::Controller("command failed")),
+        }
+    }
+
+    /// Wait for the transfer ending in the TRB at `last` to finish, returning
+    /// the number of bytes left untransferred by a short packet
+    fn wait_transfer(&mut self, slot: u8, endpoint: u8, last: u64) -> Result<u32, UsbError> {
+        let mut residual = 0;
+        loop {
+            let event = match self.pending.get_mut(&(slot, endpoint)).and_then(|events| events.pop()) {
+                Some(event) => event,
+                None => self.wait_event(|event| {
+                    event.kind() == trb::TRANSFER_EVENT && event.slot() == slot && event.endpoint() == endpoint
+                })?,
+            };
+
+            match event.completion_code() {
+                completion::SUCCESS => {}
+                completion::SHORT_PACKET => residual = event.status & 0xFF_FFFF,
+                code => {
+                    self.recover_endpoint(slot, endpoint)?;
+                    return Err(match code {
+                        completion::STALL => UsbError::Stall,
+                        completion::BABBLE | completion::TRANSACTION_ERROR => UsbError::TransactionError,
+                        _ => UsbError::Controller("transfer failed"),
+                    });
+                }
+            }
+
+            if event.parameter == last {
+                return Ok(residual);
+            }
+        }
+    }
+
+    /// Bring a halted endpoint back, skipping whatever was left on its ring
+    fn recover_endpoint(&mut self, slot: u8, endpoint: u8) -> Result<(), UsbError> {
+        let control = u32::from(slot) << 24 | u32::from(endpoint) << 16;
+        self.command(Trb { control: trb::RESET_ENDPOINT << 10 | control, ..Default::default() })?;
+
+        let dequeue = match self.slots.get(&slot).and_then(|s| s.rings.get(&endpoint)) {
+            Some(ring) => ring.dequeue_pointer(),
+            None => return Ok(()),
+        };
+        self.command(Trb { parameter: dequeue, control: trb::SET_DEQUEUE_POINTER << 10 | control, status: 0 }).map(drop)
+    }
+
+    fn context<'a>(context_dwords: usize, input: &'a mut DmaRegion<[u32]>, index: usize) -> &'a mut [u32] {
+        &mut input[index * context_dwords..(index + 1) * context_dwords]
+    }
+
+    fn buffer_address(&self) -> u64 {
+        self.buffer.physical_address().as_usize() as u64
+    }
+}
+
+// The registers and DMA memory are only touched through `&mut self`
+unsafe impl Send for Xhci {}
+
+impl HostController for Xhci {
+    fn name(&self) -> &'static str {
+        "xhci"
+    }
+
+    fn port_count(&self) -> u8 {
+        self.ports
+    }
+
+    fn port_connected(&mut self, port: u8) -> bool {
+        self.port_register(port).read() & port::CONNECTED != 0
+    }
+
+    fn reset_port(&mut self, port: u8) -> Result<Speed, UsbError> {
+        let register = self.port_register(port);
+        if register.read() & port::CONNECTED == 0 {
+            return Err(UsbError::NotConnected);
+        }
+
+        // Writing a one to the enabled bit disables the port, and the change
+        // bits are cleared by writing ones, so only power is carried over
+        register.write(port::POWER | port::RESET);
+        crate::poll_until(|| (register.read() & port::RESET_CHANGE != 0).then(|| ()))?;
+        register.write(port::POWER | (register.read() & port::CHANGE_BITS));
+        crate::delay_ms(10);
+
+        let status = register.read();
+        if status & port::ENABLED == 0 {
+            return Err(UsbError::NotConnected);
+        }
+
+        match (status >> port::SPEED_SHIFT) & port::SPEED_MASK {
+            1 => Ok(Speed::Full),
+            2 => Ok(Speed::Low),
+            3 => Ok(Speed::High),
+            _ => Ok(Speed::Super),
+        }
+    }
+
+    fn address_device(&mut self, mut device: DeviceInfo) -> Result<DeviceInfo, UsbError> {
+        let event = self.command(Trb { control: trb::ENABLE_SLOT << 10, ..Default::default() })?;
+        let slot_id = event.slot();
+
+        // The input context has an input control context before the slot and
+        // endpoint contexts
+        let dwords = self.context_dwords;
+        let mut input: DmaRegion<[u32]> = unsafe { DmaRegion::zeroed_many(33 * dwords).unwrap().assume_init() };
+        let output: DmaRegion<[u32]> = unsafe { DmaRegion::zeroed_many(32 * dwords).unwrap().assume_init() };
+        let ring = Ring::new(TRANSFER_RING_SIZE);
+
+        let speed = match device.speed {
+            Speed::Full => 1,
+            Speed::Low => 2,
+            Speed::High => 3,
+            Speed::Super => 4,
+        };
+
+        // Add the slot and the default control endpoint
+        Self::context(dwords, &mut input, 0)[1] = 0b11;
+        let slot = Self::context(dwords, &mut input, 1);
+        slot[0] = 1 << 27 | speed << 20 | (device.route & 0xF_FFFF);
+        slot[1] = u32::from(device.root_port) << 16;
+        if let Some((hub, port)) = device.transaction_translator {
+            slot[2] = u32::from(port) << 8 | u32::from(hub);
+        }
+
+        let endpoint = Self::context(dwords, &mut input, 2);
+        endpoint[1] = u32::from(device.max_packet_size) << 16 | endpoint_type::CONTROL << 3 | 3 << 1;
+        let dequeue = ring.dequeue_pointer();
+        endpoint[2] = dequeue as u32;
+        endpoint[3] = (dequeue >> 32) as u32;
+        endpoint[4] = 8;
+
+        self.device_contexts[usize::from(slot_id)] = output.physical_address().as_usize() as u64;
+        let input_address = input.physical_address().as_usize() as u64;
+
+        let mut rings = BTreeMap::new();
+        rings.insert(1, ring);
+        self.slots.insert(slot_id, Slot { input, output, rings });
+
+        self.command(Trb {
+            parameter: input_address,
+            control: trb::ADDRESS_DEVICE << 10 | u32::from(slot_id) << 24,
+            status: 0,
+        })?;
+
+        device.address = slot_id;
+        Ok(device)
+    }
+
+    fn set_max_packet_size(&mut self, device: &mut DeviceInfo, max_packet_size: u16) -> Result<(), UsbError> {
+        if device.max_packet_size == max_packet_size {
+            return Ok(());
+        }
+
+        let dwords = self.context_dwords;
+        let slot = self.slots.get_mut(&device.address).ok_or(UsbError::NotConnected)?;
+        let control = Self::context(dwords, &mut slot.input, 0);
+        control[0] = 0;
+        control[1] = 0b10;
+        let endpoint = Self::context(dwords, &mut slot.input, 2);
+        endpoint[1] = (endpoint[1] & 0xFFFF) | u32::from(max_packet_size) << 16;
+        let input_address = slot.input.physical_address().as_usize() as u64;
+
+        self.command(Trb {
+            parameter: input_address,
+            control: trb::EVALUATE_CONTEXT << 10 | u32::from(device.address) << 24,
+            status: 0,
+        })?;
+
+        device.max_packet_size = max_packet_size;
+        Ok(())
+    }
+
+    fn configure_endpoint(&mut self, device: &DeviceInfo, endpoint: &Endpoint) -> Result<(), UsbError> {
+        let kind = match (endpoint.kind, endpoint.direction) {
+            (TransferKind::Bulk, Direction::Out) => endpoint_type::BULK_OUT,
+            (TransferKind::Bulk, Direction::In) => endpoint_type::BULK_IN,
+            (TransferKind::Interrupt, Direction::In) => endpoint_type::INTERRUPT_IN,
+            _ => return Ok(()),
+        };
+
+        let index = self.interrupt_endpoints.len();
+        if kind == endpoint_type::INTERRUPT_IN && index == MAX_INTERRUPT_ENDPOINTS {
+            return Err(UsbError::NoResources);
+        }
+
+        let context_index = endpoint.number * 2 + (endpoint.direction == Direction::In) as u8;
+        // Interrupt intervals are a power of two number of microframes
+        let interval = match device.speed {
+            Speed::Low | Speed::Full => 31 - (u32::from(endpoint.interval.max(1)) * 8).leading_zeros(),
+            Speed::High | Speed::Super => u32::from(endpoint.interval.clamp(1, 16)) - 1,
+        };
+
+        let dwords = self.context_dwords;
+        let mut ring = Ring::new(TRANSFER_RING_SIZE);
+        let dequeue = ring.dequeue_pointer();
+        let slot = self.slots.get_mut(&device.address).ok_or(UsbError::NotConnected)?;
+
+        let control = Self::context(dwords, &mut slot.input, 0);
+        control[0] = 0;
+        control[1] = 1 | 1 << context_index;
+        let slot_context = Self::context(dwords, &mut slot.input, 1);
+        let entries = (slot_context[0] >> 27).max(u32::from(context_index));
+        slot_context[0] = (slot_context[0] & !(0x1F << 27)) | entries << 27;
+
+        let context = Self::context(dwords, &mut slot.input, 1 + usize::from(context_index));
+        context[0] = match kind {
+            endpoint_type::INTERRUPT_IN => interval << 16,
+            _ => 0,
+        };
+        context[1] = u32::from(endpoint.max_packet_size) << 16 | kind << 3 | 3 << 1;
+        context[2] = dequeue as u32;
+        context[3] = (dequeue >> 32) as u32;
+        context[4] = u32::from(endpoint.max_packet_size);
+        let input_address = slot.input.physical_address().as_usize() as u64;
+
+        // Interrupt endpoints always have a transfer waiting on them
+        let mut queued = None;
+        if kind == endpoint_type::INTERRUPT_IN {
+            let length = usize::from(endpoint.max_packet_size).min(INTERRUPT_BUFFER_SIZE);
+            let buffer = self.interrupt_buffers.physical_address().as_usize() + index * INTERRUPT_BUFFER_SIZE;
+            let address = ring.push(Trb {
+                parameter: buffer as u64,
+                status: length as u32,
+                control: trb::NORMAL << 10 | trb::INTERRUPT_ON_COMPLETION | trb::INTERRUPT_ON_SHORT_PACKET,
+            });
+            queued = Some(InterruptEndpoint { index, length, queued: address });
+        }
+
+        slot.rings.insert(context_index, ring);
+        self.command(Trb {
+            parameter: input_address,
+            control: trb::CONFIGURE_ENDPOINT << 10 | u32::from(device.address) << 24,
+            status: 0,
+        })?;
+
+        if let Some(interrupt) = queued {
+            self.interrupt_endpoints.insert((device.address, context_index), interrupt);
+            self.ring_doorbell(device.address, context_index);
+        }
+
+        Ok(())
+    }
+
+    fn configure_hub(&mut self, device: &DeviceInfo, ports: u8) -> Result<(), UsbError> {
+        let dwords = self.context_dwords;
+        let slot = self.slots.get_mut(&device.address).ok_or(UsbError::NotConnected)?;
+
+        let control = Self::context(dwords, &mut slot.input, 0);
+        control[0] = 0;
+        control[1] = 1;
+        let slot_context = Self::context(dwords, &mut slot.input, 1);
+        slot_context[0] |= 1 << 26;
+        slot_context[1] = (slot_context[1] & 0x00FF_FFFF) | u32::from(ports) << 24;
+        let input_address = slot.input.physical_address().as_usize() as u64;
+
+        self.command(Trb {
+            parameter: input_address,
+            control: trb::CONFIGURE_ENDPOINT << 10 | u32::from(device.address) << 24,
+            status: 0,
+        })
+        .map(drop)
+    }
+
+    fn control_transfer(
+        &mut self,
+        device: &DeviceInfo,
+        setup: SetupPacket,
+        data: &mut [u8],
+    ) -> Result<usize, UsbError> {
+        let length = usize::from(setup.length).min(data.len());
+        if length > BUFFER_SIZE {
+            return Err(UsbError::Controller("transfer too large"));
+        }
+
+        let direction = setup.direction();
+        if direction == Direction::Out {
+            self.buffer[..length].copy_from_slice(&data[..length]);
+        }
+
+        let transfer_type = match (length, direction) {
+            (0, _) => trb::TRANSFER_NONE,
+            (_, Direction::In) => trb::TRANSFER_IN,
+            (_, Direction::Out) => trb::TRANSFER_OUT,
+        };
+        // The status stage goes the opposite way of the data stage
+        let status_direction = match (length, direction) {
+            (0, _) | (_, Direction::Out) => trb::DIRECTION_IN,
+            (_, Direction::In) => 0,
+        };
+        let data_direction = match direction {
+            Direction::In => trb::DIRECTION_IN,
+            Direction::Out => 0,
+        };
+
+        let buffer = self.buffer_address();
+        let ring = self
+            .slots
+            .get_mut(&device.address)
+            .and_then(|slot| slot.rings.get_mut(&1))
+            .ok_or(UsbError::NotConnected)?;
+
+        ring.push(Trb {
+            parameter: u64::from_le_bytes(setup.to_bytes()),
+            status: 8,
+            control: trb::SETUP_STAGE << 10 | trb::IMMEDIATE_DATA | transfer_type,
+        });
+        if length > 0 {
+            ring.push(Trb {
+                parameter: buffer,
+                status: length as u32,
+                control: trb::DATA_STAGE << 10 | trb::INTERRUPT_ON_SHORT_PACKET | data_direction,
+            });
+        }
+        let last = ring.push(Trb {
+            status: 0,
+            control: trb::STATUS_STAGE << 10 | trb::INTERRUPT_ON_COMPLETION | status_direction,
+            ..Default::default()
+        });
+
+        self.ring_doorbell(device.address, 1);
+        let residual = self.wait_transfer(device.address, 1, last)?;
+        let transferred = length - (residual as usize).min(length);
+
+        if direction == Direction::In {
+            data[..transferred].copy_from_slice(&self.buffer[..transferred]);
+        }
+
+        Ok(transferred)
+    }
+
+    fn bulk_transfer(&mut self, device: &DeviceInfo, endpoint: &Endpoint, data: &mut [u8]) -> Result<usize, UsbError> {
+        if data.len() > BUFFER_SIZE {
+            return Err(UsbError::Controller("transfer too large"));
+        }
+
+        if endpoint.direction == Direction::Out {
+            self.buffer[..data.len()].copy_from_slice(data);
+        }
+
+        let context_index = endpoint.number * 2 + (endpoint.direction == Direction::In) as u8;
+        let buffer = self.buffer_address();
+        let ring = self
+            .slots
+            .get_mut(&device.address)
+            .and_then(|slot| slot.rings.get_mut(&context_index))
+            .ok_or(UsbError::NotConnected)?;
+
+        let last = ring.push(Trb {
+            parameter: buffer,
+            status: data.len() as u32,
+            control: trb::NORMAL << 10 | trb::INTERRUPT_ON_COMPLETION | trb::INTERRUPT_ON_SHORT_PACKET,
+        });
+
+        self.ring_doorbell(device.address, context_index);
+        let residual = self.wait_transfer(device.address, context_index, last)?;
+        let transferred = data.len() - (residual as usize).min(data.len());
+
+        if endpoint.direction == Direction::In {
+            data[..transferred].copy_from_slice(&self.buffer[..transferred]);
+        }
+
+        Ok(transferred)
+    }
+
+    fn poll_interrupt(
+        &mut self,
+        device: &DeviceInfo,
+        endpoint: &Endpoint,
+        data: &mut [u8],
+    ) -> Result<Option<usize>, UsbError> {
+        let context_index = endpoint.number * 2 + 1;
+        let key = (device.address, context_index);
+        let (index, length, queued) = match self.interrupt_endpoints.get(&key) {
+            Some(endpoint) => (endpoint.index, endpoint.length, endpoint.queued),
+            None => return Err(UsbError::Controller("interrupt endpoint not configured")),
+        };
+
+        // Move anything the controller has reported into the pending events
+        while let Some(event) = self.next_event() {
+            if event.kind() == trb::TRANSFER_EVENT {
+                self.pending.entry((event.slot(), event.endpoint())).or_default().push(event);
+            }
+        }
+
+        let event = match self.pending.get_mut(&key).and_then(|events| events.pop()) {
+            Some(event) if event.parameter == queued => event,
+            _ => return Ok(None),
+        };
+
+        let result = match event.completion_code() {
+            completion::SUCCESS | completion::SHORT_PACKET => Ok(()),
+            completion::STALL => Err(UsbError::Stall),
+            _ => Err(UsbError::TransactionError),
+        };
+        if result.is_err() {
+            self.recover_endpoint(device.address, context_index)?;
+        }
+
+        let transferred = length - ((event.status & 0xFF_FFFF) as usize).min(length);
+        let copied = transferred.min(data.len());
+        data[..copied].copy_from_slice(&self.interrupt_buffers[index][..copied]);
+
+        let buffer = self.interrupt_buffers.physical_address().as_usize() + index * INTERRUPT_BUFFER_SIZE;
+        let ring = self
+            .slots
+            .get_mut(&device.address)
+            .and_then(|slot| slot.rings.get_mut(&context_index))
+            .ok_or(UsbError::NotConnected)?;
+        let address = ring.push(Trb {
+            parameter: buffer as u64,
+            status: length as u32,
+            control: trb::NORMAL << 10 | trb::INTERRUPT_ON_COMPLETION | trb::INTERRUPT_ON_SHORT_PACKET,
+        });
+        if let Some(endpoint) = self.interrupt_endpoints.get_mut(&key) {
+            endpoint.queued = address;
+        }
+        self.ring_doorbell(device.address, context_index);
+
+        result.map(|_| Some(copied))
+    }
+}
@@ -158,6 +158,18 @@ impl BlockDeviceFeatures {
     pub const CONFIG_WRITE_CACHE_TOGGLE: Self = Self(1 << 11);
     pub const DISCARD: Self = Self(1 << 13);
     pub const WRITE_ZEROES: Self = Self(1 << 14);
+
+    pub const fn none() -> Self {
+        Self(0)
+    }
+
+    pub const fn new(raw: u32) -> Self {
+        Self(raw)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
 }
 
 impl core::ops::BitOr for BlockDeviceFeatures {
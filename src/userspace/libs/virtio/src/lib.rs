@@ -11,51 +11,42 @@ pub mod splitqueue;
 pub use registers::StatusFlag;
 use volatile::{Read, ReadWrite, Volatile, Write};
 
-#[repr(C)]
-pub struct VirtIoHeader {
-    pub magic: Volatile<u32, Read>,
-    pub version: Volatile<u32, Read>,
-    pub device_id: Volatile<u32, Read>,
-    pub vendor_id: Volatile<u32, Read>,
-    pub device_features: registers::DeviceFeatures,
-    pub device_features_select: Volatile<u32, Write>,
-    _reserved1: [u32; 2],
-    pub driver_features: Volatile<u32, Write>,
-    pub driver_features_select: Volatile<u32, Write>,
-    _reserved2: [u32; 2],
-    pub queue_select: Volatile<u32, Write>,
-    pub queue_size_max: Volatile<u32, Read>,
-    pub queue_size: Volatile<u32, Write>,
-    _reserved3: [u32; 2],
-    pub queue_ready: registers::QueueReady,
-    _reserved4: [u32; 2],
-    pub queue_notify: registers::QueueNotify,
-    _reserved5: [u32; 3],
-    pub interrupt_status: registers::InterruptStatus,
-    pub interrupt_ack: registers::InterruptAck,
-    _reserved6: [u32; 2],
-    pub status: registers::Status,
-    _reserved7: [u32; 3],
-    pub queue_descriptor: registers::QueueDescriptor,
-    _reserved8: [u32; 2],
-    pub queue_available: registers::QueueAvailable,
-    _reserved9: [u32; 2],
-    pub queue_used: registers::QueueUsed,
-    _reserved10: [u32; 21],
-    pub config_generation: Volatile<u32, Read>,
+volatile::register_block! {
+    pub struct VirtIoHeader [0x100] {
+        0x000 => pub magic: Volatile<u32, Read>,
+        0x004 => pub version: Volatile<u32, Read>,
+        0x008 => pub device_id: Volatile<u32, Read>,
+        0x00C => pub vendor_id: Volatile<u32, Read>,
+        0x010 => pub device_features: registers::DeviceFeatures,
+        0x014 => pub device_features_select: Volatile<u32, Write>,
+        0x020 => pub driver_features: Volatile<u32, Write>,
+        0x024 => pub driver_features_select: Volatile<u32, Write>,
+        0x030 => pub queue_select: Volatile<u32, Write>,
+        0x034 => pub queue_size_max: Volatile<u32, Read>,
+        0x038 => pub queue_size: Volatile<u32, Write>,
+        0x044 => pub queue_ready: registers::QueueReady,
+        0x050 => pub queue_notify: registers::QueueNotify,
+        0x060 => pub interrupt_status: registers::InterruptStatus,
+        0x064 => pub interrupt_ack: registers::InterruptAck,
+        0x070 => pub status: registers::Status,
+        0x080 => pub queue_descriptor: registers::QueueDescriptor,
+        0x090 => pub queue_available: registers::QueueAvailable,
+        0x0A0 => pub queue_used: registers::QueueUsed,
+        0x0FC => pub config_generation: Volatile<u32, Read>,
+    }
 }
 
 impl VirtIoHeader {
     pub fn valid_magic(&self) -> bool {
-        self.magic.read() == u32::from_le_bytes(*b"virt")
+        self.magic().read() == u32::from_le_bytes(*b"virt")
     }
 
     pub fn device_type(&self) -> Option<DeviceType> {
-        DeviceType::from_u32(self.device_id.read())
+        DeviceType::from_u32(self.device_id().read())
     }
 
     pub fn features(&self) -> u32 {
-        self.device_features.device_type_feature_bits()
+        self.device_features().device_type_feature_bits()
     }
 }
 
@@ -194,7 +185,7 @@ mod registers {
         }
 
         pub fn set_flag(&self, flag: StatusFlag) {
-            self.0.write(self.0.read() | flag as u32);
+            self.0.modify(|status| status | flag as u32);
             librust::mem::fence(librust::mem::FenceMode::Write);
         }
 
@@ -236,3 +236,11 @@ impl core::ops::BitOr for DescriptorFlags {
         Self(self.0 | rhs.0)
     }
 }
+
+impl core::ops::BitAnd for DescriptorFlags {
+    type Output = bool;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        (self.0 & rhs.0) == rhs.0
+    }
+}
@@ -0,0 +1,610 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::module::{ConstExpr, FuncType, Import, Instr, Module};
+use alloc::{string::String, vec, vec::Vec};
+
+const PAGE_SIZE: usize = 64 * 1024;
+
+/// Values are untyped on the stack, `i32`s are kept zero-extended
+type Value = u64;
+
+/// Why execution stopped early
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Trap {
+    Unreachable,
+    OutOfFuel,
+    MemoryOutOfBounds,
+    DivisionByZero,
+    IntegerOverflow,
+    StackOverflow,
+    UnknownImport {
+        module: String,
+        name: String,
+    },
+    UnknownExport,
+    InvalidArguments,
+    /// The module failed a check that would've been done by validation
+    Malformed(&'static str),
+    /// Raised by a host function
+    Host(String),
+}
+
+/// Resource limits for an instance, so a module can't take more than its
+/// share of memory or CPU time
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Maximum size of the linear memory in 64 KiB pages
+    pub max_pages: u32,
+    /// Maximum number of values on the stack
+    pub max_stack: usize,
+    pub max_call_depth: usize,
+    /// Number of instructions that can be executed across all calls into the
+    /// instance, or `None` for no limit
+    pub fuel: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self { max_pages: 16, max_stack: 64 * 1024, max_call_depth: 256, fuel: None }
+    }
+}
+
+/// Functions provided to a module by the embedder, which are the only way it
+/// can affect anything outside of its own memory
+pub trait Host {
+    /// Resolve an import to an ID which is passed to [`Host::call`], or `None`
+    /// if the host doesn't provide it (or it has the wrong type)
+    fn resolve(&mut self, import: &Import, ty: &FuncType) -> Option<usize>;
+
+    fn call(&mut self, id: usize, args: &[u64], memory: &mut Memory) -> Result<Option<u64>, Trap>;
+}
+
+/// The linear memory of an instance
+#[derive(Debug)]
+pub struct Memory {
+    bytes: Vec<u8>,
+    max_pages: u32,
+}
+
+impl Memory {
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn read(&self, addr: u64, len: u64) -> Result<&[u8], Trap> {
+        let range = self.range(addr, len)?;
+        Ok(&self.bytes[range])
+    }
+
+    pub fn read_mut(&mut self, addr: u64, len: u64) -> Result<&mut [u8], Trap> {
+        let range = self.range(addr, len)?;
+        Ok(&mut self.bytes[range])
+    }
+
+    pub fn write(&mut self, addr: u64, bytes: &[u8]) -> Result<(), Trap> {
+        self.read_mut(addr, bytes.len() as u64)?.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn range(&self, addr: u64, len: u64) -> Result<core::ops::Range<usize>, Trap> {
+        match addr.checked_add(len) {
+            Some(end) if end <= self.bytes.len() as u64 => Ok(addr as usize..end as usize),
+            _ => Err(Trap::MemoryOutOfBounds),
+        }
+    }
+
+    fn pages(&self) -> u32 {
+        (self.bytes.len() / PAGE_SIZE) as u32
+    }
+
+    /// Grow the memory by `delta` pages, returning the previous size in pages
+    fn grow(&mut self, delta: u32) -> Option<u32> {
+        let old = self.pages();
+        let new = old.checked_add(delta).filter(|new| *new <= self.max_pages)?;
+        self.bytes.resize(new as usize * PAGE_SIZE, 0);
+
+        Some(old)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Label {
+    /// Where to continue from when branched to
+    target: usize,
+    /// Number of values carried by a branch
+    arity: usize,
+    /// Height of the stack at the start of the block
+    height: usize,
+    is_loop: bool,
+}
+
+#[derive(Debug)]
+struct Frame {
+    /// Index into the module's defined (not imported) functions
+    function: usize,
+    pc: usize,
+    locals: Vec<Value>,
+    labels_base: usize,
+    stack_base: usize,
+    arity: usize,
+}
+
+/// An instantiated module
+pub struct Instance<H: Host> {
+    module: Module,
+    host: H,
+    /// The host function IDs of the module's imports
+    imports: Vec<usize>,
+    memory: Memory,
+    globals: Vec<Value>,
+    limits: Limits,
+    fuel: Option<u64>,
+}
+
+impl<H: Host> Instance<H> {
+    /// Instantiate the module, resolving its imports with `host` and running
+    /// its start function if it has one
+    pub fn new(module: Module, mut host: H, limits: Limits) -> Result<Self, Trap> {
+        let imports = module
+            .imports
+            .iter()
+            .map(|import| {
+                host.resolve(import, module.import_type(import))
+                    .ok_or_else(|| Trap::UnknownImport { module: import.module.clone(), name: import.name.clone() })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let memory = match module.memory {
+            Some(memory) if memory.min > limits.max_pages => return Err(Trap::MemoryOutOfBounds),
+            Some(memory) => Memory {
+                bytes: vec![0; memory.min as usize * PAGE_SIZE],
+                max_pages: memory.max.unwrap_or(u32::MAX).min(limits.max_pages),
+            },
+            None => Memory { bytes: Vec::new(), max_pages: 0 },
+        };
+
+        let mut globals = Vec::with_capacity(module.globals.len());
+        for global in &module.globals {
+            let value = eval_const(global.init, &globals)?;
+            globals.push(value);
+        }
+
+        let mut instance = Self { module, host, imports, memory, globals, limits, fuel: limits.fuel };
+
+        for data in &instance.module.data {
+            let offset = eval_const(data.offset, &instance.globals)? as u32;
+            instance.memory.write(u64::from(offset), &data.bytes)?;
+        }
+
+        if let Some(start) = instance.module.start {
+            instance.execute(start, &[])?;
+        }
+
+        Ok(instance)
+    }
+
+    /// Call an exported function
+    pub fn invoke(&mut self, name: &str, args: &[u64]) -> Result<Vec<u64>, Trap> {
+        let index = self.module.export(name).ok_or(Trap::UnknownExport)?;
+        match self.module.function_type(index) {
+            Some(ty) if ty.params.len() == args.len() => self.execute(index, args),
+            _ => Err(Trap::InvalidArguments),
+        }
+    }
+
+    pub fn host(&mut self) -> &mut H {
+        &mut self.host
+    }
+
+    pub fn memory(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    pub fn remaining_fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    fn execute(&mut self, index: u32, args: &[u64]) -> Result<Vec<u64>, Trap> {
+        let Self { module, host, imports, memory, globals, limits, fuel } = self;
+
+        let mut stack: Vec<Value> = args.into();
+        let mut labels: Vec<Label> = Vec::new();
+        let mut frames: Vec<Frame> = Vec::new();
+
+        call(module, host, imports, memory, limits, &mut stack, &labels, &mut frames, index)?;
+
+        while let Some(frame) = frames.last_mut() {
+            let body = &module.functions[frame.function].body;
+            let instr = body.get(frame.pc).ok_or(Trap::Malformed("ran off the end of a function"))?;
+            frame.pc += 1;
+
+            if let Some(fuel) = fuel {
+                *fuel = fuel.checked_sub(1).ok_or(Trap::OutOfFuel)?;
+            }
+
+            match instr {
+                Instr::Unreachable => return Err(Trap::Unreachable),
+                Instr::Nop => {}
+                Instr::Block { ty, end } => {
+                    let (params, results) = module.block_arity(*ty);
+                    let height = stack.len().checked_sub(params).ok_or(Trap::Malformed("stack underflow"))?;
+                    labels.push(Label { target: end + 1, arity: results, height, is_loop: false });
+                }
+                Instr::Loop { ty } => {
+                    let (params, _) = module.block_arity(*ty);
+                    let height = stack.len().checked_sub(params).ok_or(Trap::Malformed("stack underflow"))?;
+                    labels.push(Label { target: frame.pc, arity: params, height, is_loop: true });
+                }
+                Instr::If { ty, else_, end } => {
+                    let condition = pop(&mut stack)? as u32;
+                    let (params, results) = module.block_arity(*ty);
+                    let height = stack.len().checked_sub(params).ok_or(Trap::Malformed("stack underflow"))?;
+                    let label = Label { target: end + 1, arity: results, height, is_loop: false };
+
+                    match (condition, else_) {
+                        (0, None) => frame.pc = end + 1,
+                        (0, Some(else_)) => {
+                            labels.push(label);
+                            frame.pc = else_ + 1;
+                        }
+                        _ => labels.push(label),
+                    }
+                }
+                // Only reached at the end of the `then` arm of an `if`
+                Instr::Else { end } => {
+                    labels.pop();
+                    frame.pc = end + 1;
+                }
+                Instr::End => {
+                    if labels.len() > frame.labels_base {
+                        labels.pop();
+                    } else {
+                        ret(&mut stack, &mut labels, &mut frames)?;
+                    }
+                }
+                Instr::Br(depth) => branch(&mut stack, &mut labels, &mut frames, *depth)?,
+                Instr::BrIf(depth) => {
+                    if pop(&mut stack)? as u32 != 0 {
+                        branch(&mut stack, &mut labels, &mut frames, *depth)?;
+                    }
+                }
+                Instr::BrTable(depths, default) => {
+                    let index = pop(&mut stack)? as u32;
+                    let depth = depths.get(index as usize).unwrap_or(default);
+                    branch(&mut stack, &mut labels, &mut frames, *depth)?;
+                }
+                Instr::Return => ret(&mut stack, &mut labels, &mut frames)?,
+                Instr::Call(index) => {
+                    call(module, host, imports, memory, limits, &mut stack, &labels, &mut frames, *index)?
+                }
+                Instr::Drop => {
+                    pop(&mut stack)?;
+                }
+                Instr::Select => {
+                    let condition = pop(&mut stack)? as u32;
+                    let b = pop(&mut stack)?;
+                    let a = pop(&mut stack)?;
+                    stack.push(if condition != 0 { a } else { b });
+                }
+                Instr::LocalGet(index) => stack.push(frame.locals[*index as usize]),
+                Instr::LocalSet(index) => frame.locals[*index as usize] = pop(&mut stack)?,
+                Instr::LocalTee(index) => {
+                    frame.locals[*index as usize] = *stack.last().ok_or(Trap::Malformed("stack underflow"))?
+                }
+                Instr::GlobalGet(index) => stack.push(globals[*index as usize]),
+                Instr::GlobalSet(index) => globals[*index as usize] = pop(&mut stack)?,
+                Instr::Load { op, offset } => {
+                    let addr = u64::from(pop(&mut stack)? as u32) + u64::from(*offset);
+                    let (size, signed, wide) = match op {
+                        0x28 => (4, false, false),
+                        0x29 => (8, false, true),
+                        0x2C => (1, true, false),
+                        0x2D => (1, false, false),
+                        0x2E => (2, true, false),
+                        0x2F => (2, false, false),
+                        0x30 => (1, true, true),
+                        0x31 => (1, false, true),
+                        0x32 => (2, true, true),
+                        0x33 => (2, false, true),
+                        0x34 => (4, true, true),
+                        _ => (4, false, true),
+                    };
+
+                    let mut bytes = [0; 8];
+                    bytes[..size].copy_from_slice(memory.read(addr, size as u64)?);
+                    let mut value = u64::from_le_bytes(bytes);
+                    if signed {
+                        let shift = 64 - size * 8;
+                        value = (((value << shift) as i64) >> shift) as u64;
+                    }
+
+                    stack.push(if wide { value } else { u64::from(value as u32) });
+                }
+                Instr::Store { op, offset } => {
+                    let value = pop(&mut stack)?;
+                    let addr = u64::from(pop(&mut stack)? as u32) + u64::from(*offset);
+                    let size = match op {
+                        0x36 | 0x3E => 4,
+                        0x37 => 8,
+                        0x3A | 0x3C => 1,
+                        _ => 2,
+                    };
+
+                    memory.write(addr, &value.to_le_bytes()[..size])?;
+                }
+                Instr::MemorySize => stack.push(u64::from(memory.pages())),
+                Instr::MemoryGrow => {
+                    let delta = pop(&mut stack)? as u32;
+                    stack.push(u64::from(memory.grow(delta).unwrap_or(u32::MAX)));
+                }
+                Instr::MemoryCopy => {
+                    let len = u64::from(pop(&mut stack)? as u32);
+                    let src = u64::from(pop(&mut stack)? as u32);
+                    let dst = u64::from(pop(&mut stack)? as u32);
+                    let src = memory.range(src, len)?;
+                    memory.range(dst, len)?;
+                    memory.bytes.copy_within(src, dst as usize);
+                }
+                Instr::MemoryFill => {
+                    let len = u64::from(pop(&mut stack)? as u32);
+                    let value = pop(&mut stack)? as u8;
+                    let dst = u64::from(pop(&mut stack)? as u32);
+                    memory.read_mut(dst, len)?.fill(value);
+                }
+                Instr::I32Const(value) => stack.push(u64::from(*value as u32)),
+                Instr::I64Const(value) => stack.push(*value as u64),
+                Instr::Numeric(op) => numeric(*op, &mut stack)?,
+            }
+
+            if stack.len() > limits.max_stack {
+                return Err(Trap::StackOverflow);
+            }
+        }
+
+        Ok(stack)
+    }
+}
+
+fn eval_const(expr: ConstExpr, globals: &[Value]) -> Result<Value, Trap> {
+    match expr {
+        ConstExpr::I32(value) => Ok(u64::from(value as u32)),
+        ConstExpr::I64(value) => Ok(value as u64),
+        ConstExpr::Global(index) => {
+            globals.get(index as usize).copied().ok_or(Trap::Malformed("global index out of bounds"))
+        }
+    }
+}
+
+fn pop(stack: &mut Vec<Value>) -> Result<Value, Trap> {
+    stack.pop().ok_or(Trap::Malformed("stack underflow"))
+}
+
+/// Pop the top `n` values off of the stack, in order
+fn pop_n(stack: &mut Vec<Value>, n: usize) -> Result<Vec<Value>, Trap> {
+    let at = stack.len().checked_sub(n).ok_or(Trap::Malformed("stack underflow"))?;
+    Ok(stack.split_off(at))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn call<H: Host>(
+    module: &Module,
+    host: &mut H,
+    imports: &[usize],
+    memory: &mut Memory,
+    limits: &Limits,
+    stack: &mut Vec<Value>,
+    labels: &[Label],
+    frames: &mut Vec<Frame>,
+    index: u32,
+) -> Result<(), Trap> {
+    let ty = module.function_type(index).ok_or(Trap::Malformed("function index out of bounds"))?;
+    let args = pop_n(stack, ty.params.len())?;
+
+    let function = match (index as usize).checked_sub(imports.len()) {
+        Some(function) => function,
+        None => {
+            let result = host.call(imports[index as usize], &args, memory)?;
+            match (result, ty.results.len()) {
+                (Some(value), 1) => stack.push(value),
+                (None, 0) => {}
+                _ => return Err(Trap::Host("host function returned the wrong number of results".into())),
+            }
+
+            return Ok(());
+        }
+    };
+
+    if frames.len() >= limits.max_call_depth {
+        return Err(Trap::StackOverflow);
+    }
+
+    let mut locals = args;
+    locals.resize(locals.len() + module.functions[function].n_locals, 0);
+    frames.push(Frame {
+        function,
+        pc: 0,
+        locals,
+        labels_base: labels.len(),
+        stack_base: stack.len(),
+        arity: ty.results.len(),
+    });
+
+    Ok(())
+}
+
+/// Return from the current function, leaving its results on the stack
+fn ret(stack: &mut Vec<Value>, labels: &mut Vec<Label>, frames: &mut Vec<Frame>) -> Result<(), Trap> {
+    let frame = frames.pop().ok_or(Trap::Malformed("return outside of a function"))?;
+    let results = pop_n(stack, frame.arity)?;
+    stack.truncate(frame.stack_base);
+    stack.extend(results);
+    labels.truncate(frame.labels_base);
+
+    Ok(())
+}
+
+fn branch(stack: &mut Vec<Value>, labels: &mut Vec<Label>, frames: &mut Vec<Frame>, depth: u32) -> Result<(), Trap> {
+    let labels_base = frames.last().ok_or(Trap::Malformed("branch outside of a function"))?.labels_base;
+    let depth = depth as usize;
+    let n_labels = labels.len() - labels_base;
+
+    // Branching to the outermost block of a function is the same as returning
+    if depth == n_labels {
+        return ret(stack, labels, frames);
+    } else if depth > n_labels {
+        return Err(Trap::Malformed("branch depth out of bounds"));
+    }
+
+    let label = labels[labels.len() - 1 - depth];
+    let results = pop_n(stack, label.arity)?;
+    stack.truncate(label.height);
+    stack.extend(results);
+
+    // Loops are branched to at their start, so they stay on the label stack
+    match label.is_loop {
+        true => labels.truncate(labels.len() - depth),
+        false => labels.truncate(labels.len() - 1 - depth),
+    }
+
+    if let Some(frame) = frames.last_mut() {
+        frame.pc = label.target;
+    }
+
+    Ok(())
+}
+
+fn numeric(op: u8, stack: &mut Vec<Value>) -> Result<(), Trap> {
+    let result = match op {
+        0x45 => u64::from(pop(stack)? as u32 == 0),
+        0x46..=0x4F => {
+            let b = pop(stack)? as u32;
+            let a = pop(stack)? as u32;
+            u64::from(compare(op - 0x46, a as i32 as i64, b as i32 as i64, u64::from(a), u64::from(b)))
+        }
+        0x50 => u64::from(pop(stack)? == 0),
+        0x51..=0x5A => {
+            let b = pop(stack)?;
+            let a = pop(stack)?;
+            u64::from(compare(op - 0x51, a as i64, b as i64, a, b))
+        }
+        0x67..=0x69 => {
+            let a = pop(stack)? as u32;
+            u64::from(match op {
+                0x67 => a.leading_zeros(),
+                0x68 => a.trailing_zeros(),
+                _ => a.count_ones(),
+            })
+        }
+        0x6A..=0x78 => {
+            let b = pop(stack)? as u32;
+            let a = pop(stack)? as u32;
+            u64::from(binary_i32(op, a, b)?)
+        }
+        0x79..=0x7B => {
+            let a = pop(stack)?;
+            u64::from(match op {
+                0x79 => a.leading_zeros(),
+                0x7A => a.trailing_zeros(),
+                _ => a.count_ones(),
+            })
+        }
+        0x7C..=0x8A => {
+            let b = pop(stack)?;
+            let a = pop(stack)?;
+            binary_i64(op, a, b)?
+        }
+        0xA7 => u64::from(pop(stack)? as u32),
+        0xAC => pop(stack)? as u32 as i32 as i64 as u64,
+        0xAD => u64::from(pop(stack)? as u32),
+        0xC0 => u64::from(pop(stack)? as u8 as i8 as i32 as u32),
+        0xC1 => u64::from(pop(stack)? as u16 as i16 as i32 as u32),
+        0xC2 => pop(stack)? as u8 as i8 as i64 as u64,
+        0xC3 => pop(stack)? as u16 as i16 as i64 as u64,
+        0xC4 => pop(stack)? as u32 as i32 as i64 as u64,
+        _ => return Err(Trap::Malformed("unknown numeric instruction")),
+    };
+
+    stack.push(result);
+
+    Ok(())
+}
+
+/// `eq`, `ne`, `lt_s`, `lt_u`, `gt_s`, `gt_u`, `le_s`, `le_u`, `ge_s`, `ge_u`
+/// in opcode order
+fn compare(kind: u8, signed_a: i64, signed_b: i64, a: u64, b: u64) -> bool {
+    match kind {
+        0 => a == b,
+        1 => a != b,
+        2 => signed_a < signed_b,
+        3 => a < b,
+        4 => signed_a > signed_b,
+        5 => a > b,
+        6 => signed_a <= signed_b,
+        7 => a <= b,
+        8 => signed_a >= signed_b,
+        _ => a >= b,
+    }
+}
+
+fn binary_i32(op: u8, a: u32, b: u32) -> Result<u32, Trap> {
+    Ok(match op {
+        0x6A => a.wrapping_add(b),
+        0x6B => a.wrapping_sub(b),
+        0x6C => a.wrapping_mul(b),
+        0x6D => match (a as i32).checked_div(b as i32) {
+            Some(value) => value as u32,
+            None if b == 0 => return Err(Trap::DivisionByZero),
+            None => return Err(Trap::IntegerOverflow),
+        },
+        0x6E => a.checked_div(b).ok_or(Trap::DivisionByZero)?,
+        0x6F => match b {
+            0 => return Err(Trap::DivisionByZero),
+            _ => (a as i32).wrapping_rem(b as i32) as u32,
+        },
+        0x70 => a.checked_rem(b).ok_or(Trap::DivisionByZero)?,
+        0x71 => a & b,
+        0x72 => a | b,
+        0x73 => a ^ b,
+        0x74 => a.wrapping_shl(b),
+        0x75 => (a as i32).wrapping_shr(b) as u32,
+        0x76 => a.wrapping_shr(b),
+        0x77 => a.rotate_left(b % 32),
+        _ => a.rotate_right(b % 32),
+    })
+}
+
+fn binary_i64(op: u8, a: u64, b: u64) -> Result<u64, Trap> {
+    Ok(match op {
+        0x7C => a.wrapping_add(b),
+        0x7D => a.wrapping_sub(b),
+        0x7E => a.wrapping_mul(b),
+        0x7F => match (a as i64).checked_div(b as i64) {
+            Some(value) => value as u64,
+            None if b == 0 => return Err(Trap::DivisionByZero),
+            None => return Err(Trap::IntegerOverflow),
+        },
+        0x80 => a.checked_div(b).ok_or(Trap::DivisionByZero)?,
+        0x81 => match b {
+            0 => return Err(Trap::DivisionByZero),
+            _ => (a as i64).wrapping_rem(b as i64) as u64,
+        },
+        0x82 => a.checked_rem(b).ok_or(Trap::DivisionByZero)?,
+        0x83 => a & b,
+        0x84 => a | b,
+        0x85 => a ^ b,
+        0x86 => a.wrapping_shl(b as u32),
+        0x87 => (a as i64).wrapping_shr(b as u32) as u64,
+        0x88 => a.wrapping_shr(b as u32),
+        0x89 => a.rotate_left((b % 64) as u32),
+        _ => a.rotate_right((b % 64) as u32),
+    })
+}
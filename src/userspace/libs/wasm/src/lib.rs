@@ -0,0 +1,224 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A small WebAssembly interpreter for running untrusted code. Only the integer
+//! subset of the MVP is supported (no floating point, tables, or indirect
+//! calls), which is plenty for plugins compiled from Rust or C without a libc.
+//! Modules can only affect the outside world through the imports the embedder
+//! provides with a [`Host`].
+
+#![no_std]
+
+extern crate alloc;
+
+mod interpreter;
+mod module;
+
+pub use interpreter::{Host, Instance, Limits, Memory, Trap};
+pub use module::{FuncType, Import, Module, ParseError, ValueType};
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use alloc::{vec, vec::Vec};
+
+    const I32: u8 = 0x7F;
+    const I64: u8 = 0x7E;
+
+    fn leb(mut n: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = (n & 0x7F) as u8;
+            n >>= 7;
+            match n {
+                0 => break bytes.push(byte),
+                _ => bytes.push(byte | 0x80),
+            }
+        }
+
+        bytes
+    }
+
+    fn section(id: u8, items: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = leb(items.len());
+        items.iter().for_each(|item| body.extend(item));
+
+        let mut section = vec![id];
+        section.extend(leb(body.len()));
+        section.extend(body);
+        section
+    }
+
+    fn func_type(params: &[u8], results: &[u8]) -> Vec<u8> {
+        let mut ty = vec![0x60];
+        ty.extend(leb(params.len()));
+        ty.extend(params);
+        ty.extend(leb(results.len()));
+        ty.extend(results);
+        ty
+    }
+
+    fn code(locals: &[(u8, u8)], body: &[u8]) -> Vec<u8> {
+        let mut function = leb(locals.len());
+        locals.iter().for_each(|&(count, ty)| function.extend([count, ty]));
+        function.extend(body);
+
+        let mut entry = leb(function.len());
+        entry.extend(function);
+        entry
+    }
+
+    fn export(name: &str, index: u8) -> Vec<u8> {
+        let mut export = leb(name.len());
+        export.extend(name.as_bytes());
+        export.extend([0x00, index]);
+        export
+    }
+
+    fn module(sections: &[Vec<u8>]) -> Module {
+        let mut bytes = b"\0asm\x01\0\0\0".to_vec();
+        sections.iter().for_each(|section| bytes.extend(section));
+        Module::parse(&bytes).unwrap()
+    }
+
+    struct NoImports;
+
+    impl Host for NoImports {
+        fn resolve(&mut self, _: &Import, _: &FuncType) -> Option<usize> {
+            None
+        }
+
+        fn call(&mut self, _: usize, _: &[u64], _: &mut Memory) -> Result<Option<u64>, Trap> {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn recursive_factorial() {
+        #[rustfmt::skip]
+        let body = [
+            0x20, 0x00, 0x50,
+            0x04, I64,
+                0x42, 0x01,
+            0x05,
+                0x20, 0x00,
+                0x20, 0x00, 0x42, 0x01, 0x7D, 0x10, 0x00,
+                0x7E,
+            0x0B,
+            0x0B,
+        ];
+
+        let module = module(&[
+            section(1, &[func_type(&[I64], &[I64])]),
+            section(3, &[vec![0]]),
+            section(7, &[export("fact", 0)]),
+            section(10, &[code(&[], &body)]),
+        ]);
+
+        let mut instance = Instance::new(module, NoImports, Limits::default()).unwrap();
+        assert_eq!(instance.invoke("fact", &[20]), Ok(vec![2432902008176640000]));
+    }
+
+    #[test]
+    fn loop_with_branches() {
+        // Sum the numbers from 1 to the argument
+        #[rustfmt::skip]
+        let body = [
+            0x02, 0x40,
+                0x03, 0x40,
+                    0x20, 0x00, 0x45, 0x0D, 0x01,
+                    0x20, 0x01, 0x20, 0x00, 0x6A, 0x21, 0x01,
+                    0x20, 0x00, 0x41, 0x01, 0x6B, 0x21, 0x00,
+                    0x0C, 0x00,
+                0x0B,
+            0x0B,
+            0x20, 0x01,
+            0x0B,
+        ];
+
+        let module = module(&[
+            section(1, &[func_type(&[I32], &[I32])]),
+            section(3, &[vec![0]]),
+            section(7, &[export("sum", 0)]),
+            section(10, &[code(&[(1, I32)], &body)]),
+        ]);
+
+        let mut instance = Instance::new(module, NoImports, Limits::default()).unwrap();
+        assert_eq!(instance.invoke("sum", &[100]), Ok(vec![5050]));
+    }
+
+    #[test]
+    fn host_calls_and_memory() {
+        struct Log(Vec<u8>);
+
+        impl Host for Log {
+            fn resolve(&mut self, import: &Import, _: &FuncType) -> Option<usize> {
+                (import.module == "env" && import.name == "log").then_some(0)
+            }
+
+            fn call(&mut self, _: usize, args: &[u64], memory: &mut Memory) -> Result<Option<u64>, Trap> {
+                self.0.extend(memory.read(args[0], args[1])?);
+                Ok(None)
+            }
+        }
+
+        let mut import = leb(3);
+        import.extend(b"env");
+        import.extend(leb(3));
+        import.extend(b"log");
+        import.extend([0x00, 0x00]);
+
+        // Store a `!` after the string from the data segment, then log both
+        let body = [0x41, 0x12, 0x41, 0x21, 0x3A, 0x00, 0x00, 0x41, 0x10, 0x41, 0x03, 0x10, 0x00, 0x0B];
+        let mut data = vec![0x00, 0x41, 0x10, 0x0B];
+        data.extend(leb(2));
+        data.extend(b"hi");
+
+        let module = module(&[
+            section(1, &[func_type(&[I32, I32], &[]), func_type(&[], &[])]),
+            section(2, &[import]),
+            section(3, &[vec![1]]),
+            section(5, &[vec![0x00, 0x01]]),
+            section(7, &[export("run", 1)]),
+            section(10, &[code(&[], &body)]),
+            section(11, &[data]),
+        ]);
+
+        let mut instance = Instance::new(module, Log(Vec::new()), Limits::default()).unwrap();
+        assert_eq!(instance.invoke("run", &[]), Ok(vec![]));
+        assert_eq!(instance.host().0, b"hi!");
+    }
+
+    #[test]
+    fn traps() {
+        let divide = [0x20, 0x00, 0x20, 0x01, 0x6D, 0x0B];
+        let spin = [0x03, 0x40, 0x0C, 0x00, 0x0B, 0x41, 0x00, 0x0B];
+        let module = module(&[
+            section(1, &[func_type(&[I32, I32], &[I32])]),
+            section(3, &[vec![0], vec![0]]),
+            section(7, &[export("div", 0), export("spin", 1)]),
+            section(10, &[code(&[], &divide), code(&[], &spin)]),
+        ]);
+
+        let limits = Limits { fuel: Some(10_000), ..Limits::default() };
+        let mut instance = Instance::new(module, NoImports, limits).unwrap();
+        assert_eq!(instance.invoke("div", &[7, 2]), Ok(vec![3]));
+        assert_eq!(instance.invoke("div", &[7, 0]), Err(Trap::DivisionByZero));
+        assert_eq!(instance.invoke("div", &[0x8000_0000, 0xFFFF_FFFF]), Err(Trap::IntegerOverflow));
+        assert_eq!(instance.invoke("spin", &[0, 0]), Err(Trap::OutOfFuel));
+        assert_eq!(instance.invoke("div", &[1]), Err(Trap::InvalidArguments));
+    }
+
+    #[test]
+    fn rejects_floating_point() {
+        let mut bytes = b"\0asm\x01\0\0\0".to_vec();
+        bytes.extend(section(1, &[func_type(&[0x7D], &[])]));
+        assert_eq!(Module::parse(&bytes).unwrap_err(), ParseError::Unsupported("floating point"));
+    }
+}
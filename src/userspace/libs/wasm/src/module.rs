@@ -0,0 +1,630 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::{string::String, vec::Vec};
+
+const MAGIC: &[u8] = b"\0asm";
+const VERSION: u32 = 1;
+/// Keeps a tiny module from making us allocate huge amounts of memory for
+/// zero-initialized locals
+const MAX_LOCALS: u32 = 50_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    I32,
+    I64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuncType {
+    pub params: Vec<ValueType>,
+    pub results: Vec<ValueType>,
+}
+
+/// A function the module expects the embedder to provide
+#[derive(Debug, Clone)]
+pub struct Import {
+    pub module: String,
+    pub name: String,
+    pub(crate) ty: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    UnexpectedEof,
+    BadMagic,
+    UnsupportedVersion(u32),
+    IntegerTooLarge,
+    InvalidUtf8,
+    Malformed(&'static str),
+    /// The module uses a feature the interpreter doesn't implement
+    Unsupported(&'static str),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum BlockType {
+    Empty,
+    Value,
+    Func(u32),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Instr {
+    Unreachable,
+    Nop,
+    Block {
+        ty: BlockType,
+        end: usize,
+    },
+    Loop {
+        ty: BlockType,
+    },
+    If {
+        ty: BlockType,
+        else_: Option<usize>,
+        end: usize,
+    },
+    Else {
+        end: usize,
+    },
+    End,
+    Br(u32),
+    BrIf(u32),
+    BrTable(Vec<u32>, u32),
+    Return,
+    Call(u32),
+    Drop,
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    Load {
+        op: u8,
+        offset: u32,
+    },
+    Store {
+        op: u8,
+        offset: u32,
+    },
+    MemorySize,
+    MemoryGrow,
+    MemoryCopy,
+    MemoryFill,
+    I32Const(i32),
+    I64Const(i64),
+    /// Any of the integer comparison, arithmetic, or conversion instructions,
+    /// which take their operands from the stack
+    Numeric(u8),
+}
+
+#[derive(Debug)]
+pub(crate) struct Function {
+    pub ty: u32,
+    pub n_locals: usize,
+    pub body: Vec<Instr>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ConstExpr {
+    I32(i32),
+    I64(i64),
+    Global(u32),
+}
+
+#[derive(Debug)]
+pub(crate) struct Global {
+    pub mutable: bool,
+    pub init: ConstExpr,
+}
+
+#[derive(Debug)]
+pub(crate) struct Data {
+    pub offset: ConstExpr,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MemoryType {
+    pub min: u32,
+    pub max: Option<u32>,
+}
+
+/// A parsed (but not yet instantiated) module
+#[derive(Debug)]
+pub struct Module {
+    pub(crate) types: Vec<FuncType>,
+    pub(crate) imports: Vec<Import>,
+    pub(crate) functions: Vec<Function>,
+    pub(crate) memory: Option<MemoryType>,
+    pub(crate) globals: Vec<Global>,
+    pub(crate) exports: Vec<(String, u32)>,
+    pub(crate) start: Option<u32>,
+    pub(crate) data: Vec<Data>,
+}
+
+impl Module {
+    pub fn parse(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader = Reader::new(bytes);
+        if reader.bytes(4)? != MAGIC {
+            return Err(ParseError::BadMagic);
+        }
+
+        let version = u32::from_le_bytes(reader.bytes(4)?.try_into().unwrap());
+        if version != VERSION {
+            return Err(ParseError::UnsupportedVersion(version));
+        }
+
+        let mut module = Module {
+            types: Vec::new(),
+            imports: Vec::new(),
+            functions: Vec::new(),
+            memory: None,
+            globals: Vec::new(),
+            exports: Vec::new(),
+            start: None,
+            data: Vec::new(),
+        };
+
+        let mut function_types = Vec::new();
+        while !reader.is_empty() {
+            let id = reader.u8()?;
+            let len = reader.u32()? as usize;
+            let mut section = Reader::new(reader.bytes(len)?);
+
+            match id {
+                1 => module.types = section.vec(Reader::func_type)?,
+                2 => module.imports = section.vec(Reader::import)?,
+                3 => function_types = section.vec(Reader::u32)?,
+                // Tables and element segments are only used by indirect calls,
+                // which aren't supported, so there's no need to keep them
+                0 | 4 | 9 | 12 => continue,
+                5 => {
+                    let memories = section.vec(Reader::limits)?;
+                    if memories.len() > 1 {
+                        return Err(ParseError::Unsupported("multiple memories"));
+                    }
+
+                    module.memory = memories.first().copied();
+                }
+                6 => module.globals = section.vec(Reader::global)?,
+                7 => {
+                    module.exports = section
+                        .vec(|r| Ok((r.name()?, r.u8()?, r.u32()?)))?
+                        .into_iter()
+                        .filter(|(_, kind, _)| *kind == 0)
+                        .map(|(name, _, index)| (name, index))
+                        .collect()
+                }
+                8 => module.start = Some(section.u32()?),
+                10 => {
+                    let bodies = section.vec(|r| {
+                        let len = r.u32()? as usize;
+                        Ok(Reader::new(r.bytes(len)?))
+                    })?;
+
+                    if bodies.len() != function_types.len() {
+                        return Err(ParseError::Malformed("function and code section lengths differ"));
+                    }
+
+                    for (ty, mut body) in function_types.iter().copied().zip(bodies) {
+                        module.functions.push(body.function(ty)?);
+                    }
+                }
+                11 => module.data = section.vec(Reader::data)?.into_iter().flatten().collect(),
+                _ => return Err(ParseError::Malformed("unknown section")),
+            }
+
+            if !section.is_empty() {
+                return Err(ParseError::Malformed("section has trailing bytes"));
+            }
+        }
+
+        if module.functions.len() != function_types.len() {
+            return Err(ParseError::Malformed("missing code section"));
+        }
+
+        module.validate_indices()?;
+
+        Ok(module)
+    }
+
+    pub fn imports(&self) -> &[Import] {
+        &self.imports
+    }
+
+    pub fn import_type(&self, import: &Import) -> &FuncType {
+        &self.types[import.ty as usize]
+    }
+
+    /// Look up the index of an exported function
+    pub fn export(&self, name: &str) -> Option<u32> {
+        self.exports.iter().find(|(export, _)| export == name).map(|(_, index)| *index)
+    }
+
+    pub(crate) fn n_functions(&self) -> usize {
+        self.imports.len() + self.functions.len()
+    }
+
+    pub(crate) fn function_type(&self, index: u32) -> Option<&FuncType> {
+        let index = index as usize;
+        let ty = match index.checked_sub(self.imports.len()) {
+            None => self.imports[index].ty,
+            Some(index) => self.functions.get(index)?.ty,
+        };
+
+        self.types.get(ty as usize)
+    }
+
+    /// The number of parameters and results of a block
+    pub(crate) fn block_arity(&self, ty: BlockType) -> (usize, usize) {
+        match ty {
+            BlockType::Empty => (0, 0),
+            BlockType::Value => (0, 1),
+            BlockType::Func(index) => {
+                let ty = &self.types[index as usize];
+                (ty.params.len(), ty.results.len())
+            }
+        }
+    }
+
+    /// Check every index that's used to look something up while running, so
+    /// the interpreter doesn't need to
+    fn validate_indices(&self) -> Result<(), ParseError> {
+        let n_types = self.types.len() as u32;
+        let n_functions = self.n_functions() as u32;
+
+        let mut type_indices = self.imports.iter().map(|import| import.ty).chain(self.functions.iter().map(|f| f.ty));
+        if type_indices.any(|ty| ty >= n_types) {
+            return Err(ParseError::Malformed("type index out of bounds"));
+        }
+
+        let mut entry_points = self.exports.iter().map(|(_, index)| *index).chain(self.start);
+        if entry_points.any(|index| index >= n_functions) {
+            return Err(ParseError::Malformed("function index out of bounds"));
+        }
+
+        for function in &self.functions {
+            for instr in &function.body {
+                let valid = match instr {
+                    Instr::Block { ty, .. } | Instr::Loop { ty } | Instr::If { ty, .. } => match ty {
+                        BlockType::Func(index) => *index < n_types,
+                        _ => true,
+                    },
+                    Instr::Call(index) => *index < n_functions,
+                    Instr::LocalGet(index) | Instr::LocalSet(index) | Instr::LocalTee(index) => {
+                        let ty = &self.types[function.ty as usize];
+                        (*index as usize) < ty.params.len() + function.n_locals
+                    }
+                    Instr::GlobalGet(index) => (*index as usize) < self.globals.len(),
+                    Instr::GlobalSet(index) => self.globals.get(*index as usize).map_or(false, |g| g.mutable),
+                    Instr::Load { .. }
+                    | Instr::Store { .. }
+                    | Instr::MemorySize
+                    | Instr::MemoryGrow
+                    | Instr::MemoryCopy
+                    | Instr::MemoryFill => self.memory.is_some(),
+                    _ => true,
+                };
+
+                if !valid {
+                    return Err(ParseError::Malformed("invalid instruction immediate"));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn u8(&mut self) -> Result<u8, ParseError> {
+        let (&byte, rest) = self.bytes.split_first().ok_or(ParseError::UnexpectedEof)?;
+        self.bytes = rest;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ParseError> {
+        if len > self.bytes.len() {
+            return Err(ParseError::UnexpectedEof);
+        }
+
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    /// Unsigned LEB128
+    fn u32(&mut self) -> Result<u32, ParseError> {
+        let mut value = 0u64;
+        for shift in (0..35).step_by(7) {
+            let byte = self.u8()?;
+            value |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return u32::try_from(value).map_err(|_| ParseError::IntegerTooLarge);
+            }
+        }
+
+        Err(ParseError::IntegerTooLarge)
+    }
+
+    /// Signed LEB128 of at most `bits` bits
+    fn signed(&mut self, bits: u32) -> Result<i64, ParseError> {
+        let mut value = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift >= bits {
+                return Err(ParseError::IntegerTooLarge);
+            }
+
+            value |= i64::from(byte & 0x7F) << shift;
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+
+                return Ok(value);
+            }
+        }
+    }
+
+    fn vec<T>(&mut self, mut f: impl FnMut(&mut Self) -> Result<T, ParseError>) -> Result<Vec<T>, ParseError> {
+        let len = self.u32()? as usize;
+        // Every element is at least a byte long, don't let a bogus length make
+        // us allocate more than that
+        let mut items = Vec::with_capacity(len.min(self.bytes.len()));
+        for _ in 0..len {
+            items.push(f(self)?);
+        }
+
+        Ok(items)
+    }
+
+    fn name(&mut self) -> Result<String, ParseError> {
+        let len = self.u32()? as usize;
+        let bytes = self.bytes(len)?;
+        core::str::from_utf8(bytes).map(String::from).map_err(|_| ParseError::InvalidUtf8)
+    }
+
+    fn value_type(&mut self) -> Result<ValueType, ParseError> {
+        match self.u8()? {
+            0x7F => Ok(ValueType::I32),
+            0x7E => Ok(ValueType::I64),
+            0x7D | 0x7C => Err(ParseError::Unsupported("floating point")),
+            _ => Err(ParseError::Unsupported("reference types")),
+        }
+    }
+
+    fn func_type(&mut self) -> Result<FuncType, ParseError> {
+        if self.u8()? != 0x60 {
+            return Err(ParseError::Malformed("expected function type"));
+        }
+
+        Ok(FuncType { params: self.vec(Self::value_type)?, results: self.vec(Self::value_type)? })
+    }
+
+    fn import(&mut self) -> Result<Import, ParseError> {
+        let module = self.name()?;
+        let name = self.name()?;
+        match self.u8()? {
+            0x00 => Ok(Import { module, name, ty: self.u32()? }),
+            _ => Err(ParseError::Unsupported("importing anything other than functions")),
+        }
+    }
+
+    fn limits(&mut self) -> Result<MemoryType, ParseError> {
+        match self.u8()? {
+            0x00 => Ok(MemoryType { min: self.u32()?, max: None }),
+            0x01 => Ok(MemoryType { min: self.u32()?, max: Some(self.u32()?) }),
+            _ => Err(ParseError::Unsupported("shared memory")),
+        }
+    }
+
+    fn const_expr(&mut self) -> Result<ConstExpr, ParseError> {
+        let expr = match self.u8()? {
+            0x41 => ConstExpr::I32(self.signed(32)? as i32),
+            0x42 => ConstExpr::I64(self.signed(64)?),
+            0x23 => ConstExpr::Global(self.u32()?),
+            _ => return Err(ParseError::Unsupported("constant expression")),
+        };
+
+        match self.u8()? {
+            0x0B => Ok(expr),
+            _ => Err(ParseError::Unsupported("constant expression")),
+        }
+    }
+
+    fn global(&mut self) -> Result<Global, ParseError> {
+        self.value_type()?;
+        let mutable = match self.u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(ParseError::Malformed("bad global mutability")),
+        };
+
+        Ok(Global { mutable, init: self.const_expr()? })
+    }
+
+    /// Active data segments, passive segments are only used by `memory.init`
+    /// which isn't supported
+    fn data(&mut self) -> Result<Option<Data>, ParseError> {
+        let offset = match self.u32()? {
+            0 => Some(self.const_expr()?),
+            1 => None,
+            2 => match self.u32()? {
+                0 => Some(self.const_expr()?),
+                _ => return Err(ParseError::Unsupported("multiple memories")),
+            },
+            _ => return Err(ParseError::Malformed("bad data segment")),
+        };
+
+        let len = self.u32()? as usize;
+        let bytes = self.bytes(len)?;
+
+        Ok(offset.map(|offset| Data { offset, bytes: bytes.into() }))
+    }
+
+    fn block_type(&mut self) -> Result<BlockType, ParseError> {
+        match self.bytes.first() {
+            Some(0x40) => {
+                self.u8()?;
+                Ok(BlockType::Empty)
+            }
+            Some(0x7F | 0x7E | 0x7D | 0x7C | 0x70 | 0x6F) => {
+                self.value_type()?;
+                Ok(BlockType::Value)
+            }
+            _ => match self.signed(33)? {
+                index @ 0..=0xFFFF_FFFF => Ok(BlockType::Func(index as u32)),
+                _ => Err(ParseError::Malformed("bad block type")),
+            },
+        }
+    }
+
+    /// Memory instruction immediates, the alignment is only a hint so it's
+    /// ignored
+    fn memarg(&mut self) -> Result<u32, ParseError> {
+        self.u32()?;
+        self.u32()
+    }
+
+    fn function(&mut self, ty: u32) -> Result<Function, ParseError> {
+        let mut n_locals = 0u32;
+        for (count, _) in self.vec(|r| Ok((r.u32()?, r.value_type()?)))? {
+            n_locals = n_locals.saturating_add(count);
+        }
+
+        if n_locals > MAX_LOCALS {
+            return Err(ParseError::Unsupported("too many locals"));
+        }
+
+        let mut body = Vec::new();
+        // Indices of the enclosing `block`, `loop`, or `if`, along with the
+        // `else` of an `if` if it's been seen
+        let mut controls: Vec<(usize, Option<usize>)> = Vec::new();
+
+        loop {
+            let index = body.len();
+            let instr = match self.u8()? {
+                0x00 => Instr::Unreachable,
+                0x01 => Instr::Nop,
+                0x02 => {
+                    controls.push((index, None));
+                    Instr::Block { ty: self.block_type()?, end: 0 }
+                }
+                0x03 => {
+                    controls.push((index, None));
+                    Instr::Loop { ty: self.block_type()? }
+                }
+                0x04 => {
+                    controls.push((index, None));
+                    Instr::If { ty: self.block_type()?, else_: None, end: 0 }
+                }
+                0x05 => match controls.last_mut() {
+                    Some((start, else_ @ None)) if matches!(body[*start], Instr::If { .. }) => {
+                        *else_ = Some(index);
+                        Instr::Else { end: 0 }
+                    }
+                    _ => return Err(ParseError::Malformed("`else` outside of `if`")),
+                },
+                0x0B => match controls.pop() {
+                    Some((start, else_)) => {
+                        match &mut body[start] {
+                            Instr::Block { end, .. } => *end = index,
+                            Instr::If { else_: if_else, end, .. } => {
+                                *if_else = else_;
+                                *end = index;
+                            }
+                            _ => {}
+                        }
+
+                        if let Some(Instr::Else { end }) = else_.map(|else_| &mut body[else_]) {
+                            *end = index;
+                        }
+
+                        Instr::End
+                    }
+                    None => {
+                        body.push(Instr::End);
+                        break;
+                    }
+                },
+                0x0C => Instr::Br(self.u32()?),
+                0x0D => Instr::BrIf(self.u32()?),
+                0x0E => Instr::BrTable(self.vec(Self::u32)?, self.u32()?),
+                0x0F => Instr::Return,
+                0x10 => Instr::Call(self.u32()?),
+                0x11 => return Err(ParseError::Unsupported("indirect calls")),
+                0x1A => Instr::Drop,
+                0x1B => Instr::Select,
+                0x1C => {
+                    self.vec(Self::value_type)?;
+                    Instr::Select
+                }
+                0x20 => Instr::LocalGet(self.u32()?),
+                0x21 => Instr::LocalSet(self.u32()?),
+                0x22 => Instr::LocalTee(self.u32()?),
+                0x23 => Instr::GlobalGet(self.u32()?),
+                0x24 => Instr::GlobalSet(self.u32()?),
+                op @ (0x28 | 0x29 | 0x2C..=0x35) => Instr::Load { op, offset: self.memarg()? },
+                op @ (0x36 | 0x37 | 0x3A..=0x3E) => Instr::Store { op, offset: self.memarg()? },
+                op @ (0x3F | 0x40) => {
+                    if self.u8()? != 0 {
+                        return Err(ParseError::Unsupported("multiple memories"));
+                    }
+
+                    match op {
+                        0x3F => Instr::MemorySize,
+                        _ => Instr::MemoryGrow,
+                    }
+                }
+                0x41 => Instr::I32Const(self.signed(32)? as i32),
+                0x42 => Instr::I64Const(self.signed(64)?),
+                op @ (0x45..=0x5A | 0x67..=0x8A | 0xA7 | 0xAC | 0xAD | 0xC0..=0xC4) => Instr::Numeric(op),
+                0xFC => match self.u32()? {
+                    10 => {
+                        self.bytes(2)?;
+                        Instr::MemoryCopy
+                    }
+                    11 => {
+                        self.u8()?;
+                        Instr::MemoryFill
+                    }
+                    _ => return Err(ParseError::Unsupported("instruction")),
+                },
+                0x2A | 0x2B | 0x38 | 0x39 | 0x43 | 0x44 | 0x5B..=0x66 | 0x8B..=0xBF => {
+                    return Err(ParseError::Unsupported("floating point"))
+                }
+                _ => return Err(ParseError::Unsupported("instruction")),
+            };
+
+            body.push(instr);
+        }
+
+        if !self.is_empty() {
+            return Err(ParseError::Malformed("function body has trailing bytes"));
+        }
+
+        Ok(Function { ty, n_locals: n_locals as usize, body })
+    }
+}
@@ -0,0 +1,118 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use config::{protocol, Change, CHANGED};
+use json_rpc::CallContext;
+use librust::{
+    capabilities::CapabilityPtr,
+    message::KernelNotification,
+    syscalls::{receive_message, ReadMessage},
+};
+use std::{collections::BTreeMap, ipc::IpcChannel};
+
+struct Settings {
+    settings: BTreeMap<String, String>,
+    watchers: Vec<(String, CapabilityPtr)>,
+}
+
+impl Settings {
+    fn new() -> Self {
+        Self { settings: BTreeMap::new(), watchers: Vec::new() }
+    }
+
+    /// Load settings from `key=value` pairs separated by `separator`, keys
+    /// without a value are stored as an empty string
+    fn load(&mut self, source: &str, separator: char) {
+        for entry in source.split(separator).map(str::trim) {
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = entry.splitn(2, '=');
+            let key = parts.next().unwrap().trim();
+            let value = parts.next().map(str::trim).unwrap_or_default();
+
+            self.settings.insert(key.into(), value.into());
+        }
+    }
+
+    fn notify(&self, key: &str, value: Option<&str>) {
+        for (_, cptr) in self.watchers.iter().filter(|(prefix, _)| key.starts_with(&**prefix)) {
+            let change = Change { key: key.into(), value: value.map(Into::into) };
+            if let Err(e) = json_rpc::notify(&mut IpcChannel::new(*cptr), CHANGED, change) {
+                println!("[configmgr] Failed to notify watcher: {:?}", e);
+            }
+        }
+    }
+}
+
+impl protocol::Server for Settings {
+    fn get(&mut self, _: &mut CallContext, key: String) -> Option<String> {
+        self.settings.get(&key).cloned()
+    }
+
+    fn set(&mut self, _: &mut CallContext, key: String, value: String) {
+        self.notify(&key, Some(&value));
+        self.settings.insert(key, value);
+    }
+
+    fn remove(&mut self, _: &mut CallContext, key: String) -> Option<String> {
+        let old = self.settings.remove(&key);
+
+        if old.is_some() {
+            self.notify(&key, None);
+        }
+
+        old
+    }
+
+    fn list(&mut self, _: &mut CallContext, prefix: String) -> Vec<String> {
+        self.settings.keys().filter(|key| key.starts_with(&*prefix)).cloned().collect()
+    }
+
+    fn watch(&mut self, cx: &mut CallContext, prefix: String) {
+        self.watchers.push((prefix, cx.channel()));
+    }
+}
+
+fn main() {
+    let mut settings = Settings::new();
+
+    // `init` sends the contents of the initfs config file (if any) right after
+    // spawning us
+    let parent = IpcChannel::new(std::env::lookup_capability("parent").unwrap());
+    if let Ok((message, _)) = parent.read_with_all_caps() {
+        let _ = receive_message();
+        match core::str::from_utf8(message.as_bytes()) {
+            Ok(file) => settings.load(file, '\n'),
+            Err(_) => println!("[configmgr] Config file isn't valid UTF-8, ignoring"),
+        }
+    }
+
+    // The kernel command line takes priority over the config file
+    let fdt = std::env::device_tree().and_then(|ptr| unsafe { fdt::Fdt::from_ptr(ptr) }.ok());
+    if let Some(bootargs) = fdt.as_ref().and_then(|fdt| fdt.chosen().bootargs()) {
+        settings.load(bootargs, ' ');
+    }
+
+    loop {
+        let cptr = match receive_message() {
+            ReadMessage::Kernel(KernelNotification::NewChannelMessage(cptr)) if cptr.value() != 0 => cptr,
+            _ => continue,
+        };
+
+        let mut channel = IpcChannel::new(cptr);
+        let (message, caps) = match channel.read_with_all_caps() {
+            Ok(read) => read,
+            Err(_) => continue,
+        };
+
+        if let Err(e) = protocol::dispatch(&mut settings, &mut channel, message.as_bytes(), caps) {
+            println!("[configmgr] Error handling request: {:?}", e);
+        }
+    }
+}
@@ -5,13 +5,17 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+use driver_bundle::{Bundle, BundleError};
 use librust::{
     capabilities::{Capability, CapabilityRights},
-    message::KernelNotification,
+    message::{KernelNotification, SyscallResult},
     syscalls::ReadMessage,
 };
 use std::ipc::IpcChannel;
 
+/// Most DMA memory a driver bundle can declare it uses
+const MAX_DMA_BYTES: usize = 16 * 1024 * 1024;
+
 json::derive! {
     Serialize,
     struct Device {
@@ -37,7 +41,7 @@ json::derive! {
 
 fn main() {
     let args = std::env::args();
-    let ptr = std::env::a2() as *const u8;
+    let ptr = std::env::device_tree().expect("no device tree capability");
     let fdt = unsafe { fdt::Fdt::from_ptr(ptr) }.unwrap();
 
     if args.contains(&"debug") {
@@ -68,7 +72,25 @@ fn main() {
         };
 
         let mut channel = IpcChannel::new(cptr);
-        let (message, _) = channel.read_with_all_caps().unwrap();
+        let (message, caps) = channel.read_with_all_caps().unwrap();
+
+        // Driver bundles are sent as they are, and are told apart from device
+        // requests by being a tar archive
+        match Bundle::parse(message.as_bytes()) {
+            Err(BundleError::NotAnArchive) => {}
+            Ok(bundle) => {
+                let name = bundle.manifest.name.clone();
+                if let Err(e) = load_bundle(&fdt, bundle, &caps) {
+                    println!("[devicemgr] Refusing to load driver `{}`: {}", name, e);
+                }
+                continue;
+            }
+            Err(e) => {
+                println!("[devicemgr] Invalid driver bundle: {:?}", e);
+                continue;
+            }
+        }
+
         let compatible = json::deserialize::<WantedCompatible>(message.as_bytes()).unwrap().compatible;
 
         let all_compatible = fdt
@@ -106,7 +128,11 @@ fn main() {
                     let cptr = librust::syscalls::io::claim_device(device.name).unwrap();
                     caps.push(Capability::new(
                         cptr,
-                        CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::GRANT,
+                        CapabilityRights::READ
+                            | CapabilityRights::WRITE
+                            | CapabilityRights::GRANT
+                            | CapabilityRights::TRANSFER
+                            | CapabilityRights::MAP,
                     ));
                 }
 
@@ -116,3 +142,76 @@ fn main() {
         }
     }
 }
+
+/// Check a driver bundle's manifest against policy, then spawn the driver with
+/// the devices and services it declares and nothing else. `services` are the
+/// channels to each of the services the manifest lists, in the same order.
+fn load_bundle(fdt: &fdt::Fdt<'_>, bundle: Bundle<'_>, services: &[Capability]) -> Result<(), String> {
+    let manifest = &bundle.manifest;
+
+    let dma_bytes = manifest.dma_bytes.unwrap_or(0);
+    if dma_bytes > MAX_DMA_BYTES {
+        return Err(format!("asks for {} bytes of DMA memory, more than the {} allowed", dma_bytes, MAX_DMA_BYTES));
+    }
+
+    // Whoever sent the bundle leaves out services that don't exist
+    if services.len() != manifest.services().len() {
+        return Err(format!("wants {} services but was given {}", manifest.services().len(), services.len()));
+    }
+
+    let mut devices = Vec::new();
+    for request in &manifest.devices {
+        let matching = fdt
+            .all_nodes()
+            .filter(|node| node.compatible().map(|c| request.matches(c.all())).unwrap_or(false))
+            .take(request.max.unwrap_or(usize::MAX));
+
+        let n_devices = devices.len();
+        for node in matching {
+            let has_interrupts = node.interrupts().map(|mut ints| ints.next().is_some()).unwrap_or(false);
+            if has_interrupts && !request.interrupts() {
+                return Err(format!("didn't declare it handles interrupts, which {} has", node.name));
+            }
+
+            devices.push((node.name, has_interrupts));
+        }
+
+        if devices.len() == n_devices {
+            return Err(format!("no devices are compatible with {:?}", request.compatible));
+        }
+    }
+
+    let elf = loadelf::Elf::new(bundle.binary).ok_or_else(|| String::from("binary isn't an ELF file"))?;
+    let (mut space, mut env) =
+        loadelf::load_elf(&manifest.name, &elf).map_err(|_| String::from("binary couldn't be loaded"))?;
+
+    for &(device, has_interrupts) in &devices {
+        let cptr = match librust::syscalls::io::claim_device(device) {
+            SyscallResult::Ok(cptr) => cptr,
+            SyscallResult::Err(e) => return Err(format!("couldn't claim {}: {:?}", device, e)),
+        };
+
+        // Without `TRANSFER` or `GRANT` the driver can't hand its devices to
+        // anyone else
+        space.grant(device, cptr, CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::MAP);
+        match has_interrupts {
+            true => println!("[devicemgr] {}: granted {} and its interrupts", manifest.name, device),
+            false => println!("[devicemgr] {}: granted {}", manifest.name, device),
+        }
+    }
+
+    for (name, service) in manifest.services().iter().zip(services) {
+        space.grant(name, service.cptr, CapabilityRights::READ | CapabilityRights::WRITE);
+        println!("[devicemgr] {}: granted service {}", manifest.name, name);
+    }
+
+    if dma_bytes != 0 {
+        println!("[devicemgr] {}: declared {} bytes of DMA memory", manifest.name, dma_bytes);
+    }
+
+    env.a0 = 0;
+    env.a1 = 0;
+    space.spawn(env).map_err(|e| format!("couldn't be spawned: {:?}", e))?;
+
+    Ok(())
+}
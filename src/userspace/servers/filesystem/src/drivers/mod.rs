@@ -5,4 +5,40 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod usb;
 pub mod virtio;
+
+use ::virtio::devices::block::CommandError;
+
+#[derive(Debug, Clone, Copy)]
+pub enum OperationResult {
+    Read([u8; 512]),
+    Write,
+    Flush,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Error {
+    CommandError(CommandError),
+    /// `usbmgr` couldn't read or write the block
+    UsbRequestFailed,
+    NoCommandCompletion,
+}
+
+impl From<CommandError> for Error {
+    fn from(e: CommandError) -> Self {
+        Self::CommandError(e)
+    }
+}
+
+/// A block device whose commands complete asynchronously, signaled by an
+/// interrupt
+pub trait BlockDriver {
+    fn queue_read(&mut self, sector: u64);
+    fn queue_write(&mut self, sector: u64, data: &[u8]);
+    /// Write back anything the device is caching
+    fn queue_flush(&mut self);
+    /// Retrieve the result of a completed command, or
+    /// [`Error::NoCommandCompletion`] if none have completed since the last call
+    fn finish_command(&mut self) -> Result<OperationResult, Error>;
+}
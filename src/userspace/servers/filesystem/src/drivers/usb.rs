@@ -0,0 +1,57 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::{BlockDriver, Error, OperationResult};
+use usb::Usbmgr;
+
+/// A USB mass storage device, owned by `usbmgr`. Requests to it are
+/// synchronous, so commands have already completed by the time they're
+/// finished.
+pub struct BlockDevice {
+    usbmgr: Usbmgr,
+    device: u32,
+    completed: Option<Result<OperationResult, Error>>,
+}
+
+impl BlockDevice {
+    pub fn new(usbmgr: Usbmgr, device: u32) -> Self {
+        Self { usbmgr, device, completed: None }
+    }
+}
+
+impl BlockDriver for BlockDevice {
+    fn queue_read(&mut self, sector: u64) {
+        let result = match self.usbmgr.read_block(self.device, sector) {
+            Ok(Some(data)) if data.len() == 512 => {
+                let mut buffer = [0; 512];
+                buffer.copy_from_slice(&data);
+                Ok(OperationResult::Read(buffer))
+            }
+            _ => Err(Error::UsbRequestFailed),
+        };
+
+        self.completed = Some(result);
+    }
+
+    fn queue_write(&mut self, sector: u64, data: &[u8]) {
+        let result = match self.usbmgr.write_block(self.device, sector, &data[..data.len().min(512)]) {
+            Ok(true) => Ok(OperationResult::Write),
+            _ => Err(Error::UsbRequestFailed),
+        };
+
+        self.completed = Some(result);
+    }
+
+    // `usbmgr` finishes each write before replying, so there's nothing cached
+    fn queue_flush(&mut self) {
+        self.completed = Some(Ok(OperationResult::Flush));
+    }
+
+    fn finish_command(&mut self) -> Result<OperationResult, Error> {
+        self.completed.take().unwrap_or(Err(Error::NoCommandCompletion))
+    }
+}
@@ -5,9 +5,10 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+use super::{BlockDriver, Error, OperationResult};
 use librust::mem::{DmaElement, DmaRegion, PhysicalAddress};
 use std::collections::BTreeMap;
-use virtio::devices::block::{Command, CommandError, CommandKind, CommandStatus};
+use virtio::devices::block::{BlockDeviceFeatures, Command, CommandKind, CommandStatus};
 use virtio::{
     devices::block::VirtIoBlockDevice,
     splitqueue::{DescriptorFlags, SplitVirtqueue, SplitqueueIndex, VirtqueueDescriptor},
@@ -18,24 +19,7 @@ use virtio::{
 pub enum OperationRequest<'a> {
     Read { sector: u64 },
     Write { sector: u64, data: &'a [u8] },
-}
-
-#[derive(Debug, Clone, Copy)]
-pub enum OperationResult {
-    Read([u8; 512]),
-    Write,
-}
-
-#[derive(Debug, Clone, Copy)]
-pub enum Error {
-    CommandError(CommandError),
-    NoCommandCompletion,
-}
-
-impl From<CommandError> for Error {
-    fn from(e: CommandError) -> Self {
-        Self::CommandError(e)
-    }
+    Flush,
 }
 
 pub struct BlockDevice {
@@ -44,7 +28,12 @@ pub struct BlockDevice {
     queue: SplitVirtqueue,
     command_buffer: CommandBuffer,
     data_buffer: DataBuffer,
-    issued_commands: BTreeMap<SplitqueueIndex<VirtqueueDescriptor>, (usize, usize)>,
+    issued_commands: BTreeMap<SplitqueueIndex<VirtqueueDescriptor>, (usize, Option<usize>)>,
+    /// Whether the device has a write cache that needs flushing, devices
+    /// without [`BlockDeviceFeatures::FLUSH`] write through
+    write_cache: bool,
+    /// A flush that completed without going to the device
+    completed_flush: bool,
 }
 
 impl BlockDevice {
@@ -53,90 +42,103 @@ impl BlockDevice {
         let command_buffer = CommandBuffer::new(512);
         let data_buffer = DataBuffer::new(512);
 
-        device.header.status.reset();
+        device.header.status().reset();
 
-        device.header.status.set_flag(StatusFlag::Acknowledge);
-        device.header.status.set_flag(StatusFlag::Driver);
+        device.header.status().set_flag(StatusFlag::Acknowledge);
+        device.header.status().set_flag(StatusFlag::Driver);
 
-        // TODO: maybe use feature bits at some point
-        let _ = device.header.features();
+        device.header.driver_features_select().write(0);
+        device.header.device_features_select().write(0);
 
-        device.header.driver_features_select.write(0);
-        device.header.device_features_select.write(0);
+        let mut selected = BlockDeviceFeatures::none();
+        let write_cache = BlockDeviceFeatures::new(device.header.features()) & BlockDeviceFeatures::FLUSH;
+        if write_cache {
+            selected |= BlockDeviceFeatures::FLUSH;
+        }
 
-        device.header.driver_features.write(0);
+        device.header.driver_features().write(selected.bits());
 
-        device.header.status.set_flag(StatusFlag::FeaturesOk);
+        device.header.status().set_flag(StatusFlag::FeaturesOk);
 
-        if !device.header.status.is_set(StatusFlag::FeaturesOk) {
+        if !device.header.status().is_set(StatusFlag::FeaturesOk) {
             return Err(VirtIoDeviceError::FeaturesNotRecognized);
         }
 
-        device.header.queue_select.write(0);
-        device.header.queue_size.write(queue.queue_size());
-        device.header.queue_descriptor.set(queue.descriptors.physical_address());
-        device.header.queue_available.set(queue.available.physical_address());
-        device.header.queue_used.set(queue.used.physical_address());
+        device.header.queue_select().write(0);
+        device.header.queue_size().write(queue.queue_size());
+        device.header.queue_descriptor().set(queue.descriptors.physical_address());
+        device.header.queue_available().set(queue.available.physical_address());
+        device.header.queue_used().set(queue.used.physical_address());
 
-        device.header.queue_ready.ready();
+        device.header.queue_ready().ready();
 
-        device.header.status.set_flag(StatusFlag::DriverOk);
+        device.header.status().set_flag(StatusFlag::DriverOk);
 
-        if device.header.status.failed() {
+        if device.header.status().failed() {
             return Err(VirtIoDeviceError::DeviceError);
         }
 
-        Ok(Self { device, queue, command_buffer, data_buffer, issued_commands: BTreeMap::new() })
+        Ok(Self {
+            device,
+            queue,
+            command_buffer,
+            data_buffer,
+            issued_commands: BTreeMap::new(),
+            write_cache,
+            completed_flush: false,
+        })
     }
 
     fn queue_command(&mut self, operation: OperationRequest<'_>) {
         let (command_index, mut request) = self.command_buffer.alloc().unwrap();
-        let (data_index, mut buffer) = self.data_buffer.alloc().unwrap();
-        let (sector, descriptor_flag, length) = match operation {
-            OperationRequest::Read { sector } => (sector, DescriptorFlags::NEXT | DescriptorFlags::WRITE, 512),
-            OperationRequest::Write { sector, data } => (sector, DescriptorFlags::NEXT, data.len().min(512)),
+        let (sector, kind) = match operation {
+            OperationRequest::Read { sector } => (sector, CommandKind::Read),
+            OperationRequest::Write { sector, .. } => (sector, CommandKind::Write),
+            OperationRequest::Flush => (0, CommandKind::Flush),
         };
 
-        *request.get_mut() = Command {
-            kind: match operation {
-                OperationRequest::Read { .. } => CommandKind::Read,
-                OperationRequest::Write { .. } => CommandKind::Write,
-            },
-            _reserved: 0,
-            sector,
-            status: 0,
-        };
-
-        if let OperationRequest::Write { data, .. } = operation {
-            buffer.get_mut()[..length].copy_from_slice(&data[..length]);
-        }
+        *request.get_mut() = Command { kind, _reserved: 0, sector, status: 0 };
 
         let desc1 = self.queue.alloc_descriptor().unwrap();
-        let desc2 = self.queue.alloc_descriptor().unwrap();
-        let desc3 = self.queue.alloc_descriptor().unwrap();
+        let status = self.queue.alloc_descriptor().unwrap();
+
+        // Flushes have no data, so the status descriptor follows the command
+        // directly
+        let mut next = status;
+        let mut data_index = None;
+        if let OperationRequest::Read { .. } | OperationRequest::Write { .. } = operation {
+            let (index, mut buffer) = self.data_buffer.alloc().unwrap();
+            let (descriptor_flag, length) = match operation {
+                OperationRequest::Write { data, .. } => {
+                    let length = data.len().min(512);
+                    buffer.get_mut()[..length].copy_from_slice(&data[..length]);
+                    (DescriptorFlags::NEXT, length)
+                }
+                _ => (DescriptorFlags::NEXT | DescriptorFlags::WRITE, 512),
+            };
+
+            let desc2 = self.queue.alloc_descriptor().unwrap();
+            self.queue.descriptors.write(
+                desc2,
+                VirtqueueDescriptor {
+                    address: buffer.physical_address(),
+                    length: length as u32,
+                    flags: descriptor_flag,
+                    next: status,
+                },
+            );
+
+            next = desc2;
+            data_index = Some(index);
+        }
 
         self.queue.descriptors.write(
             desc1,
-            VirtqueueDescriptor {
-                address: request.physical_address(),
-                length: 16,
-                flags: DescriptorFlags::NEXT,
-                next: desc2,
-            },
+            VirtqueueDescriptor { address: request.physical_address(), length: 16, flags: DescriptorFlags::NEXT, next },
         );
 
         self.queue.descriptors.write(
-            desc2,
-            VirtqueueDescriptor {
-                address: buffer.physical_address(),
-                length: length as u32,
-                flags: descriptor_flag,
-                next: desc3,
-            },
-        );
-
-        self.queue.descriptors.write(
-            desc3,
+            status,
             VirtqueueDescriptor {
                 address: PhysicalAddress::new(request.physical_address().as_usize() + 16),
                 length: 1,
@@ -153,46 +155,65 @@ impl BlockDevice {
         // same order relative to RAM read/writes
         librust::mem::fence(librust::mem::FenceMode::Write);
 
-        self.device.header.queue_notify.notify(0);
+        self.device.header.queue_notify().notify(0);
     }
+}
 
-    pub fn queue_read(&mut self, sector: u64) {
+impl BlockDriver for BlockDevice {
+    fn queue_read(&mut self, sector: u64) {
         self.queue_command(OperationRequest::Read { sector });
     }
 
-    pub fn queue_write(&mut self, sector: u64, data: &[u8]) {
+    fn queue_write(&mut self, sector: u64, data: &[u8]) {
         self.queue_command(OperationRequest::Write { sector, data });
     }
 
-    pub fn finish_command(&mut self) -> Result<OperationResult, Error> {
+    fn queue_flush(&mut self) {
+        match self.write_cache {
+            true => self.queue_command(OperationRequest::Flush),
+            false => self.completed_flush = true,
+        }
+    }
+
+    fn finish_command(&mut self) -> Result<OperationResult, Error> {
+        if core::mem::take(&mut self.completed_flush) {
+            return Ok(OperationResult::Flush);
+        }
+
         let desc1 = SplitqueueIndex::new(self.queue.used.pop().ok_or(Error::NoCommandCompletion)?.start_index as u16);
-        let desc2 = self.queue.descriptors.read(desc1).next;
-        let desc3 = self.queue.descriptors.read(desc2).next;
 
         librust::mem::fence(librust::mem::FenceMode::Full);
-        self.device.header.interrupt_ack.acknowledge_buffer_used();
+        self.device.header.interrupt_ack().acknowledge_buffer_used();
+
+        let mut descriptor = desc1;
+        loop {
+            let VirtqueueDescriptor { flags, next, .. } = self.queue.descriptors.read(descriptor);
+            self.queue.free_descriptor(descriptor);
+
+            match flags & DescriptorFlags::NEXT {
+                true => descriptor = next,
+                false => break,
+            }
+        }
 
         let (command_idx, data_idx) = self.issued_commands.remove(&desc1).unwrap();
-        let command = self.command_buffer.get(command_idx).unwrap();
-        let data = self.data_buffer.get(data_idx).unwrap();
+        let command = *self.command_buffer.get(command_idx).unwrap().get();
+        self.command_buffer.dealloc(command_idx);
 
-        self.queue.free_descriptor(desc1);
-        self.queue.free_descriptor(desc2);
-        self.queue.free_descriptor(desc3);
+        let data = data_idx.map(|index| {
+            let data = *self.data_buffer.get(index).unwrap().get();
+            self.data_buffer.dealloc(index);
+            data
+        });
 
-        let command = command.get();
         CommandStatus::from_u8(command.status).unwrap().into_result()?;
 
-        let ret = match command.kind {
-            CommandKind::Read => Ok(OperationResult::Read(*data.get())),
+        match command.kind {
+            CommandKind::Read => Ok(OperationResult::Read(data.unwrap())),
             CommandKind::Write => Ok(OperationResult::Write),
+            CommandKind::Flush => Ok(OperationResult::Flush),
             _ => todo!(),
-        };
-
-        self.command_buffer.dealloc(command_idx);
-        self.data_buffer.dealloc(data_idx);
-
-        ret
+        }
     }
 }
 
@@ -260,7 +281,7 @@ impl DataBuffer {
 //     fn isr(_: usize, _: usize) -> Result<(), &'static str> {
 //         let mut this = crate::BLOCK_DEV.lock();
 //         let this = this.as_mut().unwrap();
-//         this.device.header.interrupt_ack.acknowledge_buffer_used();
+//         this.device.header.interrupt_ack().acknowledge_buffer_used();
 //
 //         let desc1 = this.queue.used.ring[this.queue.used.index as usize].start_index as usize;
 //         let desc2 = this.queue.descriptors[desc1].next as usize;
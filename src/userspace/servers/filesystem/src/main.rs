@@ -7,10 +7,17 @@
 
 mod drivers;
 
+use drivers::{BlockDriver, OperationResult};
+use fat32::{
+    journal::{Journal, JournalError},
+    Fat32, SECTOR_SIZE,
+};
+use fs::{protocol, CheckReport};
+use json_rpc::CallContext;
 use librust::{
     capabilities::{Capability, CapabilityPtr},
     message::KernelNotification,
-    syscalls::ReadMessage,
+    syscalls::{receive_message, ReadMessage},
 };
 use std::ipc::IpcChannel;
 
@@ -38,11 +45,152 @@ json::derive! {
 }
 
 struct BlockDevice {
+    /// `None` for devices which are reached through another server
     #[allow(dead_code)]
-    mmio_cap: CapabilityPtr,
+    mmio_cap: Option<CapabilityPtr>,
     #[allow(dead_code)]
     interrupts: Vec<usize>,
-    device: drivers::virtio::BlockDevice,
+    device: Box<dyn BlockDriver>,
+    /// Channels which were sent a message while waiting on the device
+    deferred: VecDeque<CapabilityPtr>,
+    /// A sync was requested while waiting on the device, which is done once
+    /// the current request has been handled
+    sync_requested: bool,
+}
+
+impl BlockDevice {
+    fn wait(&mut self) -> Result<OperationResult, drivers::Error> {
+        // Some drivers finish commands without an interrupt
+        match self.device.finish_command() {
+            Err(drivers::Error::NoCommandCompletion) => {}
+            result => return result,
+        }
+
+        loop {
+            match receive_message() {
+                ReadMessage::Kernel(KernelNotification::InterruptOccurred(id)) => {
+                    let result = self.device.finish_command();
+                    let _ = librust::syscalls::io::complete_interrupt(id);
+
+                    match result {
+                        Err(drivers::Error::NoCommandCompletion) => continue,
+                        result => return result,
+                    }
+                }
+                ReadMessage::Kernel(KernelNotification::NewChannelMessage(cptr)) => self.deferred.push_back(cptr),
+                ReadMessage::Kernel(KernelNotification::SyncRequested) => self.sync_requested = true,
+                _ => continue,
+            }
+        }
+    }
+}
+
+impl fat32::BlockDevice for BlockDevice {
+    type Error = drivers::Error;
+
+    fn read_sector(&mut self, sector: u64, buffer: &mut [u8; SECTOR_SIZE]) -> Result<(), Self::Error> {
+        self.device.queue_read(sector);
+        if let OperationResult::Read(data) = self.wait()? {
+            *buffer = data;
+        }
+
+        Ok(())
+    }
+
+    fn write_sector(&mut self, sector: u64, buffer: &[u8; SECTOR_SIZE]) -> Result<(), Self::Error> {
+        self.device.queue_write(sector, buffer);
+        self.wait().map(drop)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.device.queue_flush();
+        self.wait().map(drop)
+    }
+}
+
+/// Sectors reserved at the start of the volume which hold the journal, FAT32
+/// only uses the first few of its reserved sectors for the boot sector, FSInfo,
+/// and their backups
+const JOURNAL_SECTORS: core::ops::Range<u64> = 16..32;
+
+type Volume = Fat32<Journal<BlockDevice>>;
+type VolumeError = fat32::Error<JournalError<drivers::Error>>;
+
+/// Find the FAT32 volume on the device, which is either the whole device or
+/// the first FAT32 partition in its MBR, replaying its journal if the last
+/// metadata update didn't finish
+fn mount(mut device: BlockDevice) -> Result<Volume, VolumeError> {
+    let mut mbr = [0; SECTOR_SIZE];
+    fat32::BlockDevice::read_sector(&mut device, 0, &mut mbr)
+        .map_err(|e| fat32::Error::Device(JournalError::Device(e)))?;
+
+    let partition = mbr[0x1BE..0x1FE].chunks_exact(16).find(|entry| matches!(entry[4], 0x0B | 0x0C));
+    let start_sector = match partition {
+        _ if &mbr[0x52..0x5A] == b"FAT32   " => 0,
+        Some(entry) => u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as u64,
+        None => 0,
+    };
+
+    let mut boot_sector = [0; SECTOR_SIZE];
+    fat32::BlockDevice::read_sector(&mut device, start_sector, &mut boot_sector)
+        .map_err(|e| fat32::Error::Device(JournalError::Device(e)))?;
+
+    // Volumes without room for the log get an empty one, which disables
+    // journaling
+    let reserved_sectors = u16::from_le_bytes([boot_sector[0x0E], boot_sector[0x0F]]) as u64;
+    let log = match reserved_sectors >= JOURNAL_SECTORS.end {
+        true => JOURNAL_SECTORS,
+        false => 0..0,
+    };
+
+    let journal = Journal::open(device, start_sector + log.start, log.end - log.start).map_err(fat32::Error::Device)?;
+    Fat32::new(journal, start_sector)
+}
+
+struct Server {
+    volume: Volume,
+}
+
+impl Server {
+    fn check(&mut self, repair: bool) -> CheckReport {
+        let was_clean = self.volume.is_clean().unwrap_or(false);
+        let report = self.volume.check(repair).and_then(|report| match repair {
+            true => self.volume.mark_clean().map(|_| report),
+            false => Ok(report),
+        });
+
+        match report {
+            Ok(report) => CheckReport {
+                was_clean,
+                problems: report.problems.iter().map(ToString::to_string).collect(),
+                files: report.files,
+                directories: report.directories,
+                used_clusters: report.used_clusters,
+                repaired: report.repaired,
+            },
+            Err(e) => CheckReport {
+                was_clean,
+                problems: vec![format!("check failed: {:?}", e)],
+                files: 0,
+                directories: 0,
+                used_clusters: 0,
+                repaired: false,
+            },
+        }
+    }
+
+    fn sync(&mut self) {
+        match self.volume.sync() {
+            Ok(()) => println!("[filesystem] Synced the volume"),
+            Err(e) => println!("[filesystem] Failed to sync the volume: {:?}", e),
+        }
+    }
+}
+
+impl protocol::Server for Server {
+    fn check(&mut self, _: &mut CallContext, repair: bool) -> CheckReport {
+        Server::check(self, repair)
+    }
 }
 
 fn main() {
@@ -56,52 +204,108 @@ fn main() {
     let (message, capabilities) = virtiomgr.read_with_all_caps().unwrap();
     let response: VirtIoDeviceResponse = json::deserialize(message.as_bytes()).unwrap();
 
-    if response.devices.is_empty() {
-        return;
-    }
-
     for (Capability { cptr: mmio_cap, .. }, device) in capabilities.into_iter().zip(response.devices) {
         let info = librust::syscalls::io::query_mmio_cap(mmio_cap).unwrap();
 
         // println!("[filesystem] Got a VirtIO block device!");
 
         block_devices.push(BlockDevice {
-            mmio_cap,
+            mmio_cap: Some(mmio_cap),
             interrupts: device.interrupts,
-            device: drivers::virtio::BlockDevice::new(unsafe {
-                &*(info.address() as *const virtio::devices::block::VirtIoBlockDevice)
-            })
-            .unwrap(),
+            device: Box::new(
+                drivers::virtio::BlockDevice::new(unsafe {
+                    &*(info.address() as *const virtio::devices::block::VirtIoBlockDevice)
+                })
+                .unwrap(),
+            ),
+            deferred: VecDeque::new(),
+            sync_requested: false,
         });
     }
 
-    let drv = &mut block_devices[0].device;
-
-    drv.queue_read(0);
-
-    let id = loop {
-        match librust::syscalls::receive_message() {
-            ReadMessage::Kernel(KernelNotification::InterruptOccurred(id)) => {
-                break id;
+    // Fall back to USB mass storage when there's no VirtIO block device
+    if block_devices.is_empty() {
+        if let Some(mut usbmgr) = usb::Usbmgr::connect() {
+            if let Some(device) = usbmgr.block_devices().ok().and_then(|devices| devices.into_iter().next()) {
+                println!("[filesystem] Using USB device {} {}", device.vendor, device.product);
+                block_devices.push(BlockDevice {
+                    mmio_cap: None,
+                    interrupts: Vec::new(),
+                    device: Box::new(drivers::usb::BlockDevice::new(usbmgr, device.id)),
+                    deferred: VecDeque::new(),
+                    sync_requested: false,
+                });
             }
-            _ => continue,
         }
+    }
+
+    if block_devices.is_empty() {
+        return;
+    }
+
+    let volume = match mount(block_devices.remove(0)) {
+        Ok(volume) => volume,
+        Err(e) => return println!("[filesystem] No FAT32 volume found: {:?}", e),
     };
 
-    println!("[filesystem] Sector 0 = {:?}", drv.finish_command());
-    librust::syscalls::io::complete_interrupt(id).unwrap();
+    let mut server = Server { volume };
+
+    // The volume is marked dirty while it's being written to, so it not being
+    // clean means the last boot didn't finish writing (e.g. QEMU was killed)
+    if !server.volume.is_clean().unwrap_or(true) {
+        println!("[filesystem] Volume wasn't unmounted cleanly, checking it");
+        let report = server.check(true);
+        for problem in &report.problems {
+            println!("[filesystem] {}", problem);
+        }
+
+        println!("[filesystem] Check finished with {} problem(s)", report.problems.len());
+    }
 
-    drv.queue_write(0, &[1; 512][..]);
+    let mut init = lifecycle::Init::connect();
 
-    let id = loop {
-        match librust::syscalls::receive_message() {
-            ReadMessage::Kernel(KernelNotification::InterruptOccurred(id)) => {
-                break id;
+    loop {
+        if core::mem::take(&mut server.volume.device().device().sync_requested) {
+            server.sync();
+        }
+
+        let cptr = match server.volume.device().device().deferred.pop_front() {
+            Some(cptr) => cptr,
+            None => match receive_message() {
+                ReadMessage::Kernel(KernelNotification::NewChannelMessage(cptr)) => cptr,
+                // Requested from the kernel console with `Ctrl-]` `s`
+                ReadMessage::Kernel(KernelNotification::SyncRequested) => {
+                    server.sync();
+                    continue;
+                }
+                _ => continue,
+            },
+        };
+
+        if let Some(init) = init.as_mut().filter(|init| init.cptr() == cptr) {
+            if let Ok(true) = init.read_shutdown() {
+                break;
             }
-            _ => continue,
+
+            continue;
         }
-    };
 
-    println!("[filesystem] Sector 0 = {:?}", drv.finish_command());
-    librust::syscalls::io::complete_interrupt(id).unwrap();
+        let mut channel = IpcChannel::new(cptr);
+        let (message, caps) = match channel.read_with_all_caps() {
+            Ok(read) => read,
+            Err(_) => continue,
+        };
+
+        if let Err(e) = protocol::dispatch(&mut server, &mut channel, message.as_bytes(), caps) {
+            println!("[filesystem] Error handling request: {:?}", e);
+        }
+    }
+
+    if let Err(e) = server.volume.unmount() {
+        println!("[filesystem] Failed to unmount the volume: {:?}", e);
+    }
+
+    if let Some(mut init) = init {
+        let _ = init.stopped();
+    }
 }
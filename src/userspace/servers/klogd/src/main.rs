@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Streams the kernel log over UDP to the host side of QEMU's user network,
+//! e.g. `nc -ul 5140` to watch it
+
+use librust::{
+    message::SyscallResult,
+    syscalls::{
+        klog::map_kernel_log,
+        poll::{wait_many, WaitEvents, WaitItem, NO_TIMEOUT},
+    },
+};
+use std::ipc::IpcChannel;
+
+const PORT: u16 = 5140;
+const DESTINATION: &str = "10.0.2.2";
+/// Keep each datagram within a single ethernet frame
+const MAX_DATAGRAM: usize = 1400;
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    struct BindRequest {
+        port: u16,
+        port_type: String,
+    }
+}
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    struct BindResponse {
+        msg: String,
+        port: Option<u16>,
+    }
+}
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    struct SendRequest {
+        // FIXME: this should be an IpV4Socket
+        to_ip: String,
+        to_port: u16,
+        data: Vec<u8>,
+    }
+}
+
+fn main() {
+    let klog_cap = std::env::lookup_capability("klog").unwrap();
+    let mut klog = match map_kernel_log(klog_cap) {
+        SyscallResult::Ok(klog) => klog,
+        SyscallResult::Err(e) => {
+            println!("[klogd] Failed to map the kernel log: {:?}", e);
+            return;
+        }
+    };
+
+    let mut network = IpcChannel::new(std::env::lookup_capability("network").unwrap());
+    network.send_bytes(&json::to_bytes(&BindRequest { port: PORT, port_type: String::from("udp") }), &[]).unwrap();
+    let bind_response: BindResponse = json::deserialize(network.read(&mut []).unwrap().message.as_bytes()).unwrap();
+    if bind_response.port.is_none() {
+        println!("[klogd] Couldn't bind to port {}: {}", PORT, bind_response.msg);
+        return;
+    }
+
+    let mut buffer = vec![0; MAX_DATAGRAM];
+    loop {
+        let mut items =
+            [WaitItem::new(klog_cap, WaitEvents::READABLE), WaitItem::new(network.cptr(), WaitEvents::READABLE)];
+
+        if let SyscallResult::Err(e) = wait_many(&mut items, NO_TIMEOUT) {
+            println!("[klogd] Failed to wait for the kernel log: {:?}", e);
+            return;
+        }
+
+        // Nothing the network service sends back is interesting, but it has
+        // to be read to keep the channel from filling up
+        let network_gone = items[1].ready & WaitEvents::CLOSED;
+        if network_gone || (items[1].ready & WaitEvents::READABLE && network.read(&mut []).is_err()) {
+            println!("[klogd] Lost connection to the network service");
+            return;
+        }
+
+        while klog.has_unread() {
+            let (n, lost) = klog.read(&mut buffer);
+            let mut data = &buffer[..n];
+
+            if lost > 0 {
+                send(&mut network, format!("[klogd] {} bytes of kernel log were overwritten\n", lost).into_bytes());
+
+                // Skip the rest of the partially overwritten record
+                let resync = data.iter().position(|&b| b == b'\n').map(|i| i + 1).unwrap_or(data.len());
+                data = &data[resync..];
+            }
+
+            if !data.is_empty() {
+                send(&mut network, data.to_vec());
+            }
+        }
+    }
+}
+
+fn send(network: &mut IpcChannel, data: Vec<u8>) {
+    let request = SendRequest { to_ip: String::from(DESTINATION), to_port: PORT, data };
+    if let Err(e) = network.send_bytes(&json::to_bytes(&request), &[]) {
+        println!("[klogd] Failed to send kernel log: {:?}", e);
+    }
+}
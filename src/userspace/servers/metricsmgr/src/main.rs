@@ -0,0 +1,125 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use json_rpc::CallContext;
+use librust::{
+    message::{KernelNotification, SyscallResult},
+    syscalls::{
+        receive_message,
+        system::{read_kernel_metrics, KernelMetric, MetricKind},
+        ReadMessage,
+    },
+};
+use metrics::{protocol, Sample, KERNEL_PREFIX};
+use std::{collections::BTreeMap, ipc::IpcChannel};
+
+struct Collector {
+    metrics: BTreeMap<String, (MetricKind, u64)>,
+}
+
+impl Collector {
+    fn register(&mut self, name: String, kind: MetricKind) {
+        if name.starts_with(KERNEL_PREFIX) {
+            return println!("[metricsmgr] Refusing to register `{}`, the prefix is reserved for the kernel", name);
+        }
+
+        match self.metrics.get(&name) {
+            Some((existing, _)) if *existing != kind => {
+                println!("[metricsmgr] `{}` is already registered as a {}", name, existing.as_str())
+            }
+            // Services re-registering after a restart keep counting from where
+            // they left off
+            Some(_) => {}
+            None => {
+                self.metrics.insert(name, (kind, 0));
+            }
+        }
+    }
+
+    fn update(&mut self, name: &str, kind: MetricKind, f: impl FnOnce(&mut u64)) {
+        match self.metrics.get_mut(name) {
+            Some((existing, value)) if *existing == kind => f(value),
+            _ => println!("[metricsmgr] Ignoring update to unregistered {} `{}`", kind.as_str(), name),
+        }
+    }
+}
+
+fn kernel_metrics() -> Vec<KernelMetric> {
+    let mut metrics = vec![KernelMetric::default(); 16];
+    loop {
+        match read_kernel_metrics(&mut metrics) {
+            SyscallResult::Ok((written, total)) if written == total => {
+                metrics.truncate(written);
+                return metrics;
+            }
+            SyscallResult::Ok((_, total)) => metrics.resize(total, KernelMetric::default()),
+            SyscallResult::Err(e) => {
+                println!("[metricsmgr] Failed to read kernel metrics: {:?}", e);
+                return Vec::new();
+            }
+        }
+    }
+}
+
+impl protocol::Server for Collector {
+    fn register_counter(&mut self, _: &mut CallContext, name: String) {
+        self.register(name, MetricKind::Counter);
+    }
+
+    fn register_gauge(&mut self, _: &mut CallContext, name: String) {
+        self.register(name, MetricKind::Gauge);
+    }
+
+    fn add(&mut self, _: &mut CallContext, name: String, delta: u64) {
+        self.update(&name, MetricKind::Counter, |value| *value = value.saturating_add(delta));
+    }
+
+    fn set(&mut self, _: &mut CallContext, name: String, value: u64) {
+        self.update(&name, MetricKind::Gauge, |gauge| *gauge = value);
+    }
+
+    fn query(&mut self, _: &mut CallContext, prefix: String) -> Vec<Sample> {
+        let kernel = kernel_metrics().into_iter().map(|metric| Sample {
+            name: format!("{}{}", KERNEL_PREFIX, metric.name),
+            kind: metric.kind.as_str().into(),
+            value: metric.value,
+        });
+
+        let mut samples: Vec<_> = kernel
+            .chain(self.metrics.iter().map(|(name, (kind, value))| Sample {
+                name: name.clone(),
+                kind: kind.as_str().into(),
+                value: *value,
+            }))
+            .filter(|sample| sample.name.starts_with(&*prefix))
+            .collect();
+
+        samples.sort_by(|a, b| a.name.cmp(&b.name));
+        samples
+    }
+}
+
+fn main() {
+    let mut collector = Collector { metrics: BTreeMap::new() };
+
+    loop {
+        let cptr = match receive_message() {
+            ReadMessage::Kernel(KernelNotification::NewChannelMessage(cptr)) => cptr,
+            _ => continue,
+        };
+
+        let mut channel = IpcChannel::new(cptr);
+        let (message, caps) = match channel.read_with_all_caps() {
+            Ok(read) => read,
+            Err(_) => continue,
+        };
+
+        if let Err(e) = protocol::dispatch(&mut collector, &mut channel, message.as_bytes(), caps) {
+            println!("[metricsmgr] Error handling request: {:?}", e);
+        }
+    }
+}
@@ -61,14 +61,14 @@ impl VirtIoNetDevice {
             rx_buffer_map.insert(descriptor, index);
         }
 
-        device.header.status.reset();
+        device.header.status().reset();
 
-        device.header.status.set_flag(StatusFlag::Acknowledge);
-        device.header.status.set_flag(StatusFlag::Driver);
-        device.header.device_features_select.write(0);
+        device.header.status().set_flag(StatusFlag::Acknowledge);
+        device.header.status().set_flag(StatusFlag::Driver);
+        device.header.device_features_select().write(0);
 
         let mut available_features = device.header.features() as u64;
-        device.header.device_features_select.write(1);
+        device.header.device_features_select().write(1);
         available_features |= (device.header.features() as u64) << 32;
 
         let available_features = NetDeviceFeatures::new(available_features);
@@ -105,50 +105,50 @@ impl VirtIoNetDevice {
         // }
 
         let NetDeviceFeaturesSplit { low, high } = selected_features.split();
-        device.header.driver_features_select.write(0);
-        device.header.driver_features.write(low);
-        device.header.driver_features_select.write(1);
-        device.header.driver_features.write(high);
+        device.header.driver_features_select().write(0);
+        device.header.driver_features().write(low);
+        device.header.driver_features_select().write(1);
+        device.header.driver_features().write(high);
 
-        device.header.status.set_flag(StatusFlag::FeaturesOk);
+        device.header.status().set_flag(StatusFlag::FeaturesOk);
 
-        if !device.header.status.is_set(StatusFlag::FeaturesOk) {
+        if !device.header.status().is_set(StatusFlag::FeaturesOk) {
             return Err(VirtIoDeviceError::FeaturesNotRecognized);
         }
 
         // Receive Queue
-        device.header.queue_select.write(0);
+        device.header.queue_select().write(0);
         librust::mem::fence(librust::mem::FenceMode::Write);
-        assert!(device.header.queue_size_max.read() > 0);
-        device.header.queue_size.write(receive_queue.queue_size());
-        device.header.queue_available.set(receive_queue.available.physical_address());
-        device.header.queue_descriptor.set(receive_queue.descriptors.physical_address());
-        device.header.queue_used.set(receive_queue.used.physical_address());
-        device.header.queue_ready.ready();
+        assert!(device.header.queue_size_max().read() > 0);
+        device.header.queue_size().write(receive_queue.queue_size());
+        device.header.queue_available().set(receive_queue.available.physical_address());
+        device.header.queue_descriptor().set(receive_queue.descriptors.physical_address());
+        device.header.queue_used().set(receive_queue.used.physical_address());
+        device.header.queue_ready().ready();
 
         librust::mem::fence(librust::mem::FenceMode::Write);
 
         // Transmit Queue
-        device.header.queue_select.write(1);
+        device.header.queue_select().write(1);
         librust::mem::fence(librust::mem::FenceMode::Write);
-        assert!(device.header.queue_size_max.read() > 0);
-        device.header.queue_size.write(transmit_queue.queue_size());
-        device.header.queue_available.set(transmit_queue.available.physical_address());
-        device.header.queue_descriptor.set(transmit_queue.descriptors.physical_address());
-        device.header.queue_used.set(transmit_queue.used.physical_address());
-        device.header.queue_ready.ready();
+        assert!(device.header.queue_size_max().read() > 0);
+        device.header.queue_size().write(transmit_queue.queue_size());
+        device.header.queue_available().set(transmit_queue.available.physical_address());
+        device.header.queue_descriptor().set(transmit_queue.descriptors.physical_address());
+        device.header.queue_used().set(transmit_queue.used.physical_address());
+        device.header.queue_ready().ready();
 
         librust::mem::fence(librust::mem::FenceMode::Write);
 
-        device.header.status.set_flag(StatusFlag::DriverOk);
+        device.header.status().set_flag(StatusFlag::DriverOk);
 
         librust::mem::fence(librust::mem::FenceMode::Write);
 
-        if device.header.status.failed() {
+        if device.header.status().failed() {
             return Err(VirtIoDeviceError::DeviceError);
         }
 
-        device.header.queue_notify.notify(0);
+        device.header.queue_notify().notify(0);
 
         Ok(Self { device, receive_queue, transmit_queue, rx_data_buffer, rx_buffer_map, tx_data_buffer, tx_buffer_map })
     }
@@ -226,7 +226,7 @@ impl super::NetworkDriver for VirtIoNetDevice {
     }
 
     fn process_interrupt(&mut self, _: usize) -> Result<Option<&[u8]>, super::DriverError> {
-        self.device.header.interrupt_ack.acknowledge_buffer_used();
+        self.device.header.interrupt_ack().acknowledge_buffer_used();
 
         if let Some(used) = self.transmit_queue.used.pop() {
             let descr = SplitqueueIndex::new(used.start_index as u16);
@@ -280,7 +280,7 @@ impl super::NetworkDriver for VirtIoNetDevice {
 
         librust::mem::fence(librust::mem::FenceMode::Write);
 
-        self.device.header.queue_notify.notify(1);
+        self.device.header.queue_notify().notify(1);
 
         Ok(())
     }
@@ -0,0 +1,66 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Keeps track of which channel each service name refers to, see
+//! [`std::registry`] for the protocol. `init` registers every service it
+//! starts, and services can register themselves or channels they hold.
+
+use librust::{
+    capabilities::{Capability, CapabilityPtr, CapabilityRights},
+    message::KernelNotification,
+    syscalls::{receive_message, ReadMessage},
+};
+use std::{
+    collections::BTreeMap,
+    ipc::IpcChannel,
+    registry::{Request, Response},
+};
+
+fn main() {
+    let mut services = BTreeMap::<String, CapabilityPtr>::new();
+
+    loop {
+        let cptr = match receive_message() {
+            ReadMessage::Kernel(KernelNotification::NewChannelMessage(cptr)) => cptr,
+            _ => continue,
+        };
+
+        let mut channel = IpcChannel::new(cptr);
+        let (message, caps) = match channel.read_with_all_caps() {
+            Ok(read) => read,
+            Err(_) => continue,
+        };
+
+        let response = match Request::parse(message.as_bytes()) {
+            // A service registering itself is reached through the channel it
+            // sent the request over
+            Some(Request::Register(name)) => {
+                services.insert(name.into(), caps.first().map_or(cptr, |cap| cap.cptr));
+                Response::Ok
+            }
+            Some(Request::Lookup(name)) => match services.get(name) {
+                Some(&service) => {
+                    let cap = Capability::new(service, CapabilityRights::READ | CapabilityRights::WRITE);
+                    match channel.send_bytes(Response::Ok.as_str(), &[cap]) {
+                        Ok(()) => continue,
+                        // The service has exited since it was registered
+                        Err(_) => {
+                            services.remove(name);
+                            Response::NotFound
+                        }
+                    }
+                }
+                None => Response::NotFound,
+            },
+            None => Response::Invalid,
+        };
+
+        if let Err(e) = channel.send_bytes(response.as_str(), &[]) {
+            println!("[registry] Failed to reply to request: {:?}", e);
+        }
+    }
+}
@@ -7,38 +7,42 @@
 
 use volatile::Volatile;
 
-#[repr(C)]
-pub struct Uart16550 {
-    data_register: Volatile<u8>,
-    interrupt_enable: Volatile<u8>,
-    int_id_fifo_control: Volatile<u8>,
-    line_control: Volatile<u8>,
-    modem_control: Volatile<u8>,
-    line_status: Volatile<u8>,
-    modem_status: Volatile<u8>,
-    scratch: Volatile<u8>,
+volatile::register_block! {
+    struct Registers [0x08] {
+        0x00 => data_register: Volatile<u8>,
+        0x01 => interrupt_enable: Volatile<u8>,
+        0x02 => int_id_fifo_control: Volatile<u8>,
+        0x03 => line_control: Volatile<u8>,
+        0x04 => modem_control: Volatile<u8>,
+        0x05 => line_status: Volatile<u8>,
+        0x06 => modem_status: Volatile<u8>,
+        0x07 => scratch: Volatile<u8>,
+    }
 }
 
+#[repr(transparent)]
+pub struct Uart16550(Registers);
+
 impl Uart16550 {
     pub fn init(&self) {
-        self.line_control.write(0x03);
-        self.int_id_fifo_control.write(0x01);
-        self.interrupt_enable.write(0x01);
+        self.0.line_control().write(0x03);
+        self.0.int_id_fifo_control().write(0x01);
+        self.0.interrupt_enable().write(0x01);
 
-        let lcr = self.line_control.read();
-        self.line_control.write(lcr | (1 << 7));
+        let lcr = self.0.line_control().read();
+        self.0.line_control().write(lcr | (1 << 7));
 
         // Full speed, baybee
-        self.data_register.write(1);
-        self.interrupt_enable.write(0);
+        self.0.data_register().write(1);
+        self.0.interrupt_enable().write(0);
 
-        self.line_control.write(lcr);
+        self.0.line_control().write(lcr);
 
-        self.scratch.write(0);
+        self.0.scratch().write(0);
     }
 
     pub fn line_status(&self) -> u8 {
-        self.line_status.read()
+        self.0.line_status().read()
     }
 
     pub fn data_waiting(&self) -> bool {
@@ -51,7 +55,7 @@ impl Uart16550 {
     pub fn read(&self) -> u8 {
         while !self.data_waiting() {}
 
-        self.data_register.read()
+        self.0.data_register().read()
     }
 
     pub fn data_empty(&self) -> bool {
@@ -67,7 +71,7 @@ impl Uart16550 {
             self.write_str("\x1B[1D \x1B[1D");
         }
 
-        self.data_register.write(data);
+        self.0.data_register().write(data);
     }
 
     pub fn write_str(&self, s: &str) {
@@ -0,0 +1,246 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use json_rpc::CallContext;
+use librust::{
+    capabilities::Capability,
+    message::KernelNotification,
+    syscalls::{receive_message, ReadMessage},
+};
+use std::{ipc::IpcChannel, sync::Arc};
+use sync::SpinMutex;
+use usb::{
+    ehci::Ehci,
+    hid::Keyboard,
+    mass_storage::{MassStorage, BLOCK_SIZE},
+    ohci::Ohci,
+    protocol,
+    xhci::Xhci,
+    BlockDeviceInfo, Bus, HostController, UsbError,
+};
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    struct Device {
+        name: String,
+        compatible: Vec<String>,
+        interrupts: Vec<usize>,
+    }
+}
+
+json::derive! {
+    Deserialize,
+    #[derive(Debug)]
+    struct Devices {
+        devices: Vec<Device>,
+    }
+}
+
+json::derive! {
+    Serialize,
+    struct WantedCompatible {
+        compatible: Vec<String>,
+    }
+}
+
+const EHCI_COMPATIBLE: &[&str] = &["generic-ehci", "allwinner,sun20i-d1-ehci"];
+const OHCI_COMPATIBLE: &[&str] = &["generic-ohci", "allwinner,sun20i-d1-ohci"];
+// xHCI controllers on PCI (e.g. QEMU's `qemu-xhci`) aren't found yet since
+// that needs PCI enumeration
+const XHCI_COMPATIBLE: &[&str] = &["generic-xhci"];
+
+/// Bring up a controller, or `None` if it isn't one we know of or it failed to
+/// start
+fn controller(device: &Device, base: *mut u8) -> Option<Box<dyn HostController>> {
+    let is = |compatible: &[&str]| device.compatible.iter().any(|c| compatible.contains(&&**c));
+    let controller: Result<Box<dyn HostController>, UsbError> = unsafe {
+        match () {
+            _ if is(EHCI_COMPATIBLE) => Ehci::new(base).map(|c| Box::new(c) as _),
+            _ if is(OHCI_COMPATIBLE) => Ohci::new(base).map(|c| Box::new(c) as _),
+            _ if is(XHCI_COMPATIBLE) => Xhci::new(base).map(|c| Box::new(c) as _),
+            _ => return None,
+        }
+    };
+
+    match controller {
+        Ok(controller) => Some(controller),
+        Err(e) => {
+            println!("[usbmgr] Failed to start {}: {:?}", device.name, e);
+            None
+        }
+    }
+}
+
+/// How often keyboards are checked for new reports
+const KEYBOARD_POLL_MS: u64 = 10;
+
+struct Server {
+    /// Shared with the thread polling keyboards
+    buses: Arc<SpinMutex<Vec<Bus>>>,
+    /// Mass storage devices along with the index of the bus they're on
+    storage: Vec<(usize, MassStorage)>,
+}
+
+impl protocol::Server for Server {
+    fn block_devices(&mut self, _: &mut CallContext) -> Vec<BlockDeviceInfo> {
+        self.storage
+            .iter()
+            .enumerate()
+            .map(|(id, (_, storage))| BlockDeviceInfo {
+                id: id as u32,
+                vendor: storage.vendor.clone(),
+                product: storage.product.clone(),
+                blocks: storage.blocks,
+            })
+            .collect()
+    }
+
+    fn read_block(&mut self, _: &mut CallContext, device: u32, block: u64) -> Option<Vec<u8>> {
+        let (bus, storage) = self.storage.get_mut(device as usize)?;
+        let mut buffer = [0; BLOCK_SIZE];
+
+        match storage.read_block(&mut self.buses.lock()[*bus], block, &mut buffer) {
+            Ok(()) => Some(buffer.to_vec()),
+            Err(e) => {
+                println!("[usbmgr] Failed to read block {} of device {}: {:?}", block, device, e);
+                None
+            }
+        }
+    }
+
+    fn write_block(&mut self, _: &mut CallContext, device: u32, block: u64, data: Vec<u8>) -> bool {
+        let (bus, storage) = match self.storage.get_mut(device as usize) {
+            Some(storage) => storage,
+            None => return false,
+        };
+        let buffer: [u8; BLOCK_SIZE] = match data.try_into() {
+            Ok(buffer) => buffer,
+            Err(_) => return false,
+        };
+
+        match storage.write_block(&mut self.buses.lock()[*bus], block, &buffer) {
+            Ok(()) => true,
+            Err(e) => {
+                println!("[usbmgr] Failed to write block {} of device {}: {:?}", block, device, e);
+                false
+            }
+        }
+    }
+}
+
+/// Forward keys typed on the keyboards to the console, which is only notified
+/// of input by its own device, so the interrupt endpoints are polled instead
+fn poll_keyboards(buses: Arc<SpinMutex<Vec<Bus>>>, mut keyboards: Vec<(usize, Keyboard)>) {
+    loop {
+        for (bus, keyboard) in &mut keyboards {
+            let bytes = match keyboard.poll(&mut buses.lock()[*bus]) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    println!("[usbmgr] Failed to read from keyboard: {:?}", e);
+                    continue;
+                }
+            };
+
+            if !bytes.is_empty() {
+                let _ = librust::syscalls::io::push_console_input(&bytes);
+            }
+        }
+
+        usb::delay_ms(KEYBOARD_POLL_MS);
+    }
+}
+
+fn main() {
+    let devicemgr_cptr = std::env::lookup_capability("devicemgr").unwrap();
+    let mut devicemgr = IpcChannel::new(devicemgr_cptr);
+    let compatible = EHCI_COMPATIBLE.iter().chain(OHCI_COMPATIBLE).chain(XHCI_COMPATIBLE).map(|c| c.to_string());
+    devicemgr.send_bytes(&json::to_bytes(&WantedCompatible { compatible: compatible.collect() }), &[]).unwrap();
+
+    let (message, capabilities) = devicemgr.read_with_all_caps().unwrap();
+    let devices: Devices = json::deserialize(message.as_bytes()).unwrap();
+
+    let mut controllers = Vec::new();
+    for (device, Capability { cptr: mmio_cap, .. }) in devices.devices.into_iter().zip(capabilities) {
+        let info = librust::syscalls::io::query_mmio_cap(mmio_cap).unwrap();
+        controllers.push((device, info.address() as *mut u8));
+    }
+
+    // EHCI controllers need to be running before their companions so they can
+    // hand the low and full speed ports over to them
+    controllers.sort_by_key(|(device, _)| !device.compatible.iter().any(|c| EHCI_COMPATIBLE.contains(&&**c)));
+
+    let mut buses = Vec::new();
+    let mut storage_devices = Vec::new();
+    let mut keyboards = Vec::new();
+    for (device, base) in controllers {
+        let controller = match controller(&device, base) {
+            Some(controller) => controller,
+            None => continue,
+        };
+
+        let mut bus = Bus::new(controller);
+        bus.enumerate();
+
+        let bus_index = buses.len();
+        for index in 0..bus.devices.len() {
+            if Keyboard::supports(&bus.devices[index]) {
+                match Keyboard::new(&mut bus, index) {
+                    Ok(keyboard) => keyboards.push((bus_index, keyboard)),
+                    Err(e) => println!("[usbmgr] Failed to set up keyboard: {:?}", e),
+                }
+
+                continue;
+            }
+
+            if !MassStorage::supports(&bus.devices[index]) {
+                continue;
+            }
+
+            match MassStorage::new(&mut bus, index) {
+                Ok(storage) => {
+                    println!(
+                        "[usbmgr] Mass storage device {}: {} {} ({} blocks)",
+                        storage_devices.len(),
+                        storage.vendor,
+                        storage.product,
+                        storage.blocks
+                    );
+                    storage_devices.push((bus_index, storage));
+                }
+                Err(e) => println!("[usbmgr] Failed to set up mass storage device: {:?}", e),
+            }
+        }
+
+        buses.push(bus);
+    }
+
+    let buses = Arc::new(SpinMutex::new(buses));
+    if !keyboards.is_empty() {
+        println!("[usbmgr] Using {} keyboard(s) for console input", keyboards.len());
+        let buses = Arc::clone(&buses);
+        std::thread::spawn(move || poll_keyboards(buses, keyboards));
+    }
+
+    let mut server = Server { buses, storage: storage_devices };
+
+    loop {
+        let cptr = match receive_message() {
+            ReadMessage::Kernel(KernelNotification::NewChannelMessage(cptr)) if cptr != devicemgr_cptr => cptr,
+            _ => continue,
+        };
+
+        let mut channel = IpcChannel::new(cptr);
+        let (message, caps) = match channel.read_with_all_caps() {
+            Ok(read) => read,
+            Err(_) => continue,
+        };
+
+        if let Err(e) = protocol::dispatch(&mut server, &mut channel, message.as_bytes(), caps) {
+            println!("[usbmgr] Error handling request: {:?}", e);
+        }
+    }
+}
@@ -94,8 +94,13 @@ fn main() {
         let devices: Vec<_> = virtio_devices.drain_filter(|device| device.2 as u32 == dev_type).collect();
         let caps: Vec<_> = devices
             .iter()
+            // Drivers map and use the devices, but have no reason to hand them
+            // off to anyone else
             .map(|(cap, _, _, _, _)| {
-                Capability::new(*cap, CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::GRANT)
+                Capability::new(
+                    *cap,
+                    CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::GRANT | CapabilityRights::MAP,
+                )
             })
             .collect();
 
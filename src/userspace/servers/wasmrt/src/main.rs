@@ -0,0 +1,164 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Runs untrusted WebAssembly plugins. Each request is a message containing a
+//! module, optionally along with a channel capability. The module's `run`
+//! export is called and the reply is either a `0` byte followed by its results
+//! as little endian `u64`s, or a `1` byte followed by an error message.
+//!
+//! A plugin can't touch anything other than its own memory, the only imports
+//! it's given are:
+//!
+//! * `env.print(ptr: i32, len: i32)`, which prints to this task's stdout
+//! * `env.send(ptr: i32, len: i32) -> i32`, which sends a message over the
+//!   channel given with the request
+//! * `env.recv(ptr: i32, len: i32) -> i32`, which waits for a message on that
+//!   channel and copies as much of it as will fit
+//!
+//! `send` and `recv` return `-1` if no channel was given or the operation
+//! failed. Capabilities sent over the channel are never handed to the plugin,
+//! so it can't reach anything beyond what it was granted.
+
+use librust::{
+    capabilities::{Capability, CapabilityRights},
+    message::KernelNotification,
+    syscalls::{receive_message, ReadMessage},
+};
+use std::ipc::IpcChannel;
+use wasm::{FuncType, Host, Import, Instance, Limits, Memory, Module, Trap, ValueType};
+
+/// Largest module which will be run
+const MAX_MODULE_SIZE: usize = 1024 * 1024;
+const LIMITS: Limits = Limits { max_pages: 16, max_stack: 64 * 1024, max_call_depth: 256, fuel: Some(100_000_000) };
+
+#[derive(Debug, Clone, Copy)]
+enum HostFunction {
+    Print,
+    Send,
+    Recv,
+}
+
+impl HostFunction {
+    const ALL: [Self; 3] = [Self::Print, Self::Send, Self::Recv];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Print => "print",
+            Self::Send => "send",
+            Self::Recv => "recv",
+        }
+    }
+
+    fn results(self) -> &'static [ValueType] {
+        match self {
+            Self::Print => &[],
+            Self::Send | Self::Recv => &[ValueType::I32],
+        }
+    }
+}
+
+struct Plugin {
+    channel: Option<IpcChannel>,
+}
+
+impl Plugin {
+    fn send(&mut self, bytes: &[u8]) -> Option<u64> {
+        self.channel.as_mut()?.send_bytes(bytes, &[]).ok()?;
+        Some(bytes.len() as u64)
+    }
+
+    fn recv(&mut self, buffer: &mut [u8]) -> Option<u64> {
+        let (message, _) = self.channel.as_ref()?.read_with_all_caps().ok()?;
+        let bytes = message.as_bytes();
+        let n = bytes.len().min(buffer.len());
+        buffer[..n].copy_from_slice(&bytes[..n]);
+
+        Some(n as u64)
+    }
+}
+
+impl Host for Plugin {
+    fn resolve(&mut self, import: &Import, ty: &FuncType) -> Option<usize> {
+        if import.module != "env" {
+            return None;
+        }
+
+        HostFunction::ALL.iter().position(|f| {
+            f.name() == import.name && ty.params == [ValueType::I32, ValueType::I32] && ty.results == f.results()
+        })
+    }
+
+    fn call(&mut self, id: usize, args: &[u64], memory: &mut Memory) -> Result<Option<u64>, Trap> {
+        let (ptr, len) = (args[0], args[1]);
+        match HostFunction::ALL[id] {
+            HostFunction::Print => {
+                let bytes = memory.read(ptr, len)?;
+                print!("{}", String::from_utf8_lossy(bytes));
+                Ok(None)
+            }
+            HostFunction::Send => {
+                let bytes = memory.read(ptr, len)?.to_vec();
+                Ok(Some(self.send(&bytes).unwrap_or(u32::MAX as u64)))
+            }
+            HostFunction::Recv => {
+                let buffer = memory.read_mut(ptr, len)?;
+                Ok(Some(self.recv(buffer).unwrap_or(u32::MAX as u64)))
+            }
+        }
+    }
+}
+
+fn run(module: &[u8], channel: Option<IpcChannel>) -> Result<Vec<u64>, String> {
+    if module.len() > MAX_MODULE_SIZE {
+        return Err(format!("module is larger than {} bytes", MAX_MODULE_SIZE));
+    }
+
+    let module = Module::parse(module).map_err(|e| format!("invalid module: {:?}", e))?;
+    let mut instance = Instance::new(module, Plugin { channel }, LIMITS).map_err(|e| format!("{:?}", e))?;
+    instance.invoke("run", &[]).map_err(|e| format!("trapped: {:?}", e))
+}
+
+fn main() {
+    loop {
+        let cptr = match receive_message() {
+            ReadMessage::Kernel(KernelNotification::NewChannelMessage(cptr)) => cptr,
+            _ => continue,
+        };
+
+        let mut channel = IpcChannel::new(cptr);
+        let (message, caps) = match channel.read_with_all_caps() {
+            Ok(read) => read,
+            Err(_) => continue,
+        };
+
+        // Only a channel which can be both read and written is useful to the
+        // plugin, anything else is ignored
+        let plugin_channel = caps
+            .iter()
+            .find(|cap| cap.rights & (CapabilityRights::READ | CapabilityRights::WRITE))
+            .map(|&Capability { cptr, .. }| IpcChannel::new(cptr));
+
+        let reply = match run(message.as_bytes(), plugin_channel) {
+            Ok(results) => {
+                let mut reply = vec![0];
+                results.iter().for_each(|value| reply.extend_from_slice(&value.to_le_bytes()));
+                reply
+            }
+            Err(e) => {
+                println!("[wasmrt] Plugin failed: {}", e);
+                let mut reply = vec![1];
+                reply.extend_from_slice(e.as_bytes());
+                reply
+            }
+        };
+
+        drop(message);
+        if let Err(e) = channel.send_bytes(reply, &[]) {
+            println!("[wasmrt] Failed to reply: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Prints the kernel's edge coverage flags, which are only recorded when the
+//! kernel is built with `cargo xtask build vanadinite --coverage`. They're
+//! printed in the same hex encoded lines the kernel dumps when it exits, and
+//! can be turned into a report by running `cargo xtask decode` on the console
+//! log. `coverage reset` clears the flags, so that the next dump only covers
+//! what ran in between.
+
+use std::librust::{
+    message::SyscallResult,
+    syscalls::coverage::{read_coverage, reset_coverage},
+};
+
+/// Flags read and printed at a time, matching the kernel's own dumps
+const BATCH_SIZE: usize = 1024;
+
+fn main() {
+    let system = match std::env::lookup_capability("system") {
+        Some(cptr) => cptr,
+        None => {
+            println!("coverage: no system control capability");
+            return;
+        }
+    };
+
+    match std::env::args() {
+        [] => {}
+        ["reset"] => {
+            if let SyscallResult::Err(e) = reset_coverage(system) {
+                println!("coverage: failed to reset coverage: {:?}", e);
+            }
+
+            return;
+        }
+        _ => {
+            println!("usage: coverage [reset]");
+            return;
+        }
+    }
+
+    let mut flags = vec![0; BATCH_SIZE];
+    let mut start = 0;
+    loop {
+        let (n, total) = match read_coverage(system, start, &mut flags) {
+            SyscallResult::Ok(read) => read,
+            SyscallResult::Err(e) => {
+                println!("coverage: failed to read coverage: {:?}", e);
+                return;
+            }
+        };
+
+        if total == 0 {
+            println!("no coverage, was the kernel built with `--coverage`?");
+            return;
+        }
+
+        if start == 0 {
+            println!("coverage: dumping {} edges", total);
+        }
+
+        if flags[..n].iter().any(|&flag| flag != 0) {
+            let hex = flags[..n].iter().map(|flag| format!("{:02x}", flag)).collect::<String>();
+            println!("coverage-flags: {} {}", start, hex);
+        }
+
+        start += n;
+        if n == 0 || start >= total {
+            break;
+        }
+    }
+}
@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Minimal ELF core files in the layout GDB expects for Linux on RISC-V: a
+//! single `NT_PRSTATUS` note holding the registers, and a `PT_LOAD` segment
+//! for each region of the crashed task's memory map. The memory contents
+//! aren't available anymore, so the segments have no file data.
+
+use std::librust::syscalls::{mem::RegionInfo, system::CrashInfo};
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const ET_CORE: u16 = 4;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+/// Size of `struct elf_prstatus` on riscv64
+const PRSTATUS_SIZE: usize = 376;
+const PRSTATUS_CURSIG_OFFSET: usize = 12;
+const PRSTATUS_PID_OFFSET: usize = 32;
+const PRSTATUS_REGS_OFFSET: usize = 112;
+
+pub fn build(info: &CrashInfo, regions: &[RegionInfo]) -> Vec<u8> {
+    let note = prstatus_note(info);
+    let n_headers = regions.len() + 1;
+    let note_offset = ELF_HEADER_SIZE + n_headers * PROGRAM_HEADER_SIZE;
+
+    let mut out = Vec::with_capacity(note_offset + note.len());
+    out.extend_from_slice(b"\x7FELF");
+    // 64-bit, little endian, version 1, System V ABI
+    out.extend_from_slice(&[2, 1, 1, 0]);
+    out.extend_from_slice(&[0; 8]);
+    push_u16(&mut out, ET_CORE);
+    push_u16(&mut out, EM_RISCV);
+    push_u32(&mut out, 1);
+    // Entry point, program header offset, and section header offset
+    push_u64(&mut out, 0);
+    push_u64(&mut out, ELF_HEADER_SIZE as u64);
+    push_u64(&mut out, 0);
+    push_u32(&mut out, 0);
+    push_u16(&mut out, ELF_HEADER_SIZE as u16);
+    push_u16(&mut out, PROGRAM_HEADER_SIZE as u16);
+    push_u16(&mut out, n_headers as u16);
+    // No section headers
+    push_u16(&mut out, 0);
+    push_u16(&mut out, 0);
+    push_u16(&mut out, 0);
+
+    push_program_header(&mut out, PT_NOTE, 0, note_offset, 0, note.len(), 0, 4);
+    for region in regions {
+        let flags = (region.readable as u32) << 2 | (region.writable as u32) << 1 | region.executable as u32;
+        push_program_header(&mut out, PT_LOAD, flags, note_offset + note.len(), region.start, 0, region.len, 4096);
+    }
+
+    out.extend_from_slice(&note);
+    out
+}
+
+fn prstatus_note(info: &CrashInfo) -> Vec<u8> {
+    let mut prstatus = vec![0; PRSTATUS_SIZE];
+    let signal = signal_for(info.cause);
+    prstatus[..4].copy_from_slice(&signal.to_le_bytes());
+    prstatus[PRSTATUS_CURSIG_OFFSET..][..2].copy_from_slice(&(signal as u16).to_le_bytes());
+    prstatus[PRSTATUS_PID_OFFSET..][..4].copy_from_slice(&(info.tid as u32).to_le_bytes());
+
+    // `pc` followed by `x1` through `x31`
+    let registers = core::iter::once(info.pc).chain(info.registers);
+    for (i, register) in registers.enumerate() {
+        prstatus[PRSTATUS_REGS_OFFSET + i * 8..][..8].copy_from_slice(&(register as u64).to_le_bytes());
+    }
+
+    let mut note = Vec::with_capacity(20 + PRSTATUS_SIZE);
+    push_u32(&mut note, 5);
+    push_u32(&mut note, PRSTATUS_SIZE as u32);
+    push_u32(&mut note, NT_PRSTATUS);
+    // Name padded to a multiple of 4 bytes
+    note.extend_from_slice(b"CORE\0\0\0\0");
+    note.extend_from_slice(&prstatus);
+    note
+}
+
+/// The POSIX signal Linux would have sent for the fault
+fn signal_for(cause: usize) -> u32 {
+    const SIGILL: u32 = 4;
+    const SIGTRAP: u32 = 5;
+    const SIGBUS: u32 = 7;
+    const SIGSEGV: u32 = 11;
+
+    match cause {
+        2 => SIGILL,
+        3 => SIGTRAP,
+        0 | 4 | 6 => SIGBUS,
+        _ => SIGSEGV,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn push_program_header(
+    out: &mut Vec<u8>,
+    kind: u32,
+    flags: u32,
+    offset: usize,
+    vaddr: usize,
+    file_size: usize,
+    memory_size: usize,
+    align: u64,
+) {
+    push_u32(out, kind);
+    push_u32(out, flags);
+    push_u64(out, offset as u64);
+    push_u64(out, vaddr as u64);
+    // Physical address
+    push_u64(out, 0);
+    push_u64(out, file_size as u64);
+    push_u64(out, memory_size as u64);
+    push_u64(out, align);
+}
+
+fn push_u16(out: &mut Vec<u8>, n: u16) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+fn push_u64(out: &mut Vec<u8>, n: u64) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
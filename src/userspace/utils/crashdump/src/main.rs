@@ -0,0 +1,126 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+mod core_file;
+
+use std::{
+    ipc::IpcChannel,
+    librust::{
+        capabilities::CapabilityPtr,
+        message::SyscallResult,
+        syscalls::{
+            mem::RegionInfo,
+            system::{read_crash_dump, CrashInfo},
+        },
+    },
+};
+
+const REGISTER_NAMES: [&str; 31] = [
+    "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7", "s2", "s3",
+    "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+fn main() {
+    // `crashdump [--core <name>]` prints the crashes the kernel has recorded,
+    // or sends each of them as an ELF core file over the named channel
+    let mut core_channel = match std::env::args() {
+        ["--core", name] => match std::env::lookup_capability(name) {
+            Some(cptr) => Some(IpcChannel::new(cptr)),
+            None => {
+                println!("crashdump: no capability named `{}`", name);
+                return;
+            }
+        },
+        [] => None,
+        _ => {
+            println!("usage: crashdump [--core <channel>]");
+            return;
+        }
+    };
+
+    let system = match std::env::lookup_capability("system") {
+        Some(cptr) => cptr,
+        None => {
+            println!("crashdump: no system control capability");
+            return;
+        }
+    };
+
+    let mut next_id = 1;
+    let mut n_crashes = 0;
+    while let Some((info, regions)) = read_crash(system, next_id) {
+        next_id = info.id + 1;
+        n_crashes += 1;
+
+        match &mut core_channel {
+            Some(channel) => {
+                if let Err(e) = channel.send_bytes(core_file::build(&info, &regions), &[]) {
+                    println!("crashdump: failed to send core file for crash {}: {:?}", info.id, e);
+                    return;
+                }
+            }
+            None => print_crash(&info, &regions),
+        }
+    }
+
+    match core_channel {
+        Some(_) => println!("{} core files sent", n_crashes),
+        None if n_crashes == 0 => println!("no crashes recorded"),
+        None => {}
+    }
+}
+
+/// The oldest crash with an ID of at least `min_id` and its full memory map
+fn read_crash(system: CapabilityPtr, min_id: usize) -> Option<(CrashInfo, Vec<RegionInfo>)> {
+    let mut info = CrashInfo::default();
+    let mut regions = vec![RegionInfo::default(); 64];
+    loop {
+        match read_crash_dump(system, min_id, &mut info, &mut regions) {
+            SyscallResult::Ok(None) => return None,
+            SyscallResult::Ok(Some((written, total))) if written == total => {
+                regions.truncate(written);
+                return Some((info, regions));
+            }
+            SyscallResult::Ok(Some((_, total))) => regions.resize(total, RegionInfo::default()),
+            SyscallResult::Err(e) => {
+                println!("crashdump: failed to read crash dump: {:?}", e);
+                return None;
+            }
+        }
+    }
+}
+
+fn print_crash(info: &CrashInfo, regions: &[RegionInfo]) {
+    println!("crash {}: task {} (tid {}) died to a {}", info.id, info.name, info.tid, info.cause_name());
+    println!("  pc={:#018x} stval={:#018x}", info.pc, info.fault_address);
+
+    for (names, values) in REGISTER_NAMES.chunks(4).zip(info.registers.chunks(4)) {
+        let line = names.iter().zip(values).map(|(name, value)| format!("{:>3}={:#018x}", name, value));
+        println!("  {}", line.collect::<Vec<_>>().join(" "));
+    }
+
+    for region in regions {
+        let permissions = [(region.readable, 'r'), (region.writable, 'w'), (region.executable, 'x')]
+            .into_iter()
+            .map(|(set, c)| if set { c } else { '-' })
+            .collect::<String>();
+        let marker = match (region.start..region.start + region.len).contains(&info.fault_address) {
+            true => " <- fault",
+            false => "",
+        };
+
+        println!(
+            "  {:#018x} {:#018x} {:<4} {:<13} {}{}",
+            region.start,
+            region.start + region.len,
+            permissions,
+            format!("{:?}", region.kind),
+            region.name(),
+            marker
+        );
+    }
+}
@@ -5,15 +5,21 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::librust::syscalls::*;
+use std::librust::{error::KError, message::SyscallResult, syscalls::*};
 
 fn main() {
+    let _ = io::set_console_mode(io::ConsoleMode::Raw);
+
     let mut input = [0; 10];
     let mut total_read = 0;
 
     while total_read < 10 {
         let start = total_read;
-        let read = read_stdin(&mut input[start..]).unwrap();
+        let read = match read_stdin(&mut input[start..]) {
+            SyscallResult::Ok(read) => read,
+            SyscallResult::Err(KError::WouldBlock) => continue,
+            SyscallResult::Err(e) => panic!("failed to read stdin: {:?}", e),
+        };
         total_read += read;
         print!("{}", core::str::from_utf8(&input[start..][..read]).unwrap());
     }
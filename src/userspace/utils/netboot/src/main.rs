@@ -0,0 +1,178 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Fetches the initfs over TFTP and hands it to `init`, which loads the
+//! services started after this one from it instead of its own copy. Booting
+//! this way is enabled by setting `netboot.server` to the TFTP server's IP
+//! address, e.g. `netboot.server=10.0.2.2` on the kernel command line, with
+//! `netboot.file` naming the file to fetch if it isn't [`DEFAULT_FILE`].
+//! Without `netboot.server` this exits straight away, and `init` carries on
+//! with the initfs it was built with.
+
+use json::deser::DeserializeError;
+use librust::{
+    error::KError,
+    message::SyscallResult,
+    syscalls::{
+        allocation::{alloc_virtual_memory, AllocationOptions, MemoryPermissions},
+        mem::snapshot_memory,
+        poll::{wait_many, WaitEvents, WaitItem},
+    },
+};
+use std::ipc::IpcChannel;
+use tftp::{Download, Progress, TftpError};
+
+const DEFAULT_FILE: &str = "initfs.tar.lz4";
+const LOCAL_PORT: u16 = 2069;
+/// How long to wait on the server before sending the last packet again
+const TIMEOUT_US: usize = 1_000_000;
+/// Timeouts in a row before giving up, which is generous since the network
+/// interface may still be waiting on DHCP when the request is first sent
+const MAX_RETRIES: usize = 30;
+const MAX_IMAGE_SIZE: usize = 256 * 1024 * 1024;
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    struct BindRequest {
+        port: u16,
+        port_type: String,
+    }
+}
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    struct BindResponse {
+        msg: String,
+        port: Option<u16>,
+    }
+}
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    struct SendRequest {
+        // FIXME: this should be an IpV4Socket
+        to_ip: String,
+        to_port: u16,
+        data: Vec<u8>,
+    }
+}
+
+json::derive! {
+    #[derive(Debug, Clone)]
+    struct Received {
+        // FIXME: this should be an IpV4Socket
+        from_ip: String,
+        from_port: u16,
+        data: Vec<u8>,
+    }
+}
+
+#[derive(Debug)]
+enum FetchError {
+    NoNetwork,
+    Bind(String),
+    Ipc(KError),
+    Deserialize(DeserializeError),
+    TimedOut,
+    Tftp(TftpError),
+}
+
+fn main() {
+    let mut config = match config::Config::connect() {
+        Some(config) => config,
+        None => return,
+    };
+
+    let server = match config.get("netboot.server") {
+        Ok(Some(server)) => server,
+        _ => return,
+    };
+    let file = config.get("netboot.file").ok().flatten().unwrap_or_else(|| String::from(DEFAULT_FILE));
+
+    println!("[netboot] Fetching {} from {}", file, server);
+    let image = match fetch(&server, &file) {
+        Ok(image) if !image.is_empty() => image,
+        Ok(_) => return println!("[netboot] {} is empty", file),
+        Err(e) => return println!("[netboot] Failed to fetch {}: {:?}", file, e),
+    };
+
+    // The image is sent as a snapshot so `init` can map it without copying
+    let len = image.len();
+    let memory =
+        match alloc_virtual_memory(len, AllocationOptions::None, MemoryPermissions::READ | MemoryPermissions::WRITE) {
+            SyscallResult::Ok(memory) => memory,
+            SyscallResult::Err(e) => return println!("[netboot] Failed to allocate memory for the image: {:?}", e),
+        };
+
+    unsafe { core::ptr::copy_nonoverlapping(image.as_ptr(), memory, len) };
+    drop(image);
+
+    let cptr = match snapshot_memory(memory) {
+        SyscallResult::Ok((cptr, ..)) => cptr,
+        SyscallResult::Err(e) => return println!("[netboot] Failed to snapshot the image: {:?}", e),
+    };
+
+    match lifecycle::Init::connect().map(|mut init| init.provide_initfs(cptr, len)) {
+        Some(Ok(true)) => println!("[netboot] Booting from {} ({} KiB)", file, len / 1024),
+        Some(Ok(false)) => println!("[netboot] init didn't accept {}", file),
+        Some(Err(e)) => println!("[netboot] Failed to send {} to init: {:?}", file, e),
+        None => println!("[netboot] No channel to init"),
+    }
+}
+
+fn fetch(server: &str, file: &str) -> Result<Vec<u8>, FetchError> {
+    let mut network = IpcChannel::new(std::env::lookup_capability("network").ok_or(FetchError::NoNetwork)?);
+    network
+        .send_bytes(&json::to_bytes(&BindRequest { port: LOCAL_PORT, port_type: String::from("udp") }), &[])
+        .map_err(FetchError::Ipc)?;
+
+    let response = network.read(&mut []).map_err(FetchError::Ipc)?;
+    let bind_response: BindResponse =
+        json::deserialize(response.message.as_bytes()).map_err(FetchError::Deserialize)?;
+    if bind_response.port.is_none() {
+        return Err(FetchError::Bind(bind_response.msg));
+    }
+
+    let (mut download, request) = Download::new(file, MAX_IMAGE_SIZE);
+    send(&mut network, server, tftp::PORT, request)?;
+
+    let mut retries = 0;
+    while !download.is_finished() {
+        let mut items = [WaitItem::new(network.cptr(), WaitEvents::READABLE)];
+        match wait_many(&mut items, TIMEOUT_US) {
+            SyscallResult::Ok(0) if retries == MAX_RETRIES => return Err(FetchError::TimedOut),
+            SyscallResult::Ok(0) => {
+                retries += 1;
+                send(&mut network, server, download.server_port(), download.last_packet().to_vec())?;
+                continue;
+            }
+            SyscallResult::Ok(_) => {}
+            SyscallResult::Err(e) => return Err(FetchError::Ipc(e)),
+        }
+
+        let message = network.read(&mut []).map_err(FetchError::Ipc)?;
+        let received: Received = json::deserialize(message.message.as_bytes()).map_err(FetchError::Deserialize)?;
+        if received.from_ip != server {
+            continue;
+        }
+
+        match download.receive(received.from_port, &received.data).map_err(FetchError::Tftp)? {
+            Progress::Reply(packet) | Progress::Finished(packet) => {
+                retries = 0;
+                send(&mut network, server, download.server_port(), packet)?;
+            }
+            Progress::Ignore => {}
+        }
+    }
+
+    Ok(download.into_data().unwrap())
+}
+
+fn send(network: &mut IpcChannel, server: &str, port: u16, data: Vec<u8>) -> Result<(), FetchError> {
+    let request = SendRequest { to_ip: String::from(server), to_port: port, data };
+    network.send_bytes(&json::to_bytes(&request), &[]).map_err(FetchError::Ipc)
+}
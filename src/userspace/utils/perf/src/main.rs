@@ -0,0 +1,158 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::BTreeMap,
+    librust::{
+        capabilities::CapabilityPtr,
+        message::SyscallResult,
+        syscalls::{
+            perf::{perf_read, perf_start, perf_stop, PerfEvent, PerfSample},
+            poll::{wait_many, WaitEvents, WaitItem},
+        },
+    },
+};
+
+const DEFAULT_PERIOD: usize = 100_000;
+const DEFAULT_SAMPLES: usize = 1000;
+/// Give up if no samples show up for this long
+const SAMPLE_TIMEOUT_US: usize = 5_000_000;
+const TOP_PCS: usize = 20;
+
+fn main() {
+    // `perf [event] [period] [samples]` samples every hart until enough
+    // samples have been collected, then prints where they landed
+    let (event, period, n_samples) = match std::env::args() {
+        [] => (Some(PerfEvent::Cycles), Some(DEFAULT_PERIOD), Some(DEFAULT_SAMPLES)),
+        [event] => (PerfEvent::from_name(event), Some(DEFAULT_PERIOD), Some(DEFAULT_SAMPLES)),
+        [event, period] => (PerfEvent::from_name(event), period.parse().ok(), Some(DEFAULT_SAMPLES)),
+        [event, period, samples] => (PerfEvent::from_name(event), period.parse().ok(), samples.parse().ok()),
+        _ => (None, None, None),
+    };
+
+    let (event, period, n_samples) = match (event, period, n_samples) {
+        (Some(event), Some(period), Some(n_samples)) => (event, period, n_samples),
+        _ => {
+            let events = PerfEvent::ALL.map(PerfEvent::name);
+            println!("usage: perf [event] [period] [samples]");
+            println!("  events: {}", events.join(", "));
+            return;
+        }
+    };
+
+    let system = match std::env::lookup_capability("system") {
+        Some(cptr) => cptr,
+        None => {
+            println!("perf: no system control capability");
+            return;
+        }
+    };
+
+    let session = match perf_start(system, event, period) {
+        SyscallResult::Ok(session) => session,
+        SyscallResult::Err(e) => {
+            println!("perf: failed to start profiling: {:?}", e);
+            return;
+        }
+    };
+
+    println!("perf: sampling {} every {} events", event.name(), period);
+    let (samples, dropped) = collect(session, n_samples);
+
+    if let SyscallResult::Err(e) = perf_stop(session) {
+        println!("perf: failed to stop profiling: {:?}", e);
+    }
+
+    report(&samples, dropped);
+}
+
+/// Read samples until there are `n_samples` of them, or none have come in for
+/// a while
+fn collect(session: CapabilityPtr, n_samples: usize) -> (Vec<PerfSample>, usize) {
+    let mut samples = Vec::with_capacity(n_samples);
+    let mut buffer = vec![PerfSample::default(); 256];
+    let mut dropped = 0;
+
+    while samples.len() < n_samples {
+        let mut items = [WaitItem::new(session, WaitEvents::READABLE)];
+        match wait_many(&mut items, SAMPLE_TIMEOUT_US) {
+            SyscallResult::Ok(0) => {
+                println!("perf: timed out waiting for samples");
+                break;
+            }
+            SyscallResult::Ok(_) => {}
+            SyscallResult::Err(e) => {
+                println!("perf: failed to wait for samples: {:?}", e);
+                break;
+            }
+        }
+
+        match perf_read(session, &mut buffer) {
+            SyscallResult::Ok((read, newly_dropped)) => {
+                let wanted = read.min(n_samples - samples.len());
+                samples.extend_from_slice(&buffer[..wanted]);
+                dropped += newly_dropped;
+            }
+            SyscallResult::Err(e) => {
+                println!("perf: failed to read samples: {:?}", e);
+                break;
+            }
+        }
+    }
+
+    (samples, dropped)
+}
+
+fn report(samples: &[PerfSample], dropped: usize) {
+    if samples.is_empty() {
+        println!("no samples collected");
+        return;
+    }
+
+    let mut by_pc = BTreeMap::<(usize, bool), usize>::new();
+    let mut by_tid = BTreeMap::<usize, usize>::new();
+    let mut in_kernel = 0;
+    for sample in samples {
+        *by_pc.entry((sample.pc, sample.kernel)).or_default() += 1;
+        *by_tid.entry(sample.tid).or_default() += 1;
+        in_kernel += sample.kernel as usize;
+    }
+
+    let percent = |n: usize| n as f64 * 100.0 / samples.len() as f64;
+
+    println!(
+        "{} samples ({} dropped), {:.1}% kernel, {:.1}% user",
+        samples.len(),
+        dropped,
+        percent(in_kernel),
+        percent(samples.len() - in_kernel)
+    );
+
+    let mut hot = by_pc.into_iter().collect::<Vec<_>>();
+    hot.sort_by(|a, b| b.1.cmp(&a.1));
+
+    println!("\n  samples      %  pc");
+    for ((pc, kernel), n) in hot.into_iter().take(TOP_PCS) {
+        let marker = match kernel {
+            true => "[k]",
+            false => "[u]",
+        };
+
+        println!("  {:>7} {:>5.1}%  {:#018x} {}", n, percent(n), pc, marker);
+    }
+
+    let mut tasks = by_tid.into_iter().collect::<Vec<_>>();
+    tasks.sort_by(|a, b| b.1.cmp(&a.1));
+
+    println!("\n  samples      %  task");
+    for (tid, n) in tasks {
+        match tid {
+            0 => println!("  {:>7} {:>5.1}%  idle", n, percent(n)),
+            tid => println!("  {:>7} {:>5.1}%  tid {}", n, percent(n), tid),
+        }
+    }
+}
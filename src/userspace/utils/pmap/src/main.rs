@@ -0,0 +1,98 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::librust::{
+    capabilities::CapabilityPtr,
+    message::SyscallResult,
+    syscalls::mem::{query_address_space, snapshot_address_space, RegionInfo},
+};
+
+fn main() {
+    // `pmap [--snapshot] <name>` displays the address space of the task behind
+    // the named capability, otherwise our own
+    let (snapshot, args) = match std::env::args() {
+        ["--snapshot", rest @ ..] => (true, rest),
+        args => (false, args),
+    };
+
+    let task = match args.first() {
+        Some(name) => match std::env::lookup_capability(name) {
+            Some(cptr) => Some(cptr),
+            None => {
+                println!("pmap: no capability named `{}`", name);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    if snapshot {
+        return print_snapshot(task);
+    }
+
+    let mut regions = vec![RegionInfo::default(); 64];
+    loop {
+        match query_address_space(task, &mut regions) {
+            SyscallResult::Ok((written, total)) if written == total => {
+                regions.truncate(written);
+                break;
+            }
+            SyscallResult::Ok((_, total)) => regions.resize(total, RegionInfo::default()),
+            SyscallResult::Err(e) => {
+                println!("pmap: failed to query address space: {:?}", e);
+                return;
+            }
+        }
+    }
+
+    println!("{:<18} {:<18} {:>10} perm {:<13} name", "start", "end", "size", "kind");
+
+    let mut total_size = 0;
+    for region in &regions {
+        let permissions = [(region.readable, 'r'), (region.writable, 'w'), (region.executable, 'x')]
+            .into_iter()
+            .map(|(set, c)| if set { c } else { '-' })
+            .collect::<String>();
+
+        println!(
+            "{:#018x} {:#018x} {:>9}K {:<4} {:<13} {}",
+            region.start,
+            region.start + region.len,
+            region.len / 1024,
+            permissions,
+            format!("{:?}", region.kind),
+            region.name()
+        );
+
+        total_size += region.len;
+    }
+
+    println!("{} regions, {}K total", regions.len(), total_size / 1024);
+}
+
+/// Print a snapshot of the address space as a single hex encoded line, which
+/// can be pulled out of a console log and compared against a later one with
+/// `cargo xtask map_diff`
+fn print_snapshot(task: Option<CapabilityPtr>) {
+    let mut snapshot = vec![0; 256];
+    loop {
+        match snapshot_address_space(task, &mut snapshot) {
+            SyscallResult::Ok((written, total)) if written == total => {
+                snapshot.truncate(written);
+                break;
+            }
+            SyscallResult::Ok((_, total)) => snapshot.resize(total, 0),
+            SyscallResult::Err(e) => {
+                println!("pmap: failed to snapshot address space: {:?}", e);
+                return;
+            }
+        }
+    }
+
+    let hex = snapshot.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    println!("pmap-snapshot: {}", hex);
+}
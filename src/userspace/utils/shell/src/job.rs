@@ -0,0 +1,341 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Running programs from the initfs as jobs.
+//!
+//! A job is a pipeline of tasks, each with its stdout piped into the stdin of
+//! the next. The first task's stdin is a pipe the shell feeds lines from the
+//! console into while the job is in the foreground, and the last task's output
+//! goes wherever the shell's does. The console stays in raw mode the whole
+//! time, so `Ctrl-C` reaches the shell rather than the kernel's line
+//! discipline, and is forwarded to every task in the job as
+//! [`TaskEvent::Interrupt`].
+
+use crate::parse::Pipeline;
+use std::{
+    io::{PipeWriter, Write},
+    librust::{
+        capabilities::{CapabilityPtr, CapabilityRights},
+        message::SyscallResult,
+        syscalls::{
+            io::write_console_stream,
+            poll::{wait_many, WaitEvents, WaitItem},
+            read_stdin,
+            task::send_task_event,
+        },
+        task::TaskEvent,
+    },
+    process::ExitStatus,
+    registry,
+    vmspace::Vmspace,
+};
+
+/// How long the foreground job is waited on before checking the console for
+/// input again, since console input can't be waited on
+const FOREGROUND_POLL_US: usize = 10_000;
+
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+
+pub struct Jobs {
+    jobs: Vec<Job>,
+    /// Mapped from `init` the first time something is run
+    initfs: Option<tar::Archive<'static>>,
+}
+
+impl Jobs {
+    pub fn new() -> Self {
+        Self { jobs: Vec::new(), initfs: None }
+    }
+
+    /// Start `pipeline`, waiting for it to finish unless it's to be run in the
+    /// background
+    pub fn run(&mut self, command: &str, pipeline: Pipeline) {
+        let id = self.jobs.last().map(|job| job.id + 1).unwrap_or(1);
+        let job = match self.spawn(id, command, &pipeline) {
+            Ok(job) => job,
+            Err(e) => return println!("shell: {}", e),
+        };
+
+        match pipeline.background {
+            true => {
+                let tids: Vec<String> = job.tasks.iter().map(|task| task.tid.value().to_string()).collect();
+                println!("[{}] {}", job.id, tids.join(" "));
+                self.jobs.push(job);
+            }
+            false => foreground(job),
+        }
+    }
+
+    /// Report the background jobs which have finished since the last check,
+    /// and forget about them
+    pub fn reap(&mut self) {
+        self.jobs.retain_mut(|job| match job.wait(0) {
+            true => {
+                println!("[{}]  {}  {}", job.id, describe(job.status()), job.command);
+                false
+            }
+            false => true,
+        });
+    }
+
+    /// The `jobs` builtin
+    pub fn list(&mut self) {
+        self.reap();
+        for job in &self.jobs {
+            println!("[{}]  Running  {}", job.id, job.command);
+        }
+    }
+
+    /// The `fg` builtin, which brings the job with the given id, or the most
+    /// recent one, to the foreground
+    pub fn foreground(&mut self, args: &str) {
+        let index = match args.trim().trim_start_matches('%') {
+            "" => self.jobs.len().checked_sub(1),
+            id => id.parse().ok().and_then(|id: usize| self.jobs.iter().position(|job| job.id == id)),
+        };
+
+        match index {
+            Some(index) => {
+                let job = self.jobs.remove(index);
+                println!("{}", job.command);
+                foreground(job);
+            }
+            None => println!("fg: no such job"),
+        }
+    }
+
+    fn initfs(&mut self) -> Result<&tar::Archive<'static>, String> {
+        if self.initfs.is_none() {
+            let mut init = lifecycle::Init::connect().ok_or("no capability to init, so no programs to run")?;
+            let image = match init.initfs() {
+                Ok(Some(image)) => image,
+                Ok(None) => return Err(String::from("init has no initfs to run programs from")),
+                Err(e) => return Err(format!("couldn't get the initfs from init: {:?}", e)),
+            };
+
+            let archive = tar::Archive::new(image).map_err(|e| format!("the initfs isn't a tar archive: {:?}", e))?;
+            self.initfs = Some(archive);
+        }
+
+        Ok(self.initfs.as_ref().unwrap())
+    }
+
+    fn spawn(&mut self, id: usize, command: &str, pipeline: &Pipeline) -> Result<Job, String> {
+        let initfs = self.initfs()?;
+        let mut elfs = Vec::new();
+        for stage in &pipeline.stages {
+            let file = initfs.file(&stage[0]).ok_or_else(|| format!("{}: command not found", stage[0]))?;
+            elfs.push(loadelf::Elf::new(file.contents).ok_or_else(|| format!("{}: not an executable", stage[0]))?);
+        }
+
+        let mut job = Job { id, command: command.to_string(), tasks: Vec::new(), stdin: None };
+        let (mut next_stdin, stdin) = std::io::pipe().map_err(|e| format!("couldn't create a pipe: {:?}", e))?;
+        if !pipeline.background {
+            job.stdin = Some(stdin);
+        }
+
+        for (i, (stage, elf)) in pipeline.stages.iter().zip(&elfs).enumerate() {
+            let last = i == pipeline.stages.len() - 1;
+            let spawned = spawn_stage(stage, elf, next_stdin, last);
+            let (tid, cptr, stdout) = match spawned {
+                Ok(spawned) => spawned,
+                Err(e) => {
+                    job.signal(TaskEvent::Kill);
+                    return Err(e);
+                }
+            };
+
+            job.tasks.push(Task { tid, cptr, status: None });
+            match stdout {
+                Some(stdout) => next_stdin = stdout,
+                None => break,
+            }
+        }
+
+        Ok(job)
+    }
+}
+
+/// Spawn one command of a pipeline with its stdin bound to `stdin`, returning
+/// the read end of the pipe its stdout is bound to unless it's the `last`
+fn spawn_stage(
+    stage: &[String],
+    elf: &loadelf::Elf,
+    stdin: std::io::PipeReader,
+    last: bool,
+) -> Result<(Tid, CapabilityPtr, Option<std::io::PipeReader>), String> {
+    let name = &stage[0];
+    let (mut space, mut env) = loadelf::load_elf(name, elf).map_err(|_| format!("{}: couldn't load", name))?;
+    space.bind_stdin(stdin.cptr());
+
+    let stdout = match last {
+        true => None,
+        false => {
+            let (reader, writer) = std::io::pipe().map_err(|e| format!("couldn't create a pipe: {:?}", e))?;
+            space.bind_stdout(writer.cptr());
+            Some((reader, writer))
+        }
+    };
+
+    grant_capabilities(&mut space, last);
+
+    let args: Vec<&str> = stage[1..].iter().map(|arg| arg.as_str()).collect();
+    let (argc, argv) = space.create_args(&args).map_err(|e| format!("{}: couldn't pass arguments: {:?}", name, e))?;
+    env.a0 = argc;
+    env.a1 = argv;
+
+    let (tid, cptr) = space.spawn(env).map_err(|e| format!("{}: couldn't spawn: {:?}", name, e))?;
+
+    // The new task has its own copies of the pipe ends now, so dropping ours
+    // leaves it the only one which can close them
+    Ok((tid, cptr, stdout.map(|(reader, _)| reader)))
+}
+
+/// Pass on the capabilities programs expect to be given, except the channel
+/// to the `stdio` server for tasks whose output is piped, since output only
+/// goes to their stdout when they don't have one
+fn grant_capabilities(space: &mut Vmspace, stdio: bool) {
+    let rights = CapabilityRights::READ | CapabilityRights::WRITE;
+    let caps = [("stdio", rights), ("system", rights), (registry::SERVICE_NAME, rights | CapabilityRights::GRANT)];
+
+    for (name, rights) in caps {
+        if name == "stdio" && !stdio {
+            continue;
+        }
+
+        if let Some(cptr) = std::env::lookup_capability(name) {
+            space.grant(name, cptr, rights);
+        }
+    }
+}
+
+struct Job {
+    id: usize,
+    command: String,
+    tasks: Vec<Task>,
+    /// Where console input goes while the job is in the foreground, which is
+    /// closed once `Ctrl-D` is pressed. Background jobs see the end of their
+    /// input straight away.
+    stdin: Option<PipeWriter>,
+}
+
+struct Task {
+    tid: Tid,
+    /// The channel to the task, which closes when it exits
+    cptr: CapabilityPtr,
+    status: Option<ExitStatus>,
+}
+
+impl Job {
+    /// Wait up to `timeout_us` for any of the job's tasks to exit, returning
+    /// whether all of them have
+    fn wait(&mut self, timeout_us: usize) -> bool {
+        let mut items: Vec<WaitItem> = self
+            .tasks
+            .iter()
+            .filter(|task| task.status.is_none())
+            .map(|task| WaitItem::new(task.cptr, WaitEvents::CLOSED))
+            .collect();
+
+        if items.is_empty() {
+            return true;
+        }
+
+        // Without being able to wait on all of them at once, fall back to
+        // waiting on each in turn
+        if let SyscallResult::Err(_) = wait_many(&mut items, timeout_us) {
+            items.iter_mut().for_each(|item| item.ready = WaitEvents::CLOSED);
+        }
+
+        for item in items.iter().filter(|item| item.ready & WaitEvents::CLOSED) {
+            if let Some(task) = self.tasks.iter_mut().find(|task| task.cptr == item.cptr) {
+                task.status = Some(std::process::wait(item.cptr).unwrap_or(ExitStatus::Killed));
+            }
+        }
+
+        self.tasks.iter().all(|task| task.status.is_some())
+    }
+
+    /// The job's status is that of its last task, like other shells
+    fn status(&self) -> ExitStatus {
+        self.tasks.last().and_then(|task| task.status).unwrap_or(ExitStatus::Killed)
+    }
+
+    fn signal(&self, event: TaskEvent) {
+        for task in self.tasks.iter().filter(|task| task.status.is_none()) {
+            let _ = send_task_event(task.cptr, event);
+        }
+    }
+
+    /// Handle a byte of console input while the job is in the foreground,
+    /// echoing it and sending `line` to the job once it's complete
+    fn input(&mut self, byte: u8, line: &mut Vec<u8>) {
+        match byte {
+            CTRL_C => {
+                println!("^C");
+                line.clear();
+                self.signal(TaskEvent::Interrupt);
+            }
+            CTRL_D => {
+                self.send(line);
+                self.stdin = None;
+            }
+            b'\r' | b'\n' => {
+                println!();
+                line.push(b'\n');
+                self.send(line);
+            }
+            0x7F | 0x08 => {
+                if line.pop().is_some() {
+                    print!("\x1B[1D \x1B[1D");
+                }
+            }
+            _ => {
+                let _ = std::io::stdout().write(&[byte]);
+                line.push(byte);
+            }
+        }
+    }
+
+    /// Input is dropped rather than blocking the shell if the job isn't
+    /// reading it, so `Ctrl-C` still gets through
+    fn send(&mut self, line: &mut Vec<u8>) {
+        if let Some(stdin) = &self.stdin {
+            let _ = write_console_stream(stdin.cptr(), line);
+        }
+
+        line.clear();
+    }
+}
+
+/// Run `job` until all of its tasks have exited, passing it console input
+fn foreground(mut job: Job) {
+    let mut line = Vec::new();
+    while !job.wait(FOREGROUND_POLL_US) {
+        let mut input = [0; 64];
+        if let SyscallResult::Ok(n) = read_stdin(&mut input) {
+            for &byte in &input[..n] {
+                job.input(byte, &mut line);
+            }
+        }
+    }
+
+    let status = job.status();
+    if !status.success() {
+        println!("{}", describe(status));
+    }
+}
+
+fn describe(status: ExitStatus) -> String {
+    match status {
+        ExitStatus::Exited(0) => String::from("Done"),
+        ExitStatus::Exited(code) => format!("Exit {}", code),
+        ExitStatus::Killed => String::from("Killed"),
+        ExitStatus::Faulted { crash_id } => format!("Faulted (crash {})", crash_id),
+    }
+}
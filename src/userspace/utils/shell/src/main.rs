@@ -9,7 +9,11 @@
 
 extern crate alloc;
 
+mod job;
+mod parse;
+
 use core::num::NonZeroUsize;
+use std::io::Read;
 use std::ipc::IpcChannel;
 use std::librust::message::SyscallResult;
 use std::librust::syscalls::*;
@@ -24,8 +28,14 @@ fn main() {
     let mut history_index = None;
     let mut curr_history: Option<&str> = None;
     let channels: Vec<IpcChannel> = Vec::new();
+    let mut jobs = job::Jobs::new();
+
+    // Line editing and history are handled here, as is `Ctrl-C` for jobs in
+    // the foreground
+    let _ = io::set_console_mode(io::ConsoleMode::Raw);
 
     loop {
+        jobs.reap();
         print!("vanadinite> ");
 
         if let Some(cmd) = &curr_history {
@@ -83,103 +93,175 @@ fn main() {
             cmd => cmd,
         };
 
-        let (cmd, args) = cmd.split_once(' ').unwrap_or((cmd, ""));
+        match parse::parse(cmd) {
+            Ok(pipeline) if pipeline.stages.is_empty() => continue,
+            Ok(pipeline) if pipeline.is_single() && builtin(&pipeline.stages[0][0], cmd, &mut jobs, &channels) => {}
+            Ok(pipeline) => jobs.run(cmd, pipeline),
+            Err(e) => println!("shell: {}", e),
+        }
 
-        match cmd {
-            "echo" => println!("{}", args),
-            "yeet" => {
-                println!("Asking the kernel to print some of its memory!");
-                let kresult = print(unsafe { core::slice::from_raw_parts(0xffffffc000000000 as *mut u8, 1024) });
-                println!("Kernel responded with: {:?}", kresult);
+        if history.front() != Some(&cmd_str) {
+            history.push_front(cmd_str);
+        }
+        history_index = None;
+        curr_history = None;
+    }
+}
+
+/// Run `cmd` if it's a builtin, returning whether it was. Builtins get the rest
+/// of the line as it was typed rather than parsed into words.
+fn builtin(cmd: &str, line: &str, jobs: &mut job::Jobs, channels: &[IpcChannel]) -> bool {
+    let args = line.trim_start().split_once(char::is_whitespace).map(|(_, args)| args).unwrap_or("");
+
+    match cmd {
+        "echo" => println!("{}", args),
+        "yeet" => {
+            println!("Asking the kernel to print some of its memory!");
+            let kresult = print(unsafe { core::slice::from_raw_parts(0xffffffc000000000 as *mut u8, 1024) });
+            println!("Kernel responded with: {:?}", kresult);
+        }
+        "send" => match args.trim().parse::<usize>() {
+            Ok(0) | Err(_) => {
+                println!("Need valid TID :(")
             }
-            "send" => match args.trim().parse::<usize>() {
-                Ok(0) | Err(_) => {
-                    println!("Need valid TID :(")
-                }
-                Ok(tidn) => {
-                    let tid = Tid::new(NonZeroUsize::new(tidn).unwrap());
-                    let ret = send_message(tid, Message { contents: [0; 13] });
+            Ok(tidn) => {
+                let tid = Tid::new(NonZeroUsize::new(tidn).unwrap());
+                let ret = send_message(tid, Message { contents: [0; 13] });
 
-                    match ret {
-                        SyscallResult::Ok(_) => println!("Message sent to TID {}!", tidn),
-                        SyscallResult::Err(e) => println!("Couldn't send message: {:?}", e),
-                    }
+                match ret {
+                    SyscallResult::Ok(_) => println!("Message sent to TID {}!", tidn),
+                    SyscallResult::Err(e) => println!("Couldn't send message: {:?}", e),
+                }
+            }
+        },
+        "read" => println!("We had a message! {:?}", receive_message()),
+        "fsck" => fsck(args.trim() == "--repair"),
+        "shutdown" => shutdown(false),
+        "reboot" => shutdown(true),
+        "log" => read_log(args.trim()),
+        "test_alloc_mem" => match alloc_virtual_memory(
+            4096,
+            AllocationOptions::None,
+            MemoryPermissions::READ | MemoryPermissions::WRITE,
+        ) {
+            SyscallResult::Ok(ptr) => {
+                println!("Kernel returned us address: {:#p}", ptr);
+                println!("Testing read/write...");
+
+                for i in 0..4096 {
+                    unsafe { *ptr.add(i) = ((i as u8) % (126 - 32)) + 32 };
                 }
-            },
-            "read" => println!("We had a message! {:?}", receive_message()),
-            "test_alloc_mem" => match alloc_virtual_memory(
-                4096,
-                AllocationOptions::None,
-                MemoryPermissions::READ | MemoryPermissions::WRITE,
-            ) {
-                SyscallResult::Ok(ptr) => {
-                    println!("Kernel returned us address: {:#p}", ptr);
-                    println!("Testing read/write...");
-
-                    for i in 0..4096 {
-                        unsafe { *ptr.add(i) = ((i as u8) % (126 - 32)) + 32 };
-                    }
 
-                    for i in 0..(4096 / 256) {
-                        for c in 0..256 {
-                            unsafe { print!("{}", *ptr.add(i * 256 + c) as char) };
-                        }
-                        println!();
+                for i in 0..(4096 / 256) {
+                    for c in 0..256 {
+                        unsafe { print!("{}", *ptr.add(i * 256 + c) as char) };
                     }
+                    println!();
                 }
-                SyscallResult::Err(e) => println!("Kernel returned error: {:?}", e),
-            },
-            "test_std_allocator" => {
-                println!("Testing Box...");
-                let mut b = Box::new(5u32);
-                *b = 6;
-                println!("    *b = {}", b);
-
-                println!("Testing Vec...");
-                let mut v = Vec::new();
-
-                for i in 0..100usize {
-                    v.push(i);
-                }
-                println!("    v.len() = {}", v.len());
             }
-            "test_guard_page" => unsafe {
-                let sp: *mut u8;
-                core::arch::asm!("mv {}, sp", out(reg) sp);
-
-                *(sp.add(4096)) = 0;
-            },
-            "test_large_page_alloc" => unsafe {
-                let _ = alloc::alloc::alloc(alloc::alloc::Layout::from_size_align(32768, 8).unwrap());
-            },
-            "tp" => {
-                let tp: usize;
-                unsafe { core::arch::asm!("mv {}, tp", out(reg) tp) };
-
-                println!("tp={:#p}", tp as *mut u8);
+            SyscallResult::Err(e) => println!("Kernel returned error: {:?}", e),
+        },
+        "test_std_allocator" => {
+            println!("Testing Box...");
+            let mut b = Box::new(5u32);
+            *b = 6;
+            println!("    *b = {}", b);
+
+            println!("Testing Vec...");
+            let mut v = Vec::new();
+
+            for i in 0..100usize {
+                v.push(i);
             }
-            "tid" => {
-                println!("Our TID is {}", current_tid().value())
-            }
-            "where_main" => println!("main is at: {:#p}", main as *mut u8),
-            "read_channels" => {
-                for channel in &channels {
-                    if let Ok((msg, _)) = channel.read_with_all_caps() {
-                        match core::str::from_utf8(msg.as_bytes()) {
-                            Err(_) => println!("A message! Contents: {:?}", msg.as_bytes()),
-                            Ok(s) => println!("A message! It says: {}", s),
-                        }
+            println!("    v.len() = {}", v.len());
+        }
+        "test_guard_page" => unsafe {
+            let sp: *mut u8;
+            core::arch::asm!("mv {}, sp", out(reg) sp);
+
+            *(sp.add(4096)) = 0;
+        },
+        "test_large_page_alloc" => unsafe {
+            let _ = alloc::alloc::alloc(alloc::alloc::Layout::from_size_align(32768, 8).unwrap());
+        },
+        "tp" => {
+            let tp: usize;
+            unsafe { core::arch::asm!("mv {}, tp", out(reg) tp) };
+
+            println!("tp={:#p}", tp as *mut u8);
+        }
+        "tid" => {
+            println!("Our TID is {}", current_tid().value())
+        }
+        "where_main" => println!("main is at: {:#p}", main as *mut u8),
+        "read_channels" => {
+            for channel in channels {
+                if let Ok((msg, _)) = channel.read_with_all_caps() {
+                    match core::str::from_utf8(msg.as_bytes()) {
+                        Err(_) => println!("A message! Contents: {:?}", msg.as_bytes()),
+                        Ok(s) => println!("A message! It says: {}", s),
                     }
                 }
             }
-            _ => println!("unknown command :("),
         }
+        "jobs" => jobs.list(),
+        "fg" => jobs.foreground(args),
+        _ => return false,
+    }
 
-        if history.front() != Some(&cmd_str) {
-            history.push_front(cmd_str);
+    true
+}
+
+fn fsck(repair: bool) {
+    let mut filesystem = match fs::Filesystem::connect() {
+        Some(filesystem) => filesystem,
+        None => return println!("No capability to the filesystem service :("),
+    };
+
+    match filesystem.check(repair) {
+        Ok(report) => {
+            for problem in &report.problems {
+                println!("{}", problem);
+            }
+
+            println!(
+                "{} file(s), {} directories, {} cluster(s) used, {} problem(s){}",
+                report.files,
+                report.directories,
+                report.used_clusters,
+                report.problems.len(),
+                if report.repaired { ", repaired" } else { "" },
+            );
+
+            if !report.was_clean {
+                println!("The volume wasn't unmounted cleanly");
+            }
         }
-        history_index = None;
-        curr_history = None;
+        Err(e) => println!("Couldn't check the filesystem: {:?}", e),
+    }
+}
+
+fn shutdown(reboot: bool) {
+    let mut init = match lifecycle::Init::connect() {
+        Some(init) => init,
+        None => return println!("No capability to init :("),
+    };
+
+    if let Err(e) = init.shutdown(reboot) {
+        println!("Couldn't shut down: {:?}", e);
+    }
+}
+
+fn read_log(service: &str) {
+    let mut init = match lifecycle::Init::connect() {
+        Some(init) => init,
+        None => return println!("No capability to init :("),
+    };
+
+    match init.read_log(service) {
+        Ok(Some(log)) => print!("{}", log),
+        Ok(None) => println!("No log for {}", service),
+        Err(e) => println!("Couldn't read the log: {:?}", e),
     }
 }
 
@@ -203,14 +285,12 @@ fn read_input(current_cmd: Option<&str>) -> Option<Input> {
     let mut read = 0;
 
     while read < max_len {
-        let mut c = [0u8];
-        while let SyscallResult::Ok(0) = read_stdin(&mut c[..]) {}
+        let c = [read_byte()];
 
         if c[0] == b'\x1B' {
             let mut ctrl_seq = [b'\x1B', 0, 0];
             for byte in &mut ctrl_seq[1..] {
-                while let SyscallResult::Ok(0) = read_stdin(&mut c[..]) {}
-                *byte = c[0];
+                *byte = read_byte();
             }
 
             return match &ctrl_seq {
@@ -222,6 +302,12 @@ fn read_input(current_cmd: Option<&str>) -> Option<Input> {
 
         match c[0] {
             b'\r' => break,
+            // `Ctrl-C` isn't intercepted in raw mode, treat it as discarding
+            // the line
+            0x03 => {
+                println!("^C");
+                return None;
+            }
             0x7F if !buf.is_empty() => {
                 print!("\x1B[1D \x1B[1D");
                 read -= 1;
@@ -241,6 +327,13 @@ fn read_input(current_cmd: Option<&str>) -> Option<Input> {
     Some(Input::Command(buf))
 }
 
+/// Block until there's a byte of console input
+fn read_byte() -> u8 {
+    let mut byte = [0];
+    while let Ok(0) = std::io::stdin().read(&mut byte) {}
+    byte[0]
+}
+
 fn clear_line() {
     print!("\x1B[2K\x1B[1G");
 }
@@ -0,0 +1,73 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+/// A command line: one or more commands with the output of each piped into the
+/// next, e.g. `log network | grep dhcp &`
+#[derive(Debug)]
+pub struct Pipeline {
+    /// Each command's name followed by its arguments
+    pub stages: Vec<Vec<String>>,
+    /// Ended with `&`, so the shell doesn't wait for it
+    pub background: bool,
+}
+
+impl Pipeline {
+    /// Whether this could be a builtin, which can't be piped or backgrounded
+    pub fn is_single(&self) -> bool {
+        self.stages.len() == 1 && !self.background
+    }
+}
+
+/// Split `line` into words on whitespace, except inside `'...'` or `"..."`,
+/// and into commands on `|`. A trailing `&` runs the pipeline in the
+/// background.
+pub fn parse(line: &str) -> Result<Pipeline, String> {
+    let mut stages = Vec::new();
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut background = false;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        if background && !c.is_whitespace() {
+            return Err(String::from("`&` can only end a command line"));
+        }
+
+        match c {
+            '\'' | '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some(end) if end == c => break,
+                        Some(other) => word.push(other),
+                        None => return Err(format!("unterminated `{}`", c)),
+                    }
+                }
+            }
+            '|' | '&' => {
+                words.extend(word.take());
+                if words.is_empty() {
+                    return Err(format!("missing command before `{}`", c));
+                }
+
+                stages.push(core::mem::take(&mut words));
+                background = c == '&';
+            }
+            c if c.is_whitespace() => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    words.extend(word);
+    if !words.is_empty() {
+        stages.push(words);
+    } else if !stages.is_empty() && !background {
+        return Err(String::from("missing command after `|`"));
+    }
+
+    Ok(Pipeline { stages, background })
+}
@@ -0,0 +1,28 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use metrics::Metrics;
+
+fn main() {
+    let mut metrics = match Metrics::connect() {
+        Some(metrics) => metrics,
+        None => return println!("stats: no capability to `metricsmgr`"),
+    };
+
+    // `stats <prefix>` only displays the metrics starting with `prefix`
+    let prefix = std::env::args().first().copied().unwrap_or_default();
+    let samples = match metrics.query(prefix) {
+        Ok(samples) => samples,
+        Err(e) => return println!("stats: failed to query metrics: {:?}", e),
+    };
+
+    let width = samples.iter().map(|sample| sample.name.len()).max().unwrap_or(0).max("name".len());
+    println!("{:<width$} {:<7} {:>20}", "name", "kind", "value", width = width);
+    for sample in &samples {
+        println!("{:<width$} {:<7} {:>20}", sample.name, sample.kind, sample.value, width = width);
+    }
+}
@@ -0,0 +1,298 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Soak tests the scheduler, allocator, and IPC by running a mix of workers at
+//! once, each checking that what it did came out right:
+//!
+//! - `memory`: churns through heap allocations of random sizes, checking that
+//!   each one still holds what was written to it when it's freed
+//! - `ipc`: round trips to the `registry` service
+//! - `faults`: spawns tasks which immediately fault, and checks they're killed
+//! - `spawn`: spawns tasks which immediately exit, and checks their exit code
+//!
+//! `stress [memory=N] [ipc=N] [faults=N] [spawn=N] [iterations=N] [seed=N]`
+//! sets how many of each worker to run, and how many operations each does. It
+//! prints `stress: PASS` and exits with `0` if every worker succeeded, or
+//! `stress: FAIL` and exits with `1` otherwise.
+
+use std::{
+    env::RegistryError,
+    librust::{
+        syscalls::{allocation::MemoryPermissions, vmspace::VmspaceSpawnEnv, Syscall},
+        time::monotonic_ns,
+    },
+    process::ExitStatus,
+    vmspace::Vmspace,
+};
+
+const PAGE_SIZE: usize = 4096;
+/// Allocations each memory worker keeps alive at once
+const LIVE_ALLOCATIONS: usize = 32;
+/// Largest allocation a memory worker makes, big enough that some of them are
+/// given fresh pages rather than coming from the heap's free lists
+const MAX_ALLOCATION: usize = 256 * 1024;
+
+/// How many operations a worker did, or why it failed
+type WorkerResult = Result<usize, String>;
+
+struct Config {
+    memory: usize,
+    ipc: usize,
+    faults: usize,
+    spawn: usize,
+    iterations: usize,
+    seed: u64,
+}
+
+impl Config {
+    fn parse(args: &[&str]) -> Result<Self, String> {
+        let mut config = Config { memory: 2, ipc: 1, faults: 1, spawn: 1, iterations: 200, seed: monotonic_ns() | 1 };
+
+        for arg in args {
+            let (key, value) = arg.split_once('=').ok_or_else(|| format!("expected `key=value`, got `{}`", arg))?;
+            let value: u64 = value.parse().map_err(|_| format!("`{}` isn't a number", value))?;
+
+            match key {
+                "memory" => config.memory = value as usize,
+                "ipc" => config.ipc = value as usize,
+                "faults" => config.faults = value as usize,
+                "spawn" => config.spawn = value as usize,
+                "iterations" => config.iterations = value as usize,
+                "seed" => config.seed = value,
+                _ => return Err(format!("unknown option `{}`", key)),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+fn main() {
+    let config = match Config::parse(std::env::args()) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("stress: {}", e);
+            println!("usage: stress [memory=N] [ipc=N] [faults=N] [spawn=N] [iterations=N] [seed=N]");
+            std::process::exit(2);
+        }
+    };
+
+    println!(
+        "stress: {} memory, {} ipc, {} fault, and {} spawn workers, {} iterations each, seed {}",
+        config.memory, config.ipc, config.faults, config.spawn, config.iterations, config.seed
+    );
+
+    let start = monotonic_ns();
+    let iterations = config.iterations;
+    let mut workers = Vec::new();
+
+    for i in 0..config.memory {
+        let seed = config.seed.wrapping_add(i as u64);
+        workers.push((format!("memory#{}", i), std::thread::spawn(move || memory_churn(iterations, seed))));
+    }
+
+    for i in 0..config.faults {
+        workers.push((format!("faults#{}", i), std::thread::spawn(move || fault_storm(iterations))));
+    }
+
+    for i in 0..config.spawn {
+        workers.push((format!("spawn#{}", i), std::thread::spawn(move || spawn_exit(iterations))));
+    }
+
+    // Capabilities belong to the thread they were given to, so only the main
+    // thread has a channel to the registry. IPC workers take turns on it while
+    // the others run.
+    let mut results = Vec::new();
+    for i in 0..config.ipc {
+        results.push((format!("ipc#{}", i), ping_pong(iterations)));
+    }
+
+    // A worker which panicked has already printed why
+    let joined = workers.into_iter().map(|(name, worker)| (name, worker.join().unwrap_or(Err("panicked".into()))));
+    results.extend(joined);
+
+    let mut operations = 0;
+    let mut failed = 0;
+    for (name, result) in &results {
+        match result {
+            Ok(n) => {
+                println!("stress: {:<10} ok, {} operations", name, n);
+                operations += n;
+            }
+            Err(e) => {
+                println!("stress: {:<10} FAILED: {}", name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    let elapsed_ms = (monotonic_ns() - start) / 1_000_000;
+    match failed {
+        0 => println!("stress: PASS, {} operations in {} ms", operations, elapsed_ms),
+        _ => {
+            println!("stress: FAIL, {} of {} workers failed after {} ms", failed, results.len(), elapsed_ms);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn memory_churn(iterations: usize, seed: u64) -> WorkerResult {
+    let mut rng = XorShift(seed | 1);
+    let mut live: Vec<Option<(u8, Vec<u8>)>> = (0..LIVE_ALLOCATIONS).map(|_| None).collect();
+
+    for i in 0..iterations {
+        let slot = rng.next() as usize % LIVE_ALLOCATIONS;
+        if let Some((pattern, buffer)) = live[slot].take() {
+            if let Some(offset) = buffer.iter().position(|&byte| byte != pattern) {
+                return Err(format!(
+                    "allocation of {} bytes was overwritten at offset {} by iteration {}",
+                    buffer.len(),
+                    offset,
+                    i
+                ));
+            }
+        }
+
+        let size = 1 + rng.next() as usize % MAX_ALLOCATION;
+        let pattern = rng.next() as u8;
+        live[slot] = Some((pattern, vec![pattern; size]));
+    }
+
+    Ok(iterations)
+}
+
+fn ping_pong(iterations: usize) -> WorkerResult {
+    for i in 0..iterations {
+        // Nothing is registered under this name, so every lookup goes to the
+        // registry and comes back empty
+        match std::env::lookup_service("stress-nonexistent") {
+            Err(RegistryError::NotFound) => {}
+            Err(RegistryError::NoRegistry) => return Err(String::from("no channel to the registry")),
+            result => return Err(format!("lookup {} returned {:?}", i, result)),
+        }
+    }
+
+    Ok(iterations)
+}
+
+fn fault_storm(iterations: usize) -> WorkerResult {
+    for i in 0..iterations {
+        let payload = match i % 2 {
+            0 => Payload::LoadFault,
+            _ => Payload::IllegalInstruction,
+        };
+
+        match run(payload, 0)? {
+            ExitStatus::Faulted { .. } => {}
+            status => return Err(format!("task {} ended with {:?} instead of faulting", i, status)),
+        }
+    }
+
+    Ok(iterations)
+}
+
+fn spawn_exit(iterations: usize) -> WorkerResult {
+    for i in 0..iterations {
+        let code = i % 256;
+        match run(Payload::Exit, code)? {
+            ExitStatus::Exited(exited) if exited == code => {}
+            status => return Err(format!("task {} ended with {:?} instead of exiting with {}", i, status, code)),
+        }
+    }
+
+    Ok(iterations)
+}
+
+/// What a task spawned with [`run`] does
+enum Payload {
+    /// Exit with the code it was given
+    Exit,
+    LoadFault,
+    IllegalInstruction,
+}
+
+/// Spawn a task running nothing but `payload`, and wait for it to end
+fn run(payload: Payload, a0: usize) -> Result<ExitStatus, String> {
+    let code = program(payload);
+    let vmspace = Vmspace::new("stress-child");
+    let pc = {
+        let mut object = vmspace
+            .create_object(core::ptr::null(), PAGE_SIZE, MemoryPermissions::READ | MemoryPermissions::EXECUTE)
+            .map_err(|e| format!("failed to allocate code: {:?}", e))?;
+
+        for (bytes, instruction) in object.as_slice().chunks_exact_mut(4).zip(&code) {
+            bytes.copy_from_slice(&instruction.to_le_bytes());
+        }
+
+        object.vmspace_address() as usize
+    };
+
+    let env = VmspaceSpawnEnv { pc, a0, a1: 0, a2: 0, sp: 0, tp: 0, tls_size: 0, tls_align: 0 };
+    let (_, task) = vmspace.spawn(env).map_err(|e| format!("failed to spawn: {:?}", e))?;
+
+    std::process::wait(task).map_err(|e| format!("failed to wait for task: {:?}", e))
+}
+
+const ZERO: u32 = 0;
+const T0: u32 = 5;
+const T2: u32 = 7;
+const S0: u32 = 8;
+const A0: u32 = 10;
+const T3: u32 = 28;
+const T4: u32 = 29;
+const T5: u32 = 30;
+const ECALL: u32 = 0x0000_0073;
+
+/// `addi rd, rs, imm`, which is also `li` and `mv`
+fn addi(rd: u32, rs: u32, imm: u32) -> u32 {
+    (imm << 20) | (rs << 15) | (rd << 7) | 0b0010011
+}
+
+/// `ld rd, 0(rs)`
+fn ld(rd: u32, rs: u32) -> u32 {
+    (rs << 15) | (0b011 << 12) | (rd << 7) | 0b0000011
+}
+
+/// Machine code for a task running `payload`, small enough that spawning it
+/// doesn't need an ELF to load
+fn program(payload: Payload) -> Vec<u32> {
+    let mut code = vec![
+        // The exit code is passed in `a0`, which the syscall below overwrites
+        addi(S0, A0, 0),
+        // Wait for the message `Vmspace::spawn` sends once it's done with the
+        // new task, which fails if the task has already gone
+        addi(T0, ZERO, 0),
+        addi(T2, ZERO, Syscall::ReadChannel as u32),
+        addi(T3, ZERO, 0),
+        addi(T4, ZERO, 0),
+        addi(T5, ZERO, 0),
+        ECALL,
+    ];
+
+    match payload {
+        Payload::Exit => code.extend([addi(T0, ZERO, 0), addi(T2, ZERO, Syscall::Exit as u32), addi(T3, S0, 0), ECALL]),
+        Payload::LoadFault => code.push(ld(T0, ZERO)),
+        // All zeroes is defined to be an illegal instruction
+        Payload::IllegalInstruction => code.push(0),
+    }
+
+    code
+}
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+
+        x
+    }
+}
@@ -0,0 +1,65 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Prints the kernel's trace records, which are recorded when enabled with the
+//! `trace=` bootarg. They're printed as hex encoded lines to keep them small
+//! over the serial console, and can be turned into a timeline by running
+//! `cargo xtask decode` on the console log.
+
+use std::librust::{
+    message::SyscallResult,
+    syscalls::trace::{read_trace, TraceRecord},
+};
+
+/// Records read and printed at a time, which keeps each line under 13 KiB
+const BATCH_SIZE: usize = 128;
+
+fn main() {
+    let system = match std::env::lookup_capability("system") {
+        Some(cptr) => cptr,
+        None => {
+            println!("trace: no system control capability");
+            return;
+        }
+    };
+
+    let mut records = vec![TraceRecord::default(); BATCH_SIZE];
+    let mut total = 0;
+    loop {
+        let (n, dropped) = match read_trace(system, &mut records) {
+            SyscallResult::Ok(read) => read,
+            SyscallResult::Err(e) => {
+                println!("trace: failed to read trace records: {:?}", e);
+                return;
+            }
+        };
+
+        if n == 0 && dropped == 0 {
+            break;
+        }
+
+        let hex = records[..n]
+            .iter()
+            .flat_map(|record| {
+                [record.timestamp as usize, record.event, record.hart, record.tid, record.args[0], record.args[1]]
+            })
+            .flat_map(usize::to_le_bytes)
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        println!("trace-records: {} {}", dropped, hex);
+        total += n;
+
+        if n < BATCH_SIZE {
+            break;
+        }
+    }
+
+    if total == 0 {
+        println!("no trace records, is tracing enabled with the `trace=` bootarg?");
+    }
+}
@@ -0,0 +1,65 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::librust::{message::SyscallResult, syscalls::system::system_info};
+
+fn main() {
+    let info = match system_info() {
+        SyscallResult::Ok(info) => info,
+        SyscallResult::Err(e) => {
+            println!("uname: failed to query system info: {:?}", e);
+            return;
+        }
+    };
+
+    let mut all = false;
+    let mut release = false;
+    let mut version = false;
+    let mut machine = false;
+
+    for arg in std::env::args() {
+        match *arg {
+            "-a" => all = true,
+            "-r" => release = true,
+            "-v" => version = true,
+            "-m" => machine = true,
+            _ => {}
+        }
+    }
+
+    let mut parts = vec![String::from("vanadinite")];
+
+    if all || release {
+        parts.push(info.version.as_str().into());
+    }
+
+    if all || version {
+        parts.push(format!("{} ({})", info.commit, info.profile.as_str()));
+    }
+
+    if all || machine {
+        parts.push(info.platform.as_str().into());
+    }
+
+    if all {
+        let seconds = info.uptime_us / 1_000_000;
+        let millis = (info.uptime_us / 1000) % 1000;
+        parts.push(format!("{} harts, up {}.{:03}s", info.n_harts, seconds, millis));
+
+        if info.cpu_freq_hz != 0 {
+            parts.push(format!("{} MHz", info.cpu_freq_hz / 1_000_000));
+        }
+
+        for temperature in info.temperatures() {
+            parts.push(format!("{}.{}C", temperature / 1000, (temperature % 1000).abs() / 100));
+        }
+
+        parts.push(format!("last reset: {}", info.last_reset.as_str()));
+    }
+
+    println!("{}", parts.join(" "));
+}
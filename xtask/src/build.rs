@@ -5,7 +5,7 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::{Result, VanadiniteBuildOptions};
+use crate::{Result, UserspaceBuildOptions, VanadiniteBuildOptions};
 use anyhow::Context;
 use clap::{ArgEnum, Subcommand};
 use std::fs;
@@ -41,13 +41,13 @@ pub enum BuildTarget {
     /// The RISC-V ISA simulator
     Spike,
     /// Userspace applications for the `vanadinite` kernel to use on boot
-    Userspace,
+    Userspace(UserspaceBuildOptions),
 }
 
 impl BuildTarget {
     fn dependencies(&self) -> Vec<Self> {
         match self {
-            BuildTarget::Vanadinite(_) => vec![BuildTarget::Userspace],
+            BuildTarget::Vanadinite(args) => vec![BuildTarget::Userspace(args.userspace.clone())],
             BuildTarget::OpenSBI(args) /* | BuildTarget::Vanadium(args) */ => vec![BuildTarget::Vanadinite(args.clone())],
             _ => vec![],
         }
@@ -57,11 +57,31 @@ impl BuildTarget {
 impl BuildTarget {
     pub fn env(&self) -> Vec<xshell::Pushenv> {
         match self {
-            BuildTarget::Userspace => vec![],
-            BuildTarget::Vanadinite(opts) => vec![pushenv(
-                "RUSTFLAGS",
-                format!("-C code-model=medium -C link-arg=-Tvanadinite/lds/{}.lds", opts.platform),
-            )],
+            BuildTarget::Userspace(_) => vec![],
+            BuildTarget::Vanadinite(opts) => {
+                // A flag per edge, and a table of their addresses for mapping
+                // them back to functions, see `src/kernel/vanadinite/src/coverage.rs`
+                let coverage = match opts.coverage {
+                    true => concat!(
+                        " -C passes=sancov-module",
+                        " -C llvm-args=-sanitizer-coverage-level=3",
+                        " -C llvm-args=-sanitizer-coverage-inline-bool-flag",
+                        " -C llvm-args=-sanitizer-coverage-pc-table",
+                    ),
+                    false => "",
+                };
+
+                vec![
+                    pushenv(
+                        "RUSTFLAGS",
+                        format!(
+                            "-C code-model=medium -C force-frame-pointers=yes -C link-arg=-Tvanadinite/lds/{}.lds{}",
+                            opts.platform, coverage
+                        ),
+                    ),
+                    pushenv("VANADINITE_COMMIT", git_commit()),
+                ]
+            }
             BuildTarget::Vanadium(opts) => {
                 vec![pushenv("RUSTFLAGS", format!("-C code-model=medium -C link-arg=-Tlds/{}.lds", opts.platform))]
             }
@@ -72,6 +92,12 @@ impl BuildTarget {
     }
 }
 
+/// The commit hash of the current checkout, or `unknown` if it can't be
+/// determined
+pub fn git_commit() -> String {
+    cmd!("git rev-parse --short HEAD").read().unwrap_or_else(|_| String::from("unknown"))
+}
+
 pub fn build(target: BuildTarget) -> Result<()> {
     mkdir_p("build/").context("failed to make build directory")?;
 
@@ -82,26 +108,49 @@ pub fn build(target: BuildTarget) -> Result<()> {
     let _env = target.env();
 
     match target {
-        BuildTarget::Userspace => {
+        BuildTarget::Userspace(build_opts) => {
+            // Stack canaries are always enabled in `src/userspace/.cargo/config.toml`,
+            // the shadow call stack needs `std` to set it up as well
+            let hardening = match build_opts.shadow_call_stack {
+                true => &[
+                    "--features",
+                    "std/shadow-call-stack",
+                    "--config",
+                    r#"target.riscv64gc-unknown-none-elf.rustflags=["-Z", "sanitizer=shadow-call-stack"]"#,
+                ][..],
+                false => &[][..],
+            };
+
             let init_tar = std::env::current_dir()?.join("build/initfs.tar");
+            let init_tar_lz4 = init_tar.with_extension("tar.lz4");
 
             rm_rf(&init_tar)?;
+            rm_rf(&init_tar_lz4)?;
 
             let _dir = pushd("src/userspace")?;
-            cmd!("cargo build --release --workspace --target riscv64gc-unknown-none-elf").run()?;
+            cmd!("cargo build --release --workspace --target riscv64gc-unknown-none-elf {hardening...}").run()?;
 
-            let out = fs::File::create(init_tar)?;
+            let out = fs::File::create(&init_tar)?;
             let mut archive = Builder::new(out);
 
-            for (bin, path) in walkdir::WalkDir::new("target/riscv64gc-unknown-none-elf/release/")
+            let binaries = walkdir::WalkDir::new("target/riscv64gc-unknown-none-elf/release/")
                 .max_depth(1)
                 .into_iter()
                 .filter_entry(|e| !e.file_name().to_str().map(|s| s.starts_with('.')).unwrap_or(false))
                 .filter_map(|e| e.ok())
                 .map(|e| e.into_path())
-                .filter(|e| e.is_file() && e.extension().is_none())
-                .map(|p| (fs::read(&p), p))
-            {
+                .filter(|e| e.is_file() && e.extension().is_none());
+
+            // Extra files (e.g. the `configmgr` config file) to include
+            // alongside the binaries
+            let extra_files = walkdir::WalkDir::new("initfs/")
+                .max_depth(1)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .map(|e| e.into_path())
+                .filter(|e| e.is_file());
+
+            for (bin, path) in binaries.chain(extra_files).map(|p| (fs::read(&p), p)) {
                 let mut header = Header::new_ustar();
                 let bin = std::io::Cursor::new(bin?);
                 let metadata = fs::metadata(&path)?;
@@ -115,10 +164,19 @@ pub fn build(target: BuildTarget) -> Result<()> {
                 archive.append_data(&mut header, filename, bin)?;
             }
 
+            for (filename, bundle) in driver_bundles()? {
+                archive.append_data(&mut file_header(bundle.len())?, filename, &bundle[..])?;
+            }
+
             archive.finish()?;
+            drop(archive);
+
+            // `init` embeds the compressed copy, the plain one is kept around
+            // for disk images and poking at
+            fs::write(&init_tar_lz4, lz4::compress(&fs::read(&init_tar)?))?;
 
             let _dir = pushd("init/");
-            cmd!("cargo build --release").run()?;
+            cmd!("cargo build --release {hardening...}").run()?;
             cp("target/riscv64gc-unknown-none-elf/release/init", "../../../build/init")?;
         }
         BuildTarget::Vanadinite(build_opts) => {
@@ -141,6 +199,12 @@ pub fn build(target: BuildTarget) -> Result<()> {
                     --features {features}
                     {test...}
             ").run()?;
+
+            let profile = match build_opts.debug_build || build_opts.test {
+                true => "debug",
+                false => "release",
+            };
+            crate::symbols::embed(format!("target/riscv64gc-unknown-none-elf/{}/vanadinite", profile).as_ref())?;
         }
         BuildTarget::Vanadium(build_opts) => {
             let features = format!("platform.{}", build_opts.platform);
@@ -189,3 +253,50 @@ pub fn build(target: BuildTarget) -> Result<()> {
 
     Ok(())
 }
+
+/// Package each driver with a manifest in `initfs/bundles/`, named after its
+/// binary, into a driver bundle for `devicemgr` to load
+fn driver_bundles() -> Result<Vec<(String, Vec<u8>)>> {
+    let mut bundles = Vec::new();
+    let manifests = match fs::read_dir("initfs/bundles") {
+        Ok(manifests) => manifests,
+        Err(_) => return Ok(bundles),
+    };
+
+    for manifest in manifests {
+        let path = manifest?.path();
+        if path.extension().map_or(true, |ext| ext != "json") {
+            continue;
+        }
+
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let binary = fs::read(format!("target/riscv64gc-unknown-none-elf/release/{}", name))
+            .with_context(|| format!("no binary for the `{}` driver bundle", name))?;
+
+        let mut bundle = Builder::new(Vec::new());
+        for (filename, contents) in [("manifest.json", fs::read(&path)?), ("driver", binary)] {
+            bundle.append_data(&mut file_header(contents.len())?, filename, &contents[..])?;
+        }
+
+        bundles.push((format!("{}.bundle", name), bundle.into_inner()?));
+    }
+
+    Ok(bundles)
+}
+
+/// A header for a file that didn't come from the filesystem, with every field
+/// filled in since the initfs reader doesn't accept empty ones
+fn file_header(len: usize) -> Result<Header> {
+    let mut header = Header::new_ustar();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(len as u64);
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    header.set_device_major(0)?;
+    header.set_device_minor(0)?;
+    header.set_cksum();
+
+    Ok(header)
+}
@@ -0,0 +1,515 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Decoding the debugging output captured from the serial console, using the
+//! binaries from the last build to symbolize addresses:
+//!
+//! - Kernel backtraces, whose addresses are only symbolized by the kernel if
+//!   its embedded symbol table has room for them
+//! - Crash reports printed by `crashdump`, whose `pc` and `ra` are symbolized
+//!   with the binary of the task that crashed
+//! - Trace records printed by `trace`, which are sorted into a timeline
+//! - Kernel edge coverage, dumped when a kernel built with `--coverage` exits
+//!   or printed by `coverage`, which is summarized for each function
+//! - Raw dumps of the kernel log ring, see `librust::syscalls::klog`
+
+use crate::{symbols::SymbolTable, Result};
+use anyhow::{bail, Context};
+use clap::Parser;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+const KERNEL_PATH: &str = "src/kernel/target/riscv64gc-unknown-none-elf/release/vanadinite";
+const USERSPACE_DIR: &str = "src/userspace/target/riscv64gc-unknown-none-elf/release";
+const TRACE_PREFIX: &str = "trace-records: ";
+const CRASH_PREFIX: &str = "crash ";
+const COVERAGE_PREFIX: &str = "coverage-flags: ";
+/// Section of the kernel binary holding the address of each coverage flag
+const COVERAGE_PCS_SECTION: &str = "__sancov_pcs";
+/// Set in an entry of the coverage address table if it's the entry of a
+/// function rather than some other edge within it
+const PC_FUNCTION_ENTRY: u64 = 1;
+const RECORD_SIZE: usize = 48;
+/// Size of the control page at the start of a kernel log dump
+const KLOG_CONTROL_SIZE: usize = 4096;
+
+/// Names for `librust::syscalls::trace::TraceEvent`, indexed by value
+const EVENTS: &[&str] = &["unknown", "switch", "syscall-entry", "syscall-exit", "ipc-send", "ipc-recv", "fault"];
+
+#[derive(Parser)]
+pub struct DecodeOptions {
+    /// A console log, or `-` to read it from stdin
+    input: PathBuf,
+
+    /// The input is a raw dump of the kernel log ring, the control page
+    /// followed by the data, instead of a console log
+    #[clap(long)]
+    klog_dump: bool,
+
+    /// The kernel binary to symbolize kernel addresses with
+    #[clap(long, default_value = KERNEL_PATH)]
+    kernel: PathBuf,
+
+    /// The directory holding the userspace binaries to symbolize crashed
+    /// tasks with
+    #[clap(long, default_value = USERSPACE_DIR)]
+    userspace: PathBuf,
+
+    /// Frequency of the `time` CSR in Hz, for converting trace timestamps
+    #[clap(long, default_value = "10000000")]
+    timebase: u64,
+
+    /// Only report coverage for functions whose name contains this, e.g.
+    /// `mem::paging`
+    #[clap(long)]
+    coverage_filter: Option<String>,
+}
+
+pub fn decode(options: DecodeOptions) -> Result<()> {
+    let input = match options.input.as_os_str() == "-" {
+        true => {
+            let mut input = Vec::new();
+            std::io::stdin().read_to_end(&mut input).context("failed to read stdin")?;
+            input
+        }
+        false => {
+            std::fs::read(&options.input).with_context(|| format!("failed to read {}", options.input.display()))?
+        }
+    };
+
+    let text = match options.klog_dump {
+        true => klog_text(&input)?,
+        false => String::from_utf8_lossy(&input).into_owned(),
+    };
+
+    let kernel = match SymbolTable::load(&options.kernel) {
+        Ok(kernel) => Some(kernel),
+        Err(e) => {
+            eprintln!("warning: {:#}, kernel addresses won't be symbolized", e);
+            None
+        }
+    };
+
+    let mut decoder = Decoder {
+        options,
+        kernel,
+        userspace: HashMap::new(),
+        crash: None,
+        trace: Vec::new(),
+        dropped: 0,
+        coverage: Vec::new(),
+    };
+    for line in text.lines() {
+        decoder.line(line.trim_end_matches('\r'));
+    }
+
+    decoder.finish_crash();
+    decoder.print_trace();
+    decoder.print_coverage();
+
+    Ok(())
+}
+
+struct Decoder {
+    options: DecodeOptions,
+    kernel: Option<SymbolTable>,
+    /// Userspace binaries by name, loaded the first time they crash
+    userspace: HashMap<String, Option<SymbolTable>>,
+    crash: Option<Crash>,
+    trace: Vec<TraceRecord>,
+    dropped: u64,
+    /// The kernel's edge coverage flags, where later dumps replace earlier ones
+    coverage: Vec<u8>,
+}
+
+/// The parts of a `crashdump` report needed to symbolize it
+struct Crash {
+    task: String,
+    pc: Option<u64>,
+    ra: Option<u64>,
+    /// Start of each region of the task's memory map, along with its kind
+    regions: Vec<(u64, String)>,
+}
+
+impl Decoder {
+    fn line(&mut self, line: &str) {
+        if let Some(i) = line.find(TRACE_PREFIX) {
+            if let Err(e) = self.trace_batch(&line[i + TRACE_PREFIX.len()..]) {
+                println!("{}", line);
+                eprintln!("warning: invalid trace records: {:#}", e);
+            }
+
+            return;
+        }
+
+        if let Some(i) = line.find(COVERAGE_PREFIX) {
+            if let Err(e) = self.coverage_batch(&line[i + COVERAGE_PREFIX.len()..]) {
+                println!("{}", line);
+                eprintln!("warning: invalid coverage flags: {:#}", e);
+            }
+
+            return;
+        }
+
+        // Crash reports are a header followed by indented lines
+        if self.crash.is_some() && !line.starts_with("  ") {
+            self.finish_crash();
+        }
+
+        match &mut self.crash {
+            Some(crash) => crash.line(line),
+            None => self.crash = Crash::start(line),
+        }
+
+        println!("{}", self.symbolize_kernel(line));
+    }
+
+    /// Symbolize each kernel address in `line` which isn't already, either in
+    /// place of `<unknown>` or after the address
+    fn symbolize_kernel(&self, line: &str) -> String {
+        let kernel = match &self.kernel {
+            Some(kernel) => kernel,
+            None => return line.to_string(),
+        };
+
+        let mut out = String::with_capacity(line.len());
+        let mut rest = line;
+        while let Some((before, address, after)) = next_address(rest) {
+            out.push_str(before);
+            out.push_str(&rest[before.len()..][..18]);
+            rest = after;
+
+            let (name, offset) = match kernel.resolve(address) {
+                Some(symbol) if !line.contains(symbol.0) => symbol,
+                _ => continue,
+            };
+
+            match rest.strip_prefix(" <unknown>") {
+                Some(after) => {
+                    out.push_str(&format!(" {}+{:#x}", name, offset));
+                    rest = after;
+                }
+                None => out.push_str(&format!(" <{}+{:#x}>", name, offset)),
+            }
+        }
+
+        out.push_str(rest);
+        out
+    }
+
+    fn finish_crash(&mut self) {
+        let crash = match self.crash.take() {
+            Some(crash) => crash,
+            None => return,
+        };
+
+        // Threads are named `task/thread`, and run the task's binary
+        let binary = crash.task.split('/').next().unwrap_or_default().to_string();
+        let path = self.options.userspace.join(&binary);
+        let symbols = self.userspace.entry(binary).or_insert_with(|| load_userspace(&path));
+        let symbols = match symbols {
+            Some(symbols) => symbols,
+            None => return,
+        };
+
+        // The kernel loads the binary's segments one after another starting
+        // at the lowest free address, so the first of them is its load base
+        let base = crash
+            .regions
+            .iter()
+            .filter(|(_, kind)| matches!(kind.as_str(), "ReadOnly" | "Text" | "Data"))
+            .map(|(start, _)| *start)
+            .min();
+
+        let base = match base {
+            Some(base) => base,
+            None => return println!("  (no memory map, can't symbolize)"),
+        };
+
+        for (register, address) in [("pc", crash.pc), ("ra", crash.ra)] {
+            let address = match address {
+                Some(address) => address,
+                None => continue,
+            };
+
+            match address.checked_sub(base).and_then(|offset| symbols.resolve(symbols.load_address + offset)) {
+                Some((name, offset)) => println!("  {}: {}+{:#x}", register, name, offset),
+                None => println!("  {}: <unknown>", register),
+            }
+        }
+    }
+
+    fn trace_batch(&mut self, batch: &str) -> Result<()> {
+        let (dropped, hex) = batch.split_once(' ').context("missing dropped count")?;
+        self.dropped += dropped.parse::<u64>().context("invalid dropped count")?;
+
+        let bytes = decode_hex(hex.trim())?;
+        if bytes.len() % RECORD_SIZE != 0 {
+            bail!("{} bytes isn't a whole number of records", bytes.len());
+        }
+
+        let word = |record: &[u8], i: usize| u64::from_le_bytes(record[i * 8..][..8].try_into().unwrap());
+        for record in bytes.chunks_exact(RECORD_SIZE) {
+            self.trace.push(TraceRecord {
+                timestamp: word(record, 0),
+                event: word(record, 1),
+                hart: word(record, 2),
+                tid: word(record, 3),
+                args: [word(record, 4), word(record, 5)],
+            });
+        }
+
+        Ok(())
+    }
+
+    fn print_trace(&mut self) {
+        if self.trace.is_empty() {
+            return;
+        }
+
+        // Each hart's records are in order, but the harts are one after another
+        self.trace.sort_by_key(|record| record.timestamp);
+
+        println!();
+        println!("Trace ({} records, {} dropped):", self.trace.len(), self.dropped);
+        println!("{:>12} {:>4} {:>5} {:<14} details", "time (us)", "hart", "tid", "event");
+
+        let start = self.trace[0].timestamp;
+        let micros = |ticks: u64| ticks as f64 * 1_000_000.0 / self.options.timebase as f64;
+        // When each hart entered the syscall its task is making
+        let mut entries = BTreeMap::new();
+        for record in &self.trace {
+            let [a, b] = record.args;
+            let details = match record.event {
+                1 if a == 0 => String::from("from idle"),
+                1 => format!("from tid {}", a),
+                2 => {
+                    entries.insert((record.hart, record.tid), record.timestamp);
+                    format!("syscall {}", a)
+                }
+                3 => {
+                    let status = match b {
+                        1 => " (error)",
+                        2 => " (blocked)",
+                        _ => "",
+                    };
+
+                    match entries.remove(&(record.hart, record.tid)) {
+                        Some(entry) => {
+                            format!("syscall {}{}, took {:.3}us", a, status, micros(record.timestamp - entry))
+                        }
+                        None => format!("syscall {}{}", a, status),
+                    }
+                }
+                4 => format!("to tid {}, {} bytes", a, b),
+                5 => format!("from tid {}, {} bytes", a, b),
+                6 => format!("address {:#x}, scause {}", a, b),
+                _ => format!("{:#x} {:#x}", a, b),
+            };
+
+            let event = EVENTS.get(record.event as usize).copied().unwrap_or("unknown");
+            println!(
+                "{:>12.3} {:>4} {:>5} {:<14} {}",
+                micros(record.timestamp - start),
+                record.hart,
+                record.tid,
+                event,
+                details
+            );
+        }
+    }
+
+    fn coverage_batch(&mut self, batch: &str) -> Result<()> {
+        let (start, hex) = batch.split_once(' ').context("missing start index")?;
+        let start = start.parse::<usize>().context("invalid start index")?;
+
+        let flags = decode_hex(hex.trim())?;
+        if self.coverage.len() < start + flags.len() {
+            self.coverage.resize(start + flags.len(), 0);
+        }
+
+        self.coverage[start..][..flags.len()].copy_from_slice(&flags);
+        Ok(())
+    }
+
+    fn print_coverage(&self) {
+        if self.coverage.is_empty() {
+            return;
+        }
+
+        let table = match crate::symbols::read_section(&self.options.kernel, COVERAGE_PCS_SECTION) {
+            Ok(table) => table,
+            Err(e) => return eprintln!("warning: {:#}, was the kernel built with `--coverage`?", e),
+        };
+
+        // The address table is in the same order as the flags, each entry an
+        // address followed by flags saying what kind of edge it is
+        let word = |entry: &[u8], i: usize| u64::from_le_bytes(entry[i * 8..][..8].try_into().unwrap());
+        let entries: Vec<(u64, u64)> = table.chunks_exact(16).map(|entry| (word(entry, 0), word(entry, 1))).collect();
+        if entries.len() < self.coverage.len() {
+            return eprintln!(
+                "warning: the log has {} coverage flags but the kernel only has {} edges, is it from a different build?",
+                self.coverage.len(),
+                entries.len()
+            );
+        }
+
+        // Each function's entry, and how many of its edges were taken out of
+        // how many it has
+        let mut functions: Vec<(u64, usize, usize)> = Vec::new();
+        for (i, (address, kind)) in entries.iter().enumerate() {
+            if kind & PC_FUNCTION_ENTRY != 0 || functions.is_empty() {
+                functions.push((*address, 0, 0));
+            }
+
+            let function = functions.last_mut().unwrap();
+            function.2 += 1;
+            if self.coverage.get(i).map_or(false, |&flag| flag != 0) {
+                function.1 += 1;
+            }
+        }
+
+        let mut report: Vec<(String, usize, usize)> = functions
+            .into_iter()
+            .map(|(address, covered, total)| {
+                let name = match self.kernel.as_ref().and_then(|kernel| kernel.resolve(address)) {
+                    Some((name, _)) => name.to_string(),
+                    None => format!("{:#018x}", address),
+                };
+
+                (name, covered, total)
+            })
+            .filter(|(name, ..)| self.options.coverage_filter.as_ref().map_or(true, |filter| name.contains(filter)))
+            .collect();
+        report.sort();
+
+        let covered: usize = report.iter().map(|(_, covered, _)| covered).sum();
+        let total: usize = report.iter().map(|(.., total)| total).sum();
+        let entered = report.iter().filter(|(_, covered, _)| *covered > 0).count();
+        let percent = |covered: usize, total: usize| covered as f64 * 100.0 / total.max(1) as f64;
+
+        println!();
+        println!(
+            "Coverage ({} of {} edges, {:.1}%, {} of {} functions entered):",
+            covered,
+            total,
+            percent(covered, total),
+            entered,
+            report.len()
+        );
+        println!("{:>11} {:>6}  function", "edges", "%");
+
+        for (name, covered, total) in &report {
+            println!("{:>11} {:>5.1}%  {}", format!("{}/{}", covered, total), percent(*covered, *total), name);
+        }
+    }
+}
+
+impl Crash {
+    /// `crash <id>: task <name> (tid <tid>) died to a <cause>`
+    fn start(line: &str) -> Option<Self> {
+        let rest = line.strip_prefix(CRASH_PREFIX)?;
+        let (_, rest) = rest.split_once(": task ")?;
+        let (task, _) = rest.rsplit_once(" (tid ")?;
+
+        Some(Self { task: task.to_string(), pc: None, ra: None, regions: Vec::new() })
+    }
+
+    /// Registers are printed as `name=value` pairs, and memory map lines start
+    /// with the start and end of the region followed by its permissions and kind
+    fn line(&mut self, line: &str) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        for word in &words {
+            match word.split_once('=') {
+                Some(("pc", value)) => self.pc = parse_hex(value),
+                Some(("ra", value)) => self.ra = parse_hex(value),
+                _ => {}
+            }
+        }
+
+        if let [start, _end, _permissions, kind, ..] = words[..] {
+            if let Some(start) = parse_hex(start) {
+                self.regions.push((start, kind.to_string()));
+            }
+        }
+    }
+}
+
+struct TraceRecord {
+    timestamp: u64,
+    event: u64,
+    hart: u64,
+    tid: u64,
+    args: [u64; 2],
+}
+
+fn load_userspace(path: &Path) -> Option<SymbolTable> {
+    match SymbolTable::load(path) {
+        Ok(symbols) => Some(symbols),
+        Err(e) => {
+            eprintln!("warning: {:#}, the crash won't be symbolized", e);
+            None
+        }
+    }
+}
+
+/// The text of a raw kernel log ring dump, oldest first
+fn klog_text(dump: &[u8]) -> Result<String> {
+    let word = |i: usize| dump.get(i * 8..i * 8 + 8).map(|word| u64::from_le_bytes(word.try_into().unwrap()) as usize);
+    let (capacity, reserved, head) = match (word(0), word(1), word(2)) {
+        (Some(capacity), Some(reserved), Some(head)) => (capacity, reserved, head),
+        _ => bail!("kernel log dump is too short"),
+    };
+
+    let data = match dump.get(KLOG_CONTROL_SIZE..).and_then(|data| data.get(..capacity)) {
+        Some(data) if capacity != 0 => data,
+        _ => bail!("kernel log dump doesn't hold the {} bytes of data its control page says it has", capacity),
+    };
+
+    // Anything older than `capacity` bytes before the record the kernel was
+    // writing may have been overwritten already
+    let start = reserved.saturating_sub(capacity);
+    let mut text: Vec<u8> = (start..head).map(|position| data[position % capacity]).collect();
+    if start > 0 {
+        let resync = text.iter().position(|&b| b == b'\n').map(|i| i + 1).unwrap_or(text.len());
+        text.drain(..resync);
+    }
+
+    Ok(String::from_utf8_lossy(&text).into_owned())
+}
+
+/// The next `0x` prefixed 64-bit address in `line`, along with the text before
+/// and after it
+fn next_address(line: &str) -> Option<(&str, u64, &str)> {
+    let mut search = 0;
+    while let Some(i) = line[search..].find("0x").map(|i| search + i) {
+        let digits = line[i + 2..].bytes().take_while(u8::is_ascii_hexdigit).count();
+        if digits == 16 {
+            let address = u64::from_str_radix(&line[i + 2..][..16], 16).ok()?;
+            return Some((&line[..i], address, &line[i + 18..]));
+        }
+
+        search = i + 2 + digits;
+    }
+
+    None
+}
+
+fn parse_hex(value: &str) -> Option<u64> {
+    u64::from_str_radix(value.strip_prefix("0x")?, 16).ok()
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        bail!("odd number of hex digits");
+    }
+
+    (0..hex.len()).step_by(2).map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?)).collect()
+}
@@ -0,0 +1,288 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Building disk images with an MBR partition table and a single FAT32 boot
+//! partition holding the kernel, `init`, and the init filesystem, which is
+//! written directly instead of depending on host partitioning and formatting
+//! tools.
+
+use crate::{
+    build::{self, BuildTarget},
+    Result, VanadiniteBuildOptions,
+};
+use anyhow::{bail, Context};
+use clap::Parser;
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use xshell::cmd;
+
+const SECTOR_SIZE: u64 = 512;
+/// The partition starts 1 MiB in, leaving room for firmware which expects to
+/// be placed right after the MBR
+const PARTITION_START: u64 = 2048;
+/// The filesystem server keeps its journal in reserved sectors 16 through 31
+const RESERVED_SECTORS: u32 = 32;
+const NUM_FATS: u32 = 2;
+const ROOT_CLUSTER: u32 = 2;
+const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+/// FAT32 volumes must have at least this many clusters, otherwise they're
+/// detected as FAT16
+const MIN_CLUSTERS: u32 = 65525;
+
+#[derive(Parser)]
+pub struct ImageOptions {
+    /// Where to write the image
+    #[clap(long, default_value = "build/vanadinite.img")]
+    output: PathBuf,
+
+    /// Image size in MiB
+    #[clap(long, default_value = "64")]
+    size: u64,
+
+    /// Extra files to place in the root directory of the boot partition, which
+    /// must have 8.3 file names
+    #[clap(long)]
+    file: Vec<PathBuf>,
+
+    /// Write the finished image to a device (e.g. an SD card), using `sudo dd`
+    #[clap(long)]
+    flash: Option<PathBuf>,
+
+    /// Don't build anything before creating the image
+    #[clap(long)]
+    no_build: bool,
+
+    #[clap(flatten)]
+    vanadinite_options: VanadiniteBuildOptions,
+}
+
+pub fn image(options: ImageOptions) -> Result<()> {
+    if !options.no_build {
+        build::build(BuildTarget::OpenSBI(options.vanadinite_options.clone()))?;
+    }
+
+    let kernel = Path::new("src/kernel/target/riscv64gc-unknown-none-elf/release/vanadinite");
+    let mut files = vec![
+        (String::from("KERNEL.ELF"), kernel.to_path_buf()),
+        (String::from("KERNEL.BIN"), kernel.with_extension("bin")),
+        (String::from("INIT"), PathBuf::from("build/init")),
+        (String::from("INITFS.TAR"), PathBuf::from("build/initfs.tar")),
+        (String::from("FIRMWARE.BIN"), PathBuf::from("build/opensbi-riscv64-generic-fw_payload.bin")),
+    ];
+
+    for path in &options.file {
+        let name = path.file_name().and_then(|name| name.to_str()).context("invalid file name")?;
+        files.push((name.to_uppercase(), path.clone()));
+    }
+
+    let files = files
+        .into_iter()
+        .map(|(name, path)| {
+            Ok((name, std::fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    write_image(&options.output, options.size * 1024 * 1024 / SECTOR_SIZE, &files)?;
+    println!("Wrote {} ({} MiB)", options.output.display(), options.size);
+
+    if let Some(device) = options.flash {
+        let image = &options.output;
+        cmd!("sudo dd if={image} of={device} bs=4M conv=fsync status=progress").run()?;
+        println!("Flashed {}", device.display());
+    }
+
+    Ok(())
+}
+
+/// Write an image of `total_sectors` sectors with a FAT32 partition containing
+/// `files` in its root directory
+fn write_image(path: &Path, total_sectors: u64, files: &[(String, Vec<u8>)]) -> Result<()> {
+    let volume_sectors = u32::try_from(total_sectors.saturating_sub(PARTITION_START)).context("image too large")?;
+    let layout = Layout::new(volume_sectors)?;
+
+    let mut image = File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    image.set_len(total_sectors * SECTOR_SIZE)?;
+
+    let mut mbr = [0; SECTOR_SIZE as usize];
+    let entry = &mut mbr[0x1BE..0x1CE];
+    entry[0] = 0x80;
+    // CHS addresses are ignored by everything that matters, so they're set
+    // to the "too large" placeholder
+    entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[4] = 0x0C;
+    entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[8..12].copy_from_slice(&(PARTITION_START as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&volume_sectors.to_le_bytes());
+    mbr[0x1B8..0x1BC].copy_from_slice(&0x5641_4E41u32.to_le_bytes());
+    mbr[0x1FE..].copy_from_slice(&[0x55, 0xAA]);
+    write_at(&mut image, 0, &mbr)?;
+
+    let volume = PARTITION_START * SECTOR_SIZE;
+    let cluster_size = layout.sectors_per_cluster as usize * SECTOR_SIZE as usize;
+    let clusters_for = |len: usize| len.max(1).div_ceil(cluster_size);
+
+    // Lay out the root directory then each file contiguously, starting at the
+    // root cluster
+    let mut fat = vec![0x0FFF_FFF8, END_OF_CHAIN];
+    let mut allocate = |len: usize| {
+        let first = fat.len() as u32;
+        let count = clusters_for(len) as u32;
+        fat.extend((first + 1..first + count).chain([END_OF_CHAIN]));
+        first
+    };
+
+    let root_len = (files.len() + 1) * 32;
+    allocate(root_len);
+
+    let mut root = vec![0; clusters_for(root_len) * cluster_size];
+    let mut contents = Vec::new();
+    for ((name, data), entry) in files.iter().zip(root.chunks_exact_mut(32)) {
+        let first = match data.is_empty() {
+            true => 0,
+            false => allocate(data.len()),
+        };
+
+        entry[0..11].copy_from_slice(&short_name(name)?);
+        // Archive
+        entry[11] = 0x20;
+        entry[20..22].copy_from_slice(&((first >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(first as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&u32::try_from(data.len())?.to_le_bytes());
+        contents.push((first, data));
+    }
+
+    if fat.len() as u32 > layout.clusters + 2 {
+        bail!("files don't fit in a {} sector volume", volume_sectors);
+    }
+
+    for (first, data) in contents.into_iter().filter(|&(first, _)| first != 0) {
+        write_at(&mut image, volume + layout.cluster_offset(first), data)?;
+    }
+
+    write_at(&mut image, volume + layout.cluster_offset(ROOT_CLUSTER), &root)?;
+
+    let fat = fat.iter().flat_map(|entry| entry.to_le_bytes()).collect::<Vec<_>>();
+    for copy in 0..NUM_FATS {
+        let sector = RESERVED_SECTORS + copy * layout.sectors_per_fat;
+        write_at(&mut image, volume + u64::from(sector) * SECTOR_SIZE, &fat)?;
+    }
+
+    let used = (fat.len() / 4) as u32;
+    let boot_sector = layout.boot_sector(volume_sectors);
+    let fs_info = fs_info(layout.clusters + 2 - used, used);
+
+    // The backup copies live at sectors 6 and 7
+    for base in [0, 6] {
+        write_at(&mut image, volume + base * SECTOR_SIZE, &boot_sector)?;
+        write_at(&mut image, volume + (base + 1) * SECTOR_SIZE, &fs_info)?;
+    }
+
+    image.sync_all()?;
+
+    Ok(())
+}
+
+struct Layout {
+    sectors_per_cluster: u32,
+    sectors_per_fat: u32,
+    clusters: u32,
+}
+
+impl Layout {
+    fn new(volume_sectors: u32) -> Result<Self> {
+        // Cluster sizes from the FAT specification's table for FAT32
+        let sectors_per_cluster = [(532_480, 1), (16_777_216, 8), (33_554_432, 16), (67_108_864, 32)]
+            .into_iter()
+            .find(|&(max_sectors, _)| volume_sectors <= max_sectors)
+            .map_or(64, |(_, sectors_per_cluster)| sectors_per_cluster);
+
+        let available = volume_sectors.saturating_sub(RESERVED_SECTORS);
+        let per_fat_sector = (256 * sectors_per_cluster + NUM_FATS) / 2;
+        let sectors_per_fat = available.div_ceil(per_fat_sector);
+        let clusters = available.saturating_sub(NUM_FATS * sectors_per_fat) / sectors_per_cluster;
+
+        if clusters < MIN_CLUSTERS {
+            bail!("image is too small for FAT32, it must be at least 34 MiB");
+        }
+
+        Ok(Self { sectors_per_cluster, sectors_per_fat, clusters })
+    }
+
+    /// Byte offset of `cluster` from the start of the volume
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        let data_start = RESERVED_SECTORS + NUM_FATS * self.sectors_per_fat;
+        let sector = u64::from(data_start) + u64::from(cluster - 2) * u64::from(self.sectors_per_cluster);
+        sector * SECTOR_SIZE
+    }
+
+    fn boot_sector(&self, volume_sectors: u32) -> [u8; SECTOR_SIZE as usize] {
+        let mut sector = [0; SECTOR_SIZE as usize];
+        sector[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+        sector[3..11].copy_from_slice(b"VANADNTE");
+        sector[0x0B..0x0D].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+        sector[0x0D] = self.sectors_per_cluster as u8;
+        sector[0x0E..0x10].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+        sector[0x10] = NUM_FATS as u8;
+        // Fixed disk
+        sector[0x15] = 0xF8;
+        sector[0x18..0x1A].copy_from_slice(&63u16.to_le_bytes());
+        sector[0x1A..0x1C].copy_from_slice(&255u16.to_le_bytes());
+        sector[0x1C..0x20].copy_from_slice(&(PARTITION_START as u32).to_le_bytes());
+        sector[0x20..0x24].copy_from_slice(&volume_sectors.to_le_bytes());
+        sector[0x24..0x28].copy_from_slice(&self.sectors_per_fat.to_le_bytes());
+        sector[0x2C..0x30].copy_from_slice(&ROOT_CLUSTER.to_le_bytes());
+        sector[0x30..0x32].copy_from_slice(&1u16.to_le_bytes());
+        sector[0x32..0x34].copy_from_slice(&6u16.to_le_bytes());
+        sector[0x40] = 0x80;
+        sector[0x42] = 0x29;
+        sector[0x43..0x47].copy_from_slice(&0x5641_4E41u32.to_le_bytes());
+        sector[0x47..0x52].copy_from_slice(b"VANADINITE ");
+        sector[0x52..0x5A].copy_from_slice(b"FAT32   ");
+        sector[0x1FE..].copy_from_slice(&[0x55, 0xAA]);
+
+        sector
+    }
+}
+
+fn fs_info(free_clusters: u32, next_free: u32) -> [u8; SECTOR_SIZE as usize] {
+    let mut sector = [0; SECTOR_SIZE as usize];
+    sector[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+    sector[0x1E4..0x1E8].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+    sector[0x1E8..0x1EC].copy_from_slice(&free_clusters.to_le_bytes());
+    sector[0x1EC..0x1F0].copy_from_slice(&next_free.to_le_bytes());
+    sector[0x1FC..].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+
+    sector
+}
+
+/// Convert a name like `KERNEL.ELF` to its padded directory entry form
+fn short_name(name: &str) -> Result<[u8; 11]> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    let valid = |part: &str, max| {
+        part.len() <= max && part.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b"_-~".contains(&b))
+    };
+
+    if base.is_empty() || !valid(base, 8) || !valid(extension, 3) {
+        bail!("{} isn't a valid 8.3 file name", name);
+    }
+
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+
+    Ok(short)
+}
+
+fn write_at(file: &mut File, offset: u64, data: &[u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(data)?;
+
+    Ok(())
+}
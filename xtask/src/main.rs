@@ -6,10 +6,17 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 pub mod build;
+pub mod decode;
+pub mod image;
+pub mod map_diff;
 pub mod runner;
+pub mod symbols;
 
 use build::{BuildTarget, Platform};
 use clap::{AppSettings, ArgEnum, Parser};
+use decode::DecodeOptions;
+use image::ImageOptions;
+use map_diff::MapDiffOptions;
 use runner::RunOptions;
 use std::sync::{atomic::AtomicBool, Arc};
 use xshell::{pushd, rm_rf};
@@ -29,6 +36,14 @@ enum Arguments {
         #[clap(arg_enum)]
         target: CleanTarget,
     },
+    /// Symbolize and pretty-print backtraces, crash reports, trace records, and
+    /// kernel coverage from a console log, or a raw dump of the kernel log ring
+    Decode(DecodeOptions),
+    /// Build a disk image with a FAT32 boot partition containing the kernel
+    /// and userspace, optionally writing it to a device
+    Image(ImageOptions),
+    /// Compare two address space snapshots taken with `pmap --snapshot`
+    MapDiff(MapDiffOptions),
     /// Run `vanadinite`
     Run(RunOptions),
     /// Test `vanadinite`
@@ -62,6 +77,22 @@ pub struct VanadiniteBuildOptions {
 
     #[clap(long)]
     debug_build: bool,
+
+    /// Instrument the kernel to record which edges of its control flow graph
+    /// are taken, for `decode` to report on from the console log
+    #[clap(long)]
+    coverage: bool,
+
+    #[clap(flatten)]
+    userspace: UserspaceBuildOptions,
+}
+
+#[derive(Parser, Clone)]
+pub struct UserspaceBuildOptions {
+    /// Build userspace with a shadow call stack, which keeps return addresses
+    /// out of reach of stack buffer overflows
+    #[clap(long)]
+    shadow_call_stack: bool,
 }
 
 #[derive(ArgEnum, Clone, Copy)]
@@ -91,6 +122,9 @@ fn main() -> Result<()> {
     match args {
         Arguments::Build { target } => build::build(target)?,
         Arguments::Clean { target } => clean(target)?,
+        Arguments::Decode(options) => decode::decode(options)?,
+        Arguments::Image(options) => image::image(options)?,
+        Arguments::MapDiff(options) => map_diff::map_diff(options)?,
         Arguments::Run(target) => runner::run(target)?,
         Arguments::Test(target) => runner::test(target)?,
     }
@@ -0,0 +1,235 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Comparing address space snapshots taken with `pmap --snapshot`, to find
+//! where a task's memory usage grew or became fragmented between them. The
+//! snapshot format is documented on `librust::syscalls::mem::snapshot_address_space`.
+
+use crate::Result;
+use anyhow::{bail, Context};
+use clap::Parser;
+use std::{collections::BTreeMap, path::PathBuf};
+
+const MAGIC: &[u8] = b"VASS";
+const VERSION: u8 = 1;
+const PAGE_SIZE: u64 = 4096;
+const LINE_PREFIX: &str = "pmap-snapshot: ";
+
+/// Names for `librust::syscalls::mem::RegionKind`, indexed by value
+const KINDS: &[&str] =
+    &["Channel", "Data", "Guard", "ReadOnly", "Stack", "Text", "Tls", "Unoccupied", "UserAllocated", "Dma", "Mmio"];
+
+#[derive(Parser)]
+pub struct MapDiffOptions {
+    /// The earlier snapshot, either a console log containing a line printed by
+    /// `pmap --snapshot` (the last one is used) or the raw snapshot bytes
+    old: PathBuf,
+
+    /// The later snapshot, in the same form
+    new: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Region {
+    start: u64,
+    len: u64,
+    permissions: u8,
+    kind: u8,
+}
+
+impl Region {
+    fn end(&self) -> u64 {
+        self.start + self.len
+    }
+
+    fn kind_name(&self) -> &'static str {
+        KINDS.get(usize::from(self.kind)).copied().unwrap_or("Unknown")
+    }
+
+    fn permissions(&self) -> String {
+        [(1, 'r'), (2, 'w'), (4, 'x')]
+            .into_iter()
+            .map(|(bit, c)| if self.permissions & bit != 0 { c } else { '-' })
+            .collect()
+    }
+}
+
+impl std::fmt::Display for Region {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:#018x} {:#018x} {:>9}K {} {}",
+            self.start,
+            self.end(),
+            self.len / 1024,
+            self.permissions(),
+            self.kind_name()
+        )
+    }
+}
+
+pub fn map_diff(options: MapDiffOptions) -> Result<()> {
+    let old = load(&options.old)?;
+    let new = load(&options.new)?;
+
+    let old_by_start: BTreeMap<u64, Region> = old.iter().map(|r| (r.start, *r)).collect();
+    let new_by_start: BTreeMap<u64, Region> = new.iter().map(|r| (r.start, *r)).collect();
+
+    println!("Regions:");
+    let mut unchanged = 0;
+    for region in &old {
+        match new_by_start.get(&region.start) {
+            None => println!("  - {}", region),
+            Some(other) if other != region => {
+                println!("  - {}", region);
+                println!("  + {}", other);
+            }
+            Some(_) => unchanged += 1,
+        }
+    }
+
+    for region in new.iter().filter(|r| !old_by_start.contains_key(&r.start)) {
+        println!("  + {}", region);
+    }
+    println!("  ({} unchanged)", unchanged);
+
+    println!();
+    println!("{:<14} {:>10} {:>10} {:>10}", "kind", "old", "new", "change");
+
+    let totals = |regions: &[Region]| {
+        let mut totals = BTreeMap::<&str, u64>::new();
+        for region in regions {
+            *totals.entry(region.kind_name()).or_default() += region.len;
+        }
+        totals
+    };
+
+    let (old_totals, new_totals) = (totals(&old), totals(&new));
+    for kind in KINDS.iter().copied().chain(["Unknown"]) {
+        let (before, after) = (old_totals.get(kind).copied().unwrap_or(0), new_totals.get(kind).copied().unwrap_or(0));
+        if before != 0 || after != 0 {
+            print_row(kind, before, after);
+        }
+    }
+
+    let total = |regions: &[Region]| regions.iter().map(|r| r.len).sum::<u64>();
+    print_row("total", total(&old), total(&new));
+
+    println!();
+    let (old_frag, new_frag) = (Fragmentation::of(&old), Fragmentation::of(&new));
+    println!("{:<14} {:>10} {:>10}", "", "old", "new");
+    println!("{:<14} {:>10} {:>10}", "regions", old.len(), new.len());
+    println!("{:<14} {:>10} {:>10}", "gaps", old_frag.gaps, new_frag.gaps);
+    println!("{:<14} {:>9}K {:>9}K", "gap space", old_frag.gap_space / 1024, new_frag.gap_space / 1024);
+    println!("{:<14} {:>9}K {:>9}K", "largest gap", old_frag.largest_gap / 1024, new_frag.largest_gap / 1024);
+
+    Ok(())
+}
+
+fn print_row(label: &str, before: u64, after: u64) {
+    let change = after as i64 - before as i64;
+    println!("{:<14} {:>9}K {:>9}K {:>+9}K", label, before / 1024, after / 1024, change / 1024);
+}
+
+/// Unoccupied space between the lowest and highest occupied regions
+struct Fragmentation {
+    gaps: usize,
+    gap_space: u64,
+    largest_gap: u64,
+}
+
+impl Fragmentation {
+    fn of(regions: &[Region]) -> Self {
+        let gaps: Vec<u64> = regions.windows(2).map(|w| w[1].start - w[0].end()).filter(|&gap| gap != 0).collect();
+
+        Self { gaps: gaps.len(), gap_space: gaps.iter().sum(), largest_gap: gaps.iter().copied().max().unwrap_or(0) }
+    }
+}
+
+fn load(path: &PathBuf) -> Result<Vec<Region>> {
+    let contents = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let bytes = match contents.starts_with(MAGIC) {
+        true => contents,
+        false => {
+            let text = String::from_utf8_lossy(&contents);
+            let hex = match text.lines().rev().find_map(|line| Some(&line[line.find(LINE_PREFIX)?..])) {
+                Some(line) => line[LINE_PREFIX.len()..].trim(),
+                None => text.trim(),
+            };
+
+            decode_hex(hex).with_context(|| format!("no snapshot found in {}", path.display()))?
+        }
+    };
+
+    parse(&bytes).with_context(|| format!("invalid snapshot in {}", path.display()))
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        bail!("odd number of hex digits");
+    }
+
+    (0..hex.len()).step_by(2).map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?)).collect()
+}
+
+fn parse(bytes: &[u8]) -> Result<Vec<Region>> {
+    let mut reader = Reader(bytes.iter());
+
+    let mut magic = [0; 4];
+    for byte in &mut magic {
+        *byte = reader.byte()?;
+    }
+
+    if magic != MAGIC {
+        bail!("bad magic");
+    }
+
+    match reader.byte()? {
+        VERSION => {}
+        version => bail!("unsupported snapshot version {}", version),
+    }
+
+    let n_regions = reader.varint()?;
+    let mut regions = Vec::new();
+    let mut previous_end = 0;
+    for _ in 0..n_regions {
+        let start = previous_end + reader.varint()?;
+        let len = reader.varint()?;
+        let flags = reader.byte()?;
+
+        regions.push(Region {
+            start: start * PAGE_SIZE,
+            len: len * PAGE_SIZE,
+            permissions: flags & 0b111,
+            kind: flags >> 3,
+        });
+        previous_end = start + len;
+    }
+
+    Ok(regions)
+}
+
+struct Reader<'a>(std::slice::Iter<'a, u8>);
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8> {
+        self.0.next().copied().context("snapshot ended early")
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut n = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+
+        bail!("varint too long")
+    }
+}
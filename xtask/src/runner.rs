@@ -7,7 +7,7 @@
 
 use crate::{
     build::{self, BuildTarget, Platform},
-    Result, SbiImpl, Simulator, VanadiniteBuildOptions,
+    Result, SbiImpl, Simulator, UserspaceBuildOptions, VanadiniteBuildOptions,
 };
 use clap::Parser;
 use std::path::PathBuf;
@@ -31,6 +31,17 @@ pub struct RunOptions {
     #[clap(long)]
     drive_file: Option<PathBuf>,
 
+    /// Path to an initrd (a tar archive, which can be LZ4 compressed), any
+    /// `*.dtbo` device tree overlays in it are applied by the kernel at boot
+    #[clap(long)]
+    initrd: Option<PathBuf>,
+
+    /// Directory to serve over TFTP from the emulated network's gateway at
+    /// 10.0.2.2, e.g. `build` to netboot the initfs with
+    /// `--kernel-args netboot.server=10.0.2.2`
+    #[clap(long)]
+    tftp_root: Option<PathBuf>,
+
     /// Arguments passed to the kernel
     //#[clap(setting = clap::ArgSettings::AllowEmptyValues)]
     #[clap(long, default_value = "")]
@@ -40,6 +51,11 @@ pub struct RunOptions {
     #[clap(long)]
     no_build: bool,
 
+    /// Add a virtio console connected to a pseudo-terminal, which can be used
+    /// by passing `console=<path to its virtio_mmio node>` to the kernel
+    #[clap(long)]
+    virtio_console: bool,
+
     /// RAM size in MiB
     #[clap(long, default_value = "512")]
     ram: usize,
@@ -63,14 +79,19 @@ impl Default for RunOptions {
             debug_log: None,
             debug: false,
             drive_file: None,
+            initrd: None,
+            tftp_root: None,
             kernel_args: String::new(),
             no_build: false,
+            virtio_console: false,
             ram: 512,
             vanadinite_options: VanadiniteBuildOptions {
                 platform: Platform::Virt,
                 kernel_features: String::new(),
                 test: false,
                 debug_build: false,
+                coverage: false,
+                userspace: UserspaceBuildOptions { shadow_call_stack: false },
             },
             with: Simulator::Qemu,
             sbi: SbiImpl::OpenSbi,
@@ -101,6 +122,28 @@ pub fn run(options: RunOptions) -> Result<()> {
         _ => vec![],
     };
 
+    let enable_virtio_console = match (options.vanadinite_options.platform, options.virtio_console) {
+        (Platform::Virt, true) => vec![
+            String::from("-chardev"),
+            String::from("pty,id=virtcon"),
+            String::from("-device"),
+            String::from("virtio-serial-device"),
+            String::from("-device"),
+            String::from("virtconsole,chardev=virtcon"),
+        ],
+        _ => vec![],
+    };
+
+    let initrd = match &options.initrd {
+        Some(path) => vec![String::from("-initrd"), format!("{}", path.display())],
+        None => vec![],
+    };
+
+    let mut netdev = String::from("user,id=net1,hostfwd=udp:127.0.0.1:1111-10.0.2.15:1337");
+    if let Some(path) = &options.tftp_root {
+        netdev.push_str(&format!(",tftp={}", path.display()));
+    }
+
     let kernel_path = match options.vanadinite_options.debug_build {
         true => "src/kernel/target/riscv64gc-unknown-none-elf/debug/vanadinite",
         false => "src/kernel/target/riscv64gc-unknown-none-elf/release/vanadinite",
@@ -140,11 +183,13 @@ pub fn run(options: RunOptions) -> Result<()> {
                     -append {kernel_args}
                     -global virtio-mmio.force-legacy=false
                     {enable_virtio_block_device...}
-                    -netdev user,id=net1,hostfwd=udp:127.0.0.1:1111-10.0.2.15:1337
+                    {enable_virtio_console...}
+                    -netdev {netdev}
                     -device virtio-net-device,netdev=net1
                     -object filter-dump,id=f1,netdev=net1,file=testing_files/nettraffic.dat
                     -bios {sbi_firmware}
                     -kernel {kernel_path}
+                    {initrd...}
                     {debug...}
                     {debug_log...}
             ").run()?;
@@ -182,7 +227,9 @@ pub fn test(mut options: RunOptions) -> Result<()> {
     let platform = options.vanadinite_options.platform.to_string();
     let cpu_count = options.cpus.to_string();
     let ram = options.ram.to_string();
-    let kernel_args = options.kernel_args;
+    // Lets the kernel check that it was built from the same commit we're
+    // testing
+    let kernel_args = format!("{} expected-commit={}", options.kernel_args, build::git_commit());
 
     let debug_log = match &options.debug_log {
         Some(path) => vec![
@@ -197,7 +244,7 @@ pub fn test(mut options: RunOptions) -> Result<()> {
     };
 
     #[rustfmt::skip]
-    cmd!("
+    let qemu = cmd!("
         qemu-system-riscv64
             -machine {platform}
             -cpu rv64
@@ -207,7 +254,59 @@ pub fn test(mut options: RunOptions) -> Result<()> {
             -bios ../opensbi-riscv64-generic-fw_jump.bin 
             -kernel target/riscv64gc-unknown-none-elf/debug/vanadinite
             {debug_log...}
-    ").run()?;
+    ");
 
-    Ok(())
+    let status = std::process::Command::from(qemu).status()?;
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(code) => Err(anyhow::anyhow!("{}", KernelExit::from_code(code))),
+        None => Err(anyhow::anyhow!("QEMU was killed by a signal")),
+    }
+}
+
+/// Why the kernel exited, decoded from the exit code of QEMU. These need to
+/// match the codes in the kernel's `platform::ExitStatus`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelExit {
+    Success,
+    Error,
+    Panic,
+    OutOfMemory,
+    Watchdog,
+    TestFailed { index: usize },
+    Unknown(i32),
+}
+
+impl KernelExit {
+    const FIRST_TEST_FAILURE_CODE: i32 = 16;
+
+    pub fn from_code(code: i32) -> Self {
+        match code {
+            0 => Self::Success,
+            1 => Self::Error,
+            2 => Self::Panic,
+            3 => Self::OutOfMemory,
+            4 => Self::Watchdog,
+            Self::FIRST_TEST_FAILURE_CODE..=255 => {
+                Self::TestFailed { index: (code - Self::FIRST_TEST_FAILURE_CODE) as usize }
+            }
+            code => Self::Unknown(code),
+        }
+    }
+}
+
+impl std::fmt::Display for KernelExit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Success => write!(f, "kernel exited successfully"),
+            Self::Error => write!(f, "kernel exited with an error"),
+            Self::Panic => write!(f, "kernel panicked outside of a test"),
+            Self::OutOfMemory => write!(f, "kernel ran out of memory"),
+            Self::Watchdog => write!(f, "kernel watchdog expired"),
+            // The last code covers every test from there on
+            Self::TestFailed { index: index @ 239 } => write!(f, "test #{} or a later one failed", index),
+            Self::TestFailed { index } => write!(f, "test #{} failed", index),
+            Self::Unknown(code) => write!(f, "kernel exited with unknown code {}", code),
+        }
+    }
 }
@@ -0,0 +1,151 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2022 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::Result;
+use anyhow::{anyhow, bail, Context};
+use bytestream::FromBytes;
+use elf64::{Elf, SectionHeader, SymbolTableEntry};
+use std::{fs, path::Path};
+
+const SECTION_NAME: &str = ".ksyms";
+const SYMBOL_TYPE_FUNC: u8 = 2;
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 24;
+
+/// Fill the kernel's `.ksyms` section with a table of its function symbols so
+/// that backtraces can be symbolized, see `src/kernel/vanadinite/src/backtrace.rs`
+/// for the format. Symbols are dropped from the end of the address range if
+/// the table doesn't fit into the space reserved by the linker script.
+pub fn embed(kernel: &Path) -> Result<()> {
+    let mut data = fs::read(kernel).with_context(|| format!("failed to read {}", kernel.display()))?;
+    let elf = Elf::new(&data).ok_or_else(|| anyhow!("{} isn't a valid ELF file", kernel.display()))?;
+
+    let ksyms = find_section(&elf, &data, SECTION_NAME)?
+        .with_context(|| format!("kernel has no `{}` section", SECTION_NAME))?;
+    let symbols = function_symbols(&elf, &data)?;
+
+    let capacity = ksyms.size as usize;
+    let mut entries = Vec::new();
+    let mut strings = Vec::new();
+    let mut included = 0;
+    for (address, size, name) in &symbols {
+        if HEADER_SIZE + (included + 1) * ENTRY_SIZE + strings.len() + name.len() > capacity {
+            eprintln!(
+                "warning: `{}` is full, only {} of {} symbols were embedded",
+                SECTION_NAME,
+                included,
+                symbols.len()
+            );
+            break;
+        }
+
+        entries.extend_from_slice(&address.to_le_bytes());
+        entries.extend_from_slice(&size.to_le_bytes());
+        entries.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        entries.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        entries.extend_from_slice(&0u32.to_le_bytes());
+        strings.extend_from_slice(name.as_bytes());
+        included += 1;
+    }
+
+    if included == 0 {
+        bail!("no function symbols found in {}", kernel.display());
+    }
+
+    let mut table = Vec::with_capacity(capacity);
+    table.extend_from_slice(b"KSYM");
+    table.extend_from_slice(&(included as u32).to_le_bytes());
+    table.extend_from_slice(&((HEADER_SIZE + entries.len()) as u32).to_le_bytes());
+    table.extend_from_slice(&0u32.to_le_bytes());
+    table.extend_from_slice(&entries);
+    table.extend_from_slice(&strings);
+    table.resize(capacity, 0);
+
+    data[ksyms.offset as usize..][..capacity].copy_from_slice(&table);
+    fs::write(kernel, data).with_context(|| format!("failed to write {}", kernel.display()))?;
+
+    Ok(())
+}
+
+/// A binary's function symbols, for symbolizing addresses on the host
+pub struct SymbolTable {
+    /// Sorted by address
+    symbols: Vec<(u64, u32, String)>,
+    /// The lowest address of any loaded segment, which position independent
+    /// binaries are loaded relative to
+    pub load_address: u64,
+}
+
+impl SymbolTable {
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        let elf = Elf::new(&data).ok_or_else(|| anyhow!("{} isn't a valid ELF file", path.display()))?;
+        let symbols = function_symbols(&elf, &data).with_context(|| format!("no symbols in {}", path.display()))?;
+        let load_address = elf.load_segments().map(|header| header.vaddr).min().unwrap_or(0);
+
+        Ok(Self { symbols, load_address })
+    }
+
+    /// Find the function containing `address`, along with the offset of
+    /// `address` into it
+    pub fn resolve(&self, address: u64) -> Option<(&str, u64)> {
+        let index = self.symbols.partition_point(|(start, ..)| *start <= address).checked_sub(1)?;
+        let (start, size, name) = &self.symbols[index];
+        let offset = address - start;
+        match offset < u64::from(*size).max(1) {
+            true => Some((name, offset)),
+            false => None,
+        }
+    }
+}
+
+/// The contents of the section called `name` in the binary at `path`
+pub fn read_section(path: &Path, name: &str) -> Result<Vec<u8>> {
+    let data = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let elf = Elf::new(&data).ok_or_else(|| anyhow!("{} isn't a valid ELF file", path.display()))?;
+    let header = find_section(&elf, &data, name)?.with_context(|| format!("no `{}` section", name))?;
+
+    Ok(section_data(&data, &header).to_vec())
+}
+
+fn section_name(strings: &[u8], offset: u32) -> String {
+    let name = &strings[offset as usize..];
+    String::from_utf8_lossy(&name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())]).into_owned()
+}
+
+fn section_data<'a>(data: &'a [u8], header: &SectionHeader) -> &'a [u8] {
+    &data[header.offset as usize..][..header.size as usize]
+}
+
+fn find_section(elf: &Elf, data: &[u8], wanted: &str) -> Result<Option<SectionHeader>> {
+    let sections: Vec<SectionHeader> = elf.section_headers().collect();
+    let section_names = sections.get(elf.header.sh_string_index as usize).context("missing section names")?;
+
+    Ok(sections.iter().find(|header| section_name(section_data(data, section_names), header.name) == wanted).copied())
+}
+
+/// The demangled function symbols of `elf`, sorted and deduplicated by address
+fn function_symbols(elf: &Elf, data: &[u8]) -> Result<Vec<(u64, u32, String)>> {
+    let symtab = find_section(elf, data, ".symtab")?.context("no symbol table, was it stripped?")?;
+    let strtab = elf.section_headers().nth(symtab.link as usize).context("symbol table has no string table")?;
+
+    let mut symbols: Vec<(u64, u32, String)> = section_data(data, &symtab)
+        .chunks_exact(std::mem::size_of::<SymbolTableEntry>())
+        .filter_map(SymbolTableEntry::from_bytes)
+        .filter(|entry| entry.info & 0xF == SYMBOL_TYPE_FUNC && entry.value != 0)
+        .map(|entry| {
+            let demangled =
+                format!("{:#}", rustc_demangle::demangle(&section_name(section_data(data, &strtab), entry.name)));
+            (entry.value, entry.size.min(u32::MAX as u64) as u32, demangled)
+        })
+        .collect();
+
+    symbols.sort_by_key(|(address, ..)| *address);
+    symbols.dedup_by_key(|(address, ..)| *address);
+
+    Ok(symbols)
+}